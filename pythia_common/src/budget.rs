@@ -26,4 +26,22 @@ pub struct NodeStats {
     pub trace_input_kbps: f32,
     pub agent_cpu_time: f64,
     pub trace_size: u32,
+    /// Osprofiler keys the agent deleted since it started, see `retention_max_age_secs`
+    #[serde(default)]
+    pub expired_keys: u64,
+    #[serde(default)]
+    pub expired_bytes: u64,
+    /// One for each redis instance of the agent
+    #[serde(default)]
+    pub redis_shards: Vec<RedisShardStats>,
+}
+
+/// How one of the redis instances of an agent is doing
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RedisShardStats {
+    pub url: String,
+    /// Why the shard couldn't be read, if it couldn't; then the other fields are 0
+    pub error: Option<String>,
+    pub input_kbps: f32,
+    pub used_memory: u32,
 }
@@ -0,0 +1,51 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+use serde::{Deserialize, Serialize};
+
+/// Agents with less free disk than this are reported as unhealthy
+pub const MIN_FREE_DISK_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// What a Pythia agent reports about itself, so misconfigured agents are found before the
+/// controller relies on them.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AgentHealth {
+    pub host: String,
+    pub version: String,
+    /// Why redis could not be reached, if it couldn't
+    pub redis_error: Option<String>,
+    /// Why the OSProfiler tracepoint configuration (`manifest_root`) is not writable, if it isn't
+    pub manifest_error: Option<String>,
+    /// Free space on the file system of `manifest_root`, if it could be measured
+    pub free_disk_bytes: Option<u64>,
+}
+
+impl AgentHealth {
+    /// Descriptions of everything that is wrong with the agent
+    pub fn problems(&self) -> Vec<String> {
+        let mut result = Vec::new();
+        if let Some(e) = &self.redis_error {
+            result.push(format!("redis: {}", e));
+        }
+        if let Some(e) = &self.manifest_error {
+            result.push(format!("manifest: {}", e));
+        }
+        match self.free_disk_bytes {
+            Some(b) if b < MIN_FREE_DISK_BYTES => {
+                result.push(format!("disk: only {} MB free", b / 1024 / 1024))
+            }
+            Some(_) => {}
+            None => result.push("disk: free space unknown".to_string()),
+        }
+        result
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.problems().is_empty()
+    }
+}
@@ -13,7 +13,10 @@ All rights reserved.
 extern crate lazy_static;
 
 mod budget;
+mod health;
+mod logging;
 pub mod osprofiler;
+mod state;
 
 use std::error::Error;
 use std::fmt;
@@ -21,22 +24,138 @@ use std::fmt;
 pub use crate::osprofiler::AnnotationEnum;
 pub use crate::osprofiler::OSProfilerEnum;
 pub use crate::osprofiler::OSProfilerSpan;
+pub use crate::osprofiler::load_request_types;
+pub use crate::osprofiler::load_request_types_file;
+pub use crate::osprofiler::merge_sorted_spans;
+pub use crate::osprofiler::ParameterizedRequestType;
 pub use crate::osprofiler::RequestType;
-pub use crate::osprofiler::REQUEST_TYPES;
-pub use crate::osprofiler::REQUEST_TYPE_REGEXES;
+pub use crate::osprofiler::RequestTypeDefinition;
+pub use crate::osprofiler::sort_spans;
+pub use crate::osprofiler::SpanBatch;
+pub use crate::osprofiler::SpanCount;
 
 pub use crate::budget::NodeStats;
+pub use crate::budget::RedisShardStats;
+pub use crate::health::AgentHealth;
+pub use crate::logging::init_logging;
+pub use crate::logging::LogFilter;
+pub use crate::logging::Logger;
+pub use crate::state::AgentState;
 
-/// Error raised from within Pythia. It just has a String error message.
+/// Error raised from within Pythia.
 ///
-/// Rust requires everyone to have their own error type.
+/// The variant says which part of Pythia failed, so callers can match on it (e.g., retry
+/// `RpcError`s but give up on `CriticalPathError`s). Errors from libraries are wrapped and
+/// available through `source()`.
 #[derive(Debug)]
-pub struct PythiaError(String);
+pub enum PythiaError {
+    /// Problems reading, parsing, or building traces
+    ReaderError(String),
+    /// Problems talking to the agents or the tracing infrastructure
+    RpcError(String),
+    /// Problems with the search space
+    ManifestError(String),
+    /// The trace could not be turned into a critical path
+    CriticalPathError(String),
+    /// Problems applying instrumentation decisions
+    ControllerError(String),
+    Io(std::io::Error),
+    Json(serde_json::Error),
+}
+
+impl PythiaError {
+    /// Whether retrying the failed operation later might succeed
+    pub fn is_transient(&self) -> bool {
+        match self {
+            PythiaError::RpcError(_) | PythiaError::Io(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Short name of the kind of error, e.g., for reports of failed traces
+    pub fn category(&self) -> &'static str {
+        match self {
+            PythiaError::ReaderError(_) => "reader",
+            PythiaError::RpcError(_) => "rpc",
+            PythiaError::ManifestError(_) => "manifest",
+            PythiaError::CriticalPathError(_) => "critical-path",
+            PythiaError::ControllerError(_) => "controller",
+            PythiaError::Io(_) => "io",
+            PythiaError::Json(_) => "json",
+        }
+    }
+}
 
 impl fmt::Display for PythiaError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Pythia error: {}", self.0)
+        match self {
+            PythiaError::ReaderError(s) => write!(f, "Pythia reader error: {}", s),
+            PythiaError::RpcError(s) => write!(f, "Pythia RPC error: {}", s),
+            PythiaError::ManifestError(s) => write!(f, "Pythia manifest error: {}", s),
+            PythiaError::CriticalPathError(s) => write!(f, "Pythia critical path error: {}", s),
+            PythiaError::ControllerError(s) => write!(f, "Pythia controller error: {}", s),
+            PythiaError::Io(e) => write!(f, "Pythia I/O error: {}", e),
+            PythiaError::Json(e) => write!(f, "Pythia json error: {}", e),
+        }
     }
 }
 
-impl Error for PythiaError {}
+impl Error for PythiaError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PythiaError::Io(e) => Some(e),
+            PythiaError::Json(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Errors are equal if they are of the same kind and carry the same message. Wrapped I/O
+/// errors are compared by their kind, JSON errors by their category and position.
+impl PartialEq for PythiaError {
+    fn eq(&self, other: &PythiaError) -> bool {
+        use PythiaError::*;
+        match (self, other) {
+            (ReaderError(a), ReaderError(b))
+            | (RpcError(a), RpcError(b))
+            | (ManifestError(a), ManifestError(b))
+            | (CriticalPathError(a), CriticalPathError(b))
+            | (ControllerError(a), ControllerError(b)) => a == b,
+            (Io(a), Io(b)) => a.kind() == b.kind(),
+            (Json(a), Json(b)) => {
+                a.classify() == b.classify() && a.line() == b.line() && a.column() == b.column()
+            }
+            _ => false,
+        }
+    }
+}
+
+impl From<std::io::Error> for PythiaError {
+    fn from(e: std::io::Error) -> PythiaError {
+        PythiaError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for PythiaError {
+    fn from(e: serde_json::Error) -> PythiaError {
+        PythiaError::Json(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_of_different_kinds_differ() {
+        let rpc = PythiaError::RpcError("x".to_string());
+        assert_eq!(rpc, PythiaError::RpcError("x".to_string()));
+        assert_ne!(rpc, PythiaError::RpcError("y".to_string()));
+        assert_ne!(rpc, PythiaError::ReaderError("x".to_string()));
+        let io = |kind| PythiaError::from(std::io::Error::new(kind, "x"));
+        assert_eq!(io(std::io::ErrorKind::NotFound), io(std::io::ErrorKind::NotFound));
+        assert_ne!(io(std::io::ErrorKind::NotFound), io(std::io::ErrorKind::Other));
+        assert!(rpc.is_transient());
+        assert!(!PythiaError::ManifestError("x".to_string()).is_transient());
+    }
+}
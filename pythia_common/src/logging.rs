@@ -0,0 +1,195 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Logging for the controller, the command line tool and the agents.
+//!
+//! Everything logs through the `log` macros, with key-values such as `trace_id` and
+//! `group_hash` for the records that are about a trace or a group:
+//! `warn!(trace_id:% = id; "Could not build the critical path")`. `init_logging` installs a
+//! logger that writes one line per record to stderr, the key-values after the message.
+//!
+//! `PYTHIA_LOG` picks what is written, like `RUST_LOG`: a level, then `module=level` for the
+//! modules that should differ, e.g. `info,pythia::reader=debug,pythia::search=warn`.
+//! `PYTHIA_LOG_FORMAT=json` writes a JSON object per record instead, for log aggregation.
+//!
+//! This is `log` rather than `tracing` because `tracing` and `tracing-subscriber` are not in the
+//! registry mirror the project builds from offline. Records use the same key-values a `tracing`
+//! event would have, so moving over later only changes the macros' syntax.
+
+use std::io::Write;
+
+use chrono::offset::Local;
+use log::kv::{Error, Key, Value, VisitSource};
+use log::{LevelFilter, Log, Metadata, Record};
+use serde_json::Map;
+
+/// Which records are written: the level of the longest module prefix that matches the target
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogFilter {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl LogFilter {
+    /// Parses `PYTHIA_LOG`; parts that are not levels are skipped with a warning
+    pub fn parse(spec: &str, default: LevelFilter) -> LogFilter {
+        let mut result = LogFilter {
+            default,
+            modules: Vec::new(),
+        };
+        for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let parsed = match part.split_once('=') {
+                Some((module, level)) => level
+                    .parse()
+                    .map(|level| result.modules.push((module.to_string(), level))),
+                None => part.parse().map(|level| result.default = level),
+            };
+            if parsed.is_err() {
+                eprintln!("Ignoring {:?} in PYTHIA_LOG, it is not a log level", part);
+            }
+        }
+        // Longest prefix first
+        result
+            .modules
+            .sort_by_key(|(module, _)| std::cmp::Reverse(module.len()));
+        result
+    }
+
+    pub fn level(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .find(|(module, _)| {
+                target == module
+                    || (target.starts_with(module.as_str())
+                        && target[module.len()..].starts_with("::"))
+            })
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    /// The most verbose level of any module
+    fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, std::cmp::max)
+    }
+}
+
+struct Fields(Vec<(String, String)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), Error> {
+        self.0.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+}
+
+pub struct Logger {
+    filter: LogFilter,
+    json: bool,
+}
+
+impl Logger {
+    pub fn new(filter: LogFilter, json: bool) -> Logger {
+        Logger { filter, json }
+    }
+
+    pub fn format(&self, record: &Record) -> String {
+        let mut fields = Fields(Vec::new());
+        record.key_values().visit(&mut fields).ok();
+        let timestamp = Local::now().format("%Y-%m-%dT%H:%M:%S%.3f").to_string();
+        if self.json {
+            let mut object = Map::new();
+            object.insert("timestamp".into(), timestamp.into());
+            object.insert("level".into(), record.level().as_str().into());
+            object.insert("target".into(), record.target().into());
+            object.insert("message".into(), record.args().to_string().into());
+            for (key, value) in fields.0 {
+                object.insert(key, value.into());
+            }
+            serde_json::Value::Object(object).to_string()
+        } else {
+            let mut line = format!(
+                "{} {:5} {}: {}",
+                timestamp,
+                record.level(),
+                record.target(),
+                record.args()
+            );
+            for (key, value) in fields.0 {
+                line.push_str(&format!(" {}={}", key, value));
+            }
+            line
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.filter.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            writeln!(std::io::stderr(), "{}", self.format(record)).ok();
+        }
+    }
+
+    fn flush(&self) {
+        std::io::stderr().flush().ok();
+    }
+}
+
+/// Installs the logger, configured from `PYTHIA_LOG` and `PYTHIA_LOG_FORMAT`. Without
+/// `PYTHIA_LOG`, records at `default` and above are written.
+pub fn init_logging(default: LevelFilter) {
+    let filter = LogFilter::parse(&std::env::var("PYTHIA_LOG").unwrap_or_default(), default);
+    let json = std::env::var("PYTHIA_LOG_FORMAT").is_ok_and(|f| f == "json");
+    let max_level = filter.max_level();
+    match log::set_boxed_logger(Box::new(Logger::new(filter, json))) {
+        Ok(()) => log::set_max_level(max_level),
+        Err(e) => eprintln!("Could not set up logging: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use log::Level;
+
+    #[test]
+    fn filters_and_formats() {
+        let filter = LogFilter::parse(
+            "warn, pythia::reader=debug,pythia=info,bogus",
+            LevelFilter::Info,
+        );
+        assert_eq!(
+            filter.level("pythia::reader::osprofiler"),
+            LevelFilter::Debug
+        );
+        assert_eq!(filter.level("pythia::readers"), LevelFilter::Info);
+        assert_eq!(filter.level("pythia_server"), LevelFilter::Warn);
+        assert_eq!(filter.max_level(), LevelFilter::Debug);
+
+        let fields = [("trace_id", "1234")];
+        let record = Record::builder()
+            .level(Level::Warn)
+            .target("pythia::critical")
+            .args(format_args!("No critical path"))
+            .key_values(&fields)
+            .build();
+        let line = Logger::new(filter.clone(), false).format(&record);
+        assert!(line.ends_with(" WARN  pythia::critical: No critical path trace_id=1234"));
+        let json: serde_json::Value =
+            serde_json::from_str(&Logger::new(filter, true).format(&record)).unwrap();
+        assert_eq!(json["level"], "WARN");
+        assert_eq!(json["message"], "No critical path");
+        assert_eq!(json["trace_id"], "1234");
+    }
+}
@@ -8,78 +8,324 @@ All rights reserved.
 
 /// Stuff related to working with osprofiler
 ///
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::collections::VecDeque;
 use std::fmt;
+use std::path::Path;
+use std::sync::RwLock;
 
 use chrono::NaiveDateTime;
+use regex::Regex;
 use regex::RegexSet;
+use serde::de;
+use serde::ser;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use crate::PythiaError;
 
-/// Type of a request.
-///
-/// It's defined here because for now we only use them for OpenStack.
-#[derive(Serialize, Deserialize, Debug, Copy, Eq, PartialEq, Hash, Clone)]
-pub enum RequestType {
-    ServerCreate,
-    ServerDelete,
-    ServerList,
-    FloatingIPCreate,
-    FloatingIPDelete,
-    FloatingIPList,
-    UsageList,
-    Unknown,
-}
-
-lazy_static! {
-    // The ordering of the below two structures should match each other. If any
-    // tracepoint id in the trace matches any of these regexes, we set the
-    // request type accordingly.
-    pub static ref REQUEST_TYPE_REGEXES: RegexSet = RegexSet::new(&[
+/// Definitions used until `load_request_types` replaces them, as (name, regex) pairs
+const DEFAULT_REQUEST_TYPES: &[(&str, &str)] = &[
+    (
+        "ServerCreate",
         r"openstackclient\.compute\.v2\.server\.CreateServer\.take_action",
+    ),
+    (
+        "ServerList",
         r"openstackclient\.compute\.v2\.server\.ListServer\.take_action",
+    ),
+    (
+        "ServerDelete",
         r"openstackclient\.compute\.v2\.server\.DeleteServer\.take_action",
+    ),
+    (
+        "FloatingIPCreate",
         r"openstackclient\.network\.v2\.floating_ip\.CreateFloatingIP\.take_action_network",
+    ),
+    (
+        "FloatingIPList",
         r"openstackclient\.network\.v2\.floating_ip\.ListFloatingIP\.take_action_network",
+    ),
+    (
+        "FloatingIPDelete",
         r"openstackclient\.network\.v2\.floating_ip\.DeleteFloatingIP\.take_action_network",
-        r"novaclient\.v2\.usage\.UsageManager\.list",
-    ])
-    .unwrap();
-    pub static ref REQUEST_TYPES: Vec<RequestType> = vec![
-        RequestType::ServerCreate,
-        RequestType::ServerList,
-        RequestType::ServerDelete,
-        RequestType::FloatingIPCreate,
-        RequestType::FloatingIPList,
-        RequestType::FloatingIPDelete,
-        RequestType::UsageList,
-    ];
+    ),
+    ("UsageList", r"novaclient\.v2\.usage\.UsageManager\.list"),
+    (
+        "VolumeCreate",
+        r"openstackclient\.volume\.v[23]\.volume\.CreateVolume\.take_action",
+    ),
+    (
+        "VolumeAttach",
+        r"openstackclient\.compute\.v2\.server\.AddServerVolume\.take_action",
+    ),
+    (
+        "VolumeDetach",
+        r"openstackclient\.compute\.v2\.server\.RemoveServerVolume\.take_action",
+    ),
+    (
+        "PortCreate",
+        r"openstackclient\.network\.v2\.port\.CreatePort\.take_action",
+    ),
+    (
+        "PortDelete",
+        r"openstackclient\.network\.v2\.port\.DeletePort\.take_action",
+    ),
+];
+
+/// A request type and the regex that identifies it, as written in the request types file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RequestTypeDefinition {
+    pub name: String,
+    pub regex: String,
+}
+
+struct RequestTypeRegistry {
+    /// Index 0 is Unknown
+    names: Vec<String>,
+    /// Types that have a regex, in the order of `regexes`
+    defined: Vec<RequestType>,
+    regexes: RegexSet,
+}
+
+impl RequestTypeRegistry {
+    fn lookup(&self, name: &str) -> Option<RequestType> {
+        self.names
+            .iter()
+            .position(|n| n == name)
+            .map(|id| RequestType { id })
+    }
+
+    fn intern(&mut self, name: &str) -> RequestType {
+        match self.lookup(name) {
+            Some(t) => t,
+            None => {
+                self.names.push(name.to_string());
+                RequestType {
+                    id: self.names.len() - 1,
+                }
+            }
+        }
+    }
+
+    /// Names that are already interned keep their ids
+    fn define(&mut self, definitions: &[RequestTypeDefinition]) -> Result<(), regex::Error> {
+        self.regexes = RegexSet::new(definitions.iter().map(|d| &d.regex))?;
+        let defined = definitions.iter().map(|d| self.intern(&d.name)).collect();
+        self.defined = defined;
+        Ok(())
+    }
+}
+
+lazy_static! {
+    static ref REQUEST_TYPE_REGISTRY: RwLock<RequestTypeRegistry> = {
+        let mut registry = RequestTypeRegistry {
+            names: vec!["Unknown".to_string()],
+            defined: Vec::new(),
+            regexes: RegexSet::new(&[] as &[&str]).unwrap(),
+        };
+        let definitions: Vec<_> = DEFAULT_REQUEST_TYPES
+            .iter()
+            .map(|&(name, regex)| RequestTypeDefinition {
+                name: name.to_string(),
+                regex: regex.to_string(),
+            })
+            .collect();
+        registry.define(&definitions).unwrap();
+        RwLock::new(registry)
+    };
+}
+
+/// Replace the request type definitions. If a tracepoint id in a trace matches one of the regexes,
+/// the trace gets that request type.
+///
+/// This should be called at startup, before any trace is read: traces that were classified before
+/// keep their request types.
+pub fn load_request_types(definitions: &[RequestTypeDefinition]) -> Result<(), PythiaError> {
+    REQUEST_TYPE_REGISTRY
+        .write()
+        .unwrap()
+        .define(definitions)
+        .map_err(|e| PythiaError::ManifestError(format!("Bad request type regex: {}", e)))
+}
+
+/// Read request type definitions from a json file containing a list of `{"name", "regex"}`
+pub fn load_request_types_file(path: &Path) -> Result<(), PythiaError> {
+    let definitions: Vec<RequestTypeDefinition> =
+        serde_json::from_reader(std::fs::File::open(path)?)?;
+    load_request_types(&definitions)
+}
+
+/// Type of a request.
+///
+/// It's defined here because for now we only use them for OpenStack. Request types are read from
+/// configuration (see `load_request_types`), and interned so they can be copied around like
+/// tracepoint ids.
+#[derive(Copy, Eq, PartialEq, Hash, Clone)]
+pub struct RequestType {
+    id: usize,
 }
 
 impl RequestType {
+    #[allow(non_upper_case_globals)]
+    pub const Unknown: RequestType = RequestType { id: 0 };
+
+    /// Only names of defined (or previously deserialized) request types are accepted
     pub fn from_str(typ: &str) -> Result<RequestType, &str> {
-        match typ {
-            "ServerCreate" => Ok(RequestType::ServerCreate),
-            "ServerDelete" => Ok(RequestType::ServerDelete),
-            "ServerList" => Ok(RequestType::ServerList),
-            "FloatingIPCreate" => Ok(RequestType::FloatingIPCreate),
-            "FloatingIPDelete" => Ok(RequestType::FloatingIPDelete),
-            "FloatingIPList" => Ok(RequestType::FloatingIPList),
-            "UsageList" => Ok(RequestType::UsageList),
-            "Unknown" => Ok(RequestType::Unknown),
-            _ => Err("Unknown request type"),
-        }
+        REQUEST_TYPE_REGISTRY
+            .read()
+            .unwrap()
+            .lookup(typ)
+            .ok_or("Unknown request type")
+    }
+
+    pub fn name(&self) -> String {
+        REQUEST_TYPE_REGISTRY.read().unwrap().names[self.id].clone()
+    }
+
+    /// Request types whose regex matches `tracepoint`
+    pub fn matching(tracepoint: &str) -> Vec<RequestType> {
+        let registry = REQUEST_TYPE_REGISTRY.read().unwrap();
+        registry
+            .regexes
+            .matches(tracepoint)
+            .into_iter()
+            .map(|i| registry.defined[i])
+            .collect()
+    }
+
+    /// Whether `tracepoint` identifies any request type
+    pub fn is_match(tracepoint: &str) -> bool {
+        REQUEST_TYPE_REGISTRY
+            .read()
+            .unwrap()
+            .regexes
+            .is_match(tracepoint)
+    }
+
+    /// All defined request types, in the order their regexes are tried
+    pub fn all() -> Vec<RequestType> {
+        REQUEST_TYPE_REGISTRY.read().unwrap().defined.clone()
     }
 }
 
 impl fmt::Display for RequestType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}", self)
+        write!(f, "{}", self.name())
+    }
+}
+
+impl fmt::Debug for RequestType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
     }
 }
 
+impl Serialize for RequestType {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        s.serialize_str(&self.name())
+    }
+}
+
+struct RequestTypeVisitor;
+
+impl<'de> de::Visitor<'de> for RequestTypeVisitor {
+    type Value = RequestType;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "a request type name")
+    }
+
+    fn visit_str<E>(self, s: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        // Manifests may mention request types that are no longer configured; keep them
+        Ok(REQUEST_TYPE_REGISTRY.write().unwrap().intern(s))
+    }
+}
+
+impl<'de> Deserialize<'de> for RequestType {
+    fn deserialize<D>(d: D) -> Result<RequestType, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        d.deserialize_str(RequestTypeVisitor)
+    }
+}
+
+impl RequestType {
+    /// Request arguments that change the latency of this request type, so requests with different
+    /// values should not be compared with each other.
+    pub fn salient_parameters(&self) -> &'static [&'static str] {
+        match self.name().as_str() {
+            "ServerCreate" => &["flavor", "image"],
+            "VolumeCreate" => &["size", "volume_type"],
+            _ => &[],
+        }
+    }
+}
+
+/// A request type together with the values of its salient parameters
+///
+/// The parameters are extracted when the trace is classified. Requests of the same type but with
+/// different parameters (e.g., creating a small vs. a large server) are different populations.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Hash)]
+pub struct ParameterizedRequestType {
+    pub kind: RequestType,
+    pub params: BTreeMap<String, String>,
+}
+
+impl ParameterizedRequestType {
+    pub fn new(kind: RequestType) -> Self {
+        ParameterizedRequestType {
+            kind,
+            params: BTreeMap::new(),
+        }
+    }
+}
+
+impl From<RequestType> for ParameterizedRequestType {
+    fn from(kind: RequestType) -> Self {
+        ParameterizedRequestType::new(kind)
+    }
+}
+
+impl fmt::Display for ParameterizedRequestType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if !self.params.is_empty() {
+            let params = self
+                .params
+                .iter()
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect::<Vec<_>>();
+            write!(f, "[{}]", params.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses the `'key': 'value'` pairs of a python kwargs dict as printed by OSProfiler. Values
+/// that aren't strings are kept as they are printed.
+pub fn parse_kwargs(kwargs: &str) -> HashMap<String, String> {
+    lazy_static! {
+        static ref KWARG: Regex = Regex::new(r"'([^']+)': (?:'([^']*)'|([^,}]+))").unwrap();
+    }
+    KWARG
+        .captures_iter(kwargs)
+        .map(|c| {
+            let value = c.get(2).or(c.get(3)).unwrap().as_str().trim();
+            (c[1].to_string(), value.to_string())
+        })
+        .collect()
+}
+
 impl OSProfilerSpan {
     /// We need this method because span endings do not have tracepoint IDs in OSProfiler.
     ///
@@ -101,7 +347,7 @@ impl OSProfilerSpan {
                     if self.name.starts_with("asynch_wait") {
                         self.tracepoint_id.clone()
                     } else {
-                        return Err(PythiaError(format!(
+                        return Err(PythiaError::ReaderError(format!(
                             "Couldn't find trace id for {:?}",
                             self
                         )));
@@ -112,6 +358,79 @@ impl OSProfilerSpan {
     }
 }
 
+/// Spans of one request recorded on one host, as the agents publish them to Kafka or return them
+/// from `get_trace_fragment`.
+///
+/// A request can have several batches, from different hosts or from the same host at different
+/// times; the consumer merges them by `base_id`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SpanBatch {
+    pub host: String,
+    pub base_id: Uuid,
+    pub spans: Vec<OSProfilerSpan>,
+}
+
+/// How much of a request one agent has, so the controller can tell when the trace is complete
+/// without fetching its spans
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct SpanCount {
+    pub spans: usize,
+    /// A top-level span of the request ended here
+    pub root_exit: bool,
+}
+
+impl SpanCount {
+    pub fn of(base_id: &Uuid, spans: &[OSProfilerSpan]) -> SpanCount {
+        SpanCount {
+            spans: spans.len(),
+            root_exit: spans
+                .iter()
+                .any(|s| s.parent_id == *base_id && matches!(s.info, OSProfilerEnum::Exit(_))),
+        }
+    }
+}
+
+/// Order of spans in a trace: by timestamp, with entries before other spans at the same time
+pub fn span_order(a: &OSProfilerSpan, b: &OSProfilerSpan) -> Ordering {
+    fn rank(span: &OSProfilerSpan) -> u8 {
+        match span.info {
+            OSProfilerEnum::FunctionEntry(_) | OSProfilerEnum::RequestEntry(_) => 0,
+            _ => 1,
+        }
+    }
+    a.timestamp
+        .cmp(&b.timestamp)
+        .then_with(|| rank(a).cmp(&rank(b)))
+}
+
+pub fn sort_spans(spans: &mut [OSProfilerSpan]) {
+    spans.sort_by(span_order);
+}
+
+/// Merge span lists that are each sorted (e.g., trace fragments from different agents) into one
+/// sorted list
+pub fn merge_sorted_spans(lists: Vec<Vec<OSProfilerSpan>>) -> Vec<OSProfilerSpan> {
+    let mut result = Vec::with_capacity(lists.iter().map(|l| l.len()).sum());
+    let mut lists: Vec<VecDeque<_>> = lists.into_iter().map(VecDeque::from).collect();
+    loop {
+        let mut next: Option<usize> = None;
+        for i in 0..lists.len() {
+            let head = match lists[i].front() {
+                Some(h) => h,
+                None => continue,
+            };
+            next = match next {
+                Some(j) if span_order(&lists[j][0], head) != Ordering::Greater => Some(j),
+                _ => Some(i),
+            };
+        }
+        match next {
+            Some(i) => result.push(lists[i].pop_front().unwrap()),
+            None => return result,
+        }
+    }
+}
+
 /// What an OSProfiler event json has.
 ///
 /// What we collect from redis needs to exactly match this struct, otherwise
@@ -168,7 +487,7 @@ pub struct WaitForKeyValueAnnotation {
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct ArgsKeyValueInfo {
-    function: ArgsKeyValueFunction,
+    pub function: ArgsKeyValueFunction,
     tracepoint_id: String,
     host: String,
     thread_id: u64,
@@ -343,20 +662,23 @@ pub mod serde_timestamp {
     }
 }
 
-pub fn parse_field(field: &String) -> Result<OSProfilerSpan, String> {
+/// Parse a single span from its json representation.
+///
+/// Errors contain the offending field so that callers can skip the span and report it.
+pub fn parse_field(field: &String) -> Result<OSProfilerSpan, PythiaError> {
     let result: OSProfilerSpan = match serde_json::from_str(field) {
         Ok(a) => a,
         Err(e) => {
-            return Err(e.to_string());
+            return Err(PythiaError::ReaderError(format!("Malformed span {}: {}", field, e)));
         }
     };
     if result.name == "asynch_request" || result.name == "asynch_wait" {
         return match result.info {
             OSProfilerEnum::Annotation(_) => Ok(result),
-            _ => {
-                println!("{:?}", result);
-                Err("".to_string())
-            }
+            _ => Err(PythiaError::ReaderError(format!(
+                "Span {} named {} is not an annotation",
+                result.trace_id, result.name
+            ))),
         };
     }
     Ok(result)
@@ -403,4 +725,111 @@ mod tests {
         //checking if parse_field function works with the added struct to parse the code correctly
         assert_eq!(parse_field(&(r#"{"trace_id": "936DA01F9ABD4d9d80C702AF85C822A8", "parent_id": "936DA01F9ABD4d9d80C702AF85C822A8", "project": "nova", "name": "build_instance",  "base_id": "936DA01F9ABD4d9d80C702AF85C822A8", "service": "nova", "tracepoint_id": "nova/manager.py", "timestamp": "2020-06-23T14:32:34.058", "info": {"value":293402358,"tracepoint_id": "nova/usr/local", "host": "cloudlab", "thread_id": 5743728237, "pid": 4771}}"#).to_string()),Ok(test_struct));
     }
+
+    #[test]
+    fn test_merge_sorted_spans() {
+        let span = |secs: i64, value: u64| OSProfilerSpan {
+            trace_id: Uuid::nil(),
+            parent_id: Uuid::nil(),
+            project: "nova".to_string(),
+            name: "kv".to_string(),
+            base_id: Uuid::nil(),
+            service: "nova".to_string(),
+            tracepoint_id: "nova/manager.py".to_string(),
+            timestamp: NaiveDateTime::from_timestamp(secs, 0),
+            info: OSProfilerEnum::Annotation(AnnotationEnum::KeyValue(KeyValueAnnotationInfo {
+                value,
+                tracepoint_id: "nova/manager.py".to_string(),
+                host: "cloudlab".to_string(),
+                thread_id: 0,
+                pid: 0,
+            })),
+        };
+        let mut fragment = vec![span(3, 0), span(1, 1)];
+        sort_spans(&mut fragment);
+        let merged = merge_sorted_spans(vec![fragment, vec![span(2, 2), span(4, 3)], vec![]]);
+        let order: Vec<_> = merged
+            .iter()
+            .map(|s| match &s.info {
+                OSProfilerEnum::Annotation(AnnotationEnum::KeyValue(kv)) => kv.value,
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(order, vec![1, 2, 0, 3]);
+        assert_eq!(span_order(&span(1, 0), &span(1, 1)), Ordering::Equal);
+    }
+
+    #[test]
+    fn test_span_count() {
+        let base_id = Uuid::from_u128(1);
+        let exit = |parent_id: Uuid| OSProfilerSpan {
+            trace_id: Uuid::from_u128(3),
+            parent_id,
+            project: "nova".to_string(),
+            name: "build_instance-stop".to_string(),
+            base_id,
+            service: "nova".to_string(),
+            tracepoint_id: "nova/manager.py".to_string(),
+            timestamp: NaiveDateTime::default(),
+            info: OSProfilerEnum::Exit(ExitEnum::Normal(NormalExitInfo {
+                host: "cloudlab".to_string(),
+            })),
+        };
+        let nested = exit(Uuid::from_u128(2));
+        assert_eq!(
+            SpanCount::of(&base_id, &[nested.clone()]),
+            SpanCount {
+                spans: 1,
+                root_exit: false
+            }
+        );
+        assert!(SpanCount::of(&base_id, &[nested, exit(base_id)]).root_exit);
+    }
+
+    #[test]
+    fn test_parse_kwargs() {
+        let kwargs = parse_kwargs("{'flavor': 'm1.small', 'image': 'cirros', 'min_count': 1}");
+        assert_eq!(kwargs.get("flavor").unwrap(), "m1.small");
+        assert_eq!(kwargs.get("image").unwrap(), "cirros");
+        assert_eq!(kwargs.get("min_count").unwrap(), "1");
+
+        let mut rt = ParameterizedRequestType::new(RequestType::from_str("ServerCreate").unwrap());
+        assert_eq!(rt.to_string(), "ServerCreate");
+        rt.params.insert("flavor".to_string(), "m1.small".to_string());
+        assert_eq!(rt.to_string(), "ServerCreate[flavor=m1.small]");
+    }
+
+    #[test]
+    fn test_request_types() {
+        let server_create = RequestType::from_str("ServerCreate").unwrap();
+        let mut definitions: Vec<_> = DEFAULT_REQUEST_TYPES
+            .iter()
+            .map(|&(name, regex)| RequestTypeDefinition {
+                name: name.to_string(),
+                regex: regex.to_string(),
+            })
+            .collect();
+        definitions.push(RequestTypeDefinition {
+            name: "VolumeExtend".to_string(),
+            regex: r"cinderclient\.v3\.volumes\.VolumeManager\.extend".to_string(),
+        });
+        load_request_types(&definitions).unwrap();
+        let volume_extend = RequestType::from_str("VolumeExtend").unwrap();
+        assert_eq!(
+            RequestType::matching("cinderclient.v3.volumes.VolumeManager.extend"),
+            vec![volume_extend]
+        );
+        assert_eq!(
+            RequestType::matching("openstackclient.network.v2.port.DeletePort.take_action"),
+            vec![RequestType::from_str("PortDelete").unwrap()]
+        );
+        assert_eq!(RequestType::from_str("ServerCreate"), Ok(server_create));
+        assert!(RequestType::from_str("VolumeMigrate").is_err());
+
+        let json = serde_json::to_string(&vec![volume_extend, RequestType::Unknown]).unwrap();
+        assert_eq!(json, r#"["VolumeExtend","Unknown"]"#);
+        let types: Vec<RequestType> = serde_json::from_str(r#"["VolumeExtend","Other"]"#).unwrap();
+        assert_eq!(types[0], volume_extend);
+        assert_eq!(types[1].to_string(), "Other");
+    }
 }
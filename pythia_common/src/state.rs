@@ -0,0 +1,20 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+use serde::{Deserialize, Serialize};
+
+use crate::osprofiler::RequestType;
+
+/// Every tracepoint setting of an agent, as `dump_state` returns it and `load_state` applies it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AgentState {
+    pub host: String,
+    /// The tracepoint without a leading `/`, the request type the setting is for (`None` for
+    /// all) and whether it is on
+    pub tracepoints: Vec<(String, Option<RequestType>, bool)>,
+}
@@ -7,8 +7,11 @@ All rights reserved.
 */
 
 use std::error::Error;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use std::time::Instant;
 
+use log::debug;
 use procfs::{
     net::{dev_status, DeviceStatus},
     process::Process,
@@ -18,6 +21,7 @@ use procfs::{
 use pythia_common::NodeStats;
 
 use crate::osprofiler::OSProfilerReader;
+use crate::retention::RetentionStats;
 use crate::settings::Settings;
 
 /// Contains values last read, time of last reading and some settings.
@@ -27,6 +31,7 @@ pub struct NodeStatReader {
     last_stats: Option<NetworkStats>,
     last_cputime: Option<u64>,
     last_measurement: Option<Instant>,
+    retention: Arc<RetentionStats>,
 }
 
 struct NetworkStats {
@@ -48,35 +53,40 @@ impl NetworkStats {
 }
 
 impl NodeStatReader {
-    pub fn from_settings(settings: &Settings, reader: &mut OSProfilerReader) -> Self {
+    pub fn from_settings(
+        settings: &Settings,
+        reader: &OSProfilerReader,
+        retention: Arc<RetentionStats>,
+    ) -> Self {
         let mut result = NodeStatReader {
             interface: settings.network_interface.clone(),
             last_stats: None,
             last_cputime: None,
             last_measurement: None,
+            retention,
         };
         result.read_node_stats(reader).ok();
-        eprintln!("Measuring node stats -- Mert 11");
         result
     }
 
     pub fn read_node_stats(
         &mut self,
-        reader: &mut OSProfilerReader,
+        reader: &OSProfilerReader,
     ) -> Result<NodeStats, Box<dyn Error>> {
         let loadavg = LoadAverage::new()?;
         let netstat = dev_status()?;
-        let (current_trace_bytes, trace_size) = reader.get_stats();
+        let (current_trace_bytes, trace_size, redis_shards) = reader.get_stats();
         let stat = Process::myself()?.stat()?;
         let measure_time = Instant::now();
         let current_stats = NetworkStats::read(netstat.get(&self.interface).unwrap());
         let cputime = stat.utime + stat.stime + (stat.cutime + stat.cstime) as u64;
         let tps = procfs::ticks_per_second()? as u64;
+        let expired_keys = self.retention.expired_keys.load(Ordering::Relaxed);
+        let expired_bytes = self.retention.expired_bytes.load(Ordering::Relaxed);
         if self.last_measurement.is_none()
             || self.last_stats.is_none()
             || self.last_cputime.is_none()
         {
-            eprintln!("Measuring node stats - if 1");
             // First run
             self.last_measurement = Some(measure_time);
             self.last_stats = Some(current_stats);
@@ -92,10 +102,12 @@ impl NodeStatReader {
                 trace_input_kbps: 0.0,
                 agent_cpu_time: 0.0,
                 trace_size: 0,
+                expired_keys,
+                expired_bytes,
+                redis_shards,
             });
         }
         let elapsed = self.last_measurement.unwrap().elapsed().as_secs();
-        eprintln!("Measuring node stats -- MERT 2");
         let result = NodeStats {
             // Network stats
             receive_bytes_per_sec: (current_stats.receive_bytes
@@ -120,12 +132,14 @@ impl NodeStatReader {
             trace_input_kbps: current_trace_bytes,
             agent_cpu_time: ((cputime - self.last_cputime.unwrap()) / tps) as f64 / elapsed as f64,
             trace_size: trace_size,
+            expired_keys,
+            expired_bytes,
+            redis_shards,
         };
-        eprintln!("MERT hata oldumu bakalim");
         self.last_stats = Some(current_stats);
         self.last_measurement = Some(measure_time);
         self.last_cputime = Some(cputime);
-        eprintln!("MERTiko {:?}", result);
+        debug!("Node stats: {:?}", result);
         Ok(result)
     }
 }
@@ -13,10 +13,13 @@ All rights reserved.
 //! to only a single request type. Any `/` in the trace point id is kept as is,
 //! so the manifest has a lot of subfolders.
 
+use std::collections::HashSet;
 use std::fs::{read_dir, File};
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
 
+use log::{error, warn};
+
 use pythia_common::RequestType;
 
 use crate::settings::Settings;
@@ -27,9 +30,15 @@ pub struct OSProfilerController {
 
 impl OSProfilerController {
     pub fn from_settings(settings: &Settings) -> OSProfilerController {
-        OSProfilerController {
-            manifest_root: settings.manifest_root.clone(),
-        }
+        OSProfilerController::new(settings.manifest_root.clone())
+    }
+
+    pub fn new(manifest_root: PathBuf) -> OSProfilerController {
+        OSProfilerController { manifest_root }
+    }
+
+    pub fn manifest_root(&self) -> &Path {
+        &self.manifest_root
     }
 
     pub fn write_client_dir(&self, to_write: &[u8; 1]) {
@@ -42,6 +51,111 @@ impl OSProfilerController {
         }
     }
 
+    /// Makes the tracepoint files match `settings` exactly: request type files that are not in
+    /// them are removed and other tracepoints are turned off
+    pub fn load_settings(&self, settings: &[(String, Option<RequestType>, bool)]) {
+        let wanted: HashSet<(&str, Option<RequestType>)> = settings
+            .iter()
+            .map(|(tracepoint, request_type, _)| {
+                (tracepoint.trim_start_matches('/'), *request_type)
+            })
+            .collect();
+        for (tracepoint, request_type, _) in self.read_all_settings() {
+            if wanted.contains(&(tracepoint.as_str(), request_type)) {
+                continue;
+            }
+            match request_type {
+                Some(_) => {
+                    std::fs::remove_file(self.get_path(&tracepoint, &request_type)).ok();
+                }
+                None => self.write_to_tracepoint(&tracepoint, &None, b"0"),
+            }
+        }
+        for (tracepoint, request_type, on) in settings {
+            let to_write = if *on { b"1" } else { b"0" };
+            self.write_to_tracepoint(tracepoint, request_type, to_write);
+        }
+    }
+
+    /// Whether each tracepoint is on, or `None` if it has no file on this host. A request type
+    /// without its own file follows the tracepoint's file. Without a request type, a tracepoint
+    /// is on if it is on for any.
+    pub fn read_settings(&self, points: &[(String, Option<RequestType>)]) -> Vec<Option<bool>> {
+        points
+            .iter()
+            .map(|(tracepoint, request_type)| match request_type {
+                Some(_) => self
+                    .read_tracepoint(tracepoint, request_type)
+                    .or_else(|| self.read_tracepoint(tracepoint, &None)),
+                None => {
+                    let for_a_type = RequestType::all()
+                        .into_iter()
+                        .any(|t| self.read_tracepoint(tracepoint, &Some(t)) == Some(true));
+                    if for_a_type {
+                        Some(true)
+                    } else {
+                        self.read_tracepoint(tracepoint, &None)
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Every tracepoint file under `manifest_root`, as the tracepoint (relative to
+    /// `manifest_root`), the request type it is for and whether it is on
+    pub fn read_all_settings(&self) -> Vec<(String, Option<RequestType>, bool)> {
+        let mut result = Vec::new();
+        self.read_dir(&self.manifest_root, &mut result);
+        result
+    }
+
+    fn read_dir(&self, dir: &Path, result: &mut Vec<(String, Option<RequestType>, bool)>) {
+        let entries = match read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Could not read {:?}: {}", dir, e);
+                return;
+            }
+        };
+        for path in entries.filter_map(|f| f.ok()).map(|f| f.path()) {
+            if path.is_dir() {
+                self.read_dir(&path, result);
+                continue;
+            }
+            let relative = match path.strip_prefix(&self.manifest_root) {
+                Ok(relative) => relative.to_string_lossy().to_string(),
+                Err(_) => continue,
+            };
+            // Tracepoints have colons too, only a request type at the end is split off
+            let (tracepoint, request_type) = match relative.rsplit_once(':') {
+                Some((tracepoint, suffix)) => match RequestType::from_str(suffix) {
+                    Ok(t) => (tracepoint.to_string(), Some(t)),
+                    Err(_) => (relative.clone(), None),
+                },
+                None => (relative.clone(), None),
+            };
+            let mut contents = Vec::new();
+            if File::open(&path)
+                .and_then(|mut f| f.read_to_end(&mut contents))
+                .is_ok()
+            {
+                result.push((tracepoint, request_type, contents.first() == Some(&b'1')));
+            }
+        }
+    }
+
+    fn read_tracepoint(
+        &self,
+        tracepoint: &str,
+        request_type: &Option<RequestType>,
+    ) -> Option<bool> {
+        let mut contents = Vec::new();
+        File::open(self.get_path(tracepoint, request_type))
+            .and_then(|mut f| f.read_to_end(&mut contents))
+            .ok()?;
+        Some(contents.first() == Some(&b'1'))
+    }
+
     fn write_dir(&self, dir: &Path, to_write: &[u8; 1]) {
         for f in read_dir(dir).unwrap() {
             let path = f.unwrap().path();
@@ -78,7 +192,7 @@ impl OSProfilerController {
             Ok(mut f) => {
                 f.write_all(to_write).unwrap();
             }
-            Err(e) => eprintln!("Problem creating file {:?}: {}", path, e),
+            Err(e) => error!("Problem creating file {:?}: {}", path, e),
         }
     }
 
@@ -103,3 +217,54 @@ impl OSProfilerController {
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_back_settings() {
+        let root = std::env::temp_dir().join(format!("pythia-manifest-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("nova")).unwrap();
+        let controller = OSProfilerController {
+            manifest_root: root.clone(),
+        };
+        let create = RequestType::from_str("ServerCreate").unwrap();
+        let delete = RequestType::from_str("ServerDelete").unwrap();
+        controller.apply_settings(vec![
+            ("/nova/api.py:10:create".to_string(), None, *b"0"),
+            ("/nova/api.py:10:create".to_string(), Some(create), *b"1"),
+            ("nova/compute.py:20".to_string(), None, *b"1"),
+        ]);
+        let mut settings = controller.read_all_settings();
+        settings.sort_by(|a, b| (&a.0, a.1.is_some()).cmp(&(&b.0, b.1.is_some())));
+        assert_eq!(
+            settings,
+            vec![
+                ("nova/api.py:10:create".to_string(), None, false),
+                ("nova/api.py:10:create".to_string(), Some(create), true),
+                ("nova/compute.py:20".to_string(), None, true),
+            ]
+        );
+        assert_eq!(
+            controller.read_settings(&[
+                ("/nova/api.py:10:create".to_string(), Some(create)),
+                ("/nova/api.py:10:create".to_string(), Some(delete)),
+                ("/nova/api.py:10:create".to_string(), None),
+                ("/nova/missing.py:1".to_string(), None),
+            ]),
+            vec![Some(true), Some(false), Some(true), None]
+        );
+
+        let snapshot = controller.read_all_settings();
+        controller.apply_settings(vec![
+            ("/nova/api.py:10:create".to_string(), Some(delete), *b"1"),
+            ("nova/compute.py:20".to_string(), None, *b"0"),
+        ]);
+        controller.load_settings(&snapshot);
+        let mut restored = controller.read_all_settings();
+        restored.sort_by(|a, b| (&a.0, a.1.is_some()).cmp(&(&b.0, b.1.is_some())));
+        assert_eq!(restored, settings);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
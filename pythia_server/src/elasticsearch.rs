@@ -0,0 +1,159 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Spans written by OSProfiler's Elasticsearch driver.
+//!
+//! The driver indexes every span as a document of the `osprofiler-notifications` index, with the
+//! same fields as the json the redis driver writes. Its connection string looks like
+//! `elasticsearch://host:9200`, which is queried over plain HTTP.
+
+use std::any::Any;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log::{error, warn};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use pythia_common::osprofiler;
+use pythia_common::OSProfilerSpan;
+
+use crate::store::{base_ids, SpanStore};
+
+const INDEX: &str = "osprofiler-notifications";
+/// Hits read at a time; Elasticsearch doesn't return more than 10000 by default
+const PAGE_SIZE: usize = 1000;
+
+pub struct ElasticsearchStore {
+    /// e.g., `http://host:9200`
+    url: String,
+    /// Whether the index was found to have more than one shard
+    sharded: AtomicBool,
+}
+
+impl ElasticsearchStore {
+    pub fn open(url: &str) -> ElasticsearchStore {
+        ElasticsearchStore {
+            url: url.replacen("elasticsearch://", "http://", 1),
+            sharded: AtomicBool::new(false),
+        }
+    }
+
+    fn post(&self, path: &str, body: Value) -> Result<Value, String> {
+        ureq::post(&format!("{}/{}/{}", self.url, INDEX, path))
+            .send_json(body)
+            .map_err(|e| e.to_string())?
+            .into_json()
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// One page of the spans of a request, in the order they were indexed, after the sort values
+/// of the last hit of the previous page
+fn page_query(id: &str, after: Option<&Value>) -> Value {
+    let mut body = json!({
+        "query": base_id_query(&[id.to_string()]),
+        "size": PAGE_SIZE,
+        "sort": [{ "_seq_no": "asc" }],
+    });
+    if let Some(after) = after {
+        body["search_after"] = after.clone();
+    }
+    body
+}
+
+/// Matches the documents of the requests; base ids are analyzed text, so they are matched as
+/// phrases
+fn base_id_query(ids: &[String]) -> Value {
+    let phrases: Vec<Value> = ids
+        .iter()
+        .map(|id| json!({ "match_phrase": { "base_id": id } }))
+        .collect();
+    json!({ "bool": { "should": phrases, "minimum_should_match": 1 } })
+}
+
+impl SpanStore for ElasticsearchStore {
+    /// In the order they were indexed, a page at a time. Sequence numbers only order the
+    /// documents of one shard, so the index should have one (it does unless it was created
+    /// otherwise). On an error, the spans read so far are returned; they are still the first
+    /// ones.
+    fn get_matches(&self, base_id: &Uuid) -> Vec<OSProfilerSpan> {
+        let id = base_id.to_hyphenated().to_string();
+        let mut result = Vec::new();
+        let mut after: Option<Value> = None;
+        loop {
+            let response = match self.post("_search", page_query(&id, after.as_ref())) {
+                Ok(response) => response,
+                Err(e) => {
+                    warn!(trace_id:% = id; "Elasticsearch error: {}", e);
+                    return result;
+                }
+            };
+            if response["_shards"]["total"].as_u64().unwrap_or(1) > 1
+                && !self.sharded.swap(true, Ordering::Relaxed)
+            {
+                error!(
+                    "{} has more than one shard, spans may be returned out of order",
+                    INDEX
+                );
+            }
+            let hits = response["hits"]["hits"]
+                .as_array()
+                .cloned()
+                .unwrap_or_default();
+            for hit in &hits {
+                match osprofiler::parse_field(&hit["_source"].to_string()) {
+                    Ok(span) => result.push(span),
+                    Err(e) => warn!(trace_id:% = id; "Skipping span: {}", e),
+                }
+            }
+            match hits.last() {
+                Some(last) if hits.len() == PAGE_SIZE => after = Some(last["sort"].clone()),
+                _ => return result,
+            }
+        }
+    }
+
+    fn free_keys(&self, keys: Vec<String>) {
+        let body = json!({ "query": base_id_query(&base_ids(&keys)) });
+        if let Err(e) = self.post("_delete_by_query", body) {
+            error!("Could not free keys: {}", e);
+        }
+    }
+
+    fn ping(&self) -> Result<(), String> {
+        ureq::get(&self.url)
+            .call()
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_any_of_the_base_ids() {
+        let query = base_id_query(&["a-b".to_string(), "c-d".to_string()]);
+        assert_eq!(query["bool"]["should"][1]["match_phrase"]["base_id"], "c-d");
+        assert_eq!(query["bool"]["minimum_should_match"], 1);
+        let first = page_query("a-b", None);
+        assert_eq!(first["sort"][0]["_seq_no"], "asc");
+        assert!(first.get("search_after").is_none());
+        let next = page_query("a-b", Some(&json!([41])));
+        assert_eq!(next["search_after"], json!([41]));
+        assert_eq!(
+            ElasticsearchStore::open("elasticsearch://localhost:9200").url,
+            "http://localhost:9200"
+        );
+    }
+}
@@ -0,0 +1,78 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Checks behind the `health` RPC: whether the agent can do its job, not just whether it runs.
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use pythia_common::AgentHealth;
+
+use crate::controller::OSProfilerController;
+use crate::osprofiler::OSProfilerReader;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Written and removed to check that the tracepoint configuration can be changed
+const PROBE_FILE: &str = ".pythia_health";
+
+pub fn check_health(reader: &OSProfilerReader, controller: &OSProfilerController) -> AgentHealth {
+    let manifest_root = controller.manifest_root();
+    AgentHealth {
+        host: reader.host().to_string(),
+        version: VERSION.to_string(),
+        redis_error: reader.ping().err(),
+        manifest_error: check_writable(manifest_root).err(),
+        free_disk_bytes: free_disk_bytes(manifest_root),
+    }
+}
+
+fn check_writable(dir: &Path) -> Result<(), String> {
+    if !dir.is_dir() {
+        return Err(format!("{} is not a directory", dir.display()));
+    }
+    let probe = dir.join(PROBE_FILE);
+    fs::write(&probe, b"1").map_err(|e| format!("cannot write to {}: {}", dir.display(), e))?;
+    fs::remove_file(&probe).ok();
+    Ok(())
+}
+
+/// Available space on the file system of `path`, as reported by `df`
+fn free_disk_bytes(path: &Path) -> Option<u64> {
+    let output = Command::new("df").arg("-Pk").arg(path).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    parse_df(&String::from_utf8_lossy(&output.stdout))
+}
+
+fn parse_df(output: &str) -> Option<u64> {
+    // Filesystem 1024-blocks Used Available Capacity Mounted-on
+    let kilobytes: u64 = output
+        .lines()
+        .nth(1)?
+        .split_whitespace()
+        .nth(3)?
+        .parse()
+        .ok()?;
+    Some(kilobytes * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_df() {
+        let output = "Filesystem     1024-blocks     Used Available Capacity Mounted on\n\
+                      /dev/sda1         61255492 31524188  26589308      55% /\n";
+        assert_eq!(parse_df(output), Some(26589308 * 1024));
+        assert_eq!(parse_df("Filesystem\n"), None);
+    }
+}
@@ -11,33 +11,77 @@ All rights reserved.
 //! # Running the agent
 //! If using systemd, copy the files to `/etc/` and then `sudo systemctl start pythia`.
 //! Otherwise, just run the binary. Remember the port/address in the configuration so that
-//! the main Pythia would know where the agents are running.
+//! the main Pythia would know where the agents are running. Logs go to stderr (the journal
+//! under systemd); `PYTHIA_LOG` and `PYTHIA_LOG_FORMAT` control them, see
+//! `pythia_common::init_logging`.
 //!
 //! # Things related with the code
-//! The RPC commands it provides are inside `PythiaAPI`. There is some
-//! internal state of the server, which means for many requests it can
-//! only process them one at a time. This is an implementation limitation
-//! (state is encapsulated in `Arc<Mutex<>>`, even though some state never
-//! changes after init) and someone who knows more Rust can probably solve it.
+//! The RPC commands it provides are inside `PythiaAPI`. Requests are served by several threads.
+//! The reader and controller are shared without locks (redis connections come from a pool), the
+//! tracepoint settings are behind an `RwLock` so that they are applied one batch at a time, and
+//! only the node stats, which keep the previous measurement, are behind a `Mutex`.
+//!
+//! Spans are read from redis, or from MongoDB or Elasticsearch if OSProfiler writes them there,
+//! see `store`.
+//!
+//! With `retention_max_age_secs` or `retention_max_memory_bytes` set, another background thread
+//! deletes the osprofiler keys the controller never freed, see `retention`.
+//!
+//! With the `kafka` feature and `kafka_brokers` set, a background thread also publishes completed
+//! span batches to Kafka, so the controller doesn't have to poll every agent for spans.
+//!
+//! With the `messaging` feature and `messaging_url` set, another one writes the spans OSProfiler
+//! sends over oslo.messaging to redis, see `messaging`.
+//!
+//! On SIGTERM or SIGINT the agent stops taking requests, lets the publisher flush what it has,
+//! saves the tracepoint state and closes its redis connections before exiting. A second signal
+//! exits right away.
 
 pub mod budget;
 pub mod controller;
+#[cfg(feature = "elasticsearch")]
+pub mod elasticsearch;
+pub mod health;
+#[cfg(feature = "messaging")]
+pub mod messaging;
+#[cfg(feature = "mongo")]
+pub mod mongo;
 pub mod osprofiler;
+#[cfg(feature = "kafka")]
+pub mod publisher;
+pub mod retention;
 pub mod settings;
+pub mod state;
+pub mod store;
 
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
 
-use jsonrpc_core::{IoHandler, Result, Value};
+use jsonrpc_core::{Error, IoHandler, Result, Value};
 use jsonrpc_derive::rpc;
 use jsonrpc_http_server::ServerBuilder;
+use log::{debug, info, warn, LevelFilter};
 use serde_json;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use uuid::Uuid;
 
+use pythia_common::init_logging;
+use pythia_common::AgentHealth;
+use pythia_common::AgentState;
 use pythia_common::RequestType;
+use pythia_common::SpanBatch;
+use pythia_common::SpanCount;
 
 use crate::budget::NodeStatReader;
 use crate::controller::OSProfilerController;
+use crate::health::check_health;
+use crate::health::VERSION;
 use crate::osprofiler::OSProfilerReader;
+use crate::retention::{RetentionCollector, RetentionPolicy, RetentionStats};
 use crate::settings::Settings;
+use crate::state::StateStore;
 
 #[rpc(server)]
 pub trait PythiaAPI {
@@ -45,6 +89,21 @@ pub trait PythiaAPI {
     #[rpc(name = "get_events")]
     fn get_events(&self, trace_id: String) -> Result<Value>;
 
+    /// Like `get_events`, but skips the first `skip` events, which the caller already has.
+    /// OSProfiler only appends events of a trace, so they are always in the same order.
+    #[rpc(name = "get_events_after")]
+    fn get_events_after(&self, trace_id: String, skip: usize) -> Result<Value>;
+
+    /// Events of the trace recorded on this host as a `SpanBatch`, sorted by timestamp, so the
+    /// controller only has to merge the fragments of different hosts
+    #[rpc(name = "get_trace_fragment")]
+    fn get_trace_fragment(&self, trace_id: String) -> Result<SpanBatch>;
+
+    /// How many spans of the trace this host has and whether its root span ended here, so the
+    /// controller can tell when the request is done without fetching the spans each time
+    #[rpc(name = "get_span_count")]
+    fn get_span_count(&self, trace_id: String) -> Result<SpanCount>;
+
     /// Apply tracepoint configuration locally.
     ///
     /// The configuration is tuples of tracepoint ID, `Option<RequestType>` (`None`
@@ -53,6 +112,28 @@ pub trait PythiaAPI {
     #[rpc(name = "set_tracepoints")]
     fn set_tracepoints(&self, settings: Vec<(String, Option<RequestType>, [u8; 1])>) -> Result<()>;
 
+    /// Current setting of each tracepoint on this host, from the tracepoint files: `None` if the
+    /// tracepoint is not on this host. Without a request type, whether it is on for any.
+    #[rpc(name = "read_tracepoints")]
+    fn read_tracepoints(
+        &self,
+        points: Vec<(String, Option<RequestType>)>,
+    ) -> Result<Vec<Option<bool>>>;
+
+    /// Setting of every tracepoint on this host: the tracepoint without a leading `/`, the
+    /// request type the setting is for (`None` for all), and whether it is on
+    #[rpc(name = "get_tracepoint_state")]
+    fn get_tracepoint_state(&self) -> Result<Vec<(String, Option<RequestType>, bool)>>;
+
+    /// Every tracepoint setting of this host, to restore it later with `load_state`
+    #[rpc(name = "dump_state")]
+    fn dump_state(&self) -> Result<AgentState>;
+
+    /// Make the tracepoint settings of this host exactly those of `state`, from `dump_state`:
+    /// tracepoints not in it are turned off and request type settings not in it are dropped
+    #[rpc(name = "load_state")]
+    fn load_state(&self, state: AgentState) -> Result<()>;
+
     /// Change setting for all local tracepoints. `to_write` decides whether to disable (0) or
     /// enable (1) all tracepoints.
     #[rpc(name = "set_all_tracepoints")]
@@ -66,79 +147,276 @@ pub trait PythiaAPI {
     /// Delete these keys from redis. Used to free up memory, but deleted any records of traces.
     #[rpc(name = "free_keys")]
     fn free_keys(&self, keys: Vec<String>) -> Result<()>;
+
+    /// Returns "pong"; to check that the agent is up
+    #[rpc(name = "ping")]
+    fn ping(&self) -> Result<String>;
+
+    /// Whether redis is reachable, the tracepoint configuration is writable and there is disk
+    /// space left
+    #[rpc(name = "health")]
+    fn health(&self) -> Result<AgentHealth>;
+
+    /// Version of the agent
+    #[rpc(name = "version")]
+    fn version(&self) -> Result<String>;
 }
 
 struct PythiaAPIImpl {
-    reader: Arc<Mutex<OSProfilerReader>>,
-    controller: Arc<Mutex<OSProfilerController>>,
-    stats: Arc<Mutex<NodeStatReader>>,
+    reader: OSProfilerReader,
+    controller: OSProfilerController,
+    stats: Mutex<NodeStatReader>,
+    state: Arc<RwLock<StateStore>>,
+}
+
+/// The trace id sent by the controller; a malformed one is the caller's error, not a reason to
+/// bring the agent down
+fn parse_trace_id(trace_id: &str) -> Result<Uuid> {
+    Uuid::parse_str(trace_id).map_err(|e| Error::invalid_params(e.to_string()))
 }
 
 impl PythiaAPI for PythiaAPIImpl {
     fn get_events(&self, trace_id: String) -> Result<Value> {
-        eprintln!("Got request for {}", trace_id);
-        Ok(serde_json::to_value(self.reader.lock().unwrap().get_matches(&trace_id)).unwrap())
+        debug!(trace_id:% = trace_id; "Got request for events");
+        Ok(serde_json::to_value(self.reader.get_matches(&trace_id)).unwrap())
+    }
+
+    fn get_events_after(&self, trace_id: String, skip: usize) -> Result<Value> {
+        debug!(trace_id:% = trace_id; "Got request for events after {}", skip);
+        let events: Vec<_> = self
+            .reader
+            .get_matches(&trace_id)
+            .into_iter()
+            .skip(skip)
+            .collect();
+        Ok(serde_json::to_value(events).unwrap())
+    }
+
+    fn get_trace_fragment(&self, trace_id: String) -> Result<SpanBatch> {
+        debug!(trace_id:% = trace_id; "Got fragment request");
+        Ok(self.reader.get_fragment(&parse_trace_id(&trace_id)?))
+    }
+
+    fn get_span_count(&self, trace_id: String) -> Result<SpanCount> {
+        debug!(trace_id:% = trace_id; "Got span count request");
+        Ok(self.reader.get_span_count(&parse_trace_id(&trace_id)?))
     }
 
     fn set_tracepoints(&self, settings: Vec<(String, Option<RequestType>, [u8; 1])>) -> Result<()> {
-        eprintln!("Setting {} tracepoints", settings.len());
-        self.controller.lock().unwrap().apply_settings(settings);
+        info!("Setting {} tracepoints", settings.len());
+        let mut state = self.state.write().unwrap();
+        state.record(&settings, "set_tracepoints");
+        self.controller.apply_settings(settings);
+        Ok(())
+    }
+
+    fn read_tracepoints(
+        &self,
+        points: Vec<(String, Option<RequestType>)>,
+    ) -> Result<Vec<Option<bool>>> {
+        // Not in the middle of a batch
+        let _state = self.state.read().unwrap();
+        Ok(self.controller.read_settings(&points))
+    }
+
+    fn get_tracepoint_state(&self) -> Result<Vec<(String, Option<RequestType>, bool)>> {
+        let _state = self.state.read().unwrap();
+        Ok(self.controller.read_all_settings())
+    }
+
+    fn dump_state(&self) -> Result<AgentState> {
+        let _state = self.state.read().unwrap();
+        let mut tracepoints = self.controller.read_all_settings();
+        tracepoints.sort_by_key(|(tracepoint, request_type, _)| {
+            (tracepoint.clone(), request_type.map(|t| t.to_string()))
+        });
+        Ok(AgentState {
+            host: self.reader.host().to_string(),
+            tracepoints,
+        })
+    }
+
+    fn load_state(&self, state: AgentState) -> Result<()> {
+        info!(
+            "Loading {} tracepoint settings saved on {}",
+            state.tracepoints.len(),
+            state.host
+        );
+        let mut store = self.state.write().unwrap();
+        self.controller.load_settings(&state.tracepoints);
+        // Replaying this after a restart gives the same files: everything off, then the state
+        store.record_all(b"0", "load_state");
+        let settings = state
+            .tracepoints
+            .into_iter()
+            .map(|(tracepoint, request_type, on)| {
+                (tracepoint, request_type, if on { *b"1" } else { *b"0" })
+            })
+            .collect();
+        store.record(&settings, "load_state");
         Ok(())
     }
 
     fn set_all_tracepoints(&self, to_write: [u8; 1]) -> Result<()> {
-        eprintln!("Setting all tracepoints to {:?}", to_write);
-        self.controller.lock().unwrap().write_client_dir(&to_write);
+        info!("Setting all tracepoints to {:?}", to_write);
+        let mut state = self.state.write().unwrap();
+        self.controller.write_client_dir(&to_write);
+        state.record_all(&to_write, "set_all_tracepoints");
         Ok(())
     }
 
     fn read_node_stats(&self) -> Result<Value> {
-        eprintln!("Measuring node stats -- MERT");
+        debug!("Measuring node stats");
         Ok(serde_json::to_value(
             self.stats
                 .lock()
                 .unwrap()
-                .read_node_stats(&mut self.reader.lock().unwrap())
+                .read_node_stats(&self.reader)
                 .unwrap(),
         )
         .unwrap())
     }
 
     fn free_keys(&self, keys: Vec<String>) -> Result<()> {
-        eprintln!("Freeing keys {:?}", keys);
-        self.reader.lock().unwrap().free_keys(keys);
+        debug!("Freeing keys {:?}", keys);
+        self.reader.free_keys(keys);
         Ok(())
     }
+
+    fn ping(&self) -> Result<String> {
+        Ok("pong".to_string())
+    }
+
+    fn health(&self) -> Result<AgentHealth> {
+        Ok(check_health(&self.reader, &self.controller))
+    }
+
+    fn version(&self) -> Result<String> {
+        Ok(VERSION.to_string())
+    }
+}
+
+#[cfg(feature = "kafka")]
+fn start_publisher(
+    settings: &Settings,
+    shutdown: Arc<AtomicBool>,
+) -> Option<thread::JoinHandle<()>> {
+    let brokers = settings.kafka_brokers.as_ref()?;
+    let mut publisher = publisher::SpanPublisher::from_settings(settings, brokers);
+    Some(thread::spawn(move || publisher.run(&shutdown)))
+}
+
+#[cfg(not(feature = "kafka"))]
+fn start_publisher(
+    settings: &Settings,
+    _shutdown: Arc<AtomicBool>,
+) -> Option<thread::JoinHandle<()>> {
+    if settings.kafka_brokers.is_some() {
+        warn!("kafka_brokers is set, but the agent was built without the kafka feature");
+    }
+    None
+}
+
+#[cfg(feature = "messaging")]
+fn start_consumer(
+    settings: &Settings,
+    shutdown: Arc<AtomicBool>,
+) -> Option<thread::JoinHandle<()>> {
+    let url = settings.messaging_url.as_ref()?;
+    let consumer = messaging::NotificationConsumer::from_settings(settings, url);
+    Some(thread::spawn(move || consumer.run(&shutdown)))
+}
+
+#[cfg(not(feature = "messaging"))]
+fn start_consumer(
+    settings: &Settings,
+    _shutdown: Arc<AtomicBool>,
+) -> Option<thread::JoinHandle<()>> {
+    if settings.messaging_url.is_some() {
+        warn!("messaging_url is set, but the agent was built without the messaging feature");
+    }
+    None
+}
+
+fn start_retention(
+    settings: &Settings,
+    stats: Arc<RetentionStats>,
+    shutdown: Arc<AtomicBool>,
+) -> Option<thread::JoinHandle<()>> {
+    let policy = RetentionPolicy::from_settings(settings)?;
+    if settings.span_store_url.is_some() {
+        warn!("Retention only applies to spans in redis, see span_store_url");
+    }
+    let collector = RetentionCollector::new(settings, policy, stats);
+    Some(thread::spawn(move || collector.run(&shutdown)))
 }
 
 /// Starts the server in port specified at the config file and waits for requests.
 ///
 /// Needs root access.
 pub fn run_pythia_server() {
-    eprintln!("Did you remember to run as root?");
+    init_logging(LevelFilter::Info);
+    warn!("Did you remember to run as root?");
     let settings = Settings::read();
-    let reader = Arc::new(Mutex::new(OSProfilerReader::from_settings(&settings)));
-    let controller = Arc::new(Mutex::new(OSProfilerController::from_settings(&settings)));
-    let stats = Arc::new(Mutex::new(NodeStatReader::from_settings(
+    let reader = OSProfilerReader::from_settings(&settings);
+    let controller = OSProfilerController::from_settings(&settings);
+    let state = StateStore::open(&settings.state_file);
+    state.restore(&controller);
+    let state = Arc::new(RwLock::new(state));
+    let retention = Arc::new(RetentionStats::default());
+    let stats = Mutex::new(NodeStatReader::from_settings(
         &settings,
-        &mut reader.lock().unwrap(),
-    )));
+        &reader,
+        retention.clone(),
+    ));
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let publisher = start_publisher(&settings, shutdown.clone());
+    let consumer = start_consumer(&settings, shutdown.clone());
+    let collector = start_retention(&settings, retention, shutdown.clone());
     let mut io = IoHandler::new();
     io.extend_with(
         PythiaAPIImpl {
             reader,
             controller,
             stats,
+            state: state.clone(),
         }
         .to_delegate(),
     );
 
     let address = settings.server_address;
-    println!("Starting the server at {}", address);
+    info!("Starting the server at {}", address);
 
-    let _server = ServerBuilder::new(io)
+    let server = ServerBuilder::new(io)
+        .threads(settings.server_threads)
         .start_http(&address.parse().unwrap())
         .expect("Unable to start RPC server");
 
-    _server.wait();
+    let close_handle = server.close_handle();
+    let mut signals = Signals::new([SIGTERM, SIGINT]).expect("Could not handle SIGTERM and SIGINT");
+    thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            info!("Got signal {}, shutting down", signal);
+            shutdown.store(true, Ordering::Relaxed);
+            close_handle.close();
+        }
+        // Only the first signal is handled gracefully
+        if signals.forever().next().is_some() {
+            std::process::exit(1);
+        }
+    });
+
+    // Returns once the server is closed, dropping the API and with it the redis pool
+    server.wait();
+    if let Some(publisher) = publisher {
+        publisher.join().ok();
+    }
+    if let Some(consumer) = consumer {
+        consumer.join().ok();
+    }
+    if let Some(collector) = collector {
+        collector.join().ok();
+    }
+    state.read().unwrap().save();
+    info!("Stopped");
 }
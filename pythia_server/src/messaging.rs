@@ -0,0 +1,218 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Spans that OSProfiler sends over oslo.messaging.
+//!
+//! OSProfiler's `messaging://` driver sends every span as a notification on the `profiler` topic
+//! (the `profiler.info` queue of the control exchange) instead of writing it to a database. With
+//! `messaging_url` set to the deployment's `transport_url`, the agent consumes those
+//! notifications and appends each span to its store the way the redis driver would, so the
+//! controller reads them like any other spans. Agents sharing the bus split the notifications
+//! between them, which is fine since the controller asks every agent for the spans of a trace.
+//!
+//! Spans can only be appended to redis, so the agent doesn't start with another `span_store_url`.
+//! A span that can't be stored is handed back to the bus, to be delivered again.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use futures_lite::future::block_on;
+use lapin::message::DeliveryResult;
+use lapin::options::{
+    BasicAckOptions, BasicConsumeOptions, BasicNackOptions, ExchangeDeclareOptions,
+    QueueBindOptions, QueueDeclareOptions,
+};
+use lapin::types::FieldTable;
+use lapin::{Connection, ConnectionProperties, ExchangeKind};
+use log::{error, info, warn};
+use serde_json::Value;
+use uuid::Uuid;
+
+use pythia_common::osprofiler;
+
+use crate::osprofiler::RedisStore;
+use crate::settings::Settings;
+use crate::store::{open_store, SpanStore};
+
+/// How often the consumer checks whether the agent is shutting down
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_millis(200);
+/// How long to wait before handing back a span that couldn't be stored, so a store that is down
+/// isn't retried in a tight loop
+const REQUEUE_DELAY: Duration = Duration::from_secs(1);
+
+pub struct NotificationConsumer {
+    store: Arc<dyn SpanStore>,
+    url: String,
+    exchange: String,
+    /// Notifications are consumed from `<topic>.info`
+    topic: String,
+}
+
+impl NotificationConsumer {
+    pub fn from_settings(settings: &Settings, url: &str) -> NotificationConsumer {
+        let store = open_store(settings);
+        if store.as_any().downcast_ref::<RedisStore>().is_none() {
+            panic!(
+                "messaging_url is set, but spans can only be added to redis, not {}",
+                settings.span_store_url.as_deref().unwrap_or_default()
+            );
+        }
+        NotificationConsumer {
+            store: Arc::from(store),
+            url: url.to_string(),
+            exchange: settings.messaging_exchange.clone(),
+            topic: settings.messaging_topic.clone(),
+        }
+    }
+
+    /// Consumes until `shutdown` is set. Spans are written by lapin's threads as they arrive.
+    pub fn run(&self, shutdown: &AtomicBool) {
+        let connection = match block_on(self.subscribe()) {
+            Ok(connection) => connection,
+            Err(e) => {
+                error!("Could not consume notifications from {}: {}", self.url, e);
+                return;
+            }
+        };
+        info!(
+            "Consuming spans from {}.info on exchange {}",
+            self.topic, self.exchange
+        );
+        while !shutdown.load(Ordering::Relaxed) {
+            thread::sleep(SHUTDOWN_CHECK_INTERVAL);
+        }
+        if let Err(e) = block_on(connection.close(200, "Agent shutting down")) {
+            warn!("Could not close the connection to {}: {}", self.url, e);
+        }
+    }
+
+    /// Declares the queue oslo.messaging sends the notifications to, in case the agent starts
+    /// before any service does, and consumes from it
+    async fn subscribe(&self) -> lapin::Result<Connection> {
+        let connection = Connection::connect(&self.url, ConnectionProperties::default()).await?;
+        let channel = connection.create_channel().await?;
+        let queue = format!("{}.info", self.topic);
+        // Not durable, like oslo.messaging's defaults, or declaring them again would fail
+        channel
+            .exchange_declare(
+                &self.exchange,
+                ExchangeKind::Topic,
+                ExchangeDeclareOptions::default(),
+                FieldTable::default(),
+            )
+            .await?;
+        channel
+            .queue_declare(
+                &queue,
+                QueueDeclareOptions::default(),
+                FieldTable::default(),
+            )
+            .await?;
+        channel
+            .queue_bind(
+                &queue,
+                &self.exchange,
+                &queue,
+                QueueBindOptions::default(),
+                FieldTable::default(),
+            )
+            .await?;
+        let consumer = channel
+            .basic_consume(
+                &queue,
+                "pythia",
+                BasicConsumeOptions::default(),
+                FieldTable::default(),
+            )
+            .await?;
+        let store = self.store.clone();
+        consumer.set_delegate(move |delivery: DeliveryResult| {
+            let store = store.clone();
+            async move {
+                let delivery = match delivery {
+                    Ok(Some(delivery)) => delivery,
+                    Ok(None) => return,
+                    Err(e) => {
+                        error!("Could not consume notifications: {}", e);
+                        return;
+                    }
+                };
+                let stored = match parse_notification(&delivery.data) {
+                    Ok((base_id, span)) => match store.append(&base_id, &span) {
+                        Ok(()) => true,
+                        Err(e) => {
+                            error!(trace_id:% = base_id; "Could not store span: {}", e);
+                            false
+                        }
+                    },
+                    Err(e) => {
+                        warn!("Skipping notification: {}", e);
+                        true
+                    }
+                };
+                let answer = if stored {
+                    delivery.ack(BasicAckOptions::default()).await
+                } else {
+                    thread::sleep(REQUEUE_DELAY);
+                    let requeue = BasicNackOptions {
+                        requeue: true,
+                        ..BasicNackOptions::default()
+                    };
+                    delivery.nack(requeue).await
+                };
+                if let Err(e) = answer {
+                    warn!("Could not acknowledge notification: {}", e);
+                }
+            }
+        });
+        Ok(connection)
+    }
+}
+
+/// The base id and json of the span in a notification. oslo.messaging wraps the notification
+/// in an `oslo.message` string, and the span is its payload.
+pub fn parse_notification(body: &[u8]) -> Result<(Uuid, String), String> {
+    let mut message: Value = serde_json::from_slice(body).map_err(|e| e.to_string())?;
+    if let Some(inner) = message.get("oslo.message").and_then(|m| m.as_str()) {
+        message = serde_json::from_str(inner).map_err(|e| e.to_string())?;
+    }
+    let payload = match message.get("payload") {
+        Some(payload) => payload.to_string(),
+        None => return Err("no payload".to_string()),
+    };
+    let span = osprofiler::parse_field(&payload).map_err(|e| e.to_string())?;
+    Ok((span.base_id, payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unwraps_oslo_messages() {
+        let span = r#"{"trace_id": "936da01f-9abd-4d9d-80c7-02af85c822a8", "parent_id": "7d3a8d1e-4e3c-4a7b-9c2d-1f0e6b5a4c3d", "project": "nova", "name": "build_instance-stop", "base_id": "7d3a8d1e-4e3c-4a7b-9c2d-1f0e6b5a4c3d", "service": "api", "tracepoint_id": "nova/manager.py", "timestamp": "2022-03-01T10:00:00.010000", "info": {"host": "cp-1"}}"#;
+        let notification = format!(
+            r#"{{"event_type": "profiler.api", "priority": "INFO", "payload": {}}}"#,
+            span
+        );
+        let envelope = serde_json::json!({
+            "oslo.version": "2.0",
+            "oslo.message": notification,
+        });
+        let (base_id, json) = parse_notification(envelope.to_string().as_bytes()).unwrap();
+        assert_eq!(base_id.to_string(), "7d3a8d1e-4e3c-4a7b-9c2d-1f0e6b5a4c3d");
+        assert!(osprofiler::parse_field(&json).is_ok());
+        assert_eq!(
+            parse_notification(notification.as_bytes()).unwrap(),
+            (base_id, json)
+        );
+        assert!(parse_notification(br#"{"event_type": "compute.instance.create"}"#).is_err());
+    }
+}
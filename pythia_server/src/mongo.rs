@@ -0,0 +1,122 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Spans written by OSProfiler's MongoDB driver.
+//!
+//! The driver inserts every span as a document of the `profiler` collection, in the database
+//! named in the connection string (`osprofiler` if there is none). The documents have the same
+//! fields as the json the redis driver writes.
+
+use log::{error, warn};
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::options::FindOptions;
+use mongodb::sync::{Client, Collection, Database};
+use uuid::Uuid;
+
+use std::any::Any;
+use std::convert::TryFrom;
+
+use pythia_common::osprofiler;
+use pythia_common::OSProfilerSpan;
+
+use crate::store::{base_ids, SpanStore};
+
+const DATABASE: &str = "osprofiler";
+const COLLECTION: &str = "profiler";
+
+pub struct MongoStore {
+    db: Database,
+    spans: Collection<Document>,
+}
+
+impl MongoStore {
+    pub fn open(url: &str) -> MongoStore {
+        let client = Client::with_uri_str(url)
+            .unwrap_or_else(|e| panic!("Could not connect to MongoDB at {}: {}", url, e));
+        let db = client
+            .default_database()
+            .unwrap_or_else(|| client.database(DATABASE));
+        MongoStore {
+            spans: db.collection(COLLECTION),
+            db,
+        }
+    }
+}
+
+impl SpanStore for MongoStore {
+    fn get_matches(&self, base_id: &Uuid) -> Vec<OSProfilerSpan> {
+        let id = base_id.to_hyphenated().to_string();
+        // In the order they were inserted
+        let options = FindOptions::builder()
+            .projection(doc! { "_id": 0 })
+            .sort(doc! { "$natural": 1 })
+            .build();
+        let cursor = match self.spans.find(doc! { "base_id": &id }, options) {
+            Ok(cursor) => cursor,
+            Err(e) => {
+                warn!(trace_id:% = id; "MongoDB error: {}", e);
+                return Vec::new();
+            }
+        };
+        let mut result = Vec::new();
+        for document in cursor {
+            let parsed = document.map_err(|e| e.to_string()).and_then(|d| {
+                osprofiler::parse_field(&Bson::Document(d).into_relaxed_extjson().to_string())
+                    .map_err(|e| e.to_string())
+            });
+            match parsed {
+                Ok(span) => result.push(span),
+                Err(e) => warn!(trace_id:% = id; "Skipping span: {}", e),
+            }
+        }
+        result
+    }
+
+    fn free_keys(&self, keys: Vec<String>) {
+        let query = doc! { "base_id": { "$in": base_ids(&keys) } };
+        if let Err(e) = self.spans.delete_many(query, None) {
+            error!("Could not free keys: {}", e);
+        }
+    }
+
+    fn ping(&self) -> Result<(), String> {
+        self.db
+            .run_command(doc! { "ping": 1 }, None)
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    /// Only the size of the spans; MongoDB doesn't tell the input rate of a collection. Sizes
+    /// that don't fit are reported as the largest size, so they are still over any limit.
+    fn get_stats(&self) -> (f32, u32) {
+        let size = self
+            .db
+            .run_command(doc! { "collStats": COLLECTION }, None)
+            .ok()
+            .and_then(|stats| match stats.get("size") {
+                Some(Bson::Int32(s)) => Some(*s as i64),
+                Some(Bson::Int64(s)) => Some(*s),
+                Some(Bson::Double(s)) => Some(*s as i64),
+                _ => None,
+            })
+            .unwrap_or(0);
+        let size = u32::try_from(size).unwrap_or_else(|_| {
+            error!(
+                "The spans take {} bytes, more than the {} the node stats can tell",
+                size,
+                u32::MAX
+            );
+            u32::MAX
+        });
+        (0.0, size)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
@@ -8,99 +8,448 @@ All rights reserved.
 
 //! Stuff related to reading data from osprofiler
 //!
+use std::any::Any;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use log::{error, warn};
 use redis::Commands;
-use redis::Connection;
 use redis::FromRedisValue;
 use redis::Value;
 use uuid::Uuid;
 
 //use pythia_common::OSProfilerEnum;
 use pythia_common::osprofiler;
+use pythia_common::osprofiler::span_order;
+use pythia_common::sort_spans;
 use pythia_common::OSProfilerSpan;
+use pythia_common::RedisShardStats;
+use pythia_common::SpanBatch;
+use pythia_common::SpanCount;
 //mod pythia_common::osprofiler;
 use crate::settings::Settings;
+use crate::store::{open_store, SpanStore};
 
+/// Reads spans from the local span store. Stores can be shared between concurrent requests
+/// without locking (e.g., redis connections come from a pool per instance).
 pub struct OSProfilerReader {
-    redis_url: String,
-    connection: Connection,
+    store: Box<dyn SpanStore>,
+    /// Name of this host, reported with the spans
+    host: String,
+}
+
+/// Requests whose span order is remembered at most, the oldest are forgotten first
+const ORDERED_REQUESTS: usize = 10000;
+
+/// The redis instances OSProfiler's redis driver writes spans to
+pub struct RedisStore {
+    shards: Vec<RedisShard>,
+    /// With more than one instance, the order the spans of each request were returned in
+    order: Mutex<SpanOrder>,
+}
+
+/// The instance of each span of a request, in the order the spans were returned. Spans are
+/// returned in the same order every time, with new spans after them, so the controller can skip
+/// the spans it already has by count.
+#[derive(Debug, Default)]
+pub struct SpanOrder {
+    shards: HashMap<Uuid, Vec<usize>>,
+    added: VecDeque<Uuid>,
+}
+
+/// One of the redis instances OSProfiler writes spans to
+pub struct RedisShard {
+    url: String,
+    pool: r2d2::Pool<redis::Client>,
 }
 
 impl OSProfilerReader {
     pub fn from_settings(settings: &Settings) -> OSProfilerReader {
-        let redis_url = &settings.redis_url;
-        let client = redis::Client::open(&redis_url[..]).unwrap();
-        let con = client.get_connection().unwrap();
+        let host = std::fs::read_to_string("/etc/hostname")
+            .map(|s| s.trim().to_string())
+            .unwrap_or("unknown".to_string());
         OSProfilerReader {
-            redis_url: settings.redis_url.clone(),
-            connection: con,
+            store: open_store(settings),
+            host,
         }
     }
 
-    pub fn free_keys(&mut self, keys: Vec<String>) {
-        self.connection.del::<_, ()>(keys).ok();
+    pub fn host(&self) -> &str {
+        &self.host
     }
 
-    fn restart_connection(&mut self) {
-        let client = redis::Client::open(&self.redis_url[..]).unwrap();
-        self.connection = client.get_connection().unwrap();
+    /// Redis instances holding the spans, if the spans are in redis
+    pub fn shards(&self) -> &[RedisShard] {
+        match self.store.as_any().downcast_ref::<RedisStore>() {
+            Some(redis) => &redis.shards,
+            None => &[],
+        }
     }
 
-    pub fn get_stats(&mut self) -> (f32, u32) {
-        eprintln!("MERT get stats");
-        let info = redis::cmd("INFO")
-            .query::<redis::InfoDict>(&mut self.connection)
-            .unwrap();
+    pub fn ping(&self) -> Result<(), String> {
+        self.store.ping()
+    }
+
+    pub fn free_keys(&self, keys: Vec<String>) {
+        self.store.free_keys(keys)
+    }
+
+    /// Trace input and memory of the store, and the stats of each redis instance
+    pub fn get_stats(&self) -> (f32, u32, Vec<RedisShardStats>) {
+        let shards: Vec<RedisShardStats> = self.shards().iter().map(|s| s.get_stats()).collect();
+        if shards.is_empty() {
+            let (input_kbps, used_memory) = self.store.get_stats();
+            return (input_kbps, used_memory, shards);
+        }
         (
-            info.get("instantaneous_input_kbps").unwrap(),
-            info.get("used_memory_dataset").unwrap(),
+            shards.iter().map(|s| s.input_kbps).sum(),
+            shards.iter().map(|s| s.used_memory).sum(),
+            shards,
         )
     }
 
-    /// Public wrapper for get_matches_ that accepts string input and does not return RedisResult
-    pub fn get_matches(&mut self, span_id: &str) -> Vec<OSProfilerSpan> {
+    /// Public wrapper for the store that accepts string input
+    pub fn get_matches(&self, span_id: &str) -> Vec<OSProfilerSpan> {
         match Uuid::parse_str(span_id) {
-            Ok(uuid) => self.get_matches_(&uuid).unwrap(),
+            Ok(uuid) => self.store.get_matches(&uuid),
             Err(_) => panic!("Malformed UUID as base id: {}", span_id),
         }
     }
 
-    /// Get matching events from local redis instance
-    fn get_matches_(&mut self, span_id: &Uuid) -> redis::RedisResult<Vec<OSProfilerSpan>> {
+    /// The spans of the request recorded here, sorted the way the controller builds traces
+    pub fn get_fragment(&self, base_id: &Uuid) -> SpanBatch {
+        let mut spans = self.store.get_matches(base_id);
+        sort_spans(&mut spans);
+        SpanBatch {
+            host: self.host.clone(),
+            base_id: *base_id,
+            spans,
+        }
+    }
+
+    pub fn get_span_count(&self, base_id: &Uuid) -> SpanCount {
+        SpanCount::of(base_id, &self.store.get_matches(base_id))
+    }
+}
+
+impl RedisStore {
+    pub fn from_settings(settings: &Settings) -> RedisStore {
+        RedisStore {
+            shards: settings
+                .redis_urls
+                .iter()
+                .map(|url| RedisShard::open(url, settings.redis_pool_size))
+                .collect(),
+            order: Mutex::new(SpanOrder::default()),
+        }
+    }
+}
+
+impl SpanOrder {
+    /// Spans of each instance, in the order they were returned before and then the new ones
+    /// sorted by time
+    pub fn merge(
+        &mut self,
+        base_id: &Uuid,
+        found: Vec<Vec<OSProfilerSpan>>,
+    ) -> Vec<OSProfilerSpan> {
+        if found.iter().all(|spans| spans.is_empty()) {
+            self.forget(base_id);
+            return Vec::new();
+        }
+        if !self.shards.contains_key(base_id) {
+            self.added.push_back(*base_id);
+            while self.added.len() > ORDERED_REQUESTS {
+                let oldest = self.added.pop_front().unwrap();
+                self.shards.remove(&oldest);
+            }
+        }
+        let order = self.shards.entry(*base_id).or_default();
+        let mut found: Vec<std::vec::IntoIter<OSProfilerSpan>> =
+            found.into_iter().map(|spans| spans.into_iter()).collect();
+        let mut result: Vec<OSProfilerSpan> = order
+            .iter()
+            .filter_map(|&shard| found[shard].next())
+            .collect();
+        let mut new: Vec<(usize, OSProfilerSpan)> = found
+            .into_iter()
+            .enumerate()
+            .flat_map(|(shard, rest)| rest.map(move |span| (shard, span)))
+            .collect();
+        new.sort_by(|a, b| span_order(&a.1, &b.1));
+        for (shard, span) in new {
+            order.push(shard);
+            result.push(span);
+        }
+        result
+    }
+
+    pub fn forget(&mut self, base_id: &Uuid) {
+        if self.shards.remove(base_id).is_some() {
+            self.added.retain(|id| id != base_id);
+        }
+    }
+}
+
+impl SpanStore for RedisStore {
+    /// Whether every redis instance can be reached
+    fn ping(&self) -> Result<(), String> {
+        let errors: Vec<String> = self
+            .shards
+            .iter()
+            .filter_map(|shard| shard.ping().err().map(|e| format!("{}: {}", shard.url, e)))
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("; "))
+        }
+    }
+
+    /// The keys of a trace are on whichever instances its spans were written to
+    fn free_keys(&self, keys: Vec<String>) {
+        {
+            let mut order = self.order.lock().unwrap();
+            for id in keys.iter().filter_map(|k| k.strip_prefix("osprofiler:")) {
+                if let Ok(id) = Uuid::parse_str(id) {
+                    order.forget(&id);
+                }
+            }
+        }
+        for shard in &self.shards {
+            shard.free_keys(keys.clone());
+        }
+    }
+
+    /// Trace input and memory summed over the instances
+    fn get_stats(&self) -> (f32, u32) {
+        let shards: Vec<RedisShardStats> = self.shards.iter().map(|s| s.get_stats()).collect();
+        (
+            shards.iter().map(|s| s.input_kbps).sum(),
+            shards.iter().map(|s| s.used_memory).sum(),
+        )
+    }
+
+    /// Get matching events from all local redis instances. With one instance they are in the
+    /// order they were written. With more, a span written later to one instance can be older
+    /// than the spans already read from another, so the order they were returned in is kept
+    /// and new spans are added after it.
+    fn get_matches(&self, span_id: &Uuid) -> Vec<OSProfilerSpan> {
+        let mut found: Vec<Vec<OSProfilerSpan>> = self
+            .shards
+            .iter()
+            .map(|shard| shard.get_matches(span_id))
+            .collect();
+        if found.len() == 1 {
+            return found.remove(0);
+        }
+        self.order.lock().unwrap().merge(span_id, found)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    /// All spans of a request go to the same instance, so they are read back in order
+    fn append(&self, base_id: &Uuid, span: &str) -> Result<(), String> {
+        let shard = &self.shards[base_id.as_bytes()[0] as usize % self.shards.len()];
+        shard
+            .append(&format!("osprofiler:{}", base_id.to_hyphenated()), span)
+            .map_err(|e| format!("{}: {}", shard.url, e))
+    }
+}
+
+impl RedisShard {
+    fn open(url: &str, pool_size: u32) -> RedisShard {
+        let client = redis::Client::open(url).unwrap();
+        let pool = r2d2::Pool::builder()
+            .max_size(pool_size)
+            .build(client)
+            .unwrap();
+        RedisShard {
+            url: url.to_string(),
+            pool,
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn ping(&self) -> redis::RedisResult<()> {
+        redis::cmd("PING").query::<String>(&mut *self.connection()?)?;
+        Ok(())
+    }
+
+    fn connection(&self) -> redis::RedisResult<r2d2::PooledConnection<redis::Client>> {
+        self.pool.get().map_err(|e| {
+            redis::RedisError::from((
+                redis::ErrorKind::IoError,
+                "Couldn't get a redis connection from the pool",
+                e.to_string(),
+            ))
+        })
+    }
+
+    /// Appends to the value of the key, like OSProfiler's redis driver does with each span
+    fn append(&self, key: &str, value: &str) -> redis::RedisResult<()> {
+        self.connection()?.append(key, value)
+    }
+
+    pub fn free_keys(&self, keys: Vec<String>) {
+        match self.connection() {
+            Ok(mut con) => {
+                con.del::<_, ()>(keys).ok();
+            }
+            Err(e) => error!("Could not free keys at {}: {}", self.url, e),
+        }
+    }
+
+    fn get_stats(&self) -> RedisShardStats {
+        let info = self
+            .connection()
+            .and_then(|mut con| redis::cmd("INFO").query::<redis::InfoDict>(&mut *con));
+        match info {
+            Ok(info) => RedisShardStats {
+                url: self.url.clone(),
+                error: None,
+                input_kbps: info.get("instantaneous_input_kbps").unwrap_or(0.0),
+                used_memory: info.get("used_memory_dataset").unwrap_or(0),
+            },
+            Err(e) => RedisShardStats {
+                url: self.url.clone(),
+                error: Some(e.to_string()),
+                input_kbps: 0.0,
+                used_memory: 0,
+            },
+        }
+    }
+
+    fn get_matches(&self, span_id: &Uuid) -> Vec<OSProfilerSpan> {
         let mut trials = 0;
         let mut to_parse: Option<String> = None;
         while to_parse.is_none() && trials < 2 {
-            to_parse = match self
-                .connection
-                .get("osprofiler:".to_string() + &span_id.to_hyphenated().to_string())
-            {
+            to_parse = match self.connection().and_then(|mut con| {
+                con.get("osprofiler:".to_string() + &span_id.to_hyphenated().to_string())
+            }) {
                 Ok(to_parse) => match &to_parse {
                     Value::Nil => {
-                        return Ok(Vec::new());
+                        return Vec::new();
                     }
                     Value::Data(_) => Some(FromRedisValue::from_redis_value(&to_parse).unwrap()),
                     _ => {
-                        eprintln!("Got {:?} as reply", to_parse);
-                        return Ok(Vec::new());
+                        warn!(trace_id:% = span_id; "Got {:?} as reply", to_parse);
+                        return Vec::new();
                     }
                 },
                 Err(e) => {
-                    self.restart_connection();
-                    eprintln!("Got error {} for {}", e, span_id);
+                    warn!(trace_id:% = span_id; "Redis error at {}: {}", self.url, e);
                     None
                 }
             };
             trials += 1;
         }
-        let mut result = Vec::new();
-        let to_parse = to_parse.unwrap();
-        for dict_string in to_parse[1..to_parse.len() - 1].split("}{") {
-            match osprofiler::parse_field(&("{".to_string() + dict_string + "}")) {
-                Ok(span) => {
-                    result.push(span);
-                }
-                Err(e) => panic!("Problem while parsing {}: {}", dict_string, e),
+        match to_parse {
+            Some(s) => parse_spans(&s, &span_id.to_string()),
+            None => Vec::new(),
+        }
+    }
+
+    /// Redis keys holding spans. Scanning doesn't count as using the keys.
+    pub fn span_keys(&self) -> redis::RedisResult<Vec<String>> {
+        let mut con = self.connection()?;
+        let keys = con.scan_match::<_, String>("osprofiler:*")?.collect();
+        Ok(keys)
+    }
+
+    /// Length of the value of the key. This reads the key, so it resets its idle time.
+    pub fn len(&self, key: &str) -> redis::RedisResult<usize> {
+        self.connection()?.strlen(key)
+    }
+
+    /// Time since the key was last read or written. Redis doesn't track it under an LFU
+    /// `maxmemory-policy`, and answers with an error.
+    pub fn idle_time(&self, key: &str) -> redis::RedisResult<Duration> {
+        let seconds: u64 = redis::cmd("OBJECT")
+            .arg("IDLETIME")
+            .arg(key)
+            .query(&mut *self.connection()?)?;
+        Ok(Duration::from_secs(seconds))
+    }
+
+    /// Spans stored under the key, skipping the first `offset` bytes that were already read
+    pub fn get_spans_after(
+        &self,
+        key: &str,
+        offset: usize,
+    ) -> redis::RedisResult<Vec<OSProfilerSpan>> {
+        let value: String = self.connection()?.getrange(key, offset as isize, -1)?;
+        Ok(parse_spans(&value, key))
+    }
+}
+
+/// OSProfiler appends the json of each span to the value, so it looks like `{...}{...}`
+fn parse_spans(to_parse: &str, id: &str) -> Vec<OSProfilerSpan> {
+    let mut result = Vec::new();
+    if to_parse.len() < 2 {
+        return result;
+    }
+    for dict_string in to_parse[1..to_parse.len() - 1].split("}{") {
+        match osprofiler::parse_field(&("{".to_string() + dict_string + "}")) {
+            Ok(span) => {
+                result.push(span);
             }
+            Err(e) => warn!(trace_id:% = id; "Skipping span: {}", e),
         }
-        Ok(result)
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE_ID: &str = "7d3a8d1e-4e3c-4a7b-9c2d-1f0e6b5a4c3d";
+
+    fn span(name: &str, seconds: u32) -> OSProfilerSpan {
+        osprofiler::parse_field(&format!(
+            r#"{{"trace_id": "936da01f-9abd-4d9d-80c7-02af85c822a8", "parent_id": "{0}", "project": "nova", "name": "{1}-start", "base_id": "{0}", "service": "api", "tracepoint_id": "{1}", "timestamp": "2022-03-01T10:00:{2:02}.000000", "info": {{"host": "cp-1"}}}}"#,
+            BASE_ID, name, seconds
+        ))
+        .unwrap()
+    }
+
+    fn names(spans: &[OSProfilerSpan]) -> Vec<&str> {
+        spans.iter().map(|s| s.tracepoint_id.as_str()).collect()
+    }
+
+    #[test]
+    fn spans_of_several_instances_are_only_appended() {
+        let base_id = Uuid::parse_str(BASE_ID).unwrap();
+        let mut order = SpanOrder::default();
+        // The request starts on one instance, then spreads to another
+        let first = order.merge(&base_id, vec![vec![span("a", 10)], vec![]]);
+        assert_eq!(names(&first), vec!["a"]);
+        let second = order.merge(&base_id, vec![vec![span("a", 10)], vec![span("b", 30)]]);
+        assert_eq!(names(&second), vec!["a", "b"]);
+        // A late span on the first instance is older than b, but it comes after it
+        let third = order.merge(
+            &base_id,
+            vec![
+                vec![span("a", 10), span("c", 20)],
+                vec![span("b", 30), span("d", 40)],
+            ],
+        );
+        assert_eq!(names(&third), vec!["a", "b", "c", "d"]);
+        assert_eq!(names(&third[2..]), vec!["c", "d"]);
+
+        order.forget(&base_id);
+        let fresh = order.merge(
+            &base_id,
+            vec![vec![span("a", 10), span("c", 20)], vec![span("b", 30)]],
+        );
+        assert_eq!(names(&fresh), vec!["a", "c", "b"]);
     }
 }
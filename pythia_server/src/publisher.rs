@@ -0,0 +1,125 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Publishes span batches from the local redis to Kafka.
+//!
+//! A batch is published once its redis value stops growing between two scans. If more spans are
+//! appended later (e.g., an asynchronous child finishes), only the new spans are published.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use log::{error, info, warn};
+use rdkafka::config::ClientConfig;
+use rdkafka::producer::{BaseProducer, BaseRecord, Producer};
+use uuid::Uuid;
+
+use pythia_common::SpanBatch;
+
+use crate::osprofiler::OSProfilerReader;
+use crate::settings::Settings;
+
+pub struct SpanPublisher {
+    reader: OSProfilerReader,
+    producer: BaseProducer,
+    topic: String,
+    host: String,
+    interval: Duration,
+    /// Length of each key of each redis instance at the previous scan
+    last_seen: HashMap<(usize, String), usize>,
+    /// How much of each key has been published
+    published: HashMap<(usize, String), usize>,
+}
+
+impl SpanPublisher {
+    pub fn from_settings(settings: &Settings, brokers: &str) -> SpanPublisher {
+        let producer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .create()
+            .expect("Couldn't create Kafka producer");
+        let reader = OSProfilerReader::from_settings(settings);
+        SpanPublisher {
+            host: reader.host().to_string(),
+            reader,
+            producer,
+            topic: settings.kafka_topic.clone(),
+            interval: settings.kafka_publish_interval,
+            last_seen: HashMap::new(),
+            published: HashMap::new(),
+        }
+    }
+
+    /// Publishes until `shutdown` is set
+    pub fn run(&mut self, shutdown: &AtomicBool) {
+        info!("Publishing spans to Kafka topic {}", self.topic);
+        while !shutdown.load(Ordering::Relaxed) {
+            let mut seen = HashMap::new();
+            for shard in 0..self.reader.shards().len() {
+                if let Err(e) = self.publish_completed(shard, &mut seen) {
+                    let url = self.reader.shards()[shard].url();
+                    error!("Could not scan {} for spans: {}", url, e);
+                }
+            }
+            // Forget keys that were freed
+            self.published.retain(|k, _| seen.contains_key(k));
+            self.last_seen = seen;
+            self.producer.poll(Duration::from_millis(0));
+            thread::sleep(self.interval);
+        }
+    }
+
+    /// Adds the keys of the redis instance to `seen`
+    fn publish_completed(
+        &mut self,
+        shard: usize,
+        seen: &mut HashMap<(usize, String), usize>,
+    ) -> redis::RedisResult<()> {
+        let redis = &self.reader.shards()[shard];
+        for key in redis.span_keys()? {
+            let len = redis.len(&key)?;
+            let key = (shard, key);
+            let stable = self.last_seen.get(&key) == Some(&len);
+            let offset = *self.published.get(&key).unwrap_or(&0);
+            if stable && len > offset {
+                let base_id = match Uuid::parse_str(key.1.trim_start_matches("osprofiler:")) {
+                    Ok(id) => id,
+                    Err(_) => {
+                        warn!("Skipping malformed key {}", key.1);
+                        continue;
+                    }
+                };
+                let batch = SpanBatch {
+                    host: self.host.clone(),
+                    base_id,
+                    spans: redis.get_spans_after(&key.1, offset)?,
+                };
+                let payload = serde_json::to_string(&batch).unwrap();
+                let id = base_id.to_string();
+                match self
+                    .producer
+                    .send(BaseRecord::to(&self.topic).key(&id).payload(&payload))
+                {
+                    Ok(()) => {
+                        self.published.insert(key.clone(), len);
+                    }
+                    Err((e, _)) => error!(trace_id:% = id; "Could not publish spans: {}", e),
+                }
+            }
+            seen.insert(key, len);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SpanPublisher {
+    fn drop(&mut self) {
+        self.producer.flush(Duration::from_secs(1));
+    }
+}
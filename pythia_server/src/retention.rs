@@ -0,0 +1,318 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Deletes osprofiler keys that the controller is never going to free.
+//!
+//! The controller frees the keys of the traces it reads, but the keys of traces it abandoned,
+//! or that failed to build, stay in redis. With `retention_max_age_secs` or
+//! `retention_max_memory_bytes` set, a background thread scans the keys every
+//! `retention_interval` and deletes the ones that were not touched for longer than the maximum
+//! age, then the least recently touched ones until the spans fit in the memory limit. What it
+//! deleted shows up in the node stats.
+//!
+//! Redis doesn't track when keys were touched under an LFU `maxmemory-policy`. Those keys never
+//! expire by age, and are deleted after the others when over the memory limit.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+
+use crate::osprofiler::{OSProfilerReader, RedisShard};
+use crate::settings::Settings;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetentionPolicy {
+    /// Keys not read or written for this long are deleted
+    pub max_age: Option<Duration>,
+    /// Bytes of spans kept at most
+    pub max_memory: Option<u64>,
+}
+
+/// A key holding spans, as seen by a scan
+#[derive(Debug, Clone)]
+pub struct SpanKey {
+    /// Index of the redis instance of the key
+    pub shard: usize,
+    pub key: String,
+    pub len: u64,
+    /// Time since the key was last read or written, if redis knows
+    pub idle: Option<Duration>,
+}
+
+/// What a scan needs from a redis instance
+pub trait KeySource {
+    fn span_keys(&self) -> redis::RedisResult<Vec<String>>;
+    fn idle_time(&self, key: &str) -> redis::RedisResult<Duration>;
+    fn len(&self, key: &str) -> redis::RedisResult<usize>;
+}
+
+impl KeySource for RedisShard {
+    fn span_keys(&self) -> redis::RedisResult<Vec<String>> {
+        RedisShard::span_keys(self)
+    }
+
+    fn idle_time(&self, key: &str) -> redis::RedisResult<Duration> {
+        RedisShard::idle_time(self, key)
+    }
+
+    fn len(&self, key: &str) -> redis::RedisResult<usize> {
+        RedisShard::len(self, key)
+    }
+}
+
+impl RetentionPolicy {
+    /// None if neither limit is set
+    pub fn from_settings(settings: &Settings) -> Option<RetentionPolicy> {
+        if settings.retention_max_age.is_none() && settings.retention_max_memory.is_none() {
+            return None;
+        }
+        Some(RetentionPolicy {
+            max_age: settings.retention_max_age,
+            max_memory: settings.retention_max_memory,
+        })
+    }
+
+    /// Keys to delete, oldest first
+    pub fn select(&self, mut keys: Vec<SpanKey>) -> Vec<SpanKey> {
+        // Keys of unknown age sort last
+        keys.sort_by_key(|k| std::cmp::Reverse(k.idle));
+        let mut kept: u64 = keys.iter().map(|k| k.len).sum();
+        keys.into_iter()
+            .take_while(|k| {
+                let too_old = self
+                    .max_age
+                    .is_some_and(|age| k.idle.is_some_and(|idle| idle > age));
+                let too_big = self.max_memory.is_some_and(|max| kept > max);
+                if too_old || too_big {
+                    kept -= k.len;
+                }
+                too_old || too_big
+            })
+            .collect()
+    }
+}
+
+/// Totals since the agent started
+#[derive(Debug, Default)]
+pub struct RetentionStats {
+    pub expired_keys: AtomicU64,
+    pub expired_bytes: AtomicU64,
+}
+
+pub struct RetentionCollector {
+    reader: OSProfilerReader,
+    policy: RetentionPolicy,
+    interval: Duration,
+    stats: Arc<RetentionStats>,
+}
+
+impl RetentionCollector {
+    pub fn new(
+        settings: &Settings,
+        policy: RetentionPolicy,
+        stats: Arc<RetentionStats>,
+    ) -> RetentionCollector {
+        RetentionCollector {
+            reader: OSProfilerReader::from_settings(settings),
+            policy,
+            interval: settings.retention_interval,
+            stats,
+        }
+    }
+
+    /// Collects until `shutdown` is set
+    pub fn run(&self, shutdown: &AtomicBool) {
+        info!("Expiring osprofiler keys with {:?}", self.policy);
+        let mut last_scan: Option<Instant> = None;
+        while !shutdown.load(Ordering::Relaxed) {
+            if last_scan.is_none_or(|t| t.elapsed() >= self.interval) {
+                self.collect();
+                last_scan = Some(Instant::now());
+            }
+            // Short sleeps, so shutting down doesn't wait for the next scan
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+
+    /// The idle time of each key is read before its length, since reading the length touches
+    /// the key
+    pub fn scan(
+        shard: usize,
+        redis: &dyn KeySource,
+        keys: &mut Vec<SpanKey>,
+    ) -> redis::RedisResult<()> {
+        let mut untracked = 0;
+        for key in redis.span_keys()? {
+            let idle = match redis.idle_time(&key) {
+                Ok(idle) => Some(idle),
+                // Not tracked, or the key was deleted since the scan
+                Err(e) if e.kind() == redis::ErrorKind::ResponseError => {
+                    untracked += 1;
+                    None
+                }
+                Err(e) => return Err(e),
+            };
+            let len = redis.len(&key)?;
+            keys.push(SpanKey {
+                shard,
+                key,
+                len: len as u64,
+                idle,
+            });
+        }
+        if untracked > 0 {
+            warn!(
+                "Redis did not tell the idle time of {} keys, they won't expire by age",
+                untracked
+            );
+        }
+        Ok(())
+    }
+
+    fn collect(&self) {
+        let mut keys = Vec::new();
+        for (shard, redis) in self.reader.shards().iter().enumerate() {
+            // An unreachable instance doesn't hold back the others
+            if let Err(e) = Self::scan(shard, redis, &mut keys) {
+                error!("Could not scan {} for keys to expire: {}", redis.url(), e);
+            }
+        }
+        let expired = self.policy.select(keys);
+        if expired.is_empty() {
+            return;
+        }
+        let bytes: u64 = expired.iter().map(|k| k.len).sum();
+        info!(
+            "Expiring {} osprofiler keys ({} bytes)",
+            expired.len(),
+            bytes
+        );
+        for (shard, redis) in self.reader.shards().iter().enumerate() {
+            redis.free_keys(
+                expired
+                    .iter()
+                    .filter(|k| k.shard == shard)
+                    .map(|k| k.key.clone())
+                    .collect(),
+            );
+        }
+        self.stats
+            .expired_keys
+            .fetch_add(expired.len() as u64, Ordering::Relaxed);
+        self.stats.expired_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::collections::HashSet;
+
+    #[test]
+    fn expires_old_keys_then_keys_over_the_limit() {
+        let key = |name: &str, len, idle| SpanKey {
+            shard: 0,
+            key: name.to_string(),
+            len,
+            idle: Some(Duration::from_secs(idle)),
+        };
+        let keys = vec![
+            key("new", 10, 1),
+            key("old", 10, 100),
+            key("older", 10, 200),
+            key("recent", 10, 5),
+        ];
+        let names = |selected: Vec<SpanKey>| -> Vec<String> {
+            selected.into_iter().map(|k| k.key).collect()
+        };
+        let by_age = RetentionPolicy {
+            max_age: Some(Duration::from_secs(60)),
+            max_memory: None,
+        };
+        assert_eq!(names(by_age.select(keys.clone())), vec!["older", "old"]);
+        let by_memory = RetentionPolicy {
+            max_age: None,
+            max_memory: Some(15),
+        };
+        assert_eq!(
+            names(by_memory.select(keys.clone())),
+            vec!["older", "old", "recent"]
+        );
+        let both = RetentionPolicy {
+            max_age: Some(Duration::from_secs(150)),
+            max_memory: Some(25),
+        };
+        assert_eq!(names(both.select(keys.clone())), vec!["older", "old"]);
+
+        // Keys of unknown age are deleted last, and only for memory
+        let mut untracked = keys;
+        untracked.push(SpanKey {
+            idle: None,
+            ..untracked[0].clone()
+        });
+        untracked[4].key = "untracked".to_string();
+        assert_eq!(
+            names(by_age.select(untracked.clone())),
+            vec!["older", "old"]
+        );
+        let tight = RetentionPolicy {
+            max_age: None,
+            max_memory: Some(5),
+        };
+        assert_eq!(names(tight.select(untracked)).last().unwrap(), "untracked");
+    }
+
+    /// Keys whose idle time the redis commands of the scan reset, like `STRLEN` does
+    struct FakeRedis {
+        keys: Vec<(&'static str, usize, Option<u64>)>,
+        touched: RefCell<HashSet<String>>,
+    }
+
+    impl KeySource for FakeRedis {
+        fn span_keys(&self) -> redis::RedisResult<Vec<String>> {
+            Ok(self.keys.iter().map(|k| k.0.to_string()).collect())
+        }
+
+        fn idle_time(&self, key: &str) -> redis::RedisResult<Duration> {
+            let (_, _, idle) = self.keys.iter().find(|k| k.0 == key).unwrap();
+            match idle {
+                _ if self.touched.borrow().contains(key) => Ok(Duration::from_secs(0)),
+                Some(idle) => Ok(Duration::from_secs(*idle)),
+                None => Err(redis::RedisError::from((
+                    redis::ErrorKind::ResponseError,
+                    "An LFU maxmemory policy is selected, idle time not tracked",
+                ))),
+            }
+        }
+
+        fn len(&self, key: &str) -> redis::RedisResult<usize> {
+            self.touched.borrow_mut().insert(key.to_string());
+            Ok(self.keys.iter().find(|k| k.0 == key).unwrap().1)
+        }
+    }
+
+    #[test]
+    fn scans_idle_times_before_touching_keys() {
+        let redis = FakeRedis {
+            keys: vec![("osprofiler:a", 10, Some(100)), ("osprofiler:b", 20, None)],
+            touched: RefCell::new(HashSet::new()),
+        };
+        let mut keys = Vec::new();
+        RetentionCollector::scan(1, &redis, &mut keys).unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].idle, Some(Duration::from_secs(100)));
+        assert_eq!((keys[0].shard, keys[0].len), (1, 10));
+        assert_eq!(keys[1].idle, None);
+        assert_eq!(keys[1].len, 20);
+    }
+}
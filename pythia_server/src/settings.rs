@@ -8,15 +8,51 @@ All rights reserved.
 
 use std::collections::HashMap;
 use std::path::PathBuf;
+use std::time::Duration;
 
 use config::{Config, File, FileFormat};
+use pythia_common::load_request_types_file;
+
+const STATE_FILE: &str = "/opt/stack/pythia_state.json";
+const SERVER_THREADS: usize = 4;
+const REDIS_POOL_SIZE: u32 = 8;
+const KAFKA_TOPIC: &str = "pythia-spans";
+const KAFKA_PUBLISH_INTERVAL: Duration = Duration::from_secs(1);
+const MESSAGING_EXCHANGE: &str = "openstack";
+const MESSAGING_TOPIC: &str = "profiler";
+const RETENTION_INTERVAL: Duration = Duration::from_secs(60);
 
 #[derive(Debug)]
 pub struct Settings {
     pub server_address: String,
     pub manifest_root: PathBuf,
-    pub redis_url: String,
+    /// Redis instances the spans are written to; large nodes run more than one
+    pub redis_urls: Vec<String>,
+    /// OSProfiler's connection string, if it doesn't write spans to `redis_urls`
+    pub span_store_url: Option<String>,
     pub network_interface: String,
+    pub state_file: PathBuf,
+    /// Number of threads serving RPCs concurrently
+    pub server_threads: usize,
+    pub redis_pool_size: u32,
+    /// If set, completed span batches are published to this Kafka cluster
+    pub kafka_brokers: Option<String>,
+    pub kafka_topic: String,
+    /// How often redis is scanned for span batches to publish
+    pub kafka_publish_interval: Duration,
+    /// If set, spans OSProfiler sends over oslo.messaging are consumed from this AMQP broker
+    pub messaging_url: Option<String>,
+    pub messaging_exchange: String,
+    pub messaging_topic: String,
+    /// Names and regexes of the request types; None uses the built-in ones
+    pub request_types_file: Option<PathBuf>,
+    /// Osprofiler keys not touched for this long are deleted; None keeps them
+    pub retention_max_age: Option<Duration>,
+    /// The least recently touched osprofiler keys are deleted while the spans take more bytes
+    /// than this; None keeps them
+    pub retention_max_memory: Option<u64>,
+    /// How often redis is scanned for keys to delete
+    pub retention_interval: Duration,
 }
 
 impl Settings {
@@ -26,11 +62,72 @@ impl Settings {
             .merge(File::new("/etc/pythia/server.toml", FileFormat::Toml))
             .unwrap();
         let results = settings.try_into::<HashMap<String, String>>().unwrap();
+        let request_types_file = results
+            .get("request_types_file")
+            .filter(|s| s.len() > 0)
+            .map(PathBuf::from);
+        // Request types are global, so they are set up as soon as the settings are known
+        if let Some(path) = &request_types_file {
+            load_request_types_file(path)
+                .unwrap_or_else(|e| panic!("Could not load request types from {:?}: {}", path, e));
+        }
         Settings {
             server_address: results.get("server_address").unwrap().to_string(),
-            redis_url: results.get("redis_url").unwrap().to_string(),
+            redis_urls: results
+                .get("redis_url")
+                .unwrap()
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect(),
+            span_store_url: results
+                .get("span_store_url")
+                .filter(|s| s.len() > 0)
+                .cloned(),
             manifest_root: PathBuf::from(results.get("manifest_root").unwrap()),
             network_interface: results.get("network_interface").unwrap().to_string(),
+            state_file: PathBuf::from(
+                results
+                    .get("state_file")
+                    .map(|s| s.as_str())
+                    .unwrap_or(STATE_FILE),
+            ),
+            server_threads: SERVER_THREADS,
+            redis_pool_size: REDIS_POOL_SIZE,
+            kafka_brokers: results
+                .get("kafka_brokers")
+                .filter(|s| s.len() > 0)
+                .cloned(),
+            kafka_topic: results
+                .get("kafka_topic")
+                .map(|s| s.as_str())
+                .unwrap_or(KAFKA_TOPIC)
+                .to_string(),
+            kafka_publish_interval: KAFKA_PUBLISH_INTERVAL,
+            messaging_url: results
+                .get("messaging_url")
+                .filter(|s| s.len() > 0)
+                .cloned(),
+            messaging_exchange: results
+                .get("messaging_exchange")
+                .map(|s| s.as_str())
+                .unwrap_or(MESSAGING_EXCHANGE)
+                .to_string(),
+            messaging_topic: results
+                .get("messaging_topic")
+                .map(|s| s.as_str())
+                .unwrap_or(MESSAGING_TOPIC)
+                .to_string(),
+            request_types_file,
+            retention_max_age: results
+                .get("retention_max_age_secs")
+                .filter(|s| s.len() > 0)
+                .map(|s| Duration::from_secs(s.parse().expect("Bad retention_max_age_secs"))),
+            retention_max_memory: results
+                .get("retention_max_memory_bytes")
+                .filter(|s| s.len() > 0)
+                .map(|s| s.parse().expect("Bad retention_max_memory_bytes")),
+            retention_interval: RETENTION_INTERVAL,
         }
     }
 }
@@ -0,0 +1,207 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Persisted record of the tracepoint settings applied on this agent.
+//!
+//! Every setting that goes through the RPC interface is recorded here together with which call
+//! made it and when. The record is written to `state_file` after each change, and replayed on
+//! top of the manifest when the agent starts, so a restarted agent has the instrumentation the
+//! controller expects.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use chrono::offset::Local;
+use chrono::NaiveDateTime;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use pythia_common::RequestType;
+
+use crate::controller::OSProfilerController;
+
+/// A single applied setting
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SettingRecord {
+    pub tracepoint: Option<String>,
+    pub request_type: Option<RequestType>,
+    pub value: u8,
+    /// The RPC call that made the change
+    pub source: String,
+    pub time: NaiveDateTime,
+}
+
+impl SettingRecord {
+    fn new(
+        tracepoint: Option<String>,
+        request_type: Option<RequestType>,
+        value: u8,
+        source: &str,
+    ) -> SettingRecord {
+        SettingRecord {
+            tracepoint,
+            request_type,
+            value,
+            source: source.to_string(),
+            time: Local::now().naive_local(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct StateStore {
+    #[serde(skip)]
+    path: PathBuf,
+    /// Last setting applied to all tracepoints
+    all: Option<SettingRecord>,
+    /// Settings applied to individual tracepoints after `all`
+    tracepoints: HashMap<String, SettingRecord>,
+}
+
+impl StateStore {
+    /// Reads the state from the file, or starts with an empty state if there is none
+    pub fn open(path: &Path) -> StateStore {
+        let mut result = match File::open(path) {
+            Ok(f) => match serde_json::from_reader(f) {
+                Ok(state) => state,
+                Err(e) => {
+                    warn!("Ignoring unreadable state file {:?}: {}", path, e);
+                    StateStore::default()
+                }
+            },
+            Err(_) => StateStore::default(),
+        };
+        result.path = path.to_path_buf();
+        result
+    }
+
+    pub fn record_all(&mut self, to_write: &[u8; 1], source: &str) {
+        self.tracepoints.clear();
+        self.all = Some(SettingRecord::new(None, None, to_write[0], source));
+        self.save();
+    }
+
+    pub fn record(&mut self, settings: &Vec<(String, Option<RequestType>, [u8; 1])>, source: &str) {
+        for (tracepoint, request_type, to_write) in settings {
+            let key = match request_type {
+                Some(t) => format!("{}:{}", tracepoint, t),
+                None => tracepoint.clone(),
+            };
+            self.tracepoints.insert(
+                key,
+                SettingRecord::new(Some(tracepoint.clone()), *request_type, to_write[0], source),
+            );
+        }
+        self.save();
+    }
+
+    /// Re-applies the recorded settings, oldest first
+    pub fn restore(&self, controller: &OSProfilerController) {
+        if let Some(all) = &self.all {
+            info!(
+                "Restoring all tracepoints to {} (set by {} at {})",
+                all.value, all.source, all.time
+            );
+            controller.write_client_dir(&[all.value]);
+        }
+        let mut records = self.tracepoints.values().collect::<Vec<_>>();
+        records.sort_by_key(|r| r.time);
+        info!("Restoring {} tracepoint settings", records.len());
+        controller.apply_settings(
+            records
+                .iter()
+                .filter_map(|r| match &r.tracepoint {
+                    Some(tracepoint) => Some((tracepoint.clone(), r.request_type, [r.value])),
+                    None => {
+                        warn!("Skipping a setting without tracepoint: {:?}", r);
+                        None
+                    }
+                })
+                .collect(),
+        );
+    }
+
+    /// Writes to a temporary file first so a crash doesn't leave a truncated state
+    pub fn save(&self) {
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let written = File::create(&tmp)
+            .map_err(|e| e.to_string())
+            .and_then(|f| serde_json::to_writer(f, self).map_err(|e| e.to_string()))
+            .and_then(|_| std::fs::rename(&tmp, &self.path).map_err(|e| e.to_string()));
+        if let Err(e) = written {
+            error!("Could not save agent state to {:?}: {}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("pythia-state-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn restores_recorded_settings() {
+        let root = temp_path("manifest");
+        std::fs::create_dir_all(root.join("nova")).unwrap();
+        let controller = OSProfilerController::new(root.clone());
+        controller.apply_settings(vec![
+            ("nova/a".to_string(), None, *b"0"),
+            ("nova/b".to_string(), None, *b"0"),
+            ("nova/c".to_string(), None, *b"0"),
+        ]);
+        let create = RequestType::from_str("ServerCreate").unwrap();
+        let path = temp_path("file");
+        let mut store = StateStore::open(&path);
+        store.record_all(b"1", "enable_all");
+        store.record(
+            &vec![
+                ("nova/a".to_string(), None, *b"0"),
+                ("nova/b".to_string(), Some(create), *b"0"),
+            ],
+            "set_tracepoints",
+        );
+
+        // The agent restarts with everything off
+        controller.write_client_dir(b"0");
+        let mut store = StateStore::open(&path);
+        assert_eq!(store.tracepoints.len(), 2);
+        let mut corrupt = store.tracepoints["nova/a"].clone();
+        corrupt.tracepoint = None;
+        store.tracepoints.insert("corrupt".to_string(), corrupt);
+        store.restore(&controller);
+        let mut settings = controller.read_all_settings();
+        settings.sort_by(|a, b| (&a.0, a.1.is_some()).cmp(&(&b.0, b.1.is_some())));
+        assert_eq!(
+            settings,
+            vec![
+                ("nova/a".to_string(), None, false),
+                ("nova/b".to_string(), None, true),
+                ("nova/b".to_string(), Some(create), false),
+                ("nova/c".to_string(), None, true),
+            ]
+        );
+        std::fs::remove_dir_all(&root).ok();
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn ignores_unreadable_state_file() {
+        let path = temp_path("unreadable");
+        std::fs::write(&path, "not json").unwrap();
+        let store = StateStore::open(&path);
+        assert!(store.all.is_none());
+        assert!(store.tracepoints.is_empty());
+        assert_eq!(store.path, path);
+        std::fs::remove_file(&path).ok();
+    }
+}
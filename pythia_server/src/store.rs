@@ -0,0 +1,118 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Where OSProfiler writes the spans on this host.
+//!
+//! OSProfiler has drivers for redis, MongoDB and Elasticsearch, chosen by its
+//! `connection_string`. The agent reads spans through a `SpanStore`, picked by the scheme of
+//! `span_store_url` the same way, so the controller gets the same spans whichever driver is
+//! used. Redis is always available; the MongoDB and Elasticsearch stores need the `mongo` and
+//! `elasticsearch` features.
+//!
+//! Only redis is scanned by the Kafka publisher and key retention, since the other stores have
+//! their own ways to expire old documents. It is also the only store spans received over
+//! oslo.messaging are written to.
+
+use std::any::Any;
+
+use pythia_common::OSProfilerSpan;
+use uuid::Uuid;
+
+use crate::osprofiler::RedisStore;
+use crate::settings::Settings;
+
+pub trait SpanStore: Send + Sync {
+    /// Spans of the request. A later call returns the same spans in the same order, followed by
+    /// the new ones, so callers can skip the spans they already have by count.
+    fn get_matches(&self, base_id: &Uuid) -> Vec<OSProfilerSpan>;
+
+    /// Deletes the spans of the requests, given as `osprofiler:<base id>` keys
+    fn free_keys(&self, keys: Vec<String>);
+
+    /// Why the store cannot be reached, if it can't
+    fn ping(&self) -> Result<(), String>;
+
+    /// Adds the json of a span of the request, for spans that don't come through OSProfiler's
+    /// own driver
+    fn append(&self, _base_id: &Uuid, _span: &str) -> Result<(), String> {
+        Err("spans can only be added to redis".to_string())
+    }
+
+    /// Trace input in kbps and bytes of spans stored
+    fn get_stats(&self) -> (f32, u32) {
+        (0.0, 0)
+    }
+
+    /// For the parts of the agent that only work with one kind of store
+    fn as_any(&self) -> &dyn Any;
+}
+
+/// Opens the store `span_store_url` points to, or the redis instances of `redis_url`
+pub fn open_store(settings: &Settings) -> Box<dyn SpanStore> {
+    let url = match &settings.span_store_url {
+        Some(url) => url,
+        None => return Box::new(RedisStore::from_settings(settings)),
+    };
+    match url.split("://").next().unwrap() {
+        "redis" => Box::new(RedisStore::from_settings(settings)),
+        "mongodb" => open_mongo(url),
+        "elasticsearch" => open_elasticsearch(url),
+        _ => panic!("Unknown span store {}", url),
+    }
+}
+
+#[cfg(feature = "mongo")]
+fn open_mongo(url: &str) -> Box<dyn SpanStore> {
+    Box::new(crate::mongo::MongoStore::open(url))
+}
+
+#[cfg(not(feature = "mongo"))]
+fn open_mongo(url: &str) -> Box<dyn SpanStore> {
+    panic!(
+        "span_store_url is {}, but the agent was built without the mongo feature",
+        url
+    )
+}
+
+#[cfg(feature = "elasticsearch")]
+fn open_elasticsearch(url: &str) -> Box<dyn SpanStore> {
+    Box::new(crate::elasticsearch::ElasticsearchStore::open(url))
+}
+
+#[cfg(not(feature = "elasticsearch"))]
+fn open_elasticsearch(url: &str) -> Box<dyn SpanStore> {
+    panic!(
+        "span_store_url is {}, but the agent was built without the elasticsearch feature",
+        url
+    )
+}
+
+/// Base ids of `osprofiler:<base id>` keys, skipping keys that aren't
+pub fn base_ids(keys: &[String]) -> Vec<String> {
+    keys.iter()
+        .filter_map(|k| k.strip_prefix("osprofiler:"))
+        .map(|id| id.to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_base_ids_of_keys() {
+        let keys = vec![
+            "osprofiler:7b9a5d16-0fb4-4b1e-9bb7-4c1f46d4b8a2".to_string(),
+            "other".to_string(),
+        ];
+        assert_eq!(
+            base_ids(&keys),
+            vec!["7b9a5d16-0fb4-4b1e-9bb7-4c1f46d4b8a2".to_string()]
+        );
+    }
+}
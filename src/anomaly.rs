@@ -0,0 +1,228 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Flags groups whose latency changed compared to their own history.
+//!
+//! High variance says a group is unstable; an anomaly says it got slower (or faster) than it used
+//! to be. Each decision epoch, the mean latency of the paths a group got since the last epoch is
+//! compared to the means of the earlier epochs.
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+
+use crate::critical::Path;
+use crate::grouping::Group;
+use crate::grouping::GroupManager;
+
+/// Epoch means kept per group
+const BASELINE_WINDOW: usize = 50;
+/// Groups with fewer epochs of history are not scored
+const MIN_BASELINE_EPOCHS: usize = 3;
+/// Scales the MAD to the standard deviation of a normal distribution
+const MAD_SCALE: f64 = 1.4826;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AnomalyMethod {
+    /// Distance from the baseline mean in standard deviations
+    ZScore,
+    /// Distance from the baseline median in (scaled) median absolute deviations
+    Mad,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Anomaly {
+    pub group: String,
+    /// Nanoseconds
+    pub recent_mean: f64,
+    pub score: f64,
+}
+
+#[derive(Debug, Default)]
+struct Baseline {
+    epoch_means: VecDeque<f64>,
+    /// Generation, count and sum of the group's statistics when it was last seen, to get the new
+    /// paths only
+    last_generation: Option<u64>,
+    last_count: u64,
+    last_total: f64,
+}
+
+pub struct AnomalyDetector {
+    method: AnomalyMethod,
+    threshold: f64,
+    baselines: HashMap<String, Baseline>,
+}
+
+impl AnomalyDetector {
+    pub fn new(method: AnomalyMethod, threshold: f64) -> Self {
+        AnomalyDetector {
+            method,
+            threshold,
+            baselines: HashMap::new(),
+        }
+    }
+
+    pub fn method(&self) -> AnomalyMethod {
+        self.method
+    }
+
+    /// Change the threshold, keeping the baselines
+    pub fn set_threshold(&mut self, threshold: f64) {
+        self.threshold = threshold;
+    }
+
+    /// Score the paths each group got since the last call against the group's history, then add
+    /// them to the history. Returns the anomalous groups, highest score first.
+    pub fn observe(&mut self, groups: &GroupManager) -> Vec<Anomaly> {
+        let mut result = Vec::new();
+        for g in groups.active_groups() {
+            let baseline = self.baselines.entry(g.hash().to_string()).or_default();
+            let recent = match recent_mean(g, baseline) {
+                Some(m) => m,
+                None => continue,
+            };
+            if baseline.epoch_means.len() >= MIN_BASELINE_EPOCHS {
+                let score = score(self.method, &baseline.epoch_means, recent);
+                if score.abs() > self.threshold {
+                    result.push(Anomaly {
+                        group: g.hash().to_string(),
+                        recent_mean: recent,
+                        score,
+                    });
+                }
+            }
+            baseline.epoch_means.push_back(recent);
+            if baseline.epoch_means.len() > BASELINE_WINDOW {
+                baseline.epoch_means.pop_front();
+            }
+        }
+        result.sort_by(|a, b| b.score.abs().partial_cmp(&a.score.abs()).unwrap());
+        result
+    }
+
+    /// Anomalous groups first, then the rest of `problem_groups` in their order
+    pub fn prioritize<'a>(
+        &self,
+        anomalies: &[Anomaly],
+        groups: &'a GroupManager,
+        problem_groups: Vec<&'a Group>,
+    ) -> Vec<&'a Group> {
+        let mut result: Vec<&Group> = anomalies
+            .iter()
+            .filter_map(|a| groups.group(&a.group))
+            .collect();
+        result.extend(
+            problem_groups
+                .into_iter()
+                .filter(|g| anomalies.iter().all(|a| a.group != g.hash())),
+        );
+        result
+    }
+}
+
+/// Mean of the paths added to the group since the baseline last saw it
+fn recent_mean(group: &Group, baseline: &mut Baseline) -> Option<f64> {
+    let count = group.stats.count();
+    let total = group.stats.mean().0 * count as f64;
+    let (new_count, new_total) = if baseline.last_generation == Some(group.generation()) {
+        // Paths dropped from the group since can make the count go down
+        (
+            count.saturating_sub(baseline.last_count),
+            total - baseline.last_total,
+        )
+    } else {
+        // The group was used and reset since, or is new
+        (count, total)
+    };
+    baseline.last_generation = Some(group.generation());
+    baseline.last_count = count;
+    baseline.last_total = total;
+    if new_count == 0 {
+        None
+    } else {
+        Some(new_total / new_count as f64)
+    }
+}
+
+fn score(method: AnomalyMethod, history: &VecDeque<f64>, value: f64) -> f64 {
+    let n = history.len() as f64;
+    let (center, spread) = match method {
+        AnomalyMethod::ZScore => {
+            let mean = history.iter().sum::<f64>() / n;
+            let variance = history.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / n;
+            (mean, variance.sqrt())
+        }
+        AnomalyMethod::Mad => {
+            let median = median(history.iter().cloned().collect());
+            let mad = median_abs_deviation(history, median);
+            (median, mad * MAD_SCALE)
+        }
+    };
+    if spread == 0.0 {
+        if value == center {
+            0.0
+        } else {
+            std::f64::INFINITY.copysign(value - center)
+        }
+    } else {
+        (value - center) / spread
+    }
+}
+
+fn median_abs_deviation(history: &VecDeque<f64>, median_value: f64) -> f64 {
+    median(history.iter().map(|x| (x - median_value).abs()).collect())
+}
+
+fn median(mut values: Vec<f64>) -> f64 {
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let mid = values.len() / 2;
+    if values.len() % 2 == 0 {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testutils::TraceGenerator;
+
+    fn group(generator: &mut TraceGenerator, paths: usize) -> Group {
+        Group::from_critical_paths(generator.critical_paths_of_shape(paths)).remove(0)
+    }
+
+    #[test]
+    fn recent_mean_starts_over_with_the_group() {
+        let mut generator = TraceGenerator::new(1);
+        generator.concurrency = 0.0;
+        let mut baseline = Baseline::default();
+        let mut g = group(&mut generator, 20);
+        let mean = recent_mean(&g, &mut baseline).unwrap();
+        assert!((mean - g.stats.mean().0).abs() < 1e-6 * mean);
+        assert_eq!(recent_mean(&g, &mut baseline), None);
+        g.used();
+        assert_eq!(recent_mean(&g, &mut baseline), None);
+        // Refilled with more paths than before it was reset
+        let refilled = group(&mut generator, 25);
+        let mean = recent_mean(&refilled, &mut baseline).unwrap();
+        assert!((mean - refilled.stats.mean().0).abs() < 1e-6 * mean);
+    }
+
+    #[test]
+    fn scores() {
+        let history: VecDeque<f64> = vec![10.0, 11.0, 9.0, 10.0, 100.0].into_iter().collect();
+        // The outlier in the history inflates the standard deviation, but not the MAD
+        assert!(score(AnomalyMethod::ZScore, &history, 20.0) < 1.0);
+        assert!(score(AnomalyMethod::Mad, &history, 20.0) > 3.0);
+        assert_eq!(score(AnomalyMethod::Mad, &history, 10.0), 0.0);
+        let flat: VecDeque<f64> = vec![5.0; 3].into_iter().collect();
+        assert_eq!(score(AnomalyMethod::ZScore, &flat, 6.0), std::f64::INFINITY);
+    }
+}
@@ -0,0 +1,372 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! A small HTTP API for inspecting and steering a running pythia_controller.
+//!
+//! The main loop publishes its groups into a shared `ControlState` each jiffy, and reads the
+//! pause flag, the budget and the blocked tracepoints back from it. Endpoints:
+//!
+//! * `GET /status` whether the loop is paused, the budget and the number of decisions so far
+//! * `GET /` a dashboard of the groups, hypotheses, enabled tracepoints and edge latencies,
+//!   refreshed from the endpoints below
+//! * `GET /groups` every active group with its latency statistics and SLO compliance
+//! * `GET /edges` the latest latencies of each edge of each group
+//! * `GET /hypotheses` for each request type, its groups split on the enabled tracepoints, and
+//!   how much of the variance the splits explain (see `hypothesis`)
+//! * `GET /tracepoints` the enabled tracepoints
+//! * `POST /tracepoints/disable` with a tracepoint id as the body: disables it for all request
+//!   types and keeps the search from enabling it again
+//! * `POST /pause`, `POST /resume` stop and restart making decisions; traces are still collected
+//! * `POST /budget` with a number as the body: tracepoints to enable per decision
+//! * `POST /reload` read the reloadable settings again (see `ReloadableSettings`), like SIGHUP
+//!
+//! Responses are JSON. Once the controller is shutting down, POSTs fail with 503.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+
+use futures::future::Future;
+use futures::stream::Stream;
+use hyper::service::service_fn;
+use hyper::Body;
+use hyper::Method;
+use hyper::Request;
+use hyper::Response;
+use hyper::Server;
+use hyper::StatusCode;
+use log::{error, info};
+use serde::Serialize;
+
+use crate::canary::CanaryComparison;
+use crate::controller::Controller;
+use crate::critical::Path;
+use crate::grouping::Group;
+use crate::grouping::GroupManager;
+use crate::hypothesis::HypothesisTree;
+use crate::slo::SloCompliance;
+use crate::trace::TracepointID;
+use crate::units::cv;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct GroupSummary {
+    pub hash: String,
+    pub request_type: String,
+    pub traces: usize,
+    pub mean_ms: f64,
+    pub variance_ns2: f64,
+    pub cv: f64,
+    /// None if its request type has no SLO
+    pub slo: Option<SloCompliance>,
+    /// None unless the group has requests both on canaries and elsewhere
+    pub canary: Option<CanaryComparison>,
+}
+
+impl GroupSummary {
+    pub fn from_group(g: &Group) -> Self {
+        GroupSummary {
+            hash: g.hash().to_string(),
+            request_type: g.parameterized_type().to_string(),
+            traces: g.trace_count(),
+            mean_ms: g.mean.as_millis(),
+            variance_ns2: g.variance.0,
+            cv: cv(g.mean, g.variance),
+            slo: None,
+            canary: CanaryComparison::from_group(g),
+        }
+    }
+}
+
+/// Latencies of an edge of a group, for the dashboard's sparklines
+#[derive(Serialize, Debug, Clone)]
+pub struct EdgeSeries {
+    pub from: String,
+    pub to: String,
+    /// Of the latest paths, oldest first
+    pub recent_ms: Vec<f64>,
+}
+
+/// Latencies kept for each edge in `ControlState::edges`
+const SPARKLINE_POINTS: usize = 60;
+
+static DASHBOARD: &str = include_str!("dashboard.html");
+
+/// State shared between the main loop and the API
+#[derive(Serialize, Debug)]
+pub struct ControlState {
+    pub paused: bool,
+    /// Tracepoints to enable per decision
+    pub budget: usize,
+    pub decisions: usize,
+    /// Set by the main loop when it got SIGTERM or SIGINT
+    pub shutting_down: bool,
+    #[serde(skip)]
+    pub groups: Vec<GroupSummary>,
+    /// Edges of each group in `groups`, by group hash
+    #[serde(skip)]
+    pub edges: HashMap<String, Vec<EdgeSeries>>,
+    /// The groups of each request type, not split yet
+    #[serde(skip)]
+    pub trees: BTreeMap<String, HypothesisTree>,
+    /// Disabled through the API; the search won't enable these again
+    #[serde(skip)]
+    pub blocked: HashSet<TracepointID>,
+    /// Set through the API, cleared by the main loop once the settings are read again
+    #[serde(skip)]
+    pub reload: bool,
+}
+
+impl ControlState {
+    pub fn new(budget: usize) -> Self {
+        ControlState {
+            paused: false,
+            budget,
+            decisions: 0,
+            shutting_down: false,
+            groups: Vec::new(),
+            edges: HashMap::new(),
+            trees: BTreeMap::new(),
+            blocked: HashSet::new(),
+            reload: false,
+        }
+    }
+
+    /// Called by the main loop after the groups of each application are updated
+    pub fn publish_groups(&mut self, groups: &[&GroupManager]) {
+        self.groups = groups
+            .iter()
+            .flat_map(|m| m.active_groups().into_iter().map(move |g| (m, g)))
+            .map(|(m, g)| GroupSummary {
+                slo: m.slo_compliance(g),
+                ..GroupSummary::from_group(g)
+            })
+            .collect();
+        self.groups
+            .sort_by(|a, b| b.variance_ns2.partial_cmp(&a.variance_ns2).unwrap());
+        self.edges.clear();
+        self.trees.clear();
+        for g in groups.iter().flat_map(|m| m.active_groups()) {
+            let series = g
+                .recent_durations(SPARKLINE_POINTS)
+                .into_iter()
+                .map(|(from, to, durations)| EdgeSeries {
+                    from,
+                    to,
+                    recent_ms: durations.iter().map(|d| d.as_secs_f64() * 1e3).collect(),
+                })
+                .collect();
+            self.edges.insert(g.hash().to_string(), series);
+            self.trees
+                .entry(g.request_type.to_string())
+                .or_insert_with(HypothesisTree::new)
+                .add_group(g);
+        }
+    }
+}
+
+/// Serve the API from a background thread
+pub fn start_api(
+    address: &str,
+    state: Arc<Mutex<ControlState>>,
+    controller: &'static Box<dyn Controller>,
+) {
+    let addr: SocketAddr = address.parse().expect("api_address should be ip:port");
+    thread::spawn(move || {
+        let new_service = move || {
+            let state = state.clone();
+            service_fn(move |req: Request<Body>| {
+                let state = state.clone();
+                let method = req.method().clone();
+                let path = req.uri().path().to_string();
+                req.into_body().concat2().map(move |body| {
+                    let body = String::from_utf8_lossy(&body).to_string();
+                    let (status, reply) =
+                        route(&method, &path, body.trim(), &state, controller.as_ref());
+                    let content_type = if path == "/" {
+                        "text/html; charset=utf-8"
+                    } else {
+                        "application/json"
+                    };
+                    Response::builder()
+                        .status(status)
+                        .header("Content-Type", content_type)
+                        .body(Body::from(reply))
+                        .unwrap()
+                })
+            })
+        };
+        let server = Server::bind(&addr)
+            .serve(new_service)
+            .map_err(|e| error!("Control API failed: {}", e));
+        info!("Control API listening on {}", addr);
+        hyper::rt::run(server);
+    });
+}
+
+fn route(
+    method: &Method,
+    path: &str,
+    body: &str,
+    state: &Mutex<ControlState>,
+    controller: &dyn Controller,
+) -> (StatusCode, String) {
+    if method == Method::POST && state.lock().unwrap().shutting_down {
+        return error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "the controller is shutting down",
+        );
+    }
+    match (method, path) {
+        (&Method::GET, "/") => (StatusCode::OK, DASHBOARD.to_string()),
+        (&Method::GET, "/status") => json(&*state.lock().unwrap()),
+        (&Method::GET, "/groups") => json(&state.lock().unwrap().groups),
+        (&Method::GET, "/edges") => json(&state.lock().unwrap().edges),
+        (&Method::GET, "/hypotheses") => {
+            let enabled = controller.enabled_tracepoints();
+            let trees = state.lock().unwrap().trees.clone();
+            json(
+                &trees
+                    .into_iter()
+                    .map(|(request_type, mut tree)| {
+                        // Tracepoints enabled for all request types split every tree
+                        let tracepoints: Vec<TracepointID> = enabled
+                            .iter()
+                            .filter(|(_, rt)| rt.is_none_or(|r| r.to_string() == request_type))
+                            .map(|(tp, _)| *tp)
+                            .collect();
+                        tree.split_on_each(&tracepoints);
+                        serde_json::json!({
+                            "request_type": request_type,
+                            "eta_squared": tree.eta_squared(),
+                            "tree": tree,
+                        })
+                    })
+                    .collect::<Vec<_>>(),
+            )
+        }
+        (&Method::GET, "/tracepoints") => json(
+            &controller
+                .enabled_tracepoints()
+                .iter()
+                .map(|(tp, rt)| (tp.to_string(), rt.map(|r| r.to_string())))
+                .collect::<Vec<_>>(),
+        ),
+        (&Method::POST, "/tracepoints/disable") => {
+            if body.is_empty() {
+                return error(StatusCode::BAD_REQUEST, "expected a tracepoint id");
+            }
+            let tp = TracepointID::from_str(body);
+            let to_disable = controller
+                .enabled_tracepoints()
+                .into_iter()
+                .filter(|p| p.0 == tp)
+                .collect::<Vec<_>>();
+            controller.disable(&to_disable);
+            state.lock().unwrap().blocked.insert(tp);
+            info!("Disabled {} through the API", body);
+            json(&to_disable.len())
+        }
+        (&Method::POST, "/pause") => {
+            state.lock().unwrap().paused = true;
+            json(&*state.lock().unwrap())
+        }
+        (&Method::POST, "/resume") => {
+            state.lock().unwrap().paused = false;
+            json(&*state.lock().unwrap())
+        }
+        (&Method::POST, "/budget") => match body.parse() {
+            Ok(budget) => {
+                state.lock().unwrap().budget = budget;
+                json(&*state.lock().unwrap())
+            }
+            Err(_) => error(StatusCode::BAD_REQUEST, "expected a number"),
+        },
+        (&Method::POST, "/reload") => {
+            state.lock().unwrap().reload = true;
+            json(&*state.lock().unwrap())
+        }
+        _ => error(StatusCode::NOT_FOUND, "no such endpoint"),
+    }
+}
+
+fn json<T: Serialize>(value: &T) -> (StatusCode, String) {
+    (StatusCode::OK, serde_json::to_string(value).unwrap())
+}
+
+fn error(status: StatusCode, message: &str) -> (StatusCode, String) {
+    (status, serde_json::json!({ "error": message }).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::controller::TestController;
+    use crate::testutils::TraceGenerator;
+
+    #[test]
+    fn pause_budget_and_disable() {
+        let state = Mutex::new(ControlState::new(3));
+        let controller = TestController::new();
+        let tp = TracepointID::from_str("api-test");
+        controller.enable(&vec![(tp, None)]);
+
+        let (status, _) = route(&Method::POST, "/pause", "", &state, &controller);
+        assert_eq!(status, StatusCode::OK);
+        assert!(state.lock().unwrap().paused);
+        let (status, _) = route(&Method::POST, "/budget", "x", &state, &controller);
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        route(&Method::POST, "/budget", "7", &state, &controller);
+        assert_eq!(state.lock().unwrap().budget, 7);
+        route(&Method::POST, "/reload", "", &state, &controller);
+        assert!(state.lock().unwrap().reload);
+
+        let (_, reply) = route(
+            &Method::POST,
+            "/tracepoints/disable",
+            "api-test",
+            &state,
+            &controller,
+        );
+        assert_eq!(reply, "1");
+        assert!(!controller.is_enabled(&(tp, None)));
+        assert!(state.lock().unwrap().blocked.contains(&tp));
+
+        state.lock().unwrap().shutting_down = true;
+        let (status, _) = route(&Method::POST, "/resume", "", &state, &controller);
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(state.lock().unwrap().paused);
+        let (status, _) = route(&Method::GET, "/status", "", &state, &controller);
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[test]
+    fn dashboard_endpoints() {
+        let mut generator = TraceGenerator::new(3);
+        generator.request_types = Vec::new();
+        let mut manager = GroupManager::new();
+        manager.update(&generator.critical_paths(6));
+        let mut state = ControlState::new(3);
+        state.publish_groups(&[&manager]);
+        assert_eq!(state.edges.len(), state.groups.len());
+        let state = Mutex::new(state);
+        let controller = TestController::new();
+
+        let (status, page) = route(&Method::GET, "/", "", &state, &controller);
+        assert_eq!(status, StatusCode::OK);
+        assert!(page.starts_with("<!DOCTYPE html>"));
+        let (_, reply) = route(&Method::GET, "/hypotheses", "", &state, &controller);
+        let hypotheses: serde_json::Value = serde_json::from_str(&reply).unwrap();
+        assert_eq!(hypotheses[0]["request_type"], "Unknown");
+        assert_eq!(hypotheses[0]["tree"]["nodes"].as_array().unwrap().len(), 1);
+    }
+}
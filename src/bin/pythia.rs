@@ -7,30 +7,134 @@ All rights reserved.
 */
 
 use clap::{App, Arg, SubCommand};
+use std::time::Duration;
 use std::time::Instant;
 
+use log::{debug, LevelFilter};
+use pythia::export::GraphStyle;
+use pythia::export::SpanFormat;
+use pythia::query::Filter;
 use pythia::{
-    disable_all, disable_tracepoint, dump_traces, enable_all, enable_skeleton, get_crit,
-    get_manifest, get_trace, group_folder, group_from_ids, manifest_from_folder, manifest_stats,
-    measure_search_space_feasibility, read_trace_file, recent_traces, show_config,
-    show_key_value_pairs, show_manifest,
+    agents_status, apply_profile, backfill, check_config, coverage, disable_all,
+    disable_tracepoint, dump_traces, enable_all, enable_from_file, enable_skeleton, export_trace,
+    flamegraph, get_crit, get_manifest, get_trace, group_folder, group_from_ids, group_report,
+    instrumentation_impact, list_profiles, list_tracepoints, manifest_from_folder, manifest_stats,
+    measure_search_space_feasibility, pipeline, read_trace_file, recent_traces, remap_manifest,
+    restore_snapshot, save_profile, save_snapshot, set_matching, show_audit_log, show_config,
+    show_key_value_pairs, show_lineage, show_manifest, show_retained_traces,
+    show_variance_explained, slice_trace, watch_trace, OutputFormat,
 };
+use pythia_common::init_logging;
 
 fn main() {
+    init_logging(LevelFilter::Info);
     let now = Instant::now();
     let matches = App::new("Pythia")
         .version("1.0")
         .author("Emre Ates <ates@bu.edu>")
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .takes_value(true)
+                .possible_values(&["text", "json"])
+                .default_value("text")
+                .help("Print results as text or JSON; goes before the subcommand"),
+        )
         .subcommand(
             SubCommand::with_name("manifest")
-                .arg(Arg::with_name("manifest-file").required(true).index(1))
-                .arg(Arg::with_name("overwrite").long("overwrite")),
+                .arg(
+                    Arg::with_name("manifest-file")
+                        .required_unless("retry-failed")
+                        .index(1),
+                )
+                .arg(Arg::with_name("overwrite").long("overwrite"))
+                .arg(
+                    Arg::with_name("retry-failed")
+                        .long("retry-failed")
+                        .takes_value(true)
+                        .help("Add the traces in this <manifest-file>.failed file to the manifest"),
+                ),
         )
         .subcommand(
             SubCommand::with_name("get-trace")
                 .arg(Arg::with_name("trace-id").required(true).index(1))
                 .arg(Arg::with_name("to-file").long("to-file"))
-                .arg(Arg::with_name("prune").long("prune")),
+                .arg(Arg::with_name("prune").long("prune"))
+                .arg(
+                    Arg::with_name("filter")
+                        .long("filter")
+                        .takes_value(true)
+                        .help("Keep only matching events, e.g., \"host=cp-1 AND request.method=POST\""),
+                )
+                .arg(
+                    Arg::with_name("dot-style")
+                        .long("dot-style")
+                        .takes_value(true)
+                        .help("Comma separated: host, duration, variance, collapse, graphml"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("watch-trace")
+                .arg(Arg::with_name("trace-id").required(true).index(1))
+                .arg(
+                    Arg::with_name("interval")
+                        .long("interval")
+                        .takes_value(true)
+                        .default_value("3")
+                        .help("Seconds between polls"),
+                )
+                .arg(
+                    Arg::with_name("stable")
+                        .long("stable")
+                        .takes_value(true)
+                        .default_value("5")
+                        .help("Stop after this many polls without changes"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("slice-trace")
+                .arg(Arg::with_name("trace-id").required(true).index(1))
+                .arg(
+                    Arg::with_name("from")
+                        .required(true)
+                        .index(2)
+                        .help("Tracepoint, or span id if <to> is not given"),
+                )
+                .arg(Arg::with_name("to").index(3).help("Tracepoint"))
+                .arg(
+                    Arg::with_name("dot-style")
+                        .long("dot-style")
+                        .takes_value(true)
+                        .help("Comma separated: host, duration, variance, collapse, graphml"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export-trace")
+                .arg(Arg::with_name("trace-id").required(true).index(1))
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["jaeger", "chrome", "otlp"])
+                        .default_value("jaeger"),
+                )
+                .arg(Arg::with_name("to-file").long("to-file")),
+        )
+        .subcommand(
+            SubCommand::with_name("flamegraph")
+                .arg(
+                    Arg::with_name("id")
+                        .required(true)
+                        .index(1)
+                        .help("Trace id, or hash of a group with retained traces"),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["collapsed", "svg"])
+                        .default_value("collapsed"),
+                ),
         )
         .subcommand(
             SubCommand::with_name("manifest-folder")
@@ -40,6 +144,26 @@ fn main() {
             SubCommand::with_name("group-folder")
                 .arg(Arg::with_name("trace-folder").required(true).index(1)),
         )
+        .subcommand(
+            SubCommand::with_name("group-report")
+                .arg(Arg::with_name("trace-folder").required(true).index(1))
+                .arg(
+                    Arg::with_name("group")
+                        .long("group")
+                        .takes_value(true)
+                        .help("Group hash; defaults to the group with the highest variance"),
+                )
+                .arg(
+                    Arg::with_name("dot-style")
+                        .long("dot-style")
+                        .takes_value(true)
+                        .help("Draw the group instead; comma separated: host, duration, variance, graphml"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("variance-explained")
+                .arg(Arg::with_name("trace-folder").required(true).index(1)),
+        )
         .subcommand(
             SubCommand::with_name("group-ids")
                 .arg(Arg::with_name("traceid-file").required(true).index(1)),
@@ -50,7 +174,17 @@ fn main() {
         )
         .subcommand(
             SubCommand::with_name("dump-traces")
-                .arg(Arg::with_name("trace-file").required(true).index(1)),
+                .arg(
+                    Arg::with_name("trace-file")
+                        .required_unless("retry-failed")
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("retry-failed")
+                        .long("retry-failed")
+                        .takes_value(true)
+                        .help("Only read the IDs in this <trace-file>.failed file"),
+                ),
         )
         .subcommand(
             SubCommand::with_name("get-crit")
@@ -60,7 +194,15 @@ fn main() {
             SubCommand::with_name("key-value")
                 .arg(Arg::with_name("trace-id").required(true).index(1)),
         )
-        .subcommand(SubCommand::with_name("disable-all"))
+        .subcommand(
+            SubCommand::with_name("disable-all").arg(
+                Arg::with_name("request-type")
+                    .long("request-type")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1),
+            ),
+        )
         .subcommand(SubCommand::with_name("recent-traces"))
         .subcommand(
             SubCommand::with_name("disable-tracepoint")
@@ -74,51 +216,221 @@ fn main() {
             SubCommand::with_name("show-manifest")
                 .arg(Arg::with_name("request-type").required(true).index(1)),
         )
-        .subcommand(SubCommand::with_name("enable-all"))
+        .subcommand(
+            SubCommand::with_name("enable-all").arg(
+                Arg::with_name("request-type")
+                    .long("request-type")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1),
+            ),
+        )
+        .subcommand(
+            SubCommand::with_name("enable-matching").arg(Arg::with_name("pattern").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("disable-matching").arg(Arg::with_name("pattern").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("enable-from-file").arg(Arg::with_name("file").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("profile")
+                .subcommand(
+                    SubCommand::with_name("save").arg(Arg::with_name("name").required(true)),
+                )
+                .subcommand(
+                    SubCommand::with_name("apply").arg(Arg::with_name("name").required(true)),
+                )
+                .subcommand(SubCommand::with_name("list")),
+        )
+        .subcommand(
+            SubCommand::with_name("snapshot")
+                .subcommand(
+                    SubCommand::with_name("save").arg(Arg::with_name("name").required(true)),
+                )
+                .subcommand(
+                    SubCommand::with_name("restore").arg(Arg::with_name("name").required(true)),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("tracepoints")
+                .arg(Arg::with_name("request-type").long("request-type").takes_value(true))
+                .arg(Arg::with_name("grep").long("grep").takes_value(true))
+                .arg(Arg::with_name("enabled-only").long("enabled-only")),
+        )
         .subcommand(SubCommand::with_name("enable-skeleton"))
+        .subcommand(SubCommand::with_name("coverage"))
         .subcommand(SubCommand::with_name("show-config"))
+        .subcommand(
+            SubCommand::with_name("check-config").arg(Arg::with_name("redis").long("redis")),
+        )
+        .subcommand(SubCommand::with_name("agents-status"))
+        .subcommand(SubCommand::with_name("instrumentation-impact"))
+        .subcommand(
+            SubCommand::with_name("audit")
+                .arg(Arg::with_name("group").long("group").takes_value(true))
+                .arg(Arg::with_name("tracepoint").long("tracepoint").takes_value(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("lineage").arg(Arg::with_name("group-hash").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("pipeline")
+                .arg(
+                    Arg::with_name("input")
+                        .long("input")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("cycles")
+                        .long("cycles")
+                        .takes_value(true)
+                        .default_value("10"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("backfill")
+                .arg(
+                    Arg::with_name("input")
+                        .long("input")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .takes_value(true)
+                        .default_value("backfill"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("remap-manifest")
+                .arg(
+                    Arg::with_name("old")
+                        .long("old")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("trace-ids")
+                        .long("trace-ids")
+                        .takes_value(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("retained-traces")
+                .arg(Arg::with_name("group").required(true).index(1))
+                .arg(
+                    Arg::with_name("show")
+                        .long("show")
+                        .help("Print the traces instead of just listing them"),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("manifest-stats")
                 .arg(Arg::with_name("manifest-file").required(true).index(1)),
         )
         .get_matches();
+    let format = OutputFormat::from_str(matches.value_of("output").unwrap());
     match matches.subcommand() {
         ("manifest", Some(matches)) => {
             get_manifest(
-                matches.value_of("manifest-file").unwrap(),
+                matches.value_of("manifest-file"),
                 matches.occurrences_of("overwrite") > 0,
+                matches.value_of("retry-failed"),
             );
         }
         ("manifest-folder", Some(matches)) => {
             manifest_from_folder(matches.value_of("trace-folder").unwrap());
         }
         ("group-folder", Some(matches)) => {
-            group_folder(matches.value_of("trace-folder").unwrap());
+            group_folder(matches.value_of("trace-folder").unwrap(), format);
+        }
+        ("group-report", Some(matches)) => {
+            group_report(
+                matches.value_of("trace-folder").unwrap(),
+                matches.value_of("group"),
+                matches.value_of("dot-style").map(GraphStyle::from_str),
+                format,
+            );
+        }
+        ("variance-explained", Some(matches)) => {
+            show_variance_explained(matches.value_of("trace-folder").unwrap(), format);
         }
         ("group-ids", Some(matches)) => {
-            group_from_ids(matches.value_of("traceid-file").unwrap());
+            group_from_ids(matches.value_of("traceid-file").unwrap(), format);
         }
         ("read-file", Some(matches)) => {
-            read_trace_file(matches.value_of("trace-file").unwrap());
+            read_trace_file(matches.value_of("trace-file").unwrap(), format);
         }
         ("try-manifest", Some(matches)) => {
-            measure_search_space_feasibility(matches.value_of("trace-file").unwrap());
+            measure_search_space_feasibility(matches.value_of("trace-file").unwrap(), format);
         }
         ("show-manifest", Some(matches)) => {
-            show_manifest(matches.value_of("request-type").unwrap());
+            show_manifest(matches.value_of("request-type").unwrap(), format);
         }
         ("dump-traces", Some(matches)) => {
-            dump_traces(matches.value_of("trace-file").unwrap());
+            dump_traces(
+                matches.value_of("trace-file"),
+                matches.value_of("retry-failed"),
+            );
         }
         ("get-trace", Some(matches)) => {
             get_trace(
                 matches.value_of("trace-id").unwrap(),
                 matches.occurrences_of("to-file") > 0,
                 matches.occurrences_of("prune") > 0,
+                matches
+                    .value_of("filter")
+                    .map(|f| Filter::parse(f).expect("Invalid filter")),
+                matches.value_of("dot-style").map(GraphStyle::from_str),
+                format,
+            );
+        }
+        ("watch-trace", Some(matches)) => {
+            watch_trace(
+                matches.value_of("trace-id").unwrap(),
+                Duration::from_secs(
+                    matches
+                        .value_of("interval")
+                        .unwrap()
+                        .parse()
+                        .expect("interval should be a number"),
+                ),
+                matches
+                    .value_of("stable")
+                    .unwrap()
+                    .parse()
+                    .expect("stable should be a number"),
+            );
+        }
+        ("slice-trace", Some(matches)) => {
+            slice_trace(
+                matches.value_of("trace-id").unwrap(),
+                matches.value_of("from").unwrap(),
+                matches.value_of("to"),
+                matches.value_of("dot-style").map(GraphStyle::from_str),
+                format,
+            );
+        }
+        ("export-trace", Some(matches)) => {
+            export_trace(
+                matches.value_of("trace-id").unwrap(),
+                SpanFormat::from_str(matches.value_of("format").unwrap()),
+                matches.occurrences_of("to-file") > 0,
+            );
+        }
+        ("flamegraph", Some(matches)) => {
+            flamegraph(
+                matches.value_of("id").unwrap(),
+                matches.value_of("format") == Some("svg"),
             );
         }
         ("get-crit", Some(matches)) => {
-            get_crit(matches.value_of("trace-id").unwrap());
+            get_crit(matches.value_of("trace-id").unwrap(), format);
         }
         ("disable-tracepoint", Some(matches)) => {
             disable_tracepoint(matches.value_of("tracepoint-id").unwrap());
@@ -126,25 +438,112 @@ fn main() {
         ("key-value", Some(matches)) => {
             show_key_value_pairs(matches.value_of("trace-id").unwrap());
         }
-        ("disable-all", Some(_)) => {
-            disable_all();
+        ("disable-all", Some(matches)) => {
+            let request_types: Vec<&str> = matches
+                .values_of("request-type")
+                .map(|v| v.collect())
+                .unwrap_or_default();
+            disable_all(&request_types);
+        }
+        ("enable-all", Some(matches)) => {
+            let request_types: Vec<&str> = matches
+                .values_of("request-type")
+                .map(|v| v.collect())
+                .unwrap_or_default();
+            enable_all(&request_types);
+        }
+        ("enable-matching", Some(matches)) => {
+            set_matching(matches.value_of("pattern").unwrap(), true);
         }
-        ("enable-all", Some(_)) => {
-            enable_all();
+        ("disable-matching", Some(matches)) => {
+            set_matching(matches.value_of("pattern").unwrap(), false);
+        }
+        ("enable-from-file", Some(matches)) => {
+            enable_from_file(matches.value_of("file").unwrap());
+        }
+        ("profile", Some(matches)) => match matches.subcommand() {
+            ("save", Some(matches)) => save_profile(matches.value_of("name").unwrap()),
+            ("apply", Some(matches)) => apply_profile(matches.value_of("name").unwrap()),
+            ("list", Some(_)) => list_profiles(format),
+            _ => panic!("Must provide a profile subcommand: save, apply or list"),
+        },
+        ("snapshot", Some(matches)) => match matches.subcommand() {
+            ("save", Some(matches)) => save_snapshot(matches.value_of("name").unwrap()),
+            ("restore", Some(matches)) => restore_snapshot(matches.value_of("name").unwrap()),
+            _ => panic!("Must provide a snapshot subcommand: save or restore"),
+        },
+        ("tracepoints", Some(matches)) => {
+            list_tracepoints(
+                matches.value_of("request-type"),
+                matches.value_of("grep"),
+                matches.is_present("enabled-only"),
+                format,
+            );
         }
         ("enable-skeleton", Some(_)) => {
             enable_skeleton();
         }
+        ("coverage", Some(_)) => {
+            coverage(format);
+        }
         ("recent-traces", Some(_)) => {
-            recent_traces();
+            recent_traces(format);
         }
         ("show-config", Some(_)) => {
             show_config();
         }
+        ("check-config", Some(matches)) => {
+            check_config(matches.is_present("redis"));
+        }
+        ("agents-status", Some(_)) => {
+            agents_status(format);
+        }
+        ("instrumentation-impact", Some(_)) => {
+            instrumentation_impact(format);
+        }
+        ("audit", Some(matches)) => {
+            show_audit_log(
+                matches.value_of("group"),
+                matches.value_of("tracepoint"),
+                format,
+            );
+        }
+        ("lineage", Some(matches)) => {
+            show_lineage(matches.value_of("group-hash").unwrap(), format);
+        }
+        ("pipeline", Some(matches)) => {
+            pipeline(
+                matches.value_of("input").unwrap(),
+                matches
+                    .value_of("cycles")
+                    .unwrap()
+                    .parse()
+                    .expect("cycles should be a number"),
+            );
+        }
+        ("backfill", Some(matches)) => {
+            backfill(
+                matches.value_of("input").unwrap(),
+                matches.value_of("output").unwrap(),
+            );
+        }
+        ("remap-manifest", Some(matches)) => {
+            remap_manifest(
+                matches.value_of("old").unwrap(),
+                matches.value_of("trace-ids").unwrap(),
+            );
+        }
+        ("retained-traces", Some(matches)) => {
+            show_retained_traces(
+                matches.value_of("group").unwrap(),
+                matches.occurrences_of("show") > 0,
+                format,
+            );
+        }
         ("manifest-stats", Some(matches)) => {
-            manifest_stats(matches.value_of("manifest-file").unwrap());
+            manifest_stats(matches.value_of("manifest-file").unwrap(), format);
         }
         _ => panic!("Must provide a subcommand, see --help for commands"),
     };
-    eprintln!("Overall Pythia took {}us", now.elapsed().as_micros());
+    debug!("Overall Pythia took {}us", now.elapsed().as_micros());
 }
@@ -13,63 +13,392 @@ extern crate lazy_static;
 use std::collections::HashSet;
 use std::fs::File;
 use std::io::prelude::*;
+use std::path::Path as FilePath;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
 use std::sync::mpsc::channel;
-use std::thread::sleep;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::sync::Mutex;
 use std::time::Duration;
-use std::time::Instant;
 
+use log::{debug, error, info, warn, LevelFilter};
+use signal_hook::consts::SIGHUP;
+use signal_hook::consts::SIGINT;
+use signal_hook::consts::SIGTERM;
 use threadpool::ThreadPool;
 
+use pythia::anomaly::AnomalyDetector;
+use pythia::api::start_api;
+use pythia::api::ControlState;
 use pythia::budget::BudgetManager;
+use pythia::canary::Canary;
+use pythia::canary::CanaryComparison;
+use pythia::canary::CanaryVerdict;
+use pythia::canary::HostScope;
+use pythia::clock::Clock;
+use pythia::clock::SimulatedClock;
+use pythia::clock::SystemClock;
 use pythia::controller::controller_from_settings;
+use pythia::controller::CappedController;
 use pythia::controller::Controller;
+use pythia::controller::DependentController;
+use pythia::controller::FederatedController;
 use pythia::critical::CriticalPath;
 use pythia::critical::Path;
+use pythia::deadline::CycleDeadline;
+use pythia::deadline::SkippedWork;
+use pythia::epoch::read_epochs;
+use pythia::epoch::EpochTracker;
+use pythia::grouping::Group;
+use pythia::grouping::GroupLimits;
 use pythia::grouping::GroupManager;
+use pythia::grouping::{ProblemSelector, DEFAULT_CV_THRESHOLD};
+use pythia::impact::compare_epochs;
+use pythia::lineage::Lineage;
+use pythia::manifest::CostModel;
 use pythia::manifest::Manifest;
+use pythia::manifest::TracepointDependencies;
+use pythia::profile::Profile;
 use pythia::reader::reader_from_settings;
+use pythia::reader::TraceStitcher;
+use pythia::report::RunReport;
+use pythia::retention::TraceRetention;
 use pythia::search::get_strategy;
+use pythia::search::ResultsDB;
+use pythia::search::SearchStrategy;
+use pythia::search::Trial;
+use pythia::settings::settings_path;
+use pythia::settings::ManifestMethod;
+use pythia::settings::ReloadableSettings;
 use pythia::settings::Settings;
+use pythia::stopping::StopReason;
+use pythia::trace::Trace;
 use pythia::trace::TracepointID;
+use pythia_common::init_logging;
+use pythia_common::RequestType;
 
 // These are static because search strategy expects static references.
 lazy_static! {
     static ref SETTINGS: Settings = Settings::read();
-    static ref CONTROLLER: Box<dyn Controller> = controller_from_settings(&SETTINGS);
-    static ref MANIFEST: Manifest = Manifest::from_file(&SETTINGS.manifest_file.as_path())
-        .expect("Couldn't read manifest from cache");
+    // When replaying an archive, time only moves as the loop sleeps
+    static ref CLOCK: Arc<dyn Clock> = match SETTINGS.replay_dir {
+        Some(_) => Arc::new(SimulatedClock::new()),
+        None => Arc::new(SystemClock),
+    };
+    // Every application diagnosed, SETTINGS.application first, and their manifests
+    static ref APPLICATIONS: Vec<Settings> = SETTINGS.applications();
+    static ref MANIFESTS: Vec<Manifest> = APPLICATIONS
+        .iter()
+        .map(|s| match Manifest::from_file(&s.manifest_file.as_path()) {
+            Some(m) => m,
+            None if s.manifest_method == ManifestMethod::Incremental => Manifest::new(),
+            None => panic!("Couldn't read manifest from cache"),
+        })
+        .collect();
+    // Tracepoints the cap leaves out: the skeleton enabled at start, and the ones incremental
+    // manifests learn
+    static ref SKELETON: Arc<Mutex<HashSet<TracepointID>>> = Arc::new(Mutex::new(HashSet::new()));
+    // The cap is shared by all applications, and counts the tracepoints enabled as
+    // prerequisites of others
+    static ref CONTROLLER: Box<dyn Controller> = Box::new(DependentController::new(
+        Box::new(CappedController::with_shared_skeleton(
+            application_controller(),
+            SETTINGS.max_enabled_tracepoints,
+            SKELETON.clone(),
+        )),
+        all_dependencies(),
+    ));
+}
+
+fn all_dependencies() -> TracepointDependencies {
+    let mut result = TracepointDependencies::default();
+    for manifest in MANIFESTS.iter() {
+        result.extend(manifest.dependencies());
+    }
+    result
+}
+
+fn application_controller() -> Box<dyn Controller> {
+    if APPLICATIONS.len() == 1 {
+        return controller_from_settings(&SETTINGS);
+    }
+    Box::new(FederatedController::new(
+        APPLICATIONS
+            .iter()
+            .zip(MANIFESTS.iter())
+            .map(|(s, m)| (controller_from_settings(s), m.all_tracepoints()))
+            .collect(),
+    ))
 }
 
 fn reset_reader() {
-    let mut reader = reader_from_settings(&SETTINGS);
-    reader.reset_state();
+    for settings in APPLICATIONS.iter() {
+        let mut reader = reader_from_settings(settings);
+        reader.reset_state();
+    }
+}
+
+/// Find the critical paths of a trace and hand both to the main loop
+fn send_paths(tx: &Sender<(usize, Trace, Vec<CriticalPath>)>, app: usize, mut trace: Trace) {
+    let mut paths =
+        CriticalPath::top_k_from_trace(&trace, SETTINGS.critical_paths_per_trace).unwrap();
+    classify_unknown(app, &mut trace, &mut paths);
+    tx.send((app, trace, paths))
+        .expect("channel will be there waiting for the pool");
+}
+
+/// Traces without a request type get the one their critical path fits best in the manifest
+fn classify_unknown(app: usize, trace: &mut Trace, paths: &mut [CriticalPath]) {
+    let min_confidence = match SETTINGS.classify_min_confidence {
+        Some(c) if trace.request_type == RequestType::Unknown => c,
+        _ => return,
+    };
+    // Paths that can't be grouped (e.g., they branch) can't be classified either
+    let group = match paths.first().and_then(|path| {
+        Group::from_critical_paths(vec![path.clone()])
+            .into_iter()
+            .next()
+    }) {
+        Some(group) => group,
+        None => return,
+    };
+    match MANIFESTS[app].classify(&group) {
+        Some(c) if c.confidence >= min_confidence => {
+            debug!(
+                trace_id:% = trace.base_id;
+                "Classified as {} with confidence {:.2}", c.request_type, c.confidence
+            );
+            trace.request_type = c.request_type;
+            for path in paths.iter_mut() {
+                path.request_type = c.request_type;
+            }
+        }
+        Some(c) => debug!(
+            trace_id:% = trace.base_id;
+            "Not confident enough it is {}: {:.2}", c.request_type, c.confidence
+        ),
+        None => {}
+    }
+}
+
+/// What the loop keeps for each application
+struct Application {
+    settings: &'static Settings,
+    strategy: Box<dyn SearchStrategy>,
+    groups: GroupManager,
+    selector: ProblemSelector,
+    /// The manifest the application started with
+    initial_manifest: &'static Manifest,
+    /// With an incremental manifest, the copy that grows with the traces received
+    manifest: Option<Manifest>,
+    /// Paths added to `manifest` that the search strategy doesn't know about yet
+    new_paths: usize,
+}
+
+impl Application {
+    fn new(settings: &'static Settings, manifest: &'static Manifest) -> Self {
+        let mut groups = GroupManager::new();
+        groups.group_by_request_params(SETTINGS.group_by_request_params);
+        groups.set_grouping_mode(SETTINGS.grouping_mode);
+        groups.partition_by(SETTINGS.group_partition_key.clone());
+        groups.partition_by_filter(SETTINGS.group_partition_filter.clone());
+        groups.set_limits(GroupLimits::from_settings(&SETTINGS));
+        groups.set_clock(CLOCK.clone());
+        groups.set_slos(SETTINGS.slos.clone());
+        Application {
+            settings,
+            strategy: get_strategy(settings, Arc::new(manifest.clone()), &CONTROLLER),
+            groups,
+            selector: ProblemSelector::CV(DEFAULT_CV_THRESHOLD),
+            initial_manifest: manifest,
+            manifest: match settings.manifest_method {
+                ManifestMethod::Offline => None,
+                ManifestMethod::Incremental => Some(manifest.clone()),
+            },
+            new_paths: 0,
+        }
+    }
+
+    fn current_manifest(&self) -> &Manifest {
+        self.manifest.as_ref().unwrap_or(self.initial_manifest)
+    }
+
+    fn costs(&self) -> &CostModel {
+        &self.current_manifest().costs
+    }
+
+    fn observe(&mut self, trace: &Trace) {
+        if let Some(manifest) = self.manifest.as_mut() {
+            self.new_paths += manifest.add_trace(trace, self.settings.path_budget);
+        }
+    }
+
+    /// Writes the grown manifest back to its file and gives it to a new search strategy, and
+    /// exempts its new skeleton tracepoints from the cap. Returns the number of paths added
+    /// since the last time.
+    fn update_manifest(&mut self) -> usize {
+        let manifest = match self.manifest {
+            Some(ref m) if self.new_paths > 0 => m,
+            _ => return 0,
+        };
+        manifest.to_file(self.settings.manifest_file.as_path());
+        let initial: HashSet<TracepointID> = self.initial_manifest.skeleton().into_iter().collect();
+        SKELETON.lock().unwrap().extend(
+            manifest
+                .skeleton()
+                .into_iter()
+                .filter(|tp| !initial.contains(tp)),
+        );
+        self.strategy = get_strategy(self.settings, Arc::new(manifest.clone()), &CONTROLLER);
+        std::mem::replace(&mut self.new_paths, 0)
+    }
+}
+
+/// Take one from each list in turn, so every application gets a share of the budget
+fn interleave<T>(lists: Vec<Vec<T>>) -> Vec<T> {
+    let mut iters: Vec<_> = lists.into_iter().map(|l| l.into_iter()).collect();
+    let mut result = Vec::new();
+    loop {
+        let before = result.len();
+        result.extend(iters.iter_mut().filter_map(|i| i.next()));
+        if result.len() == before {
+            return result;
+        }
+    }
+}
+
+/// Write the report of the run next to the output file
+fn write_report(run_report: &RunReport, output: &str, reason: &str, apps: &[Application]) {
+    let groups: Vec<&GroupManager> = apps.iter().map(|a| &a.groups).collect();
+    let report = run_report.finish(reason, &groups, CONTROLLER.enabled_tracepoints().len());
+    match report.write(FilePath::new(output)) {
+        Ok((json, markdown)) => info!("Wrote the run report to {:?} and {:?}", json, markdown),
+        Err(e) => error!("Could not write the run report: {}", e),
+    }
 }
 
 /// Main Pythia function that runs in a loop and makes decisions
 fn main() {
-    let now = Instant::now();
-    let strategy = get_strategy(&SETTINGS, &MANIFEST, &CONTROLLER);
+    init_logging(LevelFilter::Info);
+    if let Err(problems) = SETTINGS.validate(false) {
+        for p in &problems {
+            error!("Invalid setting in {:?}: {}", settings_path(), p);
+        }
+        std::process::exit(1);
+    }
+    let now = CLOCK.now();
+    let mut apps: Vec<Application> = APPLICATIONS
+        .iter()
+        .zip(MANIFESTS.iter())
+        .map(|(s, m)| Application::new(s, m))
+        .collect();
     let mut budget_manager = BudgetManager::from_settings(&SETTINGS);
-    let mut groups = GroupManager::new();
-    let mut last_decision = Instant::now();
-    let mut last_gc = Instant::now();
+    budget_manager.set_clock(CLOCK.clone());
+    let mut run_report = RunReport::with_clock(CLOCK.clone());
+    let mut retention = TraceRetention::from_settings(&SETTINGS);
+    retention.set_clock(CLOCK.clone());
+    let mut epochs = EpochTracker::from_settings(&SETTINGS);
+    epochs.set_clock(CLOCK.clone());
+    let mut previous_epoch = None;
+    let results = SETTINGS.results_db.as_ref().and_then(|path| {
+        ResultsDB::open(path)
+            .map_err(|e| warn!("Not recording results in {:?}: {}", path, e))
+            .ok()
+    });
+    // Tracepoints enabled at the last decision, judged at the next one
+    let mut trials: Vec<(usize, Trial)> = Vec::new();
+    // Continue the lineage of the last run, so groups diagnosed before a restart keep their trees
+    let mut lineage = match &SETTINGS.lineage_file {
+        Some(path) if path.exists() => Lineage::load(path).unwrap_or_else(|e| {
+            warn!("Could not read lineage {:?}, starting anew: {}", path, e);
+            Lineage::new()
+        }),
+        _ => Lineage::new(),
+    };
+    let mut reloadable = SETTINGS.reloadable.clone();
+    let mut anomaly_detector = reloadable
+        .anomaly_method()
+        .map(|method| AnomalyDetector::new(method, reloadable.anomaly_threshold));
+    let reload = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(SIGHUP, reload.clone()).expect("Could not handle SIGHUP");
+    // The main loop finishes up after the first SIGTERM or SIGINT; a second one exits right away
+    let shutdown = Arc::new(AtomicBool::new(false));
+    for &signal in &[SIGTERM, SIGINT] {
+        signal_hook::flag::register_conditional_shutdown(signal, 1, shutdown.clone())
+            .and_then(|_| signal_hook::flag::register(signal, shutdown.clone()))
+            .expect("Could not handle SIGTERM and SIGINT");
+    }
+    let mut last_decision = CLOCK.now();
+    // Traces received soon after this are marked as transition traces
+    let mut last_change = CLOCK.now();
+    let mut last_gc = CLOCK.now();
+    let mut last_reconcile = CLOCK.now();
 
     let mut quit_in = -1;
-    let mut targets = HashSet::new();
-    // The targets are set here. Any typos, and Pythia won't stop.
-    targets.insert(TracepointID::from_str("nova/usr/local/lib/python3.6/dist-packages/nova/compute/manager.py:1859:nova.compute.manager.ComputeManager._update_scheduler_instance_info"));
-    eprintln!("Targets are {:?}", targets);
+    let mut decision_cycles = 0;
+    let control = Arc::new(Mutex::new(ControlState::new(
+        reloadable.tracepoints_per_epoch,
+    )));
+    if let Some(ref address) = SETTINGS.api_address {
+        start_api(address, control.clone(), &CONTROLLER);
+    }
+    // The targets are set in controller.toml. Any typos, and Pythia won't stop.
+    let mut targets: HashSet<TracepointID> =
+        SETTINGS.stopping_condition.target_ids().into_iter().collect();
+    info!("Targets are {:?}", targets);
 
     let filename = std::env::args().nth(1).unwrap();
-    eprintln!("Printing results to {}", filename);
-    let mut output_file = File::create(filename).unwrap();
+    info!("Printing results to {}", filename);
+    let mut output_file = File::create(&filename).unwrap();
     writeln!(output_file, "{:?}", *SETTINGS).ok();
     writeln!(output_file, "Targets: {:?}", targets).ok();
 
-    // Enable skeleton
-    CONTROLLER.disable_all();
-    let to_enable = MANIFEST
-        .skeleton()
+    // Decisions go to the canaries first, if there are any
+    let canary = Canary::from_settings(&SETTINGS);
+    let scope = match canary {
+        Some(_) => HostScope::Canary,
+        None => HostScope::All,
+    };
+
+    // Enable skeleton. An incremental manifest that starts empty learns the skeleton from
+    // whatever the application traces by default, so that is left on, and only what earlier
+    // runs enabled is turned off.
+    let mut skeleton = Vec::new();
+    for manifest in MANIFESTS.iter() {
+        match SETTINGS.skeleton_size {
+            Some(size) => {
+                let reduction = manifest.minimal_skeleton(size);
+                info!("{}", reduction);
+                writeln!(output_file, "{}", reduction).ok();
+                skeleton.extend(reduction.kept);
+            }
+            None => skeleton.extend(manifest.skeleton()),
+        }
+    }
+    // The skeleton tracepoints the reduction dropped count against the cap like any other
+    *SKELETON.lock().unwrap() = skeleton.iter().cloned().collect();
+    if skeleton.is_empty() && SETTINGS.manifest_method == ManifestMethod::Incremental {
+        info!("Manifest is empty, learning the skeleton from the traces");
+        match SETTINGS.epoch_dir {
+            Some(ref dir) => {
+                let earlier: HashSet<_> = read_epochs(dir)
+                    .into_iter()
+                    .flat_map(|epoch| epoch.enabled)
+                    .collect();
+                let earlier: Vec<_> = earlier.into_iter().collect();
+                info!(
+                    "Disabling {} tracepoints earlier runs enabled",
+                    earlier.len()
+                );
+                CONTROLLER.disable(&earlier);
+            }
+            None => warn!("Without epoch_dir, tracepoints earlier runs enabled stay on"),
+        }
+    } else {
+        CONTROLLER.disable_all();
+    }
+    let to_enable = skeleton
         .iter()
         .map(|a| {
             if !targets.get(a).is_none() {
@@ -87,49 +416,203 @@ fn main() {
     CONTROLLER.enable(&to_enable);
     writeln!(output_file, "Enabled {}", to_enable.len()).ok();
     writeln!(output_file, "Enabled {:?}", to_enable).ok();
+    if let Some(ref name) = SETTINGS.start_profile {
+        let profile = Profile::load(&SETTINGS.profile_dir, name)
+            .unwrap_or_else(|e| panic!("Could not load start_profile {}: {}", name, e));
+        CONTROLLER.enable(&profile.tracepoints);
+        writeln!(output_file, "Enabled profile {}: {:?}", name, profile.tracepoints).ok();
+        info!(
+            "Enabled {} tracepoints of profile {}",
+            profile.tracepoints.len(),
+            name
+        );
+    }
     reset_reader();
+    epochs.advance(CONTROLLER.enabled_tracepoints());
 
-    println!("Enabled following tracepoints: {:?}", to_enable);
+    info!("Enabled following tracepoints: {:?}", to_enable);
 
-    let pool = ThreadPool::new(SETTINGS.n_workers);
-    let (tx, rx) = channel();
-    for _ in 0..SETTINGS.n_workers {
-        let tx = tx.clone();
-        pool.execute(move || {
+    // A replayed archive is read by the main loop itself, so that the simulated clock only
+    // advances once per jiffy
+    let mut replay_reader = match SETTINGS.replay_dir {
+        Some(ref dir) => {
+            info!("Replaying traces from {:?}, nothing will be enabled", dir);
             let mut reader = reader_from_settings(&SETTINGS);
-            loop {
-                for trace in reader.get_recent_traces() {
-                    tx.send(CriticalPath::from_trace(&trace).unwrap())
-                        .expect("channel will be there waiting for the pool");
+            reader.set_clock(CLOCK.clone());
+            Some(reader)
+        }
+        None => None,
+    };
+    let n_workers = if replay_reader.is_some() { 0 } else { SETTINGS.n_workers };
+    let pool = ThreadPool::new(SETTINGS.n_workers * APPLICATIONS.len() + 1);
+    let (tx, rx) = channel();
+    // With a correlation key, traces of all applications go through one stitcher first
+    let stitch_tx = match SETTINGS.stitch_key {
+        Some(ref key) if n_workers > 0 => {
+            let (stitch_tx, stitch_rx) = channel();
+            let tx = tx.clone();
+            let shutdown = shutdown.clone();
+            pool.execute(move || {
+                let mut stitcher = TraceStitcher::new(key, SETTINGS.jiffy);
+                stitcher.set_clock(CLOCK.clone());
+                while !shutdown.load(Ordering::Relaxed) {
+                    for (app, trace) in stitch_rx.try_iter() {
+                        stitcher.add(app, trace);
+                    }
+                    for (app, trace) in stitcher.ready() {
+                        send_paths(&tx, app, trace);
+                    }
+                    CLOCK.sleep(SETTINGS.jiffy / 4);
                 }
-                sleep(SETTINGS.jiffy);
-            }
-        });
+            });
+            Some(stitch_tx)
+        }
+        _ => None,
+    };
+    // Each application has its own readers; traces are tagged with the application's index
+    for (app, settings) in APPLICATIONS.iter().enumerate() {
+        for _ in 0..n_workers {
+            let tx = tx.clone();
+            let stitch_tx = stitch_tx.clone();
+            let shutdown = shutdown.clone();
+            pool.execute(move || {
+                let mut reader = reader_from_settings(settings);
+                reader.set_clock(CLOCK.clone());
+                while !shutdown.load(Ordering::Relaxed) {
+                    for trace in reader.get_recent_traces() {
+                        match stitch_tx {
+                            Some(ref stitch_tx) => stitch_tx
+                                .send((app, trace))
+                                .expect("the stitcher will be there waiting"),
+                            None => send_paths(&tx, app, trace),
+                        }
+                    }
+                    CLOCK.sleep(SETTINGS.jiffy);
+                }
+            });
+        }
     }
 
     // Main pythia loop
     let mut jiffy_no = 0;
     loop {
-        writeln!(output_file, "Jiffy {}, {:?}", jiffy_no, Instant::now()).ok();
-        budget_manager.read_stats();
+        if shutdown.load(Ordering::Relaxed) {
+            info!("Shutting down");
+            control.lock().unwrap().shutting_down = true;
+            writeln!(output_file, "Stopped: received a signal").ok();
+            // Wait for the readers to stop, which closes their redis connections
+            pool.join();
+            if let Some(ref name) = SETTINGS.shutdown_profile {
+                match Profile::load(&SETTINGS.profile_dir, name) {
+                    Ok(profile) => {
+                        profile.apply(CONTROLLER.as_ref());
+                        info!("Applied profile {}", name);
+                        writeln!(output_file, "Applied profile {}", name).ok();
+                    }
+                    Err(e) => error!("Could not apply shutdown_profile {}: {}", name, e),
+                }
+            }
+            epochs.finish();
+            write_report(&run_report, &filename, "it received a signal", &apps);
+            output_file.sync_all().ok();
+            return;
+        }
+        writeln!(output_file, "Jiffy {}, {:?}", jiffy_no, CLOCK.now()).ok();
+        let deadline = CycleDeadline::start(CLOCK.clone(), SETTINGS.cycle_deadline);
+        let mut skipped = SkippedWork::default();
+        let api_reload = std::mem::replace(&mut control.lock().unwrap().reload, false);
+        if reload.swap(false, Ordering::Relaxed) || api_reload {
+            match ReloadableSettings::read() {
+                Ok(new) if new == reloadable => info!("Settings did not change"),
+                Ok(new) => {
+                    info!("Reloaded settings: {:?}", new);
+                    writeln!(output_file, "Reloaded settings: {:?}", new).ok();
+                    // A budget set through the API stays unless the file changes it
+                    if new.tracepoints_per_epoch != reloadable.tracepoints_per_epoch {
+                        control.lock().unwrap().budget = new.tracepoints_per_epoch;
+                    }
+                    match anomaly_detector.as_mut() {
+                        Some(d) if Some(d.method()) == new.anomaly_method() => {
+                            d.set_threshold(new.anomaly_threshold)
+                        }
+                        _ => {
+                            anomaly_detector = new
+                                .anomaly_method()
+                                .map(|method| AnomalyDetector::new(method, new.anomaly_threshold))
+                        }
+                    }
+                    reloadable = new;
+                }
+                Err(e) => warn!("Keeping the old settings, could not reload: {}", e),
+            }
+        }
+        if replay_reader.is_none() {
+            budget_manager.read_stats();
+        }
         budget_manager.print_stats();
         budget_manager.write_stats(&mut output_file);
         let over_budget = budget_manager.overrun();
+        run_report.jiffy(CONTROLLER.enabled_tracepoints().len(), over_budget);
 
         // Collect traces, increment groups
-        let critical_paths = rx.try_iter().collect::<Vec<_>>();
-        groups.update(&critical_paths);
+        let mut new_paths: Vec<Vec<CriticalPath>> = apps.iter().map(|_| Vec::new()).collect();
+        let received = match replay_reader {
+            Some(ref mut reader) => reader
+                .get_recent_traces()
+                .into_iter()
+                .filter_map(|mut t| {
+                    let mut p =
+                        CriticalPath::top_k_from_trace(&t, SETTINGS.critical_paths_per_trace)
+                            .ok()?;
+                    classify_unknown(0, &mut t, &mut p);
+                    Some((0, t, p))
+                })
+                .collect(),
+            None => rx.try_iter().collect::<Vec<_>>(),
+        };
+        let transition = CLOCK.elapsed(last_change) < SETTINGS.transition_period;
+        let received_count = received.len();
+        for (n, (app, mut trace, mut paths)) in received.into_iter().enumerate() {
+            if deadline.passed() {
+                skipped.traces = received_count - n;
+                break;
+            }
+            if transition {
+                trace.is_transition = true;
+                for p in paths.iter_mut() {
+                    p.g.is_transition = true;
+                }
+            }
+            if let Some(ref canary) = canary {
+                canary.mark(&mut trace, &mut paths);
+            }
+            epochs.record(&trace, &paths);
+            apps[app].observe(&trace);
+            retention.record(trace);
+            new_paths[app].extend(paths);
+        }
+        for (app, paths) in apps.iter_mut().zip(new_paths.iter()) {
+            app.groups.update(paths);
+        }
+        let critical_paths: Vec<CriticalPath> = new_paths.into_iter().flatten().collect();
+        if transition && !critical_paths.is_empty() {
+            writeln!(output_file, "Transition traces: {}", critical_paths.len()).ok();
+        }
         budget_manager.update_new_paths(&critical_paths);
-        println!(
+        let all_groups: Vec<&GroupManager> = apps.iter().map(|a| &a.groups).collect();
+        control.lock().unwrap().publish_groups(&all_groups);
+        debug!(
             "Got {} paths of duration {:?} at time {}us",
             critical_paths.len(),
             critical_paths
                 .iter()
                 .map(|p| p.duration)
                 .collect::<Vec<Duration>>(),
-            now.elapsed().as_micros()
+            CLOCK.elapsed(now).as_micros()
         );
-        println!("Groups: {}", groups);
+        for app in &apps {
+            debug!("Groups of {:?}: {}", app.settings.application, app.groups);
+        }
         writeln!(output_file, "New traces: {}", critical_paths.len()).ok();
         writeln!(
             output_file,
@@ -141,7 +624,7 @@ fn main() {
         )
         .ok();
 
-        // if over_budget || last_gc.elapsed() > SETTINGS.gc_epoch {
+        // if over_budget || CLOCK.elapsed(last_gc) > SETTINGS.gc_epoch {
             // Run garbage collection
             // if over_budget {
                 // eprintln!("Over budget, would disable but it's not implemented");
@@ -191,113 +674,326 @@ fn main() {
         //     writeln!(output_file, "Disabled {}", to_disable.len()).ok();
         //     writeln!(output_file, "Disabled {:?}", to_disable).ok();
 
-        //     last_gc = Instant::now();
+        //     last_gc = CLOCK.now();
         // }
+        if CLOCK.elapsed(last_gc) > SETTINGS.gc_epoch {
+            // Forget the names of tracepoints nothing refers to any more
+            let mut live: HashSet<TracepointID> = targets.clone();
+            live.extend(skeleton.iter());
+            live.extend(CONTROLLER.enabled_tracepoints().iter().map(|tp| tp.0));
+            live.extend(retention.tracepoints());
+            // Blocked through the control API, and matched against new edges until unblocked
+            live.extend(control.lock().unwrap().blocked.iter());
+            for (app, manifest) in apps.iter().zip(MANIFESTS.iter()) {
+                live.extend(manifest.referenced_tracepoints());
+                if let Some(grown) = &app.manifest {
+                    live.extend(grown.referenced_tracepoints());
+                }
+                live.extend(app.groups.tracepoints());
+            }
+            for (_, trial) in &trials {
+                live.extend(trial.referenced_tracepoints());
+            }
+            let collected = TracepointID::collect_garbage(&live);
+            let stats = TracepointID::interner_stats();
+            info!("Collected {} tracepoint IDs, {:?}", collected, stats);
+            writeln!(output_file, "Tracepoint IDs: {:?}", stats).ok();
+            last_gc = CLOCK.now();
+        }
+        // Changes held back by the rate limit go out as the window moves
+        if CONTROLLER.send_queued() > 0 {
+            last_change = CLOCK.now();
+        }
+        if SETTINGS
+            .reconcile_interval
+            .is_some_and(|interval| CLOCK.elapsed(last_reconcile) > interval)
+        {
+            let repaired = CONTROLLER.reconcile();
+            if repaired > 0 {
+                warn!("Repaired {} drifted tracepoint settings", repaired);
+                writeln!(output_file, "Reconciled {} tracepoint settings", repaired).ok();
+            }
+            last_reconcile = CLOCK.now();
+        }
 
-        if !over_budget && last_decision.elapsed() > SETTINGS.decision_epoch {
+        let paused = control.lock().unwrap().paused;
+        if paused {
+            info!("Paused, not making decisions");
+        }
+        if !paused && !over_budget && CLOCK.elapsed(last_decision) > SETTINGS.decision_epoch {
 
             let enabled_tracepoints: HashSet<_> =
                     CONTROLLER.enabled_tracepoints().drain(..).collect();
 
             
             // Make decision
-            let mut budget = SETTINGS.tracepoints_per_epoch;
+            // With an event budget, tracepoints are not counted
+            let mut budget = match SETTINGS.event_budget {
+                Some(_) => usize::MAX,
+                None => budget_manager.cycle_budget(control.lock().unwrap().budget),
+            };
+            let mut events = SETTINGS
+                .event_budget
+                .map_or(0.0, |e| budget_manager.cycle_event_budget(e));
+            let blocked = control.lock().unwrap().blocked.clone();
+            let mut unresolved = Vec::new();
+            for (idx, trial) in trials.drain(..) {
+                let groups = apps[idx].groups.active_groups();
+                // Wait for traces with the new tracepoints, unless they were disabled since
+                if !trial.resolved(&groups) {
+                    if trial.is_active(&**CONTROLLER) {
+                        unresolved.push((idx, trial));
+                    }
+                    continue;
+                }
+                if let Some(explained) = trial.variance_explained(&groups) {
+                    writeln!(
+                        output_file,
+                        "{} explained {:.2} of {}",
+                        trial.tracepoints.len(),
+                        explained,
+                        trial.edge()
+                    )
+                    .ok();
+                    if let Some(ref results) = results {
+                        results.record(&trial, explained);
+                    }
+                    run_report.explained(&trial, explained);
+                }
+                // The problem is localized when one sub-edge has most of the variance and
+                // there is nothing left to enable in it
+                for g in groups {
+                    let (sub_edge, share) = match trial.localize(g) {
+                        Some(found) if found.1 >= SETTINGS.verdict_threshold => found,
+                        _ => continue,
+                    };
+                    let manifest = apps[idx].current_manifest();
+                    if manifest
+                        .tracepoints_between(g, sub_edge, &enabled_tracepoints)
+                        .iter()
+                        .any(|tp| !blocked.contains(tp))
+                    {
+                        continue;
+                    }
+                    if let Some(verdict) = run_report.verdict(g, &trial, sub_edge, share) {
+                        info!("{}", verdict);
+                        writeln!(output_file, "{}", verdict).ok();
+                    }
+                }
+            }
+            trials = unresolved;
+            for app in &apps {
+                let refined = lineage.observe(&app.groups.all_groups());
+                if refined > 0 {
+                    writeln!(output_file, "Refined groups: {}", refined).ok();
+                }
+            }
             // let problem_groups = groups.problem_groups();
             
-            let problem_groups = groups.problem_groups_cv(0.05); // tsl: problem groups takes now 
-            // println!("*CV Groups: {:?}", problem_groups);
+            for app in apps.iter_mut() {
+                let added = app.update_manifest();
+                if added > 0 {
+                    info!("Added {} paths to {:?}", added, app.settings.manifest_file);
+                    writeln!(output_file, "Manifest grew by {} paths", added).ok();
+                }
+                let chosen = reloadable.problem_selection.resolve(&app.groups);
+                if chosen != app.selector {
+                    info!(
+                        "Switching problem selector of {:?} from {:?} to {:?}",
+                        app.settings.application, app.selector, chosen
+                    );
+                    app.selector = chosen;
+                }
+                writeln!(output_file, "Problem selector: {:?}", app.selector).ok();
+            }
+            // Each application has its own problem groups, they take turns using the budget
+            let mut per_app_problems = Vec::new();
+            for (idx, app) in apps.iter().enumerate() {
+                let mut problem_groups = app.groups.problem_groups_by(app.selector);
+                if let Some(detector) = anomaly_detector.as_mut() {
+                    let anomalies = detector.observe(&app.groups);
+                    for a in &anomalies {
+                        writeln!(
+                            output_file,
+                            "Anomalous group {}: mean {:?}, score {:.2}",
+                            a.group,
+                            Duration::from_nanos(a.recent_mean as u64),
+                            a.score
+                        )
+                        .ok();
+                    }
+                    problem_groups = detector.prioritize(&anomalies, &app.groups, problem_groups);
+                }
+                // Groups violating an SLO go first, even with little variance
+                for v in app.groups.slo_violations() {
+                    writeln!(output_file, "SLO violation: {}", v).ok();
+                }
+                problem_groups = app.groups.prioritize_slo_violations(problem_groups);
+                if canary.is_some() {
+                    for g in problem_groups.iter() {
+                        if let Some(c) = CanaryComparison::from_group(g) {
+                            writeln!(
+                                output_file,
+                                "Canary {}: {:.2}ms over {} requests vs {:.2}ms over {} ({:+.1}%)",
+                                g.hash(),
+                                c.canary_mean_ms,
+                                c.canary_traces,
+                                c.other_mean_ms,
+                                c.other_traces,
+                                100.0 * c.overhead()
+                            )
+                            .ok();
+                        }
+                    }
+                }
+                per_app_problems.push(problem_groups.into_iter().map(|g| (idx, g)).collect());
+            }
+            let problem_groups: Vec<(usize, &Group)> = interleave(per_app_problems);
 
-            //comment-in below line for consistently slow analysis
-            // let problem_groups_slow = groups.problem_groups_slow(95.0); // tsl: problem groups takes now 
-            // println!("*SLOW Groups: {:?}", problem_groups_slow);
+            // Tracepoints on trial on the canaries go everywhere, or away
+            for (group, points) in CONTROLLER.canary_trials() {
+                let request_type = points.iter().find_map(|p| p.1);
+                let groups: Vec<&Group> = apps
+                    .iter()
+                    .flat_map(|app| app.groups.all_groups())
+                    .filter(|g| Some(g.request_type) == request_type)
+                    .collect();
+                let comparison = match CanaryComparison::from_groups(&groups) {
+                    Some(c) => c,
+                    None => continue,
+                };
+                match comparison.verdict(SETTINGS.canary_min_requests, SETTINGS.canary_max_overhead)
+                {
+                    CanaryVerdict::Wait => {}
+                    CanaryVerdict::Promote => {
+                        CONTROLLER.enable_for_group(&points, &group);
+                        writeln!(output_file, "Promoted {:?} of {}", points, group).ok();
+                    }
+                    CanaryVerdict::RollBack => {
+                        CONTROLLER.disable(&points);
+                        writeln!(
+                            output_file,
+                            "Rolled back {:?} of {}, {:+.1}% on the canaries",
+                            points,
+                            group,
+                            100.0 * comparison.overhead()
+                        )
+                        .ok();
+                    }
+                }
+            }
 
             let mut used_groups = Vec::new();
 
             //tsl ; get problematic group types to disable tps for non-problematic ones
             let mut problematic_req_types = Vec::new();
             
-            println!("Making decision. Top 10 problem groups:");
-            for g in problem_groups.iter().take(10) {
-                println!("{}", g);
+            info!("Making decision. Top 10 problem groups:");
+            for (_, g) in problem_groups.iter().take(10) {
+                info!("{}", g);
                 // for enabled in &g.enabled_tps{
                 //     println!("Enabled: {:?} ", enabled);
                 // }
             }
+            for app in &apps {
+                for slow in app.groups.slow_partitions(SETTINGS.slow_partition_ratio) {
+                    info!("{}", slow);
+                    writeln!(output_file, "Slow partition: {}", slow).ok();
+                }
+            }
 
-            //comment-in below line for consistently slow analysis
-            // println!("Making decision. Top 10 slow problem groups:");
-            // for g in problem_groups_slow.iter().take(10) {
-            //     println!("{}", g);
-            //     // for enabled in &g.enabled_tps{
-            //     //     println!("Enabled: {:?} ", enabled);
-            //     // }
-            // }
-            for g in problem_groups {
+            let group_count = problem_groups.len();
+            for (n, (idx, g)) in problem_groups.into_iter().enumerate() {
+                // The first group is searched even if taking in the traces used up the jiffy
+                if n > 0 && deadline.passed() {
+                    skipped.groups = group_count - n;
+                    break;
+                }
                 problematic_req_types.push(g.request_type);
+                run_report.problem_group(g);
+                let retained = retention.retain_group(g);
+                if retained > 0 {
+                    writeln!(output_file, "Retained {} traces of {}", retained, g.hash()).ok();
+                }
 
                 let problem_edges = g.problem_edges();
 
-                println!("Top 10 edges of group {}:", g);
+                debug!("Top 10 edges of group {}:", g);
                 for edge in problem_edges.iter().take(10) {
                     let endpoints = g.g.edge_endpoints(*edge).unwrap();
-                    println!(
+                    debug!(
                         "({} -> {}): {}",
                         g.g[endpoints.0], g.g[endpoints.1], g.g[*edge]
                     );
                 }
-                for &edge in problem_edges.iter() {
-                    if budget <= 0 {
+                let cost = |tp| budget_manager.event_cost(apps[idx].costs(), tp);
+                for (i, &edge) in problem_edges.iter().enumerate() {
+                    if budget <= 0 || (SETTINGS.event_budget.is_some() && events <= 0.0) {
+                        break;
+                    }
+                    if i > 0 && deadline.passed() {
+                        skipped.edges += problem_edges.len() - i;
                         break;
                     }
                     let endpoints = g.g.edge_endpoints(edge).unwrap();
-                    println!(
+                    debug!(
                         "Searching ({} -> {}): {}",
                         g.g[endpoints.0], g.g[endpoints.1], g.g[edge]
                     );
-                    let decisions = strategy
-                        .search(g, edge, budget)
+                    let candidates = match SETTINGS.event_budget {
+                        Some(_) => apps[idx].strategy.search_by_cost(g, edge, events, &cost),
+                        None => apps[idx].strategy.search(g, edge, budget),
+                    };
+                    let decisions = candidates
                         .iter()
+                        .filter(|t| !blocked.contains(t))
                         .take(budget)
                         .map(|&t| (t, Some(g.request_type)))
                         .collect::<Vec<_>>();
                     budget -= decisions.len();
+                    events -= decisions.iter().map(|d| cost(d.0)).sum::<f64>();
                     for d in &decisions {
                         if !targets.get(&d.0).is_none() {
                             targets.remove(&d.0);
-                            if targets.len() == 0 {
-                                eprintln!("Found the target");
-                                quit_in = 20;
-                            } else {
-                                eprintln!("Found one target");
-                            }
+                            info!("Found one target");
                         }
                     }
-                    CONTROLLER.enable(&decisions);
+                    CONTROLLER.enable_scoped(&decisions, g.hash(), scope);
+                    run_report.enabled(g.hash(), &decisions);
+                    if decisions.len() > 0 {
+                        last_change = CLOCK.now();
+                    }
                     writeln!(output_file, "Enabled {}", decisions.len()).ok();
                     writeln!(output_file, "Enabled {:?}", decisions).ok();
                     if decisions.len() > 0 {
-                        used_groups.push(g.hash().to_string());
+                        used_groups.push((idx, g.hash().to_string()));
+                        let tracepoints = decisions.iter().map(|d| d.0).collect();
+                        lineage.diagnosed(decision_cycles + 1, g, edge, tracepoints);
+                        let tracepoints = decisions.iter().map(|d| d.0).collect();
+                        trials.push((idx, Trial::new(g, edge, tracepoints)));
                     }
                     // // tsl: record enabled tracepoints per group
                     // g.update_enabled_tracepoints(&decisions);
                 }
-                if budget <= 0 {
+                if budget <= 0 || (SETTINGS.event_budget.is_some() && events <= 0.0) {
                     break;
                 }
             }
-            println!("Problematic req types: ");
-            for item in problematic_req_types{
-                println!("{:?}, ", item)
+            info!("Problematic request types: {:?}", problematic_req_types);
+            if let Some(path) = &SETTINGS.lineage_file {
+                if let Err(e) = lineage.save(path) {
+                    error!("Could not write lineage to {:?}: {}", path, e);
+                }
             }
-            for g in used_groups {
-                groups.used(&g);
+            for (idx, g) in used_groups {
+                apps[idx].groups.used(&g);
             }
 
             //tsl : for groups that stopped being problematic; just disable tracepoints, which are enabled so far
             
             // let mut to_disable = Vec::new();
             for tp in enabled_tracepoints {
-                println!("{:?}, ", tp.1);
+                debug!("Enabled for {:?}", tp.1);
 
                 // if g.request_type == tp. && to_keep.get(&tp).is_none() {
                 //     to_disable.push(tp);
@@ -308,15 +1004,84 @@ fn main() {
 
              
 
-            last_decision = Instant::now();
+            if let Some(epoch) = epochs.advance(CONTROLLER.enabled_tracepoints()) {
+                writeln!(
+                    output_file,
+                    "Epoch {}: {} traces, {} tracepoints enabled",
+                    epoch.number,
+                    epoch.trace_ids.len(),
+                    epoch.enabled.len()
+                )
+                .ok();
+                let report = previous_epoch
+                    .as_ref()
+                    .and_then(|previous| compare_epochs(previous, &epoch, SETTINGS.impact_alpha));
+                if let Some(report) = &report {
+                    info!("{}", report);
+                    writeln!(output_file, "{}", report).ok();
+                    run_report.impact(report);
+                }
+                budget_manager.record_impact(report.as_ref());
+                previous_epoch = Some(epoch);
+            }
+            last_decision = CLOCK.now();
+            decision_cycles += 1;
+            run_report.cycle(decision_cycles);
+            control.lock().unwrap().decisions = decision_cycles;
+        }
+        if !skipped.is_empty() {
+            warn!(
+                "Jiffy {} went past the cycle deadline after {:?}, skipped {}",
+                jiffy_no,
+                deadline.elapsed(),
+                skipped
+            );
+            writeln!(output_file, "Deadline: skipped {}", skipped).ok();
+            run_report.missed_deadline(skipped);
+        }
+        match SETTINGS.stopping_condition.check(
+            decision_cycles,
+            CLOCK.elapsed(now),
+            targets.len(),
+            &apps.iter().map(|a| &a.groups).collect::<Vec<_>>(),
+        ) {
+            // Keep going for a while so the traces with the targets show up in the output
+            Some(StopReason::TargetsReached) => {
+                if quit_in < 0 {
+                    info!("Found the target");
+                    quit_in = 20;
+                }
+            }
+            Some(reason) => {
+                info!("Quitting, {}", reason);
+                writeln!(output_file, "Stopped: {}", reason).ok();
+                epochs.finish();
+                write_report(&run_report, &filename, &reason.to_string(), &apps);
+                return;
+            }
+            None => {}
         }
         quit_in -= 1;
         if quit_in == 0 {
-            eprintln!("Quitting");
+            info!("Quitting");
+            epochs.finish();
+            write_report(&run_report, &filename, &StopReason::TargetsReached.to_string(), &apps);
+            return;
+        }
+        if replay_reader.as_ref().map_or(false, |r| r.is_exhausted()) {
+            let enabled = CONTROLLER.enabled_tracepoints();
+            info!("Replay finished after {} jiffies", jiffy_no + 1);
+            info!("Would have ended with {} tracepoints enabled:", enabled.len());
+            for tp in &enabled {
+                info!("{:?}", tp);
+            }
+            writeln!(output_file, "Replay finished, enabled at the end {:?}", enabled).ok();
+            epochs.finish();
+            write_report(&run_report, &filename, "the replay finished", &apps);
             return;
         }
 
         jiffy_no += 1;
-        sleep(SETTINGS.jiffy);
+        CLOCK.sleep(SETTINGS.jiffy);
     }
 }
@@ -10,23 +10,45 @@ All rights reserved.
 //!
 //! # Usage
 //! At each cycle, run `read_stats` and `update_new_paths` with the newest critical paths. The
-//! other methods are reader methods which will provide various stats if necessary.
+//! other methods are reader methods which will provide various stats if necessary. After each
+//! decision epoch, `record_impact` tells whether the last enabled tracepoints slowed requests
+//! down, and `cycle_budget` enables fewer at the next decision if they did.
+//!
+//! Budgets can also be in events per second: `event_cost` estimates what a tracepoint adds from
+//! its events per request in the manifest and the request rate seen in recent paths (at least
+//! one request per second).
 
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
 use std::fs::File;
 use std::io::prelude::*;
+use std::sync::Arc;
 use std::time::Duration;
 use std::time::Instant;
 
+use log::{info, warn};
+
 use pythia_common::NodeStats;
 use pythia_common::RequestType;
 
+use crate::clock::Clock;
+use crate::clock::SystemClock;
 use crate::critical::CriticalPath;
 use crate::critical::Path;
+use crate::impact::ImpactReport;
+use crate::manifest::CostModel;
 use crate::rpclib::read_client_stats;
+use crate::rpclib::RetryPolicy;
 use crate::settings::Settings;
 use crate::trace::TracepointID;
 
+/// The request rate is measured over this long
+const RATE_WINDOW: Duration = Duration::from_secs(300);
+/// Tracepoints are costed at least at this many requests per second, so that they are not
+/// free before requests are seen or after an idle window
+const MIN_COST_RATE: f64 = 1.0;
+
 /// Methods to collect stats from application nodes, and decide whether we are over the limit in
 /// terms of instrumentation budget. Also contains garbage collection.
 pub struct BudgetManager {
@@ -36,6 +58,12 @@ pub struct BudgetManager {
     last_seen: HashMap<(TracepointID, Option<RequestType>), Instant>,
     gc_keep_duration: Duration,
     trace_size_limit: u32,
+    retry_policy: RetryPolicy,
+    clock: Arc<dyn Clock>,
+    /// The last tracepoints enabled measurably increased request latency
+    latency_impact: bool,
+    /// Requests seen at each update within the rate window
+    arrivals: VecDeque<(Instant, usize)>,
 }
 
 impl BudgetManager {
@@ -46,13 +74,29 @@ impl BudgetManager {
             last_seen: HashMap::new(),
             gc_keep_duration: settings.gc_keep_duration,
             trace_size_limit: settings.trace_size_limit,
+            retry_policy: RetryPolicy::from_settings(settings),
+            clock: Arc::new(SystemClock),
+            latency_impact: false,
+            arrivals: VecDeque::new(),
         }
     }
 
+    /// Use a different clock for deciding when tracepoints are old
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
     pub fn read_stats(&mut self) {
         for client in &self.clients {
-            self.last_stats
-                .insert(client.clone(), read_client_stats(client));
+            match read_client_stats(client, &self.retry_policy) {
+                Ok(stats) => {
+                    self.last_stats.insert(client.clone(), stats);
+                }
+                Err(e) => {
+                    warn!("Skipping stats of {}: {}", client, e);
+                    self.last_stats.remove(client);
+                }
+            }
         }
     }
 
@@ -64,7 +108,7 @@ impl BudgetManager {
 
     pub fn print_stats(&self) {
         for (client, stats) in &self.last_stats {
-            eprintln!("{}: {:?}", client, stats);
+            info!("{}: {:?}", client, stats);
         }
     }
 
@@ -77,9 +121,60 @@ impl BudgetManager {
         total_traces > self.trace_size_limit
     }
 
-    /// Update the garbage collector
+    /// Take the result of comparing the last two epochs into account. Without a report (e.g.,
+    /// nothing was enabled since), the budget is no longer cut.
+    pub fn record_impact(&mut self, report: Option<&ImpactReport>) {
+        self.latency_impact = report.map_or(false, |r| r.increased);
+    }
+
+    /// How many tracepoints to enable this cycle, out of `budget`. Halving never stops the
+    /// search altogether.
+    pub fn cycle_budget(&self, budget: usize) -> usize {
+        if self.latency_impact {
+            (budget / 2).max(budget.min(1))
+        } else {
+            budget
+        }
+    }
+
+    /// Events per second out of `events`, halved like `cycle_budget`
+    pub fn cycle_event_budget(&self, events: f64) -> f64 {
+        if self.latency_impact {
+            events / 2.0
+        } else {
+            events
+        }
+    }
+
+    /// Requests per second in the last few minutes
+    pub fn request_rate(&self) -> f64 {
+        let requests: usize = self.arrivals.iter().map(|(_, n)| n).sum();
+        match self.arrivals.front() {
+            Some((first, _)) => {
+                let elapsed = self.clock.elapsed(*first).as_secs_f64();
+                requests as f64 / elapsed.max(1.0)
+            }
+            None => 0.0,
+        }
+    }
+
+    /// Estimated events per second that enabling the tracepoint adds
+    pub fn event_cost(&self, costs: &CostModel, tracepoint: TracepointID) -> f64 {
+        costs.events_per_request(tracepoint) * self.request_rate().max(MIN_COST_RATE)
+    }
+
+    /// Update the garbage collector and the request rate
     pub fn update_new_paths(&mut self, paths: &Vec<CriticalPath>) {
-        let now = Instant::now();
+        let now = self.clock.now();
+        // There can be several paths per request
+        let requests = paths.iter().map(|p| p.g.base_id).collect::<HashSet<_>>();
+        self.arrivals.push_back((now, requests.len()));
+        while let Some(&(first, _)) = self.arrivals.front() {
+            if self.clock.elapsed(first) <= RATE_WINDOW {
+                break;
+            }
+            self.arrivals.pop_front();
+        }
         for path in paths {
             let mut nidx = path.start_node;
             while nidx != path.end_node {
@@ -95,10 +190,95 @@ impl BudgetManager {
     pub fn old_tracepoints(&self) -> Vec<(TracepointID, Option<RequestType>)> {
         let mut result = Vec::new();
         for (&tp, seen) in &self.last_seen {
-            if seen.elapsed() > self.gc_keep_duration {
+            if self.clock.elapsed(*seen) > self.gc_keep_duration {
                 result.push(tp);
             }
         }
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::clock::SimulatedClock;
+
+    fn manager() -> BudgetManager {
+        BudgetManager {
+            clients: Vec::new(),
+            last_stats: HashMap::new(),
+            last_seen: HashMap::new(),
+            gc_keep_duration: Duration::from_secs(60),
+            trace_size_limit: 0,
+            retry_policy: RetryPolicy {
+                retries: 0,
+                backoff: Duration::from_secs(0),
+                timeout: Duration::from_secs(1),
+            },
+            clock: Arc::new(SimulatedClock::new()),
+            latency_impact: false,
+            arrivals: VecDeque::new(),
+        }
+    }
+
+    fn report(increased: bool) -> ImpactReport {
+        ImpactReport {
+            epoch: 1,
+            added: Vec::new(),
+            before_count: 100,
+            after_count: 100,
+            before_median: 1000,
+            after_median: if increased { 2000 } else { 1000 },
+            p_value: if increased { 0.001 } else { 0.5 },
+            increased,
+        }
+    }
+
+    #[test]
+    fn halves_the_budget_after_a_slowdown() {
+        let mut budget = manager();
+        assert_eq!(budget.cycle_budget(10), 10);
+        budget.record_impact(Some(&report(true)));
+        assert_eq!(budget.cycle_budget(10), 5);
+        assert_eq!(budget.cycle_event_budget(10.0), 5.0);
+        // A budget of one is not halved to nothing
+        assert_eq!(budget.cycle_budget(1), 1);
+        assert_eq!(budget.cycle_budget(0), 0);
+        // Nothing enabled since, so nothing to compare
+        budget.record_impact(None);
+        assert_eq!(budget.cycle_budget(10), 10);
+        budget.record_impact(Some(&report(true)));
+        budget.record_impact(Some(&report(false)));
+        assert_eq!(budget.cycle_budget(10), 10);
+    }
+
+    #[test]
+    fn tracepoints_cost_something_without_requests() {
+        let clock = Arc::new(SimulatedClock::new());
+        let mut budget = manager();
+        budget.set_clock(clock.clone());
+        let costs = CostModel::default();
+        let tracepoints: Vec<TracepointID> = ["a", "b", "c"]
+            .iter()
+            .map(|t| TracepointID::from_str(t))
+            .collect();
+        let cost = |tp| budget.event_cost(&costs, tp);
+        assert_eq!(budget.request_rate(), 0.0);
+        assert!(cost(tracepoints[0]) > 0.0);
+        assert_eq!(
+            crate::search::within_cost(tracepoints.clone(), 2.0, &cost).len(),
+            2
+        );
+
+        // 600 requests over 60 seconds, then an idle window
+        budget.update_new_paths(&Vec::new());
+        clock.advance(Duration::from_secs(60));
+        budget.arrivals.push_back((clock.now(), 600));
+        assert_eq!(budget.event_cost(&costs, tracepoints[0]), 10.0);
+        clock.advance(RATE_WINDOW * 2);
+        budget.update_new_paths(&Vec::new());
+        assert_eq!(budget.request_rate(), 0.0);
+        assert_eq!(budget.event_cost(&costs, tracepoints[0]), MIN_COST_RATE);
+    }
+}
@@ -0,0 +1,207 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Canary hosts, where new instrumentation is tried before it is rolled out to the cluster.
+//!
+//! With `canary_fraction` set, the first agents of `pythia_clients` (that fraction of them,
+//! rounded up) are canaries and the tracepoints picked by the search are only enabled there.
+//! Requests that went through a canary host are marked, and each group keeps their latencies
+//! apart from the others, so the overhead of the new tracepoints can be read from the
+//! difference. Once enough requests of the type went through both, the tracepoints are enabled
+//! on every agent if the overhead is at most `canary_max_overhead`, and disabled otherwise.
+
+use std::collections::HashSet;
+
+use hyper::Uri;
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::critical::CriticalPath;
+use crate::grouping::Group;
+use crate::rpclib::read_client_health;
+use crate::rpclib::RetryPolicy;
+use crate::settings::Settings;
+use crate::trace::Trace;
+use crate::trace::Value;
+use crate::units::LatencyStats;
+
+/// Which agents a change goes to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostScope {
+    All,
+    Canary,
+}
+
+/// The first `fraction` of the agents, at least one
+pub fn canary_clients(clients: &[String], fraction: f64) -> Vec<String> {
+    let count = ((clients.len() as f64 * fraction).ceil() as usize).clamp(1, clients.len().max(1));
+    clients.iter().take(count).cloned().collect()
+}
+
+/// Host names of the canary agents, to tell which requests went through them
+pub struct Canary {
+    hosts: HashSet<String>,
+}
+
+impl Canary {
+    /// None without `canary_fraction`. Asks the canary agents for their host names, since
+    /// `pythia_clients` may have addresses instead.
+    pub fn from_settings(settings: &Settings) -> Option<Canary> {
+        let fraction = settings.canary_fraction?;
+        let policy = RetryPolicy::from_settings(settings);
+        let mut hosts = HashSet::new();
+        for client in canary_clients(&settings.pythia_clients, fraction) {
+            if let Some(host) = client
+                .parse::<Uri>()
+                .ok()
+                .and_then(|u| u.host().map(String::from))
+            {
+                hosts.insert(host);
+            }
+            match read_client_health(&client, &policy) {
+                Ok(health) => {
+                    hosts.insert(health.host);
+                }
+                Err(e) => warn!("Could not get the host name of canary {}: {}", client, e),
+            }
+        }
+        info!("Canary hosts: {:?}", hosts);
+        Some(Canary { hosts })
+    }
+
+    pub fn from_hosts(hosts: HashSet<String>) -> Canary {
+        Canary { hosts }
+    }
+
+    pub fn touches(&self, trace: &Trace) -> bool {
+        trace
+            .g
+            .node_indices()
+            .any(|n| match trace.g[n].key_value_pair.get("host") {
+                Some(Value::Str(host)) => self.hosts.contains(host),
+                _ => false,
+            })
+    }
+
+    /// Marks the trace and its paths if the request went through a canary host
+    pub fn mark(&self, trace: &mut Trace, paths: &mut [CriticalPath]) {
+        if self.touches(trace) {
+            trace.is_canary = true;
+            for p in paths.iter_mut() {
+                p.g.is_canary = true;
+            }
+        }
+    }
+}
+
+/// Latency of the requests of a group on canary hosts against the others
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct CanaryComparison {
+    pub canary_traces: u64,
+    pub canary_mean_ms: f64,
+    pub other_traces: u64,
+    pub other_mean_ms: f64,
+}
+
+impl CanaryComparison {
+    /// None unless the group has paths on both sides
+    pub fn from_group(group: &Group) -> Option<CanaryComparison> {
+        CanaryComparison::from_groups(&[group])
+    }
+
+    /// Over all the groups, e.g. of a request type, since the requests on the canaries may be
+    /// in refined groups of their own. None unless there are paths on both sides.
+    pub fn from_groups(groups: &[&Group]) -> Option<CanaryComparison> {
+        let (canary_traces, canary_mean_ms) = combined(groups, |g| &g.canary_stats);
+        let (other_traces, other_mean_ms) = combined(groups, |g| &g.non_canary_stats);
+        if canary_traces == 0 || other_traces == 0 {
+            return None;
+        }
+        Some(CanaryComparison {
+            canary_traces,
+            canary_mean_ms,
+            other_traces,
+            other_mean_ms,
+        })
+    }
+
+    /// How much slower requests on the canaries are, e.g. 0.05 for 5%
+    pub fn overhead(&self) -> f64 {
+        self.canary_mean_ms / self.other_mean_ms - 1.0
+    }
+
+    /// What to do with the tracepoints on trial once both sides had `min_requests`
+    pub fn verdict(&self, min_requests: u64, max_overhead: f64) -> CanaryVerdict {
+        if self.canary_traces < min_requests || self.other_traces < min_requests {
+            CanaryVerdict::Wait
+        } else if self.overhead() <= max_overhead {
+            CanaryVerdict::Promote
+        } else {
+            CanaryVerdict::RollBack
+        }
+    }
+}
+
+/// What becomes of tracepoints enabled only on the canaries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CanaryVerdict {
+    /// Not enough requests yet
+    Wait,
+    /// Enable them on every agent
+    Promote,
+    /// Disable them, they cost too much
+    RollBack,
+}
+
+/// Number of paths and their mean latency in milliseconds
+fn combined(groups: &[&Group], stats: fn(&Group) -> &LatencyStats) -> (u64, f64) {
+    let count: u64 = groups.iter().map(|g| stats(g).count()).sum();
+    if count == 0 {
+        return (0, 0.0);
+    }
+    let total: f64 = groups
+        .iter()
+        .map(|g| stats(g).count() as f64 * stats(g).mean().as_millis())
+        .sum();
+    (count, total / count as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_canaries() {
+        let clients: Vec<String> = (1..=5)
+            .map(|i| format!("http://10.0.0.{}:3030", i))
+            .collect();
+        assert_eq!(canary_clients(&clients, 0.2), clients[..1].to_vec());
+        assert_eq!(canary_clients(&clients, 0.5), clients[..3].to_vec());
+        assert_eq!(canary_clients(&clients, 0.01).len(), 1);
+        assert_eq!(canary_clients(&clients, 1.0), clients);
+    }
+
+    #[test]
+    fn judges_overhead() {
+        let comparison = |canary_traces, canary_mean_ms| CanaryComparison {
+            canary_traces,
+            canary_mean_ms,
+            other_traces: 100,
+            other_mean_ms: 100.0,
+        };
+        assert_eq!(comparison(10, 100.0).verdict(20, 0.05), CanaryVerdict::Wait);
+        assert_eq!(
+            comparison(20, 104.0).verdict(20, 0.05),
+            CanaryVerdict::Promote
+        );
+        assert_eq!(
+            comparison(20, 110.0).verdict(20, 0.05),
+            CanaryVerdict::RollBack
+        );
+    }
+}
@@ -0,0 +1,123 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Time source used by the controller loop, the readers and the budget manager.
+//!
+//! Everything that waits or decides based on elapsed time asks a `Clock` instead of calling
+//! `Instant::now()` or `Local::now()` directly. In production this is the `SystemClock`; tests
+//! use a `SimulatedClock` that only moves when told to, so cycle scheduling, garbage collection
+//! cooldowns and trace stability checks can be exercised deterministically and without waiting.
+
+use std::fmt::Debug;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use chrono::offset::Local;
+use chrono::NaiveDateTime;
+
+pub trait Clock: Send + Sync + Debug {
+    /// Monotonic time, used for measuring how long ago something happened
+    fn now(&self) -> Instant;
+
+    /// Local wall clock time, used for comparing against timestamps in traces
+    fn wall(&self) -> NaiveDateTime;
+
+    fn sleep(&self, duration: Duration);
+
+    fn elapsed(&self, since: Instant) -> Duration {
+        self.now().saturating_duration_since(since)
+    }
+}
+
+/// The real clock
+#[derive(Debug)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn wall(&self) -> NaiveDateTime {
+        Local::now().naive_local()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// A clock that starts at the time it is created and only moves with `advance` or `sleep`.
+/// Sleeping returns immediately after moving the clock forward.
+#[derive(Debug)]
+pub struct SimulatedClock {
+    start: Instant,
+    start_wall: NaiveDateTime,
+    elapsed: Mutex<Duration>,
+}
+
+impl SimulatedClock {
+    pub fn new() -> Self {
+        SimulatedClock {
+            start: Instant::now(),
+            start_wall: Local::now().naive_local(),
+            elapsed: Mutex::new(Duration::from_secs(0)),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+
+    /// How much simulated time has passed since the clock was created
+    pub fn total_elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+impl Default for SimulatedClock {
+    fn default() -> Self {
+        SimulatedClock::new()
+    }
+}
+
+impl Clock for SimulatedClock {
+    fn now(&self) -> Instant {
+        self.start + self.total_elapsed()
+    }
+
+    fn wall(&self) -> NaiveDateTime {
+        self.start_wall + chrono::Duration::from_std(self.total_elapsed()).unwrap()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn simulated_clock_moves_only_when_told() {
+        let clock = SimulatedClock::new();
+        let start = clock.now();
+        let start_wall = clock.wall();
+        assert_eq!(clock.elapsed(start), Duration::from_secs(0));
+        clock.sleep(Duration::from_secs(3600));
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(clock.elapsed(start), Duration::from_millis(3600500));
+        assert_eq!(
+            (clock.wall() - start_wall).to_std().unwrap(),
+            Duration::from_millis(3600500)
+        );
+    }
+}
@@ -0,0 +1,141 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Approximate grouping of critical paths by structure.
+//!
+//! With exact grouping, paths that differ by a single tracepoint end up in different groups, and
+//! each group has few traces. In the approximate modes, a path whose tracepoint sequence is
+//! similar enough to an existing group's representative path joins that group. The path's
+//! timings are mapped onto the representative through the longest common subsequence of the
+//! two, see `align`.
+
+/// How critical paths are assigned to groups
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GroupingMode {
+    /// Same tracepoint sequence (the path hash)
+    Exact,
+    /// Longest common subsequence: 2 * LCS / (len a + len b) at least the threshold
+    Lcs(f64),
+    /// Edit distance: 1 - distance / max(len a, len b) at least the threshold
+    EditDistance(f64),
+}
+
+impl GroupingMode {
+    pub fn from_str(mode: &str, threshold: f64) -> Self {
+        match mode {
+            "exact" => GroupingMode::Exact,
+            "lcs" => GroupingMode::Lcs(threshold),
+            "edit_distance" => GroupingMode::EditDistance(threshold),
+            _ => panic!("Unknown grouping mode {}", mode),
+        }
+    }
+
+    /// Similarity of the two sequences, between 0 and 1
+    pub fn similarity<T: PartialEq>(&self, a: &[T], b: &[T]) -> f64 {
+        match *self {
+            GroupingMode::Exact => {
+                if a == b {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            GroupingMode::Lcs(_) => lcs_similarity(a, b),
+            GroupingMode::EditDistance(_) => edit_similarity(a, b),
+        }
+    }
+
+    /// Whether the two sequences go to the same group
+    pub fn similar<T: PartialEq>(&self, a: &[T], b: &[T]) -> bool {
+        let threshold = match *self {
+            GroupingMode::Exact => 1.0,
+            GroupingMode::Lcs(threshold) | GroupingMode::EditDistance(threshold) => threshold,
+        };
+        self.similarity(a, b) >= threshold
+    }
+}
+
+/// Table of LCS lengths of all suffixes of `a` and `b`
+fn lcs_table<T: PartialEq>(a: &[T], b: &[T]) -> Vec<Vec<usize>> {
+    let mut table = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            table[i][j] = if a[i] == b[j] {
+                table[i + 1][j + 1] + 1
+            } else {
+                table[i + 1][j].max(table[i][j + 1])
+            };
+        }
+    }
+    table
+}
+
+pub fn lcs_similarity<T: PartialEq>(a: &[T], b: &[T]) -> f64 {
+    if a.is_empty() && b.is_empty() {
+        return 1.0;
+    }
+    2.0 * lcs_table(a, b)[0][0] as f64 / (a.len() + b.len()) as f64
+}
+
+pub fn edit_distance<T: PartialEq>(a: &[T], b: &[T]) -> usize {
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for i in 0..a.len() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for j in 0..b.len() {
+            let substitution = prev[j] + if a[i] == b[j] { 0 } else { 1 };
+            cur[j + 1] = substitution.min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+pub fn edit_similarity<T: PartialEq>(a: &[T], b: &[T]) -> f64 {
+    let longest = a.len().max(b.len());
+    if longest == 0 {
+        return 1.0;
+    }
+    1.0 - edit_distance(a, b) as f64 / longest as f64
+}
+
+/// For each element of `a`, the index of the element of `b` it is matched to in a longest
+/// common subsequence, if any
+pub fn align<T: PartialEq>(a: &[T], b: &[T]) -> Vec<Option<usize>> {
+    let table = lcs_table(a, b);
+    let mut result = vec![None; a.len()];
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            result[i] = Some(j);
+            i += 1;
+            j += 1;
+        } else if table[i + 1][j] >= table[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn similarity_and_alignment() {
+        let a = ['s', 'x', 'y', 'e'];
+        let b = ['s', 'x', 'z', 'y', 'e'];
+        assert_eq!(edit_distance(&a, &b), 1);
+        assert!((lcs_similarity(&a, &b) - 8.0 / 9.0).abs() < 1e-9);
+        assert_eq!(align(&a, &b), vec![Some(0), Some(1), Some(3), Some(4)]);
+        assert!(GroupingMode::EditDistance(0.8).similar(&a, &b));
+        assert!(!GroupingMode::Lcs(0.9).similar(&a, &b));
+        assert!(!GroupingMode::Exact.similar(&a, &b));
+    }
+}
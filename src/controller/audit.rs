@@ -0,0 +1,261 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+use std::error::Error;
+use std::fs::OpenOptions;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use chrono::NaiveDateTime;
+use log::{error, warn};
+use pythia_common::RequestType;
+use serde::{Deserialize, Serialize};
+
+use crate::canary::HostScope;
+use crate::controller::Controller;
+use crate::trace::TracepointID;
+
+/// Name of the redis stream the records are added to
+const AUDIT_STREAM: &str = "pythia_audit";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Enable,
+    Disable,
+    EnableAll,
+    DisableAll,
+}
+
+/// One call to the controller
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditRecord {
+    pub timestamp: NaiveDateTime,
+    pub action: AuditAction,
+    pub tracepoints: Vec<(TracepointID, Option<RequestType>)>,
+    /// The group whose problem edges the tracepoints were picked for, if any
+    pub group: Option<String>,
+    pub strategy: String,
+}
+
+/// Where the records go: a file with one JSON record per line, or a redis stream
+pub enum AuditLog {
+    File(PathBuf),
+    Redis(redis::Client),
+}
+
+impl AuditLog {
+    /// A `redis://` URL or a file path
+    pub fn from_str(s: &str) -> Self {
+        if s.starts_with("redis://") {
+            AuditLog::Redis(redis::Client::open(s).expect("Invalid audit_log redis URL"))
+        } else {
+            AuditLog::File(PathBuf::from(s))
+        }
+    }
+
+    pub fn append(&self, record: &AuditRecord) -> Result<(), Box<dyn Error>> {
+        let line = serde_json::to_string(record)?;
+        match self {
+            AuditLog::File(path) => {
+                let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+                writeln!(file, "{}", line)?;
+            }
+            AuditLog::Redis(client) => {
+                let mut con = client.get_connection()?;
+                redis::cmd("XADD")
+                    .arg(AUDIT_STREAM)
+                    .arg("*")
+                    .arg("record")
+                    .arg(line)
+                    .query::<String>(&mut con)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// All records, oldest first
+    pub fn read(&self) -> Result<Vec<AuditRecord>, Box<dyn Error>> {
+        let lines = match self {
+            AuditLog::File(path) => BufReader::new(std::fs::File::open(path)?)
+                .lines()
+                .collect::<Result<Vec<_>, _>>()?,
+            AuditLog::Redis(client) => {
+                let mut con = client.get_connection()?;
+                let entries: Vec<(String, Vec<String>)> = redis::cmd("XRANGE")
+                    .arg(AUDIT_STREAM)
+                    .arg("-")
+                    .arg("+")
+                    .query(&mut con)?;
+                // Each entry is the id and [field, value]
+                entries
+                    .into_iter()
+                    .filter_map(|(_, fields)| fields.into_iter().nth(1))
+                    .collect()
+            }
+        };
+        let mut result = Vec::new();
+        for line in lines.iter().filter(|l| !l.trim().is_empty()) {
+            match serde_json::from_str(line) {
+                Ok(record) => result.push(record),
+                Err(e) => warn!("Skipping malformed audit record {}: {}", line, e),
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// Wraps another controller and records every change to the enabled tracepoints in an audit
+/// log, so a diagnosis run can be reconstructed afterwards. Failing to write a record is
+/// reported but doesn't stop the change.
+pub struct AuditedController {
+    inner: Box<dyn Controller>,
+    log: AuditLog,
+    strategy: String,
+    /// Keeps records from concurrent calls whole and in order
+    lock: Mutex<()>,
+}
+
+impl AuditedController {
+    pub fn new(inner: Box<dyn Controller>, log: AuditLog, strategy: String) -> Self {
+        AuditedController {
+            inner,
+            log,
+            strategy,
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn record(
+        &self,
+        action: AuditAction,
+        tracepoints: &Vec<(TracepointID, Option<RequestType>)>,
+        group: Option<&str>,
+    ) {
+        let record = AuditRecord {
+            timestamp: chrono::Local::now().naive_local(),
+            action,
+            tracepoints: tracepoints.clone(),
+            group: group.map(|g| g.to_string()),
+            strategy: self.strategy.clone(),
+        };
+        if let Err(e) = self.log.append(&record) {
+            error!("Could not write audit record: {}", e);
+        }
+    }
+}
+
+impl Controller for AuditedController {
+    fn enable(&self, points: &Vec<(TracepointID, Option<RequestType>)>) {
+        let _guard = self.lock.lock().unwrap();
+        self.inner.enable(points);
+        self.record(AuditAction::Enable, points, None);
+    }
+
+    fn enable_for_group(&self, points: &Vec<(TracepointID, Option<RequestType>)>, group: &str) {
+        let _guard = self.lock.lock().unwrap();
+        self.inner.enable_for_group(points, group);
+        self.record(AuditAction::Enable, points, Some(group));
+    }
+
+    fn enable_scoped(
+        &self,
+        points: &Vec<(TracepointID, Option<RequestType>)>,
+        group: &str,
+        scope: HostScope,
+    ) {
+        let _guard = self.lock.lock().unwrap();
+        self.inner.enable_scoped(points, group, scope);
+        self.record(AuditAction::Enable, points, Some(group));
+    }
+
+    fn disable(&self, points: &Vec<(TracepointID, Option<RequestType>)>) {
+        let _guard = self.lock.lock().unwrap();
+        self.inner.disable(points);
+        self.record(AuditAction::Disable, points, None);
+    }
+
+    fn is_enabled(&self, point: &(TracepointID, Option<RequestType>)) -> bool {
+        self.inner.is_enabled(point)
+    }
+
+    fn disable_all(&self) {
+        let _guard = self.lock.lock().unwrap();
+        self.inner.disable_all();
+        self.record(AuditAction::DisableAll, &Vec::new(), None);
+    }
+
+    fn enable_all(&self) {
+        let _guard = self.lock.lock().unwrap();
+        self.inner.enable_all();
+        self.record(AuditAction::EnableAll, &Vec::new(), None);
+    }
+
+    fn enabled_tracepoints(&self) -> Vec<(TracepointID, Option<RequestType>)> {
+        self.inner.enabled_tracepoints()
+    }
+
+    fn query_enabled(&self, points: &Vec<(TracepointID, Option<RequestType>)>) -> Vec<bool> {
+        self.inner.query_enabled(points)
+    }
+
+    fn query_all_enabled(&self) -> Option<Vec<(TracepointID, Option<RequestType>)>> {
+        self.inner.query_all_enabled()
+    }
+
+    fn reconcile(&self) -> usize {
+        self.inner.reconcile()
+    }
+
+    fn send_queued(&self) -> usize {
+        self.inner.send_queued()
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+
+    fn canary_trials(&self) -> Vec<(String, Vec<(TracepointID, Option<RequestType>)>)> {
+        self.inner.canary_trials()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::controller::TestController;
+
+    #[test]
+    fn records_changes() {
+        let path = std::env::temp_dir().join(format!("pythia_audit_{}.jsonl", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let controller = AuditedController::new(
+            Box::new(TestController::new()),
+            AuditLog::File(path.clone()),
+            "Flat".to_string(),
+        );
+        let points = vec![(TracepointID::from_str("a"), None)];
+        controller.enable_for_group(&points, "abc");
+        controller.disable(&points);
+        controller.disable_all();
+
+        let records = AuditLog::File(path.clone()).read().unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].action, AuditAction::Enable);
+        assert_eq!(records[0].group.as_deref(), Some("abc"));
+        assert_eq!(records[0].tracepoints, points);
+        assert_eq!(records[1].action, AuditAction::Disable);
+        assert_eq!(records[2].action, AuditAction::DisableAll);
+        assert_eq!(records[2].strategy, "Flat");
+    }
+}
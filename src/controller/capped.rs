@@ -0,0 +1,202 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use log::warn;
+
+use pythia_common::RequestType;
+
+use crate::canary::HostScope;
+use crate::controller::Controller;
+use crate::trace::TracepointID;
+
+/// Wraps another controller and never lets more than `cap` non-skeleton tracepoints be enabled
+/// at the same time, no matter what the budget says.
+///
+/// Enabling requests beyond the cap are dropped and logged. Skeleton tracepoints, disabling, and
+/// the operator-facing `enable_all`/`disable_all` are passed through.
+pub struct CappedController {
+    inner: Box<dyn Controller>,
+    cap: usize,
+    skeleton: Arc<Mutex<HashSet<TracepointID>>>,
+    /// Held while checking the cap and enabling, so concurrent calls can't overshoot
+    enable_lock: Mutex<()>,
+    rejected: AtomicUsize,
+}
+
+impl CappedController {
+    pub fn new(inner: Box<dyn Controller>, cap: usize, skeleton: Vec<TracepointID>) -> Self {
+        let skeleton = Arc::new(Mutex::new(skeleton.into_iter().collect()));
+        CappedController::with_shared_skeleton(inner, cap, skeleton)
+    }
+
+    /// The skeleton can change afterwards, e.g. as an incremental manifest grows
+    pub fn with_shared_skeleton(
+        inner: Box<dyn Controller>,
+        cap: usize,
+        skeleton: Arc<Mutex<HashSet<TracepointID>>>,
+    ) -> Self {
+        CappedController {
+            inner,
+            cap,
+            skeleton,
+            enable_lock: Mutex::new(()),
+            rejected: AtomicUsize::new(0),
+        }
+    }
+
+    /// Number of enabling requests rejected so far
+    pub fn rejected(&self) -> usize {
+        self.rejected.load(Ordering::SeqCst)
+    }
+
+    fn enabled_non_skeleton(&self, skeleton: &HashSet<TracepointID>) -> usize {
+        self.inner
+            .enabled_tracepoints()
+            .iter()
+            .filter(|p| !skeleton.contains(&p.0))
+            .count()
+    }
+
+    fn enable_capped(
+        &self,
+        points: &Vec<(TracepointID, Option<RequestType>)>,
+        group: Option<&str>,
+        scope: HostScope,
+    ) {
+        let _guard = self.enable_lock.lock().unwrap();
+        let skeleton = self.skeleton.lock().unwrap().clone();
+        let mut enabled = self.enabled_non_skeleton(&skeleton);
+        let mut accepted = Vec::new();
+        for p in points {
+            if skeleton.contains(&p.0) || self.inner.is_enabled(p) {
+                accepted.push(p.clone());
+            } else if enabled < self.cap {
+                enabled += 1;
+                accepted.push(p.clone());
+            } else {
+                self.rejected.fetch_add(1, Ordering::SeqCst);
+                warn!(
+                    "Rejected enabling {:?}: {} non-skeleton tracepoints are enabled, cap is {}",
+                    p, enabled, self.cap
+                );
+            }
+        }
+        match (accepted.len(), group) {
+            (0, _) => {}
+            (_, Some(group)) => self.inner.enable_scoped(&accepted, group, scope),
+            (_, None) => self.inner.enable(&accepted),
+        }
+    }
+}
+
+impl Controller for CappedController {
+    fn enable(&self, points: &Vec<(TracepointID, Option<RequestType>)>) {
+        self.enable_capped(points, None, HostScope::All);
+    }
+
+    fn enable_for_group(&self, points: &Vec<(TracepointID, Option<RequestType>)>, group: &str) {
+        self.enable_capped(points, Some(group), HostScope::All);
+    }
+
+    fn enable_scoped(
+        &self,
+        points: &Vec<(TracepointID, Option<RequestType>)>,
+        group: &str,
+        scope: HostScope,
+    ) {
+        self.enable_capped(points, Some(group), scope);
+    }
+
+    fn disable(&self, points: &Vec<(TracepointID, Option<RequestType>)>) {
+        self.inner.disable(points);
+    }
+
+    fn is_enabled(&self, point: &(TracepointID, Option<RequestType>)) -> bool {
+        self.inner.is_enabled(point)
+    }
+
+    fn disable_all(&self) {
+        self.inner.disable_all();
+    }
+
+    fn enable_all(&self) {
+        warn!("Enabling all tracepoints, the cap of {} does not apply", self.cap);
+        self.inner.enable_all();
+    }
+
+    fn enabled_tracepoints(&self) -> Vec<(TracepointID, Option<RequestType>)> {
+        self.inner.enabled_tracepoints()
+    }
+
+    fn query_enabled(&self, points: &Vec<(TracepointID, Option<RequestType>)>) -> Vec<bool> {
+        self.inner.query_enabled(points)
+    }
+
+    fn query_all_enabled(&self) -> Option<Vec<(TracepointID, Option<RequestType>)>> {
+        self.inner.query_all_enabled()
+    }
+
+    fn reconcile(&self) -> usize {
+        self.inner.reconcile()
+    }
+
+    fn send_queued(&self) -> usize {
+        self.inner.send_queued()
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+
+    fn canary_trials(&self) -> Vec<(String, Vec<(TracepointID, Option<RequestType>)>)> {
+        self.inner.canary_trials()
+    }
+
+    fn fits(&self, points: &Vec<(TracepointID, Option<RequestType>)>) -> bool {
+        let skeleton = self.skeleton.lock().unwrap().clone();
+        let new: HashSet<_> = points
+            .iter()
+            .filter(|p| !skeleton.contains(&p.0) && !self.inner.is_enabled(p))
+            .collect();
+        self.enabled_non_skeleton(&skeleton) + new.len() <= self.cap
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::controller::TestController;
+
+    #[test]
+    fn cap_is_enforced() {
+        let skeleton = TracepointID::from_str("skeleton");
+        let controller =
+            CappedController::new(Box::new(TestController::new()), 2, vec![skeleton]);
+        let points = ["a", "b", "c"]
+            .iter()
+            .map(|s| (TracepointID::from_str(s), None))
+            .collect::<Vec<_>>();
+        controller.enable(&vec![(skeleton, None)]);
+        controller.enable(&points);
+        assert_eq!(controller.enabled_tracepoints().len(), 3);
+        assert!(!controller.is_enabled(&points[2]));
+        assert_eq!(controller.rejected(), 1);
+
+        // Re-enabling doesn't count twice, and disabling makes room
+        controller.enable(&vec![points[0]]);
+        assert_eq!(controller.rejected(), 1);
+        controller.disable(&vec![points[0]]);
+        controller.enable(&vec![points[2]]);
+        assert!(controller.is_enabled(&points[2]));
+    }
+}
@@ -0,0 +1,297 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use log::{info, warn};
+
+use pythia_common::RequestType;
+
+use crate::canary::HostScope;
+use crate::controller::Controller;
+use crate::manifest::TracepointDependencies;
+use crate::trace::TracepointID;
+
+/// Wraps another controller and enables the tracepoints each enabled tracepoint depends on
+/// along with it, so it doesn't stay silent because a span around it isn't recorded.
+///
+/// Enabling a tracepoint that is on no manifest path of its request type, or disabling one that
+/// enabled tracepoints depend on, is passed through with a warning. Prerequisites enabled this
+/// way are disabled again once nothing that needed them is enabled, and a tracepoint is only
+/// enabled if it fits under the cap along with its prerequisites.
+pub struct DependentController {
+    inner: Box<dyn Controller>,
+    dependencies: TracepointDependencies,
+    /// Prerequisites enabled only because of others, with the tracepoints that need them
+    needed_by: Mutex<NeededBy>,
+}
+
+type NeededBy =
+    HashMap<(TracepointID, Option<RequestType>), HashSet<(TracepointID, Option<RequestType>)>>;
+
+impl DependentController {
+    pub fn new(inner: Box<dyn Controller>, dependencies: TracepointDependencies) -> Self {
+        DependentController {
+            inner,
+            dependencies,
+            needed_by: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Enables the points with the prerequisites that aren't enabled yet before each of them.
+    /// A point that doesn't fit along with its prerequisites is left out with them.
+    fn enable_with<F>(&self, points: &Vec<(TracepointID, Option<RequestType>)>, enable: F)
+    where
+        F: Fn(&Vec<(TracepointID, Option<RequestType>)>),
+    {
+        let mut needed_by = self.needed_by.lock().unwrap();
+        let mut result: Vec<(TracepointID, Option<RequestType>)> = Vec::new();
+        for p in points {
+            let mut needs = Vec::new();
+            let mut unit = Vec::new();
+            match self.dependencies.prerequisites(p) {
+                Some(prerequisites) => {
+                    for &tp in prerequisites {
+                        let prerequisite = (tp, p.1);
+                        if points.contains(&prerequisite) {
+                            continue;
+                        }
+                        let enabled = self.inner.is_enabled(&prerequisite);
+                        if !enabled || needed_by.contains_key(&prerequisite) {
+                            needs.push(prerequisite);
+                        }
+                        if !enabled && !result.contains(&prerequisite) {
+                            unit.push(prerequisite);
+                        }
+                    }
+                }
+                None => warn!("{:?} is on no manifest path, it won't emit events", p),
+            }
+            if !result.contains(p) {
+                unit.push(*p);
+            }
+            let mut candidate = result.clone();
+            candidate.extend(unit.iter().cloned());
+            if !self.inner.fits(&candidate) {
+                warn!(
+                    "Not enabling {:?}, it doesn't fit under the cap with its prerequisites",
+                    p
+                );
+                continue;
+            }
+            for prerequisite in unit.iter().filter(|u| *u != p) {
+                info!("Also enabling {:?}, which {:?} depends on", prerequisite, p);
+            }
+            result = candidate;
+            for prerequisite in needs {
+                needed_by.entry(prerequisite).or_default().insert(*p);
+            }
+        }
+        // Enabled on purpose now, so they stay when what needed them goes
+        for p in points {
+            needed_by.remove(p);
+        }
+        if !result.is_empty() {
+            enable(&result);
+        }
+    }
+}
+
+impl Controller for DependentController {
+    fn enable(&self, points: &Vec<(TracepointID, Option<RequestType>)>) {
+        self.enable_with(points, |points| self.inner.enable(points));
+    }
+
+    fn enable_for_group(&self, points: &Vec<(TracepointID, Option<RequestType>)>, group: &str) {
+        self.enable_with(points, |points| self.inner.enable_for_group(points, group));
+    }
+
+    fn enable_scoped(
+        &self,
+        points: &Vec<(TracepointID, Option<RequestType>)>,
+        group: &str,
+        scope: HostScope,
+    ) {
+        self.enable_with(points, |points| {
+            self.inner.enable_scoped(points, group, scope)
+        });
+    }
+
+    /// Also disables the prerequisites nothing enabled needs any more
+    fn disable(&self, points: &Vec<(TracepointID, Option<RequestType>)>) {
+        let mut needed_by = self.needed_by.lock().unwrap();
+        let mut to_disable = points.clone();
+        for p in points {
+            needed_by.remove(p);
+        }
+        needed_by.retain(|prerequisite, users| {
+            users.retain(|u| !points.contains(u));
+            if users.is_empty() && !to_disable.contains(prerequisite) {
+                info!("Also disabling {:?}, nothing needs it", prerequisite);
+                to_disable.push(*prerequisite);
+            }
+            !users.is_empty()
+        });
+        let still_enabled: Vec<_> = self
+            .inner
+            .enabled_tracepoints()
+            .into_iter()
+            .filter(|e| !to_disable.contains(e))
+            .collect();
+        for p in points {
+            for e in &still_enabled {
+                if (p.1.is_none() || p.1 == e.1) && self.dependencies.depends_on(e, p.0) {
+                    warn!("Disabling {:?}, which {:?} depends on", p, e);
+                }
+            }
+        }
+        self.inner.disable(&to_disable);
+    }
+
+    fn is_enabled(&self, point: &(TracepointID, Option<RequestType>)) -> bool {
+        self.inner.is_enabled(point)
+    }
+
+    fn disable_all(&self) {
+        self.needed_by.lock().unwrap().clear();
+        self.inner.disable_all();
+    }
+
+    fn enable_all(&self) {
+        self.needed_by.lock().unwrap().clear();
+        self.inner.enable_all();
+    }
+
+    fn enabled_tracepoints(&self) -> Vec<(TracepointID, Option<RequestType>)> {
+        self.inner.enabled_tracepoints()
+    }
+
+    fn query_enabled(&self, points: &Vec<(TracepointID, Option<RequestType>)>) -> Vec<bool> {
+        self.inner.query_enabled(points)
+    }
+
+    fn query_all_enabled(&self) -> Option<Vec<(TracepointID, Option<RequestType>)>> {
+        self.inner.query_all_enabled()
+    }
+
+    fn reconcile(&self) -> usize {
+        self.inner.reconcile()
+    }
+
+    fn send_queued(&self) -> usize {
+        self.inner.send_queued()
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+
+    fn canary_trials(&self) -> Vec<(String, Vec<(TracepointID, Option<RequestType>)>)> {
+        self.inner.canary_trials()
+    }
+
+    fn fits(&self, points: &Vec<(TracepointID, Option<RequestType>)>) -> bool {
+        self.inner.fits(points)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use chrono::NaiveDateTime;
+    use uuid::Uuid;
+
+    use crate::controller::CappedController;
+    use crate::controller::TestController;
+    use crate::manifest::Manifest;
+    use crate::trace::Span;
+    use crate::trace::Trace;
+
+    /// outer [ inner [ leaf ] ]
+    fn nested() -> Manifest {
+        let epoch = NaiveDateTime::parse_from_str("2000/01/01 00:00", "%Y/%m/%d %H:%M").unwrap();
+        let at = |ms| epoch + chrono::Duration::milliseconds(ms);
+        let tp = TracepointID::from_str;
+        // outer [ inner [ leaf ] ]
+        let spans: Vec<Span> = ["outer", "inner", "leaf"]
+            .iter()
+            .enumerate()
+            .map(|(i, name)| Span {
+                span_id: Uuid::from_u128(i as u128 + 1),
+                parent: Some(Uuid::from_u128(i as u128)),
+                tracepoint_id: tp(name),
+                start: at(i as i64),
+                end: at(10 - i as i64),
+                key_value_pair: HashMap::new(),
+            })
+            .collect();
+        let trace = Trace::from_spans(&Uuid::from_u128(0), &spans).unwrap();
+        Manifest::from_trace_list(&vec![trace])
+    }
+
+    #[test]
+    fn enables_enclosing_spans_first() {
+        let tp = TracepointID::from_str;
+        let manifest = nested();
+        let controller =
+            DependentController::new(Box::new(TestController::new()), manifest.dependencies());
+        controller.enable(&vec![(tp("outer"), None)]);
+        controller.enable(&vec![(tp("leaf"), None)]);
+        let mut enabled = controller.enabled_tracepoints();
+        enabled.sort_by_key(|p| p.0.to_string());
+        assert_eq!(
+            enabled,
+            vec![(tp("inner"), None), (tp("leaf"), None), (tp("outer"), None)]
+        );
+
+        // Tracepoints on no path are still enabled
+        controller.enable(&vec![(tp("elsewhere"), None)]);
+        assert!(controller.is_enabled(&(tp("elsewhere"), None)));
+    }
+
+    #[test]
+    fn releases_prerequisites() {
+        let tp = TracepointID::from_str;
+        let manifest = nested();
+        let controller =
+            DependentController::new(Box::new(TestController::new()), manifest.dependencies());
+        controller.enable(&vec![(tp("outer"), None)]);
+        controller.enable(&vec![(tp("leaf"), None)]);
+        controller.disable(&vec![(tp("leaf"), None)]);
+        // Outer was enabled on its own
+        assert_eq!(controller.enabled_tracepoints(), vec![(tp("outer"), None)]);
+        controller.disable(&vec![(tp("outer"), None)]);
+
+        // Inner stays once it is enabled on its own, and keeps outer
+        controller.enable(&vec![(tp("leaf"), None)]);
+        controller.enable(&vec![(tp("inner"), None)]);
+        controller.disable(&vec![(tp("leaf"), None)]);
+        let mut enabled = controller.enabled_tracepoints();
+        enabled.sort_by_key(|p| p.0.to_string());
+        assert_eq!(enabled, vec![(tp("inner"), None), (tp("outer"), None)]);
+        controller.disable(&vec![(tp("inner"), None)]);
+        assert!(controller.enabled_tracepoints().is_empty());
+    }
+
+    #[test]
+    fn enables_nothing_over_the_cap() {
+        let tp = TracepointID::from_str;
+        let manifest = nested();
+        let capped = CappedController::new(Box::new(TestController::new()), 2, Vec::new());
+        let controller = DependentController::new(Box::new(capped), manifest.dependencies());
+        controller.enable(&vec![(tp("leaf"), None)]);
+        assert!(controller.enabled_tracepoints().is_empty());
+        controller.enable(&vec![(tp("inner"), None)]);
+        assert_eq!(controller.enabled_tracepoints().len(), 2);
+    }
+}
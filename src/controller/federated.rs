@@ -0,0 +1,194 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+use std::collections::HashSet;
+
+use pythia_common::RequestType;
+
+use crate::canary::HostScope;
+use crate::controller::Controller;
+use crate::trace::TracepointID;
+
+/// Controls several applications at once, e.g., OpenStack and the HDFS cluster under it.
+///
+/// Each tracepoint is sent to the controller of the application whose manifest has it; the
+/// first application gets the tracepoints no manifest knows about.
+pub struct FederatedController {
+    members: Vec<(Box<dyn Controller>, HashSet<TracepointID>)>,
+}
+
+impl FederatedController {
+    /// Controllers with the tracepoints of their application, first one is the default
+    pub fn new(members: Vec<(Box<dyn Controller>, HashSet<TracepointID>)>) -> Self {
+        assert!(!members.is_empty());
+        FederatedController { members }
+    }
+
+    fn owner(&self, tracepoint: &TracepointID) -> usize {
+        self.members
+            .iter()
+            .position(|(_, tracepoints)| tracepoints.contains(tracepoint))
+            .unwrap_or(0)
+    }
+
+    fn split(
+        &self,
+        points: &Vec<(TracepointID, Option<RequestType>)>,
+    ) -> Vec<Vec<(TracepointID, Option<RequestType>)>> {
+        let mut result = vec![Vec::new(); self.members.len()];
+        for p in points {
+            result[self.owner(&p.0)].push(p.clone());
+        }
+        result
+    }
+}
+
+impl Controller for FederatedController {
+    fn enable(&self, points: &Vec<(TracepointID, Option<RequestType>)>) {
+        for (member, points) in self.members.iter().zip(self.split(points)) {
+            if !points.is_empty() {
+                member.0.enable(&points);
+            }
+        }
+    }
+
+    fn enable_for_group(&self, points: &Vec<(TracepointID, Option<RequestType>)>, group: &str) {
+        for (member, points) in self.members.iter().zip(self.split(points)) {
+            if !points.is_empty() {
+                member.0.enable_for_group(&points, group);
+            }
+        }
+    }
+
+    fn enable_scoped(
+        &self,
+        points: &Vec<(TracepointID, Option<RequestType>)>,
+        group: &str,
+        scope: HostScope,
+    ) {
+        for (member, points) in self.members.iter().zip(self.split(points)) {
+            if !points.is_empty() {
+                member.0.enable_scoped(&points, group, scope);
+            }
+        }
+    }
+
+    fn disable(&self, points: &Vec<(TracepointID, Option<RequestType>)>) {
+        for (member, points) in self.members.iter().zip(self.split(points)) {
+            if !points.is_empty() {
+                member.0.disable(&points);
+            }
+        }
+    }
+
+    fn is_enabled(&self, point: &(TracepointID, Option<RequestType>)) -> bool {
+        self.members[self.owner(&point.0)].0.is_enabled(point)
+    }
+
+    fn disable_all(&self) {
+        for (controller, _) in &self.members {
+            controller.disable_all();
+        }
+    }
+
+    fn enable_all(&self) {
+        for (controller, _) in &self.members {
+            controller.enable_all();
+        }
+    }
+
+    fn enabled_tracepoints(&self) -> Vec<(TracepointID, Option<RequestType>)> {
+        self.members
+            .iter()
+            .flat_map(|(controller, _)| controller.enabled_tracepoints())
+            .collect()
+    }
+
+    fn query_enabled(&self, points: &Vec<(TracepointID, Option<RequestType>)>) -> Vec<bool> {
+        // Each member answers for its points in the order they were given
+        let mut answers: Vec<_> = self
+            .members
+            .iter()
+            .zip(self.split(points))
+            .map(|(member, points)| member.0.query_enabled(&points).into_iter())
+            .collect();
+        points
+            .iter()
+            .map(|p| answers[self.owner(&p.0)].next().unwrap())
+            .collect()
+    }
+
+    /// None unless every member can list its settings
+    fn query_all_enabled(&self) -> Option<Vec<(TracepointID, Option<RequestType>)>> {
+        let mut result = Vec::new();
+        for (controller, _) in self.members.iter() {
+            result.extend(controller.query_all_enabled()?);
+        }
+        Some(result)
+    }
+
+    fn reconcile(&self) -> usize {
+        self.members.iter().map(|(c, _)| c.reconcile()).sum()
+    }
+
+    fn send_queued(&self) -> usize {
+        self.members.iter().map(|(c, _)| c.send_queued()).sum()
+    }
+
+    fn flush(&self) {
+        for (c, _) in self.members.iter() {
+            c.flush();
+        }
+    }
+
+    fn canary_trials(&self) -> Vec<(String, Vec<(TracepointID, Option<RequestType>)>)> {
+        self.members
+            .iter()
+            .flat_map(|(c, _)| c.canary_trials())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::controller::TestController;
+
+    #[test]
+    fn routes_by_manifest() {
+        let (nova, hdfs) = (
+            TracepointID::from_str("federated-nova"),
+            TracepointID::from_str("federated-hdfs"),
+        );
+        let other = TracepointID::from_str("federated-other");
+        let controller = FederatedController::new(vec![
+            (
+                Box::new(TestController::new()),
+                vec![nova].into_iter().collect(),
+            ),
+            (
+                Box::new(TestController::new()),
+                vec![hdfs].into_iter().collect(),
+            ),
+        ]);
+        controller.enable(&vec![(nova, None), (hdfs, None), (other, None)]);
+        assert_eq!(controller.members[0].0.enabled_tracepoints().len(), 2);
+        assert_eq!(
+            controller.members[1].0.enabled_tracepoints(),
+            vec![(hdfs, None)]
+        );
+        controller.disable(&vec![(hdfs, None)]);
+        assert!(!controller.is_enabled(&(hdfs, None)));
+        assert_eq!(controller.enabled_tracepoints().len(), 2);
+        assert_eq!(
+            controller.query_enabled(&vec![(hdfs, None), (other, None), (nova, None)]),
+            vec![false, true, true]
+        );
+    }
+}
@@ -7,13 +7,15 @@ All rights reserved.
 */
 
 use std::collections::HashSet;
+use std::fs::read_to_string;
 use std::fs::File;
 use std::io::prelude::*;
 use std::mem::drop;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
 use itertools::Itertools;
+use log::info;
 
 use pythia_common::RequestType;
 
@@ -32,7 +34,7 @@ pub struct HDFSController {
 
 impl Controller for HDFSController {
     fn enable(&self, points: &Vec<(TracepointID, Option<RequestType>)>) {
-        eprintln!("Enabling {:?}", points);
+        info!("Enabling {:?}", points);
         let mut disabled_tracepoints = self.disabled_tracepoints.lock().unwrap();
         for p in points {
             disabled_tracepoints.remove(&p.0);
@@ -42,7 +44,7 @@ impl Controller for HDFSController {
     }
 
     fn disable(&self, points: &Vec<(TracepointID, Option<RequestType>)>) {
-        eprintln!("Disabling {:?}", points);
+        info!("Disabling {:?}", points);
         let mut disabled_tracepoints = self.disabled_tracepoints.lock().unwrap();
         for p in points {
             disabled_tracepoints.insert(p.0);
@@ -80,15 +82,27 @@ impl Controller for HDFSController {
             .cloned()
             .collect()
     }
+
+    /// The control file is read again, in case other controllers changed it. Request types are
+    /// ignored, the control file has none.
+    fn query_enabled(&self, points: &Vec<(TracepointID, Option<RequestType>)>) -> Vec<bool> {
+        match read_control_file(&self.controller_file) {
+            Some(disabled) => points.iter().map(|p| !disabled.contains(&p.0)).collect(),
+            None => points.iter().map(|p| self.is_enabled(p)).collect(),
+        }
+    }
 }
 
 impl HDFSController {
+    /// Starts from the tracepoints the control file already disables, so that changes made
+    /// through other controllers stay
     pub fn from_settings(settings: &Settings) -> Self {
         let manifest = Manifest::from_file(&settings.manifest_file.as_path()).unwrap();
+        let disabled = read_control_file(&settings.hdfs_control_file).unwrap_or_default();
         HDFSController {
             controller_file: settings.hdfs_control_file.clone(),
             all_tracepoints: manifest.all_tracepoints(),
-            disabled_tracepoints: Arc::new(Mutex::new(HashSet::new())),
+            disabled_tracepoints: Arc::new(Mutex::new(disabled)),
             enabled_tracepoints: Arc::new(Mutex::new(HashSet::new())),
         }
     }
@@ -105,3 +119,50 @@ impl HDFSController {
         writeln!(writer, "{}", tracepoints.iter().join("\n")).ok();
     }
 }
+
+/// The tracepoints the control file disables, one per line; None if it can't be read
+fn read_control_file(path: &Path) -> Option<HashSet<TracepointID>> {
+    let contents = read_to_string(path).ok()?;
+    Some(
+        contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(TracepointID::from_str)
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_what_it_writes() {
+        let path = std::env::temp_dir().join(format!("pythia-hdfs-{}", std::process::id()));
+        let (a, b) = (
+            TracepointID::from_str("hdfs-control/a"),
+            TracepointID::from_str("hdfs-control/b"),
+        );
+        let controller = HDFSController {
+            controller_file: path.clone(),
+            all_tracepoints: vec![a, b].into_iter().collect(),
+            disabled_tracepoints: Arc::new(Mutex::new(HashSet::new())),
+            enabled_tracepoints: Arc::new(Mutex::new(HashSet::new())),
+        };
+        controller.disable(&vec![(a, None), (b, None)]);
+        // As another controller would
+        std::fs::write(&path, format!("{}\n", a)).unwrap();
+        assert_eq!(
+            controller.query_enabled(&vec![(a, None), (b, None)]),
+            vec![false, true]
+        );
+        controller.disable(&vec![(a, None), (b, None)]);
+        assert_eq!(
+            read_control_file(&path),
+            Some(vec![a, b].into_iter().collect())
+        );
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read_control_file(&path), None);
+    }
+}
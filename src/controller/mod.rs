@@ -8,20 +8,47 @@ All rights reserved.
 
 //! Controller has an API for sending control signals. OSProfilerController sends the orders to
 //! agents while HDFSController writes the control signals to a local file. TestController does nothing.
+//! SimulatedController records what would have been done while replaying archived traces.
+//! CappedController wraps any of them and enforces a cluster-wide limit on enabled tracepoints.
+//! DependentController wraps any of them and also enables the tracepoints of enclosing spans.
+//! AuditedController records every change in an audit log (see `audit_log` in the settings).
+//! FederatedController sends each tracepoint to the controller of the application it belongs to.
+//! Only OSProfilerController can limit a change to the canary agents (see `enable_scoped`).
 
+mod audit;
+mod capped;
+mod dependent;
+mod federated;
 mod hdfs;
 mod osprofiler;
+mod rate;
+mod simulated;
 
+use pythia_common::PythiaError;
 use pythia_common::RequestType;
+use regex::Regex;
 
+use crate::canary::HostScope;
 use crate::controller::hdfs::HDFSController;
 use crate::controller::osprofiler::OSProfilerController;
 use crate::settings::ApplicationType;
 use crate::settings::Settings;
 use crate::trace::TracepointID;
 
+pub use crate::controller::audit::AuditAction;
+pub use crate::controller::audit::AuditLog;
+pub use crate::controller::audit::AuditRecord;
+pub use crate::controller::audit::AuditedController;
+pub use crate::controller::capped::CappedController;
+pub use crate::controller::dependent::DependentController;
+pub use crate::controller::federated::FederatedController;
+pub use crate::controller::rate::ChangeLimiter;
+pub use crate::controller::simulated::SimulatedAction;
+pub use crate::controller::simulated::SimulatedController;
 
 use std::collections::HashSet;
+use std::fs::read_to_string;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 
 pub trait Controller: Send + Sync {
@@ -32,17 +59,183 @@ pub trait Controller: Send + Sync {
     fn enable_all(&self);
     fn enabled_tracepoints(&self) -> Vec<(TracepointID, Option<RequestType>)>;
 
+    /// Like `is_enabled`, but asks wherever the tracepoints are set instead of relying on what
+    /// this controller has done so far
+    fn query_enabled(&self, points: &Vec<(TracepointID, Option<RequestType>)>) -> Vec<bool> {
+        points.iter().map(|p| self.is_enabled(p)).collect()
+    }
+
+    /// Every setting that is on wherever the tracepoints are set, including per-request-type
+    /// settings and tracepoints that aren't in the manifest. None if the settings can't be
+    /// listed, so only known tracepoints can be asked about with `query_enabled`.
+    fn query_all_enabled(&self) -> Option<Vec<(TracepointID, Option<RequestType>)>> {
+        None
+    }
+
+    /// Makes the agents match what this controller enabled again, e.g. after a node was
+    /// reimaged. Returns how many settings were repaired.
+    fn reconcile(&self) -> usize {
+        0
+    }
+
+    /// Sends the tracepoint changes held back by rate limiting that can go out now, and returns
+    /// how many were sent. Called every cycle of the controller loop.
+    fn send_queued(&self) -> usize {
+        0
+    }
+
+    /// Waits until every change held back by rate limiting is sent, e.g. before a command that
+    /// changed tracepoints exits
+    fn flush(&self) {}
+
+    /// Tracepoints enabled only on the canary agents (see `enable_scoped`), by the group they
+    /// were enabled for. Enabling them for the group everywhere promotes them.
+    fn canary_trials(&self) -> Vec<(String, Vec<(TracepointID, Option<RequestType>)>)> {
+        Vec::new()
+    }
+
+    /// Whether enabling all the points together stays within the limits of this controller
+    fn fits(&self, _points: &Vec<(TracepointID, Option<RequestType>)>) -> bool {
+        true
+    }
+
     fn disable_by_name(&self, point: &str) {
         self.disable(&vec![(TracepointID::from_str(point), None)]);
     }
+
+    /// Enable tracepoints picked for the problem edges of a group; the group is only used for
+    /// bookkeeping
+    fn enable_for_group(&self, points: &Vec<(TracepointID, Option<RequestType>)>, _group: &str) {
+        self.enable(points);
+    }
+
+    /// Like `enable_for_group`, but only on the agents in `scope`. Controllers that can't enable
+    /// tracepoints on some agents only enable them everywhere.
+    fn enable_scoped(
+        &self,
+        points: &Vec<(TracepointID, Option<RequestType>)>,
+        group: &str,
+        _scope: HostScope,
+    ) {
+        self.enable_for_group(points, group);
+    }
+
+    /// Enable the tracepoints out of `known` (usually the manifest's) whose ID matches the
+    /// pattern, for all request types. Returns what was enabled.
+    fn enable_matching(&self, pattern: &Regex, known: &HashSet<TracepointID>) -> Vec<TracepointID> {
+        let points = matching(pattern, known);
+        self.enable(&points.iter().map(|&tp| (tp, None)).collect());
+        points
+    }
+
+    fn disable_matching(
+        &self,
+        pattern: &Regex,
+        known: &HashSet<TracepointID>,
+    ) -> Vec<TracepointID> {
+        let points = matching(pattern, known);
+        self.disable(&points.iter().map(|&tp| (tp, None)).collect());
+        points
+    }
+
+    /// Enable every tracepoint out of `known` (usually the manifest's tracepoints of the request
+    /// type) for `request_type` only; the other request types keep their settings. Returns what
+    /// was enabled.
+    fn enable_all_for(
+        &self,
+        request_type: RequestType,
+        known: &HashSet<TracepointID>,
+    ) -> Vec<TracepointID> {
+        let points = sorted(known);
+        self.enable(&points.iter().map(|&tp| (tp, Some(request_type))).collect());
+        points
+    }
+
+    /// Disable every tracepoint out of `known` for `request_type` only, like `enable_all_for`.
+    /// Returns what was disabled.
+    fn disable_all_for(
+        &self,
+        request_type: RequestType,
+        known: &HashSet<TracepointID>,
+    ) -> Vec<TracepointID> {
+        let points = sorted(known);
+        self.disable(&points.iter().map(|&tp| (tp, Some(request_type))).collect());
+        points
+    }
+
+    /// Enable the tracepoints listed in a file, one ID per line. Empty lines and lines starting
+    /// with `#` are skipped. Nothing is enabled if a tracepoint is not in `known`.
+    fn enable_from_file(
+        &self,
+        path: &Path,
+        known: &HashSet<TracepointID>,
+    ) -> Result<Vec<TracepointID>, PythiaError> {
+        let points = read_tracepoint_file(path, known)?;
+        self.enable(&points.iter().map(|&tp| (tp, None)).collect());
+        Ok(points)
+    }
+}
+
+fn matching(pattern: &Regex, known: &HashSet<TracepointID>) -> Vec<TracepointID> {
+    let mut result = known
+        .iter()
+        .map(|tp| (tp.to_string(), *tp))
+        .filter(|(name, _)| pattern.is_match(name))
+        .collect::<Vec<_>>();
+    result.sort_by(|a, b| a.0.cmp(&b.0));
+    result.into_iter().map(|(_, tp)| tp).collect()
+}
+
+fn sorted(known: &HashSet<TracepointID>) -> Vec<TracepointID> {
+    let mut result = known.iter().cloned().collect::<Vec<_>>();
+    result.sort_by_key(|tp| tp.to_string());
+    result
+}
+
+fn read_tracepoint_file(
+    path: &Path,
+    known: &HashSet<TracepointID>,
+) -> Result<Vec<TracepointID>, PythiaError> {
+    let mut result = Vec::new();
+    let mut unknown = Vec::new();
+    for line in read_to_string(path)?.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let tp = TracepointID::from_str(line);
+        if known.contains(&tp) {
+            result.push(tp);
+        } else {
+            unknown.push(line.to_string());
+        }
+    }
+    if !unknown.is_empty() {
+        return Err(PythiaError::ControllerError(format!(
+            "Not in the manifest: {}",
+            unknown.join(", ")
+        )));
+    }
+    Ok(result)
 }
 
 pub fn controller_from_settings(settings: &Settings) -> Box<dyn Controller> {
-    match &settings.application {
-        ApplicationType::OpenStack => Box::new(OSProfilerController::from_settings(settings)),
-        ApplicationType::HDFS => Box::new(HDFSController::from_settings(settings)),
-        ApplicationType::DEATHSTAR => Box::new(HDFSController::from_settings(settings)),
-        ApplicationType::Uber => panic!("Can't control uber"),
+    let controller: Box<dyn Controller> = if settings.replay_dir.is_some() {
+        Box::new(SimulatedController::new())
+    } else {
+        match &settings.application {
+            ApplicationType::OpenStack => Box::new(OSProfilerController::from_settings(settings)),
+            ApplicationType::HDFS => Box::new(HDFSController::from_settings(settings)),
+            ApplicationType::DEATHSTAR => Box::new(HDFSController::from_settings(settings)),
+            ApplicationType::Uber => panic!("Can't control uber"),
+        }
+    };
+    match &settings.audit_log {
+        Some(log) => Box::new(AuditedController::new(
+            controller,
+            AuditLog::from_str(log),
+            format!("{:?}", settings.search_strategy),
+        )),
+        None => controller,
     }
 }
 
@@ -59,13 +252,27 @@ impl TestController {
     }
 }
 
+/// Only keeps track of what is enabled, without sending anything anywhere
 impl Controller for TestController {
-    fn enable(&self, _: &Vec<(TracepointID, Option<RequestType>)>) {}
-    fn disable(&self, _: &Vec<(TracepointID, Option<RequestType>)>) {}
-    fn is_enabled(&self, _: &(TracepointID, Option<RequestType>)) -> bool {
-        false
+    fn enable(&self, points: &Vec<(TracepointID, Option<RequestType>)>) {
+        let mut enabled_tracepoints = self.enabled_tracepoints.lock().unwrap();
+        for p in points {
+            enabled_tracepoints.insert(p.clone());
+        }
+    }
+    fn disable(&self, points: &Vec<(TracepointID, Option<RequestType>)>) {
+        let mut enabled_tracepoints = self.enabled_tracepoints.lock().unwrap();
+        for p in points {
+            enabled_tracepoints.remove(p);
+        }
+    }
+    fn is_enabled(&self, point: &(TracepointID, Option<RequestType>)) -> bool {
+        let enabled_tracepoints = self.enabled_tracepoints.lock().unwrap();
+        enabled_tracepoints.contains(point) || enabled_tracepoints.contains(&(point.0, None))
+    }
+    fn disable_all(&self) {
+        self.enabled_tracepoints.lock().unwrap().clear();
     }
-    fn disable_all(&self) {}
     fn enable_all(&self) {}
     fn enabled_tracepoints(&self) -> Vec<(TracepointID, Option<RequestType>)> {
         self.enabled_tracepoints
@@ -76,3 +283,55 @@ impl Controller for TestController {
             .collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    #[test]
+    fn enables_by_pattern_and_file() {
+        let known: HashSet<TracepointID> = ["nova/compute.py:10", "nova/api.py:20", "glance:30"]
+            .iter()
+            .map(|s| TracepointID::from_str(s))
+            .collect();
+        let controller = TestController::new();
+        let enabled = controller.enable_matching(&Regex::new("^nova/").unwrap(), &known);
+        assert_eq!(
+            enabled,
+            vec![
+                TracepointID::from_str("nova/api.py:20"),
+                TracepointID::from_str("nova/compute.py:10")
+            ]
+        );
+        controller.disable_matching(&Regex::new("compute").unwrap(), &known);
+        assert_eq!(controller.enabled_tracepoints().len(), 1);
+
+        let path = std::env::temp_dir().join(format!("pythia-tracepoints-{}", std::process::id()));
+        let mut file = std::fs::File::create(&path).unwrap();
+        writeln!(file, "# experiment 1\nglance:30\n\n  nova/compute.py:10").unwrap();
+        assert_eq!(controller.enable_from_file(&path, &known).unwrap().len(), 2);
+        assert_eq!(controller.enabled_tracepoints().len(), 3);
+        writeln!(file, "cinder:40").unwrap();
+        assert!(controller.enable_from_file(&path, &known).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn enables_all_for_a_request_type() {
+        let create = RequestType::from_str("ServerCreate").unwrap();
+        let delete = RequestType::from_str("ServerDelete").unwrap();
+        let known: HashSet<TracepointID> = ["nova/compute.py:10", "nova/api.py:20"]
+            .iter()
+            .map(|s| TracepointID::from_str(s))
+            .collect();
+        let api = TracepointID::from_str("nova/api.py:20");
+        let controller = TestController::new();
+        assert_eq!(controller.enable_all_for(create, &known)[0], api);
+        assert!(controller.is_enabled(&(api, Some(create))));
+        assert!(!controller.is_enabled(&(api, Some(delete))));
+        controller.disable_all_for(create, &known);
+        assert!(controller.enabled_tracepoints().is_empty());
+    }
+}
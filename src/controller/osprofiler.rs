@@ -6,56 +6,86 @@ Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
 All rights reserved.
 */
 
+use std::collections::HashMap;
 use std::collections::HashSet;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{error, info, warn};
 
 use pythia_common::RequestType;
 
+use crate::canary::canary_clients;
+use crate::canary::HostScope;
+use crate::controller::ChangeLimiter;
 use crate::controller::Controller;
+use crate::rpclib::call_all_clients;
+use crate::rpclib::read_client_tracepoint_state;
+use crate::rpclib::read_client_tracepoints;
 use crate::rpclib::set_all_client_tracepoints;
 use crate::rpclib::set_client_tracepoints;
+use crate::rpclib::RetryPolicy;
 use crate::settings::Settings;
 use crate::trace::TracepointID;
 
 pub struct OSProfilerController {
     client_list: Vec<String>,
+    /// Agents that `HostScope::Canary` changes go to
+    canaries: Vec<String>,
+    retry_policy: RetryPolicy,
+    agent_timeout: Duration,
+    limiter: ChangeLimiter,
 
     /// This should only be valid after disable_all is called
     enabled_tracepoints: Arc<Mutex<HashSet<(TracepointID, Option<RequestType>)>>>,
+    /// Tracepoints enabled only on the canaries, by the group they were picked for
+    canary: Mutex<HashMap<String, HashSet<(TracepointID, Option<RequestType>)>>>,
+    /// What `disable_all` or `enable_all` last set every tracepoint to, if either was called
+    all_set: Mutex<Option<u8>>,
 }
 
 impl Controller for OSProfilerController {
     fn enable(&self, points: &Vec<(TracepointID, Option<RequestType>)>) {
-        eprintln!("Enabling {:?}", points);
-        let mut enabled_tracepoints = self.enabled_tracepoints.lock().unwrap();
-        for p in points {
-            if p.1 == Some(RequestType::Unknown) {
-                enabled_tracepoints.insert((p.0, None));
-            } else {
-                enabled_tracepoints.insert(p.clone());
-            }
+        self.enable_on(points, None);
+    }
+
+    /// Tracepoints enabled only on the canaries count as enabled, until `canary_trials` shows
+    /// they can be enabled everywhere
+    fn enable_scoped(
+        &self,
+        points: &Vec<(TracepointID, Option<RequestType>)>,
+        group: &str,
+        scope: HostScope,
+    ) {
+        match scope {
+            HostScope::All => self.enable_on(points, None),
+            HostScope::Canary => self.enable_on(points, Some(group)),
         }
-        self.write_to_tracepoints(points, b"1");
     }
 
     fn disable(&self, points: &Vec<(TracepointID, Option<RequestType>)>) {
-        eprintln!("Disabling {:?}", points);
+        info!("Disabling {:?}", points);
         let mut enabled_tracepoints = self.enabled_tracepoints.lock().unwrap();
+        let mut canary = self.canary.lock().unwrap();
         for p in points {
-            if p.1 == Some(RequestType::Unknown) {
-                enabled_tracepoints.remove(&(p.0, None));
-            } else {
-                enabled_tracepoints.remove(p);
+            let p = normalized(p);
+            enabled_tracepoints.remove(&p);
+            for trial in canary.values_mut() {
+                trial.remove(&p);
             }
         }
-        self.write_to_tracepoints(points, b"0");
+        canary.retain(|_, trial| !trial.is_empty());
+        self.write_to_tracepoints(points, b"0", &self.client_list);
     }
 
     fn is_enabled(&self, point: &(TracepointID, Option<RequestType>)) -> bool {
         let enabled_tracepoints = self.enabled_tracepoints.lock().unwrap();
+        let canary = self.canary.lock().unwrap();
         // A tracepoint is enabled either globally or for a request type
-        !enabled_tracepoints.get(point).is_none()
-            || !enabled_tracepoints.get(&(point.0, None)).is_none()
+        let enabled = |set: &HashSet<(TracepointID, Option<RequestType>)>| {
+            set.contains(point) || set.contains(&(point.0, None))
+        };
+        enabled(&enabled_tracepoints) || canary.values().any(enabled)
     }
 
     /// Also removes request-type-specific controllers
@@ -67,46 +97,428 @@ impl Controller for OSProfilerController {
     fn enable_all(&self) {
         self.set_all_tracepoints(b"1");
     }
+
+    /// Tracepoints that are off on an agent but enabled here are turned back on. After
+    /// `disable_all`, tracepoints that are on but were never enabled here are turned off too.
+    /// A setting that differs the same way on every agent that has the tracepoint was changed on
+    /// purpose, e.g. with `pythia enable-matching`, and is adopted instead, so only drift
+    /// confined to some of the agents is repaired.
+    fn reconcile(&self) -> usize {
+        let enabled_tracepoints = self.enabled_tracepoints.lock().unwrap().clone();
+        let on_canaries: HashSet<_> = enabled_tracepoints
+            .iter()
+            .chain(self.canary.lock().unwrap().values().flatten())
+            .cloned()
+            .collect();
+        let strict = *self.all_set.lock().unwrap() == Some(b'0');
+        let policy = self.retry_policy.clone();
+        let answers = call_all_clients(&self.client_list, self.agent_timeout, move |client| {
+            read_client_tracepoint_state(client, &policy)
+        });
+        for client in answers.timed_out.iter() {
+            error!("Reading tracepoint state of {} timed out", client);
+        }
+        let mut fixes = HashMap::new();
+        let mut holders: HashMap<String, usize> = HashMap::new();
+        for (client, answer) in answers.results {
+            let state = match answer {
+                Ok(state) => state,
+                Err(e) => {
+                    error!("Could not read tracepoint state of {}: {}", client, e);
+                    continue;
+                }
+            };
+            let names: HashSet<&String> = state.iter().map(|(tp, _, _)| tp).collect();
+            for name in names {
+                *holders.entry(name.clone()).or_default() += 1;
+            }
+            let expected = if self.canaries.contains(&client) {
+                &on_canaries
+            } else {
+                &enabled_tracepoints
+            };
+            // Changes that are still queued are not drift
+            let mut found = drift(expected, &state, strict);
+            found.retain(|&(tp, rt, _)| !self.limiter.is_queued(&(tp, rt)));
+            fixes.insert(client, found);
+        }
+        // Only when every agent answered can a change be on all of them
+        let adopted = if answers.timed_out.is_empty() && fixes.len() == self.client_list.len() {
+            uniform(&fixes, &holders)
+        } else {
+            HashSet::new()
+        };
+        self.adopt(&adopted);
+
+        let fixes: HashMap<String, Vec<_>> = fixes
+            .into_iter()
+            .map(|(client, fixes)| {
+                let fixes: Vec<_> = fixes.into_iter().filter(|f| !adopted.contains(f)).collect();
+                for (tracepoint, request_type, to_write) in &fixes {
+                    warn!(
+                        "{} drifted: {} for {:?} should be {}",
+                        client, tracepoint, request_type, to_write[0] as char
+                    );
+                }
+                (client, fixes)
+            })
+            .filter(|(_, fixes)| !fixes.is_empty())
+            .collect();
+        let repaired = fixes.values().map(|fixes| fixes.len()).sum();
+        let clients: Vec<String> = fixes.keys().cloned().collect();
+        let policy = self.retry_policy.clone();
+        let answers = call_all_clients(&clients, self.agent_timeout, move |client| {
+            set_client_tracepoints(client, fixes[client].clone(), &policy)
+        });
+        for client in answers.timed_out {
+            error!("Repairing tracepoints of {} timed out", client);
+        }
+        for (client, answer) in answers.results {
+            if let Err(e) = answer {
+                error!("Could not repair tracepoints of {}: {}", client, e);
+            }
+        }
+        repaired
+    }
+    fn send_queued(&self) -> usize {
+        let ready = self.limiter.ready();
+        for client in self.client_list.iter() {
+            let settings: Vec<_> = ready
+                .iter()
+                .filter(|change| change.clients.contains(client))
+                .map(|change| (change.point.0, change.point.1, change.setting))
+                .collect();
+            if settings.is_empty() {
+                continue;
+            }
+            self.limiter.jitter();
+            if let Err(e) = set_client_tracepoints(client, settings, &self.retry_policy) {
+                error!("Could not set tracepoints of {}: {}", client, e);
+            }
+        }
+        ready.len()
+    }
+
+    fn flush(&self) {
+        self.send_queued();
+        while let Some(wait) = self.limiter.next_slot() {
+            info!(
+                "Waiting {:?} to send {} more tracepoint changes",
+                wait,
+                self.limiter.backlog()
+            );
+            self.limiter.sleep(wait);
+            self.send_queued();
+        }
+    }
+
+    /// Including the ones only on the canaries
     fn enabled_tracepoints(&self) -> Vec<(TracepointID, Option<RequestType>)> {
-        self.enabled_tracepoints
+        let enabled_tracepoints = self.enabled_tracepoints.lock().unwrap();
+        let canary = self.canary.lock().unwrap();
+        let mut result: HashSet<_> = enabled_tracepoints.iter().cloned().collect();
+        result.extend(canary.values().flatten());
+        result.into_iter().collect()
+    }
+
+    fn canary_trials(&self) -> Vec<(String, Vec<(TracepointID, Option<RequestType>)>)> {
+        let mut result: Vec<_> = self
+            .canary
             .lock()
             .unwrap()
             .iter()
-            .cloned()
-            .collect()
+            .map(|(group, trial)| (group.clone(), trial.iter().cloned().collect()))
+            .collect();
+        result.sort_by(|a, b| a.0.cmp(&b.0));
+        result
+    }
+
+    /// A tracepoint is enabled if it is enabled on any agent that has it; without a request type,
+    /// if it is enabled for any. Agents that can't be reached are left out.
+    fn query_enabled(&self, points: &Vec<(TracepointID, Option<RequestType>)>) -> Vec<bool> {
+        let mut result = vec![false; points.len()];
+        for client in self.client_list.iter() {
+            match read_client_tracepoints(client, points.clone(), &self.retry_policy) {
+                Ok(settings) => {
+                    for (enabled, setting) in result.iter_mut().zip(settings) {
+                        *enabled |= setting == Some(true);
+                    }
+                }
+                Err(e) => error!("Could not read tracepoints of {}: {}", client, e),
+            }
+        }
+        result
     }
 
+    /// Settings that are on on any agent
+    fn query_all_enabled(&self) -> Option<Vec<(TracepointID, Option<RequestType>)>> {
+        let policy = self.retry_policy.clone();
+        let answers = call_all_clients(&self.client_list, self.agent_timeout, move |client| {
+            read_client_tracepoint_state(client, &policy)
+        });
+        for client in answers.timed_out.iter() {
+            error!("Reading tracepoint state of {} timed out", client);
+        }
+        let mut result = HashSet::new();
+        for (client, answer) in answers.results {
+            match answer {
+                Ok(state) => result.extend(
+                    state
+                        .into_iter()
+                        .filter(|(_, _, on)| *on)
+                        .map(|(tp, rt, _)| (TracepointID::from_str(&tp), rt)),
+                ),
+                Err(e) => error!("Could not read tracepoint state of {}: {}", client, e),
+            }
+        }
+        Some(result.into_iter().collect())
+    }
 }
 
 impl OSProfilerController {
     pub fn from_settings(settings: &Settings) -> OSProfilerController {
+        let canaries = match settings.canary_fraction {
+            Some(fraction) => canary_clients(&settings.pythia_clients, fraction),
+            None => settings.pythia_clients.clone(),
+        };
         OSProfilerController {
             client_list: settings.pythia_clients.clone(),
+            canaries,
+            retry_policy: RetryPolicy::from_settings(settings),
+            agent_timeout: settings.agent_timeout,
+            limiter: ChangeLimiter::from_settings(settings),
             enabled_tracepoints: Arc::new(Mutex::new(HashSet::new())),
+            canary: Mutex::new(HashMap::new()),
+            all_set: Mutex::new(None),
         }
     }
 
+    /// On every agent, or only on the canaries for the group
+    fn enable_on(
+        &self,
+        points: &Vec<(TracepointID, Option<RequestType>)>,
+        canary_group: Option<&str>,
+    ) {
+        let mut enabled_tracepoints = self.enabled_tracepoints.lock().unwrap();
+        let mut canary = self.canary.lock().unwrap();
+        match canary_group {
+            Some(group) => {
+                info!("Enabling {:?} on {} canaries", points, self.canaries.len());
+                let trial = canary.entry(group.to_string()).or_default();
+                for p in points {
+                    let p = normalized(p);
+                    if !enabled_tracepoints.contains(&p) {
+                        trial.insert(p);
+                    }
+                }
+                if trial.is_empty() {
+                    canary.remove(group);
+                }
+                self.write_to_tracepoints(points, b"1", &self.canaries);
+            }
+            None => {
+                info!("Enabling {:?} on {} agents", points, self.client_list.len());
+                for p in points {
+                    let p = normalized(p);
+                    for trial in canary.values_mut() {
+                        trial.remove(&p);
+                    }
+                    enabled_tracepoints.insert(p);
+                }
+                canary.retain(|_, trial| !trial.is_empty());
+                self.write_to_tracepoints(points, b"1", &self.client_list);
+            }
+        }
+    }
+
+    /// Changes over the rate limit are sent by later calls to `send_queued`
     fn write_to_tracepoints(
         &self,
         points: &Vec<(TracepointID, Option<RequestType>)>,
         to_write: &[u8; 1],
+        clients: &[String],
     ) {
+        self.limiter.queue(points, to_write, clients);
+        self.send_queued();
+    }
+
+    /// The tracepoints enabled before stay enabled if everything is turned on
+    fn set_all_tracepoints(&self, to_write: &[u8; 1]) {
+        self.limiter.clear();
+        if to_write == b"0" {
+            self.enabled_tracepoints.lock().unwrap().clear();
+        }
+        self.canary.lock().unwrap().clear();
+        *self.all_set.lock().unwrap() = Some(to_write[0]);
         for client in self.client_list.iter() {
-            set_client_tracepoints(
-                client,
-                points
-                    .iter()
-                    .map(|(x, y)| ((*x).clone(), y.clone(), to_write.clone()))
-                    .collect(),
+            if let Err(e) = set_all_client_tracepoints(client, *to_write, &self.retry_policy) {
+                error!("Could not set all tracepoints of {}: {}", client, e);
+            }
+        }
+    }
+
+    /// Takes on the settings changed on every agent
+    fn adopt(&self, adopted: &HashSet<(TracepointID, Option<RequestType>, [u8; 1])>) {
+        let mut enabled_tracepoints = self.enabled_tracepoints.lock().unwrap();
+        let mut canary = self.canary.lock().unwrap();
+        for &(tracepoint, request_type, to_write) in adopted {
+            info!(
+                "Adopting {} for {:?}, which every agent has {}",
+                tracepoint,
+                request_type,
+                if &to_write == b"1" { "off" } else { "on" }
             );
+            let p = (tracepoint, request_type);
+            if &to_write == b"1" {
+                enabled_tracepoints.remove(&p);
+                for trial in canary.values_mut() {
+                    trial.remove(&p);
+                }
+            } else {
+                enabled_tracepoints.insert(p);
+            }
         }
     }
+}
 
-    fn set_all_tracepoints(&self, to_write: &[u8; 1]) {
-        for client in self.client_list.iter() {
-            set_all_client_tracepoints(client, *to_write);
+/// `Unknown` is the same as all request types for the agents
+fn normalized(point: &(TracepointID, Option<RequestType>)) -> (TracepointID, Option<RequestType>) {
+    match point.1 {
+        Some(RequestType::Unknown) => (point.0, None),
+        _ => *point,
+    }
+}
+
+/// The name an agent gives the tracepoint, which lacks the leading `/`
+fn agent_name(tracepoint: &TracepointID) -> String {
+    let name = tracepoint.to_string();
+    name.strip_prefix('/').map(String::from).unwrap_or(name)
+}
+
+/// The fixes needed on every agent that has the tracepoint, given how many agents do
+fn uniform(
+    fixes: &HashMap<String, Vec<(TracepointID, Option<RequestType>, [u8; 1])>>,
+    holders: &HashMap<String, usize>,
+) -> HashSet<(TracepointID, Option<RequestType>, [u8; 1])> {
+    let mut counts: HashMap<_, usize> = HashMap::new();
+    for fix in fixes.values().flatten() {
+        *counts.entry(*fix).or_default() += 1;
+    }
+    counts
+        .into_iter()
+        .filter(|(fix, count)| holders.get(&agent_name(&fix.0)) == Some(count))
+        .map(|(fix, _)| fix)
+        .collect()
+}
+
+/// Settings that make an agent's `state` match the `expected` tracepoints. If `strict`, nothing
+/// else should be on.
+fn drift(
+    expected: &HashSet<(TracepointID, Option<RequestType>)>,
+    state: &[(String, Option<RequestType>, bool)],
+    strict: bool,
+) -> Vec<(TracepointID, Option<RequestType>, [u8; 1])> {
+    let expected_names: HashSet<(String, Option<RequestType>)> = expected
+        .iter()
+        .map(|(tp, rt)| (agent_name(tp), *rt))
+        .collect();
+    let wanted = |tracepoint: &str, request_type: Option<RequestType>| {
+        expected_names.contains(&(tracepoint.to_string(), request_type))
+            || expected_names.contains(&(tracepoint.to_string(), None))
+    };
+    let actual: HashMap<(&str, Option<RequestType>), bool> = state
+        .iter()
+        .map(|(tp, rt, on)| ((tp.as_str(), *rt), *on))
+        .collect();
+    let mut result = Vec::new();
+    for &(tp, rt) in expected {
+        let tracepoint = agent_name(&tp);
+        let on = match actual.get(&(tracepoint.as_str(), rt)) {
+            Some(&on) => Some(on),
+            // A request type without its own file follows the tracepoint's file, unless that is
+            // about to be turned off
+            None if rt.is_some() => actual
+                .get(&(tracepoint.as_str(), None))
+                .map(|&on| on && !(strict && !wanted(&tracepoint, None))),
+            None => None,
+        };
+        // Agents without the tracepoint at all are fine
+        if on == Some(false) {
+            result.push((tp, rt, *b"1"));
+        }
+    }
+    if strict {
+        for (tracepoint, request_type, on) in state {
+            if *on && !wanted(tracepoint, *request_type) {
+                let tracepoint = TracepointID::from_str(&format!("/{}", tracepoint));
+                result.push((tracepoint, *request_type, *b"0"));
+            }
         }
     }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    #[test]
+    fn finds_drift() {
+        let create = RequestType::from_str("ServerCreate").unwrap();
+        let (api, compute, sched) = (
+            TracepointID::from_str("/drift/api.py:1"),
+            TracepointID::from_str("/drift/compute.py:2"),
+            TracepointID::from_str("/drift/scheduler.py:3"),
+        );
+        let expected = vec![(api, None), (compute, Some(create)), (sched, None)]
+            .into_iter()
+            .collect();
+        let state = vec![
+            ("drift/api.py:1".to_string(), None, true),
+            // Reset, and turned on for everything instead
+            ("drift/compute.py:2".to_string(), None, true),
+            ("drift/other.py:4".to_string(), None, true),
+        ];
+        // The scheduler is not on this agent
+        assert!(drift(&expected, &state, false).is_empty());
+        let mut fixes = drift(&expected, &state, true);
+        fixes.sort_by_key(|f| f.0.to_string());
+        assert_eq!(
+            fixes,
+            vec![
+                (compute, Some(create), *b"1"),
+                (compute, None, *b"0"),
+                (TracepointID::from_str("/drift/other.py:4"), None, *b"0"),
+            ]
+        );
+    }
 
+    #[test]
+    fn adopts_changes_on_every_agent() {
+        let (api, compute) = (
+            TracepointID::from_str("/adopt/api.py:1"),
+            TracepointID::from_str("/adopt/compute.py:2"),
+        );
+        let mut fixes = HashMap::new();
+        // Turned off on both computes, and on the only api node
+        fixes.insert(
+            "compute-1".to_string(),
+            vec![(compute, None, *b"1"), (api, None, *b"1")],
+        );
+        fixes.insert("compute-2".to_string(), vec![(compute, None, *b"1")]);
+        let holders = vec![
+            ("adopt/api.py:1".to_string(), 1),
+            ("adopt/compute.py:2".to_string(), 2),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(uniform(&fixes, &holders).len(), 2);
+
+        // A reimaged compute
+        fixes.get_mut("compute-2").unwrap().clear();
+        assert_eq!(
+            uniform(&fixes, &holders),
+            vec![(api, None, *b"1")].into_iter().collect()
+        );
+    }
 }
@@ -0,0 +1,209 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use log::info;
+use rand::Rng;
+
+use pythia_common::RequestType;
+
+use crate::clock::Clock;
+use crate::clock::SystemClock;
+use crate::settings::Settings;
+use crate::trace::TracepointID;
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// A tracepoint setting waiting to be sent to some of the agents
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub point: (TracepointID, Option<RequestType>),
+    pub setting: [u8; 1],
+    pub clients: Arc<Vec<String>>,
+}
+
+/// Paces tracepoint changes, so a burst of enables doesn't perturb the latencies being measured.
+///
+/// At most `per_minute` changes are sent in any 60 second window. Changes that don't fit are
+/// queued, in order, and sent by `ready` once the window moves, e.g. in a later controller
+/// cycle. Agents are contacted one by one with a random delay of up to `jitter` between them, so
+/// they don't all reconfigure at the same moment.
+pub struct ChangeLimiter {
+    per_minute: Option<usize>,
+    jitter: Duration,
+    clock: Arc<dyn Clock>,
+    /// When each change in the current window was sent
+    sent: Mutex<VecDeque<Instant>>,
+    queued: Mutex<VecDeque<Change>>,
+}
+
+impl ChangeLimiter {
+    pub fn new(per_minute: Option<usize>, jitter: Duration, clock: Arc<dyn Clock>) -> Self {
+        ChangeLimiter {
+            per_minute,
+            jitter,
+            clock,
+            sent: Mutex::new(VecDeque::new()),
+            queued: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self::new(
+            settings.max_tracepoint_changes_per_min,
+            settings.tracepoint_change_jitter,
+            Arc::new(SystemClock),
+        )
+    }
+
+    /// Queue the setting for the points on the clients, after the changes already queued
+    pub fn queue(
+        &self,
+        points: &[(TracepointID, Option<RequestType>)],
+        setting: &[u8; 1],
+        clients: &[String],
+    ) {
+        let clients = Arc::new(clients.to_vec());
+        self.queued
+            .lock()
+            .unwrap()
+            .extend(points.iter().map(|&point| Change {
+                point,
+                setting: *setting,
+                clients: clients.clone(),
+            }));
+    }
+
+    /// The queued changes that can be sent now, counted as sent
+    pub fn ready(&self) -> Vec<Change> {
+        let mut queued = self.queued.lock().unwrap();
+        let mut sent = self.sent.lock().unwrap();
+        let now = self.clock.now();
+        let n = queued.len().min(room(&mut sent, now, self.per_minute));
+        sent.extend(std::iter::repeat(now).take(n));
+        if n < queued.len() {
+            info!(
+                "Rate limiting tracepoint changes, {} wait for a later cycle",
+                queued.len() - n
+            );
+        }
+        queued.drain(..n).collect()
+    }
+
+    /// Number of changes still waiting to be sent
+    pub fn backlog(&self) -> usize {
+        self.queued.lock().unwrap().len()
+    }
+
+    /// How long until more changes can be sent, if any are waiting
+    pub fn next_slot(&self) -> Option<Duration> {
+        if self.backlog() == 0 {
+            return None;
+        }
+        let now = self.clock.now();
+        let sent = self.sent.lock().unwrap();
+        Some(sent.front().map_or(Duration::from_secs(0), |&t| {
+            WINDOW.saturating_sub(now.saturating_duration_since(t))
+        }))
+    }
+
+    /// Whether a change of the point is waiting to be sent
+    pub fn is_queued(&self, point: &(TracepointID, Option<RequestType>)) -> bool {
+        self.queued
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|change| &change.point == point)
+    }
+
+    /// Drop the queued changes, e.g. when every tracepoint is set at once
+    pub fn clear(&self) {
+        self.queued.lock().unwrap().clear();
+    }
+
+    pub fn sleep(&self, duration: Duration) {
+        self.clock.sleep(duration);
+    }
+
+    /// Waits a random time up to the jitter, before contacting the next agent
+    pub fn jitter(&self) {
+        if self.jitter > Duration::from_secs(0) {
+            self.clock
+                .sleep(rand::thread_rng().gen_range(Duration::from_secs(0), self.jitter));
+        }
+    }
+}
+
+/// How many more changes fit in the window ending `now`
+fn room(sent: &mut VecDeque<Instant>, now: Instant, per_minute: Option<usize>) -> usize {
+    while sent
+        .front()
+        .map_or(false, |&t| now.saturating_duration_since(t) >= WINDOW)
+    {
+        sent.pop_front();
+    }
+    match per_minute {
+        Some(limit) => limit.max(1).saturating_sub(sent.len()),
+        None => usize::MAX,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::clock::SimulatedClock;
+
+    #[test]
+    fn limits_rate() {
+        let start = Instant::now();
+        let mut sent = VecDeque::new();
+        assert_eq!(room(&mut sent, start, Some(4)), 4);
+        sent.extend(vec![start; 3]);
+        let later = start + Duration::from_secs(20);
+        assert_eq!(room(&mut sent, later, Some(4)), 1);
+        sent.push_back(later);
+        // The first three leave the window after a minute
+        assert_eq!(room(&mut sent, start + WINDOW, Some(4)), 3);
+        assert_eq!(room(&mut sent, start, None), usize::MAX);
+    }
+
+    #[test]
+    fn queues_changes_for_later_cycles() {
+        let clock = Arc::new(SimulatedClock::new());
+        let limiter = ChangeLimiter::new(Some(2), Duration::from_secs(0), clock.clone());
+        let points: Vec<_> = (0..3)
+            .map(|i| (TracepointID::from_str(&format!("/rate/api.py:{}", i)), None))
+            .collect();
+        let clients = vec!["compute-1".to_string()];
+        limiter.queue(&points, b"1", &clients);
+        limiter.queue(&points[..1], b"0", &clients);
+        let ready = limiter.ready();
+        assert_eq!(ready.len(), 2);
+        assert_eq!(ready[0].point, points[0]);
+        assert_eq!(limiter.backlog(), 2);
+        assert!(limiter.is_queued(&points[2]));
+        assert_eq!(limiter.next_slot(), Some(WINDOW));
+
+        clock.advance(Duration::from_secs(30));
+        assert!(limiter.ready().is_empty());
+        clock.advance(Duration::from_secs(30));
+        let ready: Vec<_> = limiter
+            .ready()
+            .iter()
+            .map(|c| (c.point, c.setting))
+            .collect();
+        assert_eq!(ready, vec![(points[2], *b"1"), (points[0], *b"0")]);
+        assert_eq!(limiter.next_slot(), None);
+    }
+}
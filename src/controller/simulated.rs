@@ -0,0 +1,119 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use log::info;
+
+use pythia_common::RequestType;
+
+use crate::controller::Controller;
+use crate::trace::TracepointID;
+
+/// What the simulated controller was asked to do
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SimulatedAction {
+    Enable(TracepointID, Option<RequestType>),
+    Disable(TracepointID, Option<RequestType>),
+    EnableAll,
+    DisableAll,
+}
+
+/// Used when replaying archived traces: pretends to enable tracepoints, and remembers every
+/// decision so that it can be reported at the end of the run.
+pub struct SimulatedController {
+    enabled: Mutex<HashSet<(TracepointID, Option<RequestType>)>>,
+    history: Mutex<Vec<SimulatedAction>>,
+}
+
+impl SimulatedController {
+    pub fn new() -> Self {
+        SimulatedController {
+            enabled: Mutex::new(HashSet::new()),
+            history: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// All decisions so far, in order
+    pub fn history(&self) -> Vec<SimulatedAction> {
+        self.history.lock().unwrap().clone()
+    }
+}
+
+impl Default for SimulatedController {
+    fn default() -> Self {
+        SimulatedController::new()
+    }
+}
+
+impl Controller for SimulatedController {
+    fn enable(&self, points: &Vec<(TracepointID, Option<RequestType>)>) {
+        let mut enabled = self.enabled.lock().unwrap();
+        let mut history = self.history.lock().unwrap();
+        for p in points {
+            if enabled.insert(p.clone()) {
+                info!("Would enable {:?}", p);
+                history.push(SimulatedAction::Enable(p.0, p.1));
+            }
+        }
+    }
+
+    fn disable(&self, points: &Vec<(TracepointID, Option<RequestType>)>) {
+        let mut enabled = self.enabled.lock().unwrap();
+        let mut history = self.history.lock().unwrap();
+        for p in points {
+            if enabled.remove(p) {
+                info!("Would disable {:?}", p);
+                history.push(SimulatedAction::Disable(p.0, p.1));
+            }
+        }
+    }
+
+    fn is_enabled(&self, point: &(TracepointID, Option<RequestType>)) -> bool {
+        let enabled = self.enabled.lock().unwrap();
+        enabled.contains(point) || enabled.contains(&(point.0, None))
+    }
+
+    fn disable_all(&self) {
+        self.enabled.lock().unwrap().clear();
+        self.history.lock().unwrap().push(SimulatedAction::DisableAll);
+    }
+
+    /// Nothing is tracked here, as with the test controller
+    fn enable_all(&self) {
+        self.history.lock().unwrap().push(SimulatedAction::EnableAll);
+    }
+
+    fn enabled_tracepoints(&self) -> Vec<(TracepointID, Option<RequestType>)> {
+        self.enabled.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_changes_are_recorded() {
+        let controller = SimulatedController::new();
+        let a = (TracepointID::from_str("a"), None);
+        controller.enable(&vec![a]);
+        controller.enable(&vec![a]);
+        controller.disable(&vec![a]);
+        controller.disable(&vec![a]);
+        assert_eq!(
+            controller.history(),
+            vec![
+                SimulatedAction::Enable(a.0, None),
+                SimulatedAction::Disable(a.0, None)
+            ]
+        );
+        assert!(controller.enabled_tracepoints().is_empty());
+    }
+}
@@ -0,0 +1,221 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Do the enabled tracepoints show up in the traces, and do the traces match the manifest?
+//!
+//! An enabled tracepoint that is on none of the recent traces of its request type is silent:
+//! either the tracepoints of the spans around it are off, or the code is not reached anymore.
+//! Tracepoints of request types without recent traces can't be told apart from silent ones, so
+//! they are left out. A tracepoint on the traces that the manifest doesn't have means the
+//! manifest is out of date.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
+
+use pythia_common::RequestType;
+use serde::Serialize;
+
+use crate::manifest::Manifest;
+use crate::trace::Trace;
+use crate::trace::TracepointID;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct SilentTracepoint {
+    pub tracepoint: TracepointID,
+    pub request_type: Option<RequestType>,
+    /// Tracepoints of enclosing spans that are not enabled
+    pub missing_prerequisites: Vec<TracepointID>,
+    /// The manifest has no path with the tracepoint, for its request type
+    pub off_manifest: bool,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CoverageReport {
+    pub traces: usize,
+    pub enabled: usize,
+    pub observed: usize,
+    pub silent: Vec<SilentTracepoint>,
+    /// Tracepoints on the traces that are not in the manifest, with the number of traces
+    pub unknown: Vec<(TracepointID, usize)>,
+}
+
+impl CoverageReport {
+    pub fn compute(
+        enabled: &[(TracepointID, Option<RequestType>)],
+        traces: &[Trace],
+        manifest: &Manifest,
+    ) -> CoverageReport {
+        let mut seen: HashMap<RequestType, HashSet<TracepointID>> = HashMap::new();
+        let mut trace_counts: HashMap<TracepointID, usize> = HashMap::new();
+        for trace in traces {
+            let tracepoints: HashSet<TracepointID> = trace
+                .g
+                .node_indices()
+                .map(|n| &trace.g[n])
+                .filter(|e| !e.is_synthetic)
+                .map(|e| e.tracepoint_id)
+                .collect();
+            for &tp in &tracepoints {
+                *trace_counts.entry(tp).or_insert(0) += 1;
+            }
+            seen.entry(trace.request_type)
+                .or_default()
+                .extend(tracepoints);
+        }
+        let enabled_set: HashSet<&(TracepointID, Option<RequestType>)> = enabled.iter().collect();
+        let is_enabled = |tp: TracepointID, rt: Option<RequestType>| {
+            enabled_set.contains(&(tp, rt)) || enabled_set.contains(&(tp, None))
+        };
+        let dependencies = manifest.dependencies();
+        let mut silent = Vec::new();
+        for &(tp, rt) in enabled {
+            let heard = match rt {
+                Some(rt) => match seen.get(&rt) {
+                    Some(tracepoints) => tracepoints.contains(&tp),
+                    // No traffic, so nothing to tell
+                    None => continue,
+                },
+                None => trace_counts.contains_key(&tp),
+            };
+            if heard || traces.is_empty() {
+                continue;
+            }
+            let prerequisites = dependencies.prerequisites(&(tp, rt));
+            silent.push(SilentTracepoint {
+                tracepoint: tp,
+                request_type: rt,
+                missing_prerequisites: prerequisites
+                    .unwrap_or(&[])
+                    .iter()
+                    .filter(|&&p| !is_enabled(p, rt))
+                    .cloned()
+                    .collect(),
+                off_manifest: prerequisites.is_none(),
+            });
+        }
+        silent.sort_by_key(|s| s.tracepoint.to_string());
+
+        let known = manifest.referenced_tracepoints();
+        let mut unknown: Vec<(TracepointID, usize)> = trace_counts
+            .iter()
+            .filter(|(tp, _)| !known.contains(tp))
+            .map(|(&tp, &count)| (tp, count))
+            .collect();
+        unknown.sort_by_key(|&(tp, count)| (std::cmp::Reverse(count), tp.to_string()));
+        CoverageReport {
+            traces: traces.len(),
+            enabled: enabled.len(),
+            observed: trace_counts.len(),
+            silent,
+            unknown,
+        }
+    }
+}
+
+impl fmt::Display for CoverageReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} traces with {} tracepoints; {} enabled, {} of them silent; {} not in the manifest",
+            self.traces,
+            self.observed,
+            self.enabled,
+            self.silent.len(),
+            self.unknown.len()
+        )?;
+        for s in &self.silent {
+            write!(f, "\nSilent: {}", s.tracepoint)?;
+            if let Some(rt) = s.request_type {
+                write!(f, " [{}]", rt)?;
+            }
+            if s.off_manifest {
+                write!(f, ", on no manifest path")?;
+            }
+            if !s.missing_prerequisites.is_empty() {
+                let missing: Vec<String> = s
+                    .missing_prerequisites
+                    .iter()
+                    .map(|tp| tp.to_string())
+                    .collect();
+                write!(f, ", needs {}", missing.join(", "))?;
+            }
+        }
+        for (tp, count) in &self.unknown {
+            write!(f, "\nNot in the manifest: {} ({} traces)", tp, count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use chrono::NaiveDateTime;
+    use uuid::Uuid;
+
+    use crate::trace::Span;
+
+    #[test]
+    fn finds_silent_and_unknown_tracepoints() {
+        let epoch = NaiveDateTime::parse_from_str("2000/01/01 00:00", "%Y/%m/%d %H:%M").unwrap();
+        let at = |ms| epoch + chrono::Duration::milliseconds(ms);
+        let tp = TracepointID::from_str;
+        // Each span inside the previous one
+        let nested = |names: &[&str]| {
+            let spans: Vec<Span> = names
+                .iter()
+                .enumerate()
+                .map(|(i, name)| Span {
+                    span_id: Uuid::from_u128(i as u128 + 1),
+                    parent: Some(Uuid::from_u128(i as u128)),
+                    tracepoint_id: tp(name),
+                    start: at(i as i64),
+                    end: at(10 - i as i64),
+                    key_value_pair: HashMap::new(),
+                })
+                .collect();
+            Trace::from_spans(&Uuid::from_u128(0), &spans).unwrap()
+        };
+        let manifest = Manifest::from_trace_list(&vec![nested(&["root", "outer", "inner"])]);
+        let traces = vec![nested(&["root", "new"])];
+        let enabled = vec![
+            (tp("root"), None),
+            (tp("inner"), None),
+            (tp("elsewhere"), None),
+            (tp("new"), Some(RequestType::Unknown)),
+            (tp("outer"), Some(RequestType::Unknown)),
+        ];
+        let report = CoverageReport::compute(&enabled, &traces, &manifest);
+        assert_eq!((report.traces, report.enabled, report.observed), (1, 5, 2));
+        let silent: Vec<_> = report
+            .silent
+            .iter()
+            .map(|s| {
+                (
+                    s.tracepoint,
+                    s.request_type,
+                    s.missing_prerequisites.clone(),
+                    s.off_manifest,
+                )
+            })
+            .collect();
+        assert_eq!(
+            silent,
+            vec![
+                (tp("elsewhere"), None, vec![], true),
+                (tp("inner"), None, vec![tp("outer")], false),
+                (tp("outer"), Some(RequestType::Unknown), vec![], false)
+            ]
+        );
+        assert_eq!(report.unknown, vec![(tp("new"), 1)]);
+    }
+}
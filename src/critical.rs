@@ -8,14 +8,19 @@ All rights reserved.
 
 //! Critical path-related stuff
 
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::error::Error;
 use std::time::Duration;
 
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
 use genawaiter::{rc::gen, yield_};
+use log::warn;
 use petgraph::visit::EdgeRef;
+use rand::seq::SliceRandom;
 use petgraph::{dot::Dot, graph::NodeIndex, Direction};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
@@ -30,6 +35,31 @@ use crate::trace::Trace;
 use crate::trace::TracepointID;
 use crate::PythiaError;
 
+/// Bounds the search for near-critical paths on traces with many joins
+const MAX_TOP_K_EXPANSIONS: usize = 100_000;
+/// Paths drawn in sampling mode when the budget has no path limit
+const DEFAULT_SAMPLED_PATHS: usize = 1000;
+/// Random walks per wanted path before giving up; walks often repeat a path
+const SAMPLE_ATTEMPTS_PER_PATH: usize = 10;
+
+/// Limits on the paths `CriticalPath::all_possible_paths_budgeted` goes through, so that
+/// building the search space of a trace with many forks has a predictable cost
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PathBudget {
+    /// Paths returned at most per trace
+    pub max_paths: Option<usize>,
+    /// Bytes used at most by branches waiting to be explored; branches beyond it are dropped
+    pub max_memory: Option<usize>,
+    /// Draw random paths instead of enumerating them
+    pub sample: bool,
+}
+
+impl PathBudget {
+    fn fits(&self, bytes: usize) -> bool {
+        self.max_memory.map_or(true, |max| bytes <= max)
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CriticalPath {
     /// This is the actual critical path
@@ -55,6 +85,10 @@ impl CriticalPath {
             hash: "".to_string(),
             request_type: dag.request_type,
         };
+        path.g.request_params = dag.request_params.clone();
+        path.g.is_partial = dag.is_partial;
+        path.g.is_transition = dag.is_transition;
+        path.g.is_canary = dag.is_canary;
         let mut cur_node = dag.end_node;
         let mut end_nidx = path.g.g.add_node(dag.g[cur_node].clone());
         path.end_node = end_nidx;
@@ -66,7 +100,7 @@ impl CriticalPath {
             {
                 Some(nidx) => nidx,
                 None => {
-                    return Err(Box::new(PythiaError(
+                    return Err(Box::new(PythiaError::CriticalPathError(
                         format!("Disjoint trace {}", dag.base_id).into(),
                     )))
                 }
@@ -93,6 +127,112 @@ impl CriticalPath {
         Ok(path)
     }
 
+    /// The k structurally different start-to-end paths with the least slack, the critical path
+    /// first. Going backwards from the end, a path that comes from a predecessor other than the
+    /// latest one at a join has that predecessor's wait as slack. Paths other than the first are
+    /// hypothetical.
+    pub fn top_k_from_trace(dag: &Trace, k: usize) -> Result<Vec<CriticalPath>, Box<dyn Error>> {
+        if k <= 1 {
+            return Ok(vec![CriticalPath::from_trace(dag)?]);
+        }
+        let mut result: Vec<CriticalPath> = Vec::new();
+        let mut hashes = HashSet::new();
+        // (slack, depth, insertion order): least slack first, then keep going down the current
+        // branch, so the first path found is the one `from_trace` finds
+        let mut heap = BinaryHeap::new();
+        let mut partial_paths = vec![vec![dag.end_node]];
+        heap.push(Reverse((0i64, Reverse(0usize), 0usize)));
+        let mut expansions = 0;
+        while let Some(Reverse((slack, Reverse(depth), idx))) = heap.pop() {
+            if result.len() >= k || expansions >= MAX_TOP_K_EXPANSIONS {
+                break;
+            }
+            expansions += 1;
+            let nodes = std::mem::replace(&mut partial_paths[idx], Vec::new());
+            let cur_node = *nodes.last().unwrap();
+            if cur_node == dag.start_node {
+                let mut path = CriticalPath::from_nodes(dag, nodes.iter().rev().cloned())?;
+                path.is_hypothetical = !result.is_empty();
+                if hashes.insert(path.hash().to_string()) {
+                    result.push(path);
+                }
+                continue;
+            }
+            let mut preds: Vec<_> = dag
+                .g
+                .neighbors_directed(cur_node, Direction::Incoming)
+                .collect();
+            let latest = match preds.iter().map(|&n| dag.g[n].timestamp).max() {
+                Some(t) => t,
+                None => continue,
+            };
+            // `max_by_key` in `from_trace` picks the last of equally late predecessors
+            preds.reverse();
+            for pred in preds {
+                let wait = (latest - dag.g[pred].timestamp)
+                    .num_nanoseconds()
+                    .unwrap_or(i64::max_value());
+                let mut next = nodes.clone();
+                next.push(pred);
+                heap.push(Reverse((
+                    slack.saturating_add(wait),
+                    Reverse(depth + 1),
+                    partial_paths.len(),
+                )));
+                partial_paths.push(next);
+            }
+        }
+        if result.is_empty() {
+            return Err(Box::new(PythiaError::CriticalPathError(
+                format!("Disjoint trace {}", dag.base_id).into(),
+            )));
+        }
+        Ok(result)
+    }
+
+    /// The path through the given nodes of the trace, from start to end
+    fn from_nodes<I: Iterator<Item = NodeIndex>>(
+        dag: &Trace,
+        nodes: I,
+    ) -> Result<CriticalPath, Box<dyn Error>> {
+        let mut path = CriticalPath {
+            duration: Duration::new(0, 0),
+            g: Trace::new(&dag.base_id),
+            start_node: NodeIndex::end(),
+            end_node: NodeIndex::end(),
+            is_hypothetical: false,
+            hash: "".to_string(),
+            request_type: dag.request_type,
+        };
+        path.g.request_params = dag.request_params.clone();
+        path.g.is_partial = dag.is_partial;
+        path.g.is_transition = dag.is_transition;
+        path.g.is_canary = dag.is_canary;
+        let mut prev: Option<(NodeIndex, NodeIndex)> = None;
+        for node in nodes {
+            let nidx = path.g.g.add_node(dag.g[node].clone());
+            match prev {
+                None => path.start_node = nidx,
+                Some((prev_node, prev_nidx)) => {
+                    path.g.g.add_edge(
+                        prev_nidx,
+                        nidx,
+                        dag.g[dag.g.find_edge(prev_node, node).unwrap()].clone(),
+                    );
+                }
+            }
+            path.end_node = nidx;
+            prev = Some((node, nidx));
+        }
+        path.add_synthetic_nodes(dag)?;
+        path.duration = (path.g.g[path.end_node].timestamp - path.g.g[path.start_node].timestamp)
+            .to_std()
+            .unwrap();
+        path.filter_incomplete_spans()?;
+        path.calculate_hash();
+        Ok(path)
+    }
+
     pub fn count_possible_paths(dag: &Trace) -> u64 {
         let mut count = 0;
         let mut remaining_nodes = vec![dag.start_node];
@@ -120,6 +260,25 @@ impl CriticalPath {
     /// Lazily return each path separately. If we try to return `Vec<CriticalPath>`, we run out of
     /// memory for HDFS.
     pub fn all_possible_paths<'a>(dag: &'a Trace) -> impl Iterator<Item = CriticalPath> + 'a {
+        CriticalPath::all_possible_paths_budgeted(dag, PathBudget::default())
+    }
+
+    /// Like `all_possible_paths`, but stops or samples to stay within the budget
+    pub fn all_possible_paths_budgeted<'a>(
+        dag: &'a Trace,
+        budget: PathBudget,
+    ) -> Box<dyn Iterator<Item = CriticalPath> + 'a> {
+        if budget.sample {
+            Box::new(CriticalPath::sampled_paths(dag, budget))
+        } else {
+            Box::new(CriticalPath::enumerated_paths(dag, budget))
+        }
+    }
+
+    fn enumerated_paths<'a>(
+        dag: &'a Trace,
+        budget: PathBudget,
+    ) -> impl Iterator<Item = CriticalPath> + 'a {
         gen!({
             let mut p = CriticalPath {
                 g: Trace::new(&dag.base_id),
@@ -130,10 +289,23 @@ impl CriticalPath {
                 hash: "".to_string(),
                 request_type: dag.request_type,
             };
+            p.g.request_params = dag.request_params.clone();
+            p.g.is_partial = dag.is_partial;
+            p.g.is_transition = dag.is_transition;
+            p.g.is_canary = dag.is_canary;
             let mut remaining_nodes = vec![(dag.start_node, dag.start_node, p.g.start_node, p)];
+            // Nodes in the copies of paths waiting in `remaining_nodes`
+            let mut pending_nodes = 0;
+            let mut dropped_branches = 0;
+            let mut yielded = 0;
             while !remaining_nodes.is_empty() {
+                if budget.max_paths.map_or(false, |max| yielded >= max) {
+                    warn!(trace_id:% = dag.base_id; "Stopped after {} paths", yielded);
+                    break;
+                }
                 let (mut prev_node, mut cur_node, mut cur_path_node, mut p) =
                     remaining_nodes.pop().unwrap();
+                pending_nodes -= p.g.g.node_count();
                 loop {
                     let next_nidx = p.g.g.add_node(dag.g[cur_node].clone());
                     p.end_node = next_nidx;
@@ -155,33 +327,101 @@ impl CriticalPath {
                     }
                     let next_node = next_nodes.pop().unwrap();
                     for node in next_nodes {
+                        let size = p.g.g.node_count();
+                        if !budget.fits((pending_nodes + size) * std::mem::size_of::<Event>()) {
+                            dropped_branches += 1;
+                            continue;
+                        }
+                        pending_nodes += size;
                         remaining_nodes.push((cur_node, node, next_nidx, p.clone()));
                     }
                     cur_path_node = next_nidx;
                     prev_node = cur_node;
                     cur_node = next_node;
                 }
-                match p.add_synthetic_nodes(&dag) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        eprintln!("Path extraction failed with {:?}, skipping.", e);
-                        continue;
+                if let Some(p) = p.finish_hypothetical(dag) {
+                    yielded += 1;
+                    yield_!(p);
+                }
+            }
+            if dropped_branches > 0 {
+                warn!(
+                    "Dropped {} branches of {} to stay within the memory budget",
+                    dropped_branches, dag.base_id
+                );
+            }
+        })
+        .into_iter()
+    }
+
+    /// Random walks from the start of the trace, picking a successor uniformly at each fork.
+    /// Paths on branches that fork less are more likely, so this is only roughly uniform.
+    fn sampled_paths<'a>(
+        dag: &'a Trace,
+        budget: PathBudget,
+    ) -> impl Iterator<Item = CriticalPath> + 'a {
+        let wanted = budget.max_paths.unwrap_or(DEFAULT_SAMPLED_PATHS);
+        gen!({
+            let mut rng = rand::thread_rng();
+            let mut hashes = HashSet::new();
+            let mut attempts = 0;
+            while hashes.len() < wanted && attempts < wanted * SAMPLE_ATTEMPTS_PER_PATH {
+                attempts += 1;
+                let mut nodes = vec![dag.start_node];
+                loop {
+                    let next_nodes: Vec<_> = dag
+                        .g
+                        .neighbors_directed(*nodes.last().unwrap(), Direction::Outgoing)
+                        .collect();
+                    match next_nodes.choose(&mut rng) {
+                        Some(&n) => nodes.push(n),
+                        None => break,
                     }
                 }
-                match p.filter_incomplete_spans() {
-                    Ok(_) => {}
+                let p = match CriticalPath::from_nodes(dag, nodes.into_iter()) {
+                    Ok(mut p) => {
+                        p.is_hypothetical = true;
+                        p
+                    }
                     Err(e) => {
-                        eprintln!("Incomplete span filtering failed with {:?}, skipping.", e);
+                        warn!(
+                            trace_id:% = dag.base_id;
+                            "Path extraction failed with {:?}, skipping.", e
+                        );
                         continue;
                     }
+                };
+                if hashes.insert(p.hash().to_string()) {
+                    yield_!(p);
                 }
-                p.calculate_hash();
-                yield_!(p);
             }
         })
         .into_iter()
     }
 
+    /// Add synthetic nodes, filter spans and hash a path from `all_possible_paths`
+    fn finish_hypothetical(mut self, dag: &Trace) -> Option<CriticalPath> {
+        match self.add_synthetic_nodes(&dag) {
+            Ok(_) => {}
+            Err(e) => {
+                warn!(trace_id:% = dag.base_id; "Path extraction failed with {:?}, skipping.", e);
+                return None;
+            }
+        }
+        match self.filter_incomplete_spans() {
+            Ok(_) => {}
+            Err(e) => {
+                warn!(
+                    trace_id:% = dag.base_id;
+                    "Incomplete span filtering failed with {:?}, skipping.", e
+                );
+                return None;
+            }
+        }
+        self.calculate_hash();
+        Some(self)
+    }
+
     /// Remove spans that have a start but no end and vice versa, and also extra nodes of spans
     /// that have multiple starts/endings.
     pub fn filter_incomplete_spans(&mut self) -> Result<(), Box<dyn Error>> {
@@ -219,7 +459,7 @@ impl CriticalPath {
                                 //     Dot::new(&self.g.g)
                                 // );
                                 // println!("Node to remove: {:?}", cur_node);
-                                return Err(Box::new(PythiaError(
+                                return Err(Box::new(PythiaError::CriticalPathError(
                                     format!(
                                         "We shouldn't have any incomplete spans, in trace {}",
                                         self.g.base_id,
@@ -336,7 +576,7 @@ impl CriticalPath {
                 prev_nidx = match self.prev_node(prev_nidx) {
                     Some(id) => id,
                     None => {
-                        return Err(Box::new(PythiaError(
+                        return Err(Box::new(PythiaError::CriticalPathError(
                             format!(
                                 "Failed to find prev node in trace {}\n{}",
                                 dag.base_id,
@@ -431,6 +671,39 @@ impl CriticalPath {
         unfinished
     }
 
+    /// Groups and the manifest expect every event of a path to have at most one successor. The
+    /// error names the first event that has more, and where the path goes from there.
+    pub fn check_linear(&self) -> Result<(), PythiaError> {
+        let mut cur_nidx = self.start_node;
+        // A path that loops back is not linear either
+        for _ in 0..self.g.g.node_count() {
+            let next: Vec<NodeIndex> = self
+                .g
+                .g
+                .neighbors_directed(cur_nidx, Direction::Outgoing)
+                .collect();
+            match next.len() {
+                0 => return Ok(()),
+                1 => cur_nidx = next[0],
+                _ => {
+                    return Err(PythiaError::CriticalPathError(format!(
+                        "path of {} branches at {} into {}",
+                        self.g.base_id,
+                        self.g.g[cur_nidx],
+                        next.iter()
+                            .map(|&n| self.g.g[n].to_string())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )))
+                }
+            }
+        }
+        Err(PythiaError::CriticalPathError(format!(
+            "path of {} has a cycle",
+            self.g.base_id
+        )))
+    }
+
     /// This is not used
     pub fn next_real_node(&self, nidx: NodeIndex) -> Option<NodeIndex> {
         let mut result;
@@ -628,3 +901,130 @@ impl Path for CriticalPath {
         self.g.g.node_count()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::NaiveDateTime;
+    use proptest::prelude::*;
+
+    use crate::testutils::TraceGenerator;
+
+    /// start -> fast -> end and start -> slow -> end; returns the trace, fast and slow
+    fn diamond() -> (Trace, NodeIndex, NodeIndex) {
+        let mut trace = Trace::new(&Uuid::new_v4());
+        let span = Uuid::new_v4();
+        let mut event = |name: &str, variant, ms| {
+            trace.g.add_node(Event {
+                trace_id: if variant == EventType::Annotation {
+                    Uuid::new_v4()
+                } else {
+                    span
+                },
+                tracepoint_id: TracepointID::from_str(name),
+                timestamp: NaiveDateTime::from_timestamp(0, 0) + chrono::Duration::milliseconds(ms),
+                is_synthetic: false,
+                variant,
+                key_value_pair: HashMap::new(),
+            })
+        };
+        let start = event("start", EventType::Entry, 0);
+        let fast = event("fast", EventType::Annotation, 2);
+        let slow = event("slow", EventType::Annotation, 8);
+        let end = event("end", EventType::Exit, 10);
+        for &(a, b) in &[(start, fast), (start, slow), (fast, end), (slow, end)] {
+            let duration = (trace.g[b].timestamp - trace.g[a].timestamp).to_std().unwrap();
+            trace.g.add_edge(
+                a,
+                b,
+                DAGEdge {
+                    duration,
+                    variant: EdgeType::ChildOf,
+                },
+            );
+        }
+        trace.start_node = start;
+        trace.end_node = end;
+        (trace, fast, slow)
+    }
+
+    #[test]
+    fn top_k_paths_and_slack() {
+        let (trace, fast, slow) = diamond();
+        let paths = CriticalPath::top_k_from_trace(&trace, 3).unwrap();
+        assert_eq!(paths.len(), 2);
+        let critical = CriticalPath::from_trace(&trace).unwrap();
+        assert_eq!(paths[0].hash(), critical.hash());
+        assert!(!paths[0].is_hypothetical && paths[1].is_hypothetical);
+        let second = paths[1].next_node(paths[1].start_node).unwrap();
+        assert_eq!(paths[1].at(second), TracepointID::from_str("fast"));
+
+        let slack = trace.slack();
+        assert_eq!(slack[&fast], Duration::from_millis(6));
+        assert_eq!(slack[&slow], Duration::new(0, 0));
+    }
+
+    #[test]
+    fn subtraces() {
+        let (trace, fast, slow) = diamond();
+        let sub = trace
+            .subtrace_between(TracepointID::from_str("start"), TracepointID::from_str("slow"))
+            .unwrap();
+        assert_eq!(sub.g.node_count(), 2);
+        assert_eq!(sub.end_node, slow);
+        assert_eq!(sub.duration, Duration::from_millis(8));
+        assert!(trace
+            .subtrace_between(TracepointID::from_str("fast"), TracepointID::from_str("slow"))
+            .is_none());
+        // Annotations are not spans
+        assert!(trace.subtrace_of_span(trace.g[fast].trace_id).is_none());
+        let whole = trace.subtrace_of_span(trace.g[trace.start_node].trace_id).unwrap();
+        assert_eq!(whole.g.node_count(), 4);
+    }
+
+    #[test]
+    fn budgeted_paths() {
+        let (trace, _, _) = diamond();
+        assert_eq!(CriticalPath::all_possible_paths(&trace).count(), 2);
+        let budget = PathBudget {
+            max_paths: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(
+            CriticalPath::all_possible_paths_budgeted(&trace, budget).count(),
+            1
+        );
+        let budget = PathBudget {
+            max_memory: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(
+            CriticalPath::all_possible_paths_budgeted(&trace, budget).count(),
+            1
+        );
+        let budget = PathBudget {
+            max_paths: Some(5),
+            sample: true,
+            ..Default::default()
+        };
+        let sampled = CriticalPath::all_possible_paths_budgeted(&trace, budget).collect::<Vec<_>>();
+        assert!(sampled.len() == 2 && sampled.iter().all(|p| p.is_hypothetical));
+    }
+
+    proptest! {
+        #[test]
+        fn finds_generated_critical_paths(seed in any::<u64>()) {
+            let generated = TraceGenerator::new(seed).generate();
+            let path = CriticalPath::from_trace(&generated.trace).unwrap();
+            let mut events = Vec::new();
+            let mut cur = Some(path.start_node);
+            while let Some(nidx) = cur {
+                events.push((path.at(nidx), path.g.g[nidx].variant));
+                cur = path.next_node(nidx);
+            }
+            prop_assert_eq!(events, generated.critical_path);
+            prop_assert_eq!(path.duration, generated.duration);
+        }
+    }
+}
@@ -0,0 +1,119 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! The wall-clock budget of each jiffy of the main loop.
+//!
+//! With `cycle_deadline_ms` set, the loop starts a `CycleDeadline` every jiffy and checks it
+//! while it takes in the received traces and while it searches the problem edges. Once the
+//! deadline has passed, the rest of that work is dropped and the loop goes on to the next jiffy
+//! with what it has, instead of falling further behind every jiffy. `SkippedWork` counts what
+//! was dropped, for the output file and the run report.
+
+use std::fmt;
+use std::ops::AddAssign;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use serde::Serialize;
+
+use crate::clock::Clock;
+
+pub struct CycleDeadline {
+    clock: Arc<dyn Clock>,
+    start: Instant,
+    budget: Option<Duration>,
+}
+
+impl CycleDeadline {
+    /// Starts counting now; without a budget the deadline never passes
+    pub fn start(clock: Arc<dyn Clock>, budget: Option<Duration>) -> Self {
+        CycleDeadline {
+            start: clock.now(),
+            clock,
+            budget,
+        }
+    }
+
+    pub fn passed(&self) -> bool {
+        self.budget
+            .is_some_and(|budget| self.clock.elapsed(self.start) > budget)
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.clock.elapsed(self.start)
+    }
+}
+
+/// Work the loop dropped because a jiffy ran past its deadline
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct SkippedWork {
+    /// Received traces that were not grouped
+    pub traces: usize,
+    /// Problem groups that were not searched
+    pub groups: usize,
+    /// Problem edges of the searched groups that were not searched
+    pub edges: usize,
+}
+
+impl SkippedWork {
+    pub fn is_empty(&self) -> bool {
+        *self == SkippedWork::default()
+    }
+}
+
+impl AddAssign for SkippedWork {
+    fn add_assign(&mut self, other: SkippedWork) {
+        self.traces += other.traces;
+        self.groups += other.groups;
+        self.edges += other.edges;
+    }
+}
+
+impl fmt::Display for SkippedWork {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} traces, {} problem groups, {} problem edges",
+            self.traces, self.groups, self.edges
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::clock::SimulatedClock;
+
+    #[test]
+    fn passes_after_the_budget() {
+        let clock = Arc::new(SimulatedClock::new());
+        let deadline = CycleDeadline::start(clock.clone(), Some(Duration::from_millis(500)));
+        let unlimited = CycleDeadline::start(clock.clone(), None);
+        clock.advance(Duration::from_millis(500));
+        assert!(!deadline.passed());
+        clock.advance(Duration::from_millis(1));
+        assert!(deadline.passed());
+        assert!(!unlimited.passed());
+        assert_eq!(deadline.elapsed(), Duration::from_millis(501));
+
+        let mut total = SkippedWork::default();
+        assert!(total.is_empty());
+        total += SkippedWork {
+            traces: 3,
+            groups: 1,
+            edges: 0,
+        };
+        assert!(!total.is_empty());
+        assert_eq!(
+            total.to_string(),
+            "3 traces, 1 problem groups, 0 problem edges"
+        );
+    }
+}
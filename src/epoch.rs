@@ -0,0 +1,260 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Decision epochs of the controller loop.
+//!
+//! An epoch runs from one decision to the next, so the enabled tracepoints don't change during
+//! it. Each epoch records that configuration, the traces collected under it and the latency of
+//! each critical path structure, and is written to `epoch_dir/epoch_<number>.json` when it ends.
+//! Numbers go on from the epochs already in `epoch_dir`, so a restarted controller doesn't
+//! overwrite the epochs of the previous run.
+//! Group statistics that span several epochs can then be split by the configuration they were
+//! measured under, and consecutive epochs compared to see what the instrumentation cost (see
+//! `impact`).
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path as FilePath;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use chrono::NaiveDateTime;
+use log::{error, warn};
+use pythia_common::RequestType;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::clock::Clock;
+use crate::clock::SystemClock;
+use crate::critical::CriticalPath;
+use crate::critical::Path;
+use crate::settings::Settings;
+use crate::trace::Trace;
+use crate::trace::TracepointID;
+use crate::units::LatencyStats;
+
+/// Latency of the paths with one structure during an epoch
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EpochGroupStats {
+    pub count: u64,
+    /// Nanoseconds
+    pub mean: f64,
+    /// Population variance, nanoseconds squared
+    pub variance: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Epoch {
+    pub number: usize,
+    pub start: NaiveDateTime,
+    /// None while the epoch is running
+    pub end: Option<NaiveDateTime>,
+    /// Tracepoints enabled during the epoch
+    pub enabled: Vec<(TracepointID, Option<RequestType>)>,
+    pub trace_ids: Vec<Uuid>,
+    /// End-to-end latency of each request in nanoseconds, without transition traces
+    #[serde(default)]
+    pub latencies: Vec<u64>,
+    /// Statistics of the critical paths collected during the epoch, by path hash. Transition
+    /// traces are not included.
+    pub groups: BTreeMap<String, EpochGroupStats>,
+}
+
+impl Epoch {
+    fn new(
+        number: usize,
+        start: NaiveDateTime,
+        enabled: Vec<(TracepointID, Option<RequestType>)>,
+    ) -> Self {
+        let mut enabled = enabled;
+        enabled.sort_by_key(|(tp, rt)| (tp.to_string(), rt.map(|r| r.to_string())));
+        Epoch {
+            number,
+            start,
+            end: None,
+            enabled,
+            trace_ids: Vec::new(),
+            latencies: Vec::new(),
+            groups: BTreeMap::new(),
+        }
+    }
+}
+
+/// Keeps the current epoch and writes finished ones to `epoch_dir`
+pub struct EpochTracker {
+    dir: Option<PathBuf>,
+    clock: Arc<dyn Clock>,
+    current: Option<Epoch>,
+    stats: HashMap<String, LatencyStats>,
+    /// Number of the next epoch
+    next: usize,
+}
+
+impl EpochTracker {
+    pub fn from_settings(settings: &Settings) -> Self {
+        EpochTracker::new(settings.epoch_dir.clone())
+    }
+
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        let next = dir.as_deref().and_then(last_epoch).map_or(0, |n| n + 1);
+        EpochTracker {
+            dir,
+            clock: Arc::new(SystemClock),
+            current: None,
+            stats: HashMap::new(),
+            next,
+        }
+    }
+
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    pub fn current(&self) -> Option<&Epoch> {
+        self.current.as_ref()
+    }
+
+    /// Add a trace read during the current epoch, with its critical paths
+    pub fn record(&mut self, trace: &Trace, paths: &[CriticalPath]) {
+        let epoch = match self.current.as_mut() {
+            Some(e) => e,
+            None => return,
+        };
+        epoch.trace_ids.push(trace.base_id);
+        if !trace.is_transition {
+            epoch.latencies.push(trace.duration.as_nanos() as u64);
+        }
+        for p in paths.iter().filter(|p| !p.g.is_transition) {
+            self.stats
+                .entry(p.hash().to_string())
+                .or_insert_with(LatencyStats::new)
+                .add(p.duration);
+        }
+    }
+
+    /// End the current epoch, if there is one, and start the next one with the given
+    /// tracepoints enabled. Returns the epoch that ended.
+    pub fn advance(&mut self, enabled: Vec<(TracepointID, Option<RequestType>)>) -> Option<Epoch> {
+        let now = self.clock.wall();
+        let finished = self.finish();
+        self.current = Some(Epoch::new(self.next, now, enabled));
+        self.next += 1;
+        finished
+    }
+
+    /// End the current epoch and write it out
+    pub fn finish(&mut self) -> Option<Epoch> {
+        let mut epoch = self.current.take()?;
+        epoch.end = Some(self.clock.wall());
+        epoch.groups = self
+            .stats
+            .drain()
+            .map(|(hash, s)| {
+                let stats = EpochGroupStats {
+                    count: s.count(),
+                    mean: s.mean().0,
+                    variance: s.variance().0,
+                };
+                (hash, stats)
+            })
+            .collect();
+        if let Some(dir) = &self.dir {
+            if let Err(e) = write_epoch(dir, &epoch) {
+                error!("Could not write epoch {}: {}", epoch.number, e);
+            }
+        }
+        Some(epoch)
+    }
+}
+
+fn epoch_file(dir: &FilePath, number: usize) -> PathBuf {
+    dir.join(format!("epoch_{:05}.json", number))
+}
+
+/// Largest number of the epoch files in `dir`
+fn last_epoch(dir: &FilePath) -> Option<usize> {
+    fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            name.strip_prefix("epoch_")?
+                .strip_suffix(".json")?
+                .parse()
+                .ok()
+        })
+        .max()
+}
+
+fn write_epoch(dir: &FilePath, epoch: &Epoch) -> std::io::Result<()> {
+    fs::create_dir_all(dir)?;
+    fs::write(
+        epoch_file(dir, epoch.number),
+        serde_json::to_string(epoch).unwrap(),
+    )
+}
+
+/// Read back the epochs written to `dir`, in order
+pub fn read_epochs(dir: &FilePath) -> Vec<Epoch> {
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return Vec::new(),
+    };
+    let mut result = Vec::new();
+    for entry in entries {
+        let path = entry.unwrap().path();
+        match fs::read_to_string(&path)
+            .ok()
+            .and_then(|s| serde_json::from_str::<Epoch>(&s).ok())
+        {
+            Some(e) => result.push(e),
+            None => warn!("Skipping {}, not an epoch", path.display()),
+        }
+    }
+    result.sort_by_key(|e| e.number);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::clock::SimulatedClock;
+
+    #[test]
+    fn epochs_are_persisted() {
+        let dir = std::env::temp_dir().join(format!("pythia_epochs_{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        let mut tracker = EpochTracker::new(Some(dir.clone()));
+        tracker.set_clock(Arc::new(SimulatedClock::new()));
+        let a = (TracepointID::from_str("a"), None);
+        assert!(tracker.advance(vec![a]).is_none());
+        tracker.record(&Trace::new(&Uuid::new_v4()), &[]);
+        let first = tracker
+            .advance(vec![a, (TracepointID::from_str("b"), None)])
+            .unwrap();
+        assert_eq!(first.number, 0);
+        assert_eq!(first.trace_ids.len(), 1);
+        assert_eq!(tracker.current().unwrap().number, 1);
+        tracker.finish();
+
+        // After a restart, numbers go on
+        let mut restarted = EpochTracker::new(Some(dir.clone()));
+        restarted.advance(vec![a]);
+        assert_eq!(restarted.current().unwrap().number, 2);
+        restarted.finish();
+
+        let epochs = read_epochs(&dir);
+        fs::remove_dir_all(&dir).ok();
+        assert_eq!(epochs.len(), 3);
+        assert_eq!(epochs[0].enabled, vec![a]);
+        assert_eq!(epochs[1].enabled.len(), 2);
+        assert!(epochs[1].end.is_some());
+    }
+}
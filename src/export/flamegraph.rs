@@ -0,0 +1,257 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Flamegraphs of critical paths.
+//!
+//! Walking a critical path, an Entry pushes its tracepoint on the stack of open spans and the
+//! matching Exit pops it. The time of each edge goes to the stack at its source, under a root
+//! frame for the request type. The stacks are written in the collapsed format of `flamegraph.pl`
+//! and inferno (`frame;frame;frame microseconds` per line), or drawn as an SVG directly.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use uuid::Uuid;
+
+use crate::critical::CriticalPath;
+use crate::critical::Path;
+use crate::trace::EventType;
+
+const SVG_WIDTH: f64 = 1200.0;
+const FRAME_HEIGHT: f64 = 16.0;
+/// Frames narrower than this many pixels are left out of the SVG
+const MIN_FRAME_WIDTH: f64 = 0.1;
+
+/// Time on each stack of spans, summed over the paths added
+#[derive(Debug, Default)]
+pub struct Flamegraph {
+    stacks: BTreeMap<Vec<String>, u64>,
+}
+
+/// `;` separates frames in the collapsed format
+fn frame_name(name: &str) -> String {
+    name.replace(';', ":")
+}
+
+impl Flamegraph {
+    pub fn new() -> Self {
+        Flamegraph::default()
+    }
+
+    pub fn add_path(&mut self, path: &CriticalPath) {
+        let root = path.request_type.to_string();
+        let mut open: Vec<(Uuid, String)> = Vec::new();
+        let mut cur = path.start_node;
+        while let Some(next) = path.next_node(cur) {
+            let event = &path.g.g[cur];
+            match event.variant {
+                EventType::Entry => {
+                    open.push((event.trace_id, frame_name(&event.tracepoint_id.to_string())))
+                }
+                // The Entry may be off the critical path, then the Exit closes nothing
+                EventType::Exit => {
+                    if let Some(i) = open.iter().rposition(|(id, _)| *id == event.trace_id) {
+                        open.truncate(i);
+                    }
+                }
+                EventType::Annotation => {}
+            }
+            let edge = path.g.g.find_edge(cur, next).unwrap();
+            let micros = path.g.g[edge].duration.as_micros() as u64;
+            if micros > 0 {
+                let mut stack = vec![root.clone()];
+                stack.extend(open.iter().map(|(_, name)| name.clone()));
+                *self.stacks.entry(stack).or_insert(0) += micros;
+            }
+            cur = next;
+        }
+    }
+
+    pub fn collapsed(&self) -> String {
+        let mut out = String::new();
+        for (stack, micros) in &self.stacks {
+            writeln!(out, "{} {}", stack.join(";"), micros).unwrap();
+        }
+        out
+    }
+
+    /// Frames with their start and width in microseconds, and depth, parents before children
+    fn frames(&self) -> Vec<(String, u64, u64, usize)> {
+        // The stacks are sorted, so frames with the same ancestors are next to each other
+        let mut result: Vec<(String, u64, u64, usize)> = Vec::new();
+        // Index in result of the open frame at each depth
+        let mut open: Vec<usize> = Vec::new();
+        let mut prefix: &[String] = &[];
+        let mut offset = 0;
+        for (stack, &micros) in &self.stacks {
+            let shared = prefix
+                .iter()
+                .zip(stack.iter())
+                .take_while(|(a, b)| a == b)
+                .count();
+            open.truncate(shared);
+            for &i in &open {
+                result[i].2 += micros;
+            }
+            for (depth, name) in stack.iter().enumerate().skip(shared) {
+                open.push(result.len());
+                result.push((name.clone(), offset, micros, depth));
+            }
+            offset += micros;
+            prefix = stack;
+        }
+        result
+    }
+
+    pub fn svg(&self, title: &str) -> String {
+        let frames = self.frames();
+        let total = self.stacks.values().sum::<u64>().max(1) as f64;
+        let depth = frames.iter().map(|f| f.3 + 1).max().unwrap_or(0);
+        let height = FRAME_HEIGHT * (depth as f64 + 2.0);
+        let mut out = String::new();
+        writeln!(
+            out,
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" \
+             font-family=\"Verdana\" font-size=\"12\">",
+            SVG_WIDTH, height
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "<text x=\"{}\" y=\"{}\" text-anchor=\"middle\">{}</text>",
+            SVG_WIDTH / 2.0,
+            FRAME_HEIGHT,
+            escape(title)
+        )
+        .unwrap();
+        for (name, start, micros, depth) in frames {
+            let width = micros as f64 / total * SVG_WIDTH;
+            if width < MIN_FRAME_WIDTH {
+                continue;
+            }
+            let x = start as f64 / total * SVG_WIDTH;
+            // The root at the bottom, like flamegraph.pl
+            let y = height - FRAME_HEIGHT * (depth as f64 + 1.0);
+            // Characters are about 7 pixels wide
+            let chars = (width / 7.0) as usize;
+            let label: String = if chars >= name.chars().count() {
+                name.clone()
+            } else if chars > 2 {
+                format!("{}..", name.chars().take(chars - 2).collect::<String>())
+            } else {
+                String::new()
+            };
+            writeln!(
+                out,
+                "<g><title>{} ({}us, {:.2}%)</title>\
+                 <rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{}\" fill=\"{}\" \
+                 rx=\"2\"/><text x=\"{:.1}\" y=\"{:.1}\">{}</text></g>",
+                escape(&name),
+                micros,
+                100.0 * micros as f64 / total,
+                x,
+                y,
+                width,
+                FRAME_HEIGHT - 1.0,
+                color(&name),
+                x + 3.0,
+                y + FRAME_HEIGHT - 4.0,
+                escape(&label)
+            )
+            .unwrap();
+        }
+        writeln!(out, "</svg>").unwrap();
+        out
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Warm colors like flamegraph.pl, the same for every frame of a tracepoint
+fn color(name: &str) -> String {
+    let hash = name
+        .bytes()
+        .fold(0u32, |h, b| h.wrapping_mul(31).wrapping_add(b as u32));
+    format!(
+        "rgb({},{},{})",
+        205 + hash % 50,
+        (hash >> 8) % 230,
+        (hash >> 16) % 55
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use chrono::NaiveDateTime;
+
+    use crate::trace::DAGEdge;
+    use crate::trace::EdgeType;
+    use crate::trace::Event;
+    use crate::trace::Trace;
+    use crate::trace::TracepointID;
+
+    #[test]
+    fn stacks_follow_spans() {
+        let (outer, inner) = (Uuid::new_v4(), Uuid::new_v4());
+        let events = [
+            (outer, "outer", EventType::Entry, 0),
+            (inner, "in;ner", EventType::Entry, 1),
+            (inner, "in;ner", EventType::Exit, 4),
+            (Uuid::new_v4(), "note", EventType::Annotation, 5),
+            (outer, "outer", EventType::Exit, 7),
+        ];
+        let mut trace = Trace::new(&Uuid::new_v4());
+        let nodes: Vec<_> = events
+            .iter()
+            .map(|&(id, name, variant, ms)| {
+                trace.g.add_node(Event {
+                    trace_id: id,
+                    tracepoint_id: TracepointID::from_str(name),
+                    timestamp: NaiveDateTime::from_timestamp(0, 0)
+                        + chrono::Duration::milliseconds(ms),
+                    is_synthetic: false,
+                    variant,
+                    key_value_pair: HashMap::new(),
+                })
+            })
+            .collect();
+        for (w, ms) in nodes.windows(2).zip(&[1, 3, 1, 2]) {
+            let edge = DAGEdge {
+                duration: Duration::from_millis(*ms),
+                variant: EdgeType::ChildOf,
+            };
+            trace.g.add_edge(w[0], w[1], edge);
+        }
+        trace.start_node = nodes[0];
+        trace.end_node = nodes[4];
+        trace.duration = Duration::from_millis(7);
+        let path = CriticalPath::from_trace(&trace).unwrap();
+        let mut flamegraph = Flamegraph::new();
+        flamegraph.add_path(&path);
+        flamegraph.add_path(&path);
+        assert_eq!(
+            flamegraph.collapsed(),
+            "Unknown;outer 8000\nUnknown;outer;in:ner 6000\n"
+        );
+        let frames = flamegraph.frames();
+        assert_eq!(frames[0], ("Unknown".to_string(), 0, 14000, 0));
+        assert_eq!(frames[1], ("outer".to_string(), 0, 14000, 1));
+        assert_eq!(frames[2], ("in:ner".to_string(), 8000, 6000, 2));
+        assert!(flamegraph.svg("test").contains("in:ner (6000us, 42.86%)"));
+    }
+}
@@ -0,0 +1,337 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! DOT and GraphML output for traces and groups, with more options than `petgraph::dot::Dot`.
+//!
+//! A style is a comma separated list of options, e.g. `host,duration,collapse,graphml`:
+//!
+//! * `host` fills nodes with a color per host
+//! * `duration` / `variance` scale edge pen width by the edge's (mean) duration or its variance
+//!   across the traces of a group
+//! * `collapse` removes synthetic nodes, joining their neighbors with a single edge
+//! * `graphml` writes GraphML instead of DOT
+
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+
+use log::warn;
+use petgraph::graph::NodeIndex;
+use petgraph::visit::EdgeRef;
+use petgraph::visit::IntoEdgeReferences;
+
+use crate::grouping::Group;
+use crate::trace::Trace;
+use crate::trace::Value;
+
+const HOST_COLORS: [&str; 8] = [
+    "lightblue",
+    "lightpink",
+    "palegreen",
+    "khaki",
+    "plum",
+    "lightsalmon",
+    "lightcyan",
+    "wheat",
+];
+const MAX_PEN_WIDTH: f64 = 8.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphFormat {
+    Dot,
+    GraphML,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PenWidth {
+    Uniform,
+    Duration,
+    Variance,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GraphStyle {
+    pub format: GraphFormat,
+    pub color_by_host: bool,
+    pub pen_width: PenWidth,
+    pub collapse_synthetic: bool,
+}
+
+impl Default for GraphStyle {
+    fn default() -> Self {
+        GraphStyle {
+            format: GraphFormat::Dot,
+            color_by_host: false,
+            pen_width: PenWidth::Uniform,
+            collapse_synthetic: false,
+        }
+    }
+}
+
+impl GraphStyle {
+    pub fn from_str(s: &str) -> GraphStyle {
+        let mut result = GraphStyle::default();
+        for option in s.split(',').map(|o| o.trim()).filter(|o| !o.is_empty()) {
+            match option {
+                "host" => result.color_by_host = true,
+                "duration" => result.pen_width = PenWidth::Duration,
+                "variance" => result.pen_width = PenWidth::Variance,
+                "collapse" => result.collapse_synthetic = true,
+                "dot" => result.format = GraphFormat::Dot,
+                "graphml" => result.format = GraphFormat::GraphML,
+                _ => panic!("Unknown graph style option {}", option),
+            }
+        }
+        result
+    }
+}
+
+/// What is drawn, independent of where it came from
+struct Drawing {
+    /// Label and host
+    nodes: Vec<(String, Option<String>)>,
+    /// Source, target, label, and the weight used for the pen width
+    edges: Vec<(usize, usize, String, f64)>,
+}
+
+fn host_of(value: Option<&Value>) -> Option<String> {
+    match value {
+        Some(Value::Str(s)) => Some(s.clone()),
+        Some(Value::UnsignedInt(v)) => Some(v.to_string()),
+        Some(Value::SignedInt(v)) => Some(v.to_string()),
+        None => None,
+    }
+}
+
+fn trace_drawing(trace: &Trace, style: &GraphStyle) -> Drawing {
+    if style.pen_width == PenWidth::Variance {
+        warn!("A single trace has no variance, using uniform edges");
+    }
+    let skip = |n: NodeIndex| style.collapse_synthetic && trace.g[n].is_synthetic;
+    let mut index = HashMap::new();
+    let mut nodes = Vec::new();
+    for n in trace.g.node_indices().filter(|&n| !skip(n)) {
+        index.insert(n, nodes.len());
+        let event = &trace.g[n];
+        nodes.push((event.to_string(), host_of(event.key_value_pair.get("host"))));
+    }
+    let mut edges = Vec::new();
+    for n in trace.g.node_indices().filter(|&n| !skip(n)) {
+        // Follow chains of synthetic nodes, adding up their durations
+        let mut stack: Vec<_> = trace
+            .g
+            .edges(n)
+            .map(|e| (e.target(), e.weight().duration))
+            .collect();
+        while let Some((target, duration)) = stack.pop() {
+            if skip(target) {
+                stack.extend(
+                    trace
+                        .g
+                        .edges(target)
+                        .map(|e| (e.target(), duration + e.weight().duration)),
+                );
+                continue;
+            }
+            let weight = match style.pen_width {
+                PenWidth::Duration => duration.as_nanos() as f64,
+                _ => 0.0,
+            };
+            edges.push((
+                index[&n],
+                index[&target],
+                format!("{:?}", duration),
+                weight,
+            ));
+        }
+    }
+    Drawing { nodes, edges }
+}
+
+fn group_drawing(group: &Group, style: &GraphStyle) -> Drawing {
+    let g = &group.g;
+    let mut index = HashMap::new();
+    let mut nodes = Vec::new();
+    for n in g.node_indices() {
+        index.insert(n, nodes.len());
+        let host = host_of(g[n].key_value_pair.get("host").and_then(|v| v.first()));
+        nodes.push((g[n].to_string(), host));
+    }
+    let edges = g
+        .edge_references()
+        .map(|e| {
+            let stats = &e.weight().stats;
+            let weight = match style.pen_width {
+                PenWidth::Uniform => 0.0,
+                PenWidth::Duration => stats.mean().0,
+                PenWidth::Variance => stats.variance().0,
+            };
+            (
+                index[&e.source()],
+                index[&e.target()],
+                format!("{:?} mean, {} paths", stats.mean().to_duration(), stats.count()),
+                weight,
+            )
+        })
+        .collect();
+    Drawing { nodes, edges }
+}
+
+pub fn trace_graph(trace: &Trace, style: &GraphStyle) -> String {
+    render(&trace_drawing(trace, style), style)
+}
+
+/// Synthetic nodes are already removed from group paths, so `collapse` does nothing here
+pub fn group_graph(group: &Group, style: &GraphStyle) -> String {
+    render(&group_drawing(group, style), style)
+}
+
+fn render(drawing: &Drawing, style: &GraphStyle) -> String {
+    let hosts: BTreeSet<&String> = drawing.nodes.iter().filter_map(|n| n.1.as_ref()).collect();
+    let color = |host: &Option<String>| -> Option<&str> {
+        if !style.color_by_host {
+            return None;
+        }
+        host.as_ref().map(|h| {
+            let i = hosts.iter().position(|x| *x == h).unwrap();
+            HOST_COLORS[i % HOST_COLORS.len()]
+        })
+    };
+    let max_weight = drawing.edges.iter().map(|e| e.3).fold(0.0, f64::max);
+    let pen_width = |w: f64| -> f64 {
+        if max_weight > 0.0 {
+            1.0 + (MAX_PEN_WIDTH - 1.0) * w / max_weight
+        } else {
+            1.0
+        }
+    };
+    match style.format {
+        GraphFormat::Dot => {
+            let mut out = String::from("digraph {\n");
+            for (i, (label, host)) in drawing.nodes.iter().enumerate() {
+                out += &format!("    {} [ label = \"{}\"", i, escape_dot(label));
+                if let Some(c) = color(host) {
+                    out += &format!(" style = filled fillcolor = \"{}\"", c);
+                }
+                out += " ]\n";
+            }
+            for (s, t, label, w) in &drawing.edges {
+                out += &format!(
+                    "    {} -> {} [ label = \"{}\" penwidth = {:.2} ]\n",
+                    s,
+                    t,
+                    escape_dot(label),
+                    pen_width(*w)
+                );
+            }
+            out + "}\n"
+        }
+        GraphFormat::GraphML => {
+            let mut out = String::from(concat!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+                "<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n",
+                "  <key id=\"label\" for=\"all\" attr.name=\"label\" attr.type=\"string\"/>\n",
+                "  <key id=\"host\" for=\"node\" attr.name=\"host\" attr.type=\"string\"/>\n",
+                "  <key id=\"color\" for=\"node\" attr.name=\"color\" attr.type=\"string\"/>\n",
+                "  <key id=\"width\" for=\"edge\" attr.name=\"width\" attr.type=\"double\"/>\n",
+                "  <graph edgedefault=\"directed\">\n",
+            ));
+            for (i, (label, host)) in drawing.nodes.iter().enumerate() {
+                out += &format!(
+                    "    <node id=\"n{}\"><data key=\"label\">{}</data>",
+                    i,
+                    escape_xml(label)
+                );
+                if let Some(h) = host {
+                    out += &format!("<data key=\"host\">{}</data>", escape_xml(h));
+                }
+                if let Some(c) = color(host) {
+                    out += &format!("<data key=\"color\">{}</data>", c);
+                }
+                out += "</node>\n";
+            }
+            for (s, t, label, w) in &drawing.edges {
+                out += &format!(
+                    concat!(
+                        "    <edge source=\"n{}\" target=\"n{}\"><data key=\"label\">{}</data>",
+                        "<data key=\"width\">{:.2}</data></edge>\n"
+                    ),
+                    s,
+                    t,
+                    escape_xml(label),
+                    pen_width(*w)
+                );
+            }
+            out + "  </graph>\n</graphml>\n"
+        }
+    }
+}
+
+fn escape_dot(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use chrono::NaiveDateTime;
+    use uuid::Uuid;
+
+    use crate::trace::DAGEdge;
+    use crate::trace::EdgeType;
+    use crate::trace::Event;
+    use crate::trace::EventType;
+    use crate::trace::TracepointID;
+
+    #[test]
+    fn collapses_synthetic_nodes() {
+        let mut trace = Trace::new(&Uuid::new_v4());
+        let nodes = (0..3)
+            .map(|i| {
+                let mut key_value_pair = HashMap::new();
+                key_value_pair.insert("host".to_string(), Value::Str(format!("h{}", i % 2)));
+                trace.g.add_node(Event {
+                    trace_id: Uuid::new_v4(),
+                    tracepoint_id: TracepointID::from_str(&format!("tp{}", i)),
+                    timestamp: NaiveDateTime::from_timestamp(i, 0),
+                    is_synthetic: i == 1,
+                    variant: EventType::Annotation,
+                    key_value_pair,
+                })
+            })
+            .collect::<Vec<_>>();
+        for w in nodes.windows(2) {
+            let edge = DAGEdge {
+                duration: Duration::from_millis(5),
+                variant: EdgeType::ChildOf,
+            };
+            trace.g.add_edge(w[0], w[1], edge);
+        }
+
+        let style = GraphStyle::from_str("host,duration,collapse");
+        let drawing = trace_drawing(&trace, &style);
+        assert_eq!(drawing.nodes.len(), 2);
+        assert_eq!(drawing.edges.len(), 1);
+        assert_eq!(drawing.edges[0].3, 10_000_000.0);
+        let dot = render(&drawing, &style);
+        assert!(dot.contains("fillcolor = \"lightblue\""));
+        assert!(dot.contains("penwidth = 8.00"));
+
+        let graphml = trace_graph(&trace, &GraphStyle::from_str("graphml"));
+        assert_eq!(graphml.matches("<node ").count(), 3);
+    }
+}
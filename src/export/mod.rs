@@ -0,0 +1,53 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Writes traces in formats that other tools can display.
+
+mod flamegraph;
+mod graph;
+mod spans;
+
+pub use crate::export::flamegraph::Flamegraph;
+pub use crate::export::graph::group_graph;
+pub use crate::export::graph::trace_graph;
+pub use crate::export::graph::GraphFormat;
+pub use crate::export::graph::GraphStyle;
+pub use crate::export::graph::PenWidth;
+pub use crate::export::spans::spans;
+pub use crate::export::spans::Span;
+
+use crate::trace::Trace;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpanFormat {
+    /// Jaeger UI's JSON upload format
+    Jaeger,
+    /// chrome://tracing, Perfetto
+    Chrome,
+    /// OpenTelemetry protocol, JSON encoding
+    Otlp,
+}
+
+impl SpanFormat {
+    pub fn from_str(s: &str) -> SpanFormat {
+        match s {
+            "jaeger" => SpanFormat::Jaeger,
+            "chrome" => SpanFormat::Chrome,
+            "otlp" => SpanFormat::Otlp,
+            _ => panic!("Unknown span format {}", s),
+        }
+    }
+}
+
+pub fn export_spans(trace: &Trace, format: SpanFormat) -> serde_json::Value {
+    match format {
+        SpanFormat::Jaeger => spans::to_jaeger(trace),
+        SpanFormat::Chrome => spans::to_chrome(trace),
+        SpanFormat::Otlp => spans::to_otlp(trace),
+    }
+}
@@ -0,0 +1,295 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Turns a trace DAG back into spans, and writes them in the formats other tools read.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+
+use chrono::NaiveDateTime;
+use log::warn;
+use petgraph::algo::toposort;
+use petgraph::graph::NodeIndex;
+use petgraph::Direction;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::trace::EventType;
+use crate::trace::Trace;
+use crate::trace::Value;
+
+/// An Entry event and its matching Exit
+pub struct Span {
+    pub id: Uuid,
+    pub parent: Option<Uuid>,
+    pub name: String,
+    pub host: String,
+    pub start: NaiveDateTime,
+    /// Spans without an Exit end at their start
+    pub end: NaiveDateTime,
+    pub tags: BTreeMap<String, String>,
+    /// Annotations made while this span was the innermost one
+    pub logs: Vec<(NaiveDateTime, String)>,
+}
+
+fn value_string(value: &Value) -> String {
+    match value {
+        Value::UnsignedInt(v) => v.to_string(),
+        Value::SignedInt(v) => v.to_string(),
+        Value::Str(s) => s.clone(),
+    }
+}
+
+fn micros(t: NaiveDateTime) -> i64 {
+    t.timestamp() * 1_000_000 + t.timestamp_subsec_micros() as i64
+}
+
+fn nanos(t: NaiveDateTime) -> i64 {
+    t.timestamp() * 1_000_000_000 + t.timestamp_subsec_nanos() as i64
+}
+
+/// The first 16 hex digits of a uuid, for formats with 64-bit span ids
+fn short_id(id: &Uuid) -> String {
+    id.to_simple().to_string()[..16].to_string()
+}
+
+/// Rebuilds the span hierarchy from the DAG. The span enclosing a node is found through its
+/// first predecessor: an Entry opens a span, and an Exit closes one so the enclosing span is the
+/// parent of the closed span.
+pub fn spans(trace: &Trace) -> Vec<Span> {
+    let order = match toposort(&trace.g, None) {
+        Ok(o) => o,
+        Err(_) => {
+            warn!(trace_id:% = trace.base_id; "Trace has a cycle, can't export it");
+            return Vec::new();
+        }
+    };
+    let mut enclosing: HashMap<NodeIndex, Option<Uuid>> = HashMap::new();
+    let mut parents: HashMap<Uuid, Option<Uuid>> = HashMap::new();
+    let mut result: Vec<Span> = Vec::new();
+    let mut index: HashMap<Uuid, usize> = HashMap::new();
+    for nidx in order {
+        let event = &trace.g[nidx];
+        let outer = match trace.g.neighbors_directed(nidx, Direction::Incoming).next() {
+            None => None,
+            Some(p) => match trace.g[p].variant {
+                EventType::Entry => Some(trace.g[p].trace_id),
+                EventType::Exit => *parents.get(&trace.g[p].trace_id).unwrap_or(&None),
+                EventType::Annotation => *enclosing.get(&p).unwrap_or(&None),
+            },
+        };
+        enclosing.insert(nidx, outer);
+        match event.variant {
+            EventType::Entry => {
+                parents.insert(event.trace_id, outer);
+                index.insert(event.trace_id, result.len());
+                result.push(Span {
+                    id: event.trace_id,
+                    parent: outer,
+                    name: event.tracepoint_id.to_string(),
+                    host: event
+                        .key_value_pair
+                        .get("host")
+                        .map(value_string)
+                        .unwrap_or("unknown".to_string()),
+                    start: event.timestamp,
+                    end: event.timestamp,
+                    tags: event
+                        .key_value_pair
+                        .iter()
+                        .map(|(k, v)| (k.clone(), value_string(v)))
+                        .collect(),
+                    logs: Vec::new(),
+                });
+            }
+            EventType::Exit => {
+                if let Some(&i) = index.get(&event.trace_id) {
+                    result[i].end = event.timestamp;
+                }
+            }
+            EventType::Annotation => {
+                if let Some(&i) = outer.as_ref().and_then(|id| index.get(id)) {
+                    result[i]
+                        .logs
+                        .push((event.timestamp, event.tracepoint_id.to_string()));
+                }
+            }
+        }
+    }
+    result
+}
+
+/// The JSON accepted by "JSON File" upload in the Jaeger UI
+pub fn to_jaeger(trace: &Trace) -> serde_json::Value {
+    let trace_id = trace.base_id.to_simple().to_string();
+    let spans = spans(trace);
+    let mut processes = BTreeMap::new();
+    for span in &spans {
+        let next = format!("p{}", processes.len() + 1);
+        processes.entry(span.host.clone()).or_insert(next);
+    }
+    json!({
+        "data": [{
+            "traceID": trace_id,
+            "spans": spans.iter().map(|s| json!({
+                "traceID": trace_id,
+                "spanID": short_id(&s.id),
+                "operationName": s.name,
+                "references": s.parent.iter().map(|p| json!({
+                    "refType": "CHILD_OF",
+                    "traceID": trace_id,
+                    "spanID": short_id(p),
+                })).collect::<Vec<_>>(),
+                "startTime": micros(s.start),
+                "duration": micros(s.end) - micros(s.start),
+                "tags": s.tags.iter().map(|(k, v)| json!({
+                    "key": k,
+                    "type": "string",
+                    "value": v,
+                })).collect::<Vec<_>>(),
+                "logs": s.logs.iter().map(|(t, name)| json!({
+                    "timestamp": micros(*t),
+                    "fields": [{"key": "event", "type": "string", "value": name}],
+                })).collect::<Vec<_>>(),
+                "processID": processes[&s.host],
+            })).collect::<Vec<_>>(),
+            "processes": processes.iter().map(|(host, pid)| (pid.clone(), json!({
+                "serviceName": host,
+                "tags": [],
+            }))).collect::<BTreeMap<_, _>>(),
+        }]
+    })
+}
+
+/// Trace Event Format, for chrome://tracing and Perfetto. Each host is a process; timestamps
+/// are relative to the start of the trace.
+pub fn to_chrome(trace: &Trace) -> serde_json::Value {
+    let spans = spans(trace);
+    let origin = micros(trace.g[trace.start_node].timestamp);
+    let mut pids = BTreeMap::new();
+    for span in &spans {
+        let next = pids.len() + 1;
+        pids.entry(span.host.clone()).or_insert(next);
+    }
+    let mut events = pids
+        .iter()
+        .map(|(host, pid)| {
+            json!({"name": "process_name", "ph": "M", "pid": pid, "args": {"name": host}})
+        })
+        .collect::<Vec<_>>();
+    for span in &spans {
+        events.push(json!({
+            "name": span.name,
+            "cat": "pythia",
+            "ph": "X",
+            "ts": micros(span.start) - origin,
+            "dur": micros(span.end) - micros(span.start),
+            "pid": pids[&span.host],
+            "tid": span.tags.get("thread_id").cloned().unwrap_or("0".to_string()),
+            "args": span.tags,
+        }));
+        for (t, name) in &span.logs {
+            events.push(json!({
+                "name": name,
+                "cat": "pythia",
+                "ph": "i",
+                "s": "t",
+                "ts": micros(*t) - origin,
+                "pid": pids[&span.host],
+                "tid": span.tags.get("thread_id").cloned().unwrap_or("0".to_string()),
+            }));
+        }
+    }
+    json!({ "traceEvents": events, "displayTimeUnit": "ms" })
+}
+
+/// OTLP/JSON, as sent to an OpenTelemetry collector's /v1/traces endpoint. Spans are grouped by
+/// host, which becomes the service name.
+pub fn to_otlp(trace: &Trace) -> serde_json::Value {
+    let trace_id = trace.base_id.to_simple().to_string();
+    let mut by_host: BTreeMap<String, Vec<serde_json::Value>> = BTreeMap::new();
+    for span in spans(trace) {
+        by_host.entry(span.host.clone()).or_default().push(json!({
+            "traceId": trace_id,
+            "spanId": short_id(&span.id),
+            "parentSpanId": span.parent.map(|p| short_id(&p)).unwrap_or_default(),
+            "name": span.name,
+            "kind": 1,
+            "startTimeUnixNano": nanos(span.start).to_string(),
+            "endTimeUnixNano": nanos(span.end).to_string(),
+            "attributes": span.tags.iter().map(|(k, v)| json!({
+                "key": k,
+                "value": {"stringValue": v},
+            })).collect::<Vec<_>>(),
+            "events": span.logs.iter().map(|(t, name)| json!({
+                "timeUnixNano": nanos(*t).to_string(),
+                "name": name,
+            })).collect::<Vec<_>>(),
+        }));
+    }
+    json!({
+        "resourceSpans": by_host.into_iter().map(|(host, spans)| json!({
+            "resource": {
+                "attributes": [{"key": "service.name", "value": {"stringValue": host}}],
+            },
+            "scopeSpans": [{"scope": {"name": "pythia"}, "spans": spans}],
+        })).collect::<Vec<_>>()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::trace::DAGEdge;
+    use crate::trace::EdgeType;
+    use crate::trace::Event;
+    use crate::trace::TracepointID;
+
+    fn event(id: Uuid, name: &str, variant: EventType, ms: i64) -> Event {
+        Event {
+            trace_id: id,
+            tracepoint_id: TracepointID::from_str(name),
+            timestamp: NaiveDateTime::from_timestamp(0, 0) + chrono::Duration::milliseconds(ms),
+            is_synthetic: false,
+            variant,
+            key_value_pair: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn nested_spans() {
+        let (outer, inner) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut trace = Trace::new(&Uuid::new_v4());
+        let nodes = vec![
+            trace.g.add_node(event(outer, "outer", EventType::Entry, 0)),
+            trace.g.add_node(event(inner, "inner", EventType::Entry, 1)),
+            trace.g.add_node(event(inner, "inner", EventType::Exit, 3)),
+            trace.g.add_node(event(Uuid::new_v4(), "note", EventType::Annotation, 4)),
+            trace.g.add_node(event(outer, "outer", EventType::Exit, 5)),
+        ];
+        for w in nodes.windows(2) {
+            let edge = DAGEdge {
+                duration: Default::default(),
+                variant: EdgeType::ChildOf,
+            };
+            trace.g.add_edge(w[0], w[1], edge);
+        }
+        trace.start_node = nodes[0];
+        trace.end_node = nodes[4];
+
+        let result = spans(&trace);
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].parent, None);
+        assert_eq!(result[1].parent, Some(outer));
+        assert_eq!(result[0].logs.len(), 1);
+        // Host metadata, then the outer span and its annotation, then the inner span
+        assert_eq!(to_chrome(&trace)["traceEvents"][3]["ts"], 1000);
+        assert_eq!(to_jaeger(&trace)["data"][0]["spans"][0]["duration"], 5000);
+    }
+}
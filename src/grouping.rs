@@ -8,29 +8,54 @@ All rights reserved.
 
 //! Code related to grouping critical paths
 
+use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::fmt;
 use std::fmt::Display;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
+use std::time::Instant;
 
+use log::{debug, info, trace, warn};
 use petgraph::dot::Dot;
 use petgraph::graph::EdgeIndex;
 use petgraph::graph::NodeIndex;
 use petgraph::stable_graph::StableGraph;
 use petgraph::Direction;
-use stats::variance;
-use stats::mean;
+use uuid::Uuid;
 
+use pythia_common::ParameterizedRequestType;
 use pythia_common::RequestType;
 
+use crate::clock::Clock;
+use crate::clock::SystemClock;
+use crate::clustering::align;
+use crate::clustering::GroupingMode;
 use crate::critical::CriticalPath;
 use crate::critical::Path;
+use crate::hypothesis::eta_squared;
+use crate::hypothesis::GroupSummary;
+use crate::query::Filter;
+use crate::settings::Settings;
+use crate::slo::Slo;
+use crate::slo::SloCompliance;
+use crate::slo::SLO_MIN_TRACES;
+use crate::trace::Trace;
 use crate::trace::TraceNode;
 //use crate::trace::TraceNode::key_value_pair;
 use crate::trace::TracepointID;
 use crate::trace::Value;
+use crate::units::cv;
+use crate::units::mean;
+use crate::units::variance;
+use crate::units::LatencyStats;
+use crate::units::Nanos;
+use crate::units::NanosSquared;
 
 use histogram::Histogram;
+use serde::Serialize;
 
 /// A group of critical paths
 #[derive(Clone, Debug)]
@@ -41,12 +66,31 @@ pub struct Group {
     pub start_node: NodeIndex,
     pub end_node: NodeIndex,
     pub request_type: RequestType,
-    /// The raw critical paths that this group was constructed from
+    /// Salient request parameters; only non-empty if the group manager groups by them
+    pub request_params: BTreeMap<String, String>,
+    /// Key and value shared by the paths of the group, when groups are partitioned, see
+    /// `GroupManager::partition_by`
+    pub partition: Option<(String, String)>,
+    /// The raw critical paths that this group was constructed from. A `GroupManager` with
+    /// `GroupLimits::max_traces` keeps only the latest ones; use `trace_count` for the number of
+    /// paths.
     pub traces: Vec<CriticalPath>,
-    pub variance: f64,
+    /// End-to-end latency of all paths added since the group was last used, including the ones
+    /// dropped from `traces`
+    pub stats: LatencyStats,
+    /// `stats` of the paths that went through a canary host, and of the others
+    pub canary_stats: LatencyStats,
+    pub non_canary_stats: LatencyStats,
+    /// Changes whenever `stats` start over, i.e., when the group is created or used
+    generation: u64,
+    /// Position of each path in `traces` among the paths pushed to the edges, to find its
+    /// durations in the edge sample windows
+    trace_seq: Vec<usize>,
+    paths_added: usize,
+    pub variance: NanosSquared,
    // pub key_value_pairs: HashMap<String, Vec<Value>>,
    // tsl: Group means to calculate CVs
-   pub mean: f64,
+   pub mean: Nanos,
    pub is_used: bool,
 
 
@@ -57,10 +101,81 @@ pub struct Group {
   // pub cv: f64,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// A span that is off the critical paths of a group, see `Group::near_critical_spans`
+#[derive(Serialize, Debug, Clone)]
+pub struct NearCriticalSpan {
+    /// Tracepoint where the span ends (or starts, if it has no end)
+    pub tracepoint: String,
+    /// Traces of the group that have the span off their critical path
+    pub count: usize,
+    pub min_slack: Nanos,
+    pub mean_slack: Nanos,
+}
+
+/// Latency statistics of one edge of a group, see `Group::latency_breakdown`
+#[derive(Serialize, Debug, Clone)]
+pub struct EdgeBreakdown {
+    pub from: String,
+    pub to: String,
+    pub mean: Nanos,
+    pub p50: Nanos,
+    pub p95: Nanos,
+    pub p99: Nanos,
+    pub variance: NanosSquared,
+    /// Percentage of the mean end-to-end latency spent on this edge
+    pub latency_share: f64,
+    /// Percentage of the end-to-end variance due to this edge. This is the covariance of the
+    /// edge with the whole path over the path's variance, so the shares add up to 100 but an
+    /// edge that offsets the others can have a negative share.
+    pub variance_share: f64,
+}
+
+fn next_generation() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Groups only take paths without branches, see `CriticalPath::check_linear`. The rare path
+/// that branches is left out of the groups instead of stopping the loop.
+fn is_linear(path: &CriticalPath) -> bool {
+    match path.check_linear() {
+        Ok(()) => true,
+        Err(e) => {
+            warn!(trace_id:% = path.g.base_id; "Leaving the path out of the groups: {}", e);
+            false
+        }
+    }
+}
+
+/// Only the durations of this many of the latest paths are kept on each edge
+const EDGE_SAMPLE_WINDOW: usize = 1000;
+
+#[derive(Debug, Clone)]
 pub struct GroupEdge {
-    /// These are the durations of the individual paths.
+    /// These are the durations of the latest paths, at most `EDGE_SAMPLE_WINDOW` of them, in
+    /// the order the paths were added.
     pub duration: Vec<Duration>,
+    /// Statistics of the durations of all paths, including the ones dropped from `duration`
+    pub stats: LatencyStats,
+}
+
+impl GroupEdge {
+    fn new(duration: Duration) -> Self {
+        let mut edge = GroupEdge {
+            duration: Vec::new(),
+            stats: LatencyStats::new(),
+        };
+        edge.push(duration);
+        edge
+    }
+
+    fn push(&mut self, duration: Duration) {
+        self.stats.add(duration);
+        self.duration.push(duration);
+        if self.duration.len() > EDGE_SAMPLE_WINDOW {
+            self.duration.remove(0);
+        }
+    }
 }
 
 impl Display for GroupEdge {
@@ -68,10 +183,10 @@ impl Display for GroupEdge {
         write!(
             f,
             "Edge({} elements, {:?} min, {:?} max, {:?} variance)",
-            self.duration.len(),
-            self.duration.iter().min().unwrap(),
-            self.duration.iter().max().unwrap(),
-            variance(self.duration.iter().map(|&x| x.as_nanos())),
+            self.stats.count(),
+            self.stats.min().to_duration(),
+            self.stats.max().to_duration(),
+            self.stats.variance(),
         )
     }
 }
@@ -91,7 +206,7 @@ impl Group {
 
     pub fn from_critical_paths(paths: Vec<CriticalPath>) -> Vec<Group> {
         let mut hash_map = HashMap::<String, Group>::new();
-        for path in paths {
+        for path in paths.into_iter().filter(is_linear) {
             match hash_map.get_mut(path.hash()) {
                 Some(v) => v.add_trace(&path),
                 None => {
@@ -103,11 +218,11 @@ impl Group {
         for (_, group) in hash_map.iter_mut() {
             group.calculate_variance();
             group.calculate_mean();
-            if group.variance == 0.0 {
+            if group.variance.is_zero() {
                 zeros += 1;
             }
         }
-        println!("{} groups had 0 variance", zeros);
+        debug!("{} groups had 0 variance", zeros);
         hash_map.values().cloned().collect::<Vec<Group>>()
     }
 
@@ -129,9 +244,7 @@ impl Group {
                         dag.add_edge(
                             prev_dag_nidx.unwrap(),
                             dag_nidx,
-                            GroupEdge {
-                                duration: vec![path.g.g[edge].duration],
-                            },
+                            GroupEdge::new(path.g.g[edge].duration),
                         );
                     }
                     None => panic!("No edge?"),
@@ -144,15 +257,34 @@ impl Group {
                 None => break,
             };
         }
+        let mut canary_stats = LatencyStats::new();
+        let mut non_canary_stats = LatencyStats::new();
+        if path.g.is_canary {
+            canary_stats.add(path.duration);
+        } else {
+            non_canary_stats.add(path.duration);
+        }
         Group {
             g: dag,
             start_node: start_node.unwrap(),
             end_node: end_node,
             hash: path.hash().to_string(),
             request_type: path.request_type,
+            request_params: BTreeMap::new(),
+            partition: None,
+            stats: {
+                let mut stats = LatencyStats::new();
+                stats.add(path.duration);
+                stats
+            },
+            canary_stats,
+            non_canary_stats,
+            generation: next_generation(),
             traces: vec![path],
-            variance: 0.0,
-            mean: 0.0,
+            trace_seq: vec![0],
+            paths_added: 1,
+            variance: NanosSquared(0.0),
+            mean: Nanos(0.0),
             is_used: false,
             // enabled_tps: Vec<(TracepointID, Option<RequestType>)> = Vec::new(),
             //cv: 0.0,
@@ -160,27 +292,110 @@ impl Group {
         }
     }
 
+    pub fn parameterized_type(&self) -> ParameterizedRequestType {
+        ParameterizedRequestType {
+            kind: self.request_type,
+            params: self.request_params.clone(),
+        }
+    }
+
     /// After we use a group for diagnosis, we reset the group. This function is incomplete, and we
     /// should ideally modify the edges as well.
     pub fn used(&mut self) {
         self.traces = Vec::new();
-        self.variance = 0.0;
+        self.trace_seq = Vec::new();
+        self.stats = LatencyStats::new();
+        self.canary_stats = LatencyStats::new();
+        self.non_canary_stats = LatencyStats::new();
+        self.generation = next_generation();
+        self.variance = NanosSquared(0.0);
         self.is_used = true;
     }
 
+    /// See `generation`: statistics of the same generation only changed by adding or removing
+    /// paths
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Number of paths added since the group was last used
+    pub fn trace_count(&self) -> usize {
+        self.stats.count() as usize
+    }
+
+    /// Statistics of each edge on the path, in path order
+    pub fn latency_breakdown(&self) -> Vec<EdgeBreakdown> {
+        let mut names = Vec::new();
+        let mut edges = Vec::new();
+        let mut cur_node = self.start_node;
+        while let Some(next) = self.next_node(cur_node) {
+            let edge = self.g.find_edge(cur_node, next).unwrap();
+            names.push((self.g[cur_node].to_string(), self.g[next].to_string()));
+            edges.push(&self.g[edge]);
+            cur_node = next;
+        }
+        names
+            .into_iter()
+            .zip(edge_breakdown(&edges))
+            .map(|((from, to), mut b)| {
+                b.from = from;
+                b.to = to;
+                b
+            })
+            .collect()
+    }
+
+    /// The ends of each edge on the path and the durations of its latest `n` paths, in path
+    /// order
+    pub fn recent_durations(&self, n: usize) -> Vec<(String, String, Vec<Duration>)> {
+        self.edges_in_order()
+            .into_iter()
+            .map(|e| {
+                let (from, to) = self.g.edge_endpoints(e).unwrap();
+                let durations = &self.g[e].duration;
+                (
+                    self.g[from].to_string(),
+                    self.g[to].to_string(),
+                    durations[durations.len().saturating_sub(n)..].to_vec(),
+                )
+            })
+            .collect()
+    }
+
+    /// The `n` spans with the least mean slack in the traces of this group, among the given
+    /// traces. These are not on the critical path, but would be if they took a bit longer.
+    pub fn near_critical_spans(&self, traces: &[Trace], n: usize) -> Vec<NearCriticalSpan> {
+        let members: HashSet<Uuid> = self.traces.iter().map(|p| p.g.base_id).collect();
+        let mut slacks: HashMap<TracepointID, Vec<Duration>> = HashMap::new();
+        for trace in traces.iter().filter(|t| members.contains(&t.base_id)) {
+            for span in trace.span_slack() {
+                slacks.entry(span.tracepoint_id).or_default().push(span.slack);
+            }
+        }
+        let mut result: Vec<NearCriticalSpan> = slacks
+            .into_iter()
+            .map(|(tracepoint, slack)| NearCriticalSpan {
+                tracepoint: tracepoint.to_string(),
+                count: slack.len(),
+                min_slack: Nanos::from_duration(*slack.iter().min().unwrap()),
+                mean_slack: mean(slack.iter()),
+            })
+            .collect();
+        result.sort_by(|a, b| a.mean_slack.partial_cmp(&b.mean_slack).unwrap());
+        result.truncate(n);
+        result
+    }
+
     /// Returns all edges sorted by variance.
     pub fn problem_edges(&self) -> Vec<EdgeIndex> {
-        let mut edge_variances = HashMap::<EdgeIndex, f64>::new();
+        let mut edge_variances = HashMap::<EdgeIndex, NanosSquared>::new();
         let mut cur_node = self.start_node;
         let mut prev_node = None;
         loop {
             if !prev_node.is_none() {
                 match self.g.find_edge(prev_node.unwrap(), cur_node) {
                     Some(edge) => {
-                        edge_variances.insert(
-                            edge,
-                            variance(self.g[edge].duration.iter().map(|d| d.as_secs_f64())),
-                        );
+                        edge_variances.insert(edge, self.g[edge].stats.variance());
                     }
                     None => panic!("No edge?"),
                 }
@@ -194,67 +409,233 @@ impl Group {
         // tsl : edge variances are here; so maybe; sum them up and divide them by the total variance
         let mut result = edge_variances
             .into_iter()
-            .collect::<Vec<(EdgeIndex, f64)>>();
+            .collect::<Vec<(EdgeIndex, NanosSquared)>>();
         result.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
          //tsl: let's see
-        let sum: f64 = result.iter().map(|a| a.1).sum();
-        println!("*New Metric: hash {:?}, reqtype {:?}, total var {:?}, edge_total: {:?}", self.hash, self.request_type, self.variance, sum);
+        let sum = NanosSquared(result.iter().map(|a| (a.1).0).sum());
+        trace!(
+            group_hash:% = self.hash;
+            "Edge variances of {:?}: total {:?}, edge total {:?}",
+            self.request_type,
+            self.variance,
+            sum
+        );
         result.iter().map(|a| a.0).collect()
 
        
     }
 
     fn add_trace(&mut self, path: &CriticalPath) {
-        println!("**** A trace {:?} added to group{:?}",path.g.base_id, self.hash);
+        trace!(trace_id:% = path.g.base_id, group_hash:% = self.hash; "Trace added to the group");
         self.traces.push(path.clone());
-        let mut cur_node = path.start_node;
-        let mut prev_node = None;
-        let mut cur_dag_nidx = self.start_node;
-        let mut prev_dag_nidx = None;
-        loop {
-            if !prev_dag_nidx.is_none() {
-                match path.g.g.find_edge(prev_node.unwrap(), cur_node) {
-                    Some(edge) => {
-                        let dag_edge = self
-                            .g
-                            .find_edge(prev_dag_nidx.unwrap(), cur_dag_nidx)
-                            .unwrap();
-                        self.g[dag_edge].duration.push(path.g.g[edge].duration);
-                    }
-                    None => panic!("No edge?"),
+        self.trace_seq.push(self.paths_added);
+        self.paths_added += 1;
+        self.stats.add(path.duration);
+        self.split_stats(path.g.is_canary).add(path.duration);
+        let durations = self.edge_durations(path);
+        for (edge, duration) in self.edges_in_order().into_iter().zip(durations) {
+            self.g[edge].push(duration);
+        }
+    }
+
+    fn split_stats(&mut self, is_canary: bool) -> &mut LatencyStats {
+        if is_canary {
+            &mut self.canary_stats
+        } else {
+            &mut self.non_canary_stats
+        }
+    }
+
+    /// The edges of the representative path, in order
+    pub fn edges_in_order(&self) -> Vec<EdgeIndex> {
+        nodes_in_order(self)
+            .windows(2)
+            .map(|w| self.g.find_edge(w[0], w[1]).unwrap())
+            .collect()
+    }
+
+    /// The tracepoints of the representative path, i.e., the first path of the group
+    pub fn sequence(&self) -> Vec<TracepointID> {
+        nodes_in_order(self).into_iter().map(|n| self.at(n)).collect()
+    }
+
+    /// Durations of the path for each edge of the group, in order. A path with a different
+    /// structure (see `clustering`) is aligned to the representative path; nodes of the
+    /// representative that are not in the path get the time of the node before them.
+    fn edge_durations(&self, path: &CriticalPath) -> Vec<Duration> {
+        let path_nodes = nodes_in_order(path);
+        let path_sequence = path_nodes.iter().map(|&n| path.at(n)).collect::<Vec<_>>();
+        let sequence = self.sequence();
+        if path_sequence == sequence {
+            return path_nodes
+                .windows(2)
+                .map(|w| path.g.g[path.g.g.find_edge(w[0], w[1]).unwrap()].duration)
+                .collect();
+        }
+        let start = path.g.g[path.start_node].timestamp;
+        let end = path.g.g[path.end_node].timestamp;
+        let matched = align(&sequence, &path_sequence);
+        let mut times = Vec::with_capacity(matched.len());
+        let mut last = start;
+        for (i, m) in matched.iter().enumerate() {
+            let time = if i == 0 {
+                start
+            } else if i == matched.len() - 1 {
+                end
+            } else {
+                match m {
+                    Some(j) => path.g.g[path_nodes[*j]].timestamp.max(last).min(end),
+                    None => last,
                 }
-            }
-            prev_dag_nidx = Some(cur_dag_nidx);
-            prev_node = Some(cur_node);
-            cur_node = match path.next_node(cur_node) {
-                Some(node) => node,
-                None => break,
             };
-            cur_dag_nidx = self.next_node(cur_dag_nidx).unwrap();
+            times.push(time);
+            last = time;
         }
+        times
+            .windows(2)
+            .map(|w| (w[1] - w[0]).to_std().unwrap_or_default())
+            .collect()
     }
+    /// Removes the path of the request with the given base id, e.g., when a partial path is
+    /// replaced by a longer one. Returns whether the request was in the group.
+    fn remove_trace(&mut self, base_id: Uuid) -> bool {
+        let idx = match self.traces.iter().position(|p| p.g.base_id == base_id) {
+            Some(idx) => idx,
+            None => return false,
+        };
+        let path_durations = self.edge_durations(&self.traces[idx]);
+        let edges = self.edges_in_order();
+        // The latest paths are at the end of the sample windows; older ones may be gone already
+        let seq = self.trace_seq[idx];
+        let from_end = self.paths_added - seq;
+        for (edge, duration) in edges.into_iter().zip(path_durations) {
+            let group_edge = &mut self.g[edge];
+            group_edge.stats.remove(duration);
+            if from_end <= group_edge.duration.len() {
+                let position = group_edge.duration.len() - from_end;
+                group_edge.duration.remove(position);
+            }
+        }
+        let (duration, is_canary) = (self.traces[idx].duration, self.traces[idx].g.is_canary);
+        self.stats.remove(duration);
+        self.split_stats(is_canary).remove(duration);
+        self.traces.remove(idx);
+        self.trace_seq.remove(idx);
+        self.paths_added -= 1;
+        for s in self.trace_seq.iter_mut().filter(|s| **s > seq) {
+            *s -= 1;
+        }
+        true
+    }
+
+    /// Drops the oldest paths beyond `max`; they still count in the statistics. Partial paths
+    /// are kept, since they are removed again when the request finishes.
+    fn trim_traces(&mut self, max: usize) {
+        let mut excess = self.traces.len().saturating_sub(max);
+        if excess == 0 {
+            return;
+        }
+        let traces = std::mem::replace(&mut self.traces, Vec::new());
+        let seqs = std::mem::replace(&mut self.trace_seq, Vec::new());
+        for (path, seq) in traces.into_iter().zip(seqs) {
+            if excess > 0 && !path.g.is_partial {
+                excess -= 1;
+                continue;
+            }
+            self.traces.push(path);
+            self.trace_seq.push(seq);
+        }
+    }
+
     // tsl: calculate mean of the group
     fn calculate_mean(&mut self) {
         // change below variance to mean
-        self.mean = mean(self.traces.iter().map(|x| x.duration.as_nanos()));
-        if self.mean != 0.0 {
-            println!("Set mean of {:?} - {} to {}", self.request_type, self.hash, self.mean);
+        self.mean = self.stats.mean();
+        if !self.mean.is_zero() {
+            trace!(
+                group_hash:% = self.hash;
+                "Set mean of {:?} to {}", self.request_type, self.mean
+            );
         }
     }
     fn calculate_variance(&mut self) {
-        println!(
+        trace!(
             "Duration of each trace: {:?}",
                 self.traces.iter().
                 map(|x| x.duration.as_nanos())
                 .collect::<Vec<_>>()
         );
-        self.variance = variance(self.traces.iter().map(|x| x.duration.as_nanos()));
-        if self.variance != 0.0 {
-            println!("Set variance of {:?} - {} to {}", self.request_type, self.hash, self.variance);
+        self.variance = self.stats.variance();
+        if !self.variance.is_zero() {
+            trace!(
+                group_hash:% = self.hash;
+                "Set variance of {:?} to {}", self.request_type, self.variance
+            );
         }
     }
 }
 
+/// Nodes of a path from start to end
+fn nodes_in_order<P: Path>(path: &P) -> Vec<NodeIndex> {
+    let mut result = vec![path.start_node()];
+    while let Some(next) = path.next_node(*result.last().unwrap()) {
+        result.push(next);
+    }
+    result
+}
+
+/// Statistics of consecutive edges. The means, percentiles and variances cover all paths, while
+/// the shares are computed from the latest paths still in the edges' sample windows.
+fn edge_breakdown(edges: &Vec<&GroupEdge>) -> Vec<EdgeBreakdown> {
+    let paths = edges.iter().map(|e| e.duration.len()).min().unwrap_or(0);
+    // Windows may differ in length if a path was removed after others were dropped; align them
+    // at the latest path
+    let durations: Vec<&[Duration]> = edges
+        .iter()
+        .map(|e| &e.duration[e.duration.len() - paths..])
+        .collect();
+    let totals: Vec<f64> = (0..paths)
+        .map(|j| durations.iter().map(|d| Nanos::from_duration(d[j]).0).sum())
+        .collect();
+    let total_mean = stats::mean(totals.iter().cloned());
+    let total_variance = stats::variance(totals.iter().cloned());
+    edges
+        .iter()
+        .zip(durations)
+        .map(|(e, d)| {
+            let edge_mean = mean(d.iter());
+            let covariance = if paths == 0 {
+                0.0
+            } else {
+                (0..paths)
+                    .map(|j| {
+                        (Nanos::from_duration(d[j]).0 - edge_mean.0) * (totals[j] - total_mean)
+                    })
+                    .sum::<f64>()
+                    / paths as f64
+            };
+            let share = |part: f64, whole: f64| {
+                if whole == 0.0 {
+                    0.0
+                } else {
+                    100.0 * part / whole
+                }
+            };
+            EdgeBreakdown {
+                from: String::new(),
+                to: String::new(),
+                mean: e.stats.mean(),
+                p50: e.stats.percentile(50.0),
+                p95: e.stats.percentile(95.0),
+                p99: e.stats.percentile(99.0),
+                variance: e.stats.variance(),
+                latency_share: share(edge_mean.0, total_mean),
+                variance_share: share(covariance, total_variance),
+            }
+        })
+        .collect()
+}
+
 // # key value = hostname = client | server  ---> Append trace_id 0000> 
 // 1231-123_hostname = "client" , 1233331-123_hostname = "client"
 
@@ -296,37 +677,455 @@ impl Path for Group {
     }
 }
 
+/// Ways of picking the problem groups to diagnose
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProblemSelector {
+    /// Pick one of the others from the latency distribution every decision, see
+    /// `choose_selector`
+    Auto,
+    /// Groups with the highest latency variance
+    Variance,
+    /// Groups whose coefficient of variance is above the threshold
+    CV(f64),
+    /// Groups whose mean latency is above the given percentile of group means
+    Slow(f64),
+    /// Groups flagged by anomaly detection, then the groups with the highest variance
+    Anomaly,
+}
+
+impl ProblemSelector {
+    /// The selector to use on `groups` right now; `Auto` and `Anomaly` are resolved to the
+    /// selector they stand for.
+    pub fn resolve(&self, groups: &GroupManager) -> ProblemSelector {
+        match *self {
+            ProblemSelector::Auto => groups.choose_selector(),
+            ProblemSelector::Anomaly => ProblemSelector::Variance,
+            selector => selector,
+        }
+    }
+}
+
+/// If more than this fraction of the total latency variance is between groups rather than
+/// within them, the groups are consistently slow rather than unstable.
+const CROSS_GROUP_VARIANCE_RATIO: f64 = 0.5;
+/// If the group means are spread more than this (as a coefficient of variance), raw variance
+/// favors the long groups, so compare the groups by their CV.
+const GROUP_MEAN_SPREAD: f64 = 1.0;
+pub const DEFAULT_CV_THRESHOLD: f64 = 0.05;
+pub const DEFAULT_SLOW_PERCENTILE: f64 = 95.0;
+
+/// How much of the latency variance of a request type is explained by its groups
+#[derive(Serialize, Debug, Clone)]
+pub struct VarianceExplained {
+    pub request_type: RequestType,
+    /// Eta-squared: between-group sum of squares over the total sum of squares
+    pub ratio: f64,
+    /// Eta-squared of each group against the other groups of the request type, highest first
+    pub per_leaf: Vec<(String, f64)>,
+}
+
+/// Eta-squared analysis of the groups of each request type, most explained first
+pub fn variance_explained(groups: &[&Group]) -> Vec<VarianceExplained> {
+    let mut by_type: HashMap<RequestType, Vec<&Group>> = HashMap::new();
+    for g in groups.iter().filter(|g| g.trace_count() != 0) {
+        by_type.entry(g.request_type).or_default().push(g);
+    }
+    let mut result = by_type
+        .into_iter()
+        .map(|(request_type, groups)| {
+            let summaries: Vec<GroupSummary> =
+                groups.iter().map(|g| GroupSummary::from_group(g)).collect();
+            let ratio = eta_squared(&summaries.iter().map(|s| vec![s]).collect::<Vec<_>>());
+            let mut per_leaf = groups
+                .iter()
+                .enumerate()
+                .map(|(i, g)| {
+                    let others = (0..summaries.len())
+                        .filter(|&j| j != i)
+                        .map(|j| &summaries[j])
+                        .collect();
+                    let share = eta_squared(&[vec![&summaries[i]], others]);
+                    (g.hash().to_string(), share)
+                })
+                .collect::<Vec<_>>();
+            per_leaf.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+            VarianceExplained {
+                request_type,
+                ratio,
+                per_leaf,
+            }
+        })
+        .collect::<Vec<_>>();
+    result.sort_by(|a, b| b.ratio.partial_cmp(&a.ratio).unwrap());
+    result
+}
+
+/// Sub-groups with fewer paths are not compared to their siblings
+const MIN_PARTITION_TRACES: usize = 4;
+
+/// A sub-group that is slower than the other sub-groups of the same path, e.g., slow only on
+/// one host
+#[derive(Serialize, Debug, Clone)]
+pub struct SlowPartition {
+    pub group: String,
+    pub key: String,
+    pub value: String,
+    pub mean: Nanos,
+    /// Mean latency of the other sub-groups, weighted by their number of paths
+    pub others_mean: Nanos,
+}
+
+impl Display for SlowPartition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}={} is slow in {}: mean {:?}, others {:?}",
+            self.key,
+            self.value,
+            self.group,
+            self.mean.to_duration(),
+            self.others_mean.to_duration()
+        )
+    }
+}
+
+/// The value of `key` on the first node of the path that has it
+fn partition_value(path: &CriticalPath, key: &str) -> Option<String> {
+    nodes_in_order(path)
+        .into_iter()
+        .filter_map(|n| path.g.g[n].key_value_pair.get(key))
+        .next()
+        .map(|v| match v {
+            Value::UnsignedInt(i) => i.to_string(),
+            Value::SignedInt(i) => i.to_string(),
+            Value::Str(s) => s.clone(),
+        })
+}
+
+/// Bounds on the memory used by a `GroupManager`; `None` is unlimited
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GroupLimits {
+    /// Beyond this many groups, the least recently updated ones are evicted
+    pub max_groups: Option<usize>,
+    /// Paths kept per group; older paths only count in the statistics. With 0, groups keep only
+    /// statistics.
+    pub max_traces: Option<usize>,
+    /// Groups that got no new paths for this long are evicted
+    pub idle_expiry: Option<Duration>,
+}
+
+impl GroupLimits {
+    pub fn from_settings(settings: &Settings) -> Self {
+        GroupLimits {
+            max_groups: settings.group_max_count,
+            max_traces: settings.group_max_traces,
+            idle_expiry: settings.group_idle_expiry,
+        }
+    }
+}
+
 /// This manages the grouping etc. and stores a collection of groups
 #[derive(Debug)]
 pub struct GroupManager {
     groups: HashMap<String, Group>,
+    mode: GroupingMode,
+    /// With approximate grouping, the group that paths of each other structure were put in
+    aliases: HashMap<String, String>,
+    limits: GroupLimits,
+    /// When each group last got a path, for eviction
+    last_updated: HashMap<String, Instant>,
+    clock: Arc<dyn Clock>,
+    /// Whether paths of the same request type but with different salient parameters go to
+    /// different groups
+    by_request_params: bool,
+    /// Key of `Event::key_value_pair` that splits groups into sub-groups
+    partition_key: Option<String>,
+    /// Splits groups into the paths that match and those that don't, when there is no
+    /// `partition_key`
+    partition_filter: Option<Filter>,
+    /// Groups of each request whose latest paths are partial, so they can be replaced. A request
+    /// has more than one path when several paths are taken per trace.
+    partial_paths: HashMap<Uuid, Vec<String>>,
+    /// Latency objectives of the request types, see `slo`
+    slos: Vec<Slo>,
 }
 
 impl GroupManager {
     pub fn new() -> Self {
         GroupManager {
             groups: HashMap::new(),
+            mode: GroupingMode::Exact,
+            aliases: HashMap::new(),
+            limits: GroupLimits::default(),
+            last_updated: HashMap::new(),
+            clock: Arc::new(SystemClock),
+            by_request_params: false,
+            partition_key: None,
+            partition_filter: None,
+            partial_paths: HashMap::new(),
+            slos: Vec::new(),
         }
     }
 
-    /// Add new paths to the appropriate groups
+    pub fn set_grouping_mode(&mut self, mode: GroupingMode) {
+        self.mode = mode;
+    }
+
+    pub fn set_limits(&mut self, limits: GroupLimits) {
+        self.limits = limits;
+    }
+
+    /// Use a different clock for deciding when groups are idle
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Key groups on the parameterized request type in addition to the path
+    pub fn group_by_request_params(&mut self, enabled: bool) {
+        self.by_request_params = enabled;
+    }
+
+    /// Split groups by the value of an event key (e.g., host, project), so sub-groups can be
+    /// compared with `slow_partitions`
+    pub fn partition_by(&mut self, key: Option<String>) {
+        self.partition_key = key;
+    }
+
+    /// Split groups by whether their paths match a filter expression, e.g., `host=cp-1 AND
+    /// request.method=POST`; the partition value is `true` or `false`
+    pub fn partition_by_filter(&mut self, filter: Option<Filter>) {
+        self.partition_filter = filter;
+    }
+
+    pub fn set_slos(&mut self, slos: Vec<Slo>) {
+        self.slos = slos;
+    }
+
+    /// How the group does against the objective of its request type, if it has one
+    pub fn slo_compliance(&self, group: &Group) -> Option<SloCompliance> {
+        self.slos
+            .iter()
+            .find(|s| s.request_type == group.request_type)
+            .map(|s| s.check(group))
+    }
+
+    /// Groups with enough traces that violate their objective, the furthest over first
+    pub fn slo_violations(&self) -> Vec<SloCompliance> {
+        let mut result: Vec<SloCompliance> = self
+            .groups
+            .values()
+            .filter(|g| g.trace_count() >= SLO_MIN_TRACES)
+            .filter_map(|g| self.slo_compliance(g))
+            .filter(|c| c.violated)
+            .collect();
+        result.sort_by(|a, b| b.ratio().partial_cmp(&a.ratio()).unwrap());
+        result
+    }
+
+    /// Groups that violate their objective first, then the rest of `problem_groups` in their
+    /// order
+    pub fn prioritize_slo_violations<'a>(
+        &'a self,
+        problem_groups: Vec<&'a Group>,
+    ) -> Vec<&'a Group> {
+        let violations = self.slo_violations();
+        let mut result: Vec<&Group> = violations
+            .iter()
+            .filter_map(|v| self.group(&v.group))
+            .collect();
+        result.extend(
+            problem_groups
+                .into_iter()
+                .filter(|g| violations.iter().all(|v| v.group != g.hash())),
+        );
+        result
+    }
+
+    /// The group a path belongs to
+    fn group_key(&self, path: &CriticalPath) -> String {
+        let key = if self.by_request_params && !path.g.request_params.is_empty() {
+            format!("{}/{}", path.hash(), path.g.parameterized_type())
+        } else {
+            path.hash().to_string()
+        };
+        match self.partition(path) {
+            Some((k, v)) => format!("{}#{}={}", key, k, v),
+            None => key,
+        }
+    }
+
+    fn partition(&self, path: &CriticalPath) -> Option<(String, String)> {
+        match (&self.partition_key, &self.partition_filter) {
+            (Some(key), _) => partition_value(path, key).map(|v| (key.clone(), v)),
+            (None, Some(filter)) => Some((
+                filter.to_string(),
+                filter.matches_trace(&path.g).to_string(),
+            )),
+            (None, None) => None,
+        }
+    }
+
+    /// The group a path goes to. With approximate grouping, a path without a group of its own
+    /// joins the most similar group of the same request type, if it is similar enough.
+    fn cluster_key(&mut self, path: &CriticalPath) -> String {
+        let key = self.group_key(path);
+        if self.mode == GroupingMode::Exact || self.groups.contains_key(&key) {
+            return key;
+        }
+        if let Some(alias) = self.aliases.get(&key) {
+            return alias.clone();
+        }
+        let sequence = nodes_in_order(path)
+            .into_iter()
+            .map(|n| path.at(n))
+            .collect::<Vec<_>>();
+        let by_request_params = self.by_request_params;
+        let partition = self.partition(path);
+        let mode = self.mode;
+        let best = self
+            .groups
+            .iter()
+            .filter(|(_, g)| g.request_type == path.request_type)
+            .filter(|(_, g)| !by_request_params || g.request_params == path.g.request_params)
+            .filter(|(_, g)| g.partition == partition)
+            .map(|(k, g)| (k, g.sequence()))
+            .filter(|(_, s)| mode.similar(s, &sequence))
+            .map(|(k, s)| (k, mode.similarity(&s, &sequence)))
+            .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap().then_with(|| b.0.cmp(a.0)));
+        match best {
+            Some((cluster, _)) => {
+                let cluster = cluster.clone();
+                self.aliases.insert(key, cluster.clone());
+                cluster
+            }
+            None => key,
+        }
+    }
+
+    /// Add new paths to the appropriate groups.
+    ///
+    /// If earlier paths of the same request were partial, they are replaced by the new paths.
     pub fn update(&mut self, paths: &Vec<CriticalPath>) {
         let mut updated_groups = Vec::new();
         for path in paths {
-            match self.groups.get_mut(path.hash()) {
+            for old_key in self
+                .partial_paths
+                .remove(&path.g.base_id)
+                .unwrap_or_default()
+            {
+                if let Some(old_group) = self.groups.get_mut(&old_key) {
+                    if old_group.remove_trace(path.g.base_id) {
+                        updated_groups.push(old_key);
+                    }
+                }
+            }
+        }
+        for path in paths.iter().filter(|p| !p.g.is_transition && is_linear(p)) {
+            let key = self.cluster_key(path);
+            if path.g.is_partial {
+                self.partial_paths
+                    .entry(path.g.base_id)
+                    .or_default()
+                    .push(key.clone());
+            }
+            match self.groups.get_mut(&key) {
                 Some(v) => v.add_trace(&path),
                 None => {
-                    println!("**** A trace {:?} created a group{:?}",path.g.base_id, path.hash().to_string());
-                    self.groups
-                        .insert(path.hash().to_string(), Group::new(path.clone()));
+                    debug!(
+                        trace_id:% = path.g.base_id, group_hash:% = key;
+                        "Trace created a group"
+                    );
+                    let mut group = Group::new(path.clone());
+                    if self.by_request_params {
+                        group.hash = key.clone();
+                        group.request_params = path.g.request_params.clone();
+                    }
+                    if let Some(partition) = self.partition(path) {
+                        group.hash = key.clone();
+                        group.partition = Some(partition);
+                    }
+                    self.groups.insert(key.clone(), group);
                 }
             }
-            updated_groups.push(path.hash().clone());
+            updated_groups.push(key);
         }
+        let now = self.clock.now();
         for h in updated_groups {
-            self.groups.get_mut(h).unwrap().calculate_variance();
-            self.groups.get_mut(h).unwrap().calculate_mean();
+            let group = self.groups.get_mut(&h).unwrap();
+            if let Some(max) = self.limits.max_traces {
+                group.trim_traces(max);
+            }
+            group.calculate_variance();
+            group.calculate_mean();
+            self.last_updated.insert(h, now);
         }
+        self.evict();
+    }
+
+    /// Removes idle groups, then the least recently updated groups beyond the limit
+    fn evict(&mut self) {
+        let mut evicted = Vec::new();
+        if let Some(expiry) = self.limits.idle_expiry {
+            for (key, updated) in &self.last_updated {
+                if self.clock.elapsed(*updated) > expiry {
+                    evicted.push(key.clone());
+                }
+            }
+        }
+        if let Some(max) = self.limits.max_groups {
+            let mut by_age = self
+                .last_updated
+                .iter()
+                .filter(|(key, _)| !evicted.contains(key))
+                .collect::<Vec<_>>();
+            if by_age.len() > max {
+                by_age.sort_by_key(|(_, updated)| **updated);
+                let excess = by_age.len() - max;
+                evicted.extend(by_age.into_iter().take(excess).map(|(k, _)| k.clone()));
+            }
+        }
+        if evicted.is_empty() {
+            return;
+        }
+        for key in &evicted {
+            self.groups.remove(key);
+            self.last_updated.remove(key);
+        }
+        for keys in self.partial_paths.values_mut() {
+            keys.retain(|key| !evicted.contains(key));
+        }
+        self.partial_paths.retain(|_, keys| !keys.is_empty());
+        self.aliases.retain(|_, key| !evicted.contains(key));
+        info!("Evicted {} groups, {} left", evicted.len(), self.groups.len());
+    }
+
+    pub fn group(&self, hash: &str) -> Option<&Group> {
+        self.groups.get(hash)
+    }
+
+    /// Tracepoints of every group and of the paths kept in them
+    pub fn tracepoints(&self) -> HashSet<TracepointID> {
+        let mut result = HashSet::new();
+        for group in self.groups.values() {
+            result.extend(group.g.node_indices().map(|n| group.g[n].tracepoint_id));
+            for path in &group.traces {
+                result.extend(path.g.g.node_indices().map(|n| path.g.g[n].tracepoint_id));
+            }
+        }
+        result
+    }
+
+    /// Every group, including the ones without traces since they were last used
+    pub fn all_groups(&self) -> Vec<&Group> {
+        self.groups.values().collect()
+    }
+
+    /// Groups that have traces since they were last used
+    pub fn active_groups(&self) -> Vec<&Group> {
+        self.groups
+            .values()
+            .filter(|&g| g.trace_count() != 0)
+            .collect()
     }
 
     /// Return groups filtered based on occurance and sorted by variance
@@ -334,8 +1133,8 @@ impl GroupManager {
         let mut sorted_groups: Vec<&Group> = self
             .groups
             .values()
-            .filter(|&g| g.variance != 0.0)
-            .filter(|&g| g.traces.len() > 3)
+            .filter(|&g| !g.variance.is_zero())
+            .filter(|&g| g.trace_count() > 3)
             .collect();
         sorted_groups.sort_by(|a, b| b.variance.partial_cmp(&a.variance).unwrap());
         sorted_groups
@@ -347,9 +1146,9 @@ impl GroupManager {
             .groups
             .values()
             .filter(|&g| g.is_used != true) // TODO: what happens to used groups?
-            .filter(|&g| g.variance != 0.0)
-            .filter(|&g| (g.variance.sqrt()/g.mean) > cv_threshold) // tsl: g.CV > Threshold
-            .filter(|&g| g.traces.len() > 3)
+            .filter(|&g| !g.variance.is_zero())
+            .filter(|&g| cv(g.mean, g.variance) > cv_threshold) // tsl: g.CV > Threshold
+            .filter(|&g| g.trace_count() > 3)
             .collect();
         sorted_groups.sort_by(|a, b| b.variance.partial_cmp(&a.variance).unwrap());
         // println!("\n**Groups sorted in CV Analaysis: {}", sorted_groups);
@@ -365,21 +1164,29 @@ impl GroupManager {
             .values()
             .collect();
 
-        println!("Populate histogram");
         for val in groups_vec.iter() {
            // print!("{:?},  ",(val.mean.round() as f64) / (1000000000 as f64) );
             //histogram.increment((( val.mean.round() as f64) / (1000000000 as f64)) as u64);
-            histogram.increment(val.mean.round() as u64);
+            histogram.increment(val.mean.0.round() as u64);
+        }
+        if histogram.entries() == 0 {
+            return Vec::new();
         }
         // get P percentile mean
-        let mean_threshold  = histogram.percentile(percentile).unwrap();
-        println!("**Get value {} for given P: {:?}", mean_threshold, percentile);
+        let mean_threshold = match histogram.percentile(percentile) {
+            Ok(m) => m,
+            Err(e) => {
+                warn!("No group mean at percentile {}: {}", percentile, e);
+                return Vec::new();
+            }
+        };
+        debug!("Mean at percentile {:?} is {}", percentile, mean_threshold);
 
         let mut sorted_groups: Vec<&Group> = self
             .groups
             .values()
-            .filter(|&g| g.mean > mean_threshold as f64)
-            .filter(|&g| g.traces.len() > 3)
+            .filter(|&g| g.mean > Nanos(mean_threshold as f64))
+            .filter(|&g| g.trace_count() > 3)
             .collect();
         sorted_groups.sort_by(|a, b| b.mean.partial_cmp(&a.mean).unwrap());
         sorted_groups
@@ -387,6 +1194,117 @@ impl GroupManager {
     }
 
 
+    /// Return problem groups using the given selector
+    pub fn problem_groups_by(&self, selector: ProblemSelector) -> Vec<&Group> {
+        match selector {
+            ProblemSelector::Variance => self.problem_groups(),
+            ProblemSelector::CV(threshold) => self.problem_groups_cv(threshold),
+            ProblemSelector::Slow(percentile) => self.problem_groups_slow(percentile),
+            ProblemSelector::Auto | ProblemSelector::Anomaly => {
+                self.problem_groups_by(selector.resolve(self))
+            }
+        }
+    }
+
+    /// Pick a problem selector by looking at how latency is distributed across groups.
+    ///
+    /// The total latency variance is split into the part within groups and the part between
+    /// group means. If most of it is between groups, the slow-group selector is used. Otherwise,
+    /// if group means are at very different scales the CV selector is used, else raw variance.
+    pub fn choose_selector(&self) -> ProblemSelector {
+        let groups: Vec<&Group> = self
+            .groups
+            .values()
+            .filter(|&g| g.trace_count() != 0)
+            .collect();
+        if groups.len() < 2 {
+            return ProblemSelector::CV(DEFAULT_CV_THRESHOLD);
+        }
+        let total_count: usize = groups.iter().map(|g| g.trace_count()).sum();
+        let grand_mean = groups
+            .iter()
+            .map(|g| g.mean.0 * g.trace_count() as f64)
+            .sum::<f64>()
+            / total_count as f64;
+        let within: f64 = groups
+            .iter()
+            .map(|g| g.variance.0 * g.trace_count() as f64)
+            .sum();
+        let between: f64 = groups
+            .iter()
+            .map(|g| (g.mean.0 - grand_mean).powi(2) * g.trace_count() as f64)
+            .sum();
+        if within + between == 0.0 {
+            return ProblemSelector::CV(DEFAULT_CV_THRESHOLD);
+        }
+        let ratio = between / (within + between);
+        let means: Vec<Duration> = groups.iter().map(|g| g.mean.to_duration()).collect();
+        let mean_spread = cv(mean(means.iter()), variance(means.iter()));
+        info!(
+            "Cross-group variance ratio {:.3}, group mean spread {:.3}",
+            ratio, mean_spread
+        );
+        if ratio > CROSS_GROUP_VARIANCE_RATIO {
+            ProblemSelector::Slow(DEFAULT_SLOW_PERCENTILE)
+        } else if mean_spread > GROUP_MEAN_SPREAD {
+            ProblemSelector::CV(DEFAULT_CV_THRESHOLD)
+        } else {
+            ProblemSelector::Variance
+        }
+    }
+
+    pub fn variance_explained(&self) -> Vec<VarianceExplained> {
+        variance_explained(&self.groups.values().collect::<Vec<_>>())
+    }
+
+    /// Sub-groups whose mean latency is more than `ratio` times that of the other sub-groups of
+    /// the same path, slowest first
+    pub fn slow_partitions(&self, ratio: f64) -> Vec<SlowPartition> {
+        let mut siblings: HashMap<&str, Vec<&Group>> = HashMap::new();
+        for g in self.groups.values() {
+            if let Some((key, value)) = &g.partition {
+                if g.trace_count() >= MIN_PARTITION_TRACES {
+                    let suffix = format!("#{}={}", key, value);
+                    siblings
+                        .entry(g.hash.trim_end_matches(&suffix))
+                        .or_default()
+                        .push(g);
+                }
+            }
+        }
+        let mut result = Vec::new();
+        for (base, groups) in siblings {
+            for g in &groups {
+                let (count, total) = groups
+                    .iter()
+                    .filter(|o| o.hash != g.hash)
+                    .fold((0, 0.0), |(c, t), o| {
+                        (c + o.stats.count(), t + o.stats.mean().0 * o.stats.count() as f64)
+                    });
+                if count == 0 {
+                    continue;
+                }
+                let others_mean = Nanos(total / count as f64);
+                if g.stats.mean().0 > ratio * others_mean.0 {
+                    let (key, value) = g.partition.clone().unwrap();
+                    result.push(SlowPartition {
+                        group: base.to_string(),
+                        key,
+                        value,
+                        mean: g.stats.mean(),
+                        others_mean,
+                    });
+                }
+            }
+        }
+        result.sort_by(|a, b| {
+            (b.mean.0 / b.others_mean.0)
+                .partial_cmp(&(a.mean.0 / a.others_mean.0))
+                .unwrap()
+        });
+        result
+    }
+
     /// Mark a group as "used": reset its performance data
     pub fn used(&mut self, group: &str) {
         self.groups.get_mut(group).unwrap().used();
@@ -397,12 +1315,16 @@ impl Display for Group {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "Group<{} {:?} traces, mean: {:?}, var: {:?}, cv:{:?}, hash: {:?}>",
-            self.traces.len(),
-            self.request_type,
-            self.mean/1000000.0,
-            self.variance,
-            self.variance.sqrt()/self.mean,
+            "Group<{} {}{} traces, mean: {:?}, var: {:?}, cv:{:?}, hash: {:?}>",
+            self.trace_count(),
+            self.parameterized_type(),
+            match &self.partition {
+                Some((k, v)) => format!(" {}={}", k, v),
+                None => String::new(),
+            },
+            self.mean.as_millis(),
+            self.variance.0,
+            cv(self.mean, self.variance),
             self.hash
         )
     }
@@ -413,7 +1335,7 @@ impl Display for GroupManager {
         let mut groups: Vec<&Group> = self
             .groups
             .values()
-            .filter(|&g| g.traces.len() != 0)
+            .filter(|&g| g.trace_count() != 0)
             .collect();
         groups.sort_by(|a, b| b.variance.partial_cmp(&a.variance).unwrap());
         for g in &groups {
@@ -422,3 +1344,319 @@ impl Display for GroupManager {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use proptest::prelude::*;
+
+    use crate::testutils::TraceGenerator;
+
+    #[test]
+    fn breakdown_shares_add_up() {
+        let edge = |v: &[u64]| {
+            let mut e = GroupEdge::new(Duration::from_millis(v[0]));
+            for &x in &v[1..] {
+                e.push(Duration::from_millis(x));
+            }
+            e
+        };
+        // The first edge is constant, the second one carries all of the variance
+        let (constant, varying) = (edge(&[10, 10, 10]), edge(&[10, 20, 30]));
+        let breakdown = edge_breakdown(&vec![&constant, &varying]);
+        assert!((breakdown[0].p50.0 / 10_000_000.0 - 1.0).abs() < 0.01);
+        assert!((breakdown[0].latency_share - 100.0 / 3.0).abs() < 1e-9);
+        assert_eq!(breakdown[0].variance_share, 0.0);
+        assert!((breakdown[1].variance_share - 100.0).abs() < 1e-9);
+    }
+
+    fn path(name: &str, millis: u64) -> CriticalPath {
+        chain(&[name, name], &[millis])
+    }
+
+    /// A path through the given tracepoints, with the given durations between them
+    fn chain(names: &[&str], millis: &[u64]) -> CriticalPath {
+        use crate::trace::{DAGEdge, EdgeType, Event, EventType, Trace};
+        let mut trace = Trace::new(&Uuid::new_v4());
+        let span = Uuid::new_v4();
+        let start = chrono::NaiveDateTime::from_timestamp(0, 0);
+        let mut elapsed = 0;
+        let mut nodes = Vec::new();
+        for (i, name) in names.iter().enumerate() {
+            if i > 0 {
+                elapsed += millis[i - 1];
+            }
+            let variant = if i == 0 {
+                EventType::Entry
+            } else if i == names.len() - 1 {
+                EventType::Exit
+            } else {
+                EventType::Annotation
+            };
+            nodes.push(trace.g.add_node(Event {
+                trace_id: span,
+                tracepoint_id: TracepointID::from_str(name),
+                timestamp: start + chrono::Duration::milliseconds(elapsed as i64),
+                is_synthetic: false,
+                variant,
+                key_value_pair: HashMap::new(),
+            }));
+        }
+        for (w, &ms) in nodes.windows(2).zip(millis) {
+            let edge = DAGEdge {
+                duration: Duration::from_millis(ms),
+                variant: EdgeType::ChildOf,
+            };
+            trace.g.add_edge(w[0], w[1], edge);
+        }
+        trace.start_node = nodes[0];
+        trace.end_node = *nodes.last().unwrap();
+        trace.duration = Duration::from_millis(elapsed);
+        CriticalPath::from_trace(&trace).unwrap()
+    }
+
+    #[test]
+    fn evicts_and_trims() {
+        let clock = Arc::new(crate::clock::SimulatedClock::new());
+        let mut manager = GroupManager::new();
+        manager.set_clock(clock.clone());
+        manager.set_limits(GroupLimits {
+            max_groups: Some(2),
+            max_traces: Some(1),
+            idle_expiry: Some(Duration::from_secs(60)),
+        });
+        manager.update(&vec![path("a", 10), path("a", 20), path("a", 60)]);
+        let group = manager.active_groups()[0];
+        assert_eq!(group.traces.len(), 1);
+        assert_eq!(group.trace_count(), 3);
+        assert_eq!(group.mean, Nanos(30_000_000.0));
+
+        clock.advance(Duration::from_secs(30));
+        manager.update(&vec![path("b", 10)]);
+        clock.advance(Duration::from_secs(1));
+        manager.update(&vec![path("c", 10)]);
+        // a is the least recently updated
+        assert_eq!(manager.groups.len(), 2);
+        let a = TracepointID::from_str("a");
+        assert!(manager.groups.values().all(|g| g.at(g.start_node) != a));
+
+        clock.advance(Duration::from_secs(45));
+        manager.update(&vec![path("d", 10)]);
+        // b is the least recently updated; c is still within the idle expiry
+        assert_eq!(manager.groups.len(), 2);
+        let b = TracepointID::from_str("b");
+        assert!(manager.groups.values().all(|g| g.at(g.start_node) != b));
+    }
+
+    #[test]
+    fn expires_idle_groups() {
+        let clock = Arc::new(crate::clock::SimulatedClock::new());
+        let mut manager = GroupManager::new();
+        manager.set_clock(clock.clone());
+        manager.set_limits(GroupLimits {
+            max_groups: None,
+            max_traces: None,
+            idle_expiry: Some(Duration::from_secs(60)),
+        });
+        manager.update(&vec![path("a", 10), path("b", 10)]);
+        clock.advance(Duration::from_secs(40));
+        manager.update(&vec![path("b", 20)]);
+        clock.advance(Duration::from_secs(30));
+        manager.update(&vec![path("c", 10)]);
+        // a got nothing for 70s, b for 30s
+        let starts: HashSet<String> = manager
+            .groups
+            .values()
+            .map(|g| g.at(g.start_node).to_string())
+            .collect();
+        assert_eq!(starts, ["b", "c"].iter().map(|s| s.to_string()).collect());
+    }
+
+    #[test]
+    fn leaves_out_branching_paths() {
+        let mut branching = chain(&["s", "x", "e"], &[1, 2]);
+        let start = branching.start_node;
+        let extra = branching.g.g.add_node(branching.g.g[start].clone());
+        let edge = crate::trace::DAGEdge {
+            duration: Duration::from_millis(1),
+            variant: crate::trace::EdgeType::ChildOf,
+        };
+        branching.g.g.add_edge(start, extra, edge);
+        let error = branching.check_linear().unwrap_err().to_string();
+        assert!(error.contains("branches at"), "{}", error);
+
+        let mut manager = GroupManager::new();
+        manager.update(&vec![branching.clone(), path("a", 10)]);
+        assert_eq!(manager.groups.len(), 1);
+        assert!(Group::from_critical_paths(vec![branching]).is_empty());
+    }
+
+    #[test]
+    fn clusters_similar_paths() {
+        let mut manager = GroupManager::new();
+        manager.set_grouping_mode(GroupingMode::Lcs(0.8));
+        manager.update(&vec![chain(&["s", "x", "y", "e"], &[1, 2, 3])]);
+        // An extra tracepoint joins the group, and its time counts toward the edge it splits
+        manager.update(&vec![chain(&["s", "x", "z", "y", "e"], &[1, 1, 1, 3])]);
+        // A missing tracepoint leaves the edge before it empty
+        manager.update(&vec![chain(&["s", "y", "e"], &[3, 3])]);
+        manager.update(&vec![chain(&["s", "q", "e"], &[3, 3])]);
+        assert_eq!(manager.groups.len(), 2);
+        let group = manager
+            .groups
+            .values()
+            .find(|g| g.trace_count() == 3)
+            .unwrap();
+        assert_eq!(group.sequence().len(), 4);
+        let edges = group.edges_in_order();
+        let ms = |e: EdgeIndex| group.g[e].duration.clone();
+        assert_eq!(
+            ms(edges[0]),
+            vec![1, 1, 0]
+                .into_iter()
+                .map(Duration::from_millis)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(ms(edges[1])[1], Duration::from_millis(2));
+        assert_eq!(ms(edges[1])[2], Duration::from_millis(3));
+    }
+
+    #[test]
+    fn flags_slow_partition() {
+        let on_host = |host: &str, millis| {
+            let mut path = path("a", millis);
+            let start = path.start_node;
+            path.g.g[start]
+                .key_value_pair
+                .insert("host".to_string(), Value::Str(host.to_string()));
+            path
+        };
+        let mut manager = GroupManager::new();
+        manager.partition_by(Some("host".to_string()));
+        for _ in 0..4 {
+            manager.update(&vec![on_host("h1", 10), on_host("h2", 12), on_host("h3", 40)]);
+        }
+        assert_eq!(manager.groups.len(), 3);
+        let slow = manager.slow_partitions(1.5);
+        assert_eq!(slow.len(), 1);
+        assert_eq!(slow[0].value, "h3");
+        assert_eq!(slow[0].others_mean, Nanos(11_000_000.0));
+        assert_eq!(slow[0].group, path("a", 10).hash());
+    }
+
+    #[test]
+    fn variance_between_groups() {
+        let mut manager = GroupManager::new();
+        manager.update(&vec![path("a", 10), path("a", 10), path("b", 30), path("b", 30)]);
+        let result = manager.variance_explained();
+        assert_eq!(result.len(), 1);
+        assert!((result[0].ratio - 1.0).abs() < 1e-9);
+        assert_eq!(result[0].per_leaf.len(), 2);
+    }
+
+    #[test]
+    fn chooses_selector_from_latency_distribution() {
+        let mut manager = GroupManager::new();
+        manager.update(&vec![path("a", 10), path("a", 10)]);
+        assert_eq!(
+            manager.choose_selector(),
+            ProblemSelector::CV(DEFAULT_CV_THRESHOLD)
+        );
+
+        // All of the variance is between the groups
+        manager.update(&vec![path("b", 30), path("b", 30)]);
+        assert_eq!(
+            manager.choose_selector(),
+            ProblemSelector::Slow(DEFAULT_SLOW_PERCENTILE)
+        );
+
+        // Most of it is within groups whose means are close
+        let mut manager = GroupManager::new();
+        manager.update(&vec![path("a", 5), path("a", 15)]);
+        manager.update(&vec![path("b", 6), path("b", 16)]);
+        assert_eq!(manager.choose_selector(), ProblemSelector::Variance);
+        assert_eq!(
+            ProblemSelector::Auto.resolve(&manager),
+            ProblemSelector::Variance
+        );
+
+        // ... or within groups whose means are far apart
+        let mut manager = GroupManager::new();
+        for name in &["a", "b", "c"] {
+            manager.update(&vec![path(name, 1), path(name, 1)]);
+        }
+        manager.update(&vec![path("d", 1), path("d", 199)]);
+        assert_eq!(
+            manager.choose_selector(),
+            ProblemSelector::CV(DEFAULT_CV_THRESHOLD)
+        );
+    }
+
+    #[test]
+    fn no_slow_groups_without_data() {
+        let mut manager = GroupManager::new();
+        assert!(manager.problem_groups_slow(95.0).is_empty());
+        manager.update(&vec![path("a", 10); 4]);
+        assert!(manager.problem_groups_slow(150.0).is_empty());
+    }
+
+    #[test]
+    fn prioritizes_slo_violations() {
+        let mut manager = GroupManager::new();
+        manager.set_slos(vec![Slo {
+            request_type: RequestType::Unknown,
+            percentile: 95.0,
+            target: Duration::from_millis(20),
+        }]);
+        // a varies but meets the objective, b is constant but too slow
+        manager.update(&vec![path("a", 5), path("a", 15), path("a", 5), path("a", 15)]);
+        manager.update(&vec![path("b", 30); 4]);
+        let problems = manager.problem_groups();
+        assert_eq!(problems.len(), 1);
+        let prioritized = manager.prioritize_slo_violations(problems);
+        assert_eq!(prioritized.len(), 2);
+        assert_eq!(prioritized[0].hash(), path("b", 30).hash());
+        assert!(!manager.slo_compliance(prioritized[1]).unwrap().violated);
+    }
+
+    #[test]
+    fn splits_canary_latencies() {
+        let mut manager = GroupManager::new();
+        let mut canary = path("a", 12);
+        canary.g.is_canary = true;
+        manager.update(&vec![path("a", 10), canary, path("a", 10)]);
+        let group = manager.group(path("a", 10).hash()).unwrap();
+        assert_eq!(group.trace_count(), 3);
+        assert_eq!(group.canary_stats.count(), 1);
+        assert_eq!(group.non_canary_stats.count(), 2);
+        assert!((group.canary_stats.mean().as_millis() - 12.0).abs() < 0.1);
+        let hash = group.hash().to_string();
+        manager.used(&hash);
+        assert_eq!(manager.group(&hash).unwrap().canary_stats.count(), 0);
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+        #[test]
+        fn groups_generated_traces_by_critical_path(seed in any::<u64>()) {
+            let mut generator = TraceGenerator::new(seed);
+            generator.tracepoints = 4;
+            generator.max_depth = 2;
+            generator.concurrency = 0.5;
+            let shapes: Vec<_> = (0..3).map(|_| generator.generate_shape()).collect();
+            let mut expected = HashMap::<Vec<TracepointID>, usize>::new();
+            let mut paths = Vec::new();
+            for shape in shapes.iter().cycle().take(12) {
+                let generated = generator.instantiate(shape);
+                *expected.entry(generated.sequence()).or_insert(0) += 1;
+                paths.push(CriticalPath::from_trace(&generated.trace).unwrap());
+            }
+            let groups = Group::from_critical_paths(paths);
+            prop_assert_eq!(groups.len(), expected.len());
+            for group in &groups {
+                prop_assert_eq!(Some(&group.trace_count()), expected.get(&group.sequence()));
+            }
+        }
+    }
+}
@@ -0,0 +1,299 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Hypotheses about where latency variance comes from, as a binary tree over groups.
+//!
+//! Each split asks "do the groups whose paths go through these tracepoints behave differently
+//! from the others?". Groups are kept at the leaves, and eta-squared (between-leaf sum of squares
+//! over the total sum of squares) tells how much of the latency variance the splits explain.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::critical::Path as _;
+use crate::grouping::Group;
+use crate::trace::TracepointID;
+
+/// What the tree needs to know about a group
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GroupSummary {
+    pub count: u64,
+    /// Nanoseconds
+    pub mean: f64,
+    /// Population variance, nanoseconds squared
+    pub variance: f64,
+    /// Tracepoints on the group's path
+    pub tracepoints: Vec<String>,
+}
+
+impl GroupSummary {
+    pub fn from_group(group: &Group) -> Self {
+        GroupSummary {
+            count: group.stats.count(),
+            mean: group.stats.mean().0,
+            variance: group.stats.variance().0,
+            tracepoints: group.sequence().iter().map(|t| t.to_string()).collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct HypothesisNode {
+    /// Tracepoints this node is split on; empty for leaves
+    pub split: Vec<String>,
+    /// Children for groups with and without any of the split tracepoints
+    pub children: Option<(usize, usize)>,
+    /// Hashes of the groups in this leaf
+    pub groups: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HypothesisTree {
+    nodes: Vec<HypothesisNode>,
+    groups: BTreeMap<String, GroupSummary>,
+}
+
+impl HypothesisTree {
+    pub fn new() -> Self {
+        HypothesisTree {
+            nodes: vec![HypothesisNode::default()],
+            groups: BTreeMap::new(),
+        }
+    }
+
+    pub fn root(&self) -> usize {
+        0
+    }
+
+    pub fn node(&self, idx: usize) -> &HypothesisNode {
+        &self.nodes[idx]
+    }
+
+    pub fn leaves(&self) -> Vec<usize> {
+        (0..self.nodes.len())
+            .filter(|&i| self.nodes[i].children.is_none())
+            .collect()
+    }
+
+    /// Add a group, or update its statistics if it is already in the tree
+    pub fn add_group(&mut self, group: &Group) {
+        self.add_summary(group.hash().to_string(), GroupSummary::from_group(group));
+    }
+
+    pub fn add_summary(&mut self, hash: String, summary: GroupSummary) {
+        if self.groups.insert(hash.clone(), summary).is_some() {
+            return;
+        }
+        let leaf = self.leaf_of(&self.groups[&hash]);
+        self.nodes[leaf].groups.push(hash);
+    }
+
+    /// The leaf a group with this summary belongs to
+    fn leaf_of(&self, summary: &GroupSummary) -> usize {
+        let mut cur = self.root();
+        while let Some((with, without)) = self.nodes[cur].children {
+            cur = if matches(summary, &self.nodes[cur].split) {
+                with
+            } else {
+                without
+            };
+        }
+        cur
+    }
+
+    /// Split a leaf into the groups that go through any of `tracepoints` and the rest. Returns
+    /// the two new leaves, or None if `leaf` is not a leaf.
+    pub fn split_on_tracepoints(
+        &mut self,
+        leaf: usize,
+        tracepoints: &[TracepointID],
+    ) -> Option<(usize, usize)> {
+        if leaf >= self.nodes.len() || self.nodes[leaf].children.is_some() {
+            return None;
+        }
+        let split: Vec<String> = tracepoints.iter().map(|t| t.to_string()).collect();
+        let groups = &self.groups;
+        let (with, without): (Vec<String>, Vec<String>) = self.nodes[leaf]
+            .groups
+            .drain(..)
+            .partition(|h| matches(&groups[h], &split));
+        let children = (self.nodes.len(), self.nodes.len() + 1);
+        self.nodes.push(HypothesisNode {
+            groups: with,
+            ..Default::default()
+        });
+        self.nodes.push(HypothesisNode {
+            groups: without,
+            ..Default::default()
+        });
+        self.nodes[leaf].split = split;
+        self.nodes[leaf].children = Some(children);
+        Some(children)
+    }
+
+    /// Split each leaf on each of `tracepoints` in turn, wherever the tracepoint separates the
+    /// groups of the leaf
+    pub fn split_on_each(&mut self, tracepoints: &[TracepointID]) {
+        for &tp in tracepoints {
+            let split = [tp.to_string()];
+            for leaf in self.leaves() {
+                let groups = &self.nodes[leaf].groups;
+                let with = groups
+                    .iter()
+                    .filter(|h| matches(&self.groups[*h], &split))
+                    .count();
+                if with > 0 && with < groups.len() {
+                    self.split_on_tracepoints(leaf, &[tp]);
+                }
+            }
+        }
+    }
+
+    /// Number of traces and mean latency of the groups under a node
+    pub fn node_stats(&self, idx: usize) -> (u64, f64) {
+        let summaries = self.summaries_under(idx);
+        let count: u64 = summaries.iter().map(|s| s.count).sum();
+        if count == 0 {
+            return (0, 0.0);
+        }
+        let total: f64 = summaries.iter().map(|s| s.mean * s.count as f64).sum();
+        (count, total / count as f64)
+    }
+
+    fn summaries_under(&self, idx: usize) -> Vec<&GroupSummary> {
+        let node = &self.nodes[idx];
+        match node.children {
+            Some((a, b)) => {
+                let mut result = self.summaries_under(a);
+                result.extend(self.summaries_under(b));
+                result
+            }
+            None => node.groups.iter().map(|h| &self.groups[h]).collect(),
+        }
+    }
+
+    /// Fraction of the latency variance of all traces that is between the leaves
+    pub fn eta_squared(&self) -> f64 {
+        eta_squared(
+            &self
+                .leaves()
+                .iter()
+                .map(|&l| self.summaries_under(l))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    /// Eta-squared of each leaf against the rest of the tree
+    pub fn per_leaf_eta_squared(&self) -> Vec<(usize, f64)> {
+        self.leaves()
+            .into_iter()
+            .map(|leaf| {
+                let (inside, outside): (Vec<_>, Vec<_>) = self
+                    .leaves()
+                    .into_iter()
+                    .flat_map(|l| self.summaries_under(l).into_iter().map(move |s| (l, s)))
+                    .partition(|(l, _)| *l == leaf);
+                let inside = inside.into_iter().map(|(_, s)| s).collect();
+                let outside = outside.into_iter().map(|(_, s)| s).collect();
+                (leaf, eta_squared(&[inside, outside]))
+            })
+            .collect()
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        serde_json::to_writer_pretty(File::create(path)?, self)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Ok(serde_json::from_reader(File::open(path)?)?)
+    }
+}
+
+fn matches(summary: &GroupSummary, split: &[String]) -> bool {
+    summary.tracepoints.iter().any(|t| split.contains(t))
+}
+
+/// Between-class sum of squares over the total sum of squares, for classes of groups
+pub fn eta_squared(classes: &[Vec<&GroupSummary>]) -> f64 {
+    let all = classes.iter().flatten();
+    let count: u64 = all.clone().map(|s| s.count).sum();
+    if count == 0 {
+        return 0.0;
+    }
+    let grand_mean = all.clone().map(|s| s.mean * s.count as f64).sum::<f64>() / count as f64;
+    let total: f64 = all
+        .map(|s| s.count as f64 * (s.variance + (s.mean - grand_mean).powi(2)))
+        .sum();
+    if total == 0.0 {
+        return 0.0;
+    }
+    let between: f64 = classes
+        .iter()
+        .map(|class| {
+            let n: u64 = class.iter().map(|s| s.count).sum();
+            if n == 0 {
+                return 0.0;
+            }
+            let mean = class.iter().map(|s| s.mean * s.count as f64).sum::<f64>() / n as f64;
+            n as f64 * (mean - grand_mean).powi(2)
+        })
+        .sum();
+    between / total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(mean: f64, tracepoints: &[&str]) -> GroupSummary {
+        GroupSummary {
+            count: 10,
+            mean,
+            variance: 1.0,
+            tracepoints: tracepoints.iter().map(|t| t.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn split_explains_variance() {
+        let mut tree = HypothesisTree::new();
+        tree.add_summary("fast".to_string(), summary(10.0, &["a", "b"]));
+        tree.add_summary("slow".to_string(), summary(20.0, &["a", "c"]));
+        assert_eq!(tree.eta_squared(), 0.0);
+
+        let (with, without) = tree
+            .split_on_tracepoints(tree.root(), &[TracepointID::from_str("c")])
+            .unwrap();
+        assert_eq!(tree.node(with).groups, vec!["slow".to_string()]);
+        assert_eq!(tree.node_stats(without), (10, 10.0));
+        // Between: 20 * 25; total: 20 * (1 + 25)
+        assert!((tree.eta_squared() - 25.0 / 26.0).abs() < 1e-9);
+        assert!(tree.split_on_tracepoints(tree.root(), &[]).is_none());
+
+        // New groups follow the splits
+        tree.add_summary("slower".to_string(), summary(30.0, &["c"]));
+        assert_eq!(tree.node(with).groups.len(), 2);
+
+        // b is only in the leaf of fast, a separates slow from slower
+        let mut copy = tree.clone();
+        copy.split_on_each(&[TracepointID::from_str("b"), TracepointID::from_str("a")]);
+        assert_eq!(copy.leaves().len(), 3);
+        copy.split_on_each(&[TracepointID::from_str("d")]);
+        assert_eq!(copy.leaves().len(), 3);
+
+        let copy: HypothesisTree =
+            serde_json::from_str(&serde_json::to_string(&tree).unwrap()).unwrap();
+        assert_eq!(copy.leaves(), tree.leaves());
+        assert_eq!(copy.eta_squared(), tree.eta_squared());
+    }
+}
@@ -0,0 +1,173 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Did enabling tracepoints make requests slower?
+//!
+//! Consecutive epochs differ only in the tracepoints enabled at the decision between them, so
+//! comparing their request latencies is an A/B test of that instrumentation. The comparison is a
+//! one-sided Mann-Whitney U test, which doesn't assume latencies are normally distributed; the
+//! p-value comes from the normal approximation with tie correction, so both epochs need a fair
+//! number of requests (`MIN_SAMPLES`).
+
+use std::collections::HashSet;
+use std::fmt;
+
+use pythia_common::RequestType;
+use serde::{Deserialize, Serialize};
+
+use crate::epoch::Epoch;
+use crate::trace::TracepointID;
+
+/// Fewer requests than this in either epoch are not compared
+pub const MIN_SAMPLES: usize = 20;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImpactReport {
+    /// The epoch after the change
+    pub epoch: usize,
+    /// Tracepoints enabled at the start of `epoch` that were not enabled before
+    pub added: Vec<(TracepointID, Option<RequestType>)>,
+    pub before_count: usize,
+    pub after_count: usize,
+    /// Nanoseconds
+    pub before_median: u64,
+    /// Nanoseconds
+    pub after_median: u64,
+    /// Probability of latencies at least this much higher without the new tracepoints
+    pub p_value: f64,
+    /// p_value was below the significance level
+    pub increased: bool,
+}
+
+impl fmt::Display for ImpactReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Epoch {}: {} tracepoints added, median {:.3}ms ({} requests) -> {:.3}ms ({} requests), p={:.4}{}",
+            self.epoch,
+            self.added.len(),
+            self.before_median as f64 / 1e6,
+            self.before_count,
+            self.after_median as f64 / 1e6,
+            self.after_count,
+            self.p_value,
+            if self.increased { ", latency increased" } else { "" }
+        )
+    }
+}
+
+/// Compare the latencies of two consecutive epochs. None if the second one didn't enable
+/// anything new, or either has too few requests.
+pub fn compare_epochs(before: &Epoch, after: &Epoch, alpha: f64) -> Option<ImpactReport> {
+    let enabled: HashSet<_> = before.enabled.iter().collect();
+    let added: Vec<_> = after
+        .enabled
+        .iter()
+        .filter(|tp| !enabled.contains(tp))
+        .cloned()
+        .collect();
+    if added.is_empty()
+        || before.latencies.len() < MIN_SAMPLES
+        || after.latencies.len() < MIN_SAMPLES
+    {
+        return None;
+    }
+    let p_value = mann_whitney_greater(&before.latencies, &after.latencies);
+    Some(ImpactReport {
+        epoch: after.number,
+        added,
+        before_count: before.latencies.len(),
+        after_count: after.latencies.len(),
+        before_median: median(&before.latencies),
+        after_median: median(&after.latencies),
+        p_value,
+        increased: p_value < alpha,
+    })
+}
+
+/// Reports for each pair of consecutive epochs where tracepoints were added
+pub fn compare_all(epochs: &[Epoch], alpha: f64) -> Vec<ImpactReport> {
+    epochs
+        .windows(2)
+        .filter(|w| w[1].number == w[0].number + 1)
+        .filter_map(|w| compare_epochs(&w[0], &w[1], alpha))
+        .collect()
+}
+
+fn median(values: &[u64]) -> u64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_unstable();
+    sorted[sorted.len() / 2]
+}
+
+/// One-sided p-value for the hypothesis that values in `b` tend to be larger than those in `a`
+pub fn mann_whitney_greater(a: &[u64], b: &[u64]) -> f64 {
+    let (n1, n2) = (a.len() as f64, b.len() as f64);
+    let mut all: Vec<(u64, bool)> = a
+        .iter()
+        .map(|&v| (v, false))
+        .chain(b.iter().map(|&v| (v, true)))
+        .collect();
+    all.sort_unstable_by_key(|&(v, _)| v);
+
+    // Average ranks over ties, and sum t^3 - t over the tie groups for the variance
+    let mut rank_sum_b = 0.0;
+    let mut ties = 0.0;
+    let mut i = 0;
+    while i < all.len() {
+        let mut j = i;
+        while j < all.len() && all[j].0 == all[i].0 {
+            j += 1;
+        }
+        let rank = (i + j + 1) as f64 / 2.0;
+        rank_sum_b += rank * all[i..j].iter().filter(|(_, in_b)| *in_b).count() as f64;
+        let t = (j - i) as f64;
+        ties += t * t * t - t;
+        i = j;
+    }
+    let u = rank_sum_b - n2 * (n2 + 1.0) / 2.0;
+    let n = n1 + n2;
+    let variance = n1 * n2 / 12.0 * ((n + 1.0) - ties / (n * (n - 1.0)));
+    if variance <= 0.0 {
+        return 1.0;
+    }
+    // Continuity correction
+    let z = (u - n1 * n2 / 2.0 - 0.5) / variance.sqrt();
+    1.0 - normal_cdf(z)
+}
+
+fn normal_cdf(z: f64) -> f64 {
+    0.5 * (1.0 + erf(z / std::f64::consts::SQRT_2))
+}
+
+/// Abramowitz and Stegun 7.1.26, good to about 1e-7
+fn erf(x: f64) -> f64 {
+    let sign = if x < 0.0 { -1.0 } else { 1.0 };
+    let x = x.abs();
+    let t = 1.0 / (1.0 + 0.3275911 * x);
+    let poly = t
+        * (0.254829592
+            + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+    sign * (1.0 - poly * (-x * x).exp())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_slowdown() {
+        let before: Vec<u64> = (0..50).map(|i| 1000 + i * 10).collect();
+        let same: Vec<u64> = (0..50).map(|i| 1005 + i * 10).collect();
+        let slower: Vec<u64> = (0..50).map(|i| 1300 + i * 10).collect();
+        assert!(mann_whitney_greater(&before, &same) > 0.05);
+        assert!(mann_whitney_greater(&before, &slower) < 0.001);
+        assert!(mann_whitney_greater(&slower, &before) > 0.99);
+        assert!((normal_cdf(1.96) - 0.975).abs() < 1e-4);
+    }
+}
@@ -42,17 +42,68 @@ All rights reserved.
 //! There are a bunch of functions defined in this file, they are used from `cargo run`. Try
 //! `cargo run -- --help` to see a list of functions. Typically they are used in the debugging
 //! stage. Another way to run it is `cargo install --path .` and then use `pythia`. Some important ones:
-//! * `pythia get-trace <trace_id>` read a single trace and print the dot file
-//! * `pythia [enable|disable]-all` to enable/disable all tracepoints
+//! * `pythia get-trace <trace_id>` read a single trace and print the dot file. Add
+//!   `--dot-style host,duration,collapse` to color nodes by host, draw longer edges thicker and
+//!   hide synthetic nodes, or `--dot-style graphml` for GraphML (see `export::GraphStyle`).
+//! * `pythia watch-trace <trace_id> [--interval <secs>] [--stable <polls>]` poll the agents and
+//!   print the spans of a trace as they arrive, until it stops changing; for requests that look
+//!   stuck
+//! * `pythia slice-trace <trace_id> <from> <to>` print only the part of a trace between two
+//!   tracepoints, or `pythia slice-trace <trace_id> <span_id>` the part inside one span. Takes
+//!   `--dot-style` like `get-trace`.
+//! * `pythia export-trace <trace_id> --format jaeger|chrome|otlp` print a trace as spans, to
+//!   load into the Jaeger UI, chrome://tracing or an OpenTelemetry collector
+//! * `--output json` makes commands that print traces, groups or manifests (`get-trace`,
+//!   `get-crit`, `read-file`, `group-folder`, `group-ids`, `show-manifest`, `manifest-stats`,
+//!   ...) print JSON instead, for scripting.
+//! * `pythia flamegraph <trace_id|group> [--format collapsed|svg]` where the time of a trace's
+//!   critical path goes, or of the traces retained for a group, in the collapsed stack format of
+//!   `flamegraph.pl` and inferno or as an SVG
+//! * `pythia group-report <trace_folder> [--group <hash>]` mean, percentiles and variance of
+//!   each edge of a group, how much each edge adds to the latency and variance of the group, and
+//!   the spans off the critical path with the least slack. With `--dot-style` (e.g.
+//!   `variance,host`) the group is drawn instead, its edges as thick as their variance.
+//! * `pythia variance-explained <trace_folder>` how much of the latency variance of each request
+//!   type is between its groups (eta-squared), overall and for each group
+//! * `pythia [enable|disable]-all [--request-type <type>]...` to enable/disable all tracepoints,
+//!   or with `--request-type` only the manifest's tracepoints of those request types, e.g. to
+//!   fully instrument ServerCreate while the others stay at the skeleton
+//! * `pythia [enable|disable]-matching <regex>` to enable/disable the tracepoints of the manifest
+//!   whose ID matches, and `pythia enable-from-file <file>` to enable the ones listed in a file,
+//!   one per line, e.g. to set up the instrumentation of an experiment
+//! * `pythia profile save|apply <name>` save the tracepoints that are enabled now as a named
+//!   profile under `profile_dir`, or go back to one; `pythia profile list` shows the saved ones
+//! * `pythia snapshot save|restore <name>` save every tracepoint setting of every agent under
+//!   `snapshot_dir`, or put them back exactly as they were
+//! * `pythia tracepoints [--request-type <type>] [--grep <regex>] [--enabled-only]` list the
+//!   tracepoints of the manifest and whether each is on, as the agents report it
+//! * `pythia audit [--group <hash>] [--tracepoint <id>]` show which tracepoints were enabled and
+//!   disabled when, for which group and by which search strategy (see `audit_log`)
+//! * `pythia lineage <group-hash>` show the tracepoints enabled for a group at each decision and
+//!   the refined groups they split it into, as a tree (see `lineage_file`)
+//! * `pythia instrumentation-impact` compare the request latencies of consecutive epochs in
+//!   `epoch_dir`, to see whether the tracepoints enabled in between slowed requests down
+//! * `pythia check-config [--redis]` list everything wrong with the settings, optionally
+//!   including whether redis is reachable
+//! * `pythia agents-status` ping every agent in `pythia_clients` and show its version, and
+//!   whether it can reach redis, write the tracepoint configuration and has disk space left
 //! * `pythia manifest-stats` construct a manifest and print all the stats used for the paper.
+//! * `pythia remap-manifest --old <manifest> --trace-ids <file>` carries a manifest over to a new
+//!   version of the application, aliasing tracepoints whose line numbers changed.
+//! * `pythia pipeline --input <dir> --cycles N` show what Pythia would enable, cycle by cycle,
+//!   for a folder of recorded traces. Uses the search space if it exists.
+//! * `pythia backfill --input <dir|trace-id file> --out-dir <dir>` replay a historical archive
+//!   epoch by epoch and write the group timeline and the decisions Pythia would have made.
+//! * `pythia retained-traces <group>` list the traces kept for a group that the controller
+//!   flagged as problematic (see `retention_dir` in the configuration).
 //!
 //! # Running Pythia loop
 //! 1. Make sure everything is configured correctly, read the comments in the toml files
 //! 2. Make sure the agents are running on all nodes and configuration has the correct agent
 //!    addresses
 //! 3. Create a search space according to what's written above
-//! 4. Decide the stopping condition. This is done by changing the target points in the file
-//!    `src/bin/pythia_controller.rs`.
+//! 4. Decide the stopping condition in the `[stopping_condition]` section of `controller.toml`:
+//!    target tracepoints, a CV threshold, a maximum number of decisions or a time limit.
 //! 5. Simply `cargo run --bin pythia_controller /path/to/log/output`. I typically keep the
 //!    stdout/stderr and enable backtrace to have a more detailed view of things. So, this command
 //!    could also be used: `RUST_BACKTRACE=1 cargo run --bin pythia_controller /path/to/log/output
@@ -60,45 +111,135 @@ All rights reserved.
 //! 6. Execute some requests, and wait for Pythia to do its thing. For OpenStack, there is a
 //!    `/local/tracing-pythia/workloads/continuous_workload.sh` that will run many requests in a
 //!    loop.
+//!
+//! To try a search strategy without a cluster, set `replay_dir` in `controller.toml` to a folder
+//! of archived traces. The same loop then runs against the archive with a simulated clock and
+//! controller, and prints the tracepoints it would have enabled.
+//!
+//! When the loop stops, it writes a summary of the run next to the output file:
+//! `/path/to/log/output.report.json` and a readable `/path/to/log/output.report.md` with the
+//! problem groups found, the tracepoints enabled over time, the edges Pythia ended up localizing,
+//! how much variance was explained, and the overhead (see `report`).
 
 #[macro_use]
 extern crate lazy_static;
 
+pub mod anomaly;
+pub mod api;
 pub mod budget;
+pub mod canary;
+pub mod clock;
+pub mod clustering;
 pub mod controller;
+pub mod coverage;
 pub mod critical;
+pub mod deadline;
+pub mod epoch;
+pub mod export;
 pub mod grouping;
+pub mod hypothesis;
+pub mod impact;
+pub mod lineage;
 pub mod manifest;
+pub mod profile;
+pub mod progress;
+pub mod query;
 pub mod reader;
+pub mod report;
+pub mod retention;
 pub mod rpclib;
 pub mod search;
 pub mod settings;
+pub mod slo;
+pub mod snapshot;
+pub mod stopping;
+#[cfg(test)]
+pub mod testutils;
 pub mod trace;
+pub mod units;
 
+use std::collections::HashMap;
 use std::collections::HashSet;
-use std::error::Error;
-use std::fmt;
 use std::fs::File;
 use std::io::stdin;
-use std::io::{self, BufRead};
+use std::io::{self, BufRead, Write};
 use std::path::PathBuf;
 use std::process::Command;
+use std::sync::Arc;
 use std::time::Duration;
 use std::time::Instant;
 
 use itertools::Itertools;
+use log::{debug, error, info, warn};
 #[cfg(target_os = "linux")]
 use procinfo::pid::statm_self;
 use pythia_common::RequestType;
+use regex::Regex;
+use uuid::Uuid;
+pub use pythia_common::PythiaError;
 
+use crate::api::GroupSummary;
 use crate::controller::controller_from_settings;
+use crate::controller::AuditLog;
+use crate::controller::CappedController;
+use crate::controller::Controller;
+use crate::controller::DependentController;
+use crate::controller::TestController;
+use crate::coverage::CoverageReport;
 use crate::critical::CriticalPath;
+use crate::critical::Path;
+use crate::epoch::read_epochs;
+use crate::export::export_spans;
+use crate::export::group_graph;
+use crate::export::spans;
+use crate::export::trace_graph;
+use crate::export::Flamegraph;
+use crate::export::GraphStyle;
+use crate::export::SpanFormat;
 use crate::grouping::Group;
+use crate::grouping::GroupManager;
+use crate::grouping::variance_explained;
+use crate::grouping::ProblemSelector;
+use crate::impact::compare_all;
+use crate::lineage::{Lineage, LineageTree};
 use crate::manifest::Manifest;
+use crate::profile::Profile;
+use crate::query::Filter;
+use crate::reader::filter_events;
 use crate::reader::reader_from_settings;
+use crate::rpclib::ping_client;
+use crate::rpclib::read_client_health;
+use crate::rpclib::RetryPolicy;
+use crate::search::get_strategy;
+use crate::search::SearchStrategy;
 use crate::settings::ApplicationType;
 use crate::settings::Settings;
+use crate::snapshot::Snapshot;
 use crate::trace::Trace;
+use crate::trace::TracepointID;
+use crate::units::cv;
+
+/// How subcommands print their results. Json prints a single JSON document to stdout; progress
+/// messages go to stderr either way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl OutputFormat {
+    pub fn from_str(s: &str) -> OutputFormat {
+        match s {
+            "text" => OutputFormat::Text,
+            "json" => OutputFormat::Json,
+            _ => panic!("Unknown output format {}", s),
+        }
+    }
+}
+
+fn print_json<T: serde::Serialize>(value: &T) {
+    println!("{}", serde_json::to_string_pretty(value).unwrap());
+}
 
 // use rand::seq::SliceRandom;
 // use crate::cct::CCT;
@@ -222,16 +363,65 @@ use crate::trace::Trace;
 //     );
 // }
 
-pub fn disable_all() {
-    let settings = Settings::read();
-    let controller = controller_from_settings(&settings);
-    controller.disable_all();
+/// Disable all tracepoints, or only their settings for some request types when `request_types`
+/// is not empty
+pub fn disable_all(request_types: &[&str]) {
+    set_all(request_types, false);
+}
+
+/// Enable all tracepoints, or only the manifest's tracepoints of some request types when
+/// `request_types` is not empty; the other request types keep their settings
+pub fn enable_all(request_types: &[&str]) {
+    set_all(request_types, true);
 }
 
-pub fn enable_all() {
+fn set_all(request_types: &[&str], enable: bool) {
     let settings = Settings::read();
     let controller = controller_from_settings(&settings);
-    controller.enable_all();
+    if request_types.is_empty() {
+        if enable {
+            controller.enable_all();
+        } else {
+            controller.disable_all();
+        }
+        return;
+    }
+    // Only the OpenStack agents have settings per request type
+    if settings.application != ApplicationType::OpenStack {
+        error!(
+            "--request-type only works with OpenStack, not {:?}",
+            settings.application
+        );
+        std::process::exit(1);
+    }
+    let manifest = Manifest::from_file(settings.manifest_file.as_path())
+        .expect("Couldn't read manifest from cache");
+    let per_request_type = manifest.get_per_request_types();
+    for name in request_types {
+        let request_type = RequestType::from_str(name).unwrap_or_else(|_| {
+            error!("Unknown request type {}", name);
+            std::process::exit(1);
+        });
+        let known = match per_request_type.get(&request_type) {
+            Some(known) => known,
+            None => {
+                error!("The manifest has no tracepoints for {}", request_type);
+                std::process::exit(1);
+            }
+        };
+        let points = if enable {
+            controller.enable_all_for(request_type, known)
+        } else {
+            controller.disable_all_for(request_type, known)
+        };
+        println!(
+            "{} {} tracepoints for {}",
+            if enable { "Enabled" } else { "Disabled" },
+            points.len(),
+            request_type
+        );
+    }
+    controller.flush();
 }
 
 pub fn disable_tracepoint(t: &str) {
@@ -239,12 +429,171 @@ pub fn disable_tracepoint(t: &str) {
     assert_eq!(settings.application, ApplicationType::OpenStack);
     let controller = controller_from_settings(&settings);
     controller.disable_by_name(t);
+    controller.flush();
 }
 
-pub fn recent_traces() {
+/// Enable or disable the tracepoints of the manifest whose ID matches `pattern`
+pub fn set_matching(pattern: &str, enable: bool) {
+    let settings = Settings::read();
+    let manifest = Manifest::from_file(settings.manifest_file.as_path())
+        .expect("Couldn't read manifest from cache");
+    let pattern = Regex::new(pattern).unwrap_or_else(|e| panic!("Bad pattern: {}", e));
+    let controller = controller_from_settings(&settings);
+    let known = manifest.all_tracepoints();
+    let points = if enable {
+        controller.enable_matching(&pattern, &known)
+    } else {
+        controller.disable_matching(&pattern, &known)
+    };
+    controller.flush();
+    for tp in &points {
+        println!("{}", tp);
+    }
+    println!(
+        "{} {} tracepoints",
+        if enable { "Enabled" } else { "Disabled" },
+        points.len()
+    );
+}
+
+pub fn enable_from_file(file: &str) {
+    let settings = Settings::read();
+    let manifest = Manifest::from_file(settings.manifest_file.as_path())
+        .expect("Couldn't read manifest from cache");
+    let controller = controller_from_settings(&settings);
+    match controller.enable_from_file(&PathBuf::from(file), &manifest.all_tracepoints()) {
+        Ok(points) => {
+            controller.flush();
+            println!("Enabled {} tracepoints", points.len())
+        }
+        Err(e) => {
+            error!("Could not enable tracepoints from {}: {}", file, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Save the tracepoints of the manifest that are enabled right now as a named profile
+pub fn save_profile(name: &str) {
+    let settings = Settings::read();
+    let manifest = Manifest::from_file(settings.manifest_file.as_path())
+        .expect("Couldn't read manifest from cache");
+    let controller = controller_from_settings(&settings);
+    let profile = Profile::capture(name, &*controller, &manifest.get_per_request_types());
+    match profile.save(&settings.profile_dir) {
+        Ok(path) => println!(
+            "Saved {} tracepoints to {:?}",
+            profile.tracepoints.len(),
+            path
+        ),
+        Err(e) => {
+            error!("Could not save profile {}: {}", name, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Leave only the tracepoints of a saved profile enabled
+pub fn apply_profile(name: &str) {
+    let settings = Settings::read();
+    let profile = Profile::load(&settings.profile_dir, name).unwrap_or_else(|e| {
+        error!("Could not load profile {}: {}", name, e);
+        std::process::exit(1);
+    });
+    let controller = controller_from_settings(&settings);
+    profile.apply(&*controller);
+    controller.flush();
+    println!(
+        "Applied profile {} saved at {}: {} tracepoints",
+        name,
+        profile.saved.format("%Y-%m-%d %H:%M:%S"),
+        profile.tracepoints.len()
+    );
+}
+
+/// Save every tracepoint setting of every agent as a named snapshot
+pub fn save_snapshot(name: &str) {
+    let settings = Settings::read();
+    let policy = RetryPolicy::from_settings(&settings);
+    let saved = Snapshot::capture(
+        name,
+        &settings.pythia_clients,
+        &policy,
+        settings.agent_timeout,
+    )
+    .and_then(|snapshot| Ok((snapshot.save(&settings.snapshot_dir)?, snapshot)));
+    match saved {
+        Ok((path, snapshot)) => println!(
+            "Saved {} settings of {} agents to {:?}",
+            snapshot.settings(),
+            snapshot.agents.len(),
+            path
+        ),
+        Err(e) => {
+            error!("Could not save snapshot {}: {}", name, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Put the tracepoint settings of every agent back to a saved snapshot
+pub fn restore_snapshot(name: &str) {
+    let settings = Settings::read();
+    let snapshot = Snapshot::load(&settings.snapshot_dir, name).unwrap_or_else(|e| {
+        error!("Could not load snapshot {}: {}", name, e);
+        std::process::exit(1);
+    });
+    let policy = RetryPolicy::from_settings(&settings);
+    match snapshot.restore(&settings.pythia_clients, &policy, settings.agent_timeout) {
+        Ok(agents) => println!(
+            "Restored snapshot {} saved at {} on {} agents",
+            name,
+            snapshot.saved.format("%Y-%m-%d %H:%M:%S"),
+            agents
+        ),
+        Err(e) => {
+            error!("Could not restore snapshot {}: {}", name, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+pub fn list_profiles(format: OutputFormat) {
+    let settings = Settings::read();
+    let names = Profile::list(&settings.profile_dir).unwrap_or_default();
+    let profiles = names
+        .iter()
+        .filter_map(|name| match Profile::load(&settings.profile_dir, name) {
+            Ok(profile) => Some(profile),
+            Err(e) => {
+                warn!("Skipping profile {}: {}", name, e);
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    if format == OutputFormat::Json {
+        print_json(&profiles);
+        return;
+    }
+    for profile in profiles {
+        println!(
+            "{:<20} {}  {} tracepoints",
+            profile.name,
+            profile.saved.format("%Y-%m-%d %H:%M:%S"),
+            profile.tracepoints.len()
+        );
+    }
+}
+
+pub fn recent_traces(format: OutputFormat) {
     let settings = Settings::read();
     let mut reader = reader_from_settings(&settings);
-    for trace in reader.get_recent_traces() {
+    let traces = reader.get_recent_traces();
+    if format == OutputFormat::Json {
+        print_json(&traces);
+        return;
+    }
+    for trace in traces {
         println!("Got trace {}: {}", trace.base_id, trace);
     }
 }
@@ -256,24 +605,30 @@ pub fn enable_skeleton() {
         Manifest::from_file(manifest_file.as_path()).expect("Couldn't read manifest from cache");
     let controller = controller_from_settings(&settings);
     controller.disable_all();
-    let to_enable = manifest.skeleton();
+    let to_enable = match settings.skeleton_size {
+        Some(size) => {
+            let reduction = manifest.minimal_skeleton(size);
+            println!("{}", reduction);
+            reduction.kept
+        }
+        None => manifest.skeleton(),
+    };
     controller.enable(&to_enable.iter().map(|&a| (a.clone(), None)).collect());
+    controller.flush();
     println!("Enabled following tracepoints: {:?}", to_enable);
 }
 
-pub fn manifest_stats(manfile: &str) {
-    println!("mertiko");
+pub fn manifest_stats(manfile: &str, format: OutputFormat) {
     // #[cfg(target_os = "linux")]
     // {
-        println!("mertiko2");
         let settings = Settings::read();
         let mut reader = reader_from_settings(&settings);
         reader.for_searchspace();
         let traces = reader.read_trace_file(manfile);
         let now = Instant::now();
-        let manifest = Manifest::from_trace_list(&traces);
+        let manifest = Manifest::from_trace_list_budgeted(&traces, settings.path_budget);
         let elapsed = now.elapsed();
-        println!("Overwriting manifest file");
+        info!("Overwriting manifest file");
         let manifest_file = settings.manifest_file;
         manifest.to_file(manifest_file.as_path());
         // let prev_stats = statm_self().unwrap();
@@ -286,30 +641,19 @@ pub fn manifest_stats(manfile: &str) {
             .collect::<Vec<CriticalPath>>();
         let groups = Group::from_critical_paths(critical_paths);
 
-        // Start outputting stats
-        eprintln!(
-            "Trace count: {}, event count: {}",
-            traces.len(),
-            traces.iter().map(|t| t.g.node_count()).sum::<usize>()
-        );
-        eprintln!("Manifest construction took {:?}", elapsed);
+        let trace_count = traces.len();
+        let event_count = traces.iter().map(|t| t.g.node_count()).sum::<usize>();
         let output = Command::new("du")
             .arg("-sh")
-            .arg(manifest_file)
+            .arg(&manifest_file)
             .output()
             .unwrap();
-        eprint!(
-            "Manifest size on disk:\n{}",
-            String::from_utf8(output.stdout).unwrap()
-        );
-        eprintln!(
-            "Manifest size in # of tracepoints: {}",
-            manifest
-                .per_request_type
-                .iter()
-                .map(|(_, p)| p.path_lengths().iter().sum::<usize>())
-                .sum::<usize>()
-        );
+        let size_on_disk = String::from_utf8(output.stdout).unwrap();
+        let manifest_tracepoints = manifest
+            .per_request_type
+            .iter()
+            .map(|(_, p)| p.path_lengths().iter().sum::<usize>())
+            .sum::<usize>();
         // eprintln!(
         //     "Memory footprint (in pages):\nsize: {}, resident: {}, share: {}, text: {}, data: {}",
         //     after_stats.size - prev_stats.size,
@@ -319,53 +663,34 @@ pub fn manifest_stats(manfile: &str) {
         //     after_stats.data - prev_stats.data
         // );
         let output = Command::new("getconf").arg("PAGESIZE").output().unwrap();
-        eprint!(
-            "Page size in bytes: {}",
-            String::from_utf8(output.stdout).unwrap()
-        );
-        eprintln!(
-            "Number of paths per request type:\n{}",
-            manifest
-                .per_request_type
-                .iter()
-                .map(|(k, v)| { format!("{}: {}", k, v.path_count()) })
-                .join("\n")
-        );
-        eprintln!(
-            "Total number of paths: {:?}, added paths: {:?}",
-            manifest
-                .per_request_type
-                .iter()
-                .map(|(_, v)| v.path_count())
-                .sum::<usize>(),
-            manifest
-                .per_request_type
-                .iter()
-                .map(|(_, v)| v.added_paths)
-                .sum::<usize>()
-        );
-        eprintln!(
-            "Number of unique tracepoints observed in search space: {}",
-            manifest
-                .per_request_type
-                .iter()
-                .map(|(_, v)| { v.trace_points() })
-                .flatten()
-                .collect::<HashSet<_>>()
-                .len()
-        );
+        let page_size = String::from_utf8(output.stdout).unwrap();
+        let paths_per_type = manifest
+            .per_request_type
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.path_count()))
+            .sorted()
+            .collect::<Vec<_>>();
+        let total_paths = paths_per_type.iter().map(|(_, c)| c).sum::<usize>();
+        let added_paths = manifest
+            .per_request_type
+            .iter()
+            .map(|(_, v)| v.added_paths)
+            .sum::<usize>();
+        let unique_tracepoints = manifest
+            .per_request_type
+            .iter()
+            .map(|(_, v)| { v.trace_points() })
+            .flatten()
+            .collect::<HashSet<_>>()
+            .len();
         let path_lens = manifest
             .per_request_type
             .iter()
             .map(|(_, v)| v.path_lengths())
             .flatten()
             .collect::<Vec<usize>>();
-        eprintln!(
-            "Min/Average/Max path length: {}, {}, {}",
-            path_lens.iter().min().unwrap(),
-            path_lens.iter().map(|&x| x as f64).sum::<f64>() / path_lens.len() as f64,
-            path_lens.iter().max().unwrap(),
-        );
+        let mean_path_len =
+            path_lens.iter().map(|&x| x as f64).sum::<f64>() / path_lens.len() as f64;
         // Warm-up
         let _ = groups
             .iter()
@@ -376,33 +701,210 @@ pub fn manifest_stats(manfile: &str) {
             .iter()
             .map(|t| manifest.match_performance(t))
             .collect::<Vec<_>>();
+        let mean_match = performances.iter().sum::<Duration>() / (performances.len() as u32);
+
+        if format == OutputFormat::Json {
+            print_json(&serde_json::json!({
+                "trace_count": trace_count,
+                "event_count": event_count,
+                "construction_us": elapsed.as_micros() as u64,
+                "size_on_disk": size_on_disk.split_whitespace().next(),
+                "manifest_tracepoints": manifest_tracepoints,
+                "page_size": page_size.trim().parse::<usize>().ok(),
+                "paths_per_request_type": paths_per_type
+                    .iter()
+                    .cloned()
+                    .collect::<std::collections::BTreeMap<_, _>>(),
+                "total_paths": total_paths,
+                "added_paths": added_paths,
+                "unique_tracepoints": unique_tracepoints,
+                "path_length": {
+                    "min": path_lens.iter().min(),
+                    "mean": mean_path_len,
+                    "max": path_lens.iter().max(),
+                },
+                "match_us": {
+                    "min": performances.iter().min().map(|d| d.as_micros() as u64),
+                    "mean": mean_match.as_micros() as u64,
+                    "max": performances.iter().max().map(|d| d.as_micros() as u64),
+                },
+            }));
+            return;
+        }
+
+        // Start outputting stats
+        eprintln!("Trace count: {}, event count: {}", trace_count, event_count);
+        eprintln!("Manifest construction took {:?}", elapsed);
+        eprint!("Manifest size on disk:\n{}", size_on_disk);
+        eprintln!("Manifest size in # of tracepoints: {}", manifest_tracepoints);
+        eprint!("Page size in bytes: {}", page_size);
+        eprintln!(
+            "Number of paths per request type:\n{}",
+            paths_per_type
+                .iter()
+                .map(|(k, v)| { format!("{}: {}", k, v) })
+                .join("\n")
+        );
+        eprintln!(
+            "Total number of paths: {:?}, added paths: {:?}",
+            total_paths, added_paths
+        );
+        eprintln!(
+            "Number of unique tracepoints observed in search space: {}",
+            unique_tracepoints
+        );
+        eprintln!(
+            "Min/Average/Max path length: {}, {}, {}",
+            path_lens.iter().min().unwrap(),
+            mean_path_len,
+            path_lens.iter().max().unwrap(),
+        );
         eprintln!(
             "Time to match: min {:?}, max {:?}, mean {:?}",
             performances.iter().min().unwrap(),
             performances.iter().max().unwrap(),
-            performances.iter().sum::<Duration>() / (performances.len() as u32)
+            mean_match
         );
     // }
 }
 
-pub fn show_manifest(request_type: &str) {
+pub fn show_manifest(request_type: &str, format: OutputFormat) {
     let settings = Settings::read();
     let manifest_file = settings.manifest_file;
     let manifest =
         Manifest::from_file(manifest_file.as_path()).expect("Couldn't read manifest from cache");
+    let search_space = manifest
+        .per_request_type
+        .get(&RequestType::from_str(request_type).unwrap())
+        .unwrap();
+    match format {
+        OutputFormat::Text => println!("{}", search_space),
+        OutputFormat::Json => print_json(search_space),
+    }
+}
+
+/// Which enabled tracepoints are missing from the recent traces, and which tracepoints on them
+/// are missing from the manifest
+pub fn coverage(format: OutputFormat) {
+    let settings = Settings::read();
+    let manifest = Manifest::from_file(settings.manifest_file.as_path())
+        .expect("Couldn't read manifest from cache");
+    let controller = controller_from_settings(&settings);
+    // Ask the agents, since this process didn't enable anything. If they can't list their
+    // settings, only the manifest's tracepoints can be checked.
+    let mut enabled = controller.query_all_enabled().unwrap_or_else(|| {
+        let candidates: Vec<(TracepointID, Option<RequestType>)> = manifest
+            .all_tracepoints()
+            .into_iter()
+            .map(|tp| (tp, None))
+            .chain(
+                manifest
+                    .get_per_request_types()
+                    .into_iter()
+                    .flat_map(|(rt, tps)| tps.into_iter().map(move |tp| (tp, Some(rt)))),
+            )
+            .collect();
+        candidates
+            .iter()
+            .zip(controller.query_enabled(&candidates))
+            .filter(|(_, enabled)| *enabled)
+            .map(|(p, _)| *p)
+            .collect()
+    });
+    enabled.sort_by_key(|(tp, rt)| (tp.to_string(), rt.map(|rt| rt.to_string())));
+    let mut reader = reader_from_settings(&settings);
+    let traces = reader.get_recent_traces();
+    let report = CoverageReport::compute(&enabled, &traces, &manifest);
+    match format {
+        OutputFormat::Text => println!("{}", report),
+        OutputFormat::Json => print_json(&report),
+    }
+}
+
+/// Tracepoints of the manifest, optionally of one request type or matching a regex, with whether
+/// they are enabled right now according to the controller
+pub fn list_tracepoints(
+    request_type: Option<&str>,
+    pattern: Option<&str>,
+    enabled_only: bool,
+    format: OutputFormat,
+) {
+    let settings = Settings::read();
+    let manifest = Manifest::from_file(settings.manifest_file.as_path())
+        .expect("Couldn't read manifest from cache");
+    let request_type = request_type.map(|rt| RequestType::from_str(rt).unwrap());
+    let pattern = pattern.map(|p| Regex::new(p).unwrap_or_else(|e| panic!("Bad pattern: {}", e)));
+    let mut request_types: HashMap<TracepointID, Vec<RequestType>> = HashMap::new();
+    for (rt, tracepoints) in manifest.get_per_request_types() {
+        if request_type.is_some_and(|t| t != rt) {
+            continue;
+        }
+        for tp in tracepoints {
+            request_types.entry(tp).or_default().push(rt);
+        }
+    }
+    let mut tracepoints = request_types
+        .into_iter()
+        .filter(|(tp, _)| pattern.as_ref().is_none_or(|p| p.is_match(&tp.to_string())))
+        .map(|(tp, mut rts)| {
+            rts.sort_by_key(|rt| rt.to_string());
+            (tp.to_string(), tp, rts)
+        })
+        .collect::<Vec<_>>();
+    tracepoints.sort_by(|a, b| a.0.cmp(&b.0));
+    let controller = controller_from_settings(&settings);
+    let enabled = controller.query_enabled(
+        &tracepoints
+            .iter()
+            .map(|(_, tp, _)| (*tp, request_type))
+            .collect(),
+    );
+    let rows = tracepoints
+        .into_iter()
+        .zip(enabled)
+        .filter(|(_, enabled)| *enabled || !enabled_only)
+        .map(|((name, _, rts), enabled)| (name, rts, enabled))
+        .collect::<Vec<_>>();
+    if format == OutputFormat::Json {
+        print_json(
+            &rows
+                .iter()
+                .map(|(name, rts, enabled)| {
+                    serde_json::json!({
+                        "tracepoint": name,
+                        "request_types": rts.iter().map(|rt| rt.to_string()).collect::<Vec<_>>(),
+                        "enabled": enabled,
+                    })
+                })
+                .collect::<Vec<_>>(),
+        );
+        return;
+    }
+    for (name, rts, enabled) in &rows {
+        println!(
+            "{:<3} {}  [{}]",
+            if *enabled { "on" } else { "off" },
+            name,
+            rts.iter().join(", ")
+        );
+    }
     println!(
-        "{}",
-        manifest
-            .per_request_type
-            .get(&RequestType::from_str(request_type).unwrap())
-            .unwrap()
+        "{} tracepoints, {} enabled",
+        rows.len(),
+        rows.iter().filter(|r| r.2).count()
     );
 }
 
-pub fn dump_traces(tracefile: &str) {
+/// Writes the traces to the home folder. With `retry_failed`, only the IDs in that file of failed
+/// traces are read, so an interrupted or partly failed dump can be finished.
+pub fn dump_traces(tracefile: Option<&str>, retry_failed: Option<&str>) {
     let settings = Settings::read();
     let mut reader = reader_from_settings(&settings);
-    for trace in reader.read_trace_file(tracefile) {
+    let traces = match retry_failed {
+        Some(failed_file) => reader.retry_failed(failed_file),
+        None => reader.read_trace_file(tracefile.unwrap()),
+    };
+    for trace in traces {
         let mut outfile = dirs::home_dir().unwrap();
         outfile.push(trace.base_id.to_hyphenated().to_string());
         outfile.set_extension("json");
@@ -410,36 +912,50 @@ pub fn dump_traces(tracefile: &str) {
     }
 }
 
-pub fn get_manifest(manfile: &str, overwrite: bool) {
+/// Builds the manifest from the traces in `manfile`. With `retry_failed`, the traces in that file
+/// of failed traces are added to the existing manifest instead, to finish a manifest built from a
+/// partly failed trace file.
+pub fn get_manifest(manfile: Option<&str>, overwrite: bool, retry_failed: Option<&str>) {
     let settings = Settings::read();
     let mut reader = reader_from_settings(&settings);
     reader.for_searchspace();
-    let mut traces = reader.read_trace_file(manfile);
-    if settings.application == ApplicationType::HDFS {
-        for trace in &mut traces {
-            trace.prune();
+    let failed_file = match retry_failed {
+        Some(failed_file) => failed_file,
+        None => {
+            let traces = reader.read_trace_file(manfile.unwrap());
+            manifest_from_traces(&traces, overwrite, &settings);
+            return;
         }
-    }
-    manifest_from_traces(&traces, overwrite, &settings.manifest_file);
+    };
+    let manifest_file = settings.manifest_file.as_path();
+    let mut manifest = Manifest::from_file(manifest_file).expect("Couldn't read manifest from cache");
+    let traces = reader.retry_failed(failed_file);
+    let added: usize = traces
+        .iter()
+        .map(|t| manifest.add_trace(t, settings.path_budget))
+        .sum();
+    manifest.to_file(manifest_file);
+    println!(
+        "Added {} paths of {} traces to {:?}",
+        added,
+        traces.len(),
+        manifest_file
+    );
 }
 
 pub fn manifest_from_folder(trace_folder: &str) {
     let settings = Settings::read();
     let mut reader = reader_from_settings(&settings);
     reader.for_searchspace();
-    let mut traces = reader.read_dir(trace_folder);
+    let traces = reader.read_dir(trace_folder);
     println!("Read {} traces", traces.len());
-    if settings.application == ApplicationType::HDFS {
-        for trace in &mut traces {
-            trace.prune();
-        }
-    }
-    manifest_from_traces(&traces, false, &settings.manifest_file);
+    manifest_from_traces(&traces, false, &settings);
 }
 
-fn manifest_from_traces(traces: &Vec<Trace>, overwrite: bool, manifest_file: &PathBuf) {
+fn manifest_from_traces(traces: &Vec<Trace>, overwrite: bool, settings: &Settings) {
+    let manifest_file = &settings.manifest_file;
     let now = Instant::now();
-    let manifest = Manifest::from_trace_list(&traces);
+    let manifest = Manifest::from_trace_list_budgeted(&traces, settings.path_budget);
     let elapsed = now.elapsed();
     println!("{}", manifest);
     if manifest_file.exists() {
@@ -457,57 +973,199 @@ fn manifest_from_traces(traces: &Vec<Trace>, overwrite: bool, manifest_file: &Pa
         println!("Overwriting");
     }
     manifest.to_file(manifest_file.as_path());
-    eprintln!("Manifest construction took {:?}", elapsed);
+    info!("Manifest construction took {:?}", elapsed);
 }
 
-pub fn measure_search_space_feasibility(trace_file: &str) {
+/// Carries a manifest over to a new version of the application, using traces collected from the
+/// new version to find where tracepoints moved.
+pub fn remap_manifest(old_manifest: &str, trace_ids: &str) {
     let settings = Settings::read();
+    let mut manifest = Manifest::from_file(std::path::Path::new(old_manifest))
+        .expect("Couldn't read the old manifest");
     let mut reader = reader_from_settings(&settings);
     reader.for_searchspace();
-    let mut trace = reader.read_file(trace_file);
-    if settings.application == ApplicationType::HDFS {
-        trace.prune();
+    let traces = reader.read_trace_file(trace_ids);
+    let count = manifest.remap(&traces);
+    for (old, new) in manifest.aliases.iter() {
+        println!("{} -> {}", old, new);
+    }
+    println!("Aliased {} tracepoints", count);
+    manifest.to_file(settings.manifest_file.as_path());
+    println!("Wrote remapped manifest to {:?}", settings.manifest_file);
+}
+
+pub fn measure_search_space_feasibility(trace_file: &str, format: OutputFormat) {
+    let settings = Settings::read();
+    let mut reader = reader_from_settings(&settings);
+    reader.for_searchspace();
+    let trace = reader.read_file(trace_file);
+    let manifest = Manifest::from_trace_list(&vec![trace]);
+    match format {
+        OutputFormat::Text => println!("{}", manifest),
+        OutputFormat::Json => print_json(&manifest),
     }
-    println!("{}", Manifest::from_trace_list(&vec![trace]));
 }
 
-pub fn group_folder(trace_folder: &str) {
+pub fn group_folder(trace_folder: &str, format: OutputFormat) {
     let settings = Settings::read();
     let mut reader = reader_from_settings(&settings);
     // println!(trace_folder);
     let traces = reader.read_dir(trace_folder);
-    println!("Read {} traces", traces.len());
-    group_traces(traces);
+    info!("Read {} traces", traces.len());
+    group_traces(traces, format);
 }
 
-pub fn group_from_ids(id_file: &str) {
+pub fn group_from_ids(id_file: &str, format: OutputFormat) {
     let settings = Settings::read();
     let mut reader = reader_from_settings(&settings);
-    println!("{}",id_file);
+    debug!("Reading trace IDs from {}", id_file);
     let file = File::open(id_file).unwrap();
-    // println!("mertiko");
     let traces = io::BufReader::new(file)
         .lines()
         .map(|x| reader.get_trace_from_base_id(&x.unwrap()).unwrap())
         .collect::<Vec<_>>();
-    println!("Read {} traces", traces.len());
-    group_traces(traces);
+    info!("Read {} traces", traces.len());
+    group_traces(traces, format);
+}
+
+/// Spans off the critical path shown by `group_report`
+const NEAR_CRITICAL_SPANS: usize = 10;
+
+/// Per-edge latency breakdown of one group of the traces in the folder. Without a group hash,
+/// the group with the highest variance is reported. With a graph style, the group is drawn
+/// instead.
+pub fn group_report(
+    trace_folder: &str,
+    group_hash: Option<&str>,
+    dot_style: Option<GraphStyle>,
+    format: OutputFormat,
+) {
+    let settings = Settings::read();
+    let mut reader = reader_from_settings(&settings);
+    let traces = reader.read_dir(trace_folder);
+    info!("Read {} traces", traces.len());
+    let critical_paths = traces
+        .iter()
+        .filter_map(|t| CriticalPath::from_trace(t).ok())
+        .collect::<Vec<CriticalPath>>();
+    let mut groups = Group::from_critical_paths(critical_paths);
+    groups.sort_by(|a, b| b.variance.partial_cmp(&a.variance).unwrap()); // descending order
+    let group = match group_hash {
+        Some(hash) => groups.iter().find(|g| g.hash() == hash),
+        None => groups.first(),
+    };
+    let group = match group {
+        Some(g) => g,
+        None => {
+            error!("No such group among {} groups", groups.len());
+            return;
+        }
+    };
+    if let (OutputFormat::Text, Some(style)) = (format, dot_style) {
+        print!("{}", group_graph(group, &style));
+        return;
+    }
+    let breakdown = group.latency_breakdown();
+    let near_critical = group.near_critical_spans(&traces, NEAR_CRITICAL_SPANS);
+    if format == OutputFormat::Json {
+        print_json(&serde_json::json!({
+            "group": GroupSummary::from_group(group),
+            "edges": breakdown,
+            "near_critical_spans": near_critical,
+        }));
+        return;
+    }
+    println!("{}", group);
+    println!(
+        "{:>10} {:>10} {:>10} {:>10} {:>12} {:>7} {:>7}  edge",
+        "mean_ms", "p50_ms", "p95_ms", "p99_ms", "stddev_ms", "lat_%", "var_%"
+    );
+    for edge in breakdown {
+        println!(
+            "{:>10.3} {:>10.3} {:>10.3} {:>10.3} {:>12.3} {:>7.1} {:>7.1}  {} -> {}",
+            edge.mean.as_millis(),
+            edge.p50.as_millis(),
+            edge.p95.as_millis(),
+            edge.p99.as_millis(),
+            edge.variance.sqrt().as_millis(),
+            edge.latency_share,
+            edge.variance_share,
+            edge.from,
+            edge.to
+        );
+    }
+    if near_critical.is_empty() {
+        return;
+    }
+    println!("\nNear-critical spans (off the critical path):");
+    println!("{:>12} {:>12} {:>7}  span", "min_slack_ms", "mean_slack_ms", "traces");
+    for span in near_critical {
+        println!(
+            "{:>12.3} {:>12.3} {:>7}  {}",
+            span.min_slack.as_millis(),
+            span.mean_slack.as_millis(),
+            span.count,
+            span.tracepoint
+        );
+    }
 }
 
-fn group_traces(traces: Vec<Trace>) {
+pub fn show_variance_explained(trace_folder: &str, format: OutputFormat) {
+    let settings = Settings::read();
+    let mut reader = reader_from_settings(&settings);
+    let traces = reader.read_dir(trace_folder);
+    info!("Read {} traces", traces.len());
     let critical_paths = traces
         .iter()
         .filter_map(|t| CriticalPath::from_trace(t).ok())
         .collect::<Vec<CriticalPath>>();
-    println!("Got {} paths", critical_paths.len());
+    let groups = Group::from_critical_paths(critical_paths);
+    let result = variance_explained(&groups.iter().collect::<Vec<_>>());
+    if format == OutputFormat::Json {
+        print_json(&result);
+        return;
+    }
+    for v in result {
+        println!(
+            "{:?}: {:.1}% of the variance is between {} groups",
+            v.request_type,
+            v.ratio * 100.0,
+            v.per_leaf.len()
+        );
+        for (hash, share) in v.per_leaf {
+            println!("{:>10.1}%  {}", share * 100.0, hash);
+        }
+    }
+}
+
+fn group_traces(traces: Vec<Trace>, format: OutputFormat) {
+    let critical_paths = traces
+        .iter()
+        .filter_map(|t| CriticalPath::from_trace(t).ok())
+        .collect::<Vec<CriticalPath>>();
+    info!("Got {} paths", critical_paths.len());
     let mut groups = Group::from_critical_paths(critical_paths);
-    println!("Got {} groups", groups.len());
+    info!("Got {} groups", groups.len());
     groups.sort_by(|a, b| b.traces.len().partial_cmp(&a.traces.len()).unwrap()); // descending order
+    if format == OutputFormat::Json {
+        print_json(
+            &groups
+                .iter()
+                .map(|g| {
+                    serde_json::json!({
+                        "group": GroupSummary::from_group(g),
+                        "trace_ids": g.traces.iter().map(|p| p.g.base_id).collect::<Vec<_>>(),
+                    })
+                })
+                .collect::<Vec<_>>(),
+        );
+        return;
+    }
     println!(
         "Trace count and variance of each group: {:?}",
         groups
             .iter()
-            .map(|x| (x.traces.len(), x.variance))
+            .map(|x| (x.traces.len(), x.variance.0))
             .collect::<Vec<_>>()
     );
     println!("Top 5 variance groups");
@@ -571,18 +1229,303 @@ fn group_traces(traces: Vec<Trace>) {
     }
 }
 
-pub fn read_trace_file(trace_file: &str) {
+/// Runs the whole Pythia loop over the traces in a folder without touching the application.
+///
+/// Traces are ordered by start time and split into `cycles` cycles. In each cycle the new traces
+/// are grouped, problem groups are selected, and the search strategy's decisions are applied to
+/// a `TestController`. The decisions of each cycle are printed.
+pub fn pipeline(input: &str, cycles: usize) {
+    let settings = Settings::read();
+    let (cycles, enabled) = match replay_pipeline(&settings, input, cycles) {
+        Ok(result) => result,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+    for (i, cycle) in cycles.iter().enumerate() {
+        println!(
+            "Cycle {}: {} traces, {} paths, selector {:?}, enabled {:?}",
+            i + 1,
+            cycle.traces,
+            cycle.paths,
+            cycle.selector,
+            cycle.enabled
+        );
+    }
+    println!("{} tracepoints enabled at the end", enabled);
+}
+
+/// What one cycle of `pipeline` saw and enabled
+#[derive(Debug, Clone)]
+pub struct PipelineCycle {
+    pub traces: usize,
+    pub paths: usize,
+    pub selector: ProblemSelector,
+    pub enabled: Vec<(TracepointID, Option<RequestType>)>,
+}
+
+/// The cycles of `pipeline`, and how many tracepoints are enabled at the end
+pub fn replay_pipeline(
+    settings: &Settings,
+    input: &str,
+    cycles: usize,
+) -> Result<(Vec<PipelineCycle>, usize), PythiaError> {
+    let traces = read_replay_traces(settings, input)?;
+    if traces.len() == 0 {
+        return Err(PythiaError::ReaderError(format!(
+            "No traces found in {}",
+            input
+        )));
+    }
+    println!("Read {} traces", traces.len());
+    let (controller, strategy) = replay_setup(settings, &traces);
+
+    let mut groups = GroupManager::new();
+    groups.group_by_request_params(settings.group_by_request_params);
+    groups.set_grouping_mode(settings.grouping_mode);
+    groups.partition_by(settings.group_partition_key.clone());
+    groups.partition_by_filter(settings.group_partition_filter.clone());
+    let per_cycle = (traces.len() + cycles.max(1) - 1) / cycles.max(1);
+    let mut result = Vec::new();
+    for chunk in traces.chunks(per_cycle) {
+        let critical_paths = chunk
+            .iter()
+            .filter_map(|t| {
+                CriticalPath::top_k_from_trace(t, settings.critical_paths_per_trace).ok()
+            })
+            .flatten()
+            .collect::<Vec<CriticalPath>>();
+        groups.update(&critical_paths);
+        let selector = settings.reloadable.problem_selection.resolve(&groups);
+        let decisions = replay_decisions(
+            &mut groups,
+            selector,
+            strategy.as_ref(),
+            controller,
+            settings.reloadable.tracepoints_per_epoch,
+        );
+        result.push(PipelineCycle {
+            traces: chunk.len(),
+            paths: critical_paths.len(),
+            selector,
+            enabled: decisions.into_iter().flat_map(|(_, d)| d).collect(),
+        });
+    }
+    Ok((result, controller.enabled_tracepoints().len()))
+}
+
+/// Replays a historical archive in time order, as fast as possible, and writes what Pythia would
+/// have seen and done.
+///
+/// `input` is either a folder of dumped traces or a file of trace IDs to fetch from the trace
+/// store. Traces are split into decision epochs using their own timestamps. Two files are written
+/// to `output`: `timeline.csv` has the statistics of every active group after each epoch, and
+/// `decisions.csv` has the tracepoints that would have been enabled.
+pub fn backfill(input: &str, output: &str) {
+    let settings = Settings::read();
+    let traces = match read_replay_traces(&settings, input) {
+        Ok(traces) if traces.len() > 0 => traces,
+        Ok(_) => {
+            error!("No traces found in {}", input);
+            return;
+        }
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+    println!("Read {} traces", traces.len());
+    let (controller, strategy) = replay_setup(&settings, &traces);
+
+    std::fs::create_dir_all(output).unwrap();
+    let output = PathBuf::from(output);
+    let mut timeline = File::create(output.join("timeline.csv")).unwrap();
+    writeln!(
+        timeline,
+        "epoch_start,group,request_type,traces,mean_ms,variance_ns2,cv"
+    )
+    .unwrap();
+    let mut decision_log = File::create(output.join("decisions.csv")).unwrap();
+    writeln!(decision_log, "epoch_start,selector,group,tracepoint").unwrap();
+
+    let epoch = chrono::Duration::from_std(settings.decision_epoch).unwrap();
+    let mut groups = GroupManager::new();
+    groups.group_by_request_params(settings.group_by_request_params);
+    groups.set_grouping_mode(settings.grouping_mode);
+    groups.partition_by(settings.group_partition_key.clone());
+    groups.partition_by_filter(settings.group_partition_filter.clone());
+    let mut remaining = &traces[..];
+    let mut epochs = 0;
+    while !remaining.is_empty() {
+        let epoch_start = remaining[0].g[remaining[0].start_node].timestamp;
+        let count = remaining
+            .iter()
+            .take_while(|t| t.g[t.start_node].timestamp < epoch_start + epoch)
+            .count();
+        let (chunk, rest) = remaining.split_at(count);
+        remaining = rest;
+        epochs += 1;
+
+        let critical_paths = chunk
+            .iter()
+            .filter_map(|t| {
+                CriticalPath::top_k_from_trace(t, settings.critical_paths_per_trace).ok()
+            })
+            .flatten()
+            .collect::<Vec<CriticalPath>>();
+        groups.update(&critical_paths);
+        for g in groups.active_groups() {
+            writeln!(
+                timeline,
+                "{},{},{},{},{},{},{}",
+                epoch_start,
+                g.hash(),
+                g.parameterized_type(),
+                g.traces.len(),
+                g.mean.as_millis(),
+                g.variance,
+                cv(g.mean, g.variance)
+            )
+            .unwrap();
+        }
+        let selector = settings.reloadable.problem_selection.resolve(&groups);
+        let decisions = replay_decisions(
+            &mut groups,
+            selector,
+            strategy.as_ref(),
+            controller,
+            settings.reloadable.tracepoints_per_epoch,
+        );
+        for (group, enabled) in &decisions {
+            for (tracepoint, _) in enabled {
+                writeln!(
+                    decision_log,
+                    "{},{:?},{},{}",
+                    epoch_start, selector, group, tracepoint
+                )
+                .unwrap();
+            }
+        }
+    }
+    println!(
+        "Processed {} epochs, {} tracepoints enabled at the end, results in {:?}",
+        epochs,
+        controller.enabled_tracepoints().len(),
+        output
+    );
+}
+
+/// Reads traces for offline replay, sorted by start time. `input` is either a folder of trace
+/// files or a file of trace IDs.
+fn read_replay_traces(settings: &Settings, input: &str) -> Result<Vec<Trace>, PythiaError> {
+    if !std::path::Path::new(input).exists() {
+        return Err(PythiaError::ReaderError(format!("{} does not exist", input)));
+    }
+    let mut reader = reader_from_settings(settings);
+    let mut traces = if std::path::Path::new(input).is_dir() {
+        reader.read_dir(input)
+    } else {
+        reader.read_trace_file(input)
+    };
+    traces.sort_by_key(|t| t.g[t.start_node].timestamp);
+    Ok(traces)
+}
+
+/// Sets up a test controller with the skeleton enabled and a search strategy for offline replay.
+/// If there is no manifest, one is built from the traces.
+fn replay_setup(
+    settings: &Settings,
+    traces: &Vec<Trace>,
+) -> (&'static Box<dyn Controller>, Box<dyn SearchStrategy>) {
+    let manifest = Arc::new(match Manifest::from_file(settings.manifest_file.as_path()) {
+        Some(m) => m,
+        None => {
+            println!(
+                "No manifest at {:?}, building one from the input",
+                settings.manifest_file
+            );
+            Manifest::from_trace_list_budgeted(traces, settings.path_budget)
+        }
+    });
+    let controller: Box<dyn Controller> = Box::new(DependentController::new(
+        Box::new(CappedController::new(
+            Box::new(TestController::new()),
+            settings.max_enabled_tracepoints,
+            manifest.skeleton(),
+        )),
+        manifest.dependencies(),
+    ));
+    let controller: &'static Box<dyn Controller> = Box::leak(Box::new(controller));
+    let strategy = get_strategy(settings, manifest.clone(), controller);
+    let skeleton = manifest
+        .skeleton()
+        .iter()
+        .map(|&a| (a.clone(), None))
+        .collect::<Vec<_>>();
+    controller.enable(&skeleton);
+    println!("Enabled skeleton of {} tracepoints", skeleton.len());
+    (controller, strategy)
+}
+
+/// One decision step of the controller loop: search the problem groups' edges until the budget
+/// runs out, enable the results, and mark the groups as used. Returns the decisions per group.
+fn replay_decisions(
+    groups: &mut GroupManager,
+    selector: ProblemSelector,
+    strategy: &dyn SearchStrategy,
+    controller: &Box<dyn Controller>,
+    mut budget: usize,
+) -> Vec<(String, Vec<(TracepointID, Option<RequestType>)>)> {
+    let mut result = Vec::new();
+    for g in groups.problem_groups_by(selector) {
+        let mut enabled = Vec::new();
+        for edge in g.problem_edges() {
+            if budget == 0 {
+                break;
+            }
+            let decisions = strategy
+                .search(g, edge, budget)
+                .iter()
+                .take(budget)
+                .map(|&t| (t, Some(g.request_type)))
+                .collect::<Vec<_>>();
+            budget -= decisions.len();
+            controller.enable_for_group(&decisions, g.hash());
+            enabled.extend(decisions);
+        }
+        if enabled.len() > 0 {
+            result.push((g.hash().to_string(), enabled));
+        }
+        if budget == 0 {
+            break;
+        }
+    }
+    for (g, _) in &result {
+        groups.used(g);
+    }
+    result
+}
+
+pub fn read_trace_file(trace_file: &str, format: OutputFormat) {
     
     let settings = Settings::read();
     let mut reader = reader_from_settings(&settings);
-    let mut trace = reader.read_file(trace_file);
-    if settings.application == ApplicationType::HDFS {
-        trace.prune();
+    let trace = reader.read_file(trace_file);
+    match format {
+        OutputFormat::Text => println!("{}", trace),
+        OutputFormat::Json => print_json(&trace),
     }
-    println!("{}", trace);
 }
 
-pub fn get_trace(trace_id: &str, to_file: bool, prune: bool) {
+pub fn get_trace(
+    trace_id: &str,
+    to_file: bool,
+    prune: bool,
+    filter: Option<Filter>,
+    dot_style: Option<GraphStyle>,
+    format: OutputFormat,
+) {
     
     let settings = Settings::read();
     let mut reader = reader_from_settings(&settings);
@@ -592,14 +1535,159 @@ pub fn get_trace(trace_id: &str, to_file: bool, prune: bool) {
     if prune {
         trace.prune();
     }
-    println!("{}", trace);
+    if let Some(filter) = filter {
+        filter_events(&mut trace, |e| filter.matches_event(e));
+    }
+    match (format, dot_style) {
+        (OutputFormat::Text, Some(style)) => print!("{}", trace_graph(&trace, &style)),
+        (OutputFormat::Text, None) => println!("{}", trace),
+        (OutputFormat::Json, _) => print_json(&trace),
+    }
     
     if to_file {
         let mut tracefile = dirs::home_dir().unwrap();
         tracefile.push(trace_id);
         tracefile.set_extension("json");
         trace.to_file(tracefile.as_path());
-        eprintln!("Wrote trace to {}", tracefile.to_str().unwrap());
+        info!("Wrote trace to {}", tracefile.to_str().unwrap());
+    }
+}
+
+/// Poll a trace every `interval` and print its spans as they show up, the new ones highlighted,
+/// until neither its spans nor its duration change for `stable_polls` polls
+pub fn watch_trace(trace_id: &str, interval: Duration, stable_polls: usize) {
+    let settings = Settings::read();
+    let mut reader = reader_from_settings(&settings);
+    let mut seen = HashSet::new();
+    let mut last_duration = None;
+    let mut stable = 0;
+    loop {
+        match reader.get_trace_from_base_id(trace_id) {
+            Ok(trace) => {
+                let spans = spans(&trace);
+                let new = spans.iter().filter(|s| !seen.contains(&s.id)).count();
+                println!(
+                    "{}: {} spans ({} new), {:?} so far",
+                    chrono::Local::now().format("%H:%M:%S"),
+                    spans.len(),
+                    new,
+                    trace.duration
+                );
+                if new > 0 {
+                    let origin = trace.g[trace.start_node].timestamp;
+                    let mut depths = HashMap::new();
+                    for span in &spans {
+                        let depth = span.parent.and_then(|p| depths.get(&p)).map_or(0, |d| d + 1);
+                        depths.insert(span.id, depth);
+                        let line = format!(
+                            "{}{} on {} at +{}ms for {}ms",
+                            "  ".repeat(depth),
+                            span.name,
+                            span.host,
+                            (span.start - origin).num_milliseconds(),
+                            (span.end - span.start).num_milliseconds()
+                        );
+                        if seen.insert(span.id) {
+                            println!("\x1b[1;32m+ {}\x1b[0m", line);
+                        } else {
+                            println!("  {}", line);
+                        }
+                    }
+                }
+                if new == 0 && last_duration == Some(trace.duration) {
+                    stable += 1;
+                } else {
+                    stable = 0;
+                }
+                if stable >= stable_polls {
+                    println!("No changes in {} polls, done", stable);
+                    return;
+                }
+                last_duration = Some(trace.duration);
+            }
+            Err(e) => warn!("Could not read the trace yet: {}", e),
+        }
+        std::thread::sleep(interval);
+    }
+}
+
+/// Print the part of a trace between two tracepoints, or inside a span if `to` is None and `from`
+/// is a span id
+pub fn slice_trace(
+    trace_id: &str,
+    from: &str,
+    to: Option<&str>,
+    dot_style: Option<GraphStyle>,
+    format: OutputFormat,
+) {
+    let settings = Settings::read();
+    let mut reader = reader_from_settings(&settings);
+    let trace = reader.get_trace_from_base_id(trace_id).unwrap();
+    let slice = match to {
+        Some(to) => {
+            trace.subtrace_between(TracepointID::from_str(from), TracepointID::from_str(to))
+        }
+        None => trace.subtrace_of_span(Uuid::parse_str(from).expect("Expected a span id")),
+    };
+    let slice = match slice {
+        Some(s) => s,
+        None => {
+            warn!(trace_id:% = trace_id; "Nothing between {} and {:?}", from, to);
+            return;
+        }
+    };
+    match (format, dot_style) {
+        (OutputFormat::Text, Some(style)) => print!("{}", trace_graph(&slice, &style)),
+        (OutputFormat::Text, None) => println!("{}", slice),
+        (OutputFormat::Json, _) => print_json(&slice),
+    }
+}
+
+pub fn export_trace(trace_id: &str, span_format: SpanFormat, to_file: bool) {
+    let settings = Settings::read();
+    let mut reader = reader_from_settings(&settings);
+
+    let trace = reader.get_trace_from_base_id(trace_id).unwrap();
+    let exported = serde_json::to_string_pretty(&export_spans(&trace, span_format)).unwrap();
+
+    if to_file {
+        let mut tracefile = dirs::home_dir().unwrap();
+        tracefile.push(format!("{}_{:?}", trace_id, span_format).to_lowercase());
+        tracefile.set_extension("json");
+        std::fs::write(&tracefile, exported).unwrap();
+        info!("Wrote trace to {}", tracefile.to_str().unwrap());
+    } else {
+        println!("{}", exported);
+    }
+}
+
+/// Flamegraph of the critical path of a trace, or of the traces retained for a group
+pub fn flamegraph(id: &str, svg: bool) {
+    let settings = Settings::read();
+    let traces = if Uuid::parse_str(id).is_ok() {
+        let mut reader = reader_from_settings(&settings);
+        vec![reader.get_trace_from_base_id(id).unwrap()]
+    } else {
+        let dir = settings
+            .retention_dir
+            .expect("Trace retention is disabled, set retention_dir");
+        retention::retained_traces(&dir, id)
+    };
+    if traces.is_empty() {
+        warn!("No traces were retained for {}", id);
+        return;
+    }
+    let mut flamegraph = Flamegraph::new();
+    for trace in &traces {
+        match CriticalPath::from_trace(trace) {
+            Ok(path) => flamegraph.add_path(&path),
+            Err(e) => warn!(trace_id:% = trace.base_id; "Skipping trace: {}", e),
+        }
+    }
+    if svg {
+        print!("{}", flamegraph.svg(&format!("{} ({} traces)", id, traces.len())));
+    } else {
+        print!("{}", flamegraph.collapsed());
     }
 }
 
@@ -612,26 +1700,250 @@ pub fn show_key_value_pairs(trace_id: &str) {
     println!("{:?}", trace_id);
 }
 
-pub fn get_crit(trace_id: &str) {
+pub fn get_crit(trace_id: &str, format: OutputFormat) {
     let settings = Settings::read();
     let mut reader = reader_from_settings(&settings);
     let trace = reader.get_trace_from_base_id(trace_id).unwrap();
     let crit = CriticalPath::from_trace(&trace).unwrap();
-    println!("{}", crit.g);
+    match format {
+        OutputFormat::Text => println!("{}", crit.g),
+        OutputFormat::Json => print_json(&crit),
+    }
 }
 
-pub fn show_config() {
+pub fn show_retained_traces(group_hash: &str, show: bool, format: OutputFormat) {
     let settings = Settings::read();
-    println!("{:?}", settings);
+    let dir = settings
+        .retention_dir
+        .expect("Trace retention is disabled, set retention_dir");
+    let traces = retention::retained_traces(&dir, group_hash);
+    if traces.len() == 0 {
+        warn!(group_hash:% = group_hash; "No traces were retained for the group");
+    }
+    if format == OutputFormat::Json {
+        if show {
+            print_json(&traces);
+        } else {
+            print_json(
+                &traces
+                    .iter()
+                    .map(|t| {
+                        serde_json::json!({
+                            "base_id": t.base_id,
+                            "duration_us": t.duration.as_micros() as u64,
+                            "is_partial": t.is_partial,
+                        })
+                    })
+                    .collect::<Vec<_>>(),
+            );
+        }
+        return;
+    }
+    for trace in traces {
+        if show {
+            println!("{}", trace);
+        } else {
+            println!(
+                "{} {:?}{}",
+                trace.base_id,
+                trace.duration,
+                if trace.is_partial { " (partial)" } else { "" }
+            );
+        }
+    }
 }
 
-#[derive(Debug)]
-pub struct PythiaError(String);
+/// Print the enable/disable history from the audit log, optionally only the records of one group
+/// or those that touched one tracepoint
+pub fn show_audit_log(group: Option<&str>, tracepoint: Option<&str>, format: OutputFormat) {
+    let settings = Settings::read();
+    let log = settings
+        .audit_log
+        .as_ref()
+        .expect("Auditing is disabled, set audit_log");
+    let tracepoint = tracepoint.map(TracepointID::from_str);
+    let records = AuditLog::from_str(log)
+        .read()
+        .unwrap_or_else(|e| panic!("Could not read audit log {}: {}", log, e))
+        .into_iter()
+        .filter(|r| group.map_or(true, |g| r.group.as_deref() == Some(g)))
+        .filter(|r| tracepoint.map_or(true, |t| r.tracepoints.iter().any(|p| p.0 == t)))
+        .collect::<Vec<_>>();
+    if format == OutputFormat::Json {
+        print_json(&records);
+        return;
+    }
+    for record in records {
+        println!(
+            "{} {:<11} {:>5} tracepoints  group {}  strategy {}",
+            record.timestamp.format("%Y-%m-%d %H:%M:%S"),
+            format!("{:?}", record.action),
+            record.tracepoints.len(),
+            record.group.as_deref().unwrap_or("-"),
+            record.strategy
+        );
+        for (tp, request_type) in &record.tracepoints {
+            match request_type {
+                Some(rt) => println!("    {} [{}]", tp, rt),
+                None => println!("    {}", tp),
+            }
+        }
+    }
+}
+
+/// Print the tracepoints enabled for a group and the groups they refined it into, recursively
+pub fn show_lineage(group: &str, format: OutputFormat) {
+    let settings = Settings::read();
+    let path = settings
+        .lineage_file
+        .as_ref()
+        .expect("Lineage is not kept, set lineage_file");
+    let lineage = Lineage::load(path)
+        .unwrap_or_else(|e| panic!("Could not read lineage from {:?}: {}", path, e));
+    let tree = lineage.tree(group);
+    if format == OutputFormat::Json {
+        print_json(&tree);
+        return;
+    }
+    if let Some((parent, step)) = lineage.parent(group) {
+        println!(
+            "Refined from {} at cycle {} ({})",
+            parent, step.cycle, step.edge
+        );
+    }
+    print_lineage(&tree, 0);
+}
+
+fn print_lineage(tree: &LineageTree, depth: usize) {
+    let indent = "    ".repeat(depth);
+    println!("{}{}", indent, tree.group);
+    for (step, children) in &tree.steps {
+        println!(
+            "{}  cycle {}: {}, enabled {} tracepoints, {} refined groups",
+            indent,
+            step.cycle,
+            step.edge,
+            step.tracepoints.len(),
+            children.len()
+        );
+        for child in children {
+            print_lineage(child, depth + 1);
+        }
+    }
+}
+
+/// Compare each epoch written to `epoch_dir` with the one before it
+pub fn instrumentation_impact(format: OutputFormat) {
+    let settings = Settings::read();
+    let dir = settings
+        .epoch_dir
+        .as_ref()
+        .expect("Epochs are not kept, set epoch_dir");
+    let reports = compare_all(&read_epochs(dir), settings.impact_alpha);
+    if format == OutputFormat::Json {
+        print_json(&reports);
+        return;
+    }
+    for report in &reports {
+        println!("{}", report);
+        for (tp, request_type) in &report.added {
+            match request_type {
+                Some(rt) => println!("    {} [{}]", tp, rt),
+                None => println!("    {}", tp),
+            }
+        }
+    }
+    println!(
+        "{} of {} changes increased latency",
+        reports.iter().filter(|r| r.increased).count(),
+        reports.len()
+    );
+}
 
-impl fmt::Display for PythiaError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Pythia error: {}", self.0)
+/// Ping every configured agent and print its version and health
+pub fn agents_status(format: OutputFormat) {
+    let settings = Settings::read();
+    // Agents that are down should be reported, not retried
+    let policy = RetryPolicy {
+        retries: 0,
+        ..RetryPolicy::from_settings(&settings)
+    };
+    let mut statuses = Vec::new();
+    for agent in &settings.pythia_clients {
+        let start = Instant::now();
+        let status = ping_client(agent, &policy)
+            .map(|_| start.elapsed())
+            .and_then(|ping| read_client_health(agent, &policy).map(|h| (ping, h)));
+        statuses.push((agent, status));
+    }
+    if format == OutputFormat::Json {
+        print_json(
+            &statuses
+                .iter()
+                .map(|(agent, status)| match status {
+                    Ok((ping, health)) => serde_json::json!({
+                        "agent": agent,
+                        "ping_ms": ping.as_secs_f64() * 1000.0,
+                        "health": health,
+                        "problems": health.problems(),
+                    }),
+                    Err(e) => serde_json::json!({
+                        "agent": agent,
+                        "error": e.to_string(),
+                    }),
+                })
+                .collect::<Vec<_>>(),
+        );
+        return;
     }
+    println!(
+        "{:<30} {:<20} {:>8} {:>10} {:>12}  status",
+        "agent", "host", "ping_ms", "version", "free_disk_gb"
+    );
+    for (agent, status) in statuses {
+        match status {
+            Ok((ping, health)) => {
+                let problems = health.problems();
+                println!(
+                    "{:<30} {:<20} {:>8.1} {:>10} {:>12}  {}",
+                    agent,
+                    health.host,
+                    ping.as_secs_f64() * 1000.0,
+                    health.version,
+                    health.free_disk_bytes.map_or("?".to_string(), |b| format!(
+                        "{:.1}",
+                        b as f64 / 1024.0 / 1024.0 / 1024.0
+                    )),
+                    if problems.is_empty() {
+                        "ok".to_string()
+                    } else {
+                        problems.join("; ")
+                    }
+                );
+            }
+            Err(e) => println!(
+                "{:<30} {:<20} {:>8} {:>10} {:>12}  unreachable: {}",
+                agent, "?", "-", "?", "?", e
+            ),
+        }
+    }
+}
+
+pub fn show_config() {
+    let settings = Settings::read();
+    println!("{:?}", settings);
 }
 
-impl Error for PythiaError {}
+/// Print every problem with the settings; exits with an error if there are any
+pub fn check_config(check_redis: bool) {
+    let settings = Settings::read();
+    match settings.validate(check_redis) {
+        Ok(()) => println!("Settings are valid"),
+        Err(problems) => {
+            for p in &problems {
+                println!("{}", p);
+            }
+            std::process::exit(1);
+        }
+    }
+}
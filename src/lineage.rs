@@ -0,0 +1,299 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Which tracepoints were enabled for each group, and which groups came out of it.
+//!
+//! Once tracepoints are enabled for a problem edge, later requests of the group take a longer
+//! path and land in new, refined groups. For every group the controller records each diagnosis
+//! step (the cycle, the edge, the tracepoints enabled) along with the groups that appeared
+//! afterwards with the group's path plus some of those tracepoints. The lineage is written to
+//! `lineage_file`, read back when the controller restarts, and `pythia lineage <group-hash>`
+//! prints it as a tree. Only the `MAX_GROUPS` groups diagnosed last are kept.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::fs::rename;
+use std::fs::File;
+use std::path::Path;
+
+use petgraph::graph::EdgeIndex;
+use serde::{Deserialize, Serialize};
+
+use pythia_common::PythiaError;
+use pythia_common::RequestType;
+
+use crate::critical::Path as _;
+use crate::grouping::Group;
+use crate::trace::TracepointID;
+
+/// Groups with diagnosis steps kept in the lineage
+const MAX_GROUPS: usize = 10000;
+
+/// Tracepoints enabled for one problem edge of a group
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LineageStep {
+    /// Decision cycle the tracepoints were enabled in
+    pub cycle: usize,
+    /// The problem edge, as `source -> target`
+    pub edge: String,
+    pub tracepoints: Vec<TracepointID>,
+    /// Hashes of the groups the step refined the group into
+    pub children: Vec<String>,
+}
+
+/// A group and, for each of its steps, the trees of the groups it was refined into
+#[derive(Serialize, Debug, Clone)]
+pub struct LineageTree {
+    pub group: String,
+    pub steps: Vec<(LineageStep, Vec<LineageTree>)>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Lineage {
+    steps: BTreeMap<String, Vec<LineageStep>>,
+    /// Request type and path of the groups with steps, to find their children
+    #[serde(default)]
+    paths: HashMap<String, (RequestType, Vec<TracepointID>)>,
+    /// Groups with steps, by when their last step was recorded
+    #[serde(default)]
+    order: VecDeque<String>,
+    /// Groups that existed at the last `observe`; only new groups can be children
+    #[serde(skip)]
+    seen: HashSet<String>,
+}
+
+impl Lineage {
+    pub fn new() -> Self {
+        Lineage::default()
+    }
+
+    pub fn load(path: &Path) -> Result<Lineage, PythiaError> {
+        let mut lineage: Lineage = serde_json::from_reader(File::open(path)?)?;
+        // Files written before the order was recorded have their groups forgotten first
+        let ordered: HashSet<String> = lineage.order.iter().cloned().collect();
+        for group in lineage.steps.keys().rev() {
+            if !ordered.contains(group) {
+                lineage.order.push_front(group.clone());
+            }
+        }
+        Ok(lineage)
+    }
+
+    /// Writes to a temporary file first, so a crash doesn't leave a truncated lineage
+    pub fn save(&self, path: &Path) -> Result<(), PythiaError> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        serde_json::to_writer(File::create(&tmp)?, self)?;
+        rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Record that `tracepoints` were enabled for `edge` of the group
+    pub fn diagnosed(
+        &mut self,
+        cycle: usize,
+        group: &Group,
+        edge: EdgeIndex,
+        tracepoints: Vec<TracepointID>,
+    ) {
+        let (source, target) = group.g.edge_endpoints(edge).unwrap();
+        let step = LineageStep {
+            cycle,
+            edge: format!(
+                "{} -> {}",
+                group.g[source].tracepoint_id, group.g[target].tracepoint_id
+            ),
+            tracepoints,
+            children: Vec::new(),
+        };
+        self.add_step(group.hash(), group.request_type, group.sequence(), step);
+    }
+
+    fn add_step(
+        &mut self,
+        group: &str,
+        request_type: RequestType,
+        sequence: Vec<TracepointID>,
+        step: LineageStep,
+    ) {
+        self.paths
+            .entry(group.to_string())
+            .or_insert((request_type, sequence));
+        self.steps.entry(group.to_string()).or_default().push(step);
+        self.order.retain(|g| g != group);
+        self.order.push_back(group.to_string());
+        while self.order.len() > MAX_GROUPS {
+            let oldest = self.order.pop_front().unwrap();
+            self.steps.remove(&oldest);
+            self.paths.remove(&oldest);
+        }
+    }
+
+    /// Attribute the groups that appeared since the last call to the steps that refined them.
+    /// A new group is the child of the most refined group whose path it contains, through the
+    /// latest step with a tracepoint on its path. Returns how many children were found.
+    pub fn observe(&mut self, groups: &[&Group]) -> usize {
+        let mut found = 0;
+        let mut current = HashSet::new();
+        for group in groups {
+            current.insert(group.hash().to_string());
+            if !self.seen.contains(group.hash())
+                && self.attribute(group.hash(), group.request_type, &group.sequence())
+            {
+                found += 1;
+            }
+        }
+        // A group that was dropped and comes back is new again, but not a new child
+        self.seen = current;
+        found
+    }
+
+    fn attribute(
+        &mut self,
+        group: &str,
+        request_type: RequestType,
+        sequence: &[TracepointID],
+    ) -> bool {
+        let on_path: HashSet<&TracepointID> = sequence.iter().collect();
+        let parent = self
+            .paths
+            .iter()
+            .filter(|(hash, (rt, path))| {
+                *hash != group
+                    && *rt == request_type
+                    && path.len() < sequence.len()
+                    && is_subsequence(path, sequence)
+            })
+            .max_by_key(|(hash, (_, path))| (path.len(), hash.to_string()))
+            .map(|(hash, _)| hash.clone());
+        let step = parent.and_then(|parent| {
+            self.steps
+                .get_mut(&parent)?
+                .iter_mut()
+                .rev()
+                .find(|step| step.tracepoints.iter().any(|tp| on_path.contains(tp)))
+        });
+        match step {
+            Some(step) if step.children.iter().any(|c| c == group) => false,
+            Some(step) => {
+                step.children.push(group.to_string());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The diagnosis tree starting at the group
+    pub fn tree(&self, group: &str) -> LineageTree {
+        self.subtree(group, &mut HashSet::new())
+    }
+
+    fn subtree(&self, group: &str, visited: &mut HashSet<String>) -> LineageTree {
+        visited.insert(group.to_string());
+        let mut steps = Vec::new();
+        for step in self.steps.get(group).into_iter().flatten() {
+            let children = step
+                .children
+                .iter()
+                .filter(|child| !visited.contains(*child))
+                .cloned()
+                .collect::<Vec<_>>();
+            let children = children
+                .iter()
+                .map(|child| self.subtree(child, visited))
+                .collect();
+            steps.push((step.clone(), children));
+        }
+        LineageTree {
+            group: group.to_string(),
+            steps,
+        }
+    }
+
+    /// The group and step the group was refined from, if any
+    pub fn parent(&self, group: &str) -> Option<(&str, &LineageStep)> {
+        self.steps.iter().find_map(|(parent, steps)| {
+            steps
+                .iter()
+                .find(|step| step.children.iter().any(|c| c == group))
+                .map(|step| (parent.as_str(), step))
+        })
+    }
+}
+
+fn is_subsequence(short: &[TracepointID], long: &[TracepointID]) -> bool {
+    let mut long = long.iter();
+    short.iter().all(|tp| long.any(|l| l == tp))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(names: &[&str]) -> Vec<TracepointID> {
+        names.iter().map(|n| TracepointID::from_str(n)).collect()
+    }
+
+    fn step(cycle: usize, tracepoints: &[&str]) -> LineageStep {
+        LineageStep {
+            cycle,
+            edge: "a -> d".to_string(),
+            tracepoints: path(tracepoints),
+            children: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn finds_refined_groups() {
+        let create = RequestType::from_str("ServerCreate").unwrap();
+        let delete = RequestType::from_str("ServerDelete").unwrap();
+        let mut lineage = Lineage::new();
+        lineage.add_step("parent", create, path(&["a", "d"]), step(1, &["b"]));
+        lineage.add_step("parent", create, path(&["a", "d"]), step(2, &["x"]));
+        assert!(lineage.attribute("child", create, &path(&["a", "b", "d"])));
+        assert!(!lineage.attribute("other", delete, &path(&["a", "b", "d"])));
+        assert!(!lineage.attribute("unrelated", create, &path(&["a", "b", "e"])));
+
+        // The most refined group a path contains is its parent
+        lineage.add_step("child", create, path(&["a", "b", "d"]), step(3, &["c"]));
+        assert!(lineage.attribute("grandchild", create, &path(&["a", "b", "c", "d"])));
+        let tree = lineage.tree("parent");
+        assert_eq!(tree.steps[0].1[0].group, "child");
+        assert!(tree.steps[1].1.is_empty());
+        assert_eq!(tree.steps[0].1[0].steps[0].1[0].group, "grandchild");
+        assert_eq!(lineage.parent("grandchild").unwrap().1.cycle, 3);
+        assert!(!lineage.attribute("grandchild", create, &path(&["a", "b", "c", "d"])));
+    }
+
+    #[test]
+    fn keeps_the_groups_diagnosed_last() {
+        let create = RequestType::from_str("ServerCreate").unwrap();
+        let mut lineage = Lineage::new();
+        for i in 0..MAX_GROUPS + 1 {
+            let group = format!("group-{}", i);
+            lineage.add_step(&group, create, path(&["a", "d"]), step(i, &["b"]));
+        }
+        lineage.add_step("group-1", create, path(&["a", "d"]), step(0, &["c"]));
+        lineage.add_step("new", create, path(&["a", "d"]), step(0, &["c"]));
+        assert_eq!(lineage.steps.len(), MAX_GROUPS);
+        assert_eq!(lineage.paths.len(), MAX_GROUPS);
+        assert!(!lineage.steps.contains_key("group-0"));
+        assert!(!lineage.steps.contains_key("group-2"));
+        assert!(lineage.steps.contains_key("group-1"));
+
+        let file = std::env::temp_dir().join(format!("lineage-{}.json", std::process::id()));
+        lineage.save(&file).unwrap();
+        let mut loaded = Lineage::load(&file).unwrap();
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(loaded.order, lineage.order);
+        // Children are still found after a restart
+        assert!(loaded.attribute("child", create, &path(&["a", "c", "d"])));
+    }
+}
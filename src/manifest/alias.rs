@@ -0,0 +1,178 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Aliasing of tracepoint IDs across code versions.
+//!
+//! OpenStack tracepoint IDs look like `path:line:function`. When the application is upgraded the
+//! line numbers shift, so a manifest built from old traces stops matching new ones. An
+//! `AliasMap` pairs old IDs with new IDs that have the same file and function and a nearby line.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::grouping::Group;
+use crate::trace::TracepointID;
+
+/// How many lines a tracepoint may move between versions and still be aliased
+const MAX_LINE_DRIFT: usize = 200;
+
+/// Maps tracepoint IDs of an old code version to the current one
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AliasMap {
+    old_to_new: HashMap<TracepointID, TracepointID>,
+}
+
+/// Splits `path:line:function`; None if the ID isn't in this format
+fn split_id(id: &TracepointID) -> Option<(String, usize, String)> {
+    let id = id.to_string();
+    let mut parts = id.splitn(3, ':');
+    let file = parts.next()?.to_string();
+    let line = parts.next()?.parse().ok()?;
+    let function = parts.next()?.to_string();
+    Some((file, line, function))
+}
+
+impl AliasMap {
+    /// Pairs each old tracepoint that is missing from `new` with the new tracepoint in the same
+    /// file and function with the closest line number.
+    ///
+    /// Pairs are assigned closest first, and each new tracepoint is used at most once.
+    pub fn build(old: &HashSet<TracepointID>, new: &HashSet<TracepointID>) -> AliasMap {
+        let mut candidates: HashMap<(String, String), Vec<(usize, TracepointID)>> = HashMap::new();
+        for &tp in new.difference(old) {
+            if let Some((file, line, function)) = split_id(&tp) {
+                candidates
+                    .entry((file, function))
+                    .or_insert_with(Vec::new)
+                    .push((line, tp));
+            }
+        }
+        let mut pairs = Vec::new();
+        for &tp in old.difference(new) {
+            let (file, line, function) = match split_id(&tp) {
+                Some(parts) => parts,
+                None => continue,
+            };
+            if let Some(options) = candidates.get(&(file, function)) {
+                for &(new_line, new_tp) in options {
+                    let drift = if line > new_line {
+                        line - new_line
+                    } else {
+                        new_line - line
+                    };
+                    if drift <= MAX_LINE_DRIFT {
+                        pairs.push((drift, tp, new_tp));
+                    }
+                }
+            }
+        }
+        pairs.sort_by_key(|&(drift, old_tp, new_tp)| {
+            (drift, old_tp.to_string(), new_tp.to_string())
+        });
+        let mut result = AliasMap::default();
+        let mut used = HashSet::new();
+        for (_, old_tp, new_tp) in pairs {
+            if result.old_to_new.contains_key(&old_tp) || used.contains(&new_tp) {
+                continue;
+            }
+            used.insert(new_tp);
+            result.old_to_new.insert(old_tp, new_tp);
+        }
+        result
+    }
+
+    /// The current ID of the tracepoint; itself if it has no alias
+    pub fn resolve(&self, tp: TracepointID) -> TracepointID {
+        *self.old_to_new.get(&tp).unwrap_or(&tp)
+    }
+
+    pub fn len(&self) -> usize {
+        self.old_to_new.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.old_to_new.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&TracepointID, &TracepointID)> {
+        self.old_to_new.iter()
+    }
+
+    /// Returns a copy of the group with old tracepoint IDs replaced, or None if the group
+    /// doesn't contain any aliased tracepoints.
+    ///
+    /// Traces may still come from hosts that run the old code, so groups are normalized before
+    /// matching against the manifest.
+    pub fn resolve_group(&self, group: &Group) -> Option<Group> {
+        if !group
+            .g
+            .node_indices()
+            .any(|n| self.old_to_new.contains_key(&group.g[n].tracepoint_id))
+        {
+            return None;
+        }
+        let mut result = group.clone();
+        for n in group.g.node_indices() {
+            result.g[n].tracepoint_id = self.resolve(group.g[n].tracepoint_id);
+        }
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tps(ids: &[&str]) -> HashSet<TracepointID> {
+        ids.iter().map(|s| TracepointID::from_str(s)).collect()
+    }
+
+    #[test]
+    fn closest_line_wins() {
+        let old = tps(&[
+            "nova/compute/manager.py:1859:nova.compute.manager.ComputeManager._update",
+            "nova/compute/manager.py:1900:nova.compute.manager.ComputeManager._update",
+            "nova/api.py:10:nova.api.unchanged",
+        ]);
+        let new = tps(&[
+            "nova/compute/manager.py:1866:nova.compute.manager.ComputeManager._update",
+            "nova/compute/manager.py:1911:nova.compute.manager.ComputeManager._update",
+            "nova/compute/manager.py:1870:nova.compute.manager.ComputeManager.other",
+            "nova/api.py:10:nova.api.unchanged",
+        ]);
+        let aliases = AliasMap::build(&old, &new);
+        assert_eq!(aliases.len(), 2);
+        assert_eq!(
+            aliases.resolve(TracepointID::from_str(
+                "nova/compute/manager.py:1859:nova.compute.manager.ComputeManager._update"
+            )),
+            TracepointID::from_str(
+                "nova/compute/manager.py:1866:nova.compute.manager.ComputeManager._update"
+            )
+        );
+        assert_eq!(
+            aliases.resolve(TracepointID::from_str(
+                "nova/compute/manager.py:1900:nova.compute.manager.ComputeManager._update"
+            )),
+            TracepointID::from_str(
+                "nova/compute/manager.py:1911:nova.compute.manager.ComputeManager._update"
+            )
+        );
+        let unchanged = TracepointID::from_str("nova/api.py:10:nova.api.unchanged");
+        assert_eq!(aliases.resolve(unchanged), unchanged);
+    }
+
+    #[test]
+    fn far_lines_are_not_aliased() {
+        let old = tps(&["a.py:10:a.f"]);
+        let new = tps(&["a.py:5000:a.f", "b.py:11:a.f"]);
+        assert!(AliasMap::build(&old, &new).is_empty());
+    }
+}
@@ -0,0 +1,102 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Request types for traces whose tracepoints don't match any request type regex.
+//!
+//! Each request type's search space gets a score for the trace's critical path: the share of
+//! the path's tracepoints it has seen, plus one if one of its paths contains the whole path. The
+//! best type wins, and its share of the total score is the confidence.
+
+use std::collections::HashSet;
+
+use pythia_common::RequestType;
+
+use crate::critical::Path;
+use crate::grouping::Group;
+use crate::manifest::Manifest;
+use crate::trace::TracepointID;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Classification {
+    pub request_type: RequestType,
+    /// In (0, 1]; 1 if no other request type has any of the tracepoints
+    pub confidence: f64,
+}
+
+impl Classification {
+    /// None if no request type has seen any of the group's tracepoints
+    pub fn compute(manifest: &Manifest, group: &Group) -> Option<Classification> {
+        let resolved = manifest.aliases.resolve_group(group);
+        let group = resolved.as_ref().unwrap_or(group);
+        let tracepoints: HashSet<TracepointID> =
+            group.g.node_indices().map(|n| group.at(n)).collect();
+        let mut scores: Vec<(RequestType, f64)> = manifest
+            .per_request_type
+            .iter()
+            .filter(|(&rt, _)| rt != RequestType::Unknown)
+            .map(|(&rt, ss)| {
+                let seen = tracepoints
+                    .iter()
+                    .filter(|&&tp| ss.has_tracepoint(tp))
+                    .count();
+                let mut score = seen as f64 / tracepoints.len().max(1) as f64;
+                // Only a space that has seen every tracepoint can contain the path
+                if seen == tracepoints.len() && !ss.find_matches(group, true).is_empty() {
+                    score += 1.0;
+                }
+                (rt, score)
+            })
+            .collect();
+        // The first of equally good types by name, so the result doesn't change between runs
+        scores.sort_by_key(|(rt, _)| rt.to_string());
+        let total: f64 = scores.iter().map(|(_, score)| score).sum();
+        let &(request_type, best) = scores
+            .iter()
+            .rev()
+            .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap())?;
+        if best == 0.0 {
+            return None;
+        }
+        Some(Classification {
+            request_type,
+            confidence: best / total,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::critical::CriticalPath;
+    use crate::testutils::TraceGenerator;
+
+    #[test]
+    fn finds_the_type_that_fits() {
+        let types = RequestType::all();
+        let mut generator = TraceGenerator::new(5);
+        generator.max_depth = 2;
+        generator.concurrency = 0.0;
+        // Few shared tracepoints, so each trace only fits its own type
+        generator.tracepoints = 1000;
+        let mut traces = Vec::new();
+        for &rt in &types[..2] {
+            generator.request_types = vec![rt];
+            traces.extend((0..2).map(|_| generator.generate().trace));
+        }
+        let manifest = Manifest::from_trace_list(&traces);
+        for trace in &traces {
+            let mut path = CriticalPath::from_trace(trace).unwrap();
+            path.request_type = RequestType::Unknown;
+            let group = Group::from_critical_paths(vec![path]).remove(0);
+            let classification = manifest.classify(&group).unwrap();
+            assert_eq!(classification.request_type, trace.request_type);
+            assert!(classification.confidence > 0.5);
+        }
+    }
+}
@@ -0,0 +1,54 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! How many events each tracepoint adds to a request, measured on the profiling traces.
+
+use std::collections::HashMap;
+
+use petgraph::visit::IntoNodeReferences;
+use serde::{Deserialize, Serialize};
+
+use crate::manifest::AliasMap;
+use crate::trace::Trace;
+use crate::trace::TracepointID;
+
+/// Tracepoints that were never seen are assumed to fire once per request
+const UNKNOWN_COST: f64 = 1.0;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CostModel {
+    traces: usize,
+    /// Events of each tracepoint in all traces
+    events: HashMap<TracepointID, usize>,
+}
+
+impl CostModel {
+    pub fn add_trace(&mut self, trace: &Trace) {
+        self.traces += 1;
+        for (_, event) in trace.g.node_references() {
+            *self.events.entry(event.tracepoint_id).or_insert(0) += 1;
+        }
+    }
+
+    /// Average number of events of the tracepoint per request, over all request types
+    pub fn events_per_request(&self, tracepoint: TracepointID) -> f64 {
+        match self.events.get(&tracepoint) {
+            Some(&events) => events as f64 / self.traces as f64,
+            None => UNKNOWN_COST,
+        }
+    }
+
+    /// Moves the events of renamed tracepoints to their new names
+    pub fn apply_aliases(&mut self, aliases: &AliasMap) {
+        let mut events = HashMap::new();
+        for (tp, count) in self.events.drain() {
+            *events.entry(aliases.resolve(tp)).or_insert(0) += count;
+        }
+        self.events = events;
+    }
+}
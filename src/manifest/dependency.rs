@@ -0,0 +1,108 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Which tracepoints have to be enabled for another one to emit events.
+//!
+//! A nested span is only recorded while the spans around it are, so a tracepoint depends on the
+//! entry tracepoints of the spans that enclose it on every manifest path it is on. A tracepoint
+//! that is on no path of the request type never emits events for it.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use pythia_common::RequestType;
+
+use crate::critical::Path;
+use crate::manifest::HierarchicalCriticalPath;
+use crate::manifest::Manifest;
+use crate::trace::EventType;
+use crate::trace::TracepointID;
+
+#[derive(Debug, Clone, Default)]
+pub struct TracepointDependencies {
+    /// Enclosing tracepoints, outermost first, for each request type and for all of them
+    prerequisites: HashMap<(TracepointID, Option<RequestType>), Vec<TracepointID>>,
+}
+
+impl TracepointDependencies {
+    pub fn compute(manifest: &Manifest) -> TracepointDependencies {
+        let mut result = TracepointDependencies::default();
+        for (&rt, ss) in manifest.per_request_type.iter() {
+            for path in ss.paths.values() {
+                for (tp, mut enclosing) in enclosing_spans(path) {
+                    // Recursive spans don't depend on themselves
+                    enclosing.retain(|&e| e != tp);
+                    result.add((tp, Some(rt)), enclosing.clone());
+                    result.add((tp, None), enclosing);
+                }
+            }
+        }
+        result
+    }
+
+    /// Adds the dependencies of another application's manifest
+    pub fn extend(&mut self, other: TracepointDependencies) {
+        for (point, enclosing) in other.prerequisites {
+            self.add(point, enclosing);
+        }
+    }
+
+    /// Only the tracepoints that enclose it every time are kept
+    fn add(&mut self, point: (TracepointID, Option<RequestType>), enclosing: Vec<TracepointID>) {
+        match self.prerequisites.get_mut(&point) {
+            Some(known) => {
+                let seen: HashSet<TracepointID> = enclosing.into_iter().collect();
+                known.retain(|tp| seen.contains(tp));
+            }
+            None => {
+                self.prerequisites.insert(point, enclosing);
+            }
+        }
+    }
+
+    /// Tracepoints that have to be enabled for the point to emit events, outermost first; None
+    /// if it is on no manifest path of the request type
+    pub fn prerequisites(
+        &self,
+        point: &(TracepointID, Option<RequestType>),
+    ) -> Option<&[TracepointID]> {
+        self.prerequisites.get(point).map(|p| p.as_slice())
+    }
+
+    /// Whether the second point needs the first one
+    pub fn depends_on(
+        &self,
+        point: &(TracepointID, Option<RequestType>),
+        prerequisite: TracepointID,
+    ) -> bool {
+        self.prerequisites(point)
+            .is_some_and(|p| p.contains(&prerequisite))
+    }
+}
+
+/// Each entry and annotation of the path with the entries of the spans around it
+fn enclosing_spans(path: &HierarchicalCriticalPath) -> Vec<(TracepointID, Vec<TracepointID>)> {
+    let mut result = Vec::new();
+    let mut context: Vec<TracepointID> = Vec::new();
+    let mut cur = Some(path.start_node);
+    while let Some(n) = cur {
+        let node = &path.g[n];
+        match node.variant {
+            EventType::Entry => {
+                result.push((node.tracepoint_id, context.clone()));
+                context.push(node.tracepoint_id);
+            }
+            EventType::Annotation => result.push((node.tracepoint_id, context.clone())),
+            EventType::Exit => {
+                context.pop();
+            }
+        }
+        cur = path.next_node(n);
+    }
+    result
+}
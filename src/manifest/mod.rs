@@ -9,35 +9,83 @@ All rights reserved.
 //! This module has the searchspace.
 //!
 //! Manifest has one SearchSpace per request type, and mostly relays functions to the relevant
-//! SearchSpace.
+//! SearchSpace. The paths matching each group are remembered until the manifest changes, since
+//! the same groups are diagnosed cycle after cycle.
+mod alias;
+mod classify;
+mod cost;
+mod dependency;
 mod searchspace;
+mod skeleton;
 
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::fmt;
 use std::fmt::Display;
 use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
 use std::time::Duration;
 use std::time::Instant;
 
+use log::{debug, error, warn};
+use petgraph::graph::EdgeIndex;
+use petgraph::graph::NodeIndex;
 use petgraph::visit::IntoNodeReferences;
 use petgraph::visit::NodeRef;
 use serde::{Deserialize, Serialize};
 
 use pythia_common::RequestType;
-use pythia_common::REQUEST_TYPE_REGEXES;
 
+use crate::critical::Path as _;
+use crate::critical::PathBudget;
 use crate::grouping::Group;
 use crate::manifest::searchspace::SearchSpace;
+use crate::progress::Progress;
 use crate::trace::Trace;
 use crate::trace::TracepointID;
 
+pub use crate::manifest::alias::AliasMap;
+pub use crate::manifest::classify::Classification;
+pub use crate::manifest::cost::CostModel;
+pub use crate::manifest::dependency::TracepointDependencies;
 pub use crate::manifest::searchspace::HierarchicalCriticalPath;
+pub use crate::manifest::skeleton::SkeletonReduction;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Manifest {
     pub per_request_type: HashMap<RequestType, SearchSpace>,
     pub request_type_tracepoints: Vec<TracepointID>,
+    /// Tracepoints renamed by `remap`, consulted when matching groups
+    #[serde(default)]
+    pub aliases: AliasMap,
+    /// Events per request of each tracepoint; empty in manifests from before it was measured
+    #[serde(default)]
+    pub costs: CostModel,
+    #[serde(skip)]
+    match_cache: MatchCache,
+}
+
+/// Request type and hash of the paths that matched each group, by group hash
+#[derive(Default)]
+struct MatchCache(Mutex<HashMap<String, Vec<(RequestType, String)>>>);
+
+impl MatchCache {
+    fn clear(&self) {
+        self.0.lock().unwrap().clear();
+    }
+}
+
+impl Clone for MatchCache {
+    fn clone(&self) -> Self {
+        MatchCache(Mutex::new(self.0.lock().unwrap().clone()))
+    }
+}
+
+impl fmt::Debug for MatchCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MatchCache({} groups)", self.0.lock().unwrap().len())
+    }
 }
 
 impl Manifest {
@@ -58,17 +106,33 @@ impl Manifest {
         result
     }
 
+    /// Paths that contain the group, the most common first. Cached by group hash until the
+    /// manifest changes.
     pub fn find_matches<'a>(&'a self, group: &Group) -> Vec<&'a HierarchicalCriticalPath> {
         let now = Instant::now();
-        let matches = if group.request_type == RequestType::Unknown {
+        let resolved = self.aliases.resolve_group(group);
+        let group = resolved.as_ref().unwrap_or(group);
+        if let Some(cached) = self.match_cache.0.lock().unwrap().get(group.hash()) {
+            return cached
+                .iter()
+                .filter_map(|(rt, hash)| self.per_request_type.get(rt)?.paths.get(hash))
+                .collect();
+        }
+        let matches: Vec<(RequestType, &HierarchicalCriticalPath)> = if group.request_type
+            == RequestType::Unknown
+        {
             let mut result = Vec::new();
-            for ss in self.per_request_type.values() {
-                result.extend(ss.find_matches(group, false).iter());
+            for (&rt, ss) in self.per_request_type.iter() {
+                result.extend(ss.find_matches(group, false).into_iter().map(|p| (rt, p)));
             }
             result
         } else {
             match self.per_request_type.get(&group.request_type) {
-                Some(ss) => ss.find_matches(group, false),
+                Some(ss) => ss
+                    .find_matches(group, false)
+                    .into_iter()
+                    .map(|p| (group.request_type, p))
+                    .collect(),
                 None => {
                     panic!(
                         "Request type {:?} not present in manifest",
@@ -77,30 +141,97 @@ impl Manifest {
                 }
             }
         };
-        eprintln!(
+        debug!(
             "Finding {} matching groups took {}, group size {}",
             matches.len(),
             now.elapsed().as_micros(),
             group.g.node_count()
         );
         if matches.len() == 0 {
-            println!(
+            warn!(
                 "No critical path matches the group {}:\n{}",
                 group,
                 group.dot()
             );
         }
-        matches
+        self.match_cache.0.lock().unwrap().insert(
+            group.hash().to_string(),
+            matches
+                .iter()
+                .map(|(rt, p)| (*rt, p.hash().to_string()))
+                .collect(),
+        );
+        matches.into_iter().map(|(_, p)| p).collect()
+    }
+
+    /// Tracepoints of the paths matching the group that are between the ends of the edge and not
+    /// `enabled` for the group's request type, i.e. what could still be enabled to split it
+    pub fn tracepoints_between(
+        &self,
+        group: &Group,
+        edge: EdgeIndex,
+        enabled: &HashSet<(TracepointID, Option<RequestType>)>,
+    ) -> HashSet<TracepointID> {
+        let (source, target) = group.g.edge_endpoints(edge).unwrap();
+        let source = self.aliases.resolve(group.g[source].tracepoint_id);
+        let target = self.aliases.resolve(group.g[target].tracepoint_id);
+        let mut result = HashSet::new();
+        for path in self.find_matches(group) {
+            let mut between: Option<Vec<TracepointID>> = None;
+            let mut node = Some(path.start_node);
+            while let Some(n) = node {
+                let tracepoint = path.g[n].tracepoint_id;
+                if tracepoint == target && between.is_some() {
+                    result.extend(between.take().unwrap());
+                    break;
+                }
+                if tracepoint == source {
+                    between = Some(Vec::new());
+                } else if let Some(b) = between.as_mut() {
+                    b.push(tracepoint);
+                }
+                node = path.next_node(n);
+            }
+        }
+        result.retain(|&tp| {
+            !enabled.contains(&(tp, None)) && !enabled.contains(&(tp, Some(group.request_type)))
+        });
+        result
+    }
+
+    /// The hierarchy of the group's nodes, taken from the most common manifest path that
+    /// contains it, for groups whose traces are missing the tracepoints of the enclosing spans.
+    /// See `HierarchicalCriticalPath::align_contexts`.
+    pub fn reconstruct_hierarchy(
+        &self,
+        group: &Group,
+    ) -> Option<HashMap<NodeIndex, Vec<TracepointID>>> {
+        let resolved = self.aliases.resolve_group(group);
+        let aligned = resolved.as_ref().unwrap_or(group);
+        self.find_matches(group)
+            .into_iter()
+            .find_map(|path| path.align_contexts(aligned))
+    }
+
+    /// The request type of a group of traces with none, see `Classification`
+    pub fn classify(&self, group: &Group) -> Option<Classification> {
+        Classification::compute(self, group)
     }
 
     pub fn match_performance(&self, group: &Group) -> Duration {
         // Stats: base_id,trace_len,match_count,duration(us),best_match_len"
         let now = Instant::now();
-        let matches = self
-            .per_request_type
-            .get(&group.request_type)
-            .unwrap()
-            .find_matches(group, true);
+        let request_type = match self.per_request_type.get(&group.request_type) {
+            Some(_) => group.request_type,
+            None => match self.classify(group) {
+                Some(c) => c.request_type,
+                None => panic!(
+                    "No request type of the manifest fits {}:\n{}",
+                    group.traces[0].g.base_id, group
+                ),
+            },
+        };
+        let matches = self.per_request_type[&request_type].find_matches(group, true);
         if matches.len() == 0 {
             panic!(
                 "Found no match for {}:\n{}",
@@ -114,31 +245,67 @@ impl Manifest {
         Manifest {
             per_request_type: HashMap::new(),
             request_type_tracepoints: Vec::new(),
+            aliases: AliasMap::default(),
+            costs: CostModel::default(),
+            match_cache: MatchCache::default(),
         }
     }
 
     pub fn from_trace_list(traces: &Vec<Trace>) -> Manifest {
+        Manifest::from_trace_list_budgeted(traces, PathBudget::default())
+    }
+
+    /// Build a manifest, going through at most `budget` paths of each trace
+    pub fn from_trace_list_budgeted(traces: &Vec<Trace>, budget: PathBudget) -> Manifest {
         let mut map = HashMap::<RequestType, SearchSpace>::new();
+        let progress = Progress::new("Building the manifest", traces.len());
         for trace in traces {
             match map.get_mut(&trace.request_type) {
                 Some(space) => {
-                    space.add_trace(&trace, false);
+                    space.add_trace(&trace, false, budget);
                 }
                 None => {
                     let mut space = SearchSpace::default();
-                    space.add_trace(&trace, false);
+                    space.add_trace(&trace, false, budget);
                     map.insert(trace.request_type, space);
                 }
             }
+            progress.tick();
         }
         let mut result = Manifest {
             per_request_type: map,
             request_type_tracepoints: Vec::new(),
+            aliases: AliasMap::default(),
+            costs: CostModel::default(),
+            match_cache: MatchCache::default(),
         };
+        for trace in traces {
+            result.costs.add_trace(trace);
+        }
         result.add_request_type_tracepoints(traces);
         result
     }
 
+    /// Grows the manifest with the paths of a trace seen while diagnosing. Returns the number of
+    /// new paths, 0 if the trace had nothing the manifest didn't know.
+    pub fn add_trace(&mut self, trace: &Trace, budget: PathBudget) -> usize {
+        let added = self
+            .per_request_type
+            .entry(trace.request_type)
+            .or_default()
+            .add_trace(trace, false, budget);
+        self.match_cache.clear();
+        self.costs.add_trace(trace);
+        for tp in trace.g.node_references().map(|x| x.weight().tracepoint_id) {
+            if RequestType::is_match(&tp.to_string())
+                && !self.request_type_tracepoints.contains(&tp)
+            {
+                self.request_type_tracepoints.push(tp);
+            }
+        }
+        added
+    }
+
     fn add_request_type_tracepoints(&mut self, traces: &Vec<Trace>) {
         for trace in traces {
             self.request_type_tracepoints.extend(
@@ -146,20 +313,72 @@ impl Manifest {
                     .g
                     .node_references()
                     .map(|x| x.weight().tracepoint_id.to_string())
-                    .filter(|x: &String| REQUEST_TYPE_REGEXES.is_match(x))
+                    .filter(|x: &String| RequestType::is_match(x))
                     .map(|x| TracepointID::from_str(&x)),
             );
         }
     }
 
+    /// Moves the manifest to the tracepoint IDs seen in `traces`, which come from a newer
+    /// version of the application. Returns the number of tracepoints that were aliased.
+    pub fn remap(&mut self, traces: &Vec<Trace>) -> usize {
+        let new_tracepoints = traces
+            .iter()
+            .map(|t| t.g.node_references().map(|x| x.weight().tracepoint_id))
+            .flatten()
+            .collect::<HashSet<_>>();
+        let mut old_tracepoints = self.all_tracepoints();
+        old_tracepoints.extend(self.request_type_tracepoints.iter());
+        let aliases = AliasMap::build(&old_tracepoints, &new_tracepoints);
+        for ss in self.per_request_type.values_mut() {
+            ss.apply_aliases(&aliases);
+        }
+        self.costs.apply_aliases(&aliases);
+        self.request_type_tracepoints = self
+            .request_type_tracepoints
+            .iter()
+            .map(|&tp| aliases.resolve(tp))
+            .collect();
+        let count = aliases.len();
+        self.aliases = aliases;
+        self.match_cache.clear();
+        count
+    }
+
+    /// Also writes the tracepoint IDs next to the manifest, see `interner_file`
     pub fn to_file(&self, file: &Path) {
         let writer = std::fs::File::create(file).unwrap();
         serde_json::to_writer(writer, self).ok();
+        if let Err(e) = TracepointID::dump_interner(&interner_file(file)) {
+            error!("Could not save tracepoint IDs next to {:?}: {}", file, e);
+        }
     }
 
+    /// Returns None if the file doesn't exist
     pub fn from_file(file: &Path) -> Option<Manifest> {
-        let reader = std::fs::File::open(file).unwrap();
-        serde_json::from_reader(reader).unwrap()
+        let reader = std::fs::File::open(file).ok()?;
+        let ids = interner_file(file);
+        if ids.exists() {
+            if let Err(e) = TracepointID::load_interner(&ids) {
+                warn!("Tracepoint IDs differ from the run that built {:?}: {}", file, e);
+            }
+        }
+        let mut manifest: Option<Manifest> = serde_json::from_reader(reader).unwrap();
+        for ss in manifest.iter_mut().flat_map(|m| m.per_request_type.values_mut()) {
+            ss.build_index();
+        }
+        manifest
+    }
+
+    /// Tracepoints that the manifest refers to, which must keep their IDs
+    pub fn referenced_tracepoints(&self) -> HashSet<TracepointID> {
+        let mut result = self.all_tracepoints();
+        result.extend(self.request_type_tracepoints.iter());
+        for (&old, &new) in self.aliases.iter() {
+            result.insert(old);
+            result.insert(new);
+        }
+        result
     }
 
     /// This is where a skeleton is defined. Adding/removing things to skeleton and
@@ -180,6 +399,22 @@ impl Manifest {
         result.extend(self.request_type_tracepoints.iter());
         result.iter().cloned().collect()
     }
+
+    /// Which tracepoints need which others enabled to emit events
+    pub fn dependencies(&self) -> TracepointDependencies {
+        TracepointDependencies::compute(self)
+    }
+
+    /// The skeleton cut down to `target` tracepoints, or to the ones it cannot do without
+    pub fn minimal_skeleton(&self, target: usize) -> SkeletonReduction {
+        SkeletonReduction::compute(self, target)
+    }
+}
+
+/// The tracepoint IDs of a manifest are kept in `<manifest>.ids.json`, so path hashes are the
+/// same when it is read again
+pub fn interner_file(manifest_file: &Path) -> PathBuf {
+    manifest_file.with_extension("ids.json")
 }
 
 impl Display for Manifest {
@@ -191,3 +426,219 @@ impl Display for Manifest {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::NaiveDateTime;
+    use proptest::prelude::*;
+    use uuid::Uuid;
+
+    use crate::critical::CriticalPath;
+    use crate::testutils::TraceGenerator;
+    use crate::trace::DAGEdge;
+    use crate::trace::EdgeType;
+    use crate::trace::Event;
+    use crate::trace::EventType;
+
+    /// A span of `a` with the given annotations in between, one millisecond apart
+    fn sequential_trace(annotations: &[&str]) -> Trace {
+        let mut events = vec![("a", EventType::Entry)];
+        events.extend(annotations.iter().map(|&n| (n, EventType::Annotation)));
+        events.push(("a", EventType::Exit));
+        trace_of(&events)
+    }
+
+    /// One event after the other, one millisecond apart, each entry starting a new span
+    fn trace_of(events: &[(&str, EventType)]) -> Trace {
+        let mut trace = Trace::new(&Uuid::new_v4());
+        let mut spans = Vec::new();
+        let mut previous = None;
+        for (ms, &(name, variant)) in events.iter().enumerate() {
+            let span = match variant {
+                EventType::Entry => {
+                    spans.push(Uuid::new_v4());
+                    *spans.last().unwrap()
+                }
+                EventType::Annotation => *spans.last().unwrap(),
+                EventType::Exit => spans.pop().unwrap(),
+            };
+            let node = trace.g.add_node(Event {
+                trace_id: span,
+                tracepoint_id: TracepointID::from_str(name),
+                timestamp: NaiveDateTime::from_timestamp(0, 0)
+                    + chrono::Duration::milliseconds(ms as i64),
+                is_synthetic: false,
+                variant,
+                key_value_pair: HashMap::new(),
+            });
+            match previous {
+                Some(p) => {
+                    trace.g.add_edge(
+                        p,
+                        node,
+                        DAGEdge {
+                            duration: Duration::from_millis(1),
+                            variant: EdgeType::ChildOf,
+                        },
+                    );
+                }
+                None => trace.start_node = node,
+            }
+            previous = Some(node);
+        }
+        trace.end_node = previous.unwrap();
+        trace
+    }
+
+    #[test]
+    fn grows_with_new_paths() {
+        let mut manifest = Manifest::new();
+        let skeleton = sequential_trace(&[]);
+        assert_eq!(manifest.add_trace(&skeleton, PathBudget::default()), 1);
+        assert_eq!(manifest.add_trace(&skeleton, PathBudget::default()), 0);
+        assert!(!manifest
+            .all_tracepoints()
+            .contains(&TracepointID::from_str("b")));
+
+        let detailed = sequential_trace(&["b", "c"]);
+        assert_eq!(manifest.add_trace(&detailed, PathBudget::default()), 1);
+        assert!(manifest
+            .all_tracepoints()
+            .contains(&TracepointID::from_str("b")));
+        // The skeleton-only path is part of the detailed one, so it isn't kept on its own
+        assert_eq!(
+            manifest.per_request_type[&RequestType::Unknown].path_count(),
+            1
+        );
+        assert_eq!(manifest.add_trace(&skeleton, PathBudget::default()), 0);
+    }
+
+    #[test]
+    fn forgets_matches_when_paths_change() {
+        let skeleton = sequential_trace(&[]);
+        let mut manifest = Manifest::from_trace_list(&vec![skeleton.clone()]);
+        let group = Group::from_critical_paths(vec![CriticalPath::from_trace(&skeleton).unwrap()])
+            .remove(0);
+        let first: Vec<String> = manifest
+            .find_matches(&group)
+            .iter()
+            .map(|p| p.hash().to_string())
+            .collect();
+        assert_eq!(first.len(), 1);
+        assert_eq!(
+            manifest.clone().find_matches(&group)[0].hash(),
+            first[0].as_str()
+        );
+
+        // The detailed path replaces the skeleton one, which the cache still points to
+        manifest.add_trace(&sequential_trace(&["b"]), PathBudget::default());
+        let second = manifest.find_matches(&group);
+        assert_eq!(second.len(), 1);
+        assert_ne!(second[0].hash(), first[0].as_str());
+    }
+
+    #[test]
+    fn reconstructs_missing_spans() {
+        let full = trace_of(&[
+            ("a", EventType::Entry),
+            ("b", EventType::Entry),
+            ("c", EventType::Annotation),
+            ("b", EventType::Exit),
+            ("a", EventType::Exit),
+        ]);
+        let manifest = Manifest::from_trace_list(&vec![full]);
+        // Traces collected without the tracepoints of span b
+        let partial = trace_of(&[
+            ("a", EventType::Entry),
+            ("c", EventType::Annotation),
+            ("a", EventType::Exit),
+        ]);
+        let group = Group::from_critical_paths(vec![CriticalPath::from_trace(&partial).unwrap()])
+            .remove(0);
+        let contexts = manifest.reconstruct_hierarchy(&group).unwrap();
+        let tps = |names: &[&str]| -> Vec<TracepointID> {
+            names.iter().map(|&n| TracepointID::from_str(n)).collect()
+        };
+        let mut nodes = vec![group.start_node];
+        while let Some(n) = group.next_node(*nodes.last().unwrap()) {
+            nodes.push(n);
+        }
+        assert_eq!(contexts[&nodes[0]], tps(&["a"]));
+        assert_eq!(contexts[&nodes[1]], tps(&["a", "b", "c"]));
+        assert_eq!(contexts[&nodes[2]], tps(&["a"]));
+
+        let unrelated = sequential_trace(&["d"]);
+        let group = Group::from_critical_paths(vec![CriticalPath::from_trace(&unrelated).unwrap()])
+            .remove(0);
+        assert!(manifest.reconstruct_hierarchy(&group).is_none());
+    }
+
+    #[test]
+    fn measures_events_per_request() {
+        let traces = vec![sequential_trace(&["b", "b", "b"]), sequential_trace(&["c"])];
+        let manifest = Manifest::from_trace_list(&traces);
+        let cost = |tp| {
+            manifest
+                .costs
+                .events_per_request(TracepointID::from_str(tp))
+        };
+        assert_eq!(cost("a"), 2.0);
+        assert_eq!(cost("b"), 1.5);
+        assert_eq!(cost("c"), 0.5);
+        assert_eq!(cost("never_seen"), 1.0);
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(16))]
+        #[test]
+        fn covers_generated_groups(seed in any::<u64>()) {
+            // Every fork multiplies the possible paths, so keep the traces small
+            let mut generator = TraceGenerator::new(seed);
+            generator.max_depth = 2;
+            generator.concurrency = 0.1;
+            let traces: Vec<_> = (0..3).map(|_| generator.generate().trace).collect();
+            let manifest = Manifest::from_trace_list(&traces);
+            let paths = traces
+                .iter()
+                .map(|t| CriticalPath::from_trace(t).unwrap())
+                .collect();
+            for group in Group::from_critical_paths(paths) {
+                prop_assert!(!manifest.find_matches(&group).is_empty());
+            }
+        }
+
+        #[test]
+        fn index_finds_what_a_scan_finds(seed in any::<u64>()) {
+            let mut generator = TraceGenerator::new(seed);
+            generator.max_depth = 2;
+            generator.concurrency = 0.1;
+            let traces: Vec<_> = (0..3).map(|_| generator.generate().trace).collect();
+            let manifest = Manifest::from_trace_list(&traces);
+            // Read back without an index, which falls back to checking every path
+            let unindexed: Manifest =
+                serde_json::from_str(&serde_json::to_string(&manifest).unwrap()).unwrap();
+            let paths = traces
+                .iter()
+                .map(|t| CriticalPath::from_trace(t).unwrap())
+                .collect();
+            for group in Group::from_critical_paths(paths) {
+                let ss = &manifest.per_request_type[&group.request_type];
+                let mut scanned: Vec<&str> = ss
+                    .paths
+                    .values()
+                    .filter(|p| p.contains(&group))
+                    .map(|p| p.hash())
+                    .collect();
+                scanned.sort();
+                for m in [&manifest, &unindexed] {
+                    let mut found: Vec<&str> =
+                        m.find_matches(&group).iter().map(|p| p.hash()).collect();
+                    found.sort();
+                    prop_assert_eq!(&found, &scanned);
+                }
+            }
+        }
+    }
+}
@@ -8,13 +8,19 @@ All rights reserved.
 
 //! This module has the search space without the complexity of
 //! supporting multiple request types.
+//!
+//! Matching a group means finding the paths that contain it, which is slow to check for every
+//! path of a large manifest. `MatchIndex` keeps the paths through each tracepoint, so only the
+//! paths through all of the group's tracepoints are checked.
 
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::fmt;
 use std::fmt::Display;
+use std::time::Duration;
 use std::time::Instant;
 
+use log::{debug, info};
 use petgraph::dot::Dot;
 use petgraph::graph::NodeIndex;
 use petgraph::stable_graph::StableGraph;
@@ -28,8 +34,10 @@ use serde::{Deserialize, Serialize};
 use pythia_common::RequestType;
 
 use crate::critical::CriticalPath;
+use crate::critical::PathBudget;
 use crate::critical::Path;
 use crate::grouping::Group;
+use crate::manifest::alias::AliasMap;
 use crate::trace::DAGEdge;
 use crate::trace::EventType;
 use crate::trace::Trace;
@@ -40,6 +48,9 @@ use crate::trace::TracepointID;
 #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
 pub struct HierarchicalEdge {
     variant: EdgeType,
+    /// Latency of happens-before edges in the profiling trace the path came from
+    #[serde(default)]
+    duration: Duration,
 }
 
 impl Display for HierarchicalEdge {
@@ -58,9 +69,10 @@ pub enum EdgeType {
 }
 
 impl HierarchicalEdge {
-    fn from_dag_edge(_: &DAGEdge) -> Self {
+    fn from_dag_edge(edge: &DAGEdge) -> Self {
         HierarchicalEdge {
             variant: EdgeType::HappensBefore,
+            duration: edge.duration,
         }
     }
 }
@@ -82,8 +94,12 @@ impl HierarchicalCriticalPath {
     /// This is an iterator, so it creates the paths one at a time, to avoid running out of memory.
     ///
     /// If we put all paths in a vector, we run out of memory for some traces.
-    pub fn all_possible_paths<'a>(trace: &'a Trace) -> impl Iterator<Item = Self> + 'a {
-        CriticalPath::all_possible_paths(trace).map(|x| HierarchicalCriticalPath::from_path(&x))
+    pub fn all_possible_paths<'a>(
+        trace: &'a Trace,
+        budget: PathBudget,
+    ) -> impl Iterator<Item = Self> + 'a {
+        CriticalPath::all_possible_paths_budgeted(trace, budget)
+            .map(|x| HierarchicalCriticalPath::from_path(&x))
     }
 
     /// Copies critical path and then adds hierarchical edges
@@ -122,6 +138,51 @@ impl HierarchicalCriticalPath {
         result
     }
 
+    /// Latency from a node to the next one on the path, as seen when profiling
+    pub fn duration_to_next(&self, nidx: NodeIndex) -> Duration {
+        self.g
+            .edges(nidx)
+            .find(|e| e.weight().variant == EdgeType::HappensBefore)
+            .map_or(Duration::default(), |e| e.weight().duration)
+    }
+
+    /// Reconstructs the hierarchy of a path with fewer tracepoints, such as a group of traces
+    /// collected with only the skeleton enabled, from this path that contains it. Returns the
+    /// context of each node of `other`: the spans it is in, outermost first, and the node itself
+    /// if it is an annotation. None if this path doesn't contain `other`.
+    pub fn align_contexts(
+        &self,
+        other: &dyn Path,
+    ) -> Option<HashMap<NodeIndex, Vec<TracepointID>>> {
+        let mut result = HashMap::new();
+        let mut context = Vec::new();
+        let mut cur_self = Some(self.start_node);
+        let mut cur_other = Some(other.start_node());
+        // Same alignment as `contains`: each node of `other` goes to the first one that fits
+        while let (Some(s), Some(o)) = (cur_self, cur_other) {
+            let node = &self.g[s];
+            if node.variant == EventType::Entry {
+                context.push(node.tracepoint_id);
+            }
+            if node.tracepoint_id == other.at(o) {
+                let mut node_context = context.clone();
+                if node.variant == EventType::Annotation {
+                    node_context.push(node.tracepoint_id);
+                }
+                result.insert(o, node_context);
+                cur_other = other.next_node(o);
+            }
+            if node.variant == EventType::Exit {
+                context.pop();
+            }
+            cur_self = self.next_node(s);
+        }
+        match cur_other {
+            Some(_) => None,
+            None => Some(result),
+        }
+    }
+
     /// Hierarchical children of a node. The node needs to be a span start.
     pub fn child_nodes(&self, nidx: NodeIndex) -> Vec<NodeIndex> {
         EdgeFiltered::from_fn(&self.g, |e| e.weight().variant == EdgeType::Hierarchical)
@@ -166,6 +227,7 @@ impl HierarchicalCriticalPath {
                             next_node,
                             HierarchicalEdge {
                                 variant: EdgeType::Hierarchical,
+                                duration: Duration::default(),
                             },
                         );
                     }
@@ -189,6 +251,60 @@ impl HierarchicalCriticalPath {
     }
 }
 
+/// Hashes of the paths through each tracepoint
+#[derive(Default, Debug, Clone)]
+struct MatchIndex {
+    by_tracepoint: HashMap<TracepointID, HashSet<String>>,
+    /// Number of paths indexed, to notice an index that is out of date
+    paths: usize,
+}
+
+impl MatchIndex {
+    fn insert(&mut self, path: &HierarchicalCriticalPath) {
+        for (_, node) in path.g.node_references() {
+            self.by_tracepoint
+                .entry(node.tracepoint_id)
+                .or_default()
+                .insert(path.hash().to_string());
+        }
+        self.paths += 1;
+    }
+
+    fn remove(&mut self, path: &HierarchicalCriticalPath) {
+        for (_, node) in path.g.node_references() {
+            if let Some(hashes) = self.by_tracepoint.get_mut(&node.tracepoint_id) {
+                hashes.remove(path.hash());
+                if hashes.is_empty() {
+                    self.by_tracepoint.remove(&node.tracepoint_id);
+                }
+            }
+        }
+        self.paths -= 1;
+    }
+
+    /// Paths through every tracepoint of the group, the only ones that can contain it
+    fn candidates(&self, group: &Group) -> Vec<&String> {
+        let tracepoints: HashSet<TracepointID> =
+            group.g.node_indices().map(|n| group.at(n)).collect();
+        let mut lists = Vec::new();
+        for tp in tracepoints {
+            match self.by_tracepoint.get(&tp) {
+                Some(hashes) => lists.push(hashes),
+                None => return Vec::new(),
+            }
+        }
+        // Start from the rarest tracepoint, the other lists only rule out paths
+        lists.sort_by_key(|hashes| hashes.len());
+        match lists.split_first() {
+            Some((rarest, others)) => rarest
+                .iter()
+                .filter(|&h| others.iter().all(|hashes| hashes.contains(h)))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
 /// Collection of all HierarchicalCriticalPaths
 ///
 /// Also contains more information that is pre-calculated from these paths
@@ -202,6 +318,9 @@ pub struct SearchSpace {
     /// List of tracepoints where multiple branches of execution joined, and the last tracepoint of each
     /// branch of execution.
     synchronization_points: HashSet<TracepointID>,
+    /// Built with the paths, and by `build_index` after reading a manifest
+    #[serde(skip)]
+    index: MatchIndex,
 }
 
 impl SearchSpace {
@@ -213,6 +332,17 @@ impl SearchSpace {
             .collect::<HashSet<_>>()
     }
 
+    /// Whether any path goes through the tracepoint
+    pub fn has_tracepoint(&self, tp: TracepointID) -> bool {
+        if self.index.paths == self.paths.len() {
+            self.index.by_tracepoint.contains_key(&tp)
+        } else {
+            self.paths
+                .values()
+                .any(|p| p.g.node_references().any(|(_, n)| n.tracepoint_id == tp))
+        }
+    }
+
     pub fn path_lengths(&self) -> Vec<usize> {
         self.paths.iter().map(|(_, v)| v.len()).collect()
     }
@@ -221,13 +351,28 @@ impl SearchSpace {
         self.paths.len()
     }
 
+    pub fn build_index(&mut self) {
+        self.index = MatchIndex::default();
+        for path in self.paths.values() {
+            self.index.insert(path);
+        }
+    }
+
     pub fn find_matches(&self, group: &Group, silent: bool) -> Vec<&HierarchicalCriticalPath> {
         let now = Instant::now();
-        let mut matching_hashes = self
-            .paths
-            .iter()
-            .filter(|&(_, v)| v.contains(group))
-            .map(|(k, _)| k)
+        let candidates = if self.index.paths == self.paths.len() {
+            self.index.candidates(group)
+        } else {
+            debug!("The match index is out of date, checking all paths");
+            self.paths.keys().collect()
+        };
+        let candidate_count = candidates.len();
+        let mut matching_hashes = candidates
+            .into_iter()
+            .filter(|&h| {
+                let path = &self.paths[h];
+                path.len() >= group.g.node_count() && path.contains(group)
+            })
             .collect::<Vec<&String>>();
         matching_hashes.sort_by(|&a, &b| {
             self.occurances
@@ -236,10 +381,11 @@ impl SearchSpace {
                 .cmp(&self.occurances.get(a).unwrap())
         });
         if !silent {
-            eprintln!(
-                "Finding {} matching groups out of {} took {}, group size {}",
+            debug!(
+                "Finding {} matching groups out of {} ({} candidates) took {}, group size {}",
                 matching_hashes.len(),
                 self.paths.len(),
+                candidate_count,
                 now.elapsed().as_micros(),
                 group.g.node_count()
             );
@@ -250,15 +396,16 @@ impl SearchSpace {
             .collect()
     }
 
-    /// Add a new offline profiling trace to the existing search space
-    pub fn add_trace(&mut self, trace: &Trace, verbose: bool) {
-        eprintln!("Adding {}", trace.base_id);
+    /// Add a new offline profiling trace to the existing search space. Returns the number of
+    /// paths that went into the search space, not counting the ones already covered.
+    pub fn add_trace(&mut self, trace: &Trace, verbose: bool, budget: PathBudget) -> usize {
+        debug!(trace_id:% = trace.base_id; "Adding trace");
         let mut count = 0;
         let mut overlaps = 0;
         let mut added = 0;
+        let mut inserted = 0;
         if verbose {
-            eprintln!("Counting paths...");
-            eprintln!(
+            debug!(
                 "Starting to process {} paths",
                 CriticalPath::count_possible_paths(trace)
             );
@@ -276,7 +423,7 @@ impl SearchSpace {
                 }
             }
         }
-        for path in HierarchicalCriticalPath::all_possible_paths(trace) {
+        for path in HierarchicalCriticalPath::all_possible_paths(trace, budget) {
             self.added_paths += 1;
             self.entry_points
                 .insert(path.g[path.start_node].tracepoint_id);
@@ -300,28 +447,63 @@ impl SearchSpace {
                     }
                 }
                 for p in paths_to_remove {
-                    self.paths.remove(&p);
+                    if let Some(removed) = self.paths.remove(&p) {
+                        self.index.remove(&removed);
+                    }
                     self.occurances.remove(&p);
                     added -= 1;
                     overlaps += 1;
                 }
                 if add_path {
+                    self.index.insert(&path);
                     self.paths.insert(path.hash().to_string(), path.clone());
                     self.occurances.insert(path.hash().to_string(), occurances);
                     added += 1;
+                    inserted += 1;
                 } else {
                     overlaps += 1;
                 }
             }
             count += 1;
             if verbose && (count % 1000 == 0) {
-                eprintln!("Added {}/{} paths, overlaps = {}", added, count, overlaps);
+                debug!("Added {}/{} paths, overlaps = {}", added, count, overlaps);
             }
         }
-        eprintln!(
+        info!(
             "Added {}/{} paths, removed {} overlaps",
             added, count, overlaps
         );
+        inserted
+    }
+
+    /// Renames tracepoints that have an alias. Paths are re-hashed since their hash depends on
+    /// the tracepoint IDs.
+    pub fn apply_aliases(&mut self, aliases: &AliasMap) {
+        let mut paths = HashMap::new();
+        let mut occurances = HashMap::new();
+        for (hash, mut path) in self.paths.drain() {
+            let nodes = path.g.node_indices().collect::<Vec<_>>();
+            for n in nodes {
+                path.g[n].tracepoint_id = aliases.resolve(path.g[n].tracepoint_id);
+            }
+            path.calculate_hash();
+            let count = self.occurances.remove(&hash).unwrap_or(0);
+            *occurances.entry(path.hash().to_string()).or_insert(0) += count;
+            paths.insert(path.hash().to_string(), path);
+        }
+        self.paths = paths;
+        self.occurances = occurances;
+        self.build_index();
+        self.entry_points = self
+            .entry_points
+            .iter()
+            .map(|&tp| aliases.resolve(tp))
+            .collect();
+        self.synchronization_points = self
+            .synchronization_points
+            .iter()
+            .map(|&tp| aliases.resolve(tp))
+            .collect();
     }
 
     pub fn get_top_hierarchy(&self) -> Vec<TracepointID> {
@@ -0,0 +1,207 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! A smaller skeleton, for clusters where the whole skeleton is already too much overhead.
+//!
+//! The tracepoints that tell request types apart and the synchronization points are always
+//! kept, along with whatever else is needed so no two request types look the same. The rest of
+//! the room, up to the target size, goes to the tracepoints on the most manifest paths.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
+
+use petgraph::visit::IntoNodeReferences;
+
+use pythia_common::RequestType;
+
+use crate::manifest::Manifest;
+use crate::trace::TracepointID;
+
+#[derive(Debug, Clone)]
+pub struct SkeletonReduction {
+    pub kept: Vec<TracepointID>,
+    pub dropped: Vec<TracepointID>,
+    /// Tracepoints that cannot be dropped; more than the target if the target is too small
+    pub required: usize,
+    /// Request types whose skeleton tracepoints are the same as another type's
+    pub indistinguishable: Vec<(RequestType, RequestType)>,
+    /// Share of the skeleton tracepoints of each manifest path that are kept, on average
+    pub coverage: f64,
+}
+
+impl SkeletonReduction {
+    pub fn compute(manifest: &Manifest, target: usize) -> SkeletonReduction {
+        let skeleton: HashSet<TracepointID> = manifest.skeleton().into_iter().collect();
+        let mut path_counts: HashMap<TracepointID, usize> = HashMap::new();
+        let mut path_skeletons = Vec::new();
+        let mut per_type: HashMap<RequestType, HashSet<TracepointID>> = HashMap::new();
+        for (&rt, ss) in manifest.per_request_type.iter() {
+            for path in ss.paths.values() {
+                let tracepoints: HashSet<TracepointID> = path
+                    .g
+                    .node_references()
+                    .map(|(_, n)| n.tracepoint_id)
+                    .filter(|tp| skeleton.contains(tp))
+                    .collect();
+                for &tp in &tracepoints {
+                    *path_counts.entry(tp).or_insert(0) += 1;
+                }
+                per_type.entry(rt).or_default().extend(tracepoints.iter());
+                path_skeletons.push(tracepoints);
+            }
+        }
+        // Most common first, by name among equals so the result doesn't change between runs
+        let mut by_paths: Vec<TracepointID> = skeleton.iter().cloned().collect();
+        by_paths.sort_by_key(|tp| (std::cmp::Reverse(path_counts.get(tp)), tp.to_string()));
+
+        let mut kept: HashSet<TracepointID> = manifest
+            .request_type_tracepoints
+            .iter()
+            .filter(|tp| skeleton.contains(tp))
+            .cloned()
+            .collect();
+        for ss in manifest.per_request_type.values() {
+            kept.extend(ss.get_synchronization_points());
+        }
+        let mut types: Vec<RequestType> = per_type.keys().cloned().collect();
+        types.sort_by_key(|rt| rt.to_string());
+        let mut indistinguishable = Vec::new();
+        for (i, a) in types.iter().enumerate() {
+            for b in &types[i + 1..] {
+                let (seen_a, seen_b) = (&per_type[a], &per_type[b]);
+                let tells_apart = |tp: &&TracepointID| seen_a.contains(tp) != seen_b.contains(tp);
+                if kept.iter().any(|tp| tells_apart(&tp)) {
+                    continue;
+                }
+                match by_paths.iter().find(tells_apart) {
+                    Some(&tp) => {
+                        kept.insert(tp);
+                    }
+                    None => indistinguishable.push((*a, *b)),
+                }
+            }
+        }
+        let required = kept.len();
+        for &tp in &by_paths {
+            if kept.len() >= target {
+                break;
+            }
+            kept.insert(tp);
+        }
+
+        let coverage = if path_skeletons.is_empty() {
+            1.0
+        } else {
+            path_skeletons
+                .iter()
+                .map(|tps| match tps.len() {
+                    0 => 1.0,
+                    len => tps.intersection(&kept).count() as f64 / len as f64,
+                })
+                .sum::<f64>()
+                / path_skeletons.len() as f64
+        };
+        SkeletonReduction {
+            kept: by_paths
+                .iter()
+                .filter(|tp| kept.contains(tp))
+                .cloned()
+                .collect(),
+            dropped: by_paths
+                .iter()
+                .filter(|tp| !kept.contains(tp))
+                .cloned()
+                .collect(),
+            required,
+            indistinguishable,
+            coverage,
+        }
+    }
+}
+
+impl fmt::Display for SkeletonReduction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Kept {} of {} skeleton tracepoints ({} required), {:.1}% of the skeleton of each path",
+            self.kept.len(),
+            self.kept.len() + self.dropped.len(),
+            self.required,
+            100.0 * self.coverage
+        )?;
+        for (a, b) in &self.indistinguishable {
+            write!(f, "\nCannot tell {} from {} by the skeleton", a, b)?;
+        }
+        for tp in &self.dropped {
+            write!(f, "\nDropped {}", tp)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testutils::TraceGenerator;
+
+    #[test]
+    fn keeps_what_it_cannot_do_without() {
+        let mut generator = TraceGenerator::new(11);
+        generator.max_depth = 2;
+        generator.concurrency = 0.1;
+        let traces: Vec<_> = (0..4).map(|_| generator.generate().trace).collect();
+        let manifest = Manifest::from_trace_list(&traces);
+        let skeleton: HashSet<TracepointID> = manifest.skeleton().into_iter().collect();
+
+        let smallest = manifest.minimal_skeleton(0);
+        assert_eq!(smallest.kept.len(), smallest.required);
+        for ss in manifest.per_request_type.values() {
+            for tp in ss.get_synchronization_points() {
+                assert!(smallest.kept.contains(&tp));
+            }
+        }
+        // The kept tracepoints tell apart every pair of request types the whole skeleton does
+        let mut per_type: HashMap<RequestType, HashSet<TracepointID>> = HashMap::new();
+        for (&rt, ss) in manifest.per_request_type.iter() {
+            for path in ss.paths.values() {
+                per_type.entry(rt).or_default().extend(
+                    path.g
+                        .node_references()
+                        .map(|(_, n)| n.tracepoint_id)
+                        .filter(|tp| skeleton.contains(tp)),
+                );
+            }
+        }
+        assert!(per_type.len() > 1);
+        for (a, seen_a) in &per_type {
+            for (b, seen_b) in &per_type {
+                if a == b
+                    || smallest.indistinguishable.contains(&(*a, *b))
+                    || smallest.indistinguishable.contains(&(*b, *a))
+                {
+                    continue;
+                }
+                assert!(smallest
+                    .kept
+                    .iter()
+                    .any(|tp| seen_a.contains(tp) != seen_b.contains(tp)));
+            }
+        }
+        let mut all: HashSet<TracepointID> = smallest.kept.iter().cloned().collect();
+        all.extend(smallest.dropped.iter());
+        assert_eq!(all, skeleton);
+
+        let bigger = manifest.minimal_skeleton(smallest.required + 1);
+        assert!(bigger.coverage >= smallest.coverage);
+        let whole = manifest.minimal_skeleton(skeleton.len());
+        assert!(whole.dropped.is_empty());
+        assert_eq!(whole.coverage, 1.0);
+    }
+}
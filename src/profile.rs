@@ -0,0 +1,169 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Named instrumentation profiles.
+//!
+//! A profile is a set of enabled tracepoints saved as `<profile_dir>/<name>.json`, so that
+//! experiments can start from the same instrumentation each time. `pythia profile save <name>`
+//! captures what the agents have enabled, `pythia profile apply <name>` restores it, and
+//! `start_profile` makes the controller loop start from one.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs::{create_dir_all, read_dir, File};
+use std::path::{Path, PathBuf};
+
+use chrono::offset::Local;
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+
+use pythia_common::PythiaError;
+use pythia_common::RequestType;
+
+use crate::controller::Controller;
+use crate::trace::TracepointID;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Profile {
+    pub name: String,
+    pub saved: NaiveDateTime,
+    /// `None` request type means for all request types
+    pub tracepoints: Vec<(TracepointID, Option<RequestType>)>,
+}
+
+impl Profile {
+    /// What the controller reports as enabled out of `per_request_type`, e.g. the manifest's
+    /// tracepoints. Tracepoints enabled for only some request types are kept that way.
+    pub fn capture(
+        name: &str,
+        controller: &dyn Controller,
+        per_request_type: &HashMap<RequestType, HashSet<TracepointID>>,
+    ) -> Profile {
+        let mut all: Vec<TracepointID> = per_request_type
+            .values()
+            .flatten()
+            .cloned()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        all.sort_by_key(|tp| tp.to_string());
+        let global = controller.query_enabled(&all.iter().map(|&tp| (tp, None)).collect());
+        let mut tracepoints: Vec<(TracepointID, Option<RequestType>)> = all
+            .iter()
+            .zip(global.iter())
+            .filter(|(_, &enabled)| enabled)
+            .map(|(&tp, _)| (tp, None))
+            .collect();
+        let enabled: HashSet<TracepointID> = tracepoints.iter().map(|p| p.0).collect();
+        let mut request_types: Vec<_> = per_request_type.iter().collect();
+        request_types.sort_by_key(|(rt, _)| rt.to_string());
+        for (rt, tps) in request_types {
+            let mut points: Vec<_> = tps
+                .iter()
+                .filter(|tp| !enabled.contains(tp))
+                .map(|&tp| (tp, Some(*rt)))
+                .collect();
+            points.sort_by_key(|p| p.0.to_string());
+            let states = controller.query_enabled(&points);
+            tracepoints.extend(
+                points
+                    .into_iter()
+                    .zip(states)
+                    .filter(|(_, enabled)| *enabled)
+                    .map(|(p, _)| p),
+            );
+        }
+        Profile {
+            name: name.to_string(),
+            saved: Local::now().naive_local(),
+            tracepoints,
+        }
+    }
+
+    pub fn path(dir: &Path, name: &str) -> Result<PathBuf, PythiaError> {
+        if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+            return Err(PythiaError::ControllerError(format!(
+                "{:?} is not a valid profile name",
+                name
+            )));
+        }
+        Ok(dir.join(format!("{}.json", name)))
+    }
+
+    pub fn load(dir: &Path, name: &str) -> Result<Profile, PythiaError> {
+        let file = File::open(Profile::path(dir, name)?)?;
+        Ok(serde_json::from_reader(file)?)
+    }
+
+    /// Overwrites the profile with the same name, if any
+    pub fn save(&self, dir: &Path) -> Result<PathBuf, PythiaError> {
+        let path = Profile::path(dir, &self.name)?;
+        create_dir_all(dir)?;
+        serde_json::to_writer_pretty(File::create(&path)?, self)?;
+        Ok(path)
+    }
+
+    /// Names of the saved profiles
+    pub fn list(dir: &Path) -> Result<Vec<String>, PythiaError> {
+        let mut result = Vec::new();
+        for entry in read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == "json") {
+                if let Some(name) = path.file_stem() {
+                    result.push(name.to_string_lossy().to_string());
+                }
+            }
+        }
+        result.sort();
+        Ok(result)
+    }
+
+    /// Leaves exactly the tracepoints of the profile enabled
+    pub fn apply(&self, controller: &dyn Controller) {
+        controller.disable_all();
+        controller.enable(&self.tracepoints);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::controller::TestController;
+
+    #[test]
+    fn round_trip() {
+        let create = RequestType::from_str("ServerCreate").unwrap();
+        let delete = RequestType::from_str("ServerDelete").unwrap();
+        let (a, b, c) = (
+            TracepointID::from_str("profile-a"),
+            TracepointID::from_str("profile-b"),
+            TracepointID::from_str("profile-c"),
+        );
+        let mut per_request_type = HashMap::new();
+        per_request_type.insert(create, vec![a, b].into_iter().collect());
+        per_request_type.insert(delete, vec![a, c].into_iter().collect());
+        let controller = TestController::new();
+        controller.enable(&vec![(a, None), (c, Some(delete))]);
+        let profile = Profile::capture("baseline", &controller, &per_request_type);
+        assert_eq!(profile.tracepoints, vec![(a, None), (c, Some(delete))]);
+
+        let dir = std::env::temp_dir().join(format!("pythia-profiles-{}", std::process::id()));
+        profile.save(&dir).unwrap();
+        assert_eq!(Profile::list(&dir).unwrap(), vec!["baseline".to_string()]);
+        let loaded = Profile::load(&dir, "baseline").unwrap();
+        assert_eq!(loaded, profile);
+        assert!(Profile::load(&dir, "../baseline").is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        controller.enable(&vec![(b, None)]);
+        loaded.apply(&controller);
+        assert!(!controller.is_enabled(&(b, None)));
+        assert!(controller.is_enabled(&(c, Some(delete))));
+    }
+}
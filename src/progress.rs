@@ -0,0 +1,116 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Progress of long operations, like reading thousands of traces or building a manifest.
+//!
+//! On a terminal, a progress bar shows how many items are done, how long each takes and when the
+//! rest should be done. When stderr is not a terminal (e.g., the controller under systemd, or
+//! logs sent to a file), there is no bar; progress is logged every `LOG_INTERVAL` items instead.
+
+use std::fmt::Write;
+use std::io::IsTerminal;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+
+use indicatif::ProgressBar;
+use indicatif::ProgressState;
+use indicatif::ProgressStyle;
+use log::info;
+
+/// Progress is logged every this many items without a terminal
+const LOG_INTERVAL: usize = 1000;
+const TEMPLATE: &str = "{msg} [{bar:40}] {pos}/{len} ({each} each, ETA {eta})";
+
+/// Counts the items done, possibly from several threads
+pub struct Progress {
+    /// e.g., "Reading files"
+    message: &'static str,
+    total: usize,
+    done: AtomicUsize,
+    started: Instant,
+    bar: Option<ProgressBar>,
+}
+
+impl Progress {
+    pub fn new(message: &'static str, total: usize) -> Self {
+        let bar = if std::io::stderr().is_terminal() && total > 1 {
+            let style = ProgressStyle::with_template(TEMPLATE)
+                .unwrap()
+                .with_key("each", |state: &ProgressState, w: &mut dyn Write| {
+                    write!(w, "{:.1?}", per_item(state.elapsed(), state.pos() as usize)).unwrap()
+                })
+                .progress_chars("=> ");
+            let bar = ProgressBar::new(total as u64).with_style(style);
+            bar.set_message(message);
+            Some(bar)
+        } else {
+            None
+        };
+        Progress {
+            message,
+            total,
+            done: AtomicUsize::new(0),
+            started: Instant::now(),
+            bar,
+        }
+    }
+
+    pub fn tick(&self) {
+        let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+        match &self.bar {
+            Some(bar) => bar.inc(1),
+            None if done.is_multiple_of(LOG_INTERVAL) && done < self.total => {
+                info!("{}: {}/{}", self.message, done, self.total)
+            }
+            None => {}
+        }
+        if done == self.total {
+            if let Some(bar) = &self.bar {
+                bar.finish_and_clear();
+            }
+            let elapsed = self.started.elapsed();
+            info!(
+                "{}: {} in {:.1?}, {:.1?} each",
+                self.message,
+                done,
+                elapsed,
+                per_item(elapsed, done)
+            );
+        }
+    }
+}
+
+fn per_item(elapsed: Duration, done: usize) -> Duration {
+    elapsed / done.max(1) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_items_from_several_threads() {
+        let progress = Progress::new("Reading files", 4);
+        std::thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|| {
+                    progress.tick();
+                    progress.tick();
+                });
+            }
+        });
+        assert_eq!(progress.done.load(Ordering::Relaxed), 4);
+        assert_eq!(
+            per_item(Duration::from_secs(2), 4),
+            Duration::from_millis(500)
+        );
+        assert_eq!(per_item(Duration::from_secs(2), 0), Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,180 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Filter expressions over the key-value pairs of events.
+//!
+//! An expression compares keys to values, e.g., `host=cp-1 AND request.method=POST`. Comparisons
+//! are `=`, `!=`, `<`, `<=`, `>`, `>=` (numeric if both sides are numbers), and `~` (the value
+//! contains the string). They can be combined with `AND`, `OR`, `NOT` and parentheses. The key
+//! `tracepoint` stands for the tracepoint id of the event. Values with spaces or operators in them
+//! can be quoted.
+//!
+//! An event matches if its own key-value pairs satisfy the expression. A trace (or critical path)
+//! matches if each comparison is satisfied by some event in it, so `host=cp-1 AND
+//! request.method=POST` can be true of two different events.
+
+mod parser;
+
+use std::fmt;
+
+use crate::trace::Event;
+use crate::trace::Trace;
+use crate::trace::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Contains,
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Op::Eq => "=",
+            Op::Ne => "!=",
+            Op::Lt => "<",
+            Op::Le => "<=",
+            Op::Gt => ">",
+            Op::Ge => ">=",
+            Op::Contains => "~",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    Compare { key: String, op: Op, value: String },
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+    Not(Box<Filter>),
+}
+
+impl Filter {
+    pub fn parse(s: &str) -> Result<Filter, String> {
+        parser::parse(s)
+    }
+
+    pub fn matches_event(&self, event: &Event) -> bool {
+        self.eval(&|key, op, value| compare_event(event, key, op, value))
+    }
+
+    pub fn matches_trace(&self, trace: &Trace) -> bool {
+        self.eval(&|key, op, value| {
+            trace
+                .g
+                .node_indices()
+                .any(|n| compare_event(&trace.g[n], key, op, value))
+        })
+    }
+
+    fn eval(&self, compare: &dyn Fn(&str, Op, &str) -> bool) -> bool {
+        match self {
+            Filter::Compare { key, op, value } => compare(key, *op, value),
+            Filter::And(a, b) => a.eval(compare) && b.eval(compare),
+            Filter::Or(a, b) => a.eval(compare) || b.eval(compare),
+            Filter::Not(a) => !a.eval(compare),
+        }
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Filter::Compare { key, op, value } => {
+                if value.chars().all(parser::is_word_char) && !value.is_empty() {
+                    write!(f, "{}{}{}", key, op, value)
+                } else {
+                    write!(f, "{}{}{:?}", key, op, value)
+                }
+            }
+            Filter::And(a, b) => write!(f, "({} AND {})", a, b),
+            Filter::Or(a, b) => write!(f, "({} OR {})", a, b),
+            Filter::Not(a) => write!(f, "NOT {}", a),
+        }
+    }
+}
+
+fn compare_event(event: &Event, key: &str, op: Op, value: &str) -> bool {
+    let actual = if key == "tracepoint" {
+        event.tracepoint_id.to_string()
+    } else {
+        match event.key_value_pair.get(key) {
+            Some(Value::Str(s)) => s.clone(),
+            Some(Value::UnsignedInt(i)) => i.to_string(),
+            Some(Value::SignedInt(i)) => i.to_string(),
+            // Events without the key don't match anything, not even !=
+            None => return false,
+        }
+    };
+    let ordering = match (actual.parse::<f64>(), value.parse::<f64>()) {
+        (Ok(a), Ok(b)) => a.partial_cmp(&b),
+        _ => Some(actual.as_str().cmp(value)),
+    };
+    match op {
+        Op::Eq => actual == value || ordering == Some(std::cmp::Ordering::Equal),
+        Op::Ne => !(actual == value || ordering == Some(std::cmp::Ordering::Equal)),
+        Op::Lt => ordering == Some(std::cmp::Ordering::Less),
+        Op::Le => ordering.map_or(false, |o| o != std::cmp::Ordering::Greater),
+        Op::Gt => ordering == Some(std::cmp::Ordering::Greater),
+        Op::Ge => ordering.map_or(false, |o| o != std::cmp::Ordering::Less),
+        Op::Contains => actual.contains(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use chrono::NaiveDateTime;
+    use uuid::Uuid;
+
+    use crate::trace::EventType;
+    use crate::trace::TracepointID;
+
+    fn event(pairs: &[(&str, Value)]) -> Event {
+        Event {
+            trace_id: Uuid::new_v4(),
+            tracepoint_id: TracepointID::from_str("nova/api.py:10"),
+            timestamp: NaiveDateTime::from_timestamp(0, 0),
+            is_synthetic: false,
+            variant: EventType::Annotation,
+            key_value_pair: pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.clone()))
+                .collect::<HashMap<_, _>>(),
+        }
+    }
+
+    #[test]
+    fn filters() {
+        let e = event(&[
+            ("host", Value::Str("cp-1".to_string())),
+            ("request.method", Value::Str("POST".to_string())),
+            ("pid", Value::UnsignedInt(4771)),
+        ]);
+        let check = |s: &str| Filter::parse(s).unwrap().matches_event(&e);
+        assert!(check("host=cp-1 AND request.method=POST"));
+        assert!(!check("host=cp-2 or request.method = GET"));
+        assert!(check("NOT (host=cp-2) AND pid > 1000 AND pid<=4771"));
+        assert!(check("tracepoint ~ \"api.py\""));
+        assert!(!check("missing != x"));
+        assert!(Filter::parse("host=").is_err());
+        assert!(Filter::parse("(host=cp-1").is_err());
+
+        let f = Filter::parse("host=cp-1 AND NOT pid=\"1 2\"").unwrap();
+        assert_eq!(Filter::parse(&f.to_string()).unwrap(), f);
+    }
+}
@@ -0,0 +1,167 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Recursive descent parser for filter expressions:
+//!
+//! ```text
+//! or      := and ("OR" and)*
+//! and     := not ("AND" not)*
+//! not     := "NOT" not | "(" or ")" | compare
+//! compare := word op (word | quoted)
+//! ```
+
+use std::iter::Peekable;
+use std::vec::IntoIter;
+
+use crate::query::Filter;
+use crate::query::Op;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Op(Op),
+    Word(String),
+    Quoted(String),
+}
+
+pub fn is_word_char(c: char) -> bool {
+    !c.is_whitespace() && !"()=!<>~\"'".contains(c)
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut result = Vec::new();
+    let mut chars = s.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '(' || c == ')' {
+            chars.next();
+            result.push(if c == '(' { Token::Open } else { Token::Close });
+        } else if c == '"' || c == '\'' {
+            chars.next();
+            let mut value = String::new();
+            loop {
+                match chars.next() {
+                    Some(q) if q == c => break,
+                    Some('\\') => value.extend(chars.next()),
+                    Some(other) => value.push(other),
+                    None => return Err(format!("Unterminated string in {}", s)),
+                }
+            }
+            result.push(Token::Quoted(value));
+        } else if "=!<>~".contains(c) {
+            chars.next();
+            let followed_by_eq = chars.peek() == Some(&'=');
+            let op = match (c, followed_by_eq) {
+                ('=', _) => Op::Eq,
+                ('!', true) => Op::Ne,
+                ('<', true) => Op::Le,
+                ('<', false) => Op::Lt,
+                ('>', true) => Op::Ge,
+                ('>', false) => Op::Gt,
+                ('~', _) => Op::Contains,
+                _ => return Err(format!("Unexpected {} in {}", c, s)),
+            };
+            if followed_by_eq && c != '=' && c != '~' {
+                chars.next();
+            }
+            result.push(Token::Op(op));
+        } else {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if !is_word_char(c) {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            result.push(Token::Word(word));
+        }
+    }
+    Ok(result)
+}
+
+struct Parser {
+    tokens: Peekable<IntoIter<Token>>,
+}
+
+impl Parser {
+    fn keyword(&mut self, keyword: &str) -> bool {
+        match self.tokens.peek() {
+            Some(Token::Word(w)) if w.eq_ignore_ascii_case(keyword) => {
+                self.tokens.next();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn or(&mut self) -> Result<Filter, String> {
+        let mut result = self.and()?;
+        while self.keyword("OR") {
+            result = Filter::Or(Box::new(result), Box::new(self.and()?));
+        }
+        Ok(result)
+    }
+
+    fn and(&mut self) -> Result<Filter, String> {
+        let mut result = self.not()?;
+        while self.keyword("AND") {
+            result = Filter::And(Box::new(result), Box::new(self.not()?));
+        }
+        Ok(result)
+    }
+
+    fn not(&mut self) -> Result<Filter, String> {
+        if self.keyword("NOT") {
+            return Ok(Filter::Not(Box::new(self.not()?)));
+        }
+        match self.tokens.next() {
+            Some(Token::Open) => {
+                let result = self.or()?;
+                match self.tokens.next() {
+                    Some(Token::Close) => Ok(result),
+                    other => Err(format!("Expected ), found {:?}", other)),
+                }
+            }
+            Some(Token::Word(key)) => {
+                let op = match self.tokens.next() {
+                    Some(Token::Op(op)) => op,
+                    other => {
+                        return Err(format!(
+                            "Expected an operator after {}, found {:?}",
+                            key, other
+                        ))
+                    }
+                };
+                match self.tokens.next() {
+                    Some(Token::Word(value)) | Some(Token::Quoted(value)) => {
+                        Ok(Filter::Compare { key, op, value })
+                    }
+                    other => Err(format!(
+                        "Expected a value after {}{}, found {:?}",
+                        key, op, other
+                    )),
+                }
+            }
+            other => Err(format!("Expected a comparison, found {:?}", other)),
+        }
+    }
+}
+
+pub fn parse(s: &str) -> Result<Filter, String> {
+    let mut parser = Parser {
+        tokens: tokenize(s)?.into_iter().peekable(),
+    };
+    let result = parser.or()?;
+    match parser.tokens.next() {
+        None => Ok(result),
+        Some(t) => Err(format!("Unexpected {:?} in {}", t, s)),
+    }
+}
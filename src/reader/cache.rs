@@ -0,0 +1,125 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Spans already fetched from the agents, so unfinished requests are not fetched whole again
+//! every cycle.
+//!
+//! OSProfiler only appends spans to a request, so for each agent the number of spans received
+//! tells it which ones to skip. Requests are evicted least recently used first.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+
+use uuid::Uuid;
+
+/// Spans received from an agent; the count includes spans that were dropped, e.g., because they
+/// could not be parsed
+struct Received<S> {
+    count: usize,
+    spans: Vec<S>,
+}
+
+/// Spans of a request, by the agent they came from
+type AgentSpans<S> = HashMap<String, Received<S>>;
+
+pub struct SpanCache<S> {
+    capacity: usize,
+    entries: HashMap<Uuid, (u64, AgentSpans<S>)>,
+    /// Requests by when they were last used
+    lru: BTreeMap<u64, Uuid>,
+    tick: u64,
+}
+
+impl<S> SpanCache<S> {
+    /// Holds the spans of at most `capacity` requests; 0 disables caching
+    pub fn new(capacity: usize) -> Self {
+        SpanCache {
+            capacity,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Number of spans of the request already received from the agent
+    pub fn span_count(&self, id: &Uuid, agent: &str) -> usize {
+        self.entries
+            .get(id)
+            .and_then(|(_, spans)| spans.get(agent))
+            .map_or(0, |r| r.count)
+    }
+
+    /// Add `count` newly received spans of the request, of which `new` were kept, and return all
+    /// spans kept from the agent
+    pub fn extend(&mut self, id: Uuid, agent: &str, count: usize, new: Vec<S>) -> &[S] {
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self
+            .entries
+            .entry(id)
+            .or_insert_with(|| (tick, HashMap::new()));
+        self.lru.remove(&entry.0);
+        entry.0 = tick;
+        self.lru.insert(tick, id);
+        let received = entry
+            .1
+            .entry(agent.to_string())
+            .or_insert_with(|| Received {
+                count: 0,
+                spans: Vec::new(),
+            });
+        received.count += count;
+        received.spans.extend(new);
+        while self.entries.len() > self.capacity {
+            let oldest = *self.lru.keys().next().unwrap();
+            let evicted = self.lru.remove(&oldest).unwrap();
+            self.entries.remove(&evicted);
+        }
+        match self.entries.get(&id) {
+            Some((_, spans)) => &spans[agent].spans,
+            None => &[],
+        }
+    }
+
+    /// Forget the request, e.g., when its trace is complete or its keys are freed
+    pub fn remove(&mut self, id: &Uuid) {
+        if let Some((tick, _)) = self.entries.remove(id) {
+            self.lru.remove(&tick);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.lru.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let (a, b, c) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let mut cache = SpanCache::new(2);
+        cache.extend(a, "cp-1", 1, vec![1]);
+        cache.extend(b, "cp-1", 2, vec![1]);
+        assert_eq!(cache.extend(a, "cp-1", 2, vec![2, 3]), &[1, 2, 3]);
+        cache.extend(c, "cp-2", 1, vec![1]);
+        assert_eq!(cache.span_count(&c, "cp-2"), 1);
+        assert_eq!(cache.span_count(&a, "cp-1"), 3);
+        assert_eq!(cache.span_count(&a, "cp-2"), 0);
+        assert_eq!(cache.span_count(&b, "cp-1"), 0);
+        cache.remove(&a);
+        assert_eq!(cache.span_count(&a, "cp-1"), 0);
+    }
+}
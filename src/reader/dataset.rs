@@ -0,0 +1,411 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Bulk loading of the public Uber dataset.
+//!
+//! Reading the dataset as one JSON file per request takes hours, so it can also be given as CSV
+//! or (with the `parquet` feature) Parquet files with one span per row. CSV files are
+//! memory-mapped and parsed in batches on `n_workers` threads, Parquet files one row group per
+//! worker at a time. The spans are then grouped by trace and the traces converted in parallel.
+//!
+//! The files need these columns, named as in Jaeger's JSON: `traceID`, `spanID`,
+//! `parentSpanID` (empty or `0` for the root span), `operationName`, `startTime` and `duration`
+//! (both in microseconds). Other columns are ignored. The IDs and operation names are hex strings,
+//! as in the JSON traces. All spans of a request have to be in the same file, and CSV fields
+//! can't contain line breaks, since batches are split at line breaks.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::thread;
+
+use log::{debug, warn};
+use memmap2::Mmap;
+use uuid::Uuid;
+
+use crate::progress::Progress;
+use crate::reader::uber::UberReader;
+use crate::reader::uber::UberSpan;
+use crate::reader::HexID;
+use crate::settings::Settings;
+use crate::trace::Trace;
+
+/// CSV files are parsed in batches of about this many bytes
+const BATCH_BYTES: usize = 64 << 20;
+
+/// Rows of a span, keyed by the request it belongs to
+type Rows = Vec<(Uuid, UberSpan)>;
+
+#[derive(Debug)]
+struct DatasetError(String);
+
+impl fmt::Display for DatasetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Uber dataset error: {}", self.0)
+    }
+}
+
+impl Error for DatasetError {}
+
+fn raise(s: &str) -> Box<dyn Error> {
+    Box::new(DatasetError(s.into()))
+}
+
+#[derive(Debug, Clone)]
+pub struct DatasetOptions {
+    /// Fraction of the requests to keep
+    pub sample_rate: f64,
+    /// Requests kept from each file at most
+    pub max_traces: Option<usize>,
+    pub n_workers: usize,
+}
+
+impl DatasetOptions {
+    pub fn from_settings(settings: &Settings) -> Self {
+        DatasetOptions {
+            sample_rate: settings.uber_sample_rate,
+            max_traces: settings.uber_max_traces,
+            n_workers: settings.n_workers,
+        }
+    }
+
+    /// Trace IDs are random, so their first bits decide whether a request is sampled. This keeps
+    /// all spans of a request together, and the same requests on every run.
+    fn keep(&self, trace_id: &Uuid) -> bool {
+        if self.sample_rate >= 1.0 {
+            return true;
+        }
+        let mut bits = [0; 8];
+        bits.copy_from_slice(&trace_id.as_bytes()[..8]);
+        (u64::from_be_bytes(bits) as f64) < self.sample_rate * u64::MAX as f64
+    }
+}
+
+/// Whether the file is loaded here rather than as a JSON trace
+pub fn is_dataset(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|e| e.to_str()),
+        Some("csv") | Some("parquet")
+    )
+}
+
+/// Traces of the sampled requests in the file
+pub fn load(
+    reader: &UberReader,
+    path: &Path,
+    options: &DatasetOptions,
+) -> Result<Vec<Trace>, Box<dyn Error>> {
+    let (rows, skipped) = match path.extension().and_then(|e| e.to_str()) {
+        Some("parquet") => read_parquet(path, options)?,
+        _ => read_csv(path, options)?,
+    };
+    if skipped > 0 {
+        warn!("Skipped {} malformed rows of {:?}", skipped, path);
+    }
+    let traces = group(rows, options.max_traces);
+    Ok(convert(reader, traces, options.n_workers))
+}
+
+/// Positions of the columns the reader uses
+struct Columns {
+    trace_id: usize,
+    span_id: usize,
+    parent_id: usize,
+    operation_name: usize,
+    start_time: usize,
+    duration: usize,
+}
+
+impl Columns {
+    fn from_names(names: &[String]) -> Result<Self, Box<dyn Error>> {
+        let find = |name: &str| {
+            names
+                .iter()
+                .position(|n| n == name)
+                .ok_or_else(|| raise(&format!("No {} column", name)))
+        };
+        Ok(Columns {
+            trace_id: find("traceID")?,
+            span_id: find("spanID")?,
+            parent_id: find("parentSpanID")?,
+            operation_name: find("operationName")?,
+            start_time: find("startTime")?,
+            duration: find("duration")?,
+        })
+    }
+}
+
+/// An ID as in the JSON traces; shorter ones are padded, and longer (128-bit) ones keep their
+/// first 8 bytes
+fn hex_id(s: &str) -> Result<HexID, String> {
+    let decoded = hex::decode(format!("{:0>16}", s)).map_err(|e| format!("{:?}: {}", s, e))?;
+    let mut id = [0; 8];
+    id.copy_from_slice(&decoded[..8]);
+    Ok(HexID { id: Some(id) })
+}
+
+fn span(
+    trace_id: &str,
+    span_id: &str,
+    parent_id: &str,
+    operation_name: &str,
+    start_time: u64,
+    duration: i64,
+) -> Result<(Uuid, UberSpan), String> {
+    let trace_id = hex_id(trace_id)?;
+    let parent_id = match parent_id {
+        "" | "0" => None,
+        id => Some(hex_id(id)?),
+    };
+    let span = UberSpan::from_row(
+        trace_id,
+        hex_id(span_id)?,
+        parent_id,
+        hex_id(operation_name)?,
+        start_time,
+        duration,
+    );
+    Ok((trace_id.to_uuid(), span))
+}
+
+fn number<T: FromStr>(s: &str) -> Result<T, String> {
+    s.parse().map_err(|_| format!("{:?} is not a number", s))
+}
+
+/// Ranges of about `size` bytes from `start`, ending at line breaks
+fn batches(data: &[u8], mut start: usize, size: usize) -> Vec<(usize, usize)> {
+    let mut result = Vec::new();
+    while start < data.len() {
+        let from = (start + size).min(data.len());
+        let end = match data[from..].iter().position(|&b| b == b'\n') {
+            Some(i) => from + i + 1,
+            None => data.len(),
+        };
+        result.push((start, end));
+        start = end;
+    }
+    result
+}
+
+fn read_csv(path: &Path, options: &DatasetOptions) -> Result<(Rows, usize), Box<dyn Error>> {
+    let file = File::open(path)?;
+    // The dataset is not written to while it is loaded
+    let data = unsafe { Mmap::map(&file)? };
+    let header_end = match data.iter().position(|&b| b == b'\n') {
+        Some(i) => i + 1,
+        None => return Ok((Vec::new(), 0)),
+    };
+    let names: Vec<String> = csv::Reader::from_reader(&data[..header_end])
+        .headers()?
+        .iter()
+        .map(|h| h.trim().to_string())
+        .collect();
+    let columns = &Columns::from_names(&names)?;
+    let batches = &batches(&data, header_end, BATCH_BYTES);
+    let next = &AtomicUsize::new(0);
+    let data = &data[..];
+    let results: Vec<(Rows, usize)> = thread::scope(|s| {
+        let workers: Vec<_> = (0..options.n_workers.max(1))
+            .map(|_| {
+                s.spawn(move || {
+                    let mut rows = Vec::new();
+                    let mut skipped = 0;
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        if i >= batches.len() {
+                            break;
+                        }
+                        let (start, end) = batches[i];
+                        let mut reader = csv::ReaderBuilder::new()
+                            .has_headers(false)
+                            .from_reader(&data[start..end]);
+                        for record in reader.records() {
+                            let parsed = record.map_err(|e| e.to_string()).and_then(|r| {
+                                let field = |i| r.get(i).unwrap_or("").trim();
+                                span(
+                                    field(columns.trace_id),
+                                    field(columns.span_id),
+                                    field(columns.parent_id),
+                                    field(columns.operation_name),
+                                    number(field(columns.start_time))?,
+                                    number(field(columns.duration))?,
+                                )
+                            });
+                            match parsed {
+                                Ok((trace_id, span)) => {
+                                    if options.keep(&trace_id) {
+                                        rows.push((trace_id, span));
+                                    }
+                                }
+                                Err(_) => skipped += 1,
+                            }
+                        }
+                        debug!("Parsed batch {}/{}", i + 1, batches.len());
+                    }
+                    (rows, skipped)
+                })
+            })
+            .collect();
+        workers.into_iter().map(|w| w.join().unwrap()).collect()
+    });
+    let skipped = results.iter().map(|(_, skipped)| skipped).sum();
+    Ok((
+        results.into_iter().flat_map(|(rows, _)| rows).collect(),
+        skipped,
+    ))
+}
+
+#[cfg(feature = "parquet")]
+fn read_parquet(path: &Path, options: &DatasetOptions) -> Result<(Rows, usize), Box<dyn Error>> {
+    use parquet::file::reader::FileReader;
+    use parquet::file::reader::SerializedFileReader;
+    use parquet::record::RowAccessor;
+
+    let reader = &SerializedFileReader::new(File::open(path)?)?;
+    let names: Vec<String> = reader
+        .metadata()
+        .file_metadata()
+        .schema_descr()
+        .root_schema()
+        .get_fields()
+        .iter()
+        .map(|f| f.name().to_string())
+        .collect();
+    let columns = &Columns::from_names(&names)?;
+    let row_groups = reader.metadata().num_row_groups();
+    let next = &AtomicUsize::new(0);
+    let results: Vec<Result<(Rows, usize), String>> = thread::scope(|s| {
+        let workers: Vec<_> = (0..options.n_workers.max(1))
+            .map(|_| {
+                s.spawn(move || {
+                    let mut rows = Vec::new();
+                    let mut skipped = 0;
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        if i >= row_groups {
+                            break;
+                        }
+                        let group = reader.get_row_group(i).map_err(|e| e.to_string())?;
+                        for row in group.get_row_iter(None).map_err(|e| e.to_string())? {
+                            let row = row.map_err(|e| e.to_string())?;
+                            let parsed = (|| {
+                                let string = |i| row.get_string(i).map_err(|e| e.to_string());
+                                let number = |i| row.get_long(i).map_err(|e| e.to_string());
+                                span(
+                                    string(columns.trace_id)?,
+                                    string(columns.span_id)?,
+                                    string(columns.parent_id).map(|s| s.as_str()).unwrap_or(""),
+                                    string(columns.operation_name)?,
+                                    number(columns.start_time)? as u64,
+                                    number(columns.duration)?,
+                                )
+                            })();
+                            match parsed {
+                                Ok((trace_id, span)) => {
+                                    if options.keep(&trace_id) {
+                                        rows.push((trace_id, span));
+                                    }
+                                }
+                                Err(_) => skipped += 1,
+                            }
+                        }
+                        debug!("Parsed row group {}/{}", i + 1, row_groups);
+                    }
+                    Ok((rows, skipped))
+                })
+            })
+            .collect();
+        workers.into_iter().map(|w| w.join().unwrap()).collect()
+    });
+    let mut rows = Vec::new();
+    let mut skipped = 0;
+    for result in results {
+        let (r, s) = result.map_err(|e| raise(&e))?;
+        rows.extend(r);
+        skipped += s;
+    }
+    Ok((rows, skipped))
+}
+
+#[cfg(not(feature = "parquet"))]
+fn read_parquet(_path: &Path, _options: &DatasetOptions) -> Result<(Rows, usize), Box<dyn Error>> {
+    Err(raise("Pythia was built without the parquet feature"))
+}
+
+/// Spans of each request, in order of trace ID so the same requests are kept by `max_traces`
+fn group(rows: Rows, max_traces: Option<usize>) -> Vec<(Uuid, Vec<UberSpan>)> {
+    let mut traces: HashMap<Uuid, Vec<UberSpan>> = HashMap::new();
+    for (trace_id, span) in rows {
+        traces.entry(trace_id).or_default().push(span);
+    }
+    let mut traces: Vec<_> = traces.into_iter().collect();
+    traces.sort_by_key(|&(trace_id, _)| trace_id);
+    if let Some(max) = max_traces {
+        traces.truncate(max);
+    }
+    traces
+}
+
+fn convert(
+    reader: &UberReader,
+    traces: Vec<(Uuid, Vec<UberSpan>)>,
+    n_workers: usize,
+) -> Vec<Trace> {
+    let progress = &Progress::new("Converting traces", traces.len());
+    let chunk_size = traces.len() / n_workers.max(1) + 1;
+    thread::scope(|s| {
+        let workers: Vec<_> = traces
+            .chunks(chunk_size)
+            .map(|chunk| {
+                s.spawn(move || {
+                    let mut result = Vec::new();
+                    for (trace_id, spans) in chunk {
+                        match reader.trace_from_spans(trace_id, spans) {
+                            Ok(t) => result.push(t),
+                            Err(e) => warn!(trace_id:% = trace_id; "Skipping trace: {}", e),
+                        }
+                        progress.tick();
+                    }
+                    result
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|w| w.join().unwrap())
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_batches_and_samples() {
+        let data = b"header\nab\ncdef\ng\n";
+        assert_eq!(batches(data, 7, 1), vec![(7, 10), (10, 15), (15, 17)]);
+        assert_eq!(batches(data, 7, 100), vec![(7, 17)]);
+
+        let options = |sample_rate| DatasetOptions {
+            sample_rate,
+            max_traces: None,
+            n_workers: 1,
+        };
+        let low = hex_id("1000000000000000").unwrap().to_uuid();
+        let high = hex_id("f000000000000000").unwrap().to_uuid();
+        assert!(options(0.5).keep(&low));
+        assert!(!options(0.5).keep(&high));
+        assert!(options(1.0).keep(&high));
+    }
+}
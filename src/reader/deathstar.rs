@@ -7,27 +7,22 @@ All rights reserved.
 */
 
 use std::collections::HashMap;
-use std::collections::HashSet;
 use std::convert::TryInto;
 use std::error::Error;
-use std::time::Duration;
+use std::sync::Arc;
 
 use byteorder::BigEndian;
 use byteorder::ByteOrder;
-use chrono::offset::Local;
 use chrono::NaiveDateTime;
-use futures::future;
-use futures::future::Future;
-use futures::stream::Stream;
-use futures::Async;
-use hyper::rt;
-use hyper::Client;
+use log::{debug, error};
 use petgraph::graph::NodeIndex;
-use regex::Regex;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use std::path::PathBuf;
 
+use crate::clock::Clock;
+use crate::clock::SystemClock;
+use crate::reader::xtrace::XTraceClient;
 use crate::reader::HexID;
 use crate::reader::Reader;
 use crate::settings::Settings;
@@ -39,13 +34,11 @@ use crate::trace::Value::SignedInt;
 use crate::trace::Value::Str;
 use crate::trace::Value::UnsignedInt;
 use crate::trace::{DAGEdge, EdgeType};
-use crate::PythiaError;
 //use crate::trace::Value::float;
 
 pub struct DEATHSTARReader {
-    xtrace_url: String,
-    jiffy: Duration,
-    processed_traces: HashSet<String>,
+    xtrace: XTraceClient,
+    clock: Arc<dyn Clock>,
     for_searchspace: bool,
     simplify_trace: bool,
     DEATHSTAR_trace_dir: PathBuf
@@ -56,57 +49,23 @@ impl Reader for DEATHSTARReader {
         self.for_searchspace = true;
     }
 
+    fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
     fn reset_state(&mut self) {}
 
-    /// This function parses an xtrace webpage to get all requests executed from
-    /// shell (with FsShell tag) and those with high enough elapsed time since last update
+    /// Requests with all of `xtrace_tags` that were not updated for a jiffy since the last call
     fn get_recent_traces(&mut self) -> Vec<Trace> {
-        let re1 = Regex::new(r"<td>").unwrap();
-        let re2 = Regex::new(r"tag/").unwrap();
-        let exclude1 = Regex::new(r"offset=").unwrap();
-        let exclude2 = Regex::new(r"<form").unwrap();
-        let xtrace_page = self
-            .download_webpage(format!("{}/tag/FsShell?length=100", self.xtrace_url))
-            .unwrap();
-        let mut result = Vec::new();
-        let mut trace_id: Option<String> = None;
-        let mut date_passed = false;
-        let main_re = Regex::new(r"tag/main").unwrap();
-        let delete_all = self.processed_traces.len() == 0;
-        for (idx, line) in xtrace_page
-            .lines()
-            .filter(|&s| re1.is_match(s) || re2.is_match(s))
-            .filter(|&s| !exclude1.is_match(s))
-            .filter(|&s| !exclude2.is_match(s))
-            .enumerate()
-        {
-            if idx % 10 == 0 {
-                trace_id = Some(line.split("\"").nth(5).unwrap().to_string());
-            } else if idx % 10 == 5 {
-                let date =
-                    NaiveDateTime::parse_from_str(line.trim(), "<td>%b %d %Y, %H:%M:%S</td>")
-                        .unwrap();
-                date_passed = (Local::now().naive_local() - date).to_std().unwrap() > self.jiffy;
-            } else if idx % 10 == 8 {
-                if date_passed
-                    && main_re.is_match(line)
-                    && self
-                        .processed_traces
-                        .get(&trace_id.clone().unwrap())
-                        .is_none()
-                {
-                    self.processed_traces.insert(trace_id.clone().unwrap());
-                    if !delete_all {
-                        result.push(trace_id.clone().unwrap());
-                    }
-                }
+        let ids = match self.xtrace.finished_tasks(self.clock.wall()) {
+            Ok(ids) => ids,
+            Err(e) => {
+                error!("Could not list X-Trace tasks: {}", e);
+                return Vec::new();
             }
-        }
-        result
-            .iter()
-            .map(|id| self.get_trace_from_base_id(id))
-            .filter(|x| x.is_ok())
-            .map(|x| x.unwrap())
+        };
+        ids.iter()
+            .filter_map(|id| self.get_trace_from_base_id(id).ok())
             .collect()
     }
 
@@ -127,7 +86,7 @@ impl Reader for DEATHSTARReader {
         let mut path = self.DEATHSTAR_trace_dir.clone();
         path.push(id);
         path.set_extension("json");
-        eprintln!("Reading {}", path.to_str().unwrap());
+        debug!("Reading {}", path.to_str().unwrap());
         let mut trace = self.read_file(&path.to_str().unwrap());
         Ok(trace)
     }
@@ -140,7 +99,6 @@ impl Reader for DEATHSTARReader {
             Err(_) => {
                 let reader = std::fs::File::open(file).unwrap();
                 // println!({})
-                println!("Reading  mert_ {:?}", reader);
                 let mut t: Vec<DEATHSTARTrace> = serde_json::from_reader(reader).unwrap();
                 assert!(t.len() == 1);
                 let mut trace = self.from_json(&mut t[0]);
@@ -153,19 +111,14 @@ impl Reader for DEATHSTARReader {
             }
         }
     }
-
-    fn read_dir(&mut self, _foldername: &str) -> Vec<Trace> {
-        Vec::new()
-    }
 }
 
 impl DEATHSTARReader {
     pub fn from_settings(settings: &Settings) -> Self {
         DEATHSTARReader {
-            xtrace_url: settings.xtrace_url.clone(),
+            xtrace: XTraceClient::from_settings(settings),
             DEATHSTAR_trace_dir: settings.DEATHSTAR_trace_dir.clone(),
-            jiffy: settings.jiffy,
-            processed_traces: HashSet::new(),
+            clock: Arc::new(SystemClock),
             for_searchspace: false,
             simplify_trace: true,
         }
@@ -185,39 +138,6 @@ impl DEATHSTARReader {
     //     }
     // }
 
-    fn download_webpage(&self, urn: String) -> Result<String, Box<dyn Error>> {
-        let (tx, mut rx) = futures::sync::mpsc::unbounded();
-
-        let fut = future::lazy(move || {
-            Client::new()
-                .get(urn.parse().unwrap())
-                .and_then(|res| res.into_body().concat2())
-                .and_then(move |body| {
-                    let s = ::std::str::from_utf8(&body).expect("httpbin sends utf-8 JSON");
-                    tx.unbounded_send(s.to_string()).unwrap();
-                    Ok(())
-                })
-                .map_err(|e| eprintln!("RPC Client error: {:?}", e))
-        });
-        rt::run(fut);
-        let mut result = "".to_string();
-        loop {
-            match rx.poll() {
-                Ok(Async::Ready(Some(s))) => {
-                    result = s;
-                }
-                Ok(Async::NotReady) => {}
-                Ok(Async::Ready(None)) => {
-                    break;
-                }
-                Err(_) => {
-                    return Err(Box::new(PythiaError("Poll got us Err".into())));
-                }
-            }
-        }
-        Ok(result)
-    }
-
     fn should_skip_edge(&self, mynode: &Event, parent: &Event) -> bool {
         if self.simplify_trace {
             (mynode.tracepoint_id == TracepointID::from_str("/tmp/xtrace-cpp/src/lua_baggage.cpp:33")
@@ -256,7 +176,7 @@ impl DEATHSTARReader {
 
     fn from_json(&self, data: &mut DEATHSTARTrace) -> Trace {
         let mut mydag = Trace::new(&data.id.to_uuid());
-        eprintln!("Working on {}", mydag.base_id);
+        debug!(trace_id:% = mydag.base_id; "Working on trace");
         let mut event_id_map = HashMap::new();
         let mut nidx = NodeIndex::end();
         let mut start_node = None;
@@ -296,8 +216,7 @@ impl DEATHSTARReader {
                         Some(&parent_nidx) => {
                             // Skip this edge, since it's not used.
                             if self.should_skip_edge(&mynode, &mydag.g[parent_nidx]) {
-                                println!("mert skip");
-                                println!("Skipped edge: {:?}",mynode.tracepoint_id);
+                                debug!("Skipped edge: {:?}",mynode.tracepoint_id);
                                 continue;
                             }
                             mydag.g.add_edge(
@@ -7,26 +7,27 @@ All rights reserved.
 */
 
 use std::collections::HashMap;
-use std::collections::HashSet;
 use std::convert::TryInto;
 use std::error::Error;
-use std::time::Duration;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
 
 use byteorder::BigEndian;
 use byteorder::ByteOrder;
-use chrono::offset::Local;
 use chrono::NaiveDateTime;
-use futures::future;
-use futures::future::Future;
-use futures::stream::Stream;
-use futures::Async;
-use hyper::rt;
-use hyper::Client;
+use log::{debug, error, trace, warn};
 use petgraph::graph::NodeIndex;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::clock::Clock;
+use crate::clock::SystemClock;
+use crate::progress::Progress;
+use crate::reader::trace_files;
+use crate::reader::xtrace::download_webpage;
+use crate::reader::xtrace::XTraceClient;
 use crate::reader::HexID;
 use crate::reader::Reader;
 use crate::settings::Settings;
@@ -38,157 +39,110 @@ use crate::trace::Value::SignedInt;
 use crate::trace::Value::Str;
 use crate::trace::Value::UnsignedInt;
 use crate::trace::{DAGEdge, EdgeType};
-use crate::PythiaError;
 
 use pythia_common::RequestType;
-use pythia_common::REQUEST_TYPES;
 //use crate::trace::Value::float;
 
 pub struct HDFSReader {
-    xtrace_url: String,
-    jiffy: Duration,
-    processed_traces: HashSet<String>,
-    for_searchspace: bool,
+    xtrace: XTraceClient,
+    clock: Arc<dyn Clock>,
     simplify_trace: bool,
+    /// Only files whose name matches are read from folders
+    file_pattern: Option<Regex>,
+    n_workers: usize,
 }
 
 impl Reader for HDFSReader {
-    fn for_searchspace(&mut self) {
-        self.for_searchspace = true;
+    fn for_searchspace(&mut self) {}
+
+    fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
     }
 
     fn reset_state(&mut self) {}
 
-    /// This function parses an xtrace webpage to get all requests executed from
-    /// shell (with FsShell tag) and those with high enough elapsed time since last update
+    /// Requests with all of `xtrace_tags` that were not updated for a jiffy since the last call
     fn get_recent_traces(&mut self) -> Vec<Trace> {
-        let re1 = Regex::new(r"<td>").unwrap();
-        let re2 = Regex::new(r"tag/").unwrap();
-        let exclude1 = Regex::new(r"offset=").unwrap();
-        let exclude2 = Regex::new(r"<form").unwrap();
-        let xtrace_page = self
-            .download_webpage(format!("{}/tag/FsShell?length=100", self.xtrace_url))
-            .unwrap();
-        let mut result = Vec::new();
-        let mut trace_id: Option<String> = None;
-        let mut date_passed = false;
-        let main_re = Regex::new(r"tag/main").unwrap();
-        let delete_all = self.processed_traces.len() == 0;
-        for (idx, line) in xtrace_page
-            .lines()
-            .filter(|&s| re1.is_match(s) || re2.is_match(s))
-            .filter(|&s| !exclude1.is_match(s))
-            .filter(|&s| !exclude2.is_match(s))
-            .enumerate()
-        {
-            if idx % 10 == 0 {
-                trace_id = Some(line.split("\"").nth(5).unwrap().to_string());
-            } else if idx % 10 == 5 {
-                let date =
-                    NaiveDateTime::parse_from_str(line.trim(), "<td>%b %d %Y, %H:%M:%S</td>")
-                        .unwrap();
-                date_passed = (Local::now().naive_local() - date).to_std().unwrap() > self.jiffy;
-            } else if idx % 10 == 8 {
-                if date_passed
-                    && main_re.is_match(line)
-                    && self
-                        .processed_traces
-                        .get(&trace_id.clone().unwrap())
-                        .is_none()
-                {
-                    self.processed_traces.insert(trace_id.clone().unwrap());
-                    if !delete_all {
-                        result.push(trace_id.clone().unwrap());
-                    }
-                }
+        let ids = match self.xtrace.finished_tasks(self.clock.wall()) {
+            Ok(ids) => ids,
+            Err(e) => {
+                error!("Could not list X-Trace tasks: {}", e);
+                return Vec::new();
             }
-        }
-        result
-            .iter()
-            .map(|id| self.get_trace_from_base_id(id))
-            .filter(|x| x.is_ok())
-            .map(|x| x.unwrap())
+        };
+        ids.iter()
+            .filter_map(|id| self.get_trace_from_base_id(id).ok())
             .collect()
     }
 
     fn get_trace_from_base_id(&mut self, id: &str) -> Result<Trace, Box<dyn Error>> {
         assert!(id.len() != 0);
-        let urn: String = format!("{}/interactive/reports/{}", self.xtrace_url, id);
-        let result = self.download_webpage(urn)?;
+        let result = download_webpage(self.xtrace.report_url(id))?;
         let mut t: Vec<HDFSTrace> = serde_json::from_str(&result)?;
         assert!(t.len() == 1);
-        let mut trace = self.from_json(&mut t[0]);
-        if self.for_searchspace {
-            trace.prune();
-        }
-        Ok(trace)
+        Ok(self.from_json(&mut t[0]))
     }
 
     fn read_file(&mut self, file: &str) -> Trace {
-        let reader = std::fs::File::open(file).unwrap();
-        match serde_json::from_reader(reader) {
-            // We either have a saved file, or saved xtrace output
-            Ok(v) => v,
-            Err(_) => {
-                let reader = std::fs::File::open(file).unwrap();
-                let mut t: Vec<HDFSTrace> = serde_json::from_reader(reader).unwrap();
-                assert!(t.len() == 1);
-                let mut trace = self.from_json(&mut t[0]);
-                if self.for_searchspace {
-                    trace.prune();
-                }
-                trace
-            }
-        }
+        self.try_read_file(Path::new(file)).unwrap()
     }
 
-    fn read_dir(&mut self, _foldername: &str) -> Vec<Trace> {
-        Vec::new()
+    /// X-Trace dumps are parsed on `n_workers` threads
+    fn read_dir(&mut self, foldername: &str) -> Vec<Trace> {
+        let files = trace_files(foldername, self.file_pattern.as_ref());
+        let progress = &Progress::new("Reading files", files.len());
+        let chunk_size = files.len() / self.n_workers + 1;
+        let reader = &*self;
+        thread::scope(|s| {
+            let workers: Vec<_> = files
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    s.spawn(move || {
+                        let mut traces = Vec::new();
+                        for file in chunk {
+                            match reader.try_read_file(file) {
+                                Ok(t) => traces.push(t),
+                                Err(e) => warn!("Skipping {:?}: {}", file, e),
+                            }
+                            progress.tick();
+                        }
+                        traces
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .flat_map(|w| w.join().unwrap())
+                .collect()
+        })
     }
 }
 
 impl HDFSReader {
     pub fn from_settings(settings: &Settings) -> Self {
         HDFSReader {
-            xtrace_url: settings.xtrace_url.clone(),
-            jiffy: settings.jiffy,
-            processed_traces: HashSet::new(),
-            for_searchspace: false,
+            xtrace: XTraceClient::from_settings(settings),
+            clock: Arc::new(SystemClock),
             simplify_trace: false,
+            file_pattern: settings
+                .trace_file_pattern
+                .as_ref()
+                .map(|p| Regex::new(p).expect("trace_file_pattern should be a regex")),
+            n_workers: settings.n_workers,
         }
     }
 
-    fn download_webpage(&self, urn: String) -> Result<String, Box<dyn Error>> {
-        let (tx, mut rx) = futures::sync::mpsc::unbounded();
-
-        let fut = future::lazy(move || {
-            Client::new()
-                .get(urn.parse().unwrap())
-                .and_then(|res| res.into_body().concat2())
-                .and_then(move |body| {
-                    let s = ::std::str::from_utf8(&body).expect("httpbin sends utf-8 JSON");
-                    tx.unbounded_send(s.to_string()).unwrap();
-                    Ok(())
-                })
-                .map_err(|e| eprintln!("RPC Client error: {:?}", e))
-        });
-        rt::run(fut);
-        let mut result = "".to_string();
-        loop {
-            match rx.poll() {
-                Ok(Async::Ready(Some(s))) => {
-                    result = s;
-                }
-                Ok(Async::NotReady) => {}
-                Ok(Async::Ready(None)) => {
-                    break;
-                }
-                Err(_) => {
-                    return Err(Box::new(PythiaError("Poll got us Err".into())));
-                }
-            }
+    /// We either have a saved file, or saved xtrace output
+    fn try_read_file(&self, file: &Path) -> Result<Trace, Box<dyn Error + Send + Sync>> {
+        let contents = std::fs::read_to_string(file)?;
+        if let Ok(trace) = serde_json::from_str(&contents) {
+            return Ok(trace);
+        }
+        let mut t: Vec<HDFSTrace> = serde_json::from_str(&contents)?;
+        if t.len() != 1 {
+            return Err(format!("expected one X-Trace report, found {}", t.len()).into());
         }
-        Ok(result)
+        Ok(self.from_json(&mut t[0]))
     }
 
     fn should_skip_edge(&self, mynode: &Event, parent: &Event) -> bool {
@@ -226,24 +180,25 @@ impl HDFSReader {
 
     fn from_json(&self, data: &mut HDFSTrace) -> Trace {
         let mut mydag = Trace::new(&data.id.to_uuid());
-        eprintln!("Working on {}", mydag.base_id);
+        debug!(trace_id:% = mydag.base_id; "Working on trace");
         let mut event_id_map = HashMap::new();
         let mut nidx = NodeIndex::end();
         let mut start_node = None;
         let mut wait_parents: HashMap<String, Vec<String>> = HashMap::new();
+        let mut write_size = 0;
         sort_event_list(&mut data.reports);
         for (_idx, event) in data.reports.iter().enumerate() {
             let mynode = Event::from_hdfs_node(event);
+            if let Some(Str(size)) = mynode.key_value_pair.get("Write Size") {
+                write_size += size.parse::<u64>().unwrap_or(0);
+            }
 
             let req_type: String = String::from("Executing command");
 
-            println!("Request type check: {:?}", event.label.to_string());
+            trace!("Request type check: {:?}", event.label.to_string());
             if event.label.to_string().eq(&req_type){
-                let substring = mynode.key_value_pair.get("Command").unwrap();
-
                 // Str(foo.tag[0][1..number].to_string()))
 
-                println!("**** Ukucu {:?}", substring);
                 
 
                 
@@ -254,8 +209,8 @@ impl HDFSReader {
                     Str(o) => o,
                     _ => panic!("Got something weird from request "),
                 };
+                trace!("Command {:?}", command);
 
-                println!("**** Ukucu2 {:?}", command.to_string());
 
                 
                 // mydag.request_type = RequestType::ServerCreate
@@ -333,10 +288,20 @@ impl HDFSReader {
         mydag.duration = (mydag.g[mydag.end_node].timestamp - mydag.g[mydag.start_node].timestamp)
             .to_std()
             .unwrap();
+        if write_size > 0 {
+            mydag
+                .request_params
+                .insert("write_size".to_string(), size_bucket(write_size));
+        }
         mydag
     }
 }
 
+/// Writes of similar sizes go to the same power-of-two bucket
+fn size_bucket(bytes: u64) -> String {
+    format!("<={}", bytes.next_power_of_two())
+}
+
 fn eventid_to_uuid(id: &String) -> Uuid {
     let id = id.parse::<i64>().unwrap();
     let mut buf = [0; 16];
@@ -0,0 +1,162 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Reads OpenStack traces from span batches that the agents publish to Kafka.
+//!
+//! Instead of polling redis on every agent, the controller consumes batches as they arrive and
+//! keeps them until the request looks finished: a request is built into a trace once a whole
+//! `get_recent_traces` cycle passes without new spans for it. Everything else (reading files,
+//! fetching a single trace) is done by the regular OSProfiler reader.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{error, warn};
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::{BaseConsumer, Consumer};
+use rdkafka::Message;
+use uuid::Uuid;
+
+use pythia_common::OSProfilerSpan;
+use pythia_common::SpanBatch;
+
+use crate::clock::Clock;
+use crate::critical::CriticalPath;
+use crate::reader::osprofiler::OSProfilerReader;
+use crate::reader::ParseReport;
+use crate::reader::Reader;
+use crate::settings::Settings;
+use crate::trace::Trace;
+
+/// How long to wait for the first message when draining the topic
+const POLL_TIMEOUT: Duration = Duration::from_millis(100);
+
+struct PendingRequest {
+    spans: Vec<OSProfilerSpan>,
+    /// Whether new spans arrived since the last cycle
+    updated: bool,
+}
+
+pub struct KafkaReader {
+    inner: OSProfilerReader,
+    consumer: BaseConsumer,
+    pending: HashMap<Uuid, PendingRequest>,
+}
+
+impl KafkaReader {
+    pub fn from_settings(settings: &Settings) -> KafkaReader {
+        let consumer: BaseConsumer = ClientConfig::new()
+            .set("bootstrap.servers", &settings.kafka_brokers)
+            .set("group.id", &settings.kafka_group)
+            .set("enable.auto.commit", "true")
+            .create()
+            .expect("Couldn't create Kafka consumer");
+        consumer
+            .subscribe(&[&settings.kafka_topic])
+            .expect("Couldn't subscribe to the span topic");
+        KafkaReader {
+            inner: OSProfilerReader::from_settings(settings),
+            consumer,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Reads all batches that are available right now
+    fn drain(&mut self) -> Vec<SpanBatch> {
+        let mut batches = Vec::new();
+        let mut timeout = POLL_TIMEOUT;
+        loop {
+            match self.consumer.poll(timeout) {
+                None => break,
+                Some(Err(e)) => {
+                    error!("Error consuming spans: {}", e);
+                    break;
+                }
+                Some(Ok(message)) => {
+                    match message.payload().map(|p| serde_json::from_slice::<SpanBatch>(p)) {
+                        Some(Ok(batch)) => batches.push(batch),
+                        Some(Err(e)) => warn!("Skipping malformed span batch: {}", e),
+                        None => {}
+                    }
+                }
+            }
+            // Only wait for the first message, then take what's already there
+            timeout = Duration::from_millis(0);
+        }
+        batches
+    }
+}
+
+impl Reader for KafkaReader {
+    fn read_file(&mut self, filename: &str) -> Trace {
+        self.inner.read_file(filename)
+    }
+
+    fn read_dir(&mut self, foldername: &str) -> Vec<Trace> {
+        self.inner.read_dir(foldername)
+    }
+
+    fn get_trace_from_base_id(&mut self, id: &str) -> Result<Trace, Box<dyn Error>> {
+        self.inner.get_trace_from_base_id(id)
+    }
+
+    fn get_recent_traces(&mut self) -> Vec<Trace> {
+        for batch in self.drain() {
+            let request = self
+                .pending
+                .entry(batch.base_id)
+                .or_insert_with(|| PendingRequest {
+                    spans: Vec::new(),
+                    updated: false,
+                });
+            request.spans.extend(batch.spans);
+            request.updated = true;
+        }
+        let finished = self
+            .pending
+            .iter()
+            .filter(|(_, r)| !r.updated)
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        let mut traces = Vec::new();
+        for id in finished {
+            let request = self.pending.remove(&id).unwrap();
+            match self.inner.trace_from_spans(id, request.spans) {
+                Ok(t) => match CriticalPath::from_trace(&t) {
+                    Ok(_) => traces.push(t),
+                    Err(e) => warn!(trace_id:% = id; "Dropping trace: {}", e),
+                },
+                Err(e) => warn!(trace_id:% = id; "Dropping trace: {}", e),
+            }
+        }
+        for request in self.pending.values_mut() {
+            request.updated = false;
+        }
+        traces
+    }
+
+    fn reset_state(&mut self) {
+        let dropped = self.drain().len();
+        self.pending.clear();
+        warn!("Dropped {} span batches", dropped);
+    }
+
+    fn for_searchspace(&mut self) {
+        self.inner.for_searchspace();
+    }
+
+    fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.inner.set_clock(clock);
+    }
+
+    fn parse_report(&self, id: &str) -> Option<&ParseReport> {
+        self.inner.parse_report(id)
+    }
+}
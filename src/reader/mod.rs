@@ -8,41 +8,89 @@ All rights reserved.
 
 //! This module contains a Reader trait, which reads traces.
 
+mod cache;
+mod dataset;
 mod hdfs;
 mod deathstar;
+#[cfg(feature = "kafka")]
+mod kafka;
 mod osprofiler;
+mod pipeline;
+mod replay;
+mod sampling;
+mod stitch;
 mod uber;
+mod xtrace;
 
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::fmt;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
 
 use hex;
 use itertools::Itertools;
+use log::{debug, error, warn};
+use regex::Regex;
 use serde::de;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use pythia_common::PythiaError;
+
+use crate::clock::Clock;
+use crate::progress::Progress;
 use crate::reader::hdfs::HDFSReader;
 use crate::reader::deathstar::DEATHSTARReader;
+#[cfg(feature = "kafka")]
+use crate::reader::kafka::KafkaReader;
 use crate::reader::osprofiler::OSProfilerReader;
+use crate::reader::pipeline::PipelineReader;
+use crate::reader::replay::ReplayReader;
+use crate::reader::sampling::SamplingReader;
 use crate::reader::uber::UberReader;
 use crate::settings::ApplicationType;
 use crate::settings::Settings;
+use crate::settings::TraceSource;
 use crate::trace::Trace;
 
+pub use crate::reader::pipeline::TracePass;
+pub use crate::reader::pipeline::TracePipeline;
+pub use crate::reader::pipeline::filter_events;
+pub use crate::reader::sampling::SamplingPolicy;
+pub use crate::reader::sampling::SamplingSettings;
+pub use crate::reader::stitch::stitch;
+pub use crate::reader::stitch::TraceStitcher;
+
 pub trait Reader {
     /// The file can contain a trace json, written by serde or by the tracing
     /// infrastructure
     fn read_file(&mut self, filename: &str) -> Trace;
     /// The folder contains files that may include trace jsons, written by serde
-    /// or by the tracing infrastructure
-    fn read_dir(&mut self, foldername: &str) -> Vec<Trace>;
+    /// or by the tracing infrastructure. Subfolders are read too.
+    fn read_dir(&mut self, foldername: &str) -> Vec<Trace> {
+        let files = trace_files(foldername, None);
+        let progress = Progress::new("Reading files", files.len());
+        files
+            .iter()
+            .map(|f| {
+                let trace = self.read_file(f.to_str().unwrap());
+                progress.tick();
+                trace
+            })
+            .collect()
+    }
     fn get_trace_from_base_id(&mut self, id: &str) -> Result<Trace, Box<dyn Error>>;
 
     /// This function collects new traces that have finished.
     ///
     /// It is called multiple times for OpenStack, which collects traces in the first
-    /// call and returns traces whose duration did not change in the second call.
+    /// call and returns traces whose duration did not change in the second call. If partial
+    /// traces are streamed, requests that are still running are also returned with `is_partial`
+    /// set; the same request can be returned again later.
     fn get_recent_traces(&mut self) -> Vec<Trace>;
 
     /// Used before get_recent_traces, so we know what is *recent*.
@@ -52,32 +100,263 @@ pub trait Reader {
     /// this function indicates this Reader will be used for search space
     fn for_searchspace(&mut self);
 
-    /// Read a file with one request ID per line
+    /// Replaces the clock used for deciding whether traces are old enough to be collected.
+    /// Readers that don't look at the time ignore it.
+    fn set_clock(&mut self, _clock: Arc<dyn Clock>) {}
+
+    /// Readers that replay a fixed set of traces return true once all of them were returned by
+    /// get_recent_traces. Live readers never run out.
+    fn is_exhausted(&self) -> bool {
+        false
+    }
+
+    /// Problems encountered while building the trace with the given base id, if any spans were
+    /// skipped. Readers that don't track this return None.
+    fn parse_report(&self, _id: &str) -> Option<&ParseReport> {
+        None
+    }
+
+    /// Read a file with one request ID per line. The IDs that fail are written to
+    /// `<tracefile>.failed` as they fail, and `retry_failed` takes the file.
     fn read_trace_file(&mut self, tracefile: &str) -> Vec<Trace> {
-        let trace_ids = std::fs::read_to_string(tracefile).unwrap();
+        let trace_ids = read_trace_ids(tracefile);
+        let failed_file = failed_traces_path(tracefile);
+        let mut failed = FailedTraceLog::new(failed_file.clone());
+        let traces = self.read_trace_ids(&trace_ids, &mut failed);
+        failed.finish(&failed_file);
+        traces
+    }
+
+    /// Read the IDs of a file of failed traces again; the ones that still fail replace the file
+    /// once all were read, so an interrupted retry can be started over
+    fn retry_failed(&mut self, failed_file: &str) -> Vec<Trace> {
+        let trace_ids = read_trace_ids(failed_file);
+        let mut failed = FailedTraceLog::new(PathBuf::from(format!("{}.tmp", failed_file)));
+        let traces = self.read_trace_ids(&trace_ids, &mut failed);
+        failed.finish(Path::new(failed_file));
+        traces
+    }
+
+    fn read_trace_ids(&mut self, trace_ids: &[String], failed: &mut FailedTraceLog) -> Vec<Trace> {
+        let progress = Progress::new("Reading traces", trace_ids.len());
         let mut traces = Vec::new();
-        for id in trace_ids.split('\n') {
-            if id.len() <= 1 {
-                continue;
-            }
-            println!("Working on {:?}", id);
+        for id in trace_ids {
+            debug!(trace_id = id; "Working on trace");
             match self.get_trace_from_base_id(id) {
                 Ok(t) => {
                     traces.push(t);
                 }
                 Err(e) => {
-                    eprintln!("Failed with {:?}", e);
+                    warn!(trace_id = id; "Failed with {:?}", e);
+                    failed.push(&FailedTrace::new(id, e.as_ref()));
                 }
             }
+            progress.tick();
         }
         traces
     }
 }
 
-/// Constructor for Reader
+/// A trace ID that could not be read, with why
+#[derive(Debug, Clone, PartialEq)]
+pub struct FailedTrace {
+    pub id: String,
+    /// `PythiaError::category`, or "other" for errors from libraries
+    pub category: String,
+    pub error: String,
+}
+
+impl FailedTrace {
+    fn new(id: &str, error: &(dyn Error + 'static)) -> FailedTrace {
+        FailedTrace {
+            id: id.to_string(),
+            category: error
+                .downcast_ref::<PythiaError>()
+                .map_or("other", |e| e.category())
+                .to_string(),
+            error: error.to_string().replace(['\t', '\n'], " "),
+        }
+    }
+}
+
+pub fn failed_traces_path(tracefile: &str) -> PathBuf {
+    PathBuf::from(format!("{}.failed", tracefile))
+}
+
+/// The first column of each line, so files of failed traces can be read as ID files too
+fn read_trace_ids(tracefile: &str) -> Vec<String> {
+    std::fs::read_to_string(tracefile)
+        .unwrap()
+        .lines()
+        .filter_map(|line| line.split('\t').next())
+        .filter(|id| id.len() > 1)
+        .map(|id| id.to_string())
+        .collect()
+}
+
+/// A file of failed traces, one tab-separated line per trace: ID, error category, error. Lines
+/// are written as traces fail, so a run that is interrupted leaves the failures so far.
+pub struct FailedTraceLog {
+    path: PathBuf,
+    /// Created with the first failure
+    file: Option<File>,
+    counts: BTreeMap<String, usize>,
+}
+
+impl FailedTraceLog {
+    pub fn new(path: PathBuf) -> Self {
+        FailedTraceLog {
+            path,
+            file: None,
+            counts: BTreeMap::new(),
+        }
+    }
+
+    pub fn push(&mut self, failed: &FailedTrace) {
+        *self.counts.entry(failed.category.clone()).or_insert(0) += 1;
+        if self.file.is_none() {
+            match File::create(&self.path) {
+                Ok(file) => self.file = Some(file),
+                Err(e) => error!("Could not write failed traces to {:?}: {}", self.path, e),
+            }
+        }
+        if let Some(file) = self.file.as_mut() {
+            let line = format!("{}\t{}\t{}", failed.id, failed.category, failed.error);
+            if let Err(e) = writeln!(file, "{}", line).and_then(|_| file.flush()) {
+                error!("Could not write failed traces to {:?}: {}", self.path, e);
+            }
+        }
+    }
+
+    /// Moves the file to `path`. Without failures, a file left at `path` from an earlier run is
+    /// removed.
+    pub fn finish(self, path: &Path) {
+        let result = if self.counts.is_empty() {
+            if path.exists() {
+                std::fs::remove_file(path)
+            } else {
+                Ok(())
+            }
+        } else {
+            warn!(
+                "{} traces failed ({:?}), retry them with --retry-failed {}",
+                self.counts.values().sum::<usize>(),
+                self.counts,
+                path.display()
+            );
+            match self.file {
+                Some(_) if self.path != path => std::fs::rename(&self.path, path),
+                _ => Ok(()),
+            }
+        };
+        if let Err(e) = result {
+            error!("Could not update failed traces in {:?}: {}", path, e);
+        }
+    }
+}
+
+/// All files under `folder` and its subfolders whose name matches `pattern`, if given. They are
+/// sorted so traces are read in the same order every time.
+pub fn trace_files(folder: &str, pattern: Option<&Regex>) -> Vec<PathBuf> {
+    let mut result = Vec::new();
+    let mut pending = vec![PathBuf::from(folder)];
+    while let Some(dir) = pending.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Could not read {:?}: {}", dir, e);
+                continue;
+            }
+        };
+        for entry in entries.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            if path.is_dir() {
+                pending.push(path);
+            } else if pattern.map_or(true, |p| {
+                p.is_match(&path.file_name().unwrap().to_string_lossy())
+            }) {
+                result.push(path);
+            }
+        }
+    }
+    result.sort();
+    result
+}
+
+/// Spans that were skipped or patched up while building a single trace
+#[derive(Debug, Default, Clone)]
+pub struct ParseReport {
+    /// (span id, reason) of the spans left out of the trace
+    pub skipped: Vec<(String, String)>,
+    /// Problems that were worked around without dropping the span
+    pub warnings: Vec<String>,
+}
+
+impl ParseReport {
+    pub fn skip(&mut self, span: &str, reason: String) {
+        warn!("Skipping span {}: {}", span, reason);
+        self.skipped.push((span.to_string(), reason));
+    }
+
+    pub fn warn(&mut self, warning: String) {
+        warn!("{}", warning);
+        self.warnings.push(warning);
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.skipped.is_empty() && self.warnings.is_empty()
+    }
+}
+
+impl fmt::Display for ParseReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} skipped spans, {} warnings",
+            self.skipped.len(),
+            self.warnings.len()
+        )?;
+        for (span, reason) in &self.skipped {
+            writeln!(f, "skipped {}: {}", span, reason)?;
+        }
+        for warning in &self.warnings {
+            writeln!(f, "warning: {}", warning)?;
+        }
+        Ok(())
+    }
+}
+
+/// Constructor for Reader. If `replay_dir` is set, the application's reader is wrapped in a
+/// reader that replays the archive there. Recent traces are then sampled as set in `sampling`,
+/// and every trace goes through `trace_pipeline`.
 pub fn reader_from_settings(settings: &Settings) -> Box<dyn Reader> {
+    let reader = match &settings.replay_dir {
+        Some(dir) => Box::new(ReplayReader::new(
+            application_reader(settings),
+            dir.to_str().unwrap(),
+        )),
+        None => application_reader(settings),
+    };
+    let reader = match settings.sampling.policy {
+        Some(_) => Box::new(SamplingReader::new(reader, settings.sampling.clone())),
+        None => reader,
+    };
+    if settings.trace_pipeline.is_empty() {
+        reader
+    } else {
+        Box::new(PipelineReader::new(reader, settings.trace_pipeline.clone()))
+    }
+}
+
+fn application_reader(settings: &Settings) -> Box<dyn Reader> {
     match &settings.application {
-        ApplicationType::OpenStack => Box::new(OSProfilerReader::from_settings(settings)),
+        ApplicationType::OpenStack => match settings.trace_source {
+            TraceSource::Redis => Box::new(OSProfilerReader::from_settings(settings)),
+            #[cfg(feature = "kafka")]
+            TraceSource::Kafka => Box::new(KafkaReader::from_settings(settings)),
+            #[cfg(not(feature = "kafka"))]
+            TraceSource::Kafka => panic!("Pythia was built without the kafka feature"),
+        },
         ApplicationType::HDFS => Box::new(HDFSReader::from_settings(settings)),
         ApplicationType::DEATHSTAR => Box::new(DEATHSTARReader::from_settings(settings)),
         ApplicationType::Uber => Box::new(UberReader::from_settings(settings)),
@@ -147,3 +426,59 @@ impl<'de> de::Visitor<'de> for HexIDVisitor {
         Ok(HexID { id: Some(result) })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_nested_files() {
+        let root = std::env::temp_dir().join(format!("pythia-trace-files-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(root.join("a/b")).unwrap();
+        for f in &["x.json", "a/y.json", "a/b/z.json", "a/b/notes.txt"] {
+            std::fs::write(root.join(f), "").unwrap();
+        }
+        let root_str = root.to_str().unwrap();
+        assert_eq!(trace_files(root_str, None).len(), 4);
+        let pattern = Regex::new(r"\.json$").unwrap();
+        assert_eq!(
+            trace_files(root_str, Some(&pattern)),
+            vec![root.join("a/b/z.json"), root.join("a/y.json"), root.join("x.json")]
+        );
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn failed_traces_can_be_read_back() {
+        let tracefile = std::env::temp_dir().join(format!("pythia-ids-{}", Uuid::new_v4()));
+        let tracefile = tracefile.to_str().unwrap();
+        let failed_file = failed_traces_path(tracefile);
+        let failed = vec![
+            FailedTrace::new("a1", &PythiaError::RpcError("timed out\nagain".to_string())),
+            FailedTrace::new("b2", &std::fmt::Error),
+        ];
+        assert_eq!(failed[0].category, "rpc");
+        assert_eq!(failed[1].category, "other");
+        let mut log = FailedTraceLog::new(failed_file.clone());
+        log.push(&failed[0]);
+        // Written before the run ends
+        assert_eq!(
+            std::fs::read_to_string(&failed_file).unwrap().lines().next(),
+            Some("a1\trpc\tPythia RPC error: timed out again")
+        );
+        log.push(&failed[1]);
+        log.finish(&failed_file);
+        assert_eq!(read_trace_ids(failed_file.to_str().unwrap()), vec!["a1", "b2"]);
+
+        // A retry replaces the file when it is done
+        let retry_file = PathBuf::from(format!("{}.tmp", failed_file.display()));
+        let mut log = FailedTraceLog::new(retry_file.clone());
+        log.push(&failed[1]);
+        assert_eq!(read_trace_ids(failed_file.to_str().unwrap()).len(), 2);
+        log.finish(&failed_file);
+        assert!(!retry_file.exists());
+        assert_eq!(read_trace_ids(failed_file.to_str().unwrap()), vec!["b2"]);
+        FailedTraceLog::new(retry_file).finish(&failed_file);
+        assert!(!failed_file.exists());
+    }
+}
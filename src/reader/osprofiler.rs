@@ -8,30 +8,46 @@ All rights reserved.
 
 /// Stuff related to working with osprofiler
 ///
-use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::error::Error;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::Duration;
+use std::time::Instant;
 
+use chrono::NaiveDateTime;
+use log::{debug, error, info, warn};
 use petgraph::graph::NodeIndex;
 use redis::Commands;
 use redis::Connection;
 use uuid::Uuid;
 
+use pythia_common::osprofiler::parse_kwargs;
 use pythia_common::osprofiler::ExitEnum;
+use pythia_common::merge_sorted_spans;
+use pythia_common::sort_spans;
 use pythia_common::AnnotationEnum;
 use pythia_common::OSProfilerEnum;
 use pythia_common::OSProfilerSpan;
 use pythia_common::RequestType;
-use pythia_common::REQUEST_TYPES;
-use pythia_common::REQUEST_TYPE_REGEXES;
 
+use crate::clock::Clock;
+use crate::clock::SystemClock;
 use crate::critical::CriticalPath;
+use crate::progress::Progress;
+use crate::reader::cache::SpanCache;
+use crate::reader::ParseReport;
 use crate::reader::Reader;
 
+use crate::rpclib::call_all_clients;
 use crate::rpclib::free_keys;
-use crate::rpclib::get_events_from_client;
+use crate::rpclib::get_events_after_from_client;
+use crate::rpclib::get_span_count_from_client;
+use crate::rpclib::get_trace_fragment_from_client;
+use crate::rpclib::RetryPolicy;
+use crate::settings::OutlierAction;
 use crate::settings::Settings;
 use crate::trace::Event;
 use crate::trace::EventType;
@@ -40,18 +56,101 @@ use crate::trace::TracepointID;
 use crate::trace::{DAGEdge, EdgeType};
 use crate::PythiaError;
 
+/// Requests whose root span hasn't ended and that got no new spans for this long are given up on
+const STALLED_TRACE_TIMEOUT: Duration = Duration::from_secs(3600);
+
+/// Where an unfinished request is, from the span counts of the agents
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RequestProgress {
+    /// No agent has spans of it
+    Missing,
+    /// Its root span hasn't ended, and it got new spans since the last poll
+    Running,
+    /// Its root span hasn't ended, and it got no new spans since the last poll (e.g., it is
+    /// waiting on something slow, or the exit of its root span got lost)
+    Stalled,
+    /// Its root span has ended
+    Ended,
+}
+
+impl RequestProgress {
+    fn of(counts: &[usize], root_exit: bool, previous: Option<&Vec<usize>>) -> RequestProgress {
+        if counts.iter().sum::<usize>() == 0 {
+            RequestProgress::Missing
+        } else if root_exit {
+            RequestProgress::Ended
+        } else if previous.map(|p| p.as_slice()) == Some(counts) {
+            RequestProgress::Stalled
+        } else {
+            RequestProgress::Running
+        }
+    }
+}
+
+/// Whether a trace that was just read is the whole request: its root span has ended and no
+/// spans were added while it was read (or since the last poll, if some spans couldn't be
+/// parsed). If the agents can't count spans, its duration has to stay the same for a poll
+/// instead. Spans from agents that timed out could still change either.
+fn is_complete(
+    counts: Option<&(Vec<usize>, bool)>,
+    counts_stable: bool,
+    received_spans: usize,
+    duration_stable: bool,
+    agents_answered: bool,
+) -> bool {
+    agents_answered
+        && match counts {
+            Some((c, root_exit)) => {
+                let all_read = c.iter().sum::<usize>() == received_spans;
+                *root_exit && (all_read || counts_stable)
+            }
+            None => duration_stable,
+        }
+}
+
 use crate::trace::Value::SignedInt;
 use crate::trace::Value::UnsignedInt;
 //use crate::trace::Value::float;
 use crate::trace::Value::Str;
 
 pub struct OSProfilerReader {
-    connection: Connection,
+    // Not needed when reading traces from files
+    connection: Option<Connection>,
     client_list: Vec<String>,
+    retry_policy: RetryPolicy,
+    agent_timeout: Duration,
+    // Agents that didn't send their spans in time for the trace being built
+    timed_out_agents: Vec<String>,
+    // Spans received for the trace being built, without those of asynchronous children
+    received_spans: usize,
+    // Duration of each unfinished request at the last poll, when the agents can't count spans
     prev_traces: HashMap<String, Duration>,
+    // Spans of each unfinished request on each agent at the last poll
+    prev_span_counts: HashMap<String, Vec<usize>>,
+    // False once an agent turned out not to have `get_span_count`
+    count_spans: bool,
     trace_error_count: HashMap<String, usize>,
     for_searchspace: bool,
     free_keys: bool,
+    clock_skew_correction: bool,
+    clock_skew_warning: Duration,
+    outlier_threshold: Option<Duration>,
+    outlier_action: OutlierAction,
+    // Estimated clock offset of each host for the trace being built
+    clock_offsets: HashMap<String, chrono::Duration>,
+    // Problems found in the trace being built
+    report: ParseReport,
+    // Reports of the traces where spans were skipped or patched up
+    parse_reports: HashMap<Uuid, ParseReport>,
+    stream_partial_traces: bool,
+    partial_trace_age: Duration,
+    // When each unfinished request was first seen
+    first_seen: HashMap<String, Instant>,
+    // Since when each stalled request got no new spans
+    stalled_since: HashMap<String, Instant>,
+    clock: Arc<dyn Clock>,
+    // Spans of unfinished requests, so only new spans are fetched
+    span_cache: SpanCache<OSProfilerSpan>,
 }
 
 impl Reader for OSProfilerReader {
@@ -59,14 +158,24 @@ impl Reader for OSProfilerReader {
         self.for_searchspace = true;
     }
 
+    fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    fn parse_report(&self, id: &str) -> Option<&ParseReport> {
+        self.parse_reports.get(&Uuid::parse_str(id).ok()?)
+    }
+
     fn reset_state(&mut self) {
+        self.parse_reports.clear();
+        self.span_cache.clear();
         if self.free_keys {
             redis::cmd("flushall")
-                .query::<()>(&mut self.connection)
+                .query::<()>(self.connection())
                 .ok();
         } else {
             loop {
-                match self.connection.lpop::<_, String>("osprofiler_traces") {
+                match self.connection().lpop::<_, String>("osprofiler_traces") {
                     Ok(_) => {}
                     Err(_) => {
                         break;
@@ -79,7 +188,7 @@ impl Reader for OSProfilerReader {
     fn get_recent_traces(&mut self) -> Vec<Trace> {
         let mut ids = Vec::new();
         loop {
-            let id: String = match self.connection.lpop("osprofiler_traces") {
+            let id: String = match self.connection().lpop("osprofiler_traces") {
                 Ok(i) => i,
                 Err(_) => {
                     break;
@@ -87,18 +196,20 @@ impl Reader for OSProfilerReader {
             };
             ids.push(id);
         }
-        for id in self.prev_traces.keys() {
-            ids.push(id.clone());
-        }
+        let unfinished: HashSet<&String> = self
+            .prev_traces
+            .keys()
+            .chain(self.prev_span_counts.keys())
+            .collect();
+        ids.extend(unfinished.into_iter().cloned());
         let mut traces = Vec::new();
         let mut keys = Vec::new();
         for id in &ids {
             match self.trace_error_count.get(id) {
                 Some(&i) => {
                     if i > 5 {
-                        self.prev_traces.remove(id);
-                        self.trace_error_count.remove(id);
-                        eprintln!("Giving up on {}", id);
+                        self.forget(id);
+                        warn!(trace_id:% = id; "Giving up on trace");
                         continue;
                     }
                 }
@@ -106,27 +217,58 @@ impl Reader for OSProfilerReader {
                     self.trace_error_count.insert(id.clone(), 0);
                 }
             }
-            match self.get_trace_from_base_id(&id) {
-                Ok(t) => {
-                    // Keep traces for one cycle, use them only when the duration becomes stable
-                    // (i.e., request has finished)
-                    let stable = match self.prev_traces.get(id) {
-                        Some(&d) => {
-                            if d == t.duration {
-                                true
-                            } else {
-                                false
-                            }
+            let counts = self.span_counts(id);
+            // The same counts as last time
+            let counts_stable = counts
+                .as_ref()
+                .is_some_and(|(c, _)| self.prev_span_counts.get(id) == Some(c));
+            if let Some((c, root_exit)) = &counts {
+                let progress = RequestProgress::of(c, *root_exit, self.prev_span_counts.get(id));
+                match progress {
+                    // Give up eventually
+                    RequestProgress::Missing => *self.trace_error_count.get_mut(id).unwrap() += 1,
+                    RequestProgress::Stalled => {
+                        let now = self.clock.now();
+                        let since = *self.stalled_since.entry(id.clone()).or_insert(now);
+                        if now - since > STALLED_TRACE_TIMEOUT {
+                            warn!(
+                                trace_id:% = id;
+                                "Giving up on trace, no new spans for {:?} and its root span \
+                                 hasn't ended",
+                                now - since
+                            );
+                            self.forget(id);
+                            continue;
                         }
-                        None => false,
-                    };
+                    }
+                    _ => {
+                        self.stalled_since.remove(id);
+                    }
+                }
+                if !root_exit {
+                    self.prev_span_counts.insert(id.clone(), c.clone());
+                    if !self.stream_partial_traces {
+                        // Still running, no need to fetch it
+                        continue;
+                    }
+                }
+            }
+            match self.get_trace_from_base_id(id) {
+                Ok(t) => {
+                    // Use traces only once the request has finished
+                    let stable = is_complete(
+                        counts.as_ref(),
+                        counts_stable,
+                        self.received_spans,
+                        self.prev_traces.get(id) == Some(&t.duration),
+                        self.timed_out_agents.is_empty(),
+                    );
                     if stable {
                         match CriticalPath::from_trace(&t) {
                             Ok(_) => {
                                 keys.extend(t.keys.iter().cloned());
                                 traces.push(t);
-                                self.prev_traces.remove(id);
-                                self.trace_error_count.remove(id);
+                                self.forget(id);
                             }
                             Err(_) => {
                                 *self.trace_error_count.get_mut(id).unwrap() += 1;
@@ -135,6 +277,12 @@ impl Reader for OSProfilerReader {
                         }
                     } else {
                         self.prev_traces.insert(id.clone(), t.duration);
+                        if let Some((c, _)) = counts {
+                            self.prev_span_counts.insert(id.clone(), c);
+                        }
+                        if let Some(partial) = self.partial_trace(id, &t) {
+                            traces.push(partial);
+                        }
                     }
                 }
                 Err(_) => {
@@ -144,7 +292,9 @@ impl Reader for OSProfilerReader {
         }
         if self.free_keys {
             for node in self.client_list.iter() {
-                free_keys(node, keys.clone());
+                if let Err(e) = free_keys(node, keys.clone(), &self.retry_policy) {
+                    error!("Could not free keys on {}: {}", node, e);
+                }
             }
         }
         traces
@@ -156,8 +306,26 @@ impl Reader for OSProfilerReader {
         self.from_event_list(Uuid::nil(), t).unwrap()
     }
 
-    fn read_dir(&mut self, _foldername: &str) -> Vec<Trace> {
-        Vec::new()
+    /// Reads every file in the folder as a list of spans, one trace per file
+    fn read_dir(&mut self, foldername: &str) -> Vec<Trace> {
+        let paths: Vec<PathBuf> = match std::fs::read_dir(foldername) {
+            Ok(entries) => entries.filter_map(|e| e.ok()).map(|e| e.path()).collect(),
+            Err(e) => {
+                warn!("Could not read {}: {}", foldername, e);
+                Vec::new()
+            }
+        };
+        let progress = Progress::new("Reading files", paths.len());
+        let mut results = Vec::new();
+        for path in paths {
+            match self.read_span_file(&path) {
+                Ok(Some(t)) => results.push(t),
+                Ok(None) => {}
+                Err(e) => warn!("Skipping {:?}: {}", path, e),
+            }
+            progress.tick();
+        }
+        results
     }
     /*
 
@@ -165,7 +333,7 @@ impl Reader for OSProfilerReader {
 
         let base_id = Uuid::parse_str(id).ok().unwrap();
         let mut event_list = self.get_matches_(&base_id).unwrap();
-        sort_event_list(&mut event_list);
+        sort_spans(&mut event_list);
         let mut tracepoint_id_map: HashMap<Uuid, String> = HashMap::new();
         for event in event_list.iter_mut() {
             event.tracepoint_id = event.get_tracepoint_id(&mut tracepoint_id_map);
@@ -265,12 +433,14 @@ impl Reader for OSProfilerReader {
     }*/
 
     fn get_trace_from_base_id(&mut self, id: &str) -> Result<Trace, Box<dyn Error>> {
-        eprintln!("Working on {}", id);
+        debug!(trace_id:% = id; "Working on trace");
+        self.timed_out_agents.clear();
         let mut result = match Uuid::parse_str(id) {
             Ok(uuid) => {
                 let event_list = self.get_all_matches(&uuid);
+                self.received_spans = event_list.len();
                 if event_list.len() == 0 {
-                    return Err(Box::new(PythiaError(
+                    return Err(Box::new(PythiaError::ReaderError(
                         format!("No traces match the uuid {}", uuid).into(),
                     )));
                 }
@@ -281,13 +451,20 @@ impl Reader for OSProfilerReader {
                 panic!("Malformed UUID received as base ID: {}", id);
             }
         };
+        if !self.timed_out_agents.is_empty() && self.for_searchspace {
+            // Missing paths would end up in the manifest
+            return Err(Box::new(PythiaError::RpcError(format!(
+                "Incomplete trace, no spans from {:?}",
+                self.timed_out_agents
+            ))));
+        }
         if result.request_type == RequestType::Unknown {
-            eprintln!("Warning: couldn't get type for request {}", id);
+            warn!(trace_id:% = id; "Couldn't get the request type");
         }
         result.duration = (result.g[result.end_node].timestamp
             - result.g[result.start_node].timestamp)
             .to_std()
-            .unwrap();
+            .unwrap_or(Duration::new(0, 0));
         Ok(result)
     }
 }
@@ -296,22 +473,184 @@ impl OSProfilerReader {
     pub fn from_settings(settings: &Settings) -> OSProfilerReader {
         let redis_url = &settings.redis_url;
         let client = redis::Client::open(&redis_url[..]).unwrap();
-        let con = client.get_connection().unwrap();
+        let con = match client.get_connection() {
+            Ok(con) => Some(con),
+            Err(e) => {
+                error!("Could not connect to redis at {}: {}", redis_url, e);
+                None
+            }
+        };
         OSProfilerReader {
             connection: con,
             client_list: settings.pythia_clients.clone(),
+            retry_policy: RetryPolicy::from_settings(settings),
+            agent_timeout: settings.agent_timeout,
+            timed_out_agents: Vec::new(),
+            received_spans: 0,
             prev_traces: HashMap::new(),
+            prev_span_counts: HashMap::new(),
+            count_spans: true,
             trace_error_count: HashMap::new(),
             for_searchspace: false,
             free_keys: settings.free_keys,
+            clock_skew_correction: settings.clock_skew_correction,
+            clock_skew_warning: settings.clock_skew_warning,
+            outlier_threshold: settings.outlier_threshold,
+            outlier_action: settings.outlier_action,
+            clock_offsets: HashMap::new(),
+            report: ParseReport::default(),
+            parse_reports: HashMap::new(),
+            stream_partial_traces: settings.stream_partial_traces,
+            partial_trace_age: settings.partial_trace_age,
+            first_seen: HashMap::new(),
+            stalled_since: HashMap::new(),
+            clock: Arc::new(SystemClock),
+            span_cache: SpanCache::new(settings.span_cache_size),
+        }
+    }
+
+    /// Drop what is kept about a request that is done or given up on
+    fn forget(&mut self, id: &str) {
+        if let Ok(uuid) = Uuid::parse_str(id) {
+            self.span_cache.remove(&uuid);
         }
+        self.prev_traces.remove(id);
+        self.prev_span_counts.remove(id);
+        self.trace_error_count.remove(id);
+        self.first_seen.remove(id);
+        self.stalled_since.remove(id);
     }
 
-    /// Get matching events from all redis instances
+    /// How many spans of the request each agent has, and whether its root span has ended; None
+    /// if an agent couldn't tell
+    fn span_counts(&mut self, id: &str) -> Option<(Vec<usize>, bool)> {
+        if !self.count_spans {
+            return None;
+        }
+        let trace_id = Uuid::parse_str(id).ok()?;
+        // Counting is only a shortcut, not worth retrying
+        let policy = RetryPolicy {
+            retries: 0,
+            ..self.retry_policy.clone()
+        };
+        let answers = call_all_clients(&self.client_list, self.agent_timeout, move |node| {
+            get_span_count_from_client(node, trace_id, &policy)
+        });
+        if !answers.timed_out.is_empty() {
+            return None;
+        }
+        let mut counts = Vec::new();
+        let mut root_exit = false;
+        for (node, answer) in answers.results {
+            match answer {
+                Ok(Some(count)) => {
+                    counts.push(count.spans);
+                    root_exit |= count.root_exit;
+                }
+                Ok(None) => {
+                    warn!(
+                        "{} can't count spans, waiting for durations to settle instead",
+                        node
+                    );
+                    self.count_spans = false;
+                    return None;
+                }
+                Err(_) => return None,
+            }
+        }
+        Some((counts, root_exit))
+    }
+
+    /// If streaming is enabled and the request has been running long enough, returns the
+    /// completed prefix of its trace so that it can be grouped before the request finishes.
+    fn partial_trace(&mut self, id: &str, trace: &Trace) -> Option<Trace> {
+        if !self.stream_partial_traces {
+            return None;
+        }
+        let now = self.clock.now();
+        let first_seen = *self.first_seen.entry(id.to_string()).or_insert(now);
+        if now - first_seen < self.partial_trace_age {
+            return None;
+        }
+        let prefix = trace.completed_prefix()?;
+        CriticalPath::from_trace(&prefix).ok()?;
+        info!(trace_id:% = id; "Emitting partial trace, {:?} so far", prefix.duration);
+        Some(prefix)
+    }
+
+    /// The trace of a file with a list of spans, None if the list is empty
+    fn read_span_file(&mut self, path: &Path) -> Result<Option<Trace>, String> {
+        let file = std::fs::File::open(path).map_err(|e| e.to_string())?;
+        let spans: Vec<OSProfilerSpan> =
+            serde_json::from_reader(file).map_err(|e| e.to_string())?;
+        if spans.is_empty() {
+            return Ok(None);
+        }
+        let base_id = spans[0].base_id;
+        self.trace_from_spans(base_id, spans)
+            .map(Some)
+            .map_err(|e| e.to_string())
+    }
+
+    /// Builds a trace from spans that were collected elsewhere (a file, Kafka, etc.)
+    pub fn trace_from_spans(
+        &mut self,
+        base_id: Uuid,
+        spans: Vec<OSProfilerSpan>,
+    ) -> Result<Trace, Box<dyn Error>> {
+        let mut t = self.from_event_list(base_id, spans)?;
+        t.duration = (t.g[t.end_node].timestamp - t.g[t.start_node].timestamp)
+            .to_std()
+            .unwrap_or(Duration::new(0, 0));
+        Ok(t)
+    }
+
+    fn connection(&mut self) -> &mut Connection {
+        self.connection
+            .as_mut()
+            .expect("This needs a connection to the redis server")
+    }
+
+    /// Get matching events from all redis instances, asking the agents concurrently. An agent
+    /// that nothing was received from yet sends its fragment sorted; with the span cache, the
+    /// others only send the events that were not received before. The fragments are merged.
+    /// Agents that don't answer within `agent_timeout` are added to `timed_out_agents`.
     fn get_all_matches(&mut self, span_id: &Uuid) -> Vec<OSProfilerSpan> {
-        let mut event_list = Vec::new();
-        for node in self.client_list.iter() {
-            event_list.extend(get_events_from_client(node, span_id.clone()));
+        let trace_id = *span_id;
+        let policy = self.retry_policy.clone();
+        let skips: HashMap<String, usize> = self
+            .client_list
+            .iter()
+            .map(|node| (node.clone(), self.span_cache.span_count(span_id, node)))
+            .collect();
+        let fetch = move |node: &str| match skips[node] {
+            0 => get_trace_fragment_from_client(node, trace_id, &policy).map(|fragment| {
+                let sent = fragment.spans.len();
+                (fragment.spans, sent)
+            }),
+            skip => get_events_after_from_client(node, trace_id, skip, &policy),
+        };
+        let answers = call_all_clients(&self.client_list, self.agent_timeout, fetch);
+        let mut fragments = Vec::new();
+        for (node, answer) in answers.results {
+            match answer {
+                Ok((events, sent)) if self.span_cache.is_enabled() => {
+                    let cached = self.span_cache.extend(trace_id, &node, sent, events);
+                    fragments.push(cached.to_vec());
+                }
+                Ok((events, _)) => fragments.push(events),
+                Err(e) => warn!(trace_id:% = span_id; "Skipping events from {}: {}", node, e),
+            }
+        }
+        // Events appended to a cached fragment may be out of order; `add_events` sorts them
+        let event_list = merge_sorted_spans(fragments);
+        let timed_out = answers.timed_out;
+        if !timed_out.is_empty() {
+            warn!(
+                trace_id:% = span_id;
+                "No events from {:?} within {:?}", timed_out, self.agent_timeout
+            );
+            self.timed_out_agents.extend(timed_out);
         }
         event_list
     }
@@ -322,7 +661,22 @@ impl OSProfilerReader {
         mut event_list: Vec<OSProfilerSpan>,
     ) -> Result<Trace, Box<dyn Error>> {
         let mut mydag = Trace::new(&id);
-        self.add_events(&mut mydag, &mut event_list, None)?;
+        self.clock_offsets.clear();
+        self.report = ParseReport::default();
+        let result = self.add_events(&mut mydag, &mut event_list, None);
+        let report = std::mem::take(&mut self.report);
+        if !report.is_clean() {
+            warn!(trace_id:% = id; "Parse report: {}", report);
+            self.parse_reports.insert(id, report);
+        }
+        result?;
+        if mydag.start_node == NodeIndex::end() {
+            return Err(Box::new(PythiaError::ReaderError(
+                format!("No usable spans for {}", id).into(),
+            )));
+        }
+        let salient = mydag.request_type.salient_parameters();
+        mydag.request_params.retain(|k, _| salient.contains(&k.as_str()));
         Ok(mydag)
     }
 
@@ -335,7 +689,16 @@ impl OSProfilerReader {
         if event_list.len() == 0 {
             return Ok(None);
         }
-        sort_event_list(event_list);
+        if self.clock_skew_correction {
+            correct_clock_skew(event_list, &mut self.clock_offsets, self.clock_skew_warning);
+        }
+        self.guard_outliers(event_list);
+        if event_list.is_empty() {
+            return Ok(None);
+        }
+        // Cheap when the spans came as sorted fragments, but clock skew correction and spans from
+        // the cache or files can be out of order
+        sort_spans(event_list);
         let base_id = event_list[0].base_id;
         dag.keys.push(format!("osprofiler:{}", base_id));
         let start_time = event_list[0].timestamp;
@@ -356,20 +719,47 @@ impl OSProfilerReader {
         let mut prev_nidx = None;
         let mut prev_time = start_time;
         for (idx, event) in event_list.iter().enumerate() {
-            assert!(event.base_id == base_id);
+            if event.base_id != base_id {
+                self.report.skip(
+                    &event.trace_id.to_string(),
+                    format!("base id {} does not match {}", event.base_id, base_id),
+                );
+                continue;
+            }
             assert!(prev_time <= event.timestamp);
             prev_time = event.timestamp;
             let mut mynode = Event::from_osp_span(event);
-            let current_tracepoint_id = event.get_tracepoint_id(&mut tracepoint_id_map)?;
+            let current_tracepoint_id = match event.get_tracepoint_id(&mut tracepoint_id_map) {
+                Ok(id) => id,
+                Err(e) => {
+                    self.report.skip(&event.trace_id.to_string(), e.to_string());
+                    continue;
+                }
+            };
             mynode.tracepoint_id = TracepointID::from_str(&current_tracepoint_id);
             if mynode.variant == EventType::Entry {
-                let matches: Vec<usize> = REQUEST_TYPE_REGEXES
-                    .matches(&current_tracepoint_id)
-                    .iter()
-                    .collect();
-                if matches.len() > 0 {
-                    assert!(matches.len() == 1);
-                    dag.request_type = REQUEST_TYPES[matches[0]];
+                let matches = RequestType::matching(&current_tracepoint_id);
+                if matches.len() == 1 {
+                    dag.request_type = matches[0];
+                } else if matches.len() > 1 {
+                    self.report.warn(format!(
+                        "{} matches {} request types, not setting request type",
+                        current_tracepoint_id,
+                        matches.len()
+                    ));
+                }
+            }
+            let adds_node = match &event.info {
+                OSProfilerEnum::Annotation(AnnotationEnum::WaitFor(_))
+                | OSProfilerEnum::Annotation(AnnotationEnum::Child(_)) => false,
+                _ => !wait_spans.contains(&mynode.trace_id),
+            };
+            if adds_node {
+                if let Some(reason) =
+                    missing_relation(event, idx, &children_per_parent, &active_spans, &id_map)
+                {
+                    self.report.skip(&event.trace_id.to_string(), reason);
+                    continue;
                 }
             }
             // Don't add asynch_wait into the DAGs
@@ -406,33 +796,34 @@ impl OSProfilerReader {
                     AnnotationEnum::WaitFor(_) => {
                         wait_spans.insert(event.trace_id);
                     }
-                    AnnotationEnum::Child(c) => {
-                        async_traces.insert(
-                            c.child_id,
-                            match prev_nidx {
-                                Some(i) => i,
-                                None => match parent_of_trace {
-                                    Some(nidx) => nidx,
-                                    None => {
-                                        return Err(Box::new(PythiaError(
-                                            "No parent, start with annotation".into(),
-                                        )))
-                                    }
-                                },
-                            },
-                        );
+                    AnnotationEnum::Args(a) => {
+                        // Only the salient ones are kept once the request type is known
+                        dag.request_params.extend(parse_kwargs(&a.function.kwargs));
                     }
+                    AnnotationEnum::Child(c) => match prev_nidx.or(parent_of_trace) {
+                        Some(i) => {
+                            async_traces.insert(c.child_id, i);
+                        }
+                        None => {
+                            self.report.skip(
+                                &c.child_id.to_string(),
+                                "No parent, start with annotation".to_string(),
+                            );
+                        }
+                    },
                     _ => {}
                 }
             }
             if !nidx.is_none() && !parent_of_trace.is_none() {
+                let duration = self.edge_duration(
+                    dag.g[parent_of_trace.unwrap()].timestamp,
+                    event.timestamp,
+                );
                 dag.g.add_edge(
                     parent_of_trace.unwrap(),
                     nidx.unwrap(),
                     DAGEdge {
-                        duration: (event.timestamp - dag.g[parent_of_trace.unwrap()].timestamp)
-                            .to_std()
-                            .unwrap(),
+                        duration,
                         variant: EdgeType::FollowsFrom,
                     },
                 );
@@ -447,70 +838,31 @@ impl OSProfilerReader {
             }
             match &event.info {
                 OSProfilerEnum::FunctionEntry(_) | OSProfilerEnum::RequestEntry(_) => {
-                    active_spans.insert(event.trace_id, nidx.unwrap());
-                    children_per_parent.insert(event.trace_id, None);
-                    if event.parent_id == event.base_id {
-                        match children_per_parent.get(&event.parent_id).unwrap() {
-                            Some(sibling_id) => {
-                                let sibling_node = id_map.get(sibling_id).unwrap();
-                                dag.g.add_edge(
-                                    *sibling_node,
-                                    nidx.unwrap(),
-                                    DAGEdge {
-                                        duration: (event.timestamp
-                                            - dag.g[*sibling_node].timestamp)
-                                            .to_std()
-                                            .unwrap(),
-                                        variant: EdgeType::ChildOf,
-                                    },
-                                );
-                            }
-                            None => {}
+                    let nidx = match nidx {
+                        Some(nidx) => nidx,
+                        None => continue,
+                    };
+                    // The parent's latest finished child, or the parent itself
+                    let previous = match children_per_parent.get(&event.parent_id) {
+                        Some(Some(sibling_id)) => id_map.get(sibling_id).cloned(),
+                        Some(None) if event.parent_id != event.base_id => {
+                            id_map.get(&event.parent_id).cloned()
                         }
-                    } else {
-                        match children_per_parent.get(&event.parent_id) {
-                            Some(result) => match result {
-                                Some(sibling_id) => {
-                                    let sibling_node = id_map.get(sibling_id).unwrap();
-                                    dag.g.add_edge(
-                                        *sibling_node,
-                                        nidx.unwrap(),
-                                        DAGEdge {
-                                            duration: (event.timestamp
-                                                - dag.g[*sibling_node].timestamp)
-                                                .to_std()
-                                                .unwrap(),
-                                            variant: EdgeType::ChildOf,
-                                        },
-                                    );
-                                }
-                                None => {
-                                    let parent_node = id_map.get(&event.parent_id).unwrap();
-                                    dag.g.add_edge(
-                                        *parent_node,
-                                        nidx.unwrap(),
-                                        DAGEdge {
-                                            duration: (event.timestamp
-                                                - dag.g[*parent_node].timestamp)
-                                                .to_std()
-                                                .unwrap(),
-                                            variant: EdgeType::ChildOf,
-                                        },
-                                    );
-                                }
+                        _ => None,
+                    };
+                    active_spans.insert(event.trace_id, nidx);
+                    children_per_parent.insert(event.trace_id, None);
+                    if let Some(previous) = previous {
+                        let duration =
+                            self.edge_duration(dag.g[previous].timestamp, event.timestamp);
+                        dag.g.add_edge(
+                            previous,
+                            nidx,
+                            DAGEdge {
+                                duration,
+                                variant: EdgeType::ChildOf,
                             },
-                            None => {
-                                // Parent has finished execution before child starts - shouldn't happen
-                                let parent_node = &dag.g[match id_map.get(&event.parent_id) {
-                                    Some(&nidx) => nidx,
-                                    None => {
-                                        panic!("Warning: Parent of node {:?} not found. Silently ignoring this event", event);
-                                    }
-                                }];
-                                assert!(event.timestamp > parent_node.timestamp);
-                                panic!("Parent of node {:?} not found: {:?}", event, parent_node);
-                            }
-                        }
+                        );
                     }
                 }
                 OSProfilerEnum::Annotation(_) => {
@@ -518,40 +870,27 @@ impl OSProfilerReader {
                         None => {
                             // Don't add wait for annotations
                         }
-                        Some(nidx) => match children_per_parent.get(&event.parent_id).unwrap() {
-                            Some(sibling_id) => {
-                                let sibling_node = id_map.get(sibling_id).unwrap();
+                        Some(nidx) => {
+                            // If idx == 0, annotation is the first node and the edge is added in
+                            // add_async
+                            let previous = match children_per_parent.get(&event.parent_id) {
+                                Some(Some(sibling_id)) => id_map.get(sibling_id).cloned(),
+                                Some(None) if idx != 0 => id_map.get(&event.parent_id).cloned(),
+                                _ => None,
+                            };
+                            if let Some(previous) = previous {
+                                let duration =
+                                    self.edge_duration(dag.g[previous].timestamp, event.timestamp);
                                 dag.g.add_edge(
-                                    *sibling_node,
+                                    previous,
                                     nidx,
                                     DAGEdge {
-                                        duration: (event.timestamp
-                                            - dag.g[*sibling_node].timestamp)
-                                            .to_std()
-                                            .unwrap(),
+                                        duration,
                                         variant: EdgeType::ChildOf,
                                     },
                                 );
                             }
-                            None => {
-                                // If idx == 0, annotation is the first node and the edge is added in
-                                // add_async
-                                if idx != 0 {
-                                    let parent_node = id_map.get(&event.parent_id).unwrap();
-                                    dag.g.add_edge(
-                                        *parent_node,
-                                        nidx,
-                                        DAGEdge {
-                                            duration: (event.timestamp
-                                                - dag.g[*parent_node].timestamp)
-                                                .to_std()
-                                                .unwrap(),
-                                            variant: EdgeType::ChildOf,
-                                        },
-                                    );
-                                }
-                            }
-                        },
+                        }
                     }
                 }
                 OSProfilerEnum::Exit(_) => {
@@ -559,33 +898,20 @@ impl OSProfilerReader {
                         add_next_to_waiters = true;
                     } else {
                         let start_span = active_spans.remove(&event.trace_id).unwrap();
-                        match children_per_parent.remove(&event.trace_id).unwrap() {
-                            Some(child_id) => {
-                                let child_node = id_map.get(&child_id).unwrap();
-                                dag.g.add_edge(
-                                    *child_node,
-                                    nidx.unwrap(),
-                                    DAGEdge {
-                                        duration: (event.timestamp - dag.g[*child_node].timestamp)
-                                            .to_std()
-                                            .unwrap(),
-                                        variant: EdgeType::ChildOf,
-                                    },
-                                );
-                            }
-                            None => {
-                                dag.g.add_edge(
-                                    start_span,
-                                    nidx.unwrap(),
-                                    DAGEdge {
-                                        duration: (event.timestamp - dag.g[start_span].timestamp)
-                                            .to_std()
-                                            .unwrap(),
-                                        variant: EdgeType::ChildOf,
-                                    },
-                                );
-                            }
-                        }
+                        let previous = match children_per_parent.remove(&event.trace_id) {
+                            Some(Some(child_id)) => *id_map.get(&child_id).unwrap(),
+                            _ => start_span,
+                        };
+                        let duration =
+                            self.edge_duration(dag.g[previous].timestamp, event.timestamp);
+                        dag.g.add_edge(
+                            previous,
+                            nidx.unwrap(),
+                            DAGEdge {
+                                duration,
+                                variant: EdgeType::ChildOf,
+                            },
+                        );
                     }
                 }
             }
@@ -597,20 +923,23 @@ impl OSProfilerReader {
             }
         }
         for (trace_id, parent) in async_traces.iter() {
-            let last_node = self.add_asynch(&mut dag, trace_id, *parent)?;
-            if last_node.is_none() {
-                continue;
-            }
-            let last_node = last_node.unwrap();
+            let last_node = match self.add_asynch(&mut dag, trace_id, *parent) {
+                Ok(Some(node)) => node,
+                Ok(None) => continue,
+                Err(e) => {
+                    self.report.skip(&trace_id.to_string(), e.to_string());
+                    continue;
+                }
+            };
             match &waiters.get(trace_id) {
                 Some(parent) => {
+                    let duration =
+                        self.edge_duration(dag.g[last_node].timestamp, dag.g[**parent].timestamp);
                     dag.g.add_edge(
                         last_node,
                         **parent,
                         DAGEdge {
-                            duration: (dag.g[**parent].timestamp - dag.g[last_node].timestamp)
-                                .to_std()
-                                .unwrap(),
+                            duration,
                             variant: EdgeType::FollowsFrom,
                         },
                     );
@@ -621,6 +950,87 @@ impl OSProfilerReader {
         Ok(nidx)
     }
 
+    /// Duration of an edge between two events. If the events are out of order (e.g., due to
+    /// clock skew), the edge gets zero duration and a warning is added to the parse report.
+    fn edge_duration(&mut self, from: NaiveDateTime, to: NaiveDateTime) -> Duration {
+        match (to - from).to_std() {
+            Ok(d) => d,
+            Err(_) => {
+                self.report
+                    .warn(format!("Event at {} happens before its parent at {}", to, from));
+                Duration::new(0, 0)
+            }
+        }
+    }
+
+    /// Finds events that are earlier than the entry of their parent (or, for exits, of their own
+    /// span) by more than `outlier_threshold`. Their spans are dropped, or they are moved to just
+    /// after that entry, and either is recorded in the parse report. Without this, such events
+    /// sort before their parent and the spans under them are skipped.
+    fn guard_outliers(&mut self, event_list: &mut Vec<OSProfilerSpan>) {
+        let threshold = match self.outlier_threshold {
+            Some(t) => chrono::Duration::from_std(t).unwrap(),
+            None => return,
+        };
+        // Moving an entry can make its children outliers, so this repeats until nothing moves
+        for _ in 0..event_list.len() {
+            let entries: HashMap<Uuid, NaiveDateTime> = event_list
+                .iter()
+                .filter(|e| {
+                    matches!(
+                        e.info,
+                        OSProfilerEnum::FunctionEntry(_) | OSProfilerEnum::RequestEntry(_)
+                    )
+                })
+                .map(|e| (e.trace_id, e.timestamp))
+                .collect();
+            let outliers: Vec<(usize, NaiveDateTime)> = event_list
+                .iter()
+                .enumerate()
+                .filter_map(|(i, e)| {
+                    let reference = match e.info {
+                        OSProfilerEnum::Exit(_) => entries.get(&e.trace_id),
+                        _ => entries.get(&e.parent_id),
+                    }?;
+                    if e.timestamp < *reference - threshold {
+                        Some((i, *reference))
+                    } else {
+                        None
+                    }
+                })
+                .collect();
+            if outliers.is_empty() {
+                return;
+            }
+            match self.outlier_action {
+                OutlierAction::Drop => {
+                    let mut dropped = HashSet::new();
+                    for (i, reference) in outliers {
+                        let event = &event_list[i];
+                        let reason = format!(
+                            "timestamp {} is before its parent at {}",
+                            event.timestamp, reference
+                        );
+                        self.report.skip(&event.trace_id.to_string(), reason);
+                        dropped.insert(event.trace_id);
+                    }
+                    event_list.retain(|e| !dropped.contains(&e.trace_id));
+                }
+                OutlierAction::Clamp => {
+                    for (i, reference) in outliers {
+                        let event = &mut event_list[i];
+                        let clamped = reference + chrono::Duration::microseconds(1);
+                        self.report.warn(format!(
+                            "Moved {} of {} from {} to {}",
+                            event.name, event.trace_id, event.timestamp, clamped
+                        ));
+                        event.timestamp = clamped;
+                    }
+                }
+            }
+        }
+    }
+
     fn add_asynch(
         &mut self,
         mut dag: &mut Trace,
@@ -633,22 +1043,187 @@ impl OSProfilerReader {
         }
         self.add_events(&mut dag, &mut event_list, Some(parent))
     }
+
+}
+
+/// Checks that the spans an event will be connected to have been seen, and returns what is
+/// missing otherwise.
+fn missing_relation(
+    event: &OSProfilerSpan,
+    idx: usize,
+    children_per_parent: &HashMap<Uuid, Option<Uuid>>,
+    active_spans: &HashMap<Uuid, NodeIndex>,
+    id_map: &HashMap<Uuid, NodeIndex>,
+) -> Option<String> {
+    match &event.info {
+        OSProfilerEnum::FunctionEntry(_) | OSProfilerEnum::RequestEntry(_) => {
+            match children_per_parent.get(&event.parent_id) {
+                // Parent has finished execution before child starts - shouldn't happen
+                None => Some(format!("Parent {} not found", event.parent_id)),
+                Some(None)
+                    if event.parent_id != event.base_id
+                        && !id_map.contains_key(&event.parent_id) =>
+                {
+                    Some(format!("Parent {} has no node", event.parent_id))
+                }
+                _ => None,
+            }
+        }
+        OSProfilerEnum::Annotation(_) => match children_per_parent.get(&event.parent_id) {
+            None => Some(format!("Parent {} not found", event.parent_id)),
+            Some(None) if idx != 0 && !id_map.contains_key(&event.parent_id) => {
+                Some(format!("Parent {} has no node", event.parent_id))
+            }
+            _ => None,
+        },
+        OSProfilerEnum::Exit(_) => {
+            if !active_spans.contains_key(&event.trace_id)
+                || !children_per_parent.contains_key(&event.trace_id)
+            {
+                Some("Exit without a matching entry".to_string())
+            } else {
+                None
+            }
+        }
+    }
 }
 
-fn sort_event_list(event_list: &mut Vec<OSProfilerSpan>) {
-    // Sorts events by timestamp
-    event_list.sort_by(|a, b| {
-        if a.timestamp == b.timestamp {
-            match a.info {
-                OSProfilerEnum::FunctionEntry(_) | OSProfilerEnum::RequestEntry(_) => {
-                    Ordering::Less
+/// Estimates the clock offset of each host from parent/child span relations and shifts
+/// the timestamps in `event_list` so that children start after and end before their parents.
+///
+/// Offsets are relative to the host of the first span, and are kept in `offsets` so that
+/// asynchronous children of the same trace are shifted consistently. Offsets larger than
+/// `warning` are logged.
+fn correct_clock_skew(
+    event_list: &mut [OSProfilerSpan],
+    offsets: &mut HashMap<String, chrono::Duration>,
+    warning: Duration,
+) {
+    // trace_id -> (host, parent_id, entry time, exit time)
+    let mut spans =
+        HashMap::<Uuid, (String, Uuid, Option<NaiveDateTime>, Option<NaiveDateTime>)>::new();
+    let mut hosts = Vec::new();
+    for event in event_list.iter() {
+        let host = match span_host(event) {
+            Some(h) => h.to_string(),
+            None => continue,
+        };
+        if !hosts.contains(&host) {
+            hosts.push(host.clone());
+        }
+        let span = spans
+            .entry(event.trace_id)
+            .or_insert((host, event.parent_id, None, None));
+        match &event.info {
+            OSProfilerEnum::FunctionEntry(_) | OSProfilerEnum::RequestEntry(_) => {
+                span.2 = Some(event.timestamp);
+            }
+            OSProfilerEnum::Exit(_) => {
+                span.3 = Some(event.timestamp);
+            }
+            _ => {}
+        }
+    }
+    if hosts.len() < 2 && offsets.is_empty() {
+        return;
+    }
+    // (parent host, child host) -> (minimum shift needed so that children start after their
+    // parents, maximum shift allowed so that children end before their parents)
+    let mut bounds =
+        HashMap::<(String, String), (Option<chrono::Duration>, Option<chrono::Duration>)>::new();
+    for (child_host, parent_id, child_entry, child_exit) in spans.values() {
+        let (parent_host, _, parent_entry, parent_exit) = match spans.get(parent_id) {
+            Some(p) => p,
+            None => continue,
+        };
+        if parent_host == child_host {
+            continue;
+        }
+        let bound = bounds
+            .entry((parent_host.clone(), child_host.clone()))
+            .or_insert((None, None));
+        if let (Some(p), Some(c)) = (parent_entry, child_entry) {
+            let lower = *p - *c;
+            bound.0 = Some(bound.0.map_or(lower, |b| b.max(lower)));
+        }
+        if let (Some(p), Some(c)) = (parent_exit, child_exit) {
+            let upper = *p - *c;
+            bound.1 = Some(bound.1.map_or(upper, |b| b.min(upper)));
+        }
+    }
+    if offsets.is_empty() {
+        offsets.insert(hosts[0].clone(), chrono::Duration::zero());
+    }
+    // Propagate offsets over the host graph until nothing changes
+    let mut new_hosts = Vec::new();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for ((parent_host, child_host), (lower, upper)) in bounds.iter() {
+            let shift = match (lower, upper) {
+                (Some(l), _) if *l > chrono::Duration::zero() => *l,
+                (l, Some(u)) if *u < chrono::Duration::zero() => match l {
+                    Some(l) => (*u).max(*l),
+                    None => *u,
+                },
+                _ => chrono::Duration::zero(),
+            };
+            match (
+                offsets.get(parent_host).cloned(),
+                offsets.get(child_host).cloned(),
+            ) {
+                (Some(p), None) => {
+                    offsets.insert(child_host.clone(), p + shift);
+                    new_hosts.push(child_host);
+                    changed = true;
+                }
+                (None, Some(c)) => {
+                    offsets.insert(parent_host.clone(), c - shift);
+                    new_hosts.push(parent_host);
+                    changed = true;
                 }
-                _ => Ordering::Greater,
+                _ => {}
             }
-        } else {
-            a.timestamp.cmp(&b.timestamp)
         }
-    });
+    }
+    // Hosts seen before were already reported
+    for host in new_hosts {
+        let offset = offsets[host];
+        let skew = offset.num_nanoseconds().unwrap_or(i64::MAX).unsigned_abs();
+        if skew as u128 > warning.as_nanos() {
+            warn!(
+                "Clock of host {} is skewed by {}ms",
+                host,
+                offset.num_milliseconds()
+            );
+        }
+    }
+    for event in event_list.iter_mut() {
+        let host = match span_host(event) {
+            Some(h) => Some(h.to_string()),
+            None => spans.get(&event.trace_id).map(|s| s.0.clone()),
+        };
+        if let Some(offset) = host.and_then(|h| offsets.get(&h)) {
+            event.timestamp = event.timestamp + *offset;
+        }
+    }
+}
+
+/// Host the span was recorded on, if its kind of span has one
+fn span_host(event: &OSProfilerSpan) -> Option<&str> {
+    let host = match &event.info {
+        OSProfilerEnum::FunctionEntry(info) => &info.host,
+        OSProfilerEnum::RequestEntry(info) => &info.host,
+        OSProfilerEnum::Exit(ExitEnum::Normal(info)) => &info.host,
+        OSProfilerEnum::Exit(ExitEnum::Error(info)) => &info.host,
+        OSProfilerEnum::Annotation(AnnotationEnum::KeyValue(info)) => &info.host,
+        OSProfilerEnum::Annotation(AnnotationEnum::WaitFor(info)) => &info.host,
+        OSProfilerEnum::Annotation(AnnotationEnum::Child(info)) => &info.host,
+        OSProfilerEnum::Annotation(AnnotationEnum::Plain(info)) => &info.host,
+        OSProfilerEnum::Annotation(AnnotationEnum::Log(info)) => &info.host,
+        OSProfilerEnum::Annotation(_) => return None,
+    };
+    Some(host)
 }
 
 impl Event {
@@ -714,3 +1289,94 @@ impl Event {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_are_complete_once_the_root_span_ended() {
+        let counts = vec![3, 2];
+        assert_eq!(
+            RequestProgress::of(&[0, 0], false, None),
+            RequestProgress::Missing
+        );
+        assert_eq!(
+            RequestProgress::of(&counts, false, None),
+            RequestProgress::Running
+        );
+        // No new spans, but still open: waiting on something, not an error
+        assert_eq!(
+            RequestProgress::of(&counts, false, Some(&counts)),
+            RequestProgress::Stalled
+        );
+        assert_eq!(
+            RequestProgress::of(&counts, true, Some(&counts)),
+            RequestProgress::Ended
+        );
+
+        let ended = (counts.clone(), true);
+        let open = (counts, false);
+        assert!(is_complete(Some(&ended), false, 5, false, true));
+        // A span was added while the trace was read
+        assert!(!is_complete(Some(&ended), false, 4, false, true));
+        // ... or a span couldn't be parsed, and nothing changed since the last poll
+        assert!(is_complete(Some(&ended), true, 4, false, true));
+        assert!(!is_complete(Some(&open), true, 5, true, true));
+        assert!(!is_complete(Some(&ended), false, 5, false, false));
+        // Agents that can't count spans
+        assert!(is_complete(None, false, 5, true, true));
+        assert!(!is_complete(None, false, 5, false, true));
+    }
+
+    fn span(trace_id: u128, parent_id: u128, host: &str, entry: bool, ms: i64) -> OSProfilerSpan {
+        let info = if entry {
+            serde_json::json!({
+                "function": {"name": "f"},
+                "thread_id": 1,
+                "host": host,
+                "tracepoint_id": "t",
+                "pid": 1,
+            })
+        } else {
+            serde_json::json!({ "host": host })
+        };
+        let timestamp = NaiveDateTime::default() + chrono::Duration::milliseconds(ms);
+        serde_json::from_value(serde_json::json!({
+            "trace_id": Uuid::from_u128(trace_id),
+            "parent_id": Uuid::from_u128(parent_id),
+            "project": "p",
+            "name": if entry { "f-start" } else { "f-stop" },
+            "base_id": Uuid::from_u128(1),
+            "service": "s",
+            "tracepoint_id": "t",
+            "timestamp": timestamp.format("%Y-%m-%dT%H:%M:%S%.6f").to_string(),
+            "info": info,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn clock_skew_is_corrected_from_parent_child_spans() {
+        let mut events = vec![
+            span(2, 1, "a", true, 0),
+            span(3, 2, "b", true, -40),
+            span(3, 2, "b", false, 10),
+            span(2, 1, "a", false, 100),
+        ];
+        let mut offsets = HashMap::new();
+        let ms = chrono::Duration::milliseconds;
+        correct_clock_skew(&mut events, &mut offsets, Duration::from_millis(10));
+        assert_eq!(offsets["a"], chrono::Duration::zero());
+        assert_eq!(offsets["b"], ms(40));
+        let start = NaiveDateTime::default();
+        assert_eq!(events[1].timestamp, start);
+        assert_eq!(events[2].timestamp, start + ms(50));
+        assert_eq!(events[3].timestamp, start + ms(100));
+
+        // Later batches of the same trace are shifted by the offsets already known
+        let mut events = vec![span(4, 2, "b", true, 60)];
+        correct_clock_skew(&mut events, &mut offsets, Duration::from_millis(10));
+        assert_eq!(events[0].timestamp, start + ms(100));
+    }
+}
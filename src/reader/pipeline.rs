@@ -0,0 +1,385 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Clean-up passes applied to every trace a reader returns.
+//!
+//! The passes are configured with `trace_pipeline` in the settings, as a comma separated list of
+//! pass names that run in order. Passes that remove a node connect its predecessors to its
+//! successors, so the trace stays connected and edge durations still add up.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::error::Error;
+use std::sync::Arc;
+
+use petgraph::graph::NodeIndex;
+use petgraph::visit::Dfs;
+use petgraph::visit::Reversed;
+use petgraph::visit::Walker;
+use petgraph::Direction;
+use uuid::Uuid;
+
+use crate::clock::Clock;
+use crate::reader::ParseReport;
+use crate::reader::Reader;
+use crate::trace::DAGEdge;
+use crate::trace::Event;
+use crate::trace::EventType;
+use crate::trace::Trace;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TracePass {
+    /// Remove branches that do not end in the end node, see `Trace::prune`
+    Prune,
+    /// Remove spans that start and end at the same time, with everything inside them
+    DropZeroDuration,
+    /// When a span is immediately followed by another span at the same tracepoint, keep only
+    /// the last attempt
+    CollapseRetries,
+    /// Remove annotation events
+    DropAnnotations,
+    /// Remove repeated synthetic events at the same tracepoint
+    DedupeSynthetic,
+}
+
+impl TracePass {
+    pub fn from_str(s: &str) -> Self {
+        match s {
+            "prune" => TracePass::Prune,
+            "drop-zero-duration" => TracePass::DropZeroDuration,
+            "collapse-retries" => TracePass::CollapseRetries,
+            "drop-annotations" => TracePass::DropAnnotations,
+            "dedupe-synthetic" => TracePass::DedupeSynthetic,
+            _ => panic!("Unknown trace pass {}", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TracePipeline {
+    pub passes: Vec<TracePass>,
+}
+
+impl TracePipeline {
+    /// Comma separated pass names
+    pub fn from_str(s: &str) -> Self {
+        TracePipeline {
+            passes: s
+                .split(',')
+                .map(|p| p.trim())
+                .filter(|p| !p.is_empty())
+                .map(TracePass::from_str)
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.passes.is_empty()
+    }
+
+    pub fn apply(&self, trace: &mut Trace) {
+        self.apply_passes(trace, true);
+    }
+
+    /// Runs the passes, skipping `Prune` unless `prune` is set
+    fn apply_passes(&self, trace: &mut Trace, prune: bool) {
+        for pass in &self.passes {
+            match pass {
+                TracePass::Prune if !prune => {}
+                TracePass::Prune => trace.prune(),
+                TracePass::DropZeroDuration => drop_zero_duration(trace),
+                TracePass::CollapseRetries => collapse_retries(trace),
+                TracePass::DropAnnotations => drop_annotations(trace),
+                TracePass::DedupeSynthetic => dedupe_synthetic(trace),
+            }
+        }
+    }
+}
+
+/// Remove a node, connecting each of its predecessors to each of its successors
+fn remove_bridged(trace: &mut Trace, nidx: NodeIndex) {
+    if nidx == trace.start_node || nidx == trace.end_node {
+        return;
+    }
+    let preds: Vec<_> = trace
+        .g
+        .neighbors_directed(nidx, Direction::Incoming)
+        .collect();
+    let succs: Vec<_> = trace
+        .g
+        .neighbors_directed(nidx, Direction::Outgoing)
+        .map(|s| (s, trace.g[trace.g.find_edge(nidx, s).unwrap()].variant.clone()))
+        .collect();
+    trace.g.remove_node(nidx);
+    for &p in &preds {
+        for (s, variant) in &succs {
+            let (s, variant) = (*s, variant.clone());
+            if trace.g.find_edge(p, s).is_none() {
+                let duration = (trace.g[s].timestamp - trace.g[p].timestamp)
+                    .to_std()
+                    .unwrap_or_default();
+                trace.g.add_edge(p, s, DAGEdge { duration, variant });
+            }
+        }
+    }
+}
+
+/// Remove nodes in time order, so chains of removed nodes end up bridged from outside
+fn remove_all_bridged(trace: &mut Trace, mut nodes: Vec<NodeIndex>) {
+    nodes.sort_by_key(|&n| trace.g[n].timestamp);
+    nodes.dedup();
+    for n in nodes {
+        if trace.g.contains_node(n) {
+            remove_bridged(trace, n);
+        }
+    }
+}
+
+/// Entry and exit of each span that has exactly one of each
+fn spans(trace: &Trace) -> HashMap<Uuid, (NodeIndex, NodeIndex)> {
+    let mut entries = HashMap::new();
+    let mut exits = HashMap::new();
+    for n in trace.g.node_indices() {
+        let event = &trace.g[n];
+        match event.variant {
+            EventType::Entry => entries.entry(event.trace_id).or_insert_with(Vec::new).push(n),
+            EventType::Exit => exits.entry(event.trace_id).or_insert_with(Vec::new).push(n),
+            EventType::Annotation => {}
+        }
+    }
+    entries
+        .into_iter()
+        .filter(|(_, e)| e.len() == 1)
+        .filter_map(|(id, e)| match exits.get(&id) {
+            Some(x) if x.len() == 1 => Some((id, (e[0], x[0]))),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The nodes of a span: those reachable from its entry that reach its exit
+fn span_nodes(trace: &Trace, entry: NodeIndex, exit: NodeIndex) -> Vec<NodeIndex> {
+    let after: HashSet<NodeIndex> = Dfs::new(&trace.g, entry).iter(&trace.g).collect();
+    Dfs::new(Reversed(&trace.g), exit)
+        .iter(Reversed(&trace.g))
+        .filter(|n| after.contains(n))
+        .collect()
+}
+
+fn drop_zero_duration(trace: &mut Trace) {
+    let to_remove = spans(trace)
+        .values()
+        .filter(|&&(entry, exit)| trace.g[entry].timestamp == trace.g[exit].timestamp)
+        .flat_map(|&(entry, exit)| span_nodes(trace, entry, exit))
+        .collect();
+    remove_all_bridged(trace, to_remove);
+}
+
+fn collapse_retries(trace: &mut Trace) {
+    let spans = spans(trace);
+    let to_remove = spans
+        .values()
+        .filter(|&&(entry, exit)| {
+            let mut next = trace.g.neighbors_directed(exit, Direction::Outgoing);
+            match (next.next(), next.next()) {
+                (Some(n), None) => {
+                    trace.g[n].variant == EventType::Entry
+                        && trace.g[n].tracepoint_id == trace.g[entry].tracepoint_id
+                        && spans.contains_key(&trace.g[n].trace_id)
+                }
+                _ => false,
+            }
+        })
+        .flat_map(|&(entry, exit)| span_nodes(trace, entry, exit))
+        .collect();
+    remove_all_bridged(trace, to_remove);
+}
+
+/// Keep only the events for which `keep` is true, plus the start and end nodes
+pub fn filter_events<F: Fn(&Event) -> bool>(trace: &mut Trace, keep: F) {
+    let to_remove = trace
+        .g
+        .node_indices()
+        .filter(|&n| !keep(&trace.g[n]))
+        .collect();
+    remove_all_bridged(trace, to_remove);
+}
+
+fn drop_annotations(trace: &mut Trace) {
+    filter_events(trace, |e| e.variant != EventType::Annotation);
+}
+
+fn dedupe_synthetic(trace: &mut Trace) {
+    let to_remove = trace
+        .g
+        .node_indices()
+        .filter(|&n| trace.g[n].is_synthetic)
+        .filter(|&n| {
+            let mut preds = trace.g.neighbors_directed(n, Direction::Incoming);
+            match (preds.next(), preds.next()) {
+                (Some(p), None) => {
+                    trace.g[p].is_synthetic && trace.g[p].tracepoint_id == trace.g[n].tracepoint_id
+                }
+                _ => false,
+            }
+        })
+        .collect();
+    remove_all_bridged(trace, to_remove);
+}
+
+/// Runs the pipeline on everything the wrapped reader returns
+pub struct PipelineReader {
+    inner: Box<dyn Reader>,
+    pipeline: TracePipeline,
+    for_searchspace: bool,
+}
+
+impl PipelineReader {
+    pub fn new(inner: Box<dyn Reader>, pipeline: TracePipeline) -> Self {
+        PipelineReader {
+            inner,
+            pipeline,
+            for_searchspace: false,
+        }
+    }
+
+    /// Pruning drops the branches the controller needs to see, so it only runs on traces read
+    /// for the search space
+    fn apply(&self, mut trace: Trace) -> Trace {
+        self.pipeline.apply_passes(&mut trace, self.for_searchspace);
+        trace
+    }
+}
+
+impl Reader for PipelineReader {
+    fn read_file(&mut self, filename: &str) -> Trace {
+        let trace = self.inner.read_file(filename);
+        self.apply(trace)
+    }
+
+    fn read_dir(&mut self, foldername: &str) -> Vec<Trace> {
+        let traces = self.inner.read_dir(foldername);
+        traces.into_iter().map(|t| self.apply(t)).collect()
+    }
+
+    fn get_trace_from_base_id(&mut self, id: &str) -> Result<Trace, Box<dyn Error>> {
+        let trace = self.inner.get_trace_from_base_id(id)?;
+        Ok(self.apply(trace))
+    }
+
+    fn get_recent_traces(&mut self) -> Vec<Trace> {
+        let traces = self.inner.get_recent_traces();
+        traces.into_iter().map(|t| self.apply(t)).collect()
+    }
+
+    fn reset_state(&mut self) {
+        self.inner.reset_state();
+    }
+
+    fn for_searchspace(&mut self) {
+        self.for_searchspace = true;
+        self.inner.for_searchspace();
+    }
+
+    fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.inner.set_clock(clock);
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.inner.is_exhausted()
+    }
+
+    fn parse_report(&self, id: &str) -> Option<&ParseReport> {
+        self.inner.parse_report(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::NaiveDateTime;
+
+    use crate::trace::EdgeType;
+    use crate::trace::TracepointID;
+
+    #[test]
+    fn passes() {
+        let mut trace = Trace::new(&Uuid::new_v4());
+        let (outer, first, second) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let events = vec![
+            (outer, "outer", EventType::Entry, 0),
+            (first, "call", EventType::Entry, 1),
+            (first, "call", EventType::Exit, 3),
+            (second, "call", EventType::Entry, 4),
+            (Uuid::new_v4(), "note", EventType::Annotation, 5),
+            (second, "call", EventType::Exit, 6),
+            (outer, "outer", EventType::Exit, 7),
+        ];
+        let nodes = events
+            .into_iter()
+            .map(|(id, name, variant, ms)| {
+                trace.g.add_node(Event {
+                    trace_id: id,
+                    tracepoint_id: TracepointID::from_str(name),
+                    timestamp: NaiveDateTime::from_timestamp(0, 0)
+                        + chrono::Duration::milliseconds(ms),
+                    is_synthetic: false,
+                    variant,
+                    key_value_pair: HashMap::new(),
+                })
+            })
+            .collect::<Vec<_>>();
+        for w in nodes.windows(2) {
+            let duration = (trace.g[w[1]].timestamp - trace.g[w[0]].timestamp)
+                .to_std()
+                .unwrap();
+            let variant = EdgeType::ChildOf;
+            trace.g.add_edge(w[0], w[1], DAGEdge { duration, variant });
+        }
+        trace.start_node = nodes[0];
+        trace.end_node = nodes[6];
+
+        TracePipeline::from_str("collapse-retries, drop-annotations").apply(&mut trace);
+        assert_eq!(trace.g.node_count(), 4);
+        let edge = trace.g.find_edge(nodes[0], nodes[3]).unwrap();
+        assert_eq!(trace.g[edge].duration, std::time::Duration::from_millis(4));
+        assert!(trace.g.find_edge(nodes[3], nodes[5]).is_some());
+    }
+
+    #[test]
+    fn prunes_only_for_searchspace() {
+        let mut trace = Trace::new(&Uuid::new_v4());
+        let nodes = ["start", "end", "dangling"]
+            .iter()
+            .map(|name| {
+                trace.g.add_node(Event {
+                    trace_id: Uuid::new_v4(),
+                    tracepoint_id: TracepointID::from_str(name),
+                    timestamp: NaiveDateTime::default(),
+                    is_synthetic: false,
+                    variant: EventType::Annotation,
+                    key_value_pair: HashMap::new(),
+                })
+            })
+            .collect::<Vec<_>>();
+        for &n in &nodes[1..] {
+            let duration = std::time::Duration::from_millis(0);
+            let variant = EdgeType::ChildOf;
+            trace.g.add_edge(nodes[0], n, DAGEdge { duration, variant });
+        }
+        trace.start_node = nodes[0];
+        trace.end_node = nodes[1];
+
+        let pipeline = TracePipeline::from_str("prune");
+        pipeline.apply_passes(&mut trace, false);
+        assert_eq!(trace.g.node_count(), 3);
+        pipeline.apply_passes(&mut trace, true);
+        assert_eq!(trace.g.node_count(), 2);
+    }
+}
@@ -0,0 +1,222 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Replays a folder of archived traces as if they were arriving live.
+//!
+//! The archive is read with the application's own reader the first time traces are requested.
+//! After that, every call to `get_recent_traces` returns the traces that would have finished by
+//! now, where "now" is measured on the reader's clock from the first call and mapped onto the
+//! archive starting from its earliest trace. With a simulated clock the controller loop can run
+//! through days of traces in seconds.
+//!
+//! Traces are returned as they were archived. Enabling or disabling tracepoints does not change
+//! them, so the archive should be collected with everything enabled.
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::sync::Arc;
+
+use chrono::NaiveDateTime;
+use log::info;
+
+use crate::clock::Clock;
+use crate::clock::SystemClock;
+use crate::reader::ParseReport;
+use crate::reader::Reader;
+use crate::trace::Trace;
+
+pub struct ReplayReader {
+    inner: Box<dyn Reader>,
+    dir: String,
+    clock: Arc<dyn Clock>,
+    /// Traces not returned yet, ordered by end time. None until the archive is read.
+    pending: Option<VecDeque<Trace>>,
+    /// Wall clock time of the first call, and the archive time it corresponds to
+    origin: Option<(NaiveDateTime, NaiveDateTime)>,
+}
+
+impl ReplayReader {
+    pub fn new(inner: Box<dyn Reader>, dir: &str) -> Self {
+        ReplayReader {
+            inner,
+            dir: dir.to_string(),
+            clock: Arc::new(SystemClock),
+            pending: None,
+            origin: None,
+        }
+    }
+
+    fn load(&mut self) -> &mut VecDeque<Trace> {
+        if self.pending.is_none() {
+            let mut traces = self.inner.read_dir(&self.dir);
+            traces.sort_by_key(|t| end_time(t));
+            info!("Replaying {} traces from {}", traces.len(), self.dir);
+            self.pending = Some(traces.into_iter().collect());
+        }
+        self.pending.as_mut().unwrap()
+    }
+}
+
+fn end_time(trace: &Trace) -> NaiveDateTime {
+    trace.g[trace.end_node].timestamp
+}
+
+impl Reader for ReplayReader {
+    fn read_file(&mut self, filename: &str) -> Trace {
+        self.inner.read_file(filename)
+    }
+
+    fn read_dir(&mut self, foldername: &str) -> Vec<Trace> {
+        self.inner.read_dir(foldername)
+    }
+
+    fn get_trace_from_base_id(&mut self, id: &str) -> Result<Trace, Box<dyn Error>> {
+        self.inner.get_trace_from_base_id(id)
+    }
+
+    fn get_recent_traces(&mut self) -> Vec<Trace> {
+        let now = self.clock.wall();
+        if self.origin.is_none() {
+            let first = self.load().iter().map(|t| t.g[t.start_node].timestamp).min();
+            match first {
+                Some(first) => self.origin = Some((now, first)),
+                None => return Vec::new(),
+            }
+        }
+        let (wall_start, archive_start) = self.origin.unwrap();
+        let archive_now = archive_start + (now - wall_start);
+        let pending = self.pending.as_mut().unwrap();
+        let mut result = Vec::new();
+        while pending.front().map_or(false, |t| end_time(t) <= archive_now) {
+            result.push(pending.pop_front().unwrap());
+        }
+        result
+    }
+
+    /// The archive is replayed from the start
+    fn reset_state(&mut self) {
+        self.pending = None;
+        self.origin = None;
+    }
+
+    fn for_searchspace(&mut self) {
+        self.inner.for_searchspace();
+    }
+
+    fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock.clone();
+        self.inner.set_clock(clock);
+    }
+
+    fn parse_report(&self, id: &str) -> Option<&ParseReport> {
+        self.inner.parse_report(id)
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.pending.as_ref().map_or(false, |p| p.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use uuid::Uuid;
+
+    use crate::clock::SimulatedClock;
+    use crate::trace::DAGEdge;
+    use crate::trace::EdgeType;
+    use crate::trace::Event;
+    use crate::trace::EventType;
+    use crate::trace::TracepointID;
+
+    /// An archive of traces, returned by read_dir
+    struct Archive(Vec<Trace>);
+
+    impl Reader for Archive {
+        fn read_file(&mut self, _filename: &str) -> Trace {
+            unimplemented!()
+        }
+
+        fn read_dir(&mut self, _foldername: &str) -> Vec<Trace> {
+            self.0.clone()
+        }
+
+        fn get_trace_from_base_id(&mut self, _id: &str) -> Result<Trace, Box<dyn Error>> {
+            unimplemented!()
+        }
+
+        fn get_recent_traces(&mut self) -> Vec<Trace> {
+            unimplemented!()
+        }
+
+        fn reset_state(&mut self) {}
+
+        fn for_searchspace(&mut self) {}
+    }
+
+    /// A trace that runs from `start` to `end` seconds into the archive
+    fn trace(start: i64, end: i64) -> Trace {
+        let mut trace = Trace::new(&Uuid::new_v4());
+        let nodes = [(start, EventType::Entry), (end, EventType::Exit)]
+            .iter()
+            .map(|&(secs, variant)| {
+                trace.g.add_node(Event {
+                    trace_id: Uuid::new_v4(),
+                    tracepoint_id: TracepointID::from_str("request"),
+                    timestamp: NaiveDateTime::default() + chrono::Duration::seconds(secs),
+                    is_synthetic: false,
+                    variant,
+                    key_value_pair: HashMap::new(),
+                })
+            })
+            .collect::<Vec<_>>();
+        let edge = DAGEdge {
+            duration: Duration::from_secs((end - start) as u64),
+            variant: EdgeType::ChildOf,
+        };
+        trace.g.add_edge(nodes[0], nodes[1], edge);
+        trace.start_node = nodes[0];
+        trace.end_node = nodes[1];
+        trace
+    }
+
+    #[test]
+    fn replays_traces_as_they_finish() {
+        let traces = vec![trace(0, 5), trace(10, 12), trace(2, 30)];
+        let ids = traces.iter().map(|t| t.base_id).collect::<Vec<_>>();
+        let clock = Arc::new(SimulatedClock::new());
+        let mut reader = ReplayReader::new(Box::new(Archive(traces)), "archive");
+        reader.set_clock(clock.clone());
+        let returned = |reader: &mut ReplayReader| {
+            reader
+                .get_recent_traces()
+                .iter()
+                .map(|t| t.base_id)
+                .collect::<Vec<_>>()
+        };
+
+        assert!(returned(&mut reader).is_empty());
+        assert!(!reader.is_exhausted());
+        clock.advance(Duration::from_secs(12));
+        assert_eq!(returned(&mut reader), vec![ids[0], ids[1]]);
+        clock.advance(Duration::from_secs(10));
+        assert!(returned(&mut reader).is_empty());
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(returned(&mut reader), vec![ids[2]]);
+        assert!(reader.is_exhausted());
+
+        reader.reset_state();
+        assert!(!reader.is_exhausted());
+        clock.advance(Duration::from_secs(5));
+        assert!(returned(&mut reader).is_empty());
+    }
+}
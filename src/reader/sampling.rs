@@ -0,0 +1,383 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Sampling of the traces collected from a busy cluster, read from the `[sampling]` section of
+//! `controller.toml`:
+//!
+//! ```toml
+//! [sampling]
+//! policy = "stratified"
+//! traces_per_sec = 20
+//! log_file = "/tmp/pythia_sampling.csv"
+//! ```
+//!
+//! `policy` is "probabilistic" (keeps each request with `probability`), "rate-limited" (keeps at
+//! most `traces_per_sec` traces per second) or "stratified" (the same limit for each request
+//! type, so that rare types are not crowded out by common ones). Without the section every trace
+//! is kept.
+//!
+//! Only `get_recent_traces` is sampled; traces read from files for the search space are not.
+//! Each call that drops traces is logged, one line per request type with the wall time, the
+//! request type, the traces seen and kept, and the weight of a kept trace so far (traces seen
+//! over traces kept since the start). Statistics over the kept traces can be scaled by that
+//! weight. The lines go to `log_file` as CSV, or to stderr without one.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+
+use config::Value;
+use log::{debug, error};
+use uuid::Uuid;
+
+use pythia_common::RequestType;
+
+use crate::clock::Clock;
+use crate::clock::SystemClock;
+use crate::reader::ParseReport;
+use crate::reader::Reader;
+use crate::trace::Trace;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SamplingPolicy {
+    /// Probability of keeping each request
+    Probabilistic(f64),
+    /// Traces kept per second
+    RateLimited(f64),
+    /// Traces kept per second for each request type
+    Stratified(f64),
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SamplingSettings {
+    /// None keeps every trace
+    pub policy: Option<SamplingPolicy>,
+    /// Where decisions are logged; None logs them to stderr
+    pub log_file: Option<PathBuf>,
+}
+
+impl SamplingSettings {
+    pub fn from_table(table: HashMap<String, Value>) -> Self {
+        let mut policy = None;
+        let mut probability = None;
+        let mut traces_per_sec = None;
+        let mut log_file = None;
+        for (key, value) in table {
+            match key.as_str() {
+                "policy" => {
+                    policy = Some(
+                        value
+                            .into_str()
+                            .expect("sampling.policy should be a string"),
+                    );
+                }
+                "probability" => {
+                    probability = Some(
+                        value
+                            .into_float()
+                            .expect("sampling.probability should be a number"),
+                    );
+                }
+                "traces_per_sec" => {
+                    traces_per_sec = Some(
+                        value
+                            .into_float()
+                            .expect("sampling.traces_per_sec should be a number"),
+                    );
+                }
+                "log_file" => {
+                    log_file = Some(
+                        value
+                            .into_str()
+                            .expect("sampling.log_file should be a path"),
+                    )
+                    .filter(|s| !s.is_empty())
+                    .map(PathBuf::from);
+                }
+                _ => panic!("Unknown sampling setting {}", key),
+            }
+        }
+        let rate = || traces_per_sec.expect("sampling.traces_per_sec is needed by the policy");
+        SamplingSettings {
+            policy: match policy.as_deref() {
+                None | Some("") | Some("none") => None,
+                Some("probabilistic") => Some(SamplingPolicy::Probabilistic(
+                    probability.expect("sampling.probability is needed by the policy"),
+                )),
+                Some("rate-limited") => Some(SamplingPolicy::RateLimited(rate())),
+                Some("stratified") => Some(SamplingPolicy::Stratified(rate())),
+                Some(other) => panic!("Unknown sampling policy {}", other),
+            },
+            log_file,
+        }
+    }
+
+    /// Problems with the values, for `Settings::validate`
+    pub fn problems(&self) -> Vec<String> {
+        match self.policy {
+            Some(SamplingPolicy::Probabilistic(p)) if !(p > 0.0 && p <= 1.0) => {
+                vec![format!("sampling.probability ({}) should be in (0, 1]", p)]
+            }
+            Some(SamplingPolicy::RateLimited(r)) | Some(SamplingPolicy::Stratified(r))
+                if r <= 0.0 =>
+            {
+                vec![format!(
+                    "sampling.traces_per_sec ({}) should be positive",
+                    r
+                )]
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Allows `rate` traces per second, and bursts of up to a second's worth
+struct TokenBucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, now: Instant) -> Self {
+        TokenBucket {
+            tokens: rate,
+            last: now,
+        }
+    }
+
+    fn take(&mut self, rate: f64, now: Instant) -> bool {
+        let refill = now.saturating_duration_since(self.last).as_secs_f64() * rate;
+        self.tokens = (self.tokens + refill).min(rate.max(1.0));
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Base IDs are random, so their first bits decide whether a request is kept. Partial traces of
+/// a request get the same decision as the complete one.
+fn keep_request(base_id: &Uuid, probability: f64) -> bool {
+    if probability >= 1.0 {
+        return true;
+    }
+    let mut bits = [0; 8];
+    bits.copy_from_slice(&base_id.as_bytes()[..8]);
+    (u64::from_be_bytes(bits) as f64) < probability * u64::MAX as f64
+}
+
+/// Samples what the wrapped reader collects
+pub struct SamplingReader {
+    inner: Box<dyn Reader>,
+    settings: SamplingSettings,
+    clock: Arc<dyn Clock>,
+    /// One for all traces if rate-limited, one per request type if stratified
+    buckets: HashMap<Option<RequestType>, TokenBucket>,
+    /// Traces seen and kept of each request type since the start
+    totals: HashMap<RequestType, (usize, usize)>,
+}
+
+impl SamplingReader {
+    pub fn new(inner: Box<dyn Reader>, settings: SamplingSettings) -> Self {
+        SamplingReader {
+            inner,
+            settings,
+            clock: Arc::new(SystemClock),
+            buckets: HashMap::new(),
+            totals: HashMap::new(),
+        }
+    }
+
+    fn keep(&mut self, trace: &Trace, now: Instant) -> bool {
+        let (rate, key) = match self.settings.policy {
+            None => return true,
+            Some(SamplingPolicy::Probabilistic(p)) => return keep_request(&trace.base_id, p),
+            Some(SamplingPolicy::RateLimited(rate)) => (rate, None),
+            Some(SamplingPolicy::Stratified(rate)) => (rate, Some(trace.request_type)),
+        };
+        self.buckets
+            .entry(key)
+            .or_insert_with(|| TokenBucket::new(rate, now))
+            .take(rate, now)
+    }
+
+    /// Traces of the request type seen for each one kept so far
+    pub fn weight(&self, request_type: RequestType) -> f64 {
+        match self.totals.get(&request_type) {
+            Some(&(seen, kept)) if kept > 0 => seen as f64 / kept as f64,
+            _ => 1.0,
+        }
+    }
+
+    fn log(&self, counts: &HashMap<RequestType, (usize, usize)>) -> Result<(), Box<dyn Error>> {
+        let wall = self.clock.wall();
+        let lines: Vec<String> = counts
+            .iter()
+            .map(|(&request_type, &(seen, kept))| {
+                let weight = self.weight(request_type);
+                format!("{},{},{},{},{}", wall, request_type, seen, kept, weight)
+            })
+            .collect();
+        match &self.settings.log_file {
+            Some(path) => {
+                let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+                for line in lines {
+                    writeln!(file, "{}", line)?;
+                }
+            }
+            None => {
+                for line in lines {
+                    debug!("Sampled {}", line);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Reader for SamplingReader {
+    fn read_file(&mut self, filename: &str) -> Trace {
+        self.inner.read_file(filename)
+    }
+
+    fn read_dir(&mut self, foldername: &str) -> Vec<Trace> {
+        self.inner.read_dir(foldername)
+    }
+
+    fn get_trace_from_base_id(&mut self, id: &str) -> Result<Trace, Box<dyn Error>> {
+        self.inner.get_trace_from_base_id(id)
+    }
+
+    fn get_recent_traces(&mut self) -> Vec<Trace> {
+        let now = self.clock.now();
+        let mut counts = HashMap::new();
+        let mut result = Vec::new();
+        for trace in self.inner.get_recent_traces() {
+            let keep = self.keep(&trace, now);
+            for c in [&mut counts, &mut self.totals] {
+                let count = c.entry(trace.request_type).or_insert((0, 0));
+                count.0 += 1;
+                count.1 += keep as usize;
+            }
+            if keep {
+                result.push(trace);
+            }
+        }
+        if counts.values().any(|&(seen, kept)| seen > kept) {
+            if let Err(e) = self.log(&counts) {
+                error!("Could not log sampling decisions: {}", e);
+            }
+        }
+        result
+    }
+
+    fn reset_state(&mut self) {
+        self.inner.reset_state();
+    }
+
+    fn for_searchspace(&mut self) {
+        self.inner.for_searchspace();
+    }
+
+    fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock.clone();
+        self.inner.set_clock(clock);
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.inner.is_exhausted()
+    }
+
+    fn parse_report(&self, id: &str) -> Option<&ParseReport> {
+        self.inner.parse_report(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use crate::clock::SimulatedClock;
+
+    /// Returns the same batch of traces on every call
+    struct Batches(Vec<Trace>);
+
+    impl Reader for Batches {
+        fn read_file(&mut self, _filename: &str) -> Trace {
+            unimplemented!()
+        }
+
+        fn get_trace_from_base_id(&mut self, _id: &str) -> Result<Trace, Box<dyn Error>> {
+            unimplemented!()
+        }
+
+        fn get_recent_traces(&mut self) -> Vec<Trace> {
+            self.0.clone()
+        }
+
+        fn reset_state(&mut self) {}
+
+        fn for_searchspace(&mut self) {}
+    }
+
+    #[test]
+    fn samples_by_policy() {
+        let batch = |types: &[&str]| {
+            let traces = types.iter().map(|t| {
+                let mut trace = Trace::new(&Uuid::new_v4());
+                trace.request_type = RequestType::from_str(t).unwrap();
+                trace
+            });
+            Box::new(Batches(traces.collect()))
+        };
+        let sampled = |policy| {
+            let settings = SamplingSettings {
+                policy: Some(policy),
+                log_file: None,
+            };
+            let mut reader = SamplingReader::new(batch(&["ServerCreate"; 9]), settings);
+            let clock = Arc::new(SimulatedClock::new());
+            reader.set_clock(clock.clone());
+            let first = reader.get_recent_traces().len();
+            clock.advance(Duration::from_millis(500));
+            (first, reader.get_recent_traces().len())
+        };
+        assert_eq!(sampled(SamplingPolicy::Probabilistic(1.0)), (9, 9));
+        assert_eq!(sampled(SamplingPolicy::RateLimited(4.0)), (4, 2));
+
+        // Each request type gets its own budget
+        let settings = SamplingSettings {
+            policy: Some(SamplingPolicy::Stratified(2.0)),
+            log_file: None,
+        };
+        let types = [
+            "ServerCreate",
+            "ServerCreate",
+            "ServerCreate",
+            "ServerDelete",
+        ];
+        let mut reader = SamplingReader::new(batch(&types), settings);
+        reader.set_clock(Arc::new(SimulatedClock::new()));
+        assert_eq!(reader.get_recent_traces().len(), 3);
+        let create = RequestType::from_str("ServerCreate").unwrap();
+        assert_eq!(reader.weight(create), 1.5);
+        assert_eq!(
+            reader.weight(RequestType::from_str("ServerDelete").unwrap()),
+            1.0
+        );
+    }
+}
@@ -0,0 +1,334 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Stitching traces of different applications into one.
+//!
+//! When a request crosses from one application to another (e.g., nova writes to HDFS), the
+//! caller's span id can be propagated into the callee's trace. The callee's events then carry
+//! the span id under the correlation key (`stitch_key` in the settings), and the callee's trace
+//! is inserted into the caller's, between the entry and exit of that span. Critical paths of the
+//! stitched trace go through both applications.
+//!
+//! A callee finishes before its caller, so its trace is usually read first. Traces that refer to
+//! a span are held for a while before they are returned, waiting for their caller; traces that
+//! refer to nothing are returned right away, with the waiting traces that refer to their spans
+//! stitched in. A callee read after its caller is returned on its own.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use petgraph::graph::NodeIndex;
+use uuid::Uuid;
+
+use crate::clock::Clock;
+use crate::clock::SystemClock;
+use crate::trace::DAGEdge;
+use crate::trace::EdgeType;
+use crate::trace::EventType;
+use crate::trace::Trace;
+use crate::trace::Value;
+
+struct Pending {
+    /// Index of the application the trace was read from
+    app: usize,
+    received: Instant,
+    trace: Trace,
+}
+
+/// Takes traces from several readers and returns them with the traces they call stitched in
+pub struct TraceStitcher {
+    key: String,
+    wait: Duration,
+    /// Traces waiting for their caller, by base id
+    pending: HashMap<Uuid, Pending>,
+    /// Base ids of the waiting traces that refer to each span id
+    by_reference: HashMap<Uuid, Vec<Uuid>>,
+    /// Traces that can be returned without waiting
+    done: Vec<(usize, Trace)>,
+    clock: Arc<dyn Clock>,
+}
+
+impl TraceStitcher {
+    /// Traces are kept for `wait` in case their caller or callees show up
+    pub fn new(key: &str, wait: Duration) -> Self {
+        TraceStitcher {
+            key: key.to_string(),
+            wait,
+            pending: HashMap::new(),
+            by_reference: HashMap::new(),
+            done: Vec::new(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Add a trace read from application `app`. Waiting traces that refer to its spans are
+    /// stitched into it. It then waits for its caller if it refers to a span, and is ready
+    /// otherwise.
+    pub fn add(&mut self, app: usize, trace: Trace) {
+        let mut trace = trace;
+        for span in spans(&trace) {
+            for id in self.by_reference.remove(&span).unwrap_or_default() {
+                if let Some(callee) = self.take(&id) {
+                    stitch(&mut trace, callee.trace, &self.key);
+                }
+            }
+        }
+        let references = outside_references(&trace, &self.key);
+        if references.is_empty() {
+            self.done.push((app, trace));
+            return;
+        }
+        for span in references {
+            self.by_reference
+                .entry(span)
+                .or_default()
+                .push(trace.base_id);
+        }
+        let received = self.clock.now();
+        self.pending.insert(
+            trace.base_id,
+            Pending {
+                app,
+                received,
+                trace,
+            },
+        );
+    }
+
+    /// Removes a waiting trace and its references
+    fn take(&mut self, id: &Uuid) -> Option<Pending> {
+        let pending = self.pending.remove(id)?;
+        for span in outside_references(&pending.trace, &self.key) {
+            if let Some(ids) = self.by_reference.get_mut(&span) {
+                ids.retain(|i| i != id);
+                if ids.is_empty() {
+                    self.by_reference.remove(&span);
+                }
+            }
+        }
+        Some(pending)
+    }
+
+    /// Traces that refer to nothing, and those that waited long enough for their caller, with
+    /// the application they were read from (the caller's, for stitched traces)
+    pub fn ready(&mut self) -> Vec<(usize, Trace)> {
+        let expired: Vec<Uuid> = self
+            .pending
+            .iter()
+            .filter(|(_, p)| self.clock.elapsed(p.received) >= self.wait)
+            .map(|(&id, _)| id)
+            .collect();
+        let mut ready = std::mem::replace(&mut self.done, Vec::new());
+        for id in expired {
+            let p = self.take(&id).unwrap();
+            ready.push((p.app, p.trace));
+        }
+        ready
+    }
+
+    /// Everything still waiting, e.g., when the reader is shutting down
+    pub fn flush(&mut self) -> Vec<(usize, Trace)> {
+        self.by_reference.clear();
+        let mut ready = std::mem::replace(&mut self.done, Vec::new());
+        ready.extend(self.pending.drain().map(|(_, p)| (p.app, p.trace)));
+        ready
+    }
+}
+
+/// Span ids the callee refers to under `key`
+fn callee_references(callee: &Trace, key: &str) -> Vec<Uuid> {
+    callee
+        .g
+        .node_indices()
+        .filter_map(|n| match callee.g[n].key_value_pair.get(key) {
+            Some(Value::Str(s)) => Uuid::parse_str(s).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+fn spans(trace: &Trace) -> HashSet<Uuid> {
+    trace
+        .g
+        .node_indices()
+        .map(|n| trace.g[n].trace_id)
+        .collect()
+}
+
+/// Span ids the trace refers to under `key` that are not its own, e.g., not those of callees
+/// stitched into it
+fn outside_references(trace: &Trace, key: &str) -> HashSet<Uuid> {
+    let own = spans(trace);
+    callee_references(trace, key)
+        .into_iter()
+        .filter(|r| !own.contains(r))
+        .collect()
+}
+
+/// Entry and exit (if there is one) of the span of `caller` that `callee` was called from
+fn caller_span(
+    callee: &Trace,
+    caller: &Trace,
+    key: &str,
+) -> Option<(NodeIndex, Option<NodeIndex>)> {
+    let references = callee_references(callee, key);
+    if references.is_empty() || callee.base_id == caller.base_id {
+        return None;
+    }
+    let mut spans: HashMap<Uuid, (Option<NodeIndex>, Option<NodeIndex>)> = HashMap::new();
+    for n in caller.g.node_indices() {
+        let event = &caller.g[n];
+        if !references.contains(&event.trace_id) {
+            continue;
+        }
+        let span = spans.entry(event.trace_id).or_default();
+        match event.variant {
+            EventType::Entry => span.0 = Some(n),
+            EventType::Exit => span.1 = Some(n),
+            EventType::Annotation => {}
+        }
+    }
+    references
+        .iter()
+        .filter_map(|r| spans.get(r))
+        .find_map(|&(entry, exit)| entry.map(|e| (e, exit)))
+}
+
+fn edge(trace: &Trace, from: NodeIndex, to: NodeIndex, variant: EdgeType) -> DAGEdge {
+    DAGEdge {
+        duration: (trace.g[to].timestamp - trace.g[from].timestamp)
+            .to_std()
+            .unwrap_or_default(),
+        variant,
+    }
+}
+
+/// Insert `callee` into `caller`, after the entry of the span it was called from and before its
+/// exit. Returns false if the callee doesn't refer to any span of the caller.
+pub fn stitch(caller: &mut Trace, callee: Trace, key: &str) -> bool {
+    let (entry, exit) = match caller_span(&callee, caller, key) {
+        Some(span) => span,
+        None => return false,
+    };
+    let mut mapping = HashMap::new();
+    for n in callee.g.node_indices() {
+        mapping.insert(n, caller.g.add_node(callee.g[n].clone()));
+    }
+    for e in callee.g.edge_indices() {
+        let (source, target) = callee.g.edge_endpoints(e).unwrap();
+        caller
+            .g
+            .add_edge(mapping[&source], mapping[&target], callee.g[e].clone());
+    }
+    let (start, end) = (mapping[&callee.start_node], mapping[&callee.end_node]);
+    let to_start = edge(caller, entry, start, EdgeType::ChildOf);
+    caller.g.add_edge(entry, start, to_start);
+    if let Some(exit) = exit {
+        let from_end = edge(caller, end, exit, EdgeType::FollowsFrom);
+        caller.g.add_edge(end, exit, from_end);
+    }
+    caller.keys.extend(callee.keys);
+    caller.is_partial |= callee.is_partial;
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::NaiveDateTime;
+
+    use crate::clock::SimulatedClock;
+    use crate::trace::Event;
+    use crate::trace::TracepointID;
+
+    /// A trace that is one span, optionally called from `parent`
+    fn span_trace(
+        name: &str,
+        span: Uuid,
+        start_ms: i64,
+        end_ms: i64,
+        parent: Option<Uuid>,
+    ) -> Trace {
+        let mut trace = Trace::new(&Uuid::new_v4());
+        let mut kv = HashMap::new();
+        if let Some(parent) = parent {
+            kv.insert("parent_span".to_string(), Value::Str(parent.to_string()));
+        }
+        let nodes: Vec<_> = vec![(EventType::Entry, start_ms), (EventType::Exit, end_ms)]
+            .into_iter()
+            .map(|(variant, ms)| {
+                trace.g.add_node(Event {
+                    trace_id: span,
+                    tracepoint_id: TracepointID::from_str(name),
+                    timestamp: NaiveDateTime::from_timestamp(0, 0)
+                        + chrono::Duration::milliseconds(ms),
+                    is_synthetic: false,
+                    variant,
+                    key_value_pair: kv.clone(),
+                })
+            })
+            .collect();
+        let e = edge(&trace, nodes[0], nodes[1], EdgeType::ChildOf);
+        trace.g.add_edge(nodes[0], nodes[1], e);
+        trace.start_node = nodes[0];
+        trace.end_node = nodes[1];
+        trace
+    }
+
+    #[test]
+    fn stitches_waiting_callees() {
+        let clock = Arc::new(SimulatedClock::new());
+        let mut stitcher = TraceStitcher::new("parent_span", Duration::from_secs(10));
+        stitcher.set_clock(clock.clone());
+
+        let nova_span = Uuid::new_v4();
+        let nova = span_trace("nova", nova_span, 0, 10, None);
+        let hdfs = span_trace("hdfs", Uuid::new_v4(), 2, 8, Some(nova_span));
+        let unrelated = span_trace("hdfs", Uuid::new_v4(), 2, 8, None);
+        let missing_span = Uuid::new_v4();
+        let orphan = span_trace("hdfs", Uuid::new_v4(), 2, 8, Some(missing_span));
+        stitcher.add(1, hdfs);
+        stitcher.add(1, orphan);
+        assert!(stitcher.ready().is_empty());
+        stitcher.add(1, unrelated);
+        stitcher.add(0, nova);
+        // The caller is there, the orphan keeps waiting
+        assert_eq!(
+            stitcher.by_reference.keys().collect::<Vec<_>>(),
+            vec![&missing_span]
+        );
+
+        let mut ready = stitcher.ready();
+        ready.sort_by_key(|(app, _)| *app);
+        assert_eq!(ready.len(), 2);
+        clock.sleep(Duration::from_secs(10));
+        assert_eq!(stitcher.ready().len(), 1);
+        assert!(stitcher.by_reference.is_empty());
+        let (app, stitched) = &ready[0];
+        assert_eq!(*app, 0);
+        assert_eq!(stitched.g.node_count(), 4);
+        let from_nova = stitched
+            .g
+            .edge_indices()
+            .find(|&e| {
+                let (s, t) = stitched.g.edge_endpoints(e).unwrap();
+                stitched.g[s].tracepoint_id != stitched.g[t].tracepoint_id
+            })
+            .unwrap();
+        assert_eq!(stitched.g[from_nova].duration, Duration::from_millis(2));
+        assert_eq!(ready[1].0, 1);
+    }
+}
@@ -14,16 +14,20 @@ use std::path::PathBuf;
 
 use chrono::Duration;
 use chrono::NaiveDateTime;
+use log::{debug, error, warn};
 use petgraph::algo::connected_components;
 use petgraph::graph::{Graph, NodeIndex};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::reader::dataset;
+use crate::reader::dataset::DatasetOptions;
 use crate::reader::HexID;
 use crate::reader::Reader;
 use crate::settings::Settings;
 use crate::trace::Event;
 use crate::trace::EventType;
+use crate::trace::Span;
 use crate::trace::Trace;
 use crate::trace::TracepointID;
 use crate::trace::{DAGEdge, EdgeType};
@@ -46,6 +50,7 @@ fn raise(s: &str) -> Box<dyn Error> {
 
 pub struct UberReader {
     uber_trace_dir: PathBuf,
+    dataset: DatasetOptions,
 }
 
 impl Reader for UberReader {
@@ -59,7 +64,7 @@ impl Reader for UberReader {
         let mut path = self.uber_trace_dir.clone();
         path.push(id);
         path.set_extension("json");
-        eprintln!("Reading {}", path.to_str().unwrap());
+        debug!("Reading {}", path.to_str().unwrap());
         self.try_read_file(&path.to_str().unwrap())
     }
 
@@ -72,11 +77,18 @@ impl Reader for UberReader {
         for entry in std::fs::read_dir(foldername).unwrap() {
             let entry = entry.unwrap();
             let path = entry.path();
-            eprintln!("Reading {}", path.to_str().unwrap());
+            debug!("Reading {}", path.to_str().unwrap());
+            if dataset::is_dataset(&path) {
+                match dataset::load(self, &path, &self.dataset) {
+                    Ok(traces) => results.extend(traces),
+                    Err(e) => error!("Loading {:?} failed with {}", path, e),
+                }
+                continue;
+            }
             match self.try_read_file(&path.to_str().unwrap()) {
                 Ok(t) => results.push(t),
                 Err(e) => {
-                    eprintln!("Parsing failed with {:?}", e);
+                    warn!("Parsing failed with {:?}", e);
                 }
             }
         }
@@ -105,6 +117,7 @@ impl UberReader {
     pub fn from_settings(settings: &Settings) -> Self {
         UberReader {
             uber_trace_dir: settings.uber_trace_dir.clone(),
+            dataset: DatasetOptions::from_settings(settings),
         }
     }
 
@@ -139,27 +152,21 @@ impl UberReader {
             } else {
                 return Err(raise(&format!("Got {} references", span.references.len())));
             };
-            let (start_time, end_time) = convert_uber_timestamp(span.start_time, span.duration);
+            let (start, end) = convert_uber_timestamp(span.start_time, span.duration);
+            let interval = Span {
+                span_id: span.span_id.to_uuid(),
+                parent: parent.map(|p| p.to_uuid()),
+                tracepoint_id: TracepointID::from_str(&span.operation_name.to_string()),
+                start,
+                end,
+                key_value_pair: HashMap::new(),
+            };
             events.push(UberEvent {
-                e: Event {
-                    trace_id: span.span_id.to_uuid(),
-                    tracepoint_id: TracepointID::from_str(&span.operation_name.to_string()),
-                    timestamp: start_time,
-                    is_synthetic: false,
-                    variant: EventType::Entry,
-                    key_value_pair: HashMap::new(),
-                },
+                e: interval.entry(),
                 parent_id: parent,
             });
             events.push(UberEvent {
-                e: Event {
-                    trace_id: span.span_id.to_uuid(),
-                    tracepoint_id: TracepointID::from_str(&span.operation_name.to_string()),
-                    timestamp: end_time,
-                    is_synthetic: false,
-                    variant: EventType::Exit,
-                    key_value_pair: HashMap::new(),
-                },
+                e: interval.exit(),
                 parent_id: parent,
             });
         }
@@ -169,8 +176,17 @@ impl UberReader {
     fn from_json(&self, data: &mut UberTrace) -> Result<Trace, Box<dyn Error>> {
         assert!(data.data.len() == 1);
         let trace = &data.data[0];
-        let mut mydag = Trace::new(&trace.trace_id.to_uuid());
-        let mut event_list = self.to_events_edges(&trace.spans)?;
+        self.trace_from_spans(&trace.trace_id.to_uuid(), &trace.spans)
+    }
+
+    /// Builds the DAG of a request from all of its spans, in any order
+    pub fn trace_from_spans(
+        &self,
+        trace_id: &Uuid,
+        spans: &Vec<UberSpan>,
+    ) -> Result<Trace, Box<dyn Error>> {
+        let mut mydag = Trace::new(trace_id);
+        let mut event_list = self.to_events_edges(spans)?;
         event_list.sort_by(|a, b| a.e.timestamp.cmp(&b.e.timestamp));
         let mut state = UberParsingState {
             active_spans: HashMap::new(),
@@ -372,6 +388,44 @@ pub struct UberSpan {
     process_id: HexID,
 }
 
+impl UberSpan {
+    /// A span from a row of the bulk dataset, which only has the fields the reader uses
+    pub fn from_row(
+        trace_id: HexID,
+        span_id: HexID,
+        parent_id: Option<HexID>,
+        operation_name: HexID,
+        start_time: u64,
+        duration: i64,
+    ) -> Self {
+        let none = HexID { id: None };
+        UberSpan {
+            trace_id,
+            span_id,
+            flags: 0,
+            operation_name,
+            references: parent_id
+                .map(|span_id| UberReference {
+                    ref_type: "CHILD_OF".to_string(),
+                    trace_id,
+                    span_id,
+                })
+                .into_iter()
+                .collect(),
+            start_time,
+            duration,
+            tags: Vec::new(),
+            logs: Vec::new(),
+            process: UberProcess {
+                service_name: none,
+                tags: Vec::new(),
+            },
+            warnings: None,
+            process_id: none,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
 pub struct UberEdge {
     parent: HexID,
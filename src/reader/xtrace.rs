@@ -0,0 +1,272 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Talking to the X-Trace server, for the applications traced with X-Trace (HDFS, DeathStar).
+//!
+//! New requests are found through the JSON API of the server: tasks are listed page by page, only
+//! in the time range since the last poll, so a long profiling run does not miss requests that
+//! fell off the first page.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::time::Duration;
+
+use chrono::NaiveDateTime;
+use futures::future;
+use futures::future::Future;
+use futures::stream::Stream;
+use futures::Async;
+use hyper::rt;
+use hyper::Client;
+use log::error;
+use serde::Deserialize;
+
+use crate::settings::Settings;
+use crate::PythiaError;
+
+/// Lists tasks; takes tag, offset, length, and since/until in milliseconds since the epoch
+const TASKS_PATH: &str = "tasks";
+/// How long returned tasks are remembered, so they are not returned again if they are updated
+const REMEMBER_RETURNED: Duration = Duration::from_secs(3600);
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct TaskRecord {
+    task_id: String,
+    /// Milliseconds since the epoch
+    last_updated: i64,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+pub struct XTraceClient {
+    url: String,
+    /// Requests must have all of these tags; the first one is used in the query
+    tags: Vec<String>,
+    page_size: usize,
+    /// Tasks that were not updated for this long are finished
+    jiffy: Duration,
+    /// Tasks updated before this were already returned
+    watermark: Option<NaiveDateTime>,
+    /// Tasks returned in the last `REMEMBER_RETURNED`, and when they were returned
+    returned: HashMap<String, NaiveDateTime>,
+}
+
+impl XTraceClient {
+    pub fn from_settings(settings: &Settings) -> Self {
+        XTraceClient {
+            url: settings.xtrace_url.clone(),
+            tags: settings.xtrace_tags.clone(),
+            page_size: settings.xtrace_page_size,
+            jiffy: settings.jiffy,
+            watermark: None,
+            returned: HashMap::new(),
+        }
+    }
+
+    pub fn report_url(&self, id: &str) -> String {
+        format!("{}/interactive/reports/{}", self.url, id)
+    }
+
+    /// Ids of the tasks with all the tags that finished since the last call. The first call
+    /// returns nothing, so requests from before Pythia started are ignored.
+    pub fn finished_tasks(&mut self, now: NaiveDateTime) -> Result<Vec<String>, Box<dyn Error>> {
+        let url = self.url.clone();
+        let tags = self.tags.clone();
+        let page_size = self.page_size;
+        self.finished_tasks_from(now, |since, until, offset| {
+            tasks_page(&url, &tags, page_size, since, until, offset)
+        })
+    }
+
+    /// `finished_tasks`, with the pages of tasks updated in `[since, until)` from `fetch`
+    fn finished_tasks_from<F>(
+        &mut self,
+        now: NaiveDateTime,
+        mut fetch: F,
+    ) -> Result<Vec<String>, Box<dyn Error>>
+    where
+        F: FnMut(NaiveDateTime, NaiveDateTime, usize) -> Result<Vec<TaskRecord>, Box<dyn Error>>,
+    {
+        let until = now - chrono::Duration::from_std(self.jiffy).unwrap();
+        let since = match self.watermark {
+            Some(w) => w,
+            None => {
+                self.watermark = Some(until);
+                return Ok(Vec::new());
+            }
+        };
+        let mut result = Vec::new();
+        let mut offset = 0;
+        loop {
+            let page = fetch(since, until, offset)?;
+            for task in &page {
+                let updated = from_millis(task.last_updated);
+                if updated >= until || self.returned.contains_key(&task.task_id) {
+                    continue;
+                }
+                if self.tags.iter().all(|t| task.tags.contains(t)) {
+                    self.returned.insert(task.task_id.clone(), until);
+                    result.push(task.task_id.clone());
+                }
+            }
+            if page.len() < self.page_size {
+                break;
+            }
+            offset += page.len();
+        }
+        self.watermark = Some(until);
+        let forget = until - chrono::Duration::from_std(REMEMBER_RETURNED).unwrap();
+        self.returned.retain(|_, returned| *returned >= forget);
+        Ok(result)
+    }
+}
+
+fn tasks_page(
+    url: &str,
+    tags: &[String],
+    page_size: usize,
+    since: NaiveDateTime,
+    until: NaiveDateTime,
+    offset: usize,
+) -> Result<Vec<TaskRecord>, Box<dyn Error>> {
+    let mut url = format!(
+        "{}/{}?offset={}&length={}&since={}&until={}",
+        url,
+        TASKS_PATH,
+        offset,
+        page_size,
+        since.timestamp_millis(),
+        until.timestamp_millis()
+    );
+    if let Some(tag) = tags.first() {
+        url.push_str(&format!("&tag={}", tag));
+    }
+    Ok(serde_json::from_str(&download_webpage(url)?)?)
+}
+
+fn from_millis(millis: i64) -> NaiveDateTime {
+    NaiveDateTime::from_timestamp(millis / 1000, (millis % 1000) as u32 * 1_000_000)
+}
+
+pub fn download_webpage(urn: String) -> Result<String, Box<dyn Error>> {
+    let (tx, mut rx) = futures::sync::mpsc::unbounded();
+
+    let fut = future::lazy(move || {
+        Client::new()
+            .get(urn.parse().unwrap())
+            .and_then(|res| res.into_body().concat2())
+            .and_then(move |body| {
+                let s = ::std::str::from_utf8(&body).expect("httpbin sends utf-8 JSON");
+                tx.unbounded_send(s.to_string()).unwrap();
+                Ok(())
+            })
+            .map_err(|e| error!("RPC Client error: {:?}", e))
+    });
+    rt::run(fut);
+    let mut result = "".to_string();
+    loop {
+        match rx.poll() {
+            Ok(Async::Ready(Some(s))) => {
+                result = s;
+            }
+            Ok(Async::NotReady) => {}
+            Ok(Async::Ready(None)) => {
+                break;
+            }
+            Err(_) => {
+                return Err(Box::new(PythiaError::RpcError("Poll got us Err".into())));
+            }
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_tasks() {
+        let page: Vec<TaskRecord> = serde_json::from_str(
+            r#"[{"taskId": "abc", "firstSeen": 1000, "lastUpdated": 1592921554058,
+                 "numReports": 3, "tags": ["FsShell", "main"]}]"#,
+        )
+        .unwrap();
+        assert_eq!(page[0].task_id, "abc");
+        assert_eq!(
+            from_millis(page[0].last_updated).to_string(),
+            "2020-06-23 14:12:34.058"
+        );
+    }
+
+    fn task(id: &str, last_updated: i64, tags: &[&str]) -> TaskRecord {
+        TaskRecord {
+            task_id: id.to_string(),
+            last_updated,
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+        }
+    }
+
+    /// Tasks returned by the poll at `now`, and the (since, offset) of each page asked for
+    fn poll(
+        client: &mut XTraceClient,
+        tasks: &[TaskRecord],
+        now: i64,
+    ) -> (Vec<String>, Vec<(i64, usize)>) {
+        let mut pages = Vec::new();
+        let returned = client
+            .finished_tasks_from(from_millis(now), |since, until, offset| {
+                pages.push((since.timestamp_millis(), offset));
+                // Like the server, with both ends of the range included
+                Ok(tasks
+                    .iter()
+                    .filter(|t| {
+                        let updated = from_millis(t.last_updated);
+                        updated >= since && updated <= until
+                    })
+                    .skip(offset)
+                    .take(2)
+                    .cloned()
+                    .collect())
+            })
+            .unwrap();
+        (returned, pages)
+    }
+
+    #[test]
+    fn pages_through_tasks_since_the_watermark() {
+        let mut client = XTraceClient {
+            url: String::new(),
+            tags: vec!["FsShell".to_string(), "main".to_string()],
+            page_size: 2,
+            jiffy: Duration::from_secs(1),
+            watermark: None,
+            returned: HashMap::new(),
+        };
+        let mut tasks = vec![
+            task("a", 2000, &["FsShell", "main"]),
+            task("b", 2500, &["FsShell"]),
+            task("c", 3000, &["FsShell", "main"]),
+            task("d", 3500, &["FsShell", "main"]),
+            task("e", 9500, &["FsShell", "main"]),
+        ];
+        // The first poll only sets the watermark
+        let (returned, pages) = poll(&mut client, &tasks, 2000);
+        assert!(returned.is_empty() && pages.is_empty());
+        // Every page is read; b doesn't have all the tags, e is still running
+        let (returned, pages) = poll(&mut client, &tasks, 5000);
+        assert_eq!(returned, vec!["a", "c", "d"]);
+        assert_eq!(pages, vec![(1000, 0), (1000, 2), (1000, 4)]);
+        // Updated after it was returned, and not returned again
+        tasks[2].last_updated = 6000;
+        let (returned, pages) = poll(&mut client, &tasks, 11000);
+        assert_eq!(returned, vec!["e"]);
+        assert_eq!(pages[0], (4000, 0));
+    }
+}
@@ -0,0 +1,527 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! The report pythia_controller writes when it stops.
+//!
+//! `RunReport` is fed by the main loop as it goes: the problem groups it diagnoses, the
+//! tracepoints it enables for them, how much of an edge's variance the enabled tracepoints
+//! explained, the verdicts on where the problems are, and how the instrumentation affected the
+//! application. When the loop stops, `finish` adds the final state of the groups, and `write`
+//! puts the result next to the output file, as `<output>.report.json` and as a markdown summary
+//! in `<output>.report.md`.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::NaiveDateTime;
+use petgraph::graph::EdgeIndex;
+use serde::Serialize;
+
+use pythia_common::RequestType;
+
+use crate::clock::Clock;
+use crate::clock::SystemClock;
+use crate::critical::Path as _;
+use crate::deadline::SkippedWork;
+use crate::grouping::EdgeBreakdown;
+use crate::grouping::Group;
+use crate::grouping::GroupManager;
+use crate::grouping::VarianceExplained;
+use crate::impact::ImpactReport;
+use crate::search::Trial;
+use crate::trace::TracepointID;
+use crate::units::cv;
+
+/// Edges of each final problem group in the report, by share of the group's variance
+const LOCALIZED_EDGES: usize = 3;
+/// Final problem groups in the report, by variance
+const FINAL_GROUPS: usize = 10;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ProblemGroupRecord {
+    pub hash: String,
+    pub request_type: String,
+    /// Decision cycle the group was first diagnosed at
+    pub first_cycle: usize,
+    /// Decision cycles the group was diagnosed at
+    pub cycles: usize,
+    pub traces: usize,
+    pub mean_ms: f64,
+    pub cv: f64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct EnabledRecord {
+    pub cycle: usize,
+    pub wall: NaiveDateTime,
+    pub group: String,
+    pub tracepoints: Vec<(TracepointID, Option<RequestType>)>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ExplainedRecord {
+    pub cycle: usize,
+    pub edge: String,
+    pub tracepoints: usize,
+    pub explained: f64,
+}
+
+/// A problem edge whose variance the enabled tracepoints put on one sub-edge, which the manifest
+/// can't split any further
+#[derive(Serialize, Debug, Clone)]
+pub struct Verdict {
+    pub cycle: usize,
+    pub wall: NaiveDateTime,
+    pub group: String,
+    pub request_type: String,
+    /// The problem edge the tracepoints were enabled for
+    pub edge: String,
+    /// The tracepoints at the ends of the sub-edge
+    pub source: TracepointID,
+    pub target: TracepointID,
+    /// Share of the edge's variance on the sub-edge
+    pub variance_share: f64,
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Verdict for group {}: {} -> {} has {:.1}% of the variance of {}",
+            self.group,
+            self.source,
+            self.target,
+            100.0 * self.variance_share,
+            self.edge
+        )
+    }
+}
+
+/// The problem edges of a group when the loop stopped
+#[derive(Serialize, Debug, Clone)]
+pub struct LocalizedGroup {
+    pub hash: String,
+    pub request_type: String,
+    pub traces: usize,
+    pub mean_ms: f64,
+    pub cv: f64,
+    /// The edges with the highest share of the group's variance
+    pub edges: Vec<EdgeBreakdown>,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Overhead {
+    /// Most tracepoints enabled at once
+    pub max_enabled: usize,
+    pub enabled_at_end: usize,
+    /// Jiffies where the agents reported more trace data than `trace_size_limit`
+    pub over_budget_jiffies: usize,
+    /// Jiffies that went past `cycle_deadline_ms`, and what they skipped in total
+    pub missed_deadlines: usize,
+    pub skipped: SkippedWork,
+    /// Comparisons of consecutive epochs, see `impact`
+    pub impacts: Vec<ImpactReport>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct FinalReport {
+    pub started: NaiveDateTime,
+    pub stopped: NaiveDateTime,
+    pub duration: Duration,
+    pub reason: String,
+    pub decision_cycles: usize,
+    pub problem_groups: Vec<ProblemGroupRecord>,
+    pub enabled: Vec<EnabledRecord>,
+    pub explained: Vec<ExplainedRecord>,
+    pub verdicts: Vec<Verdict>,
+    pub localized: Vec<LocalizedGroup>,
+    pub variance_explained: Vec<VarianceExplained>,
+    pub overhead: Overhead,
+}
+
+/// What happened during a run of the main loop
+pub struct RunReport {
+    clock: Arc<dyn Clock>,
+    started: NaiveDateTime,
+    start: std::time::Instant,
+    cycle: usize,
+    problem_groups: BTreeMap<String, ProblemGroupRecord>,
+    enabled: Vec<EnabledRecord>,
+    explained: Vec<ExplainedRecord>,
+    verdicts: Vec<Verdict>,
+    overhead: Overhead,
+}
+
+impl RunReport {
+    pub fn new() -> Self {
+        RunReport::with_clock(Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        RunReport {
+            started: clock.wall(),
+            start: clock.now(),
+            clock,
+            cycle: 0,
+            problem_groups: BTreeMap::new(),
+            enabled: Vec::new(),
+            explained: Vec::new(),
+            verdicts: Vec::new(),
+            overhead: Overhead::default(),
+        }
+    }
+
+    /// Called every jiffy
+    pub fn jiffy(&mut self, enabled: usize, over_budget: bool) {
+        self.overhead.max_enabled = self.overhead.max_enabled.max(enabled);
+        self.overhead.over_budget_jiffies += over_budget as usize;
+    }
+
+    /// Called for each jiffy that went past its deadline
+    pub fn missed_deadline(&mut self, skipped: SkippedWork) {
+        self.overhead.missed_deadlines += 1;
+        self.overhead.skipped += skipped;
+    }
+
+    /// Called after each decision cycle; `cycle` counts the cycles made so far
+    pub fn cycle(&mut self, cycle: usize) {
+        self.cycle = cycle;
+    }
+
+    /// A group the current decision cycle diagnoses
+    pub fn problem_group(&mut self, group: &Group) {
+        let cycle = self.cycle;
+        let record = self
+            .problem_groups
+            .entry(group.hash().to_string())
+            .or_insert_with(|| ProblemGroupRecord {
+                hash: group.hash().to_string(),
+                request_type: group.parameterized_type().to_string(),
+                first_cycle: cycle,
+                cycles: 0,
+                traces: 0,
+                mean_ms: 0.0,
+                cv: 0.0,
+            });
+        record.cycles += 1;
+        record.traces = group.trace_count();
+        record.mean_ms = group.mean.as_millis();
+        record.cv = cv(group.mean, group.variance);
+    }
+
+    pub fn enabled(&mut self, group: &str, decisions: &[(TracepointID, Option<RequestType>)]) {
+        if decisions.is_empty() {
+            return;
+        }
+        self.enabled.push(EnabledRecord {
+            cycle: self.cycle,
+            wall: self.clock.wall(),
+            group: group.to_string(),
+            tracepoints: decisions.to_vec(),
+        });
+    }
+
+    pub fn explained(&mut self, trial: &Trial, explained: f64) {
+        self.explained.push(ExplainedRecord {
+            cycle: self.cycle,
+            edge: trial.edge(),
+            tracepoints: trial.tracepoints.len(),
+            explained,
+        });
+    }
+
+    /// Records that the trial localized the group's problem on `sub_edge`. Returns the verdict,
+    /// or None if the group already has one for the same sub-edge.
+    pub fn verdict(
+        &mut self,
+        group: &Group,
+        trial: &Trial,
+        sub_edge: EdgeIndex,
+        variance_share: f64,
+    ) -> Option<&Verdict> {
+        let (source, target) = group.g.edge_endpoints(sub_edge).unwrap();
+        let (source, target) = (group.g[source].tracepoint_id, group.g[target].tracepoint_id);
+        if self
+            .verdicts
+            .iter()
+            .any(|v| v.group == group.hash() && v.source == source && v.target == target)
+        {
+            return None;
+        }
+        self.verdicts.push(Verdict {
+            cycle: self.cycle,
+            wall: self.clock.wall(),
+            group: group.hash().to_string(),
+            request_type: group.parameterized_type().to_string(),
+            edge: trial.edge(),
+            source,
+            target,
+            variance_share,
+        });
+        self.verdicts.last()
+    }
+
+    pub fn impact(&mut self, report: &ImpactReport) {
+        self.overhead.impacts.push(report.clone());
+    }
+
+    /// The report with the final state of `groups`, one manager per application
+    pub fn finish(&self, reason: &str, groups: &[&GroupManager], enabled: usize) -> FinalReport {
+        let mut final_groups: Vec<&Group> =
+            groups.iter().flat_map(|m| m.problem_groups()).collect();
+        final_groups.sort_by(|a, b| b.variance.partial_cmp(&a.variance).unwrap());
+        let localized = final_groups
+            .into_iter()
+            .take(FINAL_GROUPS)
+            .map(|g| {
+                let mut edges = g.latency_breakdown();
+                edges.sort_by(|a, b| b.variance_share.partial_cmp(&a.variance_share).unwrap());
+                edges.truncate(LOCALIZED_EDGES);
+                LocalizedGroup {
+                    hash: g.hash().to_string(),
+                    request_type: g.parameterized_type().to_string(),
+                    traces: g.trace_count(),
+                    mean_ms: g.mean.as_millis(),
+                    cv: cv(g.mean, g.variance),
+                    edges,
+                }
+            })
+            .collect();
+        let mut problem_groups: Vec<ProblemGroupRecord> =
+            self.problem_groups.values().cloned().collect();
+        problem_groups.sort_by_key(|g| (g.first_cycle, std::cmp::Reverse(g.cycles)));
+        FinalReport {
+            started: self.started,
+            stopped: self.clock.wall(),
+            duration: self.clock.elapsed(self.start),
+            reason: reason.to_string(),
+            decision_cycles: self.cycle,
+            problem_groups,
+            enabled: self.enabled.clone(),
+            explained: self.explained.clone(),
+            verdicts: self.verdicts.clone(),
+            localized,
+            variance_explained: groups.iter().flat_map(|m| m.variance_explained()).collect(),
+            overhead: Overhead {
+                enabled_at_end: enabled,
+                ..self.overhead.clone()
+            },
+        }
+    }
+}
+
+impl Default for RunReport {
+    fn default() -> Self {
+        RunReport::new()
+    }
+}
+
+impl FinalReport {
+    /// Writes `<output>.report.json` and `<output>.report.md`, returns their paths
+    pub fn write(&self, output: &Path) -> std::io::Result<(PathBuf, PathBuf)> {
+        let json = PathBuf::from(format!("{}.report.json", output.display()));
+        let markdown = PathBuf::from(format!("{}.report.md", output.display()));
+        fs::write(&json, serde_json::to_string_pretty(self).unwrap())?;
+        fs::write(&markdown, self.to_markdown())?;
+        Ok((json, markdown))
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "# Pythia run report\n").unwrap();
+        writeln!(out, "* Started: {}", self.started).unwrap();
+        writeln!(out, "* Stopped: {} ({:?})", self.stopped, self.duration).unwrap();
+        writeln!(out, "* Reason: {}", self.reason).unwrap();
+        writeln!(out, "* Decision cycles: {}", self.decision_cycles).unwrap();
+
+        writeln!(out, "\n## Problem groups\n").unwrap();
+        writeln!(
+            out,
+            "| Group | Request type | First cycle | Cycles | Traces | Mean (ms) | CV |"
+        )
+        .unwrap();
+        writeln!(out, "|---|---|---|---|---|---|---|").unwrap();
+        for g in &self.problem_groups {
+            writeln!(
+                out,
+                "| {} | {} | {} | {} | {} | {:.2} | {:.3} |",
+                g.hash, g.request_type, g.first_cycle, g.cycles, g.traces, g.mean_ms, g.cv
+            )
+            .unwrap();
+        }
+
+        writeln!(out, "\n## Tracepoints enabled\n").unwrap();
+        for e in &self.enabled {
+            writeln!(
+                out,
+                "* Cycle {} ({}), group {}: {}",
+                e.cycle,
+                e.wall,
+                e.group,
+                e.tracepoints
+                    .iter()
+                    .map(|(tp, _)| format!("`{}`", tp))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+            .unwrap();
+        }
+
+        writeln!(out, "\n## Verdicts\n").unwrap();
+        for v in &self.verdicts {
+            writeln!(
+                out,
+                "* Cycle {}, group {} ({}): `{}` -> `{}` has {:.1}% of the variance of {}",
+                v.cycle,
+                v.group,
+                v.request_type,
+                v.source,
+                v.target,
+                100.0 * v.variance_share,
+                v.edge
+            )
+            .unwrap();
+        }
+
+        writeln!(out, "\n## Localized edges\n").unwrap();
+        for g in &self.localized {
+            writeln!(
+                out,
+                "### {} ({}, {} traces, mean {:.2}ms, CV {:.3})\n",
+                g.hash, g.request_type, g.traces, g.mean_ms, g.cv
+            )
+            .unwrap();
+            for e in &g.edges {
+                writeln!(
+                    out,
+                    "* `{}` -> `{}`: {:.1}% of the variance, {:.1}% of the latency, p95 {:.2}ms",
+                    e.from,
+                    e.to,
+                    e.variance_share,
+                    e.latency_share,
+                    e.p95.as_millis()
+                )
+                .unwrap();
+            }
+            writeln!(out).unwrap();
+        }
+
+        writeln!(out, "## Variance explained\n").unwrap();
+        for v in &self.variance_explained {
+            writeln!(
+                out,
+                "* {}: {:.1}% between groups",
+                v.request_type,
+                100.0 * v.ratio
+            )
+            .unwrap();
+        }
+        for e in &self.explained {
+            writeln!(
+                out,
+                "* Cycle {}: {} tracepoints explained {:.2} of {}",
+                e.cycle, e.tracepoints, e.explained, e.edge
+            )
+            .unwrap();
+        }
+
+        writeln!(out, "\n## Overhead\n").unwrap();
+        writeln!(
+            out,
+            "* Most tracepoints enabled: {}",
+            self.overhead.max_enabled
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "* Enabled at the end: {}",
+            self.overhead.enabled_at_end
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "* Jiffies over the trace size limit: {}",
+            self.overhead.over_budget_jiffies
+        )
+        .unwrap();
+        if self.overhead.missed_deadlines > 0 {
+            writeln!(
+                out,
+                "* Jiffies past the cycle deadline: {}, skipping {}",
+                self.overhead.missed_deadlines, self.overhead.skipped
+            )
+            .unwrap();
+        }
+        for impact in &self.overhead.impacts {
+            writeln!(out, "* {}", impact).unwrap();
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::clock::SimulatedClock;
+    use crate::testutils::TraceGenerator;
+
+    #[test]
+    fn reports_problem_groups_and_decisions() {
+        let clock = Arc::new(SimulatedClock::new());
+        let mut report = RunReport::with_clock(clock.clone());
+        let mut generator = TraceGenerator::new(7);
+        // Without concurrency every trace of the shape has the same critical path
+        generator.concurrency = 0.0;
+        let mut manager = GroupManager::new();
+        manager.update(&generator.critical_paths_of_shape(8));
+        let group = manager.problem_groups()[0];
+        report.jiffy(3, false);
+        report.problem_group(group);
+        report.enabled(group.hash(), &[(TracepointID::from_str("x"), None)]);
+        report.cycle(1);
+        report.problem_group(group);
+        clock.advance(Duration::from_secs(60));
+        report.jiffy(4, true);
+        report.missed_deadline(SkippedWork {
+            traces: 5,
+            groups: 0,
+            edges: 2,
+        });
+
+        let trial = Trial::new(group, group.edges_in_order()[0], Vec::new());
+        let sub_edge = group.edges_in_order()[0];
+        assert!(report.verdict(group, &trial, sub_edge, 0.9).is_some());
+        assert!(report.verdict(group, &trial, sub_edge, 0.95).is_none());
+
+        let result = report.finish("made 1 decisions", &[&manager], 4);
+        assert_eq!(result.duration, Duration::from_secs(60));
+        assert_eq!(result.problem_groups.len(), 1);
+        assert_eq!(result.problem_groups[0].first_cycle, 0);
+        assert_eq!(result.problem_groups[0].cycles, 2);
+        assert_eq!(result.enabled.len(), 1);
+        assert_eq!(result.localized[0].hash, group.hash());
+        assert!(result.localized[0].edges.len() <= LOCALIZED_EDGES);
+        assert_eq!(result.overhead.max_enabled, 4);
+        assert_eq!(result.overhead.over_budget_jiffies, 1);
+        assert_eq!(result.overhead.missed_deadlines, 1);
+        assert_eq!(result.overhead.skipped.traces, 5);
+        let markdown = result.to_markdown();
+        assert!(markdown.contains("| 0 | 2 | 8 |"));
+        assert!(markdown.contains("`x`"));
+        assert_eq!(result.verdicts.len(), 1);
+        assert!(markdown.contains("has 90.0% of the variance"));
+        assert!(markdown.contains("skipping 5 traces, 0 problem groups, 2 problem edges"));
+    }
+}
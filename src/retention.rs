@@ -0,0 +1,171 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Keeps the raw traces of problematic groups around for later drill-down.
+//!
+//! Groups only hold critical paths, and the spans behind them are freed from redis as soon as a
+//! trace is read. To still have the full traces when localization finishes (which can take
+//! days), the controller records every trace it reads here. Recent traces are kept in memory for
+//! `retention_window`; once a group is flagged problematic, the traces of its members that are
+//! still in memory are written to `retention_dir/<group>/<base id>.json`.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use log::{error, warn};
+use uuid::Uuid;
+
+use crate::clock::Clock;
+use crate::clock::SystemClock;
+use crate::critical::Path;
+use crate::grouping::Group;
+use crate::settings::Settings;
+use crate::trace::Trace;
+use crate::trace::TracepointID;
+
+pub struct TraceRetention {
+    dir: Option<PathBuf>,
+    window: Duration,
+    clock: Arc<dyn Clock>,
+    /// Traces read in the last `window`, and when they were read
+    recent: HashMap<Uuid, (Instant, Trace)>,
+    /// (group directory, trace) pairs that were already written, for traces still in `recent`
+    persisted: HashSet<(String, Uuid)>,
+}
+
+impl TraceRetention {
+    pub fn from_settings(settings: &Settings) -> Self {
+        TraceRetention {
+            dir: settings.retention_dir.clone(),
+            window: settings.retention_window,
+            clock: Arc::new(SystemClock),
+            recent: HashMap::new(),
+            persisted: HashSet::new(),
+        }
+    }
+
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.dir.is_some()
+    }
+
+    /// Keep the trace in memory so that it can be persisted if its group becomes problematic.
+    /// Traces older than the retention window are dropped.
+    pub fn record(&mut self, trace: Trace) {
+        if !self.is_enabled() {
+            return;
+        }
+        let now = self.clock.now();
+        let window = self.window;
+        self.recent.retain(|_, (seen, _)| now - *seen <= window);
+        // Traces that left the window can't be written again
+        let recent = &self.recent;
+        self.persisted.retain(|(_, id)| recent.contains_key(id));
+        // A partial trace of the same request is replaced by the newer one
+        self.recent.insert(trace.base_id, (now, trace));
+    }
+
+    /// Tracepoints of the traces kept in memory
+    pub fn tracepoints(&self) -> HashSet<TracepointID> {
+        self.recent
+            .values()
+            .flat_map(|(_, t)| t.g.node_indices().map(move |n| t.g[n].tracepoint_id))
+            .collect()
+    }
+
+    /// Write the member traces of a problematic group to disk. Returns how many traces were
+    /// newly written.
+    pub fn retain_group(&mut self, group: &Group) -> usize {
+        let dir = match &self.dir {
+            Some(d) => group_dir(d, group.hash()),
+            None => return 0,
+        };
+        if let Err(e) = fs::create_dir_all(&dir) {
+            error!("Could not create {}: {}", dir.display(), e);
+            return 0;
+        }
+        let dir_name = dir_name(group.hash());
+        let mut written = 0;
+        for path in &group.traces {
+            let id = path.g.base_id;
+            if self.persisted.contains(&(dir_name.clone(), id)) {
+                continue;
+            }
+            if let Some((_, trace)) = self.recent.get(&id) {
+                let mut file = dir.clone();
+                file.push(id.to_hyphenated().to_string());
+                file.set_extension("json");
+                trace.to_file(&file);
+                self.persisted.insert((dir_name.clone(), id));
+                written += 1;
+            }
+        }
+        written
+    }
+}
+
+/// Read back the traces that were retained for the group with the given hash
+pub fn retained_traces(retention_dir: &std::path::Path, group_hash: &str) -> Vec<Trace> {
+    let dir = group_dir(retention_dir, group_hash);
+    let entries = match fs::read_dir(&dir) {
+        Ok(e) => e,
+        Err(_) => return Vec::new(),
+    };
+    let mut result = Vec::new();
+    for entry in entries {
+        let path = entry.unwrap().path();
+        match fs::read_to_string(&path)
+            .ok()
+            .and_then(|s| serde_json::from_str::<Trace>(&s).ok())
+        {
+            Some(t) => result.push(t),
+            None => warn!("Skipping {}, not a trace", path.display()),
+        }
+    }
+    result.sort_by_key(|t| t.duration);
+    result
+}
+
+/// Group hashes may contain the parameterized request type, which has slashes and brackets
+fn dir_name(group_hash: &str) -> String {
+    group_hash
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+        .collect()
+}
+
+fn group_dir(retention_dir: &std::path::Path, group_hash: &str) -> PathBuf {
+    let mut dir = retention_dir.to_path_buf();
+    dir.push(dir_name(group_hash));
+    dir
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn group_hashes_make_flat_directories() {
+        assert_eq!(
+            dir_name("abc123/ServerCreate[flavor=m1.small]"),
+            "abc123_ServerCreate_flavor_m1_small_"
+        );
+        assert_eq!(
+            group_dir(std::path::Path::new("/tmp/retained"), "abc/def"),
+            PathBuf::from("/tmp/retained/abc_def")
+        );
+    }
+}
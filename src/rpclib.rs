@@ -7,22 +7,38 @@ All rights reserved.
 */
 
 //! Methods that talk to Pythia agents.
+//!
+//! Every call is retried according to a `RetryPolicy` with exponential backoff, and each attempt
+//! is bounded by a timeout. Calls that still fail are returned as `PythiaError::RpcError`s so
+//! that the caller can skip the agent.
+
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
 
 use futures::future::Future;
-use futures::stream::Stream;
-use futures::Async;
-use hyper::rt;
 use jsonrpc_client_transports::transports::http;
+use jsonrpc_core::ErrorCode;
 use jsonrpc_core::Value;
 use jsonrpc_core_client::{RpcChannel, RpcError, TypedClient};
+use log::{error, warn};
 use serde_json;
+use tokio::runtime::Runtime;
+use tokio::timer::Timeout;
 use uuid::Uuid;
 
+use pythia_common::AgentHealth;
+use pythia_common::AgentState;
 use pythia_common::NodeStats;
 use pythia_common::OSProfilerSpan;
 use pythia_common::RequestType;
+use pythia_common::SpanBatch;
+use pythia_common::SpanCount;
 
+use crate::settings::Settings;
 use crate::trace::TracepointID;
+use crate::PythiaError;
 
 #[derive(Clone)]
 struct PythiaClient(TypedClient);
@@ -33,11 +49,52 @@ impl From<RpcChannel> for PythiaClient {
     }
 }
 
+/// How agent RPCs are retried
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Number of retries after the first attempt
+    pub retries: usize,
+    /// Wait before the first retry; doubled after each retry
+    pub backoff: Duration,
+    /// How long to wait for each attempt
+    pub timeout: Duration,
+}
+
+impl RetryPolicy {
+    pub fn from_settings(settings: &Settings) -> RetryPolicy {
+        RetryPolicy {
+            retries: settings.rpc_retries,
+            backoff: settings.rpc_backoff,
+            timeout: settings.rpc_timeout,
+        }
+    }
+}
+
 impl PythiaClient {
     fn get_events(&self, trace_id: String) -> impl Future<Item = Value, Error = RpcError> {
         self.0.call_method("get_events", "String", (trace_id,))
     }
 
+    fn get_events_after(
+        &self,
+        trace_id: String,
+        skip: usize,
+    ) -> impl Future<Item = Value, Error = RpcError> {
+        self.0.call_method("get_events_after", "String", (trace_id, skip))
+    }
+
+    fn get_trace_fragment(
+        &self,
+        trace_id: String,
+    ) -> impl Future<Item = SpanBatch, Error = RpcError> {
+        self.0.call_method("get_trace_fragment", "SpanBatch", (trace_id,))
+    }
+
+    fn get_span_count(&self, trace_id: String) -> impl Future<Item = SpanCount, Error = RpcError> {
+        self.0
+            .call_method("get_span_count", "SpanCount", (trace_id,))
+    }
+
     fn set_all_tracepoints(&self, to_write: [u8; 1]) -> impl Future<Item = (), Error = RpcError> {
         self.0.call_method("set_all_tracepoints", "", (to_write,))
     }
@@ -53,6 +110,29 @@ impl PythiaClient {
         self.0.call_method("set_tracepoints", "", (new_settings,))
     }
 
+    fn read_tracepoints(
+        &self,
+        points: Vec<(TracepointID, Option<RequestType>)>,
+    ) -> impl Future<Item = Vec<Option<bool>>, Error = RpcError> {
+        let points: Vec<(String, Option<RequestType>)> =
+            points.iter().map(|(x, y)| (x.to_string(), *y)).collect();
+        self.0.call_method("read_tracepoints", "Vec", (points,))
+    }
+
+    fn get_tracepoint_state(
+        &self,
+    ) -> impl Future<Item = Vec<(String, Option<RequestType>, bool)>, Error = RpcError> {
+        self.0.call_method("get_tracepoint_state", "Vec", ())
+    }
+
+    fn dump_state(&self) -> impl Future<Item = AgentState, Error = RpcError> {
+        self.0.call_method("dump_state", "AgentState", ())
+    }
+
+    fn load_state(&self, state: AgentState) -> impl Future<Item = (), Error = RpcError> {
+        self.0.call_method("load_state", "", (state,))
+    }
+
     fn read_node_stats(&self) -> impl Future<Item = NodeStats, Error = RpcError> {
         self.0.call_method("read_node_stats", "", ())
     }
@@ -60,171 +140,375 @@ impl PythiaClient {
     fn free_keys(&self, keys: Vec<String>) -> impl Future<Item = (), Error = RpcError> {
         self.0.call_method("free_keys", "", (keys,))
     }
-}
 
-/// Read the overhead stats from the agent
-pub fn read_client_stats(client_uri: &str) -> NodeStats {
-    let (tx, mut rx) = futures::sync::mpsc::unbounded();
+    fn ping(&self) -> impl Future<Item = String, Error = RpcError> {
+        self.0.call_method("ping", "String", ())
+    }
+
+    fn health(&self) -> impl Future<Item = AgentHealth, Error = RpcError> {
+        self.0.call_method("health", "AgentHealth", ())
+    }
 
-    let run = http::connect(client_uri)
-        .and_then(move |client: PythiaClient| {
-            client.read_node_stats().and_then(move |result| {
+    fn version(&self) -> impl Future<Item = String, Error = RpcError> {
+        self.0.call_method("version", "String", ())
+    }
+}
+
+/// Makes a single call to the agent, waiting at most `timeout` for it to finish.
+///
+/// The call runs on its own thread and runtime, so that it can be made from inside another
+/// runtime. Whatever is still running when the call finishes or times out is dropped with the
+/// runtime, so a hung agent doesn't hold on to the thread.
+fn call_once<T, F, R>(client_uri: &str, timeout: Duration, call: F) -> Result<T, PythiaError>
+where
+    T: Send + 'static,
+    F: FnOnce(&PythiaClient) -> R + Send + 'static,
+    R: Future<Item = T, Error = RpcError> + Send + 'static,
+{
+    let uri = client_uri.to_string();
+    let worker = thread::spawn(move || {
+        let mut runtime = Runtime::new().map_err(|e| e.to_string())?;
+        let run = http::connect(&uri).and_then(move |client: PythiaClient| {
+            call(&client).map(move |result| {
                 drop(client);
-                let _ = tx.unbounded_send(result);
-                Ok(())
+                result
             })
+        });
+        let result = runtime.block_on(Timeout::new(run, timeout));
+        let _ = runtime.shutdown_now().wait();
+        result.map_err(|e| {
+            if e.is_elapsed() {
+                format!("timed out after {:?}", timeout)
+            } else {
+                format!("{:?}", e)
+            }
         })
-        .map_err(|e| eprintln!("RPC Client error: {:?}", e));
-
-    rt::run(run);
+    });
+    match worker.join() {
+        Ok(Ok(v)) => Ok(v),
+        Ok(Err(e)) => Err(PythiaError::RpcError(format!("{}: {}", client_uri, e))),
+        Err(_) => Err(PythiaError::RpcError(format!(
+            "{}: got nothing from request",
+            client_uri
+        ))),
+    }
+}
 
+/// Makes the call, retrying with exponential backoff according to the policy
+fn call_with_retries<T, F, R>(
+    client_uri: &str,
+    policy: &RetryPolicy,
+    call: F,
+) -> Result<T, PythiaError>
+where
+    T: Send + 'static,
+    F: FnOnce(&PythiaClient) -> R + Clone + Send + 'static,
+    R: Future<Item = T, Error = RpcError> + Send + 'static,
+{
+    let mut backoff = policy.backoff;
+    let mut attempt = 0;
     loop {
-        match rx.poll() {
-            Ok(Async::Ready(Some(v))) => {
-                return v;
+        match call_once(client_uri, policy.timeout, call.clone()) {
+            Ok(v) => return Ok(v),
+            Err(e) => {
+                if !e.is_transient() {
+                    return Err(e);
+                }
+                if attempt >= policy.retries {
+                    error!("Giving up on {} after {} attempts", client_uri, attempt + 1);
+                    return Err(e);
+                }
+                warn!("RPC Client error: {}, retrying in {:?}", e, backoff);
+                thread::sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
             }
-            Ok(Async::NotReady) => {}
-            Ok(Async::Ready(None)) => panic!("Got nothing from request"),
-            Err(e) => panic!("Got error from poll: {:?}", e),
         }
     }
 }
 
-/// Get events matching the trace_id. OSProfiler-specific
-pub fn get_events_from_client(client_uri: &str, trace_id: Uuid) -> Vec<OSProfilerSpan> {
-    let (tx, mut rx) = futures::sync::mpsc::unbounded();
-
-    let run = http::connect(client_uri)
-        .and_then(move |client: PythiaClient| {
-            client
-                .get_events(trace_id.to_hyphenated().to_string())
-                .and_then(move |result| {
-                    drop(client);
-                    let _ = tx.unbounded_send(result);
-                    Ok(())
-                })
-        })
-        .map_err(|e| eprintln!("RPC Client error: {:?}", e));
-
-    rt::run(run);
-    let mut final_result = Vec::new();
+/// What the agents answered to a call made to all of them at once
+pub struct AgentResults<T> {
+    /// In the order of the agents, without the ones that timed out
+    pub results: Vec<(String, Result<T, PythiaError>)>,
+    /// Agents that didn't answer in time
+    pub timed_out: Vec<String>,
+}
 
-    loop {
-        match rx.poll() {
-            Ok(Async::Ready(Some(v))) => {
-                let traces = match v {
-                    Value::Array(o) => o,
-                    _ => panic!("Got something weird from request {:?}", v),
-                };
-                final_result.extend(
-                    traces
-                        .iter()
-                        .map(|x| x.to_string())
-                        .map(|x: String| serde_json::from_str(&x).unwrap())
-                        .collect::<Vec<OSProfilerSpan>>(),
-                );
-            }
-            Ok(Async::NotReady) => {}
-            Ok(Async::Ready(None)) => {
-                break;
-            }
-            Err(e) => panic!("Got error from poll: {:?}", e),
+/// Makes the call to all agents concurrently, waiting at most `timeout` for them, so one slow
+/// agent doesn't hold up the others. Calls that time out are left running and their results are
+/// dropped.
+pub fn call_all_clients<T, F>(clients: &[String], timeout: Duration, call: F) -> AgentResults<T>
+where
+    T: Send + 'static,
+    F: Fn(&str) -> Result<T, PythiaError> + Clone + Send + 'static,
+{
+    let deadline = Instant::now() + timeout;
+    let (tx, rx) = channel();
+    for (i, client) in clients.iter().enumerate() {
+        let (tx, call, client) = (tx.clone(), call.clone(), client.clone());
+        thread::spawn(move || {
+            let _ = tx.send((i, call(&client)));
+        });
+    }
+    drop(tx);
+    let mut answers: Vec<Option<Result<T, PythiaError>>> = clients.iter().map(|_| None).collect();
+    for _ in clients {
+        match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok((i, answer)) => answers[i] = Some(answer),
+            Err(_) => break,
+        }
+    }
+    let mut result = AgentResults {
+        results: Vec::new(),
+        timed_out: Vec::new(),
+    };
+    for (client, answer) in clients.iter().zip(answers) {
+        match answer {
+            Some(answer) => result.results.push((client.clone(), answer)),
+            None => result.timed_out.push(client.clone()),
         }
     }
-    final_result
+    result
 }
 
-/// Used by controller
-pub fn set_all_client_tracepoints(client_uri: &str, to_write: [u8; 1]) {
-    let (tx, mut rx) = futures::sync::mpsc::unbounded();
-
-    let run = http::connect(client_uri)
-        .and_then(move |client: PythiaClient| {
-            client
-                .set_all_tracepoints(to_write.clone())
-                .and_then(move |x| {
-                    drop(client);
-                    let _ = tx.unbounded_send(x);
-                    Ok(())
-                })
+/// Read the overhead stats from the agent
+pub fn read_client_stats(client_uri: &str, policy: &RetryPolicy) -> Result<NodeStats, PythiaError> {
+    call_with_retries(client_uri, policy, |client| client.read_node_stats())
+}
+
+/// Check that the agent is up
+pub fn ping_client(client_uri: &str, policy: &RetryPolicy) -> Result<(), PythiaError> {
+    call_with_retries(client_uri, policy, |client| client.ping()).map(|_| ())
+}
+
+/// Whether the agent can reach redis, write the tracepoint configuration, etc.
+pub fn read_client_health(
+    client_uri: &str,
+    policy: &RetryPolicy,
+) -> Result<AgentHealth, PythiaError> {
+    call_with_retries(client_uri, policy, |client| client.health())
+}
+
+pub fn read_client_version(client_uri: &str, policy: &RetryPolicy) -> Result<String, PythiaError> {
+    call_with_retries(client_uri, policy, |client| client.version())
+}
+
+/// Get events matching the trace_id. OSProfiler-specific
+pub fn get_events_from_client(
+    client_uri: &str,
+    trace_id: Uuid,
+    policy: &RetryPolicy,
+) -> Result<Vec<OSProfilerSpan>, PythiaError> {
+    let id = trace_id.to_hyphenated().to_string();
+    let v = call_with_retries(client_uri, policy, move |client| client.get_events(id))?;
+    parse_events(client_uri, trace_id, v)
+}
+
+/// Like `get_events_from_client`, but only the events after the first `skip`. Also returns how
+/// many events the agent sent, including those that could not be parsed.
+pub fn get_events_after_from_client(
+    client_uri: &str,
+    trace_id: Uuid,
+    skip: usize,
+    policy: &RetryPolicy,
+) -> Result<(Vec<OSProfilerSpan>, usize), PythiaError> {
+    let id = trace_id.to_hyphenated().to_string();
+    let v = call_with_retries(client_uri, policy, move |client| {
+        client.get_events_after(id, skip)
+    })?;
+    let sent = v.as_array().map_or(0, |a| a.len());
+    Ok((parse_events(client_uri, trace_id, v)?, sent))
+}
+
+/// Get the agent's spans of the trace, already parsed and sorted. OSProfiler-specific
+pub fn get_trace_fragment_from_client(
+    client_uri: &str,
+    trace_id: Uuid,
+    policy: &RetryPolicy,
+) -> Result<SpanBatch, PythiaError> {
+    let id = trace_id.to_hyphenated().to_string();
+    call_with_retries(client_uri, policy, move |client| {
+        client.get_trace_fragment(id)
+    })
+}
+
+/// How many spans of the trace the agent has, and whether the root span ended there. None if the
+/// agent is too old to count spans.
+pub fn get_span_count_from_client(
+    client_uri: &str,
+    trace_id: Uuid,
+    policy: &RetryPolicy,
+) -> Result<Option<SpanCount>, PythiaError> {
+    let id = trace_id.to_hyphenated().to_string();
+    call_with_retries(client_uri, policy, move |client| {
+        client.get_span_count(id).then(|result| match result {
+            Ok(count) => Ok(Some(count)),
+            Err(RpcError::JsonRpcError(e)) if e.code == ErrorCode::MethodNotFound => Ok(None),
+            Err(e) => Err(e),
         })
-        .map_err(|e| eprintln!("RPC Client error: {:?}", e));
+    })
+}
 
-    rt::run(run);
-    loop {
-        match rx.poll() {
-            Ok(Async::Ready(Some(()))) => {
-                return;
-            }
-            Ok(Async::NotReady) => {}
-            Ok(Async::Ready(None)) => {
-                break;
-            }
-            Err(e) => panic!("Got error from poll: {:?}", e),
+fn parse_events(
+    client_uri: &str,
+    trace_id: Uuid,
+    v: Value,
+) -> Result<Vec<OSProfilerSpan>, PythiaError> {
+    let traces = match v {
+        Value::Array(o) => o,
+        _ => {
+            return Err(PythiaError::RpcError(format!(
+                "Got something weird from request {:?}",
+                v
+            )))
+        }
+    };
+    let mut final_result = Vec::new();
+    for x in traces {
+        match serde_json::from_value::<OSProfilerSpan>(x) {
+            Ok(span) => final_result.push(span),
+            Err(e) => warn!(trace_id:% = trace_id; "Skipping span from {}: {}", client_uri, e),
         }
     }
+    Ok(final_result)
+}
+
+/// Used by controller
+pub fn set_all_client_tracepoints(
+    client_uri: &str,
+    to_write: [u8; 1],
+    policy: &RetryPolicy,
+) -> Result<(), PythiaError> {
+    call_with_retries(client_uri, policy, move |client| {
+        client.set_all_tracepoints(to_write)
+    })
 }
 
 /// Used by controller
 pub fn set_client_tracepoints(
     client_uri: &str,
     settings: Vec<(TracepointID, Option<RequestType>, [u8; 1])>,
-) {
-    let (tx, mut rx) = futures::sync::mpsc::unbounded();
+    policy: &RetryPolicy,
+) -> Result<(), PythiaError> {
+    call_with_retries(client_uri, policy, move |client| {
+        client.set_tracepoints(settings)
+    })
+}
 
-    let run = http::connect(client_uri)
-        .and_then(move |client: PythiaClient| {
-            client.set_tracepoints(settings).and_then(move |x| {
-                drop(client);
-                tx.unbounded_send(x).unwrap();
-                Ok(())
-            })
-        })
-        .map_err(|e| eprintln!("RPC Client error: {:?}", e));
+/// Settings of the tracepoints on the agent, `None` for the ones it doesn't have
+pub fn read_client_tracepoints(
+    client_uri: &str,
+    points: Vec<(TracepointID, Option<RequestType>)>,
+    policy: &RetryPolicy,
+) -> Result<Vec<Option<bool>>, PythiaError> {
+    call_with_retries(client_uri, policy, move |client| {
+        client.read_tracepoints(points)
+    })
+}
 
-    rt::run(run);
-    loop {
-        match rx.poll() {
-            Ok(Async::Ready(Some(()))) => {
-                return;
-            }
-            Ok(Async::NotReady) => {}
-            Ok(Async::Ready(None)) => {
-                break;
-            }
-            Err(e) => panic!("Got error from poll: {:?}", e),
-        }
-    }
+/// Every tracepoint setting on the agent, see `get_tracepoint_state` of the agents
+pub fn read_client_tracepoint_state(
+    client_uri: &str,
+    policy: &RetryPolicy,
+) -> Result<Vec<(String, Option<RequestType>, bool)>, PythiaError> {
+    call_with_retries(client_uri, policy, |client| client.get_tracepoint_state())
+}
+
+/// Every tracepoint setting of the agent along with its host, see `dump_state` of the agents
+pub fn dump_client_state(
+    client_uri: &str,
+    policy: &RetryPolicy,
+) -> Result<AgentState, PythiaError> {
+    call_with_retries(client_uri, policy, |client| client.dump_state())
+}
+
+/// Make the tracepoint settings of the agent exactly `state`
+pub fn load_client_state(
+    client_uri: &str,
+    state: AgentState,
+    policy: &RetryPolicy,
+) -> Result<(), PythiaError> {
+    call_with_retries(client_uri, policy, move |client| client.load_state(state))
 }
 
 /// Free the used traces from redis so that we don't use too much memory
-pub fn free_keys(client_uri: &str, keys: Vec<String>) {
+pub fn free_keys(
+    client_uri: &str,
+    keys: Vec<String>,
+    policy: &RetryPolicy,
+) -> Result<(), PythiaError> {
     if keys.len() == 0 {
-        return;
+        return Ok(());
     }
-    let (tx, mut rx) = futures::sync::mpsc::unbounded();
+    call_with_retries(client_uri, policy, move |client| client.free_keys(keys))
+}
 
-    let run = http::connect(client_uri)
-        .and_then(move |client: PythiaClient| {
-            client.free_keys(keys).and_then(move |x| {
-                drop(client);
-                tx.unbounded_send(x).unwrap();
-                Ok(())
-            })
-        })
-        .map_err(|e| eprintln!("RPC Client error: {:?}", e));
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    rt::run(run);
-    loop {
-        match rx.poll() {
-            Ok(Async::Ready(Some(()))) => {
-                return;
-            }
-            Ok(Async::NotReady) => {}
-            Ok(Async::Ready(None)) => {
-                break;
-            }
-            Err(e) => panic!("Got error from poll: {:?}", e),
+    use std::net::TcpListener;
+
+    fn policy(retries: usize, backoff_ms: u64, timeout_ms: u64) -> RetryPolicy {
+        RetryPolicy {
+            retries,
+            backoff: Duration::from_millis(backoff_ms),
+            timeout: Duration::from_millis(timeout_ms),
+        }
+    }
+
+    #[test]
+    fn failed_calls_back_off() {
+        // Nothing listens on the port once the listener is dropped
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let agent = format!("http://127.0.0.1:{}", port);
+        let start = Instant::now();
+        assert!(ping_client(&agent, &policy(2, 50, 1000)).is_err());
+        assert!(start.elapsed() >= Duration::from_millis(150));
+    }
+
+    #[test]
+    fn hung_calls_time_out() {
+        // Connections are accepted by the kernel, but never answered
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let agent = format!("http://{}", listener.local_addr().unwrap());
+        let start = Instant::now();
+        match ping_client(&agent, &policy(0, 0, 200)) {
+            Err(PythiaError::RpcError(e)) => assert!(e.contains("timed out"), "{}", e),
+            other => panic!("Expected a timeout, got {:?}", other),
         }
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn slow_agents_time_out() {
+        let clients: Vec<String> = ["fast", "slow", "broken"]
+            .iter()
+            .map(|c| c.to_string())
+            .collect();
+        let answers = call_all_clients(&clients, Duration::from_millis(200), |client| {
+            match client {
+                "slow" => thread::sleep(Duration::from_secs(2)),
+                "broken" => return Err(PythiaError::RpcError("refused".to_string())),
+                _ => {}
+            }
+            Ok(client.len())
+        });
+        assert_eq!(answers.timed_out, vec!["slow".to_string()]);
+        assert_eq!(
+            answers.results,
+            vec![
+                ("fast".to_string(), Ok(4)),
+                (
+                    "broken".to_string(),
+                    Err(PythiaError::RpcError("refused".to_string()))
+                )
+            ]
+        );
     }
 }
@@ -0,0 +1,136 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Picks which of the candidate tracepoints to enable when there are more than the budget.
+//!
+//! By default a random subset is chosen, so an unlucky subset may be picked again and again. In
+//! coverage mode, the candidates already tried for a (group, edge) pair are remembered, and
+//! untried candidates are picked first. When every candidate was tried, the rotation starts over.
+//! The tried candidates are saved to `coverage_state_file` after each pick, so the rotation
+//! survives controller restarts.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use log::error;
+use rand::seq::SliceRandom;
+
+use crate::settings::Settings;
+use crate::trace::TracepointID;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TieBreaking {
+    Random,
+    Coverage,
+}
+
+pub struct CandidatePicker {
+    mode: TieBreaking,
+    state_file: PathBuf,
+    /// Candidates tried in the current rotation, per (group, edge) key
+    tried: Mutex<HashMap<String, HashSet<TracepointID>>>,
+}
+
+impl CandidatePicker {
+    pub fn from_settings(settings: &Settings) -> Self {
+        let tried = match settings.tie_breaking {
+            TieBreaking::Coverage => File::open(&settings.coverage_state_file)
+                .ok()
+                .and_then(|f| serde_json::from_reader(f).ok())
+                .unwrap_or_default(),
+            TieBreaking::Random => HashMap::new(),
+        };
+        CandidatePicker {
+            mode: settings.tie_breaking,
+            state_file: settings.coverage_state_file.clone(),
+            tried: Mutex::new(tried),
+        }
+    }
+
+    /// Choose at most `budget` of the candidates. `key` identifies the group and the edge the
+    /// candidates were found for.
+    pub fn pick(
+        &self,
+        key: &str,
+        mut candidates: Vec<TracepointID>,
+        budget: usize,
+    ) -> Vec<TracepointID> {
+        if self.mode == TieBreaking::Random {
+            let mut rng = rand::thread_rng();
+            return candidates
+                .choose_multiple(&mut rng, budget)
+                .cloned()
+                .collect();
+        }
+        // Same order every time, so rotations are reproducible
+        candidates.sort_by_key(|c| c.to_string());
+        candidates.dedup();
+        let mut tried = self.tried.lock().unwrap();
+        let result = pick_untried(tried.entry(key.to_string()).or_default(), &candidates, budget);
+        if let Err(e) = File::create(&self.state_file)
+            .map_err(|e| e.to_string())
+            .and_then(|f| serde_json::to_writer(f, &*tried).map_err(|e| e.to_string()))
+        {
+            error!("Could not save search coverage to {:?}: {}", self.state_file, e);
+        }
+        result
+    }
+}
+
+/// Takes untried candidates first; if they run out, starts a new rotation
+fn pick_untried(
+    tried: &mut HashSet<TracepointID>,
+    candidates: &Vec<TracepointID>,
+    budget: usize,
+) -> Vec<TracepointID> {
+    let mut result: Vec<TracepointID> = candidates
+        .iter()
+        .filter(|c| !tried.contains(c))
+        .take(budget)
+        .cloned()
+        .collect();
+    if result.len() < budget {
+        tried.clear();
+        let more = candidates
+            .iter()
+            .filter(|c| !result.contains(c))
+            .take(budget - result.len())
+            .cloned()
+            .collect::<Vec<_>>();
+        result.extend(more);
+    }
+    tried.extend(result.iter().cloned());
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotates_through_candidates() {
+        let candidates = ["a", "b", "c"]
+            .iter()
+            .map(|s| TracepointID::from_str(s))
+            .collect::<Vec<_>>();
+        let mut tried = HashSet::new();
+        assert_eq!(pick_untried(&mut tried, &candidates, 2), candidates[..2].to_vec());
+        // Only c is left, so the rotation restarts with a
+        assert_eq!(
+            pick_untried(&mut tried, &candidates, 2),
+            vec![candidates[2], candidates[0]]
+        );
+        assert_eq!(
+            pick_untried(&mut tried, &candidates, 2),
+            vec![candidates[1], candidates[0]]
+        );
+    }
+}
@@ -7,8 +7,11 @@ All rights reserved.
 */
 
 use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
 use std::time::Instant;
 
+use log::debug;
 use petgraph::graph::EdgeIndex;
 
 use crate::controller::Controller;
@@ -16,13 +19,16 @@ use crate::critical::Path;
 use crate::grouping::Group;
 use crate::manifest::HierarchicalCriticalPath;
 use crate::manifest::Manifest;
+use crate::search::budget_spread;
 use crate::search::SearchStrategy;
+use crate::search::SpreadPolicy;
 use crate::settings::Settings;
 use crate::trace::TracepointID;
 
 pub struct FlatSearch {
     controller: &'static Box<dyn Controller>,
-    manifest: &'static Manifest,
+    manifest: Arc<Manifest>,
+    spread: SpreadPolicy,
 }
 
 impl SearchStrategy for FlatSearch {
@@ -37,7 +43,7 @@ impl SearchStrategy for FlatSearch {
                     .iter()
                     .take(remaining_budget),
             );
-            eprintln!("Finding middle took {}", now.elapsed().as_micros(),);
+            debug!("Finding middle took {}", now.elapsed().as_micros(),);
             result = result
                 .into_iter()
                 .filter(|&x| !self.controller.is_enabled(&(x, Some(group.request_type))))
@@ -45,17 +51,38 @@ impl SearchStrategy for FlatSearch {
         }
         result.drain().collect()
     }
+
+    /// Spreads more and more tracepoints over the edge until they would cost too much
+    fn search_by_cost(
+        &self,
+        group: &Group,
+        edge: EdgeIndex,
+        events: f64,
+        cost: &dyn Fn(TracepointID) -> f64,
+    ) -> Vec<TracepointID> {
+        let mut result = Vec::new();
+        for k in 1.. {
+            let picks = self.search(group, edge, k);
+            if picks.len() <= result.len() || picks.iter().map(|&tp| cost(tp)).sum::<f64>() > events
+            {
+                break;
+            }
+            result = picks;
+        }
+        result
+    }
 }
 
 impl FlatSearch {
-    pub fn new(_s: &Settings, m: &'static Manifest, c: &'static Box<dyn Controller>) -> Self {
+    pub fn new(s: &Settings, m: Arc<Manifest>, c: &'static Box<dyn Controller>) -> Self {
         FlatSearch {
             controller: c,
             manifest: m,
+            spread: s.spread_policy.clone(),
         }
     }
 
-    /// Find n tracepoints that equally separate the edge according to the path
+    /// Find n tracepoints that separate the edge according to the path and the spread policy
     fn split_group_by_n(
         &self,
         path: &HierarchicalCriticalPath, // Contains full search space
@@ -63,83 +90,44 @@ impl FlatSearch {
         edge: EdgeIndex,
         n: usize,
     ) -> Vec<TracepointID> {
-        let mut result = Vec::new();
         let (source, target) = group.g.edge_endpoints(edge).unwrap();
-        let mut path_source = path.start_node;
-        let path_target;
-        let mut nodes_between = 0;
+        // Not-enabled tracepoints between the source and the target, and the latency before each
+        let mut candidates = Vec::new();
+        let mut gaps = Vec::new();
+        let mut gap = Duration::default();
         let mut cur_path_idx = path.start_node;
         let mut cur_group_idx = group.start_node;
+        let mut between = false;
         loop {
             if path.g[cur_path_idx] == group.g[cur_group_idx] {
-                if cur_group_idx == source {
-                    path_source = cur_path_idx;
-                    nodes_between = 0;
-                }
                 if cur_group_idx == target {
-                    path_target = cur_path_idx;
-                    nodes_between -= 1;
+                    gaps.push(gap);
                     break;
                 }
-                cur_group_idx = group.next_node(cur_group_idx).unwrap();
-            }
-            cur_path_idx = path.next_node(cur_path_idx).unwrap();
-            nodes_between += 1;
-        }
-        if nodes_between == 0 {
-            println!("The matching nodes are consecutive");
-        }
-        let mut gaps = Vec::new();
-        if nodes_between <= n {
-            for _ in 0..nodes_between {
-                gaps.push(0);
-            }
-        } else {
-            for _ in 0..n {
-                gaps.push(nodes_between / (n + 1));
-            }
-        }
-        cur_path_idx = path.next_node(path_source).unwrap();
-        for i in gaps {
-            for _ in 0..i {
-                cur_path_idx = path.next_node(cur_path_idx).unwrap();
-                if cur_path_idx == path_target {
-                    eprintln!("Reached target prematurely");
-                    break;
+                if cur_group_idx == source {
+                    between = true;
+                    candidates.clear();
+                    gaps.clear();
+                    gap = Duration::default();
                 }
-            }
-            if self
-                .controller
-                .is_enabled(&(path.g[cur_path_idx].tracepoint_id, Some(path.request_type)))
-            {
-                match path.next_node(cur_path_idx) {
-                    Some(nidx) => {
-                        cur_path_idx = nidx;
-                        if cur_path_idx == path_target {
-                            cur_path_idx = path.prev_node(cur_path_idx).unwrap();
-                            cur_path_idx = path.prev_node(cur_path_idx).unwrap();
-                        }
-                    }
-                    None => {
-                        cur_path_idx = path.prev_node(cur_path_idx).unwrap();
-                    }
-                };
-                if cur_path_idx == path_source {
-                    println!("Couldn't find not enabled nodes in between");
-                    continue;
+                cur_group_idx = group.next_node(cur_group_idx).unwrap();
+            } else if between {
+                let tracepoint = path.g[cur_path_idx].tracepoint_id;
+                if !self
+                    .controller
+                    .is_enabled(&(tracepoint, Some(path.request_type)))
+                {
+                    candidates.push(tracepoint);
+                    gaps.push(gap);
+                    gap = Duration::default();
                 }
             }
-            if cur_path_idx == path_target {
-                println!("Already reached target node, breaking");
-                continue;
-            }
-            result.push(path.g[cur_path_idx].tracepoint_id);
-            if cur_path_idx == path_target || cur_path_idx == path_source {
-                eprintln!("Some old assertions failed");
-                break;
-            }
+            gap += path.duration_to_next(cur_path_idx);
             cur_path_idx = path.next_node(cur_path_idx).unwrap();
         }
-        result
+        if candidates.is_empty() {
+            debug!("Couldn't find not enabled nodes in between");
+        }
+        budget_spread(&candidates, n, self.spread.with_latencies(gaps))
     }
 }
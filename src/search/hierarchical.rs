@@ -7,15 +7,18 @@ All rights reserved.
 */
 
 use std::collections::HashSet;
+use std::sync::Arc;
 
+use log::debug;
 use petgraph::graph::{EdgeIndex, NodeIndex};
-use rand::seq::SliceRandom;
 
 use crate::controller::Controller;
 use crate::critical::Path;
 use crate::grouping::Group;
 use crate::manifest::HierarchicalCriticalPath;
 use crate::manifest::Manifest;
+use crate::search::edge_key;
+use crate::search::CandidatePicker;
 use crate::search::SearchStrategy;
 use crate::settings::Settings;
 use crate::trace::EventType;
@@ -23,15 +26,24 @@ use crate::trace::TracepointID;
 
 pub struct HierarchicalSearch {
     controller: &'static Box<dyn Controller>,
-    manifest: &'static Manifest,
+    manifest: Arc<Manifest>,
+    picker: CandidatePicker,
 }
 
 impl SearchStrategy for HierarchicalSearch {
     fn search(&self, group: &Group, edge: EdgeIndex, budget: usize) -> Vec<TracepointID> {
-        let mut rng = &mut rand::thread_rng();
         let (source, target) = group.g.edge_endpoints(edge).unwrap();
-        let source_context = self.get_context(group, source);
-        let target_context = self.get_context(group, target);
+        // Groups of partially instrumented traces lack the enclosing spans, the manifest has them
+        let (source_context, target_context) = match self.manifest.reconstruct_hierarchy(group) {
+            Some(mut contexts) => (
+                contexts.remove(&source).unwrap(),
+                contexts.remove(&target).unwrap(),
+            ),
+            None => (
+                self.get_context(group, source),
+                self.get_context(group, target),
+            ),
+        };
         let mut common_context = Vec::new();
         let mut idx = 0;
         loop {
@@ -44,23 +56,23 @@ impl SearchStrategy for HierarchicalSearch {
                 break;
             }
         }
-        println!("Common context for the search: {:?}", common_context);
+        debug!("Common context for the search: {:?}", common_context);
         let matches = self.manifest.find_matches(group);
         let mut result = self.search_context(&matches, common_context);
         result = result
             .into_iter()
             .filter(|&x| !self.controller.is_enabled(&(x, Some(group.request_type))))
             .collect();
-        result = result.choose_multiple(&mut rng, budget).cloned().collect();
-        result
+        self.picker.pick(&edge_key(group, edge), result, budget)
     }
 }
 
 impl HierarchicalSearch {
-    pub fn new(_s: &Settings, m: &'static Manifest, c: &'static Box<dyn Controller>) -> Self {
+    pub fn new(s: &Settings, m: Arc<Manifest>, c: &'static Box<dyn Controller>) -> Self {
         HierarchicalSearch {
             controller: c,
             manifest: m,
+            picker: CandidatePicker::from_settings(s),
         }
     }
 
@@ -155,6 +167,8 @@ mod tests {
     use crate::settings::Settings;
     use crate::trace::TracepointID;
 
+    use std::sync::Arc;
+
     use pythia_common::RequestType;
 
     lazy_static! {
@@ -167,11 +181,11 @@ mod tests {
     #[test]
     fn it_works() {
         CONTROLLER.disable_all();
-        let search = HierarchicalSearch::new(&SETTINGS, &MANIFEST, &CONTROLLER);
+        let search = HierarchicalSearch::new(&SETTINGS, Arc::new(MANIFEST.clone()), &CONTROLLER);
         let mut manifest = MANIFEST.clone();
         let mut paths: Vec<HierarchicalCriticalPath> = manifest
             .per_request_type
-            .get_mut(&RequestType::ServerCreate)
+            .get_mut(&RequestType::from_str("ServerCreate").unwrap())
             .unwrap()
             .paths
             .values()
@@ -8,15 +8,20 @@ All rights reserved.
 
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::sync::Arc;
 
+use log::warn;
 use petgraph::graph::EdgeIndex;
-use rand::seq::IteratorRandom;
 
 use pythia_common::RequestType;
 
 use crate::controller::Controller;
 use crate::grouping::Group;
 use crate::manifest::Manifest;
+use crate::search::edge_key;
+use crate::search::results::similar_edge_key;
+use crate::search::CandidatePicker;
+use crate::search::ResultsDB;
 use crate::search::SearchStrategy;
 use crate::settings::Settings;
 use crate::trace::TracepointID;
@@ -25,26 +30,44 @@ use crate::trace::TracepointID;
 pub struct HistoricSearch {
     controller: &'static Box<dyn Controller>,
     per_request_types: HashMap<RequestType, HashSet<TracepointID>>,
+    picker: CandidatePicker,
+    /// Outcomes of earlier runs; candidates that helped with similar edges come first
+    results: Option<ResultsDB>,
 }
 
 impl SearchStrategy for HistoricSearch {
-    fn search(&self, group: &Group, _edge: EdgeIndex, budget: usize) -> Vec<TracepointID> {
-        let mut rng = rand::thread_rng();
-        self.per_request_types
+    fn search(&self, group: &Group, edge: EdgeIndex, budget: usize) -> Vec<TracepointID> {
+        let candidates = self
+            .per_request_types
             .get(&group.request_type)
             .unwrap()
             .iter()
             .filter(|&tp| !self.controller.is_enabled(&(*tp, Some(group.request_type))))
             .cloned()
-            .choose_multiple(&mut rng, budget)
+            .collect();
+        let similar = similar_edge_key(group, edge);
+        match self.results {
+            Some(ref results) if results.has_results(&similar) => results
+                .rank(&similar, candidates)
+                .into_iter()
+                .take(budget)
+                .collect(),
+            _ => self.picker.pick(&edge_key(group, edge), candidates, budget),
+        }
     }
 }
 
 impl HistoricSearch {
-    pub fn new(_s: &Settings, m: &'static Manifest, c: &'static Box<dyn Controller>) -> Self {
+    pub fn new(s: &Settings, m: Arc<Manifest>, c: &'static Box<dyn Controller>) -> Self {
         HistoricSearch {
             controller: c,
             per_request_types: m.get_per_request_types(),
+            picker: CandidatePicker::from_settings(s),
+            results: s.results_db.as_ref().and_then(|path| {
+                ResultsDB::open(path)
+                    .map_err(|e| warn!("Not using past results in {:?}: {}", path, e))
+                    .ok()
+            }),
         }
     }
 }
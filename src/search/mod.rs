@@ -10,15 +10,27 @@ All rights reserved.
 //!
 //! The trait should be implemented by the search strategy.
 
+mod coverage;
 mod flat;
 mod hierarchical;
 mod historic;
+mod results;
+mod spread;
+
+use std::sync::Arc;
 
 use petgraph::graph::EdgeIndex;
 
 use crate::controller::Controller;
+use crate::critical::Path;
 use crate::grouping::Group;
 use crate::manifest::Manifest;
+pub use crate::search::coverage::CandidatePicker;
+pub use crate::search::coverage::TieBreaking;
+pub use crate::search::results::ResultsDB;
+pub use crate::search::results::Trial;
+pub use crate::search::spread::budget_spread;
+pub use crate::search::spread::SpreadPolicy;
 use crate::search::flat::FlatSearch;
 use crate::search::hierarchical::HierarchicalSearch;
 use crate::search::historic::HistoricSearch;
@@ -29,9 +41,52 @@ pub trait SearchStrategy {
     /// Simply return a list of tracepoints to enable. The number of trace points should be <= the
     /// budget
     fn search(&self, group: &Group, edge: EdgeIndex, budget: usize) -> Vec<TracepointID>;
+
+    /// Like `search`, but the budget is in events per second, and `cost` estimates the events
+    /// per second each tracepoint would add. By default, the candidates are taken in the
+    /// strategy's order while they fit.
+    fn search_by_cost(
+        &self,
+        group: &Group,
+        edge: EdgeIndex,
+        events: f64,
+        cost: &dyn Fn(TracepointID) -> f64,
+    ) -> Vec<TracepointID> {
+        within_cost(self.search(group, edge, usize::MAX), events, cost)
+    }
+}
+
+/// The candidates, in order, that fit in `events` per second together; ones that are too
+/// expensive are skipped
+pub fn within_cost(
+    candidates: Vec<TracepointID>,
+    events: f64,
+    cost: &dyn Fn(TracepointID) -> f64,
+) -> Vec<TracepointID> {
+    let mut left = events;
+    let mut result = Vec::new();
+    for tp in candidates {
+        let c = cost(tp);
+        if c <= left {
+            left -= c;
+            result.push(tp);
+        }
+    }
+    result
+}
+
+/// Identifies an edge of a group across cycles, for remembering what was tried for it
+pub fn edge_key(group: &Group, edge: EdgeIndex) -> String {
+    let (source, target) = group.g.edge_endpoints(edge).unwrap();
+    format!(
+        "{}:{}->{}",
+        group.hash(),
+        group.g[source].tracepoint_id,
+        group.g[target].tracepoint_id
+    )
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum SearchStrategyType {
     Flat,
     Hierarchical,
@@ -41,7 +96,7 @@ pub enum SearchStrategyType {
 /// Constructor for search strategy
 pub fn get_strategy(
     s: &Settings,
-    m: &'static Manifest,
+    m: Arc<Manifest>,
     c: &'static Box<dyn Controller>,
 ) -> Box<dyn SearchStrategy> {
     match &s.search_strategy {
@@ -0,0 +1,343 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Results of past diagnosis runs, for the Historic strategy.
+//!
+//! Each time tracepoints are enabled for a problem edge, the controller records a `Trial`. At the
+//! next decision it checks how much of the edge's variance the new tracepoints pinned on a single
+//! sub-edge, and stores (edge, tracepoints, variance explained) in a sled database that outlives
+//! the run. Edges are identified by request type and endpoint tracepoints rather than by group, so
+//! similar edges of other groups and other runs share their history.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use log::error;
+use petgraph::graph::EdgeIndex;
+use serde::{Deserialize, Serialize};
+
+use pythia_common::RequestType;
+
+use crate::controller::Controller;
+use crate::grouping::Group;
+use crate::trace::TracepointID;
+
+/// A trial succeeded if the tracepoints put at least this fraction of the variance on one
+/// sub-edge
+const SUCCESS_THRESHOLD: f64 = 0.5;
+
+lazy_static! {
+    // sled locks its files, so every strategy and the controller share one handle per database
+    static ref OPEN_DATABASES: Mutex<HashMap<PathBuf, sled::Db>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Outcome {
+    tracepoints: Vec<TracepointID>,
+    variance_explained: f64,
+}
+
+/// Tracepoints enabled for a problem edge, waiting for traces to show whether they helped
+#[derive(Debug, Clone)]
+pub struct Trial {
+    request_type: RequestType,
+    source: TracepointID,
+    target: TracepointID,
+    pub tracepoints: Vec<TracepointID>,
+}
+
+impl Trial {
+    pub fn new(group: &Group, edge: EdgeIndex, tracepoints: Vec<TracepointID>) -> Self {
+        let (source, target) = group.g.edge_endpoints(edge).unwrap();
+        Trial {
+            request_type: group.request_type,
+            source: group.g[source].tracepoint_id,
+            target: group.g[target].tracepoint_id,
+            tracepoints,
+        }
+    }
+
+    /// The ends of the edge and the tracepoints enabled for it
+    pub fn referenced_tracepoints(&self) -> Vec<TracepointID> {
+        let mut result = vec![self.source, self.target];
+        result.extend(self.tracepoints.iter());
+        result
+    }
+
+    /// Key of the edge, shared with similar edges; see `similar_edge_key`
+    pub fn edge(&self) -> String {
+        format!("{:?}:{}->{}", self.request_type, self.source, self.target)
+    }
+
+    /// In the groups that have the edge, the largest variance of a sub-edge over the variance of
+    /// all sub-edges between the endpoints, averaged over groups weighted by traces. Groups where
+    /// none of the tracepoints showed up count as 0. None if no group has the edge yet.
+    pub fn variance_explained(&self, groups: &[&Group]) -> Option<f64> {
+        let mut explained = 0.0;
+        let mut traces = 0;
+        for group in groups
+            .iter()
+            .filter(|g| g.request_type == self.request_type)
+        {
+            let (start, end) = match self.endpoints(group) {
+                Some(span) => span,
+                None => continue,
+            };
+            traces += group.trace_count();
+            if let Some((_, share)) = self.largest_sub_edge(group, start, end) {
+                explained += group.trace_count() as f64 * share;
+            }
+        }
+        if traces == 0 {
+            None
+        } else {
+            Some(explained / traces as f64)
+        }
+    }
+
+    /// Whether traces with the new tracepoints between the endpoints arrived, so the trial can be
+    /// judged
+    pub fn resolved(&self, groups: &[&Group]) -> bool {
+        groups.iter().any(|&g| self.localize(g).is_some())
+    }
+
+    /// Whether any of the tracepoints is still enabled, so the trial can still resolve
+    pub fn is_active(&self, controller: &dyn Controller) -> bool {
+        self.tracepoints
+            .iter()
+            .any(|&tp| controller.is_enabled(&(tp, Some(self.request_type))))
+    }
+
+    /// The sub-edge between the endpoints with the largest share of their variance in the
+    /// group, and that share. None if the group doesn't have the edge or none of the tracepoints
+    /// showed up in it.
+    pub fn localize(&self, group: &Group) -> Option<(EdgeIndex, f64)> {
+        if group.request_type != self.request_type {
+            return None;
+        }
+        let (start, end) = self.endpoints(group)?;
+        let (i, share) = self.largest_sub_edge(group, start, end)?;
+        Some((group.edges_in_order()[i], share))
+    }
+
+    /// Positions of the endpoints on the group's path
+    fn endpoints(&self, group: &Group) -> Option<(usize, usize)> {
+        let sequence = group.sequence();
+        let start = sequence.iter().position(|&tp| tp == self.source)?;
+        let after = &sequence[start + 1..];
+        let end = start + 1 + after.iter().position(|&tp| tp == self.target)?;
+        Some((start, end))
+    }
+
+    /// Position on the group's path and variance share of the largest sub-edge between `start`
+    /// and `end`
+    fn largest_sub_edge(&self, group: &Group, start: usize, end: usize) -> Option<(usize, f64)> {
+        if !group.sequence()[start + 1..end]
+            .iter()
+            .any(|tp| self.tracepoints.contains(tp))
+        {
+            return None;
+        }
+        let variances: Vec<f64> = group.latency_breakdown()[start..end]
+            .iter()
+            .map(|e| e.variance.0)
+            .collect();
+        let total: f64 = variances.iter().sum();
+        if total <= 0.0 {
+            return None;
+        }
+        let mut largest = 0;
+        for (i, &v) in variances.iter().enumerate() {
+            if v > variances[largest] {
+                largest = i;
+            }
+        }
+        Some((start + largest, variances[largest] / total))
+    }
+}
+
+/// Identifies edges with the same endpoints in groups of the same request type
+pub fn similar_edge_key(group: &Group, edge: EdgeIndex) -> String {
+    Trial::new(group, edge, Vec::new()).edge()
+}
+
+#[derive(Clone)]
+pub struct ResultsDB {
+    db: sled::Db,
+}
+
+impl ResultsDB {
+    pub fn open(path: &Path) -> sled::Result<Self> {
+        let mut open = OPEN_DATABASES.lock().unwrap();
+        let db = match open.get(path) {
+            Some(db) => db.clone(),
+            None => {
+                let db = sled::open(path)?;
+                open.insert(path.to_path_buf(), db.clone());
+                db
+            }
+        };
+        Ok(ResultsDB { db })
+    }
+
+    pub fn record(&self, trial: &Trial, variance_explained: f64) {
+        let outcome = Outcome {
+            tracepoints: trial.tracepoints.clone(),
+            variance_explained,
+        };
+        if let Err(e) = self
+            .db
+            .generate_id()
+            .map(|id| format!("{}/{:020}", trial.edge(), id))
+            .and_then(|key| self.db.insert(key, serde_json::to_vec(&outcome).unwrap()))
+            .and_then(|_| self.db.flush())
+        {
+            error!("Could not record the result for {}: {}", trial.edge(), e);
+        }
+    }
+
+    fn outcomes(&self, edge: &str) -> Vec<Outcome> {
+        self.db
+            .scan_prefix(format!("{}/", edge))
+            .values()
+            .filter_map(|v| v.ok())
+            .filter_map(|v| serde_json::from_slice(&v).ok())
+            .collect()
+    }
+
+    pub fn has_results(&self, edge: &str) -> bool {
+        self.db.scan_prefix(format!("{}/", edge)).next().is_some()
+    }
+
+    /// Times each tracepoint was enabled for the edge, and how many of those succeeded
+    pub fn success_counts(&self, edge: &str) -> HashMap<TracepointID, (usize, usize)> {
+        let mut result = HashMap::new();
+        for outcome in self.outcomes(edge) {
+            for tp in outcome.tracepoints {
+                let counts = result.entry(tp).or_insert((0, 0));
+                counts.0 += 1;
+                if outcome.variance_explained >= SUCCESS_THRESHOLD {
+                    counts.1 += 1;
+                }
+            }
+        }
+        result
+    }
+
+    /// Sorts the candidates by past success rate for the edge. Rates are smoothed so untried
+    /// tracepoints come after the ones that worked, and before the ones that didn't.
+    pub fn rank(&self, edge: &str, mut candidates: Vec<TracepointID>) -> Vec<TracepointID> {
+        let counts = self.success_counts(edge);
+        let rate = |tp: &TracepointID| {
+            let (tries, successes) = counts.get(tp).cloned().unwrap_or((0, 0));
+            (successes + 1) as f64 / (tries + 2) as f64
+        };
+        candidates.sort_by(|a, b| rate(b).partial_cmp(&rate(a)).unwrap());
+        candidates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testutils::TraceGenerator;
+
+    #[test]
+    fn ranks_by_success_rate() {
+        let dir = std::env::temp_dir().join(format!("pythia_results_{}", std::process::id()));
+        let db = ResultsDB::open(&dir).unwrap();
+        let tp = |s| TracepointID::from_str(s);
+        let trial = |tracepoints| Trial {
+            request_type: RequestType::Unknown,
+            source: tp("start"),
+            target: tp("end"),
+            tracepoints,
+        };
+        db.record(&trial(vec![tp("good")]), 0.9);
+        db.record(&trial(vec![tp("good"), tp("bad")]), 0.8);
+        db.record(&trial(vec![tp("bad")]), 0.1);
+        db.record(&trial(vec![tp("bad")]), 0.2);
+
+        let edge = trial(Vec::new()).edge();
+        assert_eq!(db.success_counts(&edge)[&tp("bad")], (3, 1));
+        assert_eq!(
+            db.rank(&edge, vec![tp("bad"), tp("untried"), tp("good")]),
+            vec![tp("good"), tp("untried"), tp("bad")]
+        );
+        drop(db);
+        OPEN_DATABASES.lock().unwrap().remove(&dir);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn localizes_on_the_largest_sub_edge() {
+        let mut generator = TraceGenerator::new(1);
+        generator.concurrency = 0.0;
+        let group = &Group::from_critical_paths(generator.critical_paths_of_shape(20))[0];
+        let sequence = group.sequence();
+        assert!(sequence.len() > 2);
+        let trial = Trial {
+            request_type: group.request_type,
+            source: sequence[0],
+            target: *sequence.last().unwrap(),
+            tracepoints: sequence[1..sequence.len() - 1].to_vec(),
+        };
+        let (edge, share) = trial.localize(group).unwrap();
+        let breakdown = group.latency_breakdown();
+        let edges = group.edges_in_order();
+        let i = edges.iter().position(|&e| e == edge).unwrap();
+        assert!(breakdown
+            .iter()
+            .all(|b| b.variance_share <= breakdown[i].variance_share));
+        assert!(share > 0.0 && share <= 1.0);
+        assert_eq!(trial.variance_explained(&[group]), Some(share));
+        assert!(trial.resolved(&[group]));
+
+        // Nothing to localize until one of the tracepoints shows up
+        let untried = Trial {
+            tracepoints: Vec::new(),
+            ..trial
+        };
+        assert!(untried.localize(group).is_none());
+        assert!(!untried.resolved(&[group]));
+    }
+
+    #[test]
+    fn variance_explained_counts_groups_with_the_edge() {
+        let mut generator = TraceGenerator::new(1);
+        generator.concurrency = 0.0;
+        let group = &Group::from_critical_paths(generator.critical_paths_of_shape(20))[0];
+        let sequence = group.sequence();
+        let trial = Trial {
+            request_type: group.request_type,
+            source: sequence[0],
+            target: *sequence.last().unwrap(),
+            tracepoints: sequence[1..sequence.len() - 1].to_vec(),
+        };
+        let (_, share) = trial.localize(group).unwrap();
+        assert_eq!(trial.variance_explained(&[]), None);
+        assert_eq!(trial.variance_explained(&[group, group]), Some(share));
+
+        // The edge is there, but the tracepoints didn't show up
+        let missing = Trial {
+            tracepoints: vec![TracepointID::from_str("missing")],
+            ..trial.clone()
+        };
+        assert_eq!(missing.variance_explained(&[group]), Some(0.0));
+
+        // The group doesn't have the edge
+        let elsewhere = Trial {
+            target: TracepointID::from_str("elsewhere"),
+            ..trial
+        };
+        assert_eq!(elsewhere.variance_explained(&[group]), None);
+    }
+}
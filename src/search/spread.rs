@@ -0,0 +1,191 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Spreading a budget over the tracepoints of an edge, as in VProfiler.
+//!
+//! The candidates are the tracepoints between the source and the target of a problem edge, in
+//! path order. Enabling `k` of them splits the edge into `k + 1` parts; the policy decides where
+//! the splits go.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate::trace::TracepointID;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SpreadPolicy {
+    /// The same number of candidates between consecutive picks
+    Even,
+    /// The middle candidate first, then the middles of the two halves, and so on. Useful when
+    /// the budget may run out before all picks are used, since the first picks split the most.
+    BinarySplit,
+    /// The same historic latency between consecutive picks. There is one latency per gap: from
+    /// the source to the first candidate, between candidates, and from the last to the target.
+    /// Without latencies (e.g., an old manifest), this is the same as `Even`.
+    WeightedByLatency(Vec<Duration>),
+}
+
+impl SpreadPolicy {
+    /// The same policy, with the latencies of the gaps if it needs them
+    pub fn with_latencies(&self, gaps: Vec<Duration>) -> SpreadPolicy {
+        match self {
+            SpreadPolicy::WeightedByLatency(_) => SpreadPolicy::WeightedByLatency(gaps),
+            other => other.clone(),
+        }
+    }
+}
+
+/// Picks at most `k` of the candidates, which are in path order
+pub fn budget_spread(
+    candidates: &[TracepointID],
+    k: usize,
+    policy: SpreadPolicy,
+) -> Vec<TracepointID> {
+    let n = candidates.len();
+    if k >= n {
+        return candidates.to_vec();
+    }
+    let indices = match policy {
+        SpreadPolicy::Even => even(n, k),
+        SpreadPolicy::BinarySplit => binary_split(n, k),
+        SpreadPolicy::WeightedByLatency(gaps) => {
+            if gaps.len() == n + 1 && gaps.iter().any(|g| *g > Duration::new(0, 0)) {
+                weighted(&gaps, k)
+            } else {
+                even(n, k)
+            }
+        }
+    };
+    indices.into_iter().map(|i| candidates[i]).collect()
+}
+
+/// As if the source and the target were candidates too, and all were equally far apart
+fn even(n: usize, k: usize) -> Vec<usize> {
+    (1..=k).map(|i| i * (n + 1) / (k + 1) - 1).collect()
+}
+
+fn binary_split(n: usize, k: usize) -> Vec<usize> {
+    let mut result = Vec::new();
+    let mut ranges = VecDeque::new();
+    ranges.push_back((0, n));
+    while result.len() < k {
+        let (start, end) = match ranges.pop_front() {
+            Some(range) => range,
+            None => break,
+        };
+        if start == end {
+            continue;
+        }
+        let middle = (start + end) / 2;
+        result.push(middle);
+        ranges.push_back((start, middle));
+        ranges.push_back((middle + 1, end));
+    }
+    result
+}
+
+/// Each pick is the unpicked candidate closest to an equal share of the total latency
+fn weighted(gaps: &[Duration], k: usize) -> Vec<usize> {
+    let mut offsets = Vec::new();
+    let mut offset = 0.0;
+    for gap in &gaps[..gaps.len() - 1] {
+        offset += gap.as_secs_f64();
+        offsets.push(offset);
+    }
+    let total = offset + gaps.last().unwrap().as_secs_f64();
+    let mut result: Vec<usize> = Vec::new();
+    for i in 1..=k {
+        let wanted = total * i as f64 / (k + 1) as f64;
+        let closest = (0..offsets.len())
+            .filter(|c| !result.contains(c))
+            .min_by(|&a, &b| {
+                (offsets[a] - wanted)
+                    .abs()
+                    .partial_cmp(&(offsets[b] - wanted).abs())
+                    .unwrap()
+            })
+            .unwrap();
+        result.push(closest);
+    }
+    result.sort();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use proptest::prelude::*;
+
+    use crate::testutils::TraceGenerator;
+
+    fn tracepoints(n: usize) -> Vec<TracepointID> {
+        (0..n)
+            .map(|i| TracepointID::from_str(&format!("spread_{}", i)))
+            .collect()
+    }
+
+    fn positions(picked: Vec<TracepointID>, candidates: &[TracepointID]) -> Vec<usize> {
+        picked
+            .iter()
+            .map(|p| candidates.iter().position(|c| c == p).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn spreads_by_policy() {
+        let candidates = tracepoints(7);
+        let spread = |k, policy| positions(budget_spread(&candidates, k, policy), &candidates);
+        assert_eq!(spread(1, SpreadPolicy::Even), vec![3]);
+        assert_eq!(spread(3, SpreadPolicy::Even), vec![1, 3, 5]);
+        assert_eq!(spread(10, SpreadPolicy::Even).len(), 7);
+        assert_eq!(spread(3, SpreadPolicy::BinarySplit), vec![3, 1, 5]);
+        assert_eq!(spread(4, SpreadPolicy::BinarySplit), vec![3, 1, 5, 0]);
+
+        // Almost all of the latency is between the last two candidates
+        let mut gaps = vec![Duration::from_millis(1); 8];
+        gaps[6] = Duration::from_secs(1);
+        assert_eq!(
+            spread(2, SpreadPolicy::WeightedByLatency(gaps)),
+            vec![5, 6]
+        );
+        assert_eq!(
+            spread(1, SpreadPolicy::WeightedByLatency(Vec::new())),
+            vec![3]
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn picks_within_budget(seed in any::<u64>(), k in 0usize..10, latency in any::<bool>()) {
+            // Candidates are the inner tracepoints of a generated critical path, as in FlatSearch
+            let generated = TraceGenerator::new(seed).generate();
+            let sequence = generated.sequence();
+            let mut candidates = Vec::new();
+            for &tp in &sequence[1..sequence.len() - 1] {
+                if !candidates.contains(&tp) {
+                    candidates.push(tp);
+                }
+            }
+            let policy = if latency {
+                let gaps = (0..=candidates.len()).map(|i| Duration::from_millis(i as u64));
+                SpreadPolicy::WeightedByLatency(gaps.collect())
+            } else {
+                SpreadPolicy::BinarySplit
+            };
+            for policy in vec![SpreadPolicy::Even, policy] {
+                let picked = positions(budget_spread(&candidates, k, policy), &candidates);
+                prop_assert_eq!(picked.len(), k.min(candidates.len()));
+                let mut distinct = picked.clone();
+                distinct.sort();
+                distinct.dedup();
+                prop_assert_eq!(distinct.len(), picked.len());
+            }
+        }
+    }
+}
@@ -7,51 +7,265 @@ All rights reserved.
 */
 
 //! This file contains all the hard-coded settings and parsing code for the toml file.
+//!
+//! `Settings::read` only fails on values it can't parse. Whether the values make sense together
+//! (agents, manifest, budgets, regexes) is checked by `Settings::validate`, which is run before
+//! the controller starts so a bad configuration is reported all at once.
+//!
+//! The settings in `ReloadableSettings` can be changed while the controller runs: it reads them
+//! again on SIGHUP or `POST /reload`, and keeps its groups. Everything else needs a restart.
 
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::path::Path;
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::time::Duration;
 
 use config::{Config, File, FileFormat};
+use hyper::Uri;
+use pythia_common::load_request_types_file;
+use pythia_common::RequestType;
+use pythia_common::RequestTypeDefinition;
+use regex::Regex;
 
+use crate::anomaly::AnomalyMethod;
+use crate::clustering::GroupingMode;
+use crate::critical::PathBudget;
+use crate::grouping::{ProblemSelector, DEFAULT_CV_THRESHOLD, DEFAULT_SLOW_PERCENTILE};
+use crate::manifest::Manifest;
+use crate::profile::Profile;
+use crate::query::Filter;
+use crate::reader::SamplingSettings;
+use crate::reader::TracePipeline;
 use crate::search::SearchStrategyType;
+use crate::search::SpreadPolicy;
+use crate::search::TieBreaking;
+use crate::slo::Slo;
+use crate::stopping::StoppingCondition;
 
 const SETTINGS_PATH: &str = "/etc/pythia/controller.toml";
 const DECISION_EPOCH: Duration = Duration::from_secs(120);
 const PYTHIA_JIFFY: Duration = Duration::from_secs(20);
+const XTRACE_TAGS: &str = "FsShell,main";
+const XTRACE_PAGE_SIZE: usize = 100;
 const GC_EPOCH: Duration = Duration::from_secs(120);
 const GC_KEEP_DURATION: Duration = Duration::from_secs(360);
 const TRACEPOINTS_PER_EPOCH: usize = 3;
 const DISABLE_RATIO: f32 = 0.1;
 const TRACE_SIZE_LIMIT: u32 = 100000000;
 const N_WORKERS: usize = 4;
+const UBER_SAMPLE_RATE: f64 = 1.0;
+const CRITICAL_PATHS_PER_TRACE: usize = 1;
 const FREE_KEYS: bool = false;
+const RPC_RETRIES: usize = 3;
+const RPC_BACKOFF: Duration = Duration::from_millis(500);
+const RPC_TIMEOUT: Duration = Duration::from_secs(30);
+const AGENT_TIMEOUT: Duration = Duration::from_secs(60);
+const SPAN_CACHE_SIZE: usize = 1000;
+const TRACEPOINT_CHANGE_JITTER: Duration = Duration::from_millis(500);
+const CLOCK_SKEW_CORRECTION: bool = false;
+const CLOCK_SKEW_WARNING: Duration = Duration::from_millis(10);
+const GROUP_BY_REQUEST_PARAMS: bool = false;
+const GROUPING_SIMILARITY: f64 = 0.9;
+const SLOW_PARTITION_RATIO: f64 = 1.5;
+const ANOMALY_THRESHOLD: f64 = 3.0;
+const IMPACT_ALPHA: f64 = 0.05;
+const VERDICT_THRESHOLD: f64 = 0.8;
+const STREAM_PARTIAL_TRACES: bool = false;
+const PARTIAL_TRACE_AGE: Duration = Duration::from_secs(60);
+const MAX_ENABLED_TRACEPOINTS: usize = 200;
+const RETENTION_WINDOW: Duration = Duration::from_secs(3600);
+const CLASSIFY_MIN_CONFIDENCE: f64 = 0.5;
+const CANARY_MIN_REQUESTS: u64 = 20;
+const CANARY_MAX_OVERHEAD: f64 = 0.05;
+const COVERAGE_STATE_FILE: &str = "/opt/stack/pythia_coverage.json";
+const API_ADDRESS: &str = "127.0.0.1:3031";
+const KAFKA_BROKERS: &str = "localhost:9092";
+const KAFKA_TOPIC: &str = "pythia-spans";
+const KAFKA_GROUP: &str = "pythia-controller";
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Settings {
     pub application: ApplicationType,
     pub manifest_file: PathBuf,
+    /// Whether the manifest comes from offline profiling or grows as the controller sees traces
+    pub manifest_method: ManifestMethod,
+    /// Other applications diagnosed by the same controller, with their manifests. They share the
+    /// budget with `application`, but have their own groups.
+    pub federated_applications: Vec<(ApplicationType, PathBuf)>,
+    /// Event key under which a trace has the id of the span, in another application's trace,
+    /// that it was called from; those traces are stitched together. None disables stitching.
+    pub stitch_key: Option<String>,
     pub pythia_clients: Vec<String>,
     pub redis_url: String,
     pub xtrace_url: String,
+    /// Only requests with all of these X-Trace tags are collected
+    pub xtrace_tags: Vec<String>,
+    /// Tasks asked from the X-Trace server at once
+    pub xtrace_page_size: usize,
     pub uber_trace_dir: PathBuf,
+    /// Fraction of the requests loaded from CSV or Parquet files of the Uber dataset
+    pub uber_sample_rate: f64,
+    /// Requests loaded from each file of the Uber dataset at most; None is unlimited
+    pub uber_max_traces: Option<usize>,
     pub DEATHSTAR_trace_dir: PathBuf,
     pub hdfs_control_file: PathBuf,
     pub deathstar_control_file: PathBuf,
 
     pub search_strategy: SearchStrategyType,
+    /// Where the Flat strategy puts the tracepoints it enables between the ends of an edge
+    pub spread_policy: SpreadPolicy,
+    pub stopping_condition: StoppingCondition,
+    /// How strategies choose among more candidates than the budget allows
+    pub tie_breaking: TieBreaking,
+    /// Where the candidates tried for each group and edge are kept in coverage mode
+    pub coverage_state_file: PathBuf,
+    /// Database of how well the tracepoints enabled for each edge worked, kept across runs and
+    /// used by the Historic strategy; None doesn't record them
+    pub results_db: Option<PathBuf>,
     pub jiffy: Duration,
     pub decision_epoch: Duration,
     pub gc_epoch: Duration,
     pub gc_keep_duration: Duration,
-    pub tracepoints_per_epoch: usize,
+    /// Hard limit on non-skeleton tracepoints enabled at once across the cluster
+    pub max_enabled_tracepoints: usize,
+    /// Enable only this many skeleton tracepoints of each manifest, see `minimal_skeleton`;
+    /// None enables the whole skeleton
+    pub skeleton_size: Option<usize>,
+    /// Fraction of the agents that get the tracepoints picked by the search first, see `canary`;
+    /// None enables them everywhere
+    pub canary_fraction: Option<f64>,
+    /// Requests of the type needed on the canaries and on the other agents before tracepoints on
+    /// trial there are promoted or rolled back
+    pub canary_min_requests: u64,
+    /// Tracepoints that slow requests on the canaries by more than this share are rolled back,
+    /// the others are enabled everywhere
+    pub canary_max_overhead: f64,
+    /// Traces without a request type get the one the manifest matches best, if it is at least
+    /// this confident, see `Manifest::classify`; None leaves them Unknown
+    pub classify_min_confidence: Option<f64>,
+    /// How often the agents' tracepoints are checked against the controller's; None never
+    pub reconcile_interval: Option<Duration>,
+    /// Time each jiffy may spend on the received traces and the search before the rest is
+    /// skipped, see `deadline`; None never skips
+    pub cycle_deadline: Option<Duration>,
+    /// Estimated events per second that one decision can add, instead of tracepoints_per_epoch;
+    /// None counts tracepoints
+    pub event_budget: Option<f64>,
     pub disable_ratio: f32,
     pub trace_size_limit: u32,
+    /// Limits on the paths of each trace that go into the search space
+    pub path_budget: PathBudget,
     pub n_workers: usize,
     pub free_keys: bool,
+    pub rpc_retries: usize,
+    /// Wait before the first retry of an agent RPC, doubled after each retry
+    pub rpc_backoff: Duration,
+    pub rpc_timeout: Duration,
+    /// How long to wait for each agent's spans of a trace, retries included
+    pub agent_timeout: Duration,
+    /// Unfinished requests whose spans are kept, so only new spans are fetched; 0 disables it
+    pub span_cache_size: usize,
+    /// Tracepoint changes sent to the agents per minute at most; None is unlimited
+    pub max_tracepoint_changes_per_min: Option<usize>,
+    /// Random delay of up to this much before each agent is reconfigured
+    pub tracepoint_change_jitter: Duration,
+    /// Traces received this long after tracepoints changed are left out of group statistics
+    pub transition_period: Duration,
+    pub clock_skew_correction: bool,
+    /// Hosts whose clocks are estimated to be off by more than this are logged
+    pub clock_skew_warning: Duration,
+    /// Events earlier than their parent by more than this are outliers (e.g., after a clock
+    /// reset); None doesn't look for them
+    pub outlier_threshold: Option<Duration>,
+    pub outlier_action: OutlierAction,
+    pub group_by_request_params: bool,
+    /// Paths with the least slack taken from each trace for grouping; 1 is the critical path only
+    pub critical_paths_per_trace: usize,
+    /// Exact grouping by critical path structure, or clustering of similar structures
+    pub grouping_mode: GroupingMode,
+    /// Event key (e.g., host) whose values split groups into sub-groups that are compared
+    pub group_partition_key: Option<String>,
+    /// Filter expression that splits groups into matching and other paths, if there is no
+    /// partition key
+    pub group_partition_filter: Option<Filter>,
+    /// A sub-group is reported as slow if its mean is this many times that of its siblings
+    pub slow_partition_ratio: f64,
+    /// Groups kept in memory at most; None is unlimited
+    pub group_max_count: Option<usize>,
+    /// Critical paths kept per group; older paths only count in the group statistics
+    pub group_max_traces: Option<usize>,
+    /// Groups that got no traces for this long are dropped
+    pub group_idle_expiry: Option<Duration>,
+    /// Emit partial traces of requests that are still running
+    pub stream_partial_traces: bool,
+    /// How long a request has to be running before its partial traces are emitted
+    pub partial_trace_age: Duration,
+    /// Where traces of problematic groups are kept for drill-down; None disables retention
+    pub retention_dir: Option<PathBuf>,
+    /// File or `redis://` URL where every enable/disable is recorded; None disables auditing
+    pub audit_log: Option<String>,
+    /// Where each decision epoch is written when it ends; None doesn't keep them
+    pub epoch_dir: Option<PathBuf>,
+    /// Where the tracepoints enabled for each group and the groups they refined it into are
+    /// written after each decision; None doesn't keep them
+    pub lineage_file: Option<PathBuf>,
+    /// Significance level for deciding that newly enabled tracepoints increased latency
+    pub impact_alpha: f64,
+    /// Share of a problem edge's variance a single sub-edge must have, once it can't be split
+    /// any further, for the problem to count as localized
+    pub verdict_threshold: f64,
+    /// How long read traces are kept in memory in case their group becomes problematic
+    pub retention_window: Duration,
+    /// Names and regexes of the OpenStack request types; None uses the built-in ones
+    pub request_types_file: Option<PathBuf>,
+    /// Regex for the names of trace files read from folders (HDFS only); None reads all files
+    pub trace_file_pattern: Option<String>,
+    /// Clean-up passes applied to every trace the readers return
+    pub trace_pipeline: TracePipeline,
+    /// Which of the recently collected traces are kept
+    pub sampling: SamplingSettings,
+    /// Latency objectives; groups that violate them are diagnosed first
+    pub slos: Vec<Slo>,
+    /// Run the controller loop against the traces archived here instead of the live application
+    pub replay_dir: Option<PathBuf>,
+    /// Where named instrumentation profiles are saved, by default `profiles` next to the
+    /// settings file
+    pub profile_dir: PathBuf,
+    /// Where whole-cluster snapshots of the tracepoint settings are saved, by default
+    /// `snapshots` next to the settings file
+    pub snapshot_dir: PathBuf,
+    /// Profile whose tracepoints are enabled with the skeleton when the controller starts
+    pub start_profile: Option<String>,
+    /// Profile applied when the controller is stopped with SIGTERM or SIGINT
+    pub shutdown_profile: Option<String>,
+    /// Where the controller serves its HTTP control API; None disables it
+    pub api_address: Option<String>,
+    /// Where the OpenStack reader gets new spans from
+    pub trace_source: TraceSource,
+    pub kafka_brokers: String,
+    pub kafka_topic: String,
+    pub kafka_group: String,
+    pub reloadable: ReloadableSettings,
+    /// Values of the settings file that couldn't be used, reported by `validate`
+    parse_problems: Vec<String>,
+}
+
+/// Settings that can change during a run
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReloadableSettings {
+    /// Tracepoints enabled per decision
+    pub tracepoints_per_epoch: usize,
+    /// Which groups the controller diagnoses
+    pub problem_selection: ProblemSelector,
+    /// How groups whose latency changed from their history are found; None disables it
+    pub anomaly_detection: Option<AnomalyMethod>,
+    /// Score above which a group is anomalous, in (robust) standard deviations
+    pub anomaly_threshold: f64,
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum ApplicationType {
     HDFS,
     OpenStack,
@@ -59,53 +273,1006 @@ pub enum ApplicationType {
     DEATHSTAR
 }
 
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ManifestMethod {
+    /// Built by `pythia manifest` from profiling traces with everything enabled
+    Offline,
+    /// Starts from whatever the manifest file has, possibly nothing, and adds the paths of every
+    /// trace the controller receives, writing them back to the manifest file
+    Incremental,
+}
+
+/// What the OpenStack reader does with events that are far earlier than their parent
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum OutlierAction {
+    /// Leave out the whole span
+    Drop,
+    /// Move the event to just after the parent
+    Clamp,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TraceSource {
+    /// Poll the agents, which read their local redis
+    Redis,
+    /// Consume span batches that the agents publish to Kafka
+    Kafka,
+}
+
+impl ApplicationType {
+    pub fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "OpenStack" => Ok(ApplicationType::OpenStack),
+            "HDFS" => Ok(ApplicationType::HDFS),
+            "Uber" => Ok(ApplicationType::Uber),
+            "DEATHSTAR" => Ok(ApplicationType::DEATHSTAR),
+            _ => Err(format!("Unknown application type {}", s)),
+        }
+    }
+}
+
+/// The installed settings file, or the one PYTHIA_CONFIG points to (e.g., for the integration
+/// tests)
+pub fn settings_path() -> PathBuf {
+    match std::env::var_os("PYTHIA_CONFIG") {
+        Some(path) => PathBuf::from(path),
+        None => PathBuf::from(SETTINGS_PATH),
+    }
+}
+
 impl Settings {
     pub fn read() -> Settings {
-        let mut settings = Config::default();
-        settings
-            .merge(File::new(SETTINGS_PATH, FileFormat::Toml))
-            .unwrap();
-        let results = settings.try_into::<HashMap<String, String>>().unwrap();
-        let manifest_file = PathBuf::from(results.get("manifest_file").unwrap());
-        let hdfs_control_file = PathBuf::from(results.get("hdfs_control_file").unwrap());
-        let deathstar_control_file = PathBuf::from(results.get("hdfs_control_file").unwrap());
-        let pythia_clients = results.get("pythia_clients").unwrap();
+        Settings::read_from(&settings_path())
+    }
+
+    /// Like `read`, from a settings file other than the installed one
+    pub fn read_from(path: &Path) -> Settings {
+        let settings = read_file(path).unwrap();
+        let stopping_condition = match settings.get_table("stopping_condition") {
+            Ok(table) => StoppingCondition::from_table(table),
+            Err(_) => StoppingCondition::default(),
+        };
+        let sampling = match settings.get_table("sampling") {
+            Ok(table) => SamplingSettings::from_table(table),
+            Err(_) => SamplingSettings::default(),
+        };
+        // Its keys are request type names, which may come from request_types_file below
+        let slo_table = settings.get_table("slo").ok();
+        let results = plain_values(settings).unwrap();
+        let values = Values::new(&results);
+        let reloadable = ReloadableSettings::parse(&values);
+        let manifest_file = PathBuf::from(values.required("manifest_file"));
+        let hdfs_control_file = PathBuf::from(values.required("hdfs_control_file"));
+        let deathstar_control_file = PathBuf::from(values.required("hdfs_control_file"));
+        let pythia_clients = values.required("pythia_clients");
         let pythia_clients = if pythia_clients.len() == 0 {
             Vec::new()
         } else {
             pythia_clients.split(",").map(|x| x.to_string()).collect()
         };
-        Settings {
+        let application = match values.get("application") {
+            Some(s) => ApplicationType::from_str(s)
+                .unwrap_or_else(|e| values.invalid(e, ApplicationType::OpenStack)),
+            None => values.invalid(
+                "application is missing".to_string(),
+                ApplicationType::OpenStack,
+            ),
+        };
+        let federated_applications = values
+            .get("federated_applications")
+            .map(|s| s.as_str())
+            .unwrap_or("")
+            .split(",")
+            .map(|a| a.trim())
+            .filter(|a| a.len() > 0)
+            .filter_map(|a| {
+                let mut parts = a.splitn(2, ':');
+                let application = match ApplicationType::from_str(parts.next().unwrap()) {
+                    Ok(application) => application,
+                    Err(e) => return values.invalid(e, None),
+                };
+                match parts.next() {
+                    Some(manifest_file) => Some((application, PathBuf::from(manifest_file))),
+                    None => {
+                        let problem = "federated_applications should be application:manifest_file";
+                        values.invalid(format!("{}, not {}", problem, a), None)
+                    }
+                }
+            })
+            .collect();
+        let request_types_file = values
+            .get("request_types_file")
+            .filter(|s| s.len() > 0)
+            .map(PathBuf::from);
+        // Request types are global, so they are set up as soon as the settings are known. If they
+        // can't be loaded, `validate` says why.
+        if let Some(path) = &request_types_file {
+            load_request_types_file(path).ok();
+        }
+        let slos = slo_table.map(Slo::from_table).unwrap_or_default();
+        // HDFS traces have branches that never join back, which Pythia can't use
+        let trace_pipeline = match values.get("trace_pipeline") {
+            Some(s) => TracePipeline::from_str(s),
+            None if application == ApplicationType::HDFS => TracePipeline::from_str("prune"),
+            None => TracePipeline::default(),
+        };
+        let jiffy = values
+            .number("jiffy_ms")
+            .map(Duration::from_millis)
+            .unwrap_or(PYTHIA_JIFFY);
+        let grouping_similarity = values
+            .number("grouping_similarity")
+            .unwrap_or(GROUPING_SIMILARITY);
+        let mut settings = Settings {
             manifest_file,
+            manifest_method: match values.get("manifest_method").map(|s| s.as_str()) {
+                None | Some("offline") => ManifestMethod::Offline,
+                Some("incremental") => ManifestMethod::Incremental,
+                Some(s) => values.unknown("manifest_method", s, ManifestMethod::Offline),
+            },
+            federated_applications,
+            stitch_key: values.get("stitch_key").filter(|s| s.len() > 0).cloned(),
             hdfs_control_file,
             deathstar_control_file,
             pythia_clients,
-            redis_url: results.get("redis_url").unwrap().to_string(),
-            uber_trace_dir: PathBuf::from(results.get("uber_trace_dir").unwrap()),
-            DEATHSTAR_trace_dir: PathBuf::from(results.get("DEATHSTAR_trace_dir").unwrap()),
-            application: match results.get("application").unwrap().as_str() {
-                "OpenStack" => ApplicationType::OpenStack,
-                "HDFS" => ApplicationType::HDFS,
-                "Uber" => ApplicationType::Uber,
-                "DEATHSTAR" => ApplicationType::DEATHSTAR,
-                _ => panic!("Unknown application type"),
-            },
-            xtrace_url: results.get("xtrace_url").unwrap().to_string(),
-            decision_epoch: DECISION_EPOCH,
-            search_strategy: match results.get("search_strategy").unwrap().as_str() {
+            redis_url: values.required("redis_url").to_string(),
+            uber_trace_dir: PathBuf::from(values.required("uber_trace_dir")),
+            uber_sample_rate: values
+                .number("uber_sample_rate")
+                .unwrap_or(UBER_SAMPLE_RATE),
+            uber_max_traces: values.number("uber_max_traces"),
+            DEATHSTAR_trace_dir: PathBuf::from(values.required("DEATHSTAR_trace_dir")),
+            application,
+            xtrace_url: values.required("xtrace_url").to_string(),
+            xtrace_tags: values
+                .get("xtrace_tags")
+                .map(|s| s.as_str())
+                .unwrap_or(XTRACE_TAGS)
+                .split(",")
+                .map(|t| t.trim())
+                .filter(|t| t.len() > 0)
+                .map(|t| t.to_string())
+                .collect(),
+            xtrace_page_size: values
+                .number("xtrace_page_size")
+                .unwrap_or(XTRACE_PAGE_SIZE),
+            decision_epoch: values
+                .number("decision_epoch_secs")
+                .map(Duration::from_secs)
+                .unwrap_or(DECISION_EPOCH),
+            search_strategy: match values.required("search_strategy") {
                 "Flat" => SearchStrategyType::Flat,
                 "Hierarchical" => SearchStrategyType::Hierarchical,
                 "Historic" => SearchStrategyType::Historic,
-                _ => panic!("Unknown search strategy"),
+                s => values.unknown("search_strategy", s, SearchStrategyType::Flat),
+            },
+            spread_policy: match values.get("spread_policy").map(|s| s.as_str()) {
+                None | Some("even") => SpreadPolicy::Even,
+                Some("binary") => SpreadPolicy::BinarySplit,
+                // The latencies come from the manifest, for each edge
+                Some("latency") => SpreadPolicy::WeightedByLatency(Vec::new()),
+                Some(s) => values.unknown("spread_policy", s, SpreadPolicy::Even),
+            },
+            stopping_condition,
+            tie_breaking: match values.get("tie_breaking").map(|s| s.as_str()) {
+                None | Some("random") => TieBreaking::Random,
+                Some("coverage") => TieBreaking::Coverage,
+                Some(s) => values.unknown("tie_breaking", s, TieBreaking::Random),
             },
-            tracepoints_per_epoch: TRACEPOINTS_PER_EPOCH,
-            jiffy: PYTHIA_JIFFY,
+            coverage_state_file: PathBuf::from(
+                values
+                    .get("coverage_state_file")
+                    .map(|s| s.as_str())
+                    .unwrap_or(COVERAGE_STATE_FILE),
+            ),
+            results_db: values
+                .get("results_db")
+                .filter(|s| s.len() > 0)
+                .map(PathBuf::from),
+            max_enabled_tracepoints: values
+                .number("max_enabled_tracepoints")
+                .unwrap_or(MAX_ENABLED_TRACEPOINTS),
+            skeleton_size: values.number("skeleton_size"),
+            canary_fraction: values.number("canary_fraction"),
+            canary_min_requests: values
+                .number("canary_min_requests")
+                .unwrap_or(CANARY_MIN_REQUESTS),
+            canary_max_overhead: values
+                .number("canary_max_overhead")
+                .unwrap_or(CANARY_MAX_OVERHEAD),
+            classify_min_confidence: match values.get("classify_min_confidence") {
+                Some(s) if s.is_empty() => None,
+                Some(_) => values.number("classify_min_confidence"),
+                None => Some(CLASSIFY_MIN_CONFIDENCE),
+            },
+            reconcile_interval: values
+                .number("reconcile_interval_secs")
+                .map(Duration::from_secs),
+            cycle_deadline: values
+                .number("cycle_deadline_ms")
+                .map(Duration::from_millis),
+            event_budget: values.number("event_budget"),
+            jiffy,
             gc_epoch: GC_EPOCH,
             gc_keep_duration: GC_KEEP_DURATION,
             disable_ratio: DISABLE_RATIO,
             trace_size_limit: TRACE_SIZE_LIMIT,
+            path_budget: PathBudget {
+                max_paths: values.number("max_paths_per_trace"),
+                max_memory: values
+                    .number::<usize>("max_path_memory_mb")
+                    .map(|mb| mb * 1024 * 1024),
+                sample: values.get("path_sampling").map_or(false, |s| s == "true"),
+            },
             n_workers: N_WORKERS,
             free_keys: FREE_KEYS,
+            rpc_retries: values.number("rpc_retries").unwrap_or(RPC_RETRIES),
+            rpc_backoff: values
+                .number("rpc_backoff_ms")
+                .map(Duration::from_millis)
+                .unwrap_or(RPC_BACKOFF),
+            rpc_timeout: values
+                .number("rpc_timeout_secs")
+                .map(Duration::from_secs)
+                .unwrap_or(RPC_TIMEOUT),
+            agent_timeout: values
+                .number("agent_timeout_secs")
+                .map(Duration::from_secs)
+                .unwrap_or(AGENT_TIMEOUT),
+            span_cache_size: values.number("span_cache_size").unwrap_or(SPAN_CACHE_SIZE),
+            max_tracepoint_changes_per_min: values.number("max_tracepoint_changes_per_min"),
+            tracepoint_change_jitter: values
+                .number("tracepoint_change_jitter_ms")
+                .map(Duration::from_millis)
+                .unwrap_or(TRACEPOINT_CHANGE_JITTER),
+            transition_period: values
+                .number("transition_period_secs")
+                .map(Duration::from_secs)
+                .unwrap_or(jiffy * 2),
+            clock_skew_correction: match values.get("clock_skew_correction") {
+                Some(s) => s == "true",
+                None => CLOCK_SKEW_CORRECTION,
+            },
+            clock_skew_warning: values
+                .number("clock_skew_warning_ms")
+                .map(Duration::from_millis)
+                .unwrap_or(CLOCK_SKEW_WARNING),
+            outlier_threshold: values
+                .number("outlier_threshold_ms")
+                .map(Duration::from_millis),
+            outlier_action: match values.get("outlier_action").map(|s| s.as_str()) {
+                None | Some("clamp") => OutlierAction::Clamp,
+                Some("drop") => OutlierAction::Drop,
+                Some(s) => values.unknown("outlier_action", s, OutlierAction::Clamp),
+            },
+            group_by_request_params: match values.get("group_by_request_params") {
+                Some(s) => s == "true",
+                None => GROUP_BY_REQUEST_PARAMS,
+            },
+            critical_paths_per_trace: values
+                .number("critical_paths_per_trace")
+                .unwrap_or(CRITICAL_PATHS_PER_TRACE),
+            grouping_mode: match values.get("grouping").map(|s| s.as_str()) {
+                None => GroupingMode::Exact,
+                Some(s @ "exact") | Some(s @ "lcs") | Some(s @ "edit_distance") => {
+                    GroupingMode::from_str(s, grouping_similarity)
+                }
+                Some(s) => values.unknown("grouping", s, GroupingMode::Exact),
+            },
+            group_partition_key: values
+                .get("group_partition_key")
+                .filter(|s| s.len() > 0)
+                .cloned(),
+            group_partition_filter: values
+                .get("group_partition_filter")
+                .filter(|s| s.len() > 0)
+                .and_then(|s| match Filter::parse(s) {
+                    Ok(filter) => Some(filter),
+                    Err(e) => {
+                        values.invalid(format!("Invalid group_partition_filter: {}", e), None)
+                    }
+                }),
+            slow_partition_ratio: values
+                .number("slow_partition_ratio")
+                .unwrap_or(SLOW_PARTITION_RATIO),
+            group_max_count: values.number("group_max_count"),
+            group_max_traces: values.number("group_max_traces"),
+            group_idle_expiry: values
+                .number("group_idle_expiry_secs")
+                .map(Duration::from_secs),
+            stream_partial_traces: match values.get("stream_partial_traces") {
+                Some(s) => s == "true",
+                None => STREAM_PARTIAL_TRACES,
+            },
+            partial_trace_age: values
+                .number("partial_trace_age_secs")
+                .map(Duration::from_secs)
+                .unwrap_or(PARTIAL_TRACE_AGE),
+            retention_dir: values
+                .get("retention_dir")
+                .filter(|s| s.len() > 0)
+                .map(PathBuf::from),
+            audit_log: values.get("audit_log").filter(|s| s.len() > 0).cloned(),
+            epoch_dir: values
+                .get("epoch_dir")
+                .filter(|s| s.len() > 0)
+                .map(PathBuf::from),
+            lineage_file: values
+                .get("lineage_file")
+                .filter(|s| s.len() > 0)
+                .map(PathBuf::from),
+            impact_alpha: values.number("impact_alpha").unwrap_or(IMPACT_ALPHA),
+            verdict_threshold: values
+                .number("verdict_threshold")
+                .unwrap_or(VERDICT_THRESHOLD),
+            retention_window: values
+                .number("retention_window_secs")
+                .map(Duration::from_secs)
+                .unwrap_or(RETENTION_WINDOW),
+            trace_pipeline,
+            sampling,
+            slos,
+            request_types_file,
+            trace_file_pattern: values
+                .get("trace_file_pattern")
+                .filter(|s| s.len() > 0)
+                .cloned(),
+            replay_dir: values
+                .get("replay_dir")
+                .filter(|s| s.len() > 0)
+                .map(PathBuf::from),
+            profile_dir: match values.get("profile_dir").filter(|s| s.len() > 0) {
+                Some(dir) => PathBuf::from(dir),
+                None => path.parent().unwrap_or(Path::new(".")).join("profiles"),
+            },
+            snapshot_dir: match values.get("snapshot_dir").filter(|s| s.len() > 0) {
+                Some(dir) => PathBuf::from(dir),
+                None => path.parent().unwrap_or(Path::new(".")).join("snapshots"),
+            },
+            start_profile: values.get("start_profile").filter(|s| s.len() > 0).cloned(),
+            shutdown_profile: values
+                .get("shutdown_profile")
+                .filter(|s| s.len() > 0)
+                .cloned(),
+            api_address: match values.get("api_address") {
+                Some(s) if s.len() == 0 => None,
+                Some(s) => Some(s.clone()),
+                None => Some(API_ADDRESS.to_string()),
+            },
+            trace_source: match values.get("trace_source").map(|s| s.as_str()) {
+                None | Some("redis") => TraceSource::Redis,
+                Some("kafka") => TraceSource::Kafka,
+                Some(s) => values.unknown("trace_source", s, TraceSource::Redis),
+            },
+            kafka_brokers: values
+                .get("kafka_brokers")
+                .map(|s| s.as_str())
+                .unwrap_or(KAFKA_BROKERS)
+                .to_string(),
+            kafka_topic: values
+                .get("kafka_topic")
+                .map(|s| s.as_str())
+                .unwrap_or(KAFKA_TOPIC)
+                .to_string(),
+            kafka_group: KAFKA_GROUP.to_string(),
+            reloadable,
+            parse_problems: Vec::new(),
+        };
+        settings.parse_problems = values.problems.into_inner();
+        settings
+    }
+
+    /// Settings for each application the controller diagnoses, `application` first. They are the
+    /// same except for the application and its manifest.
+    pub fn applications(&self) -> Vec<Settings> {
+        let mut result = vec![self.clone()];
+        for (application, manifest_file) in &self.federated_applications {
+            let mut settings = self.clone();
+            settings.application = *application;
+            settings.manifest_file = manifest_file.clone();
+            settings.federated_applications = Vec::new();
+            result.push(settings);
+        }
+        result
+    }
+
+    /// Everything wrong with the settings, so they can be fixed in one go. Connecting to redis is
+    /// only tried if `check_redis` is set.
+    pub fn validate(&self, check_redis: bool) -> Result<(), Vec<String>> {
+        let mut problems = self.parse_problems.clone();
+
+        // Only OpenStack is reconfigured through agents
+        if self.application == ApplicationType::OpenStack
+            && self.pythia_clients.is_empty()
+            && self.replay_dir.is_none()
+        {
+            problems.push("pythia_clients is empty, no agent would be reconfigured".to_string());
+        }
+        for client in &self.pythia_clients {
+            match client.parse::<Uri>() {
+                Ok(uri) if uri.scheme_part().is_some() && uri.host().is_some() => {}
+                Ok(_) => problems.push(format!(
+                    "Agent {} in pythia_clients should be of the form http://host:port",
+                    client
+                )),
+                Err(e) => problems.push(format!("Agent {} is not a valid URI: {}", client, e)),
+            }
+        }
+        if self.xtrace_url.parse::<Uri>().is_err() {
+            problems.push(format!("xtrace_url {} is not a valid URI", self.xtrace_url));
+        }
+
+        match redis::Client::open(&self.redis_url[..]) {
+            Ok(client) => {
+                if check_redis {
+                    if let Err(e) = client.get_connection() {
+                        problems.push(format!(
+                            "Cannot connect to redis at {}: {}",
+                            self.redis_url, e
+                        ));
+                    }
+                }
+            }
+            Err(e) => problems.push(format!("redis_url {} is invalid: {}", self.redis_url, e)),
+        }
+
+        for settings in self.applications() {
+            if let Some(problem) = settings.check_manifest() {
+                problems.push(problem);
+            }
+        }
+        if self.replay_dir.is_some() && !self.federated_applications.is_empty() {
+            problems.push("Replaying an archive only works for one application".to_string());
+        }
+        if self.replay_dir.is_some() && self.stitch_key.is_some() {
+            problems.push("Traces are not stitched when replaying an archive".to_string());
+        }
+        if self.manifest_method == ManifestMethod::Incremental
+            && !self.federated_applications.is_empty()
+        {
+            problems.push("Incremental manifests only work for one application".to_string());
+        }
+
+        problems.extend(self.reloadable.problems());
+        if self.reloadable.tracepoints_per_epoch == 0 {
+            problems.push("tracepoints_per_epoch is 0, nothing would ever be enabled".to_string());
+        }
+        if self.max_enabled_tracepoints < self.reloadable.tracepoints_per_epoch {
+            problems.push(format!(
+                "max_enabled_tracepoints ({}) is less than one decision's worth ({})",
+                self.max_enabled_tracepoints, self.reloadable.tracepoints_per_epoch
+            ));
+        }
+        if let Some(fraction) = self.canary_fraction {
+            if !(fraction > 0.0 && fraction <= 1.0) {
+                problems.push(format!("canary_fraction ({}) should be in (0, 1]", fraction));
+            }
+            if self.application != ApplicationType::OpenStack {
+                problems.push("Canaries only work with OpenStack agents".to_string());
+            }
+            if !(self.canary_max_overhead >= 0.0) {
+                problems.push(format!(
+                    "canary_max_overhead ({}) should not be negative",
+                    self.canary_max_overhead
+                ));
+            }
+        }
+        if let Some(confidence) = self.classify_min_confidence {
+            if !(confidence > 0.0 && confidence <= 1.0) {
+                problems.push(format!(
+                    "classify_min_confidence ({}) should be in (0, 1]",
+                    confidence
+                ));
+            }
+        }
+        if self.event_budget.map_or(false, |e| e <= 0.0) {
+            problems.push(
+                "event_budget should be positive, leave it empty to count tracepoints".to_string(),
+            );
+        }
+        if self.max_tracepoint_changes_per_min == Some(0) {
+            problems.push(
+                "max_tracepoint_changes_per_min is 0, leave it empty for no limit".to_string(),
+            );
+        }
+        if self.path_budget.max_paths == Some(0) {
+            problems.push("max_paths_per_trace is 0, leave it empty for no limit".to_string());
+        }
+        if self.path_budget.max_memory == Some(0) {
+            problems.push("max_path_memory_mb is 0, leave it empty for no limit".to_string());
+        }
+        if self.critical_paths_per_trace == 0 {
+            problems.push("critical_paths_per_trace is 0, traces would have no paths".to_string());
+        }
+        if self.group_max_count == Some(0) || self.group_max_traces == Some(0) {
+            problems.push(
+                "group_max_count and group_max_traces should be empty or positive".to_string(),
+            );
+        }
+        problems.extend(self.sampling.problems());
+        for slo in &self.slos {
+            if !(slo.percentile > 0.0 && slo.percentile <= 100.0) {
+                problems.push(format!(
+                    "slo.{} percentile ({}) should be in (0, 100]",
+                    slo.request_type, slo.percentile
+                ));
+            }
+            if slo.target.as_nanos() == 0 {
+                problems.push(format!("slo.{} target should be positive", slo.request_type));
+            }
+        }
+        if self.cycle_deadline == Some(Duration::from_secs(0)) {
+            problems.push("cycle_deadline_ms should be empty or positive".to_string());
+        }
+        if let Some(name) = &self.start_profile {
+            if let Err(e) = Profile::load(&self.profile_dir, name) {
+                problems.push(format!("Cannot load start_profile {}: {}", name, e));
+            }
+        }
+        if let Some(name) = &self.shutdown_profile {
+            if let Err(e) = Profile::load(&self.profile_dir, name) {
+                problems.push(format!("Cannot load shutdown_profile {}: {}", name, e));
+            }
+        }
+        if !(self.uber_sample_rate > 0.0 && self.uber_sample_rate <= 1.0) {
+            problems.push(format!(
+                "uber_sample_rate ({}) should be in (0, 1]",
+                self.uber_sample_rate
+            ));
+        }
+        if !(self.impact_alpha > 0.0 && self.impact_alpha < 1.0) {
+            problems.push(format!(
+                "impact_alpha ({}) should be between 0 and 1",
+                self.impact_alpha
+            ));
+        }
+        if !(self.verdict_threshold > 0.0 && self.verdict_threshold <= 1.0) {
+            problems.push(format!(
+                "verdict_threshold ({}) should be in (0, 1]",
+                self.verdict_threshold
+            ));
+        }
+        if self.reloadable.anomaly_threshold <= 0.0 {
+            problems.push(format!(
+                "anomaly_threshold ({}) should be positive",
+                self.reloadable.anomaly_threshold
+            ));
+        }
+        if self.transition_period >= self.decision_epoch {
+            problems.push(format!(
+                "transition_period_secs ({:?}) should be shorter than a decision epoch ({:?})",
+                self.transition_period, self.decision_epoch
+            ));
+        }
+
+        if let Some(pattern) = &self.trace_file_pattern {
+            if let Err(e) = Regex::new(pattern) {
+                problems.push(format!("trace_file_pattern does not compile: {}", e));
+            }
+        }
+        if let Some(path) = &self.request_types_file {
+            match std::fs::read_to_string(path)
+                .map_err(|e| e.to_string())
+                .and_then(|s| {
+                    serde_json::from_str::<Vec<RequestTypeDefinition>>(&s)
+                        .map_err(|e| e.to_string())
+                }) {
+                Ok(definitions) => {
+                    for d in definitions {
+                        if let Err(e) = Regex::new(&d.regex) {
+                            problems.push(format!(
+                                "Regex of request type {} in {:?} does not compile: {}",
+                                d.name, path, e
+                            ));
+                        }
+                    }
+                }
+                Err(e) => {
+                    problems.push(format!("Cannot read request types from {:?}: {}", path, e))
+                }
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+
+    /// The manifest should exist, unless it is incremental, and be built from traces of
+    /// `application`: only OpenStack traces have request types.
+    fn check_manifest(&self) -> Option<String> {
+        let file = match std::fs::File::open(&self.manifest_file) {
+            Ok(f) => f,
+            // An incremental manifest is created from the first traces
+            Err(_) if self.manifest_method == ManifestMethod::Incremental => return None,
+            Err(e) => {
+                return Some(format!(
+                    "Cannot open manifest {:?}, build it with pythia manifest: {}",
+                    self.manifest_file, e
+                ))
+            }
+        };
+        let manifest: Manifest = match serde_json::from_reader(file) {
+            Ok(m) => m,
+            Err(e) => {
+                return Some(format!(
+                    "Manifest {:?} is corrupt: {}",
+                    self.manifest_file, e
+                ))
+            }
+        };
+        let typed = manifest
+            .per_request_type
+            .keys()
+            .any(|&rt| rt != RequestType::Unknown);
+        let empty = manifest.per_request_type.is_empty();
+        match (&self.application, typed) {
+            (ApplicationType::OpenStack, false) if !empty => Some(format!(
+                "Manifest {:?} has no OpenStack request types, is it for another application?",
+                self.manifest_file
+            )),
+            (ApplicationType::OpenStack, _) => None,
+            (application, true) => Some(format!(
+                "Manifest {:?} has OpenStack request types, but the application is {:?}",
+                self.manifest_file, application
+            )),
+            (_, false) => None,
         }
     }
 }
+
+impl ReloadableSettings {
+    /// Read the settings file again. Unlike `Settings::read`, bad values are returned as errors,
+    /// so a running controller can keep its current settings.
+    pub fn read() -> Result<Self, String> {
+        let settings = read_file(&settings_path()).map_err(|e| e.to_string())?;
+        Self::from_values(&plain_values(settings).map_err(|e| e.to_string())?)
+    }
+
+    fn from_values(results: &HashMap<String, String>) -> Result<Self, String> {
+        let values = Values::new(results);
+        let settings = Self::parse(&values);
+        let mut problems = values.problems.into_inner();
+        problems.extend(settings.problems());
+        if problems.is_empty() {
+            Ok(settings)
+        } else {
+            Err(problems.join(", "))
+        }
+    }
+
+    fn parse(values: &Values) -> Self {
+        ReloadableSettings {
+            tracepoints_per_epoch: values
+                .number("tracepoints_per_epoch")
+                .unwrap_or(TRACEPOINTS_PER_EPOCH),
+            problem_selection: match values.get("problem_selection").map(|s| s.as_str()) {
+                None | Some("auto") => ProblemSelector::Auto,
+                Some("variance") => ProblemSelector::Variance,
+                Some("cv") => ProblemSelector::CV(
+                    values
+                        .number("cv_threshold")
+                        .unwrap_or(DEFAULT_CV_THRESHOLD),
+                ),
+                Some("slow") => ProblemSelector::Slow(
+                    values
+                        .number("slow_percentile")
+                        .unwrap_or(DEFAULT_SLOW_PERCENTILE),
+                ),
+                Some("anomaly") => ProblemSelector::Anomaly,
+                Some(s) => values.unknown("problem_selection", s, ProblemSelector::Auto),
+            },
+            anomaly_detection: match values.get("anomaly_detection").map(|s| s.as_str()) {
+                None | Some("") => None,
+                Some("zscore") => Some(AnomalyMethod::ZScore),
+                Some("mad") => Some(AnomalyMethod::Mad),
+                Some(s) => values.unknown("anomaly_detection", s, None),
+            },
+            anomaly_threshold: values
+                .number("anomaly_threshold")
+                .unwrap_or(ANOMALY_THRESHOLD),
+        }
+    }
+
+    /// Values that parse but can't be used
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        match self.problem_selection {
+            ProblemSelector::CV(threshold) if !(threshold > 0.0) => {
+                problems.push(format!("cv_threshold ({}) should be positive", threshold))
+            }
+            ProblemSelector::Slow(percentile) if !(percentile >= 0.0 && percentile <= 100.0) => {
+                problems.push(format!(
+                    "slow_percentile ({}) should be in [0, 100]",
+                    percentile
+                ))
+            }
+            _ => {}
+        }
+        problems
+    }
+
+    /// The anomaly detection method to use, if any; the anomaly problem selection needs one
+    pub fn anomaly_method(&self) -> Option<AnomalyMethod> {
+        match self.problem_selection {
+            ProblemSelector::Anomaly => self.anomaly_detection.or(Some(AnomalyMethod::ZScore)),
+            _ => self.anomaly_detection,
+        }
+    }
+}
+
+/// The plain values of a settings file. Values that can't be used are recorded as problems and
+/// replaced by their defaults, so that all of them can be reported at once.
+struct Values<'a> {
+    results: &'a HashMap<String, String>,
+    problems: RefCell<Vec<String>>,
+}
+
+impl<'a> Values<'a> {
+    fn new(results: &'a HashMap<String, String>) -> Self {
+        Values {
+            results,
+            problems: RefCell::new(Vec::new()),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&'a String> {
+        self.results.get(key)
+    }
+
+    /// The value of a key every settings file has, empty if it is missing
+    fn required(&self, key: &str) -> &'a str {
+        match self.results.get(key) {
+            Some(s) => s,
+            None => self.invalid(format!("{} is missing", key), ""),
+        }
+    }
+
+    /// None if the key is missing or empty
+    fn number<T: FromStr>(&self, key: &str) -> Option<T> {
+        let s = self.results.get(key).filter(|s| s.len() > 0)?;
+        match s.parse() {
+            Ok(v) => Some(v),
+            Err(_) => self.invalid(format!("{} ({}) should be a number", key, s), None),
+        }
+    }
+
+    /// Records the problem and returns the default
+    fn invalid<T>(&self, problem: String, default: T) -> T {
+        self.problems.borrow_mut().push(problem);
+        default
+    }
+
+    fn unknown<T>(&self, key: &str, value: &str, default: T) -> T {
+        self.invalid(format!("Unknown {} {}", key, value), default)
+    }
+}
+
+fn read_file(path: &Path) -> Result<Config, config::ConfigError> {
+    let mut settings = Config::default();
+    settings.merge(File::new(path.to_str().unwrap(), FileFormat::Toml))?;
+    Ok(settings)
+}
+
+/// Everything but the tables is a plain key = "value"
+fn plain_values(settings: Config) -> Result<HashMap<String, String>, config::ConfigError> {
+    Ok(settings
+        .try_into::<HashMap<String, config::Value>>()?
+        .into_iter()
+        .filter_map(|(k, v)| v.into_str().ok().map(|v| (k, v)))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reloadable_values() {
+        let mut values: HashMap<String, String> = vec![
+            ("problem_selection", "cv"),
+            ("cv_threshold", "0.1"),
+            ("tracepoints_per_epoch", "5"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        let settings = ReloadableSettings::from_values(&values).unwrap();
+        assert_eq!(settings.problem_selection, ProblemSelector::CV(0.1));
+        assert_eq!(settings.tracepoints_per_epoch, 5);
+        assert_eq!(settings.anomaly_method(), None);
+
+        values.insert("cv_threshold".to_string(), "high".to_string());
+        assert!(ReloadableSettings::from_values(&values).is_err());
+    }
+
+    /// Problems with the example settings after `change`. The manifest is incremental, so that
+    /// it doesn't have to exist.
+    fn problems(change: impl FnOnce(&mut Settings)) -> Vec<String> {
+        let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("etc/pythia/controller.toml");
+        let mut settings = Settings::read_from(&path);
+        settings.manifest_method = ManifestMethod::Incremental;
+        change(&mut settings);
+        settings.validate(false).err().unwrap_or_default()
+    }
+
+    fn assert_problem(change: impl FnOnce(&mut Settings), problem: &str) {
+        let problems = problems(change);
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].contains(problem), "{:?}", problems);
+    }
+
+    #[test]
+    fn example_settings_are_valid() {
+        assert_eq!(problems(|_| ()), Vec::<String>::new());
+    }
+
+    #[test]
+    fn openstack_needs_agents() {
+        assert_problem(|s| s.pythia_clients.clear(), "pythia_clients is empty");
+        assert!(problems(|s| {
+            s.pythia_clients.clear();
+            s.application = ApplicationType::HDFS;
+        })
+        .is_empty());
+    }
+
+    #[test]
+    fn agents_are_urls() {
+        assert_problem(
+            |s| s.pythia_clients.push("/ctl".to_string()),
+            "should be of the form http://host:port",
+        );
+        assert_problem(
+            |s| s.pythia_clients.push("http://ctl 3030".to_string()),
+            "is not a valid URI",
+        );
+    }
+
+    #[test]
+    fn xtrace_url_is_a_uri() {
+        assert_problem(|s| s.xtrace_url = "http://".to_string(), "xtrace_url");
+    }
+
+    #[test]
+    fn redis_url_is_valid() {
+        assert_problem(|s| s.redis_url = "localhost".to_string(), "redis_url");
+    }
+
+    #[test]
+    fn offline_manifest_exists() {
+        assert_problem(
+            |s| {
+                s.manifest_method = ManifestMethod::Offline;
+                s.manifest_file = PathBuf::from("/nonexistent/manifest.json");
+            },
+            "Cannot open manifest",
+        );
+    }
+
+    #[test]
+    fn replay_does_not_stitch() {
+        assert_problem(
+            |s| {
+                s.replay_dir = Some(PathBuf::from("/tmp/archive"));
+                s.stitch_key = Some("parent_span".to_string());
+            },
+            "not stitched",
+        );
+    }
+
+    #[test]
+    fn tracepoints_per_epoch_is_positive() {
+        assert_problem(
+            |s| s.reloadable.tracepoints_per_epoch = 0,
+            "tracepoints_per_epoch is 0",
+        );
+    }
+
+    #[test]
+    fn budget_fits_an_epoch() {
+        assert_problem(
+            |s| s.max_enabled_tracepoints = s.reloadable.tracepoints_per_epoch - 1,
+            "less than one decision's worth",
+        );
+    }
+
+    #[test]
+    fn limits_are_not_zero() {
+        assert_problem(
+            |s| s.max_tracepoint_changes_per_min = Some(0),
+            "max_tracepoint_changes_per_min is 0",
+        );
+        assert_problem(|s| s.path_budget.max_paths = Some(0), "max_paths_per_trace");
+        assert_problem(|s| s.path_budget.max_memory = Some(0), "max_path_memory_mb");
+        assert_problem(
+            |s| s.critical_paths_per_trace = 0,
+            "critical_paths_per_trace",
+        );
+        assert_problem(|s| s.group_max_count = Some(0), "group_max_count");
+        assert_problem(|s| s.group_max_traces = Some(0), "group_max_traces");
+    }
+
+    #[test]
+    fn impact_alpha_is_a_probability() {
+        assert_problem(|s| s.impact_alpha = 1.0, "impact_alpha");
+        assert_problem(|s| s.impact_alpha = std::f64::NAN, "impact_alpha");
+    }
+
+    #[test]
+    fn anomaly_threshold_is_positive() {
+        assert_problem(
+            |s| s.reloadable.anomaly_threshold = 0.0,
+            "anomaly_threshold",
+        );
+    }
+
+    #[test]
+    fn selection_thresholds_are_in_range() {
+        assert_problem(
+            |s| s.reloadable.problem_selection = ProblemSelector::Slow(150.0),
+            "slow_percentile",
+        );
+        assert_problem(
+            |s| s.reloadable.problem_selection = ProblemSelector::CV(0.0),
+            "cv_threshold",
+        );
+    }
+
+    #[test]
+    fn reports_every_bad_value() {
+        let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("etc/pythia/controller.toml");
+        let example = std::fs::read_to_string(&path).unwrap();
+        let changed = example
+            .replace("jiffy_ms = \"\"", "jiffy_ms = \"soon\"")
+            .replace("rpc_retries = \"3\"", "rpc_retries = \"-1\"")
+            .replace(
+                "search_strategy = \"Hierarchical\"",
+                "search_strategy = \"Deep\"",
+            )
+            .replace("xtrace_url = \"http://localhost:4080\"", "");
+        let path =
+            std::env::temp_dir().join(format!("pythia_settings_{}.toml", std::process::id()));
+        std::fs::write(&path, changed).unwrap();
+        let mut settings = Settings::read_from(&path);
+        std::fs::remove_file(&path).ok();
+        settings.manifest_method = ManifestMethod::Incremental;
+        let problems = settings.validate(false).unwrap_err();
+        for problem in &[
+            "jiffy_ms (soon) should be a number",
+            "rpc_retries (-1) should be a number",
+            "Unknown search_strategy Deep",
+            "xtrace_url is missing",
+        ] {
+            assert!(problems.iter().any(|p| p == problem), "{:?}", problems);
+        }
+    }
+
+    #[test]
+    fn transition_fits_an_epoch() {
+        assert_problem(
+            |s| s.transition_period = s.decision_epoch,
+            "transition_period_secs",
+        );
+    }
+
+    #[test]
+    fn trace_file_pattern_compiles() {
+        assert_problem(
+            |s| s.trace_file_pattern = Some("(".to_string()),
+            "trace_file_pattern",
+        );
+    }
+
+    #[test]
+    fn request_types_file_is_readable() {
+        assert_problem(
+            |s| s.request_types_file = Some(PathBuf::from("/nonexistent/types.json")),
+            "Cannot read request types",
+        );
+    }
+}
@@ -0,0 +1,159 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! End-to-end latency objectives of request types, read from the `[slo]` section of
+//! `controller.toml`:
+//!
+//! ```toml
+//! [slo]
+//! ServerCreate = "p95 < 30s"
+//! ServerDelete = "p99 < 1500ms"
+//! ```
+//!
+//! A group violates its objective when the percentile of its latencies since it was last used is
+//! above the target. Violating groups are diagnosed before the groups picked by the problem
+//! selector, whatever their variance.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+use config::Value;
+use serde::Serialize;
+
+use pythia_common::RequestType;
+
+use crate::critical::Path;
+use crate::grouping::Group;
+
+/// Groups with fewer traces have percentiles that say little, same as in problem selection
+pub const SLO_MIN_TRACES: usize = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Slo {
+    pub request_type: RequestType,
+    /// Between 0 and 100
+    pub percentile: f64,
+    pub target: Duration,
+}
+
+impl Slo {
+    /// Parses an objective like `p95 < 30s`; the target can be in us, ms, s or m
+    pub fn from_str(request_type: RequestType, s: &str) -> Result<Slo, String> {
+        let mut parts = s.splitn(2, '<');
+        let percentile = parts.next().unwrap().trim();
+        let target = parts
+            .next()
+            .ok_or_else(|| format!("{:?} should look like p95 < 30s", s))?
+            .trim();
+        let percentile: f64 = percentile
+            .strip_prefix('p')
+            .and_then(|p| p.parse().ok())
+            .ok_or_else(|| format!("{:?} is not a percentile like p95", percentile))?;
+        let split = target
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(target.len());
+        let value: f64 = target[..split]
+            .parse()
+            .map_err(|_| format!("{:?} is not a duration like 30s", target))?;
+        let seconds = match target[split..].trim() {
+            "us" => value / 1e6,
+            "ms" => value / 1e3,
+            "s" => value,
+            "m" => value * 60.0,
+            unit => return Err(format!("Unknown unit {:?} in {:?}", unit, target)),
+        };
+        Ok(Slo {
+            request_type,
+            percentile,
+            target: Duration::from_secs_f64(seconds),
+        })
+    }
+
+    /// Request type names are keys; they have to be loaded before this is called
+    pub fn from_table(table: HashMap<String, Value>) -> Vec<Slo> {
+        let mut result: Vec<Slo> = table
+            .into_iter()
+            .map(|(key, value)| {
+                let request_type = RequestType::from_str(&key)
+                    .unwrap_or_else(|_| panic!("Unknown request type {} in slo", key));
+                let objective = value
+                    .into_str()
+                    .unwrap_or_else(|_| panic!("slo.{} should be a string like p95 < 30s", key));
+                Slo::from_str(request_type, &objective)
+                    .unwrap_or_else(|e| panic!("Bad objective for {}: {}", key, e))
+            })
+            .collect();
+        result.sort_by_key(|s| s.request_type.to_string());
+        result
+    }
+
+    pub fn check(&self, group: &Group) -> SloCompliance {
+        let observed = group.stats.percentile(self.percentile).to_duration();
+        SloCompliance {
+            group: group.hash().to_string(),
+            request_type: self.request_type.to_string(),
+            percentile: self.percentile,
+            target: self.target,
+            observed,
+            violated: observed > self.target,
+        }
+    }
+}
+
+/// How a group does against the objective of its request type
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SloCompliance {
+    pub group: String,
+    pub request_type: String,
+    pub percentile: f64,
+    pub target: Duration,
+    pub observed: Duration,
+    pub violated: bool,
+}
+
+impl SloCompliance {
+    /// How far over the target the group is; below 1 if it meets it
+    pub fn ratio(&self) -> f64 {
+        self.observed.as_secs_f64() / self.target.as_secs_f64()
+    }
+}
+
+impl fmt::Display for SloCompliance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} of {}: p{} {:?} {} target {:?}",
+            self.request_type,
+            self.group,
+            self.percentile,
+            self.observed,
+            if self.violated { ">" } else { "<=" },
+            self.target
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_objectives() {
+        let create = RequestType::from_str("ServerCreate").unwrap();
+        let slo = Slo::from_str(create, "p95 < 30s").unwrap();
+        assert_eq!(slo.percentile, 95.0);
+        assert_eq!(slo.target, Duration::from_secs(30));
+        let slo = Slo::from_str(create, " p99.9<1500ms ").unwrap();
+        assert_eq!(slo.percentile, 99.9);
+        assert_eq!(slo.target, Duration::from_millis(1500));
+        assert!(Slo::from_str(create, "p95 30s").is_err());
+        assert!(Slo::from_str(create, "95 < 30s").is_err());
+        assert!(Slo::from_str(create, "p95 < 30 days").is_err());
+    }
+}
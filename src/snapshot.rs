@@ -0,0 +1,192 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Whole-cluster snapshots of the instrumentation.
+//!
+//! Unlike a profile, which keeps the enabled tracepoints of the manifest, a snapshot is every
+//! tracepoint setting of every agent as its `dump_state` returns it, saved as
+//! `<snapshot_dir>/<name>.json`. `pythia snapshot restore <name>` makes each agent load its
+//! settings back exactly, so an experiment or an operator mistake can be undone.
+
+use std::collections::BTreeMap;
+use std::fs::{create_dir_all, rename, File};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::offset::Local;
+use chrono::NaiveDateTime;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use pythia_common::AgentState;
+use pythia_common::PythiaError;
+
+use crate::rpclib::{call_all_clients, dump_client_state, load_client_state, RetryPolicy};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub name: String,
+    pub saved: NaiveDateTime,
+    /// Settings of each agent, by its address in `pythia_clients`
+    pub agents: BTreeMap<String, AgentState>,
+}
+
+impl Snapshot {
+    /// Fails unless every agent answers within `timeout`: a snapshot missing an agent could not
+    /// be restored exactly
+    pub fn capture(
+        name: &str,
+        clients: &[String],
+        policy: &RetryPolicy,
+        timeout: Duration,
+    ) -> Result<Snapshot, PythiaError> {
+        let policy = policy.clone();
+        let answers = call_all_clients(clients, timeout, move |client| {
+            dump_client_state(client, &policy)
+        });
+        let mut problems: Vec<String> = answers
+            .timed_out
+            .iter()
+            .map(|client| format!("{} timed out", client))
+            .collect();
+        let mut agents = BTreeMap::new();
+        for (client, answer) in answers.results {
+            match answer {
+                Ok(state) => {
+                    agents.insert(client, state);
+                }
+                Err(e) => problems.push(format!("{}: {}", client, e)),
+            }
+        }
+        if !problems.is_empty() {
+            return Err(PythiaError::RpcError(format!(
+                "Could not read the state of every agent: {}",
+                problems.join(", ")
+            )));
+        }
+        Ok(Snapshot {
+            name: name.to_string(),
+            saved: Local::now().naive_local(),
+            agents,
+        })
+    }
+
+    /// Loads the saved settings on each agent of `clients`. Agents that are not in the snapshot
+    /// are left alone; the others are all tried before reporting which failed.
+    pub fn restore(
+        &self,
+        clients: &[String],
+        policy: &RetryPolicy,
+        timeout: Duration,
+    ) -> Result<usize, PythiaError> {
+        for client in clients.iter().filter(|c| !self.agents.contains_key(*c)) {
+            warn!(
+                "Agent {} is not in snapshot {}, leaving it as it is",
+                client, self.name
+            );
+        }
+        for client in self.agents.keys().filter(|c| !clients.contains(c)) {
+            warn!(
+                "Agent {} of snapshot {} is not in pythia_clients",
+                client, self.name
+            );
+        }
+        let known: Vec<String> = clients
+            .iter()
+            .filter(|c| self.agents.contains_key(*c))
+            .cloned()
+            .collect();
+        let (agents, policy) = (self.agents.clone(), policy.clone());
+        let answers = call_all_clients(&known, timeout, move |client| {
+            load_client_state(client, agents[client].clone(), &policy)
+        });
+        let mut problems: Vec<String> = answers
+            .timed_out
+            .iter()
+            .map(|client| format!("{} timed out", client))
+            .collect();
+        for (client, answer) in answers.results {
+            if let Err(e) = answer {
+                problems.push(format!("{}: {}", client, e));
+            }
+        }
+        if !problems.is_empty() {
+            return Err(PythiaError::RpcError(format!(
+                "Could not restore every agent: {}",
+                problems.join(", ")
+            )));
+        }
+        Ok(known.len())
+    }
+
+    pub fn path(dir: &Path, name: &str) -> Result<PathBuf, PythiaError> {
+        if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+            return Err(PythiaError::ControllerError(format!(
+                "{:?} is not a valid snapshot name",
+                name
+            )));
+        }
+        Ok(dir.join(format!("{}.json", name)))
+    }
+
+    pub fn load(dir: &Path, name: &str) -> Result<Snapshot, PythiaError> {
+        let file = File::open(Snapshot::path(dir, name)?)?;
+        Ok(serde_json::from_reader(file)?)
+    }
+
+    /// Overwrites the snapshot with the same name, if any. Writes to a temporary file first, so
+    /// a crash doesn't leave a truncated snapshot.
+    pub fn save(&self, dir: &Path) -> Result<PathBuf, PythiaError> {
+        let path = Snapshot::path(dir, &self.name)?;
+        create_dir_all(dir)?;
+        let tmp = dir.join(format!("{}.json.tmp", self.name));
+        serde_json::to_writer_pretty(File::create(&tmp)?, self)?;
+        rename(&tmp, &path)?;
+        Ok(path)
+    }
+
+    /// Number of tracepoint settings over all agents
+    pub fn settings(&self) -> usize {
+        self.agents.values().map(|a| a.tracepoints.len()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pythia_common::RequestType;
+
+    #[test]
+    fn round_trip() {
+        let create = RequestType::from_str("ServerCreate").unwrap();
+        let mut agents = BTreeMap::new();
+        agents.insert(
+            "http://compute-1:3030".to_string(),
+            AgentState {
+                host: "compute-1".to_string(),
+                tracepoints: vec![
+                    ("nova/api.py:10:create".to_string(), None, false),
+                    ("nova/api.py:10:create".to_string(), Some(create), true),
+                ],
+            },
+        );
+        let snapshot = Snapshot {
+            name: "before-experiment".to_string(),
+            saved: Local::now().naive_local(),
+            agents,
+        };
+        assert_eq!(snapshot.settings(), 2);
+
+        let dir = std::env::temp_dir().join(format!("pythia-snapshots-{}", std::process::id()));
+        snapshot.save(&dir).unwrap();
+        assert_eq!(Snapshot::load(&dir, "before-experiment").unwrap(), snapshot);
+        assert!(Snapshot::load(&dir, "../before-experiment").is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,180 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! When the main Pythia loop should stop, read from the `[stopping_condition]` section of
+//! `controller.toml`:
+//!
+//! ```toml
+//! [stopping_condition]
+//! targets = ["nova/usr/local/lib/python3.6/dist-packages/nova/compute/manager.py:1859:..."]
+//! cv_below = 0.05
+//! max_cycles = 500
+//! max_wall_time_secs = 86400
+//! ```
+//!
+//! All keys are optional, and the loop stops at the first condition that holds. Without any of
+//! them Pythia runs until it is killed.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+use config::Value;
+
+use crate::grouping::GroupManager;
+use crate::trace::TracepointID;
+use crate::units::cv;
+
+#[derive(Debug, Clone, Default)]
+pub struct StoppingCondition {
+    /// Stop once all of these tracepoints are enabled by the search
+    pub targets: Vec<String>,
+    /// Stop once no group with enough traces has a coefficient of variance above this
+    pub cv_below: Option<f64>,
+    /// Stop after this many decision cycles
+    pub max_cycles: Option<usize>,
+    pub max_wall_time: Option<Duration>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StopReason {
+    TargetsReached,
+    LowVariation(f64),
+    MaxCycles(usize),
+    MaxWallTime(Duration),
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StopReason::TargetsReached => write!(f, "all targets were enabled"),
+            StopReason::LowVariation(c) => write!(f, "the highest group CV is {:.3}", c),
+            StopReason::MaxCycles(n) => write!(f, "made {} decisions", n),
+            StopReason::MaxWallTime(d) => write!(f, "ran for {:?}", d),
+        }
+    }
+}
+
+impl StoppingCondition {
+    pub fn from_table(table: HashMap<String, Value>) -> Self {
+        let mut result = StoppingCondition::default();
+        for (key, value) in table {
+            match key.as_str() {
+                "targets" => {
+                    result.targets = value
+                        .into_array()
+                        .expect("stopping_condition.targets should be a list")
+                        .into_iter()
+                        .map(|v| v.into_str().expect("targets should be tracepoint ids"))
+                        .collect();
+                }
+                "cv_below" => {
+                    result.cv_below = Some(
+                        value
+                            .into_float()
+                            .expect("stopping_condition.cv_below should be a number"),
+                    );
+                }
+                "max_cycles" => {
+                    result.max_cycles = Some(
+                        value
+                            .into_int()
+                            .expect("stopping_condition.max_cycles should be a number")
+                            as usize,
+                    );
+                }
+                "max_wall_time_secs" => {
+                    result.max_wall_time = Some(Duration::from_secs(
+                        value
+                            .into_int()
+                            .expect("stopping_condition.max_wall_time_secs should be a number")
+                            as u64,
+                    ));
+                }
+                _ => panic!("Unknown stopping condition {}", key),
+            }
+        }
+        result
+    }
+
+    pub fn target_ids(&self) -> Vec<TracepointID> {
+        self.targets
+            .iter()
+            .map(|t| TracepointID::from_str(t))
+            .collect()
+    }
+
+    /// Evaluated once per cycle. `targets_left` is how many of the targets are not enabled yet.
+    /// `groups` has the groups of each application.
+    pub fn check(
+        &self,
+        cycles: usize,
+        elapsed: Duration,
+        targets_left: usize,
+        groups: &[&GroupManager],
+    ) -> Option<StopReason> {
+        if !self.targets.is_empty() && targets_left == 0 {
+            return Some(StopReason::TargetsReached);
+        }
+        if let Some(max) = self.max_cycles {
+            if cycles >= max {
+                return Some(StopReason::MaxCycles(cycles));
+            }
+        }
+        if let Some(max) = self.max_wall_time {
+            if elapsed >= max {
+                return Some(StopReason::MaxWallTime(elapsed));
+            }
+        }
+        if let Some(threshold) = self.cv_below {
+            // Groups with few traces don't tell much, same as in problem selection
+            let highest = groups
+                .iter()
+                .flat_map(|m| m.active_groups())
+                .filter(|g| g.trace_count() > 3)
+                .map(|g| cv(g.mean, g.variance))
+                .fold(None, |acc: Option<f64>, c| Some(acc.map_or(c, |a| a.max(c))));
+            if let Some(highest) = highest {
+                if highest < threshold {
+                    return Some(StopReason::LowVariation(highest));
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_checks() {
+        let mut table = HashMap::new();
+        table.insert("max_cycles".to_string(), Value::from(10));
+        table.insert(
+            "targets".to_string(),
+            Value::from(vec![Value::from("a"), Value::from("b")]),
+        );
+        let condition = StoppingCondition::from_table(table);
+        assert_eq!(condition.targets, vec!["a", "b"]);
+        assert_eq!(condition.max_wall_time, None);
+
+        let groups = GroupManager::new();
+        let elapsed = Duration::from_secs(100);
+        assert_eq!(condition.check(3, elapsed, 1, &[&groups]), None);
+        assert_eq!(
+            condition.check(10, elapsed, 1, &[&groups]),
+            Some(StopReason::MaxCycles(10))
+        );
+        assert_eq!(
+            condition.check(3, elapsed, 0, &[&groups]),
+            Some(StopReason::TargetsReached)
+        );
+    }
+}
@@ -0,0 +1,317 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Synthetic traces for tests.
+//!
+//! `TraceGenerator` builds random traces of nested spans, some of them running concurrently, and
+//! remembers which branch of each fork finished last. Every trace therefore comes with the
+//! critical path it should have, so critical path extraction, grouping and the search space can
+//! be checked on many shapes without hand-written fixtures.
+//!
+//! Generation is split in two: a `TraceShape` is the structure of a request (which spans and
+//! annotations, nested how), and `instantiate` draws new latencies for it. Traces of the same
+//! shape may still have different critical paths, since the latencies decide which concurrent
+//! branch is the slowest.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::time::Duration;
+
+use chrono::NaiveDateTime;
+use petgraph::graph::NodeIndex;
+use petgraph::Direction;
+use rand::rngs::StdRng;
+use rand::Rng;
+use rand::SeedableRng;
+use uuid::Uuid;
+
+use pythia_common::RequestType;
+
+use crate::critical::CriticalPath;
+use crate::trace::DAGEdge;
+use crate::trace::EdgeType;
+use crate::trace::Event;
+use crate::trace::EventType;
+use crate::trace::Trace;
+use crate::trace::TracepointID;
+
+/// Latency between an event and the one before it
+#[derive(Debug, Clone)]
+pub enum Latency {
+    Constant(Duration),
+    Uniform(Duration, Duration),
+    /// With the given mean
+    Exponential(Duration),
+}
+
+impl Latency {
+    /// Never zero, so consecutive events have different timestamps
+    fn sample(&self, rng: &mut StdRng) -> Duration {
+        let result = match self {
+            Latency::Constant(d) => *d,
+            Latency::Uniform(low, high) if low < high => {
+                Duration::from_nanos(rng.gen_range(low.as_nanos() as u64, high.as_nanos() as u64))
+            }
+            Latency::Uniform(low, _) => *low,
+            Latency::Exponential(mean) => mean.mul_f64(-(1.0 - rng.gen::<f64>()).ln()),
+        };
+        result.max(Duration::from_micros(1))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Block {
+    Annotation(TracepointID),
+    /// Entry and exit of a span, with the blocks that run inside it in order
+    Span(TracepointID, Vec<Block>),
+    /// Spans that start together; the next block waits for all of them
+    Fork(Vec<Block>),
+}
+
+/// The structure of a request, without latencies
+#[derive(Debug, Clone)]
+pub struct TraceShape {
+    pub request_type: RequestType,
+    /// The top-level span, containing the whole request
+    pub root: Block,
+}
+
+pub struct GeneratedTrace {
+    pub trace: Trace,
+    /// Tracepoints and event types on the critical path, from the start of the request
+    pub critical_path: Vec<(TracepointID, EventType)>,
+    pub duration: Duration,
+}
+
+impl GeneratedTrace {
+    /// The tracepoints of the critical path, which is what groups are made of
+    pub fn sequence(&self) -> Vec<TracepointID> {
+        self.critical_path.iter().map(|&(tp, _)| tp).collect()
+    }
+}
+
+/// Settings are public so tests can change them after `new`
+pub struct TraceGenerator {
+    rng: StdRng,
+    /// Unknown if empty
+    pub request_types: Vec<RequestType>,
+    /// Number of distinct tracepoint names to draw from
+    pub tracepoints: usize,
+    /// Spans nested deeper than this only contain annotations
+    pub max_depth: usize,
+    /// Blocks directly inside a span
+    pub max_children: usize,
+    /// Spans in a fork
+    pub max_fanout: usize,
+    /// Probability that a block is a fork
+    pub concurrency: f64,
+    pub latency: Latency,
+}
+
+impl TraceGenerator {
+    pub fn new(seed: u64) -> Self {
+        TraceGenerator {
+            rng: StdRng::seed_from_u64(seed),
+            request_types: RequestType::all(),
+            tracepoints: 20,
+            max_depth: 3,
+            max_children: 4,
+            max_fanout: 3,
+            concurrency: 0.2,
+            latency: Latency::Exponential(Duration::from_millis(5)),
+        }
+    }
+
+    pub fn generate(&mut self) -> GeneratedTrace {
+        let shape = self.generate_shape();
+        self.instantiate(&shape)
+    }
+
+    pub fn generate_shape(&mut self) -> TraceShape {
+        let request_type = if self.request_types.is_empty() {
+            RequestType::Unknown
+        } else {
+            self.request_types[self.rng.gen_range(0, self.request_types.len())]
+        };
+        TraceShape {
+            request_type,
+            root: self.span(0),
+        }
+    }
+
+    /// Critical paths of `count` traces, each of a new shape
+    pub fn critical_paths(&mut self, count: usize) -> Vec<CriticalPath> {
+        (0..count)
+            .map(|_| CriticalPath::from_trace(&self.generate().trace).unwrap())
+            .collect()
+    }
+
+    /// Critical paths of `count` traces of one new shape. Without concurrency they all have the
+    /// same tracepoints, so they end up in the same group.
+    pub fn critical_paths_of_shape(&mut self, count: usize) -> Vec<CriticalPath> {
+        let shape = self.generate_shape();
+        (0..count)
+            .map(|_| CriticalPath::from_trace(&self.instantiate(&shape).trace).unwrap())
+            .collect()
+    }
+
+    /// A trace of the shape, with new latencies
+    pub fn instantiate(&mut self, shape: &TraceShape) -> GeneratedTrace {
+        let mut builder = Builder {
+            trace: Trace::new(&Uuid::new_v4()),
+            frontier: Vec::new(),
+            previous: HashMap::new(),
+            latency: &self.latency,
+            rng: &mut self.rng,
+        };
+        builder.add_block(&shape.root);
+        let mut trace = builder.trace;
+        let end = builder.frontier[0];
+        let mut critical_path = Vec::new();
+        let mut cur = Some(end);
+        while let Some(nidx) = cur {
+            critical_path.push((trace.g[nidx].tracepoint_id, trace.g[nidx].variant));
+            trace.start_node = nidx;
+            cur = builder.previous.get(&nidx).cloned();
+        }
+        critical_path.reverse();
+        trace.end_node = end;
+        trace.request_type = shape.request_type;
+        trace.duration = (trace.g[end].timestamp - trace.g[trace.start_node].timestamp)
+            .to_std()
+            .unwrap();
+        GeneratedTrace {
+            duration: trace.duration,
+            trace,
+            critical_path,
+        }
+    }
+
+    fn tracepoint(&mut self) -> TracepointID {
+        let i = self.rng.gen_range(0, self.tracepoints.max(1));
+        TracepointID::from_str(&format!("synthetic:{}", i))
+    }
+
+    fn span(&mut self, depth: usize) -> Block {
+        let tracepoint = self.tracepoint();
+        let children = self.rng.gen_range(0, self.max_children + 1);
+        let blocks = (0..children).map(|_| self.block(depth)).collect();
+        Block::Span(tracepoint, blocks)
+    }
+
+    fn block(&mut self, depth: usize) -> Block {
+        if depth >= self.max_depth {
+            Block::Annotation(self.tracepoint())
+        } else if self.max_fanout >= 2 && self.rng.gen_bool(self.concurrency) {
+            let fanout = self.rng.gen_range(2, self.max_fanout + 1);
+            Block::Fork((0..fanout).map(|_| self.span(depth + 1)).collect())
+        } else if self.rng.gen_bool(0.5) {
+            self.span(depth + 1)
+        } else {
+            Block::Annotation(self.tracepoint())
+        }
+    }
+}
+
+struct Builder<'a> {
+    trace: Trace,
+    /// Events the next event happens after
+    frontier: Vec<NodeIndex>,
+    /// The latest event each event waited for, i.e., its predecessor on the critical path
+    previous: HashMap<NodeIndex, NodeIndex>,
+    latency: &'a Latency,
+    rng: &'a mut StdRng,
+}
+
+impl<'a> Builder<'a> {
+    fn add_block(&mut self, block: &Block) {
+        match block {
+            Block::Annotation(tp) => {
+                self.add_event(*tp, EventType::Annotation, Uuid::new_v4());
+            }
+            Block::Span(tp, blocks) => {
+                let span = Uuid::new_v4();
+                self.add_event(*tp, EventType::Entry, span);
+                for block in blocks {
+                    self.add_block(block);
+                }
+                self.add_event(*tp, EventType::Exit, span);
+            }
+            Block::Fork(branches) => {
+                let start = self.frontier.clone();
+                let mut ends = Vec::new();
+                for branch in branches {
+                    self.frontier = start.clone();
+                    self.add_block(branch);
+                    ends.extend(self.frontier.drain(..));
+                }
+                self.break_ties(&ends);
+                self.frontier = ends;
+            }
+        }
+    }
+
+    fn add_event(&mut self, tracepoint_id: TracepointID, variant: EventType, trace_id: Uuid) {
+        let trace = &mut self.trace;
+        let latest = self
+            .frontier
+            .iter()
+            .cloned()
+            .max_by_key(|&nidx| trace.g[nidx].timestamp);
+        let timestamp = match latest {
+            Some(nidx) => trace.g[nidx].timestamp,
+            None => NaiveDateTime::from_timestamp(0, 0),
+        } + chrono::Duration::from_std(self.latency.sample(self.rng)).unwrap();
+        let node = trace.g.add_node(Event {
+            trace_id,
+            tracepoint_id,
+            timestamp,
+            is_synthetic: false,
+            variant,
+            key_value_pair: HashMap::new(),
+        });
+        for &prev in &self.frontier {
+            let duration = (timestamp - trace.g[prev].timestamp).to_std().unwrap();
+            trace.g.add_edge(
+                prev,
+                node,
+                DAGEdge {
+                    duration,
+                    variant: EdgeType::ChildOf,
+                },
+            );
+        }
+        if let Some(nidx) = latest {
+            self.previous.insert(node, nidx);
+        }
+        self.frontier = vec![node];
+    }
+
+    /// Concurrent spans that end at the same time would make the critical path ambiguous, so
+    /// later ones are pushed back by a nanosecond
+    fn break_ties(&mut self, ends: &[NodeIndex]) {
+        let mut seen = HashSet::new();
+        for &end in ends {
+            while !seen.insert(self.trace.g[end].timestamp) {
+                self.trace.g[end].timestamp += chrono::Duration::nanoseconds(1);
+            }
+            let incoming: Vec<_> = self
+                .trace
+                .g
+                .neighbors_directed(end, Direction::Incoming)
+                .collect();
+            for prev in incoming {
+                let edge = self.trace.g.find_edge(prev, end).unwrap();
+                self.trace.g[edge].duration = (self.trace.g[end].timestamp
+                    - self.trace.g[prev].timestamp)
+                    .to_std()
+                    .unwrap();
+            }
+        }
+    }
+}
@@ -17,9 +17,14 @@ use std::time::Duration;
 
 use bimap::BiMap;
 use chrono::NaiveDateTime;
+use log::debug;
+use petgraph::algo::toposort;
 use petgraph::dot::Dot;
 use petgraph::graph::NodeIndex;
 use petgraph::stable_graph::StableGraph;
+use petgraph::visit::Dfs;
+use petgraph::visit::Reversed;
+use petgraph::visit::Walker;
 use petgraph::Direction;
 use serde::de;
 use serde::ser;
@@ -27,9 +32,13 @@ use serde::{Deserialize, Serialize};
 use std::path::Path;
 use uuid::Uuid;
 use stats::variance;
+use pythia_common::ParameterizedRequestType;
+use pythia_common::PythiaError;
 use pythia_common::RequestType;
 
+use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::collections::HashSet;
 
 //The enum Value contains variants which are added depending on the type of key-value pairs needed
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
@@ -48,9 +57,22 @@ pub struct Trace {
     pub start_node: NodeIndex,
     pub end_node: NodeIndex,
     pub request_type: RequestType,
+    /// Values of the salient parameters of the request type, see `parameterized_type`
+    #[serde(default)]
+    pub request_params: BTreeMap<String, String>,
     pub duration: Duration,
     /// used by osprofiler to find keys to delete from redis
     pub keys: Vec<String>,
+    /// The request was still running when the trace was read, see `completed_prefix`
+    #[serde(default)]
+    pub is_partial: bool,
+    /// The trace was collected right after tracepoints were changed, when latencies may still be
+    /// perturbed by the change; such traces are left out of group statistics
+    #[serde(default)]
+    pub is_transition: bool,
+    /// The request went through a canary host, see `canary`
+    #[serde(default)]
+    pub is_canary: bool,
 }
 
 impl Trace {
@@ -61,8 +83,125 @@ impl Trace {
             start_node: NodeIndex::end(),
             end_node: NodeIndex::end(),
             request_type: RequestType::Unknown,
+            request_params: BTreeMap::new(),
             duration: Duration::new(0, 0),
             keys: Vec::new(),
+            is_partial: false,
+            is_transition: false,
+            is_canary: false,
+        }
+    }
+
+    /// Builds the DAG of a request from readers that record intervals rather than events, in
+    /// any order. Each span becomes an entry and an exit, with its children in between: children
+    /// that overlap in time run concurrently, each one after all of the children that ended
+    /// before it started.
+    pub fn from_spans(base_id: &Uuid, spans: &[Span]) -> Result<Trace, PythiaError> {
+        let ids: HashSet<Uuid> = spans.iter().map(|s| s.span_id).collect();
+        if ids.len() != spans.len() {
+            return Err(PythiaError::ReaderError(format!(
+                "Trace {} has spans with the same id",
+                base_id
+            )));
+        }
+        let mut children: HashMap<Option<Uuid>, Vec<&Span>> = HashMap::new();
+        for span in spans {
+            let parent = span.parent.filter(|p| ids.contains(p));
+            children.entry(parent).or_default().push(span);
+        }
+        for siblings in children.values_mut() {
+            siblings.sort_by_key(|s| (s.start, s.end));
+        }
+        let root = match children.get(&None).map(|roots| roots.as_slice()) {
+            Some(&[root]) => root,
+            roots => {
+                return Err(PythiaError::ReaderError(format!(
+                    "Trace {} has {} root spans",
+                    base_id,
+                    roots.map_or(0, |r| r.len())
+                )))
+            }
+        };
+        let mut trace = Trace::new(base_id);
+        let (start, end) = trace.add_span(root, &children, &[]);
+        if trace.g.node_count() != 2 * spans.len() {
+            return Err(PythiaError::ReaderError(format!(
+                "Trace {} has spans that are not reachable from the root",
+                base_id
+            )));
+        }
+        trace.start_node = start;
+        trace.end_node = end;
+        trace.duration = (root.end - root.start)
+            .to_std()
+            .unwrap_or(Duration::new(0, 0));
+        Ok(trace)
+    }
+
+    /// Adds the span and its children after `preds`, returns its entry and exit
+    fn add_span(
+        &mut self,
+        span: &Span,
+        children: &HashMap<Option<Uuid>, Vec<&Span>>,
+        preds: &[NodeIndex],
+    ) -> (NodeIndex, NodeIndex) {
+        let entry = self.g.add_node(span.entry());
+        for &p in preds {
+            self.add_edge_between(p, entry, EdgeType::ChildOf);
+        }
+        // Children that overlap start after the same predecessors
+        let mut wave_preds = vec![entry];
+        let mut running: Vec<(NaiveDateTime, NodeIndex)> = Vec::new();
+        for child in children.get(&Some(span.span_id)).into_iter().flatten() {
+            if !running.is_empty() && running.iter().all(|&(end, _)| end <= child.start) {
+                wave_preds = running.drain(..).map(|(_, exit)| exit).collect();
+            }
+            let (_, exit) = self.add_span(child, children, &wave_preds);
+            running.push((child.end, exit));
+        }
+        let last: Vec<NodeIndex> = if running.is_empty() {
+            vec![entry]
+        } else {
+            running.into_iter().map(|(_, exit)| exit).collect()
+        };
+        let exit = self.g.add_node(span.exit());
+        for p in last {
+            self.add_edge_between(p, exit, EdgeType::FollowsFrom);
+        }
+        (entry, exit)
+    }
+
+    fn add_edge_between(&mut self, a: NodeIndex, b: NodeIndex, variant: EdgeType) {
+        let duration = (self.g[b].timestamp - self.g[a].timestamp)
+            .to_std()
+            .unwrap_or(Duration::new(0, 0));
+        self.g.add_edge(a, b, DAGEdge { duration, variant });
+    }
+
+    /// For a request that is still running, returns the part of the trace up to the last span
+    /// that finished. Events after that belong to spans that are still running, so their
+    /// latencies aren't known yet. Returns None if no span finished yet.
+    pub fn completed_prefix(&self) -> Option<Trace> {
+        let last_exit = self
+            .g
+            .node_indices()
+            .filter(|&n| self.g[n].variant == EventType::Exit)
+            .max_by_key(|&n| self.g[n].timestamp)?;
+        let cutoff = self.g[last_exit].timestamp;
+        let mut result = self.clone();
+        result.g.retain_nodes(|g, n| g[n].timestamp <= cutoff);
+        result.end_node = last_exit;
+        result.duration = (cutoff - result.g[result.start_node].timestamp)
+            .to_std()
+            .unwrap_or(Duration::new(0, 0));
+        result.is_partial = true;
+        Some(result)
+    }
+
+    pub fn parameterized_type(&self) -> ParameterizedRequestType {
+        ParameterizedRequestType {
+            kind: self.request_type,
+            params: self.request_params.clone(),
         }
     }
 
@@ -97,6 +236,53 @@ impl Trace {
         }
     }
 
+    /// The part of the trace from the first event at tracepoint `a` to the last event at
+    /// tracepoint `b` reachable from it: the events that come after the first and before the
+    /// second. None if `b` can't be reached from `a`.
+    pub fn subtrace_between(&self, a: TracepointID, b: TracepointID) -> Option<Trace> {
+        let mut starts: Vec<_> = self
+            .g
+            .node_indices()
+            .filter(|&n| self.g[n].tracepoint_id == a)
+            .collect();
+        starts.sort_by_key(|&n| self.g[n].timestamp);
+        starts.into_iter().find_map(|start| {
+            let end = Dfs::new(&self.g, start)
+                .iter(&self.g)
+                .filter(|&n| self.g[n].tracepoint_id == b && n != start)
+                .max_by_key(|&n| self.g[n].timestamp)?;
+            Some(self.subtrace(start, end))
+        })
+    }
+
+    /// The part of the trace between the entry and the exit of a span
+    pub fn subtrace_of_span(&self, span: Uuid) -> Option<Trace> {
+        let find = |variant| {
+            self.g
+                .node_indices()
+                .find(|&n| self.g[n].trace_id == span && self.g[n].variant == variant)
+        };
+        Some(self.subtrace(find(EventType::Entry)?, find(EventType::Exit)?))
+    }
+
+    /// Events that come after `start` and before `end`, inclusive
+    fn subtrace(&self, start: NodeIndex, end: NodeIndex) -> Trace {
+        let after: HashSet<NodeIndex> = Dfs::new(&self.g, start).iter(&self.g).collect();
+        let between: HashSet<NodeIndex> = Dfs::new(Reversed(&self.g), end)
+            .iter(Reversed(&self.g))
+            .filter(|n| after.contains(n))
+            .collect();
+        let mut result = self.clone();
+        result.g.retain_nodes(|_, n| between.contains(&n));
+        result.start_node = start;
+        result.end_node = end;
+        result.duration = (self.g[end].timestamp - self.g[start].timestamp)
+            .to_std()
+            .unwrap_or(Duration::new(0, 0));
+        result.keys = Vec::new();
+        result
+    }
+
     /// Return nodes with outdegree == 0
     pub fn possible_end_nodes(&self) -> Vec<NodeIndex> {
         let mut result = Vec::new();
@@ -173,7 +359,7 @@ impl Trace {
                 }
             }
         }
-        eprintln!("Removed {} nodes when pruning", removed_count);
+        debug!("Removed {} nodes when pruning", removed_count);
     }
 
     pub fn get_keys(&self) {
@@ -181,6 +367,80 @@ impl Trace {
             self.g[node].print_key_values();
         }
     }
+
+    /// How much each node could be delayed before the end of the trace is delayed. At a join,
+    /// every predecessor but the latest has the time until the latest one as slack. Nodes that
+    /// don't lead to the end are left out.
+    pub fn slack(&self) -> HashMap<NodeIndex, Duration> {
+        let mut result = HashMap::new();
+        let order = match toposort(&self.g, None) {
+            Ok(o) => o,
+            Err(_) => return result,
+        };
+        result.insert(self.end_node, Duration::new(0, 0));
+        for &nidx in order.iter().rev() {
+            if nidx == self.end_node {
+                continue;
+            }
+            let slack = self
+                .g
+                .neighbors_directed(nidx, Direction::Outgoing)
+                .filter_map(|next| {
+                    let after = result.get(&next)?;
+                    let latest = self
+                        .g
+                        .neighbors_directed(next, Direction::Incoming)
+                        .map(|p| self.g[p].timestamp)
+                        .max()?;
+                    let wait = (latest - self.g[nidx].timestamp).to_std().unwrap_or_default();
+                    Some(wait + *after)
+                })
+                .min();
+            if let Some(slack) = slack {
+                result.insert(nidx, slack);
+            }
+        }
+        result
+    }
+
+    /// Spans off the critical path and how much longer each could take before becoming
+    /// critical, smallest slack first. A span's slack is the slack of its end, or of its start
+    /// if it has no end.
+    pub fn span_slack(&self) -> Vec<SpanSlack> {
+        let slack = self.slack();
+        let mut spans: HashMap<Uuid, SpanSlack> = HashMap::new();
+        for (&nidx, &s) in &slack {
+            let event = &self.g[nidx];
+            match event.variant {
+                EventType::Exit => {}
+                EventType::Entry if !spans.contains_key(&event.trace_id) => {}
+                _ => continue,
+            }
+            spans.insert(
+                event.trace_id,
+                SpanSlack {
+                    span: event.trace_id,
+                    tracepoint_id: event.tracepoint_id,
+                    slack: s,
+                },
+            );
+        }
+        let mut result: Vec<SpanSlack> = spans
+            .into_iter()
+            .map(|(_, s)| s)
+            .filter(|s| s.slack > Duration::new(0, 0))
+            .collect();
+        result.sort_by_key(|s| s.slack);
+        result
+    }
+}
+
+/// See `Trace::span_slack`
+#[derive(Debug, Clone)]
+pub struct SpanSlack {
+    pub span: Uuid,
+    pub tracepoint_id: TracepointID,
+    pub slack: Duration,
 }
 impl Event {
     pub fn print_key_values(&self) {
@@ -216,6 +476,44 @@ pub enum EventType {
     Annotation,
 }
 
+/// An interval, as recorded by span-based tracers like Jaeger. Readers for these tracers build
+/// their traces from spans with `Trace::from_spans`, or turn each span into an entry and an
+/// exit themselves; the rest of the code, like critical path extraction, only sees events.
+#[derive(Debug, Clone)]
+pub struct Span {
+    pub span_id: Uuid,
+    pub parent: Option<Uuid>,
+    pub tracepoint_id: TracepointID,
+    pub start: NaiveDateTime,
+    pub end: NaiveDateTime,
+    /// Kept on the entry event
+    pub key_value_pair: HashMap<String, Value>,
+}
+
+impl Span {
+    pub fn entry(&self) -> Event {
+        Event {
+            trace_id: self.span_id,
+            tracepoint_id: self.tracepoint_id,
+            timestamp: self.start,
+            is_synthetic: false,
+            variant: EventType::Entry,
+            key_value_pair: self.key_value_pair.clone(),
+        }
+    }
+
+    pub fn exit(&self) -> Event {
+        Event {
+            trace_id: self.span_id,
+            tracepoint_id: self.tracepoint_id,
+            timestamp: self.end,
+            is_synthetic: false,
+            variant: EventType::Exit,
+            key_value_pair: HashMap::new(),
+        }
+    }
+}
+
 impl Display for Event {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match &self.variant {
@@ -348,7 +646,96 @@ impl PartialEq<TraceNode> for Event {
 }
 
 lazy_static! {
-    static ref TRACEPOINT_ID_MAP: Mutex<BiMap<String, usize>> = Mutex::new(BiMap::new());
+    static ref TRACEPOINT_ID_MAP: Mutex<Interner> = Mutex::new(Interner::default());
+}
+
+/// Size of the tracepoint name table, see `TracepointID::interner_stats`
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct InternerStats {
+    pub live: usize,
+    /// IDs handed out since the start, including collected ones
+    pub created: usize,
+    pub collected: usize,
+    /// Total length of the live names
+    pub name_bytes: usize,
+}
+
+/// The names behind tracepoint IDs. IDs are never reused, so an ID that was collected can't
+/// come back as another tracepoint.
+#[derive(Default)]
+struct Interner {
+    ids: BiMap<String, usize>,
+    next_id: usize,
+    /// Garbage collection generation in which each ID was last interned
+    last_used: HashMap<usize, usize>,
+    generation: usize,
+    collected: usize,
+}
+
+impl Interner {
+    fn intern(&mut self, name: &str) -> usize {
+        let id = match self.ids.get_by_left(&name.to_string()) {
+            Some(&id) => id,
+            None => {
+                let id = self.next_id;
+                self.next_id += 1;
+                self.ids.insert(name.to_string(), id);
+                id
+            }
+        };
+        self.last_used.insert(id, self.generation);
+        id
+    }
+
+    /// Adds names with fixed IDs, unless one of them has another ID already
+    fn load(&mut self, table: HashMap<String, usize>) -> Result<usize, String> {
+        for (name, &id) in &table {
+            match (self.ids.get_by_left(name), self.ids.get_by_right(&id)) {
+                (Some(&existing), _) if existing != id => {
+                    return Err(format!("{} is already {}, not {}", name, existing, id))
+                }
+                (_, Some(existing)) if existing != name => {
+                    return Err(format!("{} is already {}, not {}", id, existing, name))
+                }
+                _ => {}
+            }
+        }
+        for (name, id) in table.iter() {
+            self.ids.insert(name.clone(), *id);
+            self.last_used.insert(*id, self.generation);
+            self.next_id = self.next_id.max(id + 1);
+        }
+        Ok(table.len())
+    }
+
+    /// Drops the IDs that are not in `live` and were not interned since the last collection, so
+    /// tracepoints of traces that are still being read survive. Returns how many were dropped.
+    fn collect(&mut self, live: &HashSet<usize>) -> usize {
+        let generation = self.generation;
+        let garbage: Vec<usize> = self
+            .ids
+            .right_values()
+            .filter(|id| !live.contains(id))
+            .filter(|id| self.last_used.get(id).map_or(true, |&g| g < generation))
+            .cloned()
+            .collect();
+        for id in &garbage {
+            self.ids.remove_by_right(id);
+            self.last_used.remove(id);
+        }
+        self.collected += garbage.len();
+        self.generation += 1;
+        garbage.len()
+    }
+
+    fn stats(&self) -> InternerStats {
+        InternerStats {
+            live: self.ids.len(),
+            created: self.next_id,
+            collected: self.collected,
+            name_bytes: self.ids.left_values().map(|name| name.len()).sum(),
+        }
+    }
 }
 
 /// We do some tricks to keep tracepoint ids as `usize`s so it uses less memory than strings.
@@ -359,26 +746,52 @@ pub struct TracepointID {
 
 impl TracepointID {
     pub fn to_string(&self) -> String {
-        TRACEPOINT_ID_MAP
-            .lock()
-            .unwrap()
-            .get_by_right(&self.id)
-            .unwrap()
-            .clone()
+        match TRACEPOINT_ID_MAP.lock().unwrap().ids.get_by_right(&self.id) {
+            Some(name) => name.clone(),
+            None => format!("<collected tracepoint {}>", self.id),
+        }
     }
 
     pub fn from_str(s: &str) -> Self {
-        let mut map = TRACEPOINT_ID_MAP.lock().unwrap();
-        match map.get_by_left(&s.to_string()) {
-            Some(&id) => Self { id: id },
-            None => {
-                let id = map.len();
-                map.insert(s.to_string(), id);
-                Self { id: id }
-            }
+        Self {
+            id: TRACEPOINT_ID_MAP.lock().unwrap().intern(s),
         }
     }
 
+    pub fn interner_stats() -> InternerStats {
+        TRACEPOINT_ID_MAP.lock().unwrap().stats()
+    }
+
+    /// Writes every name and its ID, so a later run can give the tracepoints the same IDs.
+    /// Path hashes depend on the IDs.
+    pub fn dump_interner(file: &Path) -> std::io::Result<()> {
+        let table: HashMap<String, usize> = TRACEPOINT_ID_MAP
+            .lock()
+            .unwrap()
+            .ids
+            .iter()
+            .map(|(name, &id)| (name.clone(), id))
+            .collect();
+        serde_json::to_writer(std::fs::File::create(file)?, &table)?;
+        Ok(())
+    }
+
+    /// Reads a table written by `dump_interner`. Fails without changing anything if a name
+    /// already has a different ID, so it should be loaded before anything else is interned.
+    pub fn load_interner(file: &Path) -> Result<usize, String> {
+        let table = std::fs::File::open(file)
+            .map_err(|e| e.to_string())
+            .and_then(|f| serde_json::from_reader(f).map_err(|e| e.to_string()))?;
+        TRACEPOINT_ID_MAP.lock().unwrap().load(table)
+    }
+
+    /// Forgets the names of tracepoints that are not in `live` and did not show up since the
+    /// last collection. Returns how many were forgotten.
+    pub fn collect_garbage(live: &HashSet<TracepointID>) -> usize {
+        let live = live.iter().map(|tp| tp.id).collect();
+        TRACEPOINT_ID_MAP.lock().unwrap().collect(&live)
+    }
+
     pub fn bytes(&self) -> [u8; 8] {
         self.id.to_ne_bytes()
     }
@@ -433,3 +846,92 @@ impl<'de> Deserialize<'de> for TracepointID {
         d.deserialize_str(TracepointIDVisitor)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interner_collects_unused_ids() {
+        let mut interner = Interner::default();
+        let kept = interner.intern("kept");
+        let dropped = interner.intern("dropped");
+        let recent = interner.intern("recent");
+        let live = vec![kept].into_iter().collect();
+        // Everything was interned since the last collection
+        assert_eq!(interner.collect(&live), 0);
+        assert_eq!(interner.intern("recent"), recent);
+        assert_eq!(interner.collect(&live), 1);
+        assert!(interner.ids.get_by_right(&dropped).is_none());
+        assert_eq!(interner.intern("dropped"), 3);
+
+        let stats = interner.stats();
+        assert_eq!((stats.live, stats.created, stats.collected), (3, 4, 1));
+
+        let mut other = Interner::default();
+        let table: HashMap<String, usize> = interner
+            .ids
+            .iter()
+            .map(|(n, &id)| (n.clone(), id))
+            .collect();
+        assert_eq!(other.load(table.clone()), Ok(3));
+        assert_eq!(other.intern("kept"), kept);
+        assert_eq!(other.intern("new"), 4);
+        let mut clashing = table;
+        clashing.insert("kept".to_string(), 7);
+        assert!(other.load(clashing).is_err());
+    }
+
+    #[test]
+    fn builds_traces_from_spans() {
+        use crate::critical::CriticalPath;
+        use crate::critical::Path as _;
+
+        let epoch = NaiveDateTime::parse_from_str("2000/01/01 00:00", "%Y/%m/%d %H:%M").unwrap();
+        let at = |ms| epoch + chrono::Duration::milliseconds(ms);
+        let span = |id: u128, parent: Option<u128>, name, start, end| Span {
+            span_id: Uuid::from_u128(id),
+            parent: parent.map(Uuid::from_u128),
+            tracepoint_id: TracepointID::from_str(name),
+            start: at(start),
+            end: at(end),
+            key_value_pair: HashMap::new(),
+        };
+        // Two overlapping children, then one after both
+        let spans = vec![
+            span(4, Some(1), "after", 60, 90),
+            span(2, Some(1), "short", 10, 30),
+            span(1, None, "root", 0, 100),
+            span(3, Some(1), "long", 20, 50),
+        ];
+        let trace = Trace::from_spans(&Uuid::from_u128(0), &spans).unwrap();
+        assert_eq!(trace.g.node_count(), 8);
+        assert_eq!(trace.duration, Duration::from_millis(100));
+        let after = trace
+            .g
+            .node_indices()
+            .find(|&n| trace.g[n].tracepoint_id == TracepointID::from_str("after"))
+            .unwrap();
+        assert_eq!(
+            trace
+                .g
+                .neighbors_directed(after, Direction::Incoming)
+                .count(),
+            2
+        );
+
+        let path = CriticalPath::from_trace(&trace).unwrap();
+        let on_path: Vec<String> = path
+            .g
+            .g
+            .node_indices()
+            .filter(|&n| path.g.g[n].variant == EventType::Entry)
+            .map(|n| path.at(n).to_string())
+            .collect();
+        assert!(on_path.contains(&"long".to_string()));
+        assert!(!on_path.contains(&"short".to_string()));
+
+        let orphans = vec![span(1, None, "root", 0, 10), span(2, None, "other", 0, 10)];
+        assert!(Trace::from_spans(&Uuid::from_u128(0), &orphans).is_err());
+    }
+}
@@ -0,0 +1,262 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Typed latency units used in statistics.
+//!
+//! All latency statistics (means, variances, coefficients of variance) are computed from
+//! `Duration`s through the helpers in this file, so that every value is in nanoseconds (or
+//! nanoseconds squared for variances) and values computed in different places can be compared.
+
+use std::fmt;
+use std::fmt::Display;
+use std::time::Duration;
+
+use histogram::Histogram;
+use serde::Serialize;
+
+/// Percentiles of `LatencyStats` are kept to two significant digits
+const HISTOGRAM_PRECISION: u32 = 2;
+/// Longer latencies are counted as this long in percentiles
+const HISTOGRAM_MAX_MICROS: u64 = 3_600_000_000;
+
+/// A latency in nanoseconds
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, PartialOrd)]
+pub struct Nanos(pub f64);
+
+/// A latency variance in nanoseconds squared
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, PartialOrd)]
+pub struct NanosSquared(pub f64);
+
+impl Nanos {
+    pub fn from_duration(d: Duration) -> Nanos {
+        Nanos(d.as_nanos() as f64)
+    }
+
+    /// Negative values are clamped to zero
+    pub fn to_duration(self) -> Duration {
+        Duration::from_nanos(self.0.max(0.0).round() as u64)
+    }
+
+    pub fn as_millis(self) -> f64 {
+        self.0 / 1_000_000.0
+    }
+
+    pub fn as_secs(self) -> f64 {
+        self.0 / 1_000_000_000.0
+    }
+
+    pub fn is_zero(self) -> bool {
+        self.0 == 0.0
+    }
+}
+
+impl From<Duration> for Nanos {
+    fn from(d: Duration) -> Nanos {
+        Nanos::from_duration(d)
+    }
+}
+
+impl NanosSquared {
+    /// Standard deviation corresponding to this variance
+    pub fn sqrt(self) -> Nanos {
+        Nanos(self.0.sqrt())
+    }
+
+    pub fn is_zero(self) -> bool {
+        self.0 == 0.0
+    }
+}
+
+impl Display for Nanos {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Display for NanosSquared {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Mean of the durations
+pub fn mean<'a, I: Iterator<Item = &'a Duration>>(durations: I) -> Nanos {
+    Nanos(stats::mean(durations.map(|&d| Nanos::from_duration(d).0)))
+}
+
+/// Population variance of the durations
+pub fn variance<'a, I: Iterator<Item = &'a Duration>>(durations: I) -> NanosSquared {
+    NanosSquared(stats::variance(
+        durations.map(|&d| Nanos::from_duration(d).0),
+    ))
+}
+
+/// Nearest-rank percentile of the durations, with `p` between 0 and 100. Zero if empty.
+pub fn percentile(durations: &[Duration], p: f64) -> Nanos {
+    if durations.is_empty() {
+        return Nanos(0.0);
+    }
+    let mut sorted = durations.to_vec();
+    sorted.sort();
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    Nanos::from_duration(sorted[rank.max(1).min(sorted.len()) - 1])
+}
+
+/// Statistics of a stream of latencies that are updated one sample at a time, without keeping
+/// the samples. The mean and variance are exact (Welford's algorithm); percentiles come from a
+/// histogram of microseconds, so they are approximate and the memory used is fixed (~20KB).
+#[derive(Debug, Clone)]
+pub struct LatencyStats {
+    count: u64,
+    mean: f64,
+    /// Sum of squared differences from the mean
+    m2: f64,
+    histogram: Histogram,
+}
+
+impl LatencyStats {
+    pub fn new() -> Self {
+        LatencyStats {
+            count: 0,
+            mean: 0.0,
+            m2: 0.0,
+            histogram: Histogram::configure()
+                .precision(HISTOGRAM_PRECISION)
+                .max_value(HISTOGRAM_MAX_MICROS)
+                .build()
+                .unwrap(),
+        }
+    }
+
+    pub fn add(&mut self, d: Duration) {
+        let x = Nanos::from_duration(d).0;
+        self.count += 1;
+        let delta = x - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (x - self.mean);
+        self.histogram.increment(micros(d)).unwrap();
+    }
+
+    /// Undoes an earlier `add` of the same duration
+    pub fn remove(&mut self, d: Duration) {
+        if self.count <= 1 {
+            *self = LatencyStats::new();
+            return;
+        }
+        let x = Nanos::from_duration(d).0;
+        self.count -= 1;
+        let delta = x - self.mean;
+        self.mean -= delta / self.count as f64;
+        self.m2 = (self.m2 - delta * (x - self.mean)).max(0.0);
+        self.histogram.decrement(micros(d)).ok();
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn mean(&self) -> Nanos {
+        Nanos(self.mean)
+    }
+
+    /// Population variance, same as `variance`
+    pub fn variance(&self) -> NanosSquared {
+        if self.count == 0 {
+            NanosSquared(0.0)
+        } else {
+            NanosSquared(self.m2 / self.count as f64)
+        }
+    }
+
+    /// Approximate percentile, with `p` between 0 and 100. Zero if there are no samples.
+    pub fn percentile(&self, p: f64) -> Nanos {
+        match self.histogram.percentile(p) {
+            Ok(v) => Nanos(v as f64 * 1000.0),
+            Err(_) => Nanos(0.0),
+        }
+    }
+
+    pub fn min(&self) -> Nanos {
+        self.percentile(0.0)
+    }
+
+    pub fn max(&self) -> Nanos {
+        self.percentile(100.0)
+    }
+}
+
+fn micros(d: Duration) -> u64 {
+    (d.as_micros() as u64).min(HISTOGRAM_MAX_MICROS)
+}
+
+/// Coefficient of variance; unitless. Zero if the mean is zero.
+pub fn cv(mean: Nanos, variance: NanosSquared) -> f64 {
+    if mean.is_zero() {
+        0.0
+    } else {
+        variance.sqrt().0 / mean.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn millis(v: &[u64]) -> Vec<Duration> {
+        v.iter().map(|&x| Duration::from_millis(x)).collect()
+    }
+
+    #[test]
+    fn known_fixture() {
+        let durations = millis(&[1, 2, 3]);
+        assert_eq!(mean(durations.iter()), Nanos(2_000_000.0));
+        let var = variance(durations.iter());
+        assert!((var.0 - 2e12 / 3.0).abs() < 1.0);
+        assert!((cv(mean(durations.iter()), var) - (2.0f64 / 3.0).sqrt() / 2.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn units_agree() {
+        // Same latencies given at different granularities give the same statistics
+        let a = millis(&[10, 20, 60]);
+        let b: Vec<Duration> = [10_000u64, 20_000, 60_000]
+            .iter()
+            .map(|&x| Duration::from_micros(x))
+            .collect();
+        assert_eq!(variance(a.iter()), variance(b.iter()));
+        assert_eq!(Nanos::from_duration(a[2]).as_millis(), 60.0);
+        assert_eq!(Nanos(1.5e9).to_duration(), Duration::from_millis(1500));
+        assert_eq!(cv(Nanos(0.0), NanosSquared(4.0)), 0.0);
+    }
+
+    #[test]
+    fn percentiles() {
+        let durations = millis(&[5, 1, 4, 2, 3]);
+        assert_eq!(percentile(&durations, 50.0), Nanos(3_000_000.0));
+        assert_eq!(percentile(&durations, 99.0), Nanos(5_000_000.0));
+        assert_eq!(percentile(&durations, 0.0), Nanos(1_000_000.0));
+        assert_eq!(percentile(&[], 50.0), Nanos(0.0));
+    }
+
+    #[test]
+    fn running_stats_match_batch() {
+        let durations = millis(&[10, 20, 60, 20, 20]);
+        let mut stats = LatencyStats::new();
+        for d in &durations {
+            stats.add(*d);
+        }
+        stats.add(Duration::from_millis(500));
+        stats.remove(Duration::from_millis(500));
+        assert_eq!(stats.count(), 5);
+        assert!((stats.mean().0 - mean(durations.iter()).0).abs() < 1e-3);
+        assert!((stats.variance().0 / variance(durations.iter()).0 - 1.0).abs() < 1e-9);
+        // Within the two digits the histogram keeps
+        assert!((stats.percentile(50.0).0 / 20_000_000.0 - 1.0).abs() < 0.01);
+        assert!((stats.max().0 / 60_000_000.0 - 1.0).abs() < 0.01);
+    }
+}
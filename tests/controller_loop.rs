@@ -0,0 +1,413 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! The whole controller loop, against a fake agent and a fake redis.
+//!
+//! pythia_controller runs as it would on a cluster, with `PYTHIA_CONFIG` pointing it at a redis
+//! and an agent served from this process. Request ids are pushed to the redis list the agents
+//! write to, and the agent answers with the spans of `tests/fixtures/osprofiler` under each id,
+//! stretched so the requests take different times. Like OSProfiler, it leaves out the spans of
+//! disabled tracepoints, so the controller only sees what it enabled. The test runs a few
+//! decision cycles and checks that the requests are grouped and that the controller enables
+//! tracepoints for the group.
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use chrono::NaiveDateTime;
+use futures::future::Future;
+use futures::stream::Stream;
+use hyper::service::service_fn;
+use hyper::Body;
+use hyper::Request;
+use hyper::Response;
+use hyper::Server;
+use jsonrpc_core::IoHandler;
+use jsonrpc_core::Params;
+use serde_json::Value;
+use uuid::Uuid;
+
+use pythia::manifest::Manifest;
+use pythia::reader::reader_from_settings;
+use pythia::settings::ApplicationType;
+use pythia::settings::Settings;
+use pythia_common::NodeStats;
+use pythia_common::OSProfilerSpan;
+use pythia_common::RequestType;
+use pythia_common::SpanBatch;
+use pythia_common::SpanCount;
+
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.6f";
+const DECISION_CYCLES: usize = 3;
+/// The controller should be done long before this
+const TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Answers the redis commands the controller's readers send, from one list of request ids
+struct FakeRedis {
+    url: String,
+    traces: Arc<Mutex<VecDeque<String>>>,
+}
+
+impl FakeRedis {
+    fn start() -> FakeRedis {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("redis://{}", listener.local_addr().unwrap());
+        let traces = Arc::new(Mutex::new(VecDeque::new()));
+        let list = traces.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let list = list.clone();
+                thread::spawn(move || serve_redis(stream.unwrap(), &list));
+            }
+        });
+        FakeRedis { url, traces }
+    }
+
+    fn push_trace(&self, base_id: &Uuid) {
+        self.traces
+            .lock()
+            .unwrap()
+            .push_back(base_id.to_hyphenated().to_string());
+    }
+}
+
+/// Reads commands as arrays of bulk strings, until the connection is closed
+fn serve_redis(stream: TcpStream, traces: &Mutex<VecDeque<String>>) {
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+            return;
+        }
+        let argc: usize = line.trim_start_matches('*').trim().parse().unwrap();
+        let mut args = Vec::new();
+        for _ in 0..argc {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            let len: usize = line.trim_start_matches('$').trim().parse().unwrap();
+            let mut arg = vec![0; len + 2];
+            reader.read_exact(&mut arg).unwrap();
+            arg.truncate(len);
+            args.push(String::from_utf8(arg).unwrap());
+        }
+        let reply = match args[0].to_uppercase().as_str() {
+            "LPOP" => match traces.lock().unwrap().pop_front() {
+                Some(id) => format!("${}\r\n{}\r\n", id.len(), id),
+                None => "$-1\r\n".to_string(),
+            },
+            "FLUSHALL" => {
+                traces.lock().unwrap().clear();
+                "+OK\r\n".to_string()
+            }
+            "PING" => "+PONG\r\n".to_string(),
+            command => format!("-ERR unknown command '{}'\r\n", command),
+        };
+        writer.write_all(reply.as_bytes()).unwrap();
+    }
+}
+
+/// Tracepoint settings of the fake agent; request types are ignored
+#[derive(Default)]
+struct AgentState {
+    /// What `set_all_tracepoints` last set every tracepoint to
+    all: bool,
+    /// Tracepoints set since then, without the leading `/`
+    tracepoints: HashMap<String, bool>,
+    /// Every tracepoint `set_tracepoints` turned on, in order
+    enabled: Vec<String>,
+}
+
+impl AgentState {
+    fn is_enabled(&self, tracepoint: &str) -> bool {
+        *self
+            .tracepoints
+            .get(tracepoint.trim_start_matches('/'))
+            .unwrap_or(&self.all)
+    }
+}
+
+/// An agent serving the spans of one canned request under any base id
+struct FakeAgent {
+    url: String,
+    state: Arc<Mutex<AgentState>>,
+}
+
+impl FakeAgent {
+    fn start(fixture: Vec<Value>) -> FakeAgent {
+        let state = Arc::new(Mutex::new(AgentState::default()));
+        let io = Arc::new(agent_methods(fixture, state.clone()));
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let new_service = move || {
+                let io = io.clone();
+                service_fn(move |req: Request<Body>| {
+                    let io = io.clone();
+                    req.into_body().concat2().map(move |body| {
+                        let reply = io
+                            .handle_request_sync(&String::from_utf8_lossy(&body))
+                            .unwrap_or_default();
+                        Response::builder()
+                            .header("Content-Type", "application/json")
+                            .body(Body::from(reply))
+                            .unwrap()
+                    })
+                })
+            };
+            let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(new_service);
+            tx.send(server.local_addr()).unwrap();
+            hyper::rt::run(server.map_err(|e| panic!("Fake agent failed: {}", e)));
+        });
+        FakeAgent {
+            url: format!("http://{}", rx.recv().unwrap()),
+            state,
+        }
+    }
+}
+
+fn agent_methods(fixture: Vec<Value>, state: Arc<Mutex<AgentState>>) -> IoHandler {
+    let mut io = IoHandler::new();
+    let s = state.clone();
+    io.add_method("set_all_tracepoints", move |params: Params| {
+        let (to_write,): ([u8; 1],) = params.parse()?;
+        let mut s = s.lock().unwrap();
+        s.all = to_write == *b"1";
+        s.tracepoints.clear();
+        Ok(Value::Null)
+    });
+    let s = state.clone();
+    io.add_method("set_tracepoints", move |params: Params| {
+        let (settings,): (Vec<(String, Option<RequestType>, [u8; 1])>,) = params.parse()?;
+        let mut s = s.lock().unwrap();
+        for (tracepoint, _, to_write) in settings {
+            let tracepoint = tracepoint.trim_start_matches('/').to_string();
+            if to_write == *b"1" {
+                s.enabled.push(tracepoint.clone());
+            }
+            s.tracepoints.insert(tracepoint, to_write == *b"1");
+        }
+        Ok(Value::Null)
+    });
+    let (f, s) = (fixture.clone(), state.clone());
+    io.add_method("get_span_count", move |params: Params| {
+        let (trace_id,): (String,) = params.parse()?;
+        let base_id = Uuid::parse_str(&trace_id).unwrap();
+        let spans = canned_spans(&f, base_id, &s.lock().unwrap());
+        Ok(serde_json::to_value(SpanCount::of(&base_id, &spans)).unwrap())
+    });
+    io.add_method("get_trace_fragment", move |params: Params| {
+        let (trace_id,): (String,) = params.parse()?;
+        let base_id = Uuid::parse_str(&trace_id).unwrap();
+        let batch = SpanBatch {
+            host: "fake".to_string(),
+            base_id,
+            spans: canned_spans(&fixture, base_id, &state.lock().unwrap()),
+        };
+        Ok(serde_json::to_value(batch).unwrap())
+    });
+    io.add_method("read_node_stats", |_| {
+        let stats = NodeStats {
+            receive_bytes_per_sec: 0,
+            transmit_bytes_per_sec: 0,
+            receive_drop_per_sec: 0,
+            transmit_drop_per_sec: 0,
+            load_avg_1_min: 0.0,
+            load_avg_5_min: 0.0,
+            tasks_runnable: 0,
+            trace_input_kbps: 0.0,
+            agent_cpu_time: 0.0,
+            trace_size: 0,
+            expired_keys: 0,
+            expired_bytes: 0,
+            redis_shards: Vec::new(),
+        };
+        Ok(serde_json::to_value(stats).unwrap())
+    });
+    io.add_method("free_keys", |_| Ok(Value::Null));
+    io.add_method("ping", |_| Ok(Value::String("pong".to_string())));
+    io
+}
+
+/// The spans of the fixture under another base id, taking 1 to 4 times as long depending on the
+/// id. Spans of disabled tracepoints are left out, and their children moved to their parent.
+fn canned_spans(fixture: &[Value], base_id: Uuid, state: &AgentState) -> Vec<OSProfilerSpan> {
+    let factor = 1 + (base_id.as_bytes()[0] % 4) as i32;
+    let timestamp = |span: &Value| {
+        NaiveDateTime::parse_from_str(span["timestamp"].as_str().unwrap(), TIMESTAMP_FORMAT)
+            .unwrap()
+    };
+    let start = timestamp(&fixture[0]);
+    let old_base_id = fixture[0]["base_id"].as_str().unwrap();
+    let base_id = base_id.to_hyphenated().to_string();
+    // Span ids of the skipped spans, with the closest parent that is kept
+    let mut skipped: HashMap<String, String> = HashMap::new();
+    let mut result = Vec::new();
+    for span in fixture {
+        let mut parent = span["parent_id"].as_str().unwrap().to_string();
+        while let Some(p) = skipped.get(&parent) {
+            parent = p.clone();
+        }
+        if !state.is_enabled(span["tracepoint_id"].as_str().unwrap()) {
+            skipped.insert(span["trace_id"].as_str().unwrap().to_string(), parent);
+            continue;
+        }
+        if parent == old_base_id {
+            parent = base_id.clone();
+        }
+        let at = start + (timestamp(span) - start) * factor;
+        let mut span = span.clone();
+        span["parent_id"] = Value::String(parent);
+        span["base_id"] = Value::String(base_id.clone());
+        span["timestamp"] = Value::String(at.format(TIMESTAMP_FORMAT).to_string());
+        result.push(serde_json::from_value(span).unwrap());
+    }
+    result
+}
+
+fn root() -> &'static Path {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+}
+
+/// The installed settings with some values replaced; `[stopping_condition]` stops after
+/// `DECISION_CYCLES`
+fn write_settings(path: &Path, values: &[(&str, String)]) {
+    let installed = fs::read_to_string(root().join("etc/pythia/controller.toml")).unwrap();
+    let mut lines = Vec::new();
+    for line in installed.lines() {
+        let key = line.split(" = ").next().unwrap();
+        match values.iter().find(|(k, _)| *k == key) {
+            Some((k, v)) => lines.push(format!("{} = {:?}", k, v)),
+            None if key == "targets" => lines.push(format!("max_cycles = {}", DECISION_CYCLES)),
+            None => lines.push(line.to_string()),
+        }
+    }
+    for (key, _) in values {
+        assert!(
+            lines.iter().any(|l| l.starts_with(&format!("{} = ", key))),
+            "{} is not in controller.toml",
+            key
+        );
+    }
+    fs::write(path, lines.join("\n")).unwrap();
+}
+
+/// A manifest of the fixture trace, with everything enabled
+fn write_manifest(path: &Path) {
+    let settings = Settings::read_from(&root().join("etc/pythia/controller.toml"));
+    assert_eq!(settings.application, ApplicationType::OpenStack);
+    let mut reader = reader_from_settings(&settings);
+    let fixture = root().join("tests/fixtures/osprofiler");
+    let traces = reader.read_dir(fixture.to_str().unwrap());
+    Manifest::from_trace_list(&traces).to_file(path);
+}
+
+#[test]
+fn groups_requests_and_enables_tracepoints() {
+    let dir = std::env::temp_dir().join(format!("pythia-controller-loop-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let fixture: Vec<Value> = serde_json::from_reader(
+        File::open(root().join("tests/fixtures/osprofiler/server_create.json")).unwrap(),
+    )
+    .unwrap();
+    let redis = FakeRedis::start();
+    let agent = FakeAgent::start(fixture);
+    let manifest_file = dir.join("manifest.json");
+    write_manifest(&manifest_file);
+    let settings_file = dir.join("controller.toml");
+    let path = |p: &PathBuf| p.to_str().unwrap().to_string();
+    write_settings(
+        &settings_file,
+        &[
+            ("manifest_file", path(&manifest_file)),
+            ("redis_url", redis.url.clone()),
+            ("pythia_clients", agent.url.clone()),
+            ("search_strategy", "Flat".to_string()),
+            ("problem_selection", "variance".to_string()),
+            ("span_cache_size", "0".to_string()),
+            ("jiffy_ms", "200".to_string()),
+            ("decision_epoch_secs", "1".to_string()),
+            ("transition_period_secs", "0".to_string()),
+            ("max_tracepoint_changes_per_min", String::new()),
+            ("tracepoint_change_jitter_ms", "0".to_string()),
+            ("reconcile_interval_secs", String::new()),
+            ("rpc_retries", "0".to_string()),
+            ("rpc_timeout_secs", "5".to_string()),
+            ("results_db", String::new()),
+            ("retention_dir", String::new()),
+            ("audit_log", String::new()),
+            ("epoch_dir", String::new()),
+            ("lineage_file", path(&dir.join("lineage.json"))),
+            ("api_address", String::new()),
+        ],
+    );
+
+    let output = dir.join("output.txt");
+    let log = dir.join("controller.log");
+    let mut controller = Command::new(env!("CARGO_BIN_EXE_pythia_controller"))
+        .arg(&output)
+        .env("PYTHIA_CONFIG", &settings_file)
+        .stderr(File::create(&log).unwrap())
+        .spawn()
+        .unwrap();
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = controller.try_wait().unwrap() {
+            break status;
+        }
+        if started.elapsed() > TIMEOUT {
+            controller.kill().ok();
+            panic!("The controller didn't stop, see {:?}", log);
+        }
+        redis.push_trace(&Uuid::new_v4());
+        thread::sleep(Duration::from_millis(50));
+    };
+    assert!(status.success(), "The controller failed, see {:?}", log);
+
+    let report: Value =
+        serde_json::from_reader(File::open(format!("{}.report.json", output.display())).unwrap())
+            .unwrap();
+    assert_eq!(report["decision_cycles"], DECISION_CYCLES);
+    let groups = report["problem_groups"].as_array().unwrap();
+    assert!(!groups.is_empty(), "No problem groups, see {:?}", log);
+    assert!(groups
+        .iter()
+        .all(|g| g["request_type"] == "ServerCreate" && g["traces"].as_u64().unwrap() > 3));
+    // The decisions reached the agent
+    let decisions = report["enabled"].as_array().unwrap();
+    assert!(!decisions.is_empty(), "Nothing was enabled, see {:?}", log);
+    let enabled = &agent.state.lock().unwrap().enabled;
+    let lineage: Value =
+        serde_json::from_reader(File::open(dir.join("lineage.json")).unwrap()).unwrap();
+    for decision in decisions {
+        assert!(groups.iter().any(|g| g["hash"] == decision["group"]));
+        let group = decision["group"].as_str().unwrap();
+        assert!(!lineage["steps"][group].as_array().unwrap().is_empty());
+        for tracepoint in decision["tracepoints"].as_array().unwrap() {
+            let tracepoint = tracepoint[0].as_str().unwrap().trim_start_matches('/');
+            assert!(enabled.iter().any(|e| e == tracepoint));
+        }
+    }
+    fs::remove_dir_all(&dir).ok();
+}
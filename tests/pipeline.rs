@@ -0,0 +1,39 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! `pythia pipeline` over the golden OSProfiler trace, with no manifest installed.
+
+use std::path::Path;
+
+use pythia::replay_pipeline;
+use pythia::settings::Settings;
+
+fn settings() -> Settings {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let mut settings = Settings::read_from(&root.join("etc/pythia/controller.toml"));
+    // The manifest is built from the input instead
+    settings.manifest_file = std::env::temp_dir().join("pythia-pipeline-no-manifest.json");
+    settings
+}
+
+#[test]
+fn replays_a_folder() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let input = root.join("tests/fixtures/osprofiler");
+    let (cycles, _) = replay_pipeline(&settings(), input.to_str().unwrap(), 3).unwrap();
+    assert_eq!(cycles.len(), 1);
+    assert_eq!(cycles[0].traces, 1);
+    assert!(cycles[0].paths > 0);
+}
+
+#[test]
+fn missing_input_is_an_error() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let input = root.join("tests/fixtures/does-not-exist");
+    assert!(replay_pipeline(&settings(), input.to_str().unwrap(), 3).is_err());
+}
@@ -0,0 +1,160 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Golden traces for the readers.
+//!
+//! Each folder under `tests/fixtures` has a trace as the tracing infrastructure writes it. The
+//! tests pin the shape of the DAG each reader builds from it, so that changes to the parsing code
+//! (e.g., a new `AnnotationEnum` variant that the untagged enums try first) show up as failures
+//! instead of silently different graphs.
+
+use std::path::Path;
+use std::time::Duration;
+
+use pythia::critical::CriticalPath;
+use pythia::reader::reader_from_settings;
+use pythia::settings::ApplicationType;
+use pythia::settings::OutlierAction;
+use pythia::settings::Settings;
+use pythia::trace::Trace;
+use pythia::trace::TracepointID;
+use pythia_common::RequestType;
+
+/// The installed settings, for another application
+fn fixture_settings(application: ApplicationType) -> Settings {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let mut settings = Settings::read_from(&root.join("etc/pythia/controller.toml"));
+    settings.application = application;
+    settings
+}
+
+fn fixture_dir(folder: &str) -> String {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    root.join("tests/fixtures")
+        .join(folder)
+        .to_str()
+        .unwrap()
+        .to_string()
+}
+
+/// The only trace in `tests/fixtures/<folder>`, read with the default settings of `application`
+fn read_fixture(application: ApplicationType, folder: &str) -> Trace {
+    let mut reader = reader_from_settings(&fixture_settings(application));
+    let mut traces = reader.read_dir(&fixture_dir(folder));
+    assert_eq!(traces.len(), 1);
+    traces.pop().unwrap()
+}
+
+/// Node and edge counts of the DAG, and of its critical path
+fn assert_shape(trace: &Trace, nodes: usize, edges: usize, critical_nodes: usize) {
+    assert_eq!(trace.g.node_count(), nodes);
+    assert_eq!(trace.g.edge_count(), edges);
+    let path = CriticalPath::from_trace(trace).unwrap();
+    assert_eq!(path.g.g.node_count(), critical_nodes);
+    assert_eq!(path.duration, trace.duration);
+}
+
+fn assert_ends(trace: &Trace, start: &str, end: &str) {
+    assert_eq!(
+        trace.g[trace.start_node].tracepoint_id,
+        TracepointID::from_str(start)
+    );
+    assert_eq!(
+        trace.g[trace.end_node].tracepoint_id,
+        TracepointID::from_str(end)
+    );
+}
+
+#[test]
+fn osprofiler_server_create() {
+    let trace = read_fixture(ApplicationType::OpenStack, "osprofiler");
+    // Two child spans in sequence; the annotations inside the first one are on the critical path
+    assert_shape(&trace, 8, 7, 8);
+    assert_eq!(
+        trace.request_type,
+        RequestType::from_str("ServerCreate").unwrap()
+    );
+    assert_eq!(trace.duration, Duration::from_millis(10));
+    // Only the salient parameters of ServerCreate are kept
+    assert_eq!(trace.request_params.len(), 2);
+    assert_eq!(trace.request_params["flavor"], "m1.tiny");
+    assert_eq!(trace.request_params["image"], "cirros");
+    let take_action = "/usr/local/lib/python3.6/dist-packages/openstackclient/compute/v2/\
+        server.py:662:openstackclient.compute.v2.server.CreateServer.take_action";
+    assert_ends(&trace, take_action, take_action);
+}
+
+#[test]
+fn osprofiler_clock_reset() {
+    // The scheduler span starts an hour before its parent
+    let mut settings = fixture_settings(ApplicationType::OpenStack);
+    settings.outlier_threshold = Some(Duration::from_secs(1));
+    settings.outlier_action = OutlierAction::Drop;
+    let mut reader = reader_from_settings(&settings);
+    let trace = reader
+        .read_dir(&fixture_dir("osprofiler_clock_reset"))
+        .remove(0);
+    assert_shape(&trace, 6, 5, 6);
+    let report = reader.parse_report(&trace.base_id.to_string()).unwrap();
+    assert_eq!(report.skipped.len(), 1);
+
+    // Moved to just after the client span starts, so it is kept
+    settings.outlier_action = OutlierAction::Clamp;
+    let mut reader = reader_from_settings(&settings);
+    let trace = reader
+        .read_dir(&fixture_dir("osprofiler_clock_reset"))
+        .remove(0);
+    assert_eq!(trace.g.node_count(), 8);
+    assert_eq!(trace.duration, Duration::from_millis(10));
+    let report = reader.parse_report(&trace.base_id.to_string()).unwrap();
+    assert_eq!(report.warnings.len(), 1);
+}
+
+#[test]
+fn hdfs_put() {
+    let trace = read_fixture(ApplicationType::HDFS, "hdfs");
+    // A fork and a join; the later branch is critical
+    assert_shape(&trace, 4, 4, 3);
+    assert_eq!(trace.request_type, RequestType::Unknown);
+    assert_eq!(trace.duration, Duration::from_millis(7));
+    assert_eq!(trace.request_params["write_size"], "<=4096");
+    assert_ends(&trace, "FsShell.java:320", "DFSOutputStream.java:2271");
+}
+
+#[test]
+fn deathstar_compose_post() {
+    let trace = read_fixture(ApplicationType::DEATHSTAR, "deathstar");
+    // The baggage event is dropped, and its child attached to its parent
+    assert_shape(&trace, 3, 2, 3);
+    assert_eq!(trace.request_type, RequestType::Unknown);
+    assert_eq!(trace.duration, Duration::from_millis(6));
+    assert_ends(
+        &trace,
+        "/social-network/src/nginx/compose_post.lua:12",
+        "/social-network/src/nginx/compose_post.lua:40",
+    );
+}
+
+#[test]
+fn uber_request() {
+    let trace = read_fixture(ApplicationType::Uber, "uber");
+    // Entry and exit of the root and two sequential children
+    assert_shape(&trace, 6, 5, 6);
+    assert_eq!(trace.request_type, RequestType::Unknown);
+    assert_eq!(trace.duration, Duration::from_millis(10));
+    assert_ends(&trace, "0a0b0c0d0e0f1011", "0a0b0c0d0e0f1011");
+}
+
+#[test]
+fn uber_dataset() {
+    // The spans of the JSON trace as CSV rows, out of order and with a malformed row
+    let trace = read_fixture(ApplicationType::Uber, "uber_dataset");
+    assert_shape(&trace, 6, 5, 6);
+    assert_eq!(trace.duration, Duration::from_millis(10));
+    assert_ends(&trace, "0a0b0c0d0e0f1011", "0a0b0c0d0e0f1011");
+}
@@ -0,0 +1,97 @@
+#!/bin/bash
+SECONDS=0
+HMAC_KEY=Devstack1
+
+# set verbosity of output
+if [[ $3 = "-v" ]]
+then
+    set -x
+fi
+
+log() {
+    echo "[$$] $(date +'%T'): $@"
+}
+
+# set number of iterations
+if [[ $2 =~ ^-?[0-9]+$ ]]
+then
+    TRACE_FILE=$1
+    iter=$2
+else
+    log "Usage: $0 <trace_file> <iterations> (<-v> option)"
+    exit 1
+fi
+
+poll_status () {
+    # Waits until the server or volume reaches the given status
+    local kind
+    local id
+    local target
+    local status
+    kind=$1
+    id=$2
+    target=$3
+    openstack $kind list &> $tmpfile
+    status=$(grep $id $tmpfile | awk '{print $6}')
+    if [[ $status == 'ERROR' || $status == 'error' ]]
+    then
+        log "$kind $id entered ERROR state: exiting"
+        cleanup
+        exit
+    fi
+    if [[ $status != $target ]]
+    then
+        sleep 2
+        poll_status $kind $id $target
+    fi
+}
+
+attach_volume () {
+    # Requires server and volume ids
+    local trace_id
+    openstack --os-profile $HMAC_KEY server add volume $1 $2 &> $tmpfile
+    trace_id=$(grep 'Trace ID:' $tmpfile | awk '{print $3}')
+    echo $trace_id >> $TRACE_FILE
+    poll_status volume $2 'in-use'
+}
+
+detach_volume () {
+    # Requires server and volume ids
+    local trace_id
+    openstack --os-profile $HMAC_KEY server remove volume $1 $2 &> $tmpfile
+    trace_id=$(grep 'Trace ID:' $tmpfile | awk '{print $3}')
+    echo $trace_id >> $TRACE_FILE
+    poll_status volume $2 'available'
+}
+
+cleanup () {
+    openstack server delete $server &> /dev/null
+    openstack volume delete $volume &> /dev/null
+}
+
+tmpfile=$(mktemp /tmp/workload.XXXXXX)
+log "START: tmpfile is $tmpfile"
+
+# The server and volume are not traced, only attaching and detaching
+openstack server create test_server --flavor m1.tiny --image cirros --network flat-lan-1-net &> $tmpfile
+server=$(grep '| id' $tmpfile | awk '{print $4}')
+poll_status server $server 'ACTIVE'
+openstack volume create --size 1 test_volume &> $tmpfile
+volume=$(grep '| id' $tmpfile | awk '{print $4}')
+poll_status volume $volume 'available'
+log "Created server ${server} and volume ${volume}"
+
+for i in `seq $iter`
+do
+    log "Attaching volume $i ..."
+    attach_volume $server $volume
+    log "Attached "${volume}
+    log "Detaching volume $i ..."
+    detach_volume $server $volume
+    log "Detached "${volume}
+done
+
+cleanup
+
+duration=$SECONDS
+log "END: DURATION: $duration seconds"
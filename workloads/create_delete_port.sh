@@ -0,0 +1,60 @@
+#!/bin/bash
+SECONDS=0
+HMAC_KEY=Devstack1
+
+# set verbosity of output
+if [[ $3 = "-v" ]]
+then
+    set -x
+fi
+
+log() {
+    echo "[$$] $(date +'%T'): $@"
+}
+
+# set number of iterations
+if [[ $2 =~ ^-?[0-9]+$ ]]
+then
+    TRACE_FILE=$1
+    iter=$2
+else
+    log "Usage: $0 <trace_file> <iterations> (<-v> option)"
+    exit 1
+fi
+
+NETWORK=flat-lan-1-net
+
+create_port () {
+    # Returns the port id
+    local port_id
+    local trace_id
+    openstack --os-profile $HMAC_KEY port create --network $NETWORK test_port &> $tmpfile
+    port_id=$(grep '| id' $tmpfile | awk '{print $4}')
+    trace_id=$(grep 'Trace ID:' $tmpfile | awk '{print $3}')
+    echo $trace_id >> $TRACE_FILE
+    echo $port_id
+}
+
+delete_port () {
+    # Requires port id
+    local trace_id
+    openstack --os-profile $HMAC_KEY port delete $1 &> $tmpfile
+    trace_id=$(grep 'Trace ID:' $tmpfile | awk '{print $3}')
+    echo $trace_id >> $TRACE_FILE
+}
+
+tmpfile=$(mktemp /tmp/workload.XXXXXX)
+log "START: tmpfile is $tmpfile"
+
+for i in `seq $iter`
+do
+    log "Creating port $i ..."
+    port=$(create_port)
+    log "Created "${port}
+    log "Deleting "${port}...""
+    delete_port $port
+    log "Deleted "${port}
+done
+
+duration=$SECONDS
+log "END: DURATION: $duration seconds"
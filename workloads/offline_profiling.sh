@@ -7,7 +7,7 @@ redis-cli flushall
 pythia enable-all
 rm ~/offline_traces.txt
 
-for script in "create_delete_ip" "create_delete_vm" "create_delete_vm" "create_delete_vm" "usage_list"
+for script in "create_delete_ip" "create_delete_vm" "create_delete_vm" "create_delete_vm" "usage_list" "create_delete_volume" "attach_detach_volume" "create_delete_port"
 do
     ~/pythia/workloads/${script}.sh ~/offline_traces.txt $NUM_ITERS
 